@@ -0,0 +1,267 @@
+//! Protocol banner challenge for line based protocols such as SMTP and IMAP.
+//!
+//! The filter rewrites the greeting the upstream server sends on connect so it
+//! carries a per-connection challenge, then holds everything the client sends
+//! until the first line is a valid solution:
+//!
+//! ```text
+//! S: 220-POW 3f2a...c1 16
+//! S: 220 mail.example.com ESMTP ready
+//! C: POW 00000000000003e8
+//! C: EHLO client.example.com
+//! ```
+//!
+//! A solution is a hex nonce such that `sha256(challenge || nonce)` starts
+//! with at least `difficulty` zero bits. The solution line is stripped before
+//! the data reaches the upstream, anything else closes the connection.
+
+use std::fmt::Display;
+use std::time::UNIX_EPOCH;
+
+use pow_runtime::filter::Filter;
+use pow_runtime::filter_state::State;
+use pow_runtime::response::Response;
+use pow_runtime::stream::{StreamCtx, StreamFlow, StreamHook, StreamHookHolder};
+use pow_runtime::{Ctx, HttpHook};
+use proxy_wasm::traits::StreamContext;
+use proxy_wasm::types::ContextType;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+pow_runtime::filter!(Banner);
+
+const MAX_LINE: usize = 512;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Protocol {
+    /// The challenge goes into a `220-` continuation line in front of the greeting.
+    Smtp,
+    /// The challenge goes into an untagged `* POW` line after the greeting.
+    Imap,
+}
+
+impl Protocol {
+    /// Insert the challenge line into the first greeting line of `banner`,
+    /// or `None` if the greeting line is not complete yet.
+    fn inject(&self, banner: &[u8], challenge: &str) -> Option<Vec<u8>> {
+        let end = find_line(banner)?;
+        let (greeting, rest) = banner.split_at(end);
+        let mut out = Vec::with_capacity(banner.len() + challenge.len() + 16);
+        match self {
+            Protocol::Smtp => {
+                out.extend(format!("220-POW {}\r\n", challenge).as_bytes());
+                out.extend(greeting);
+            }
+            Protocol::Imap => {
+                out.extend(greeting);
+                out.extend(format!("* POW {}\r\n", challenge).as_bytes());
+            }
+        }
+        out.extend(rest);
+        Some(out)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Config {
+    pub protocol: Protocol,
+    /// Required number of leading zero bits.
+    pub difficulty: u32,
+}
+
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+pub enum Error {
+    #[error("reply line is too long")]
+    LineTooLong,
+    #[error("expected `POW <hex nonce>`")]
+    Malformed,
+    #[error("invalid nonce")]
+    InvalidNonce,
+    #[error("connection closed before the challenge was answered")]
+    Incomplete,
+    #[error("failed to rewrite buffer: {0}")]
+    Buffer(String),
+}
+
+/// Position right after the first `\n`.
+fn find_line(data: &[u8]) -> Option<usize> {
+    data.iter().position(|b| *b == b'\n').map(|i| i + 1)
+}
+
+fn leading_zero_bits(hash: &[u8]) -> u32 {
+    let mut bits = 0;
+    for byte in hash {
+        if *byte == 0 {
+            bits += 8;
+        } else {
+            bits += byte.leading_zeros();
+            break;
+        }
+    }
+    bits
+}
+
+fn verify(challenge: &[u8], difficulty: u32, line: &[u8]) -> Result<(), Error> {
+    let line = std::str::from_utf8(line).map_err(|_| Error::Malformed)?;
+    let nonce = line
+        .trim_end()
+        .strip_prefix("POW ")
+        .ok_or(Error::Malformed)?;
+    let nonce = hex::decode(nonce).map_err(|_| Error::Malformed)?;
+    let hash = Sha256::new()
+        .chain_update(challenge)
+        .chain_update(&nonce)
+        .finalize();
+    if leading_zero_bits(&hash) < difficulty {
+        return Err(Error::InvalidNonce);
+    }
+    Ok(())
+}
+
+struct Banner {
+    config: Config,
+}
+
+impl Filter for Banner {
+    const NAME: &'static str = "Banner challenge";
+    type Config = Config;
+    type Hook = NoHttp;
+
+    fn configure(_context_id: u32, config: Config) -> Result<Self, String> {
+        Ok(Banner { config })
+    }
+
+    fn hook(_ctx: Ctx, _state: State<Self>) -> NoHttp {
+        NoHttp
+    }
+
+    fn stream_context(context_id: u32, state: State<Self>) -> Option<Box<dyn StreamContext>> {
+        let hook = BannerHook {
+            ctx: StreamCtx::new(context_id),
+            state,
+            challenge: new_challenge(context_id),
+        };
+        Some(Box::new(StreamHookHolder::new(context_id, hook)))
+    }
+
+    fn context_type(&self) -> ContextType {
+        ContextType::StreamContext
+    }
+}
+
+/// This filter only handles raw connections.
+struct NoHttp;
+
+impl HttpHook for NoHttp {
+    async fn on_request_headers(
+        &self,
+        _num_headers: usize,
+        _end_of_stream: bool,
+    ) -> Result<(), impl Into<Response>> {
+        Ok::<(), Response>(())
+    }
+}
+
+fn new_challenge(context_id: u32) -> [u8; 16] {
+    let nanos = proxy_wasm::hostcalls::get_current_time()
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |d| d.as_nanos());
+    let hash = Sha256::new()
+        .chain_update(context_id.to_be_bytes())
+        .chain_update(nanos.to_be_bytes())
+        .finalize();
+    let mut challenge = [0; 16];
+    challenge.copy_from_slice(&hash[..16]);
+    challenge
+}
+
+struct BannerHook {
+    ctx: StreamCtx,
+    state: State<Banner>,
+    challenge: [u8; 16],
+}
+
+impl StreamHook for BannerHook {
+    async fn on_upstream_data(
+        &self,
+        data: Vec<u8>,
+        _end_of_stream: bool,
+    ) -> Result<StreamFlow, impl Display> {
+        let challenge = format!(
+            "{} {}",
+            hex::encode(self.challenge),
+            self.state.config.difficulty
+        );
+        let Some(banner) = self.state.config.protocol.inject(&data, &challenge) else {
+            return Ok(StreamFlow::NeedMore);
+        };
+        if let Err(s) = self.ctx.set_upstream_data(0, data.len(), &banner) {
+            return Err(Error::Buffer(format!("{:?}", s)));
+        }
+        Ok(StreamFlow::Passthrough)
+    }
+
+    async fn on_downstream_data(
+        &self,
+        data: Vec<u8>,
+        end_of_stream: bool,
+    ) -> Result<StreamFlow, impl Display> {
+        let Some(end) = find_line(&data) else {
+            if data.len() > MAX_LINE {
+                return Err(Error::LineTooLong);
+            }
+            if end_of_stream {
+                return Err(Error::Incomplete);
+            }
+            return Ok(StreamFlow::NeedMore);
+        };
+        verify(&self.challenge, self.state.config.difficulty, &data[..end])?;
+        if let Err(s) = self.ctx.set_downstream_data(0, end, &[]) {
+            return Err(Error::Buffer(format!("{:?}", s)));
+        }
+        Ok(StreamFlow::Passthrough)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn inject_smtp_banner() {
+        let banner = Protocol::Smtp.inject(b"220 mx ESMTP\r\n", "ab 8");
+        assert_eq!(
+            banner.as_deref(),
+            Some(&b"220-POW ab 8\r\n220 mx ESMTP\r\n"[..])
+        );
+        assert_eq!(Protocol::Smtp.inject(b"220 mx", "ab 8"), None);
+    }
+
+    #[test]
+    fn inject_imap_banner() {
+        let banner = Protocol::Imap.inject(b"* OK ready\r\n", "ab 8");
+        assert_eq!(
+            banner.as_deref(),
+            Some(&b"* OK ready\r\n* POW ab 8\r\n"[..])
+        );
+    }
+
+    #[test]
+    fn verify_reply_line() {
+        let challenge = [7u8; 16];
+        let nonce = (0u64..)
+            .find(|n| {
+                let hash = Sha256::new()
+                    .chain_update(challenge)
+                    .chain_update(n.to_be_bytes())
+                    .finalize();
+                leading_zero_bits(&hash) >= 8
+            })
+            .unwrap();
+        let line = format!("POW {}\r\n", hex::encode(nonce.to_be_bytes()));
+        assert_eq!(verify(&challenge, 8, line.as_bytes()), Ok(()));
+        assert_eq!(verify(&challenge, 8, b"EHLO x\r\n"), Err(Error::Malformed));
+    }
+}
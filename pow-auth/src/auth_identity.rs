@@ -1,21 +1,163 @@
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+use ring::signature::{
+    UnparsedPublicKey, ECDSA_P256_SHA256_ASN1, ECDSA_P256_SHA256_FIXED, ED25519,
+};
 use secp256k1::Message;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use sha2::{Digest, Sha256};
 
+#[derive(Debug, PartialEq, Eq, thiserror::Error)]
+pub enum Error {
+    #[error("invalid signature: {0}")]
+    Signature(String),
+    #[error("signature mismatch")]
+    Mismatch,
+}
+
+/// Checks signatures over the 32 byte digest a client signs.
+pub trait Verifier {
+    fn verify(&self, message: &[u8; 32], signature: &[u8]) -> Result<(), Error>;
+}
+
+/// ECDSA over the digest itself, the signature DER encoded.
+impl Verifier for secp256k1::PublicKey {
+    fn verify(&self, message: &[u8; 32], signature: &[u8]) -> Result<(), Error> {
+        let signature = secp256k1::ecdsa::Signature::from_der(signature)
+            .map_err(|e| Error::Signature(e.to_string()))?;
+        secp256k1::Secp256k1::verification_only()
+            .verify_ecdsa(&Message::from_digest(*message), &signature, self)
+            .map_err(|_| Error::Mismatch)
+    }
+}
+
+/// The digest is the signed message, the signature 64 bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Ed25519PublicKey(pub [u8; 32]);
+
+impl Verifier for Ed25519PublicKey {
+    fn verify(&self, message: &[u8; 32], signature: &[u8]) -> Result<(), Error> {
+        UnparsedPublicKey::new(&ED25519, self.0)
+            .verify(message, signature)
+            .map_err(|_| Error::Mismatch)
+    }
+}
+
+/// ECDSA with SHA-256 of the digest, as WebCrypto signs it, the signature
+/// either the 64 bytes WebCrypto produces or DER encoded. The key is an
+/// uncompressed point.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct P256PublicKey(pub Vec<u8>);
+
+impl Verifier for P256PublicKey {
+    fn verify(&self, message: &[u8; 32], signature: &[u8]) -> Result<(), Error> {
+        let algorithm = match signature.len() {
+            64 => &ECDSA_P256_SHA256_FIXED,
+            _ => &ECDSA_P256_SHA256_ASN1,
+        };
+        UnparsedPublicKey::new(algorithm, &self.0)
+            .verify(message, signature)
+            .map_err(|_| Error::Mismatch)
+    }
+}
+
+/// A granted key, written `<type>:<hex>` with the type `secp256k1`,
+/// `ed25519` or `p256`. Keys without a type are secp256k1 ones.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum PublicKey {
+    Secp256k1(secp256k1::PublicKey),
+    Ed25519(Ed25519PublicKey),
+    P256(P256PublicKey),
+}
+
+impl PublicKey {
+    pub fn verifier(&self) -> &dyn Verifier {
+        match self {
+            PublicKey::Secp256k1(key) => key,
+            PublicKey::Ed25519(key) => key,
+            PublicKey::P256(key) => key,
+        }
+    }
+}
+
+pub(crate) fn decode_hex(s: &str) -> Result<Vec<u8>, String> {
+    if s.len() % 2 != 0 || !s.is_ascii() {
+        return Err("expect an even number of hex digits".to_string());
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).map_err(|e| e.to_string()))
+        .collect()
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+impl FromStr for PublicKey {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (kind, key) = s.split_once(':').unwrap_or(("secp256k1", s));
+        match kind {
+            "secp256k1" => key
+                .parse()
+                .map(PublicKey::Secp256k1)
+                .map_err(|e| e.to_string()),
+            "ed25519" => {
+                let bytes = decode_hex(key)?;
+                let key = bytes
+                    .try_into()
+                    .map_err(|_| "expect a 32 byte ed25519 key".to_string())?;
+                Ok(PublicKey::Ed25519(Ed25519PublicKey(key)))
+            }
+            "p256" => {
+                let bytes = decode_hex(key)?;
+                if bytes.len() != 65 || bytes[0] != 0x04 {
+                    return Err("expect an uncompressed p256 point".to_string());
+                }
+                Ok(PublicKey::P256(P256PublicKey(bytes)))
+            }
+            _ => Err(format!("unknown key type {}", kind)),
+        }
+    }
+}
+
+impl Display for PublicKey {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PublicKey::Secp256k1(key) => write!(f, "{}", key),
+            PublicKey::Ed25519(key) => write!(f, "ed25519:{}", encode_hex(&key.0)),
+            PublicKey::P256(key) => write!(f, "p256:{}", encode_hex(&key.0)),
+        }
+    }
+}
+
+impl Serialize for PublicKey {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for PublicKey {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
 pub struct AuthIdentity<'a, D> {
-    pub_key: &'a secp256k1::PublicKey,
+    pub_key: &'a dyn Verifier,
     data: D,
-    signature: &'a secp256k1::ecdsa::Signature,
+    signature: &'a [u8],
 }
 
 impl<'a, D> AuthIdentity<'a, D>
 where
     D: Into<Message> + Clone,
 {
-    pub fn new(
-        pub_key: &'a secp256k1::PublicKey,
-        data: D,
-        signature: &'a secp256k1::ecdsa::Signature,
-    ) -> Self {
+    pub fn new(pub_key: &'a dyn Verifier, data: D, signature: &'a [u8]) -> Self {
         Self {
             pub_key,
             data,
@@ -23,30 +165,100 @@ where
         }
     }
 
-    pub fn verify(&self) -> Result<(), secp256k1::Error> {
-        let secp = secp256k1::Secp256k1::new();
+    pub fn verify(&self) -> Result<(), Error> {
         let msg: Message = self.data.clone().into();
-        secp.verify_ecdsa(&msg, self.signature, self.pub_key)
+        self.pub_key.verify(msg.as_ref(), self.signature)
     }
 }
 
+/// What a verified request is remembered by to refuse its replays: the key
+/// and the digest it signed. Not the signature, which is malleable, e.g. a
+/// P-256 one is accepted in two encodings and with either `s` or `n - s`.
+pub fn replay_key(public_key: &PublicKey, message: &Message) -> String {
+    let digest = Sha256::new()
+        .chain_update(public_key.to_string())
+        .chain_update(message.as_ref())
+        .finalize();
+    format!("sig:{:x}", digest)
+}
+
+/// Header naming the version of the [`AuthFactors`] a client signed, `1`
+/// when absent.
+pub const HEADER_VERSION: &str = "X-Auth-Version";
+
+/// What the `legacy` profile signs. Version 1 covers the path and the
+/// timestamp only, so its signatures stay valid for another method or
+/// virtual host. Version 2 starts with the version byte and also covers the
+/// method, the authority, the nonce and the body digest the client declares.
+/// The nonce follows the timestamp prefixed with its big endian `u16`
+/// length, `0` without one.
 #[derive(Debug, Clone)]
 pub struct AuthFactors<'a> {
+    version: u8,
+    method: String,
+    authority: String,
     url: &'a str,
+    body_sha256: Option<[u8; 32]>,
+    nonce: Option<String>,
     timestamp: u64,
 }
 
 impl<'a> AuthFactors<'a> {
     pub fn new(url: &'a str, timestamp: u64) -> Self {
-        Self { url, timestamp }
+        Self {
+            version: 1,
+            method: String::new(),
+            authority: String::new(),
+            url,
+            body_sha256: None,
+            nonce: None,
+            timestamp,
+        }
+    }
+
+    pub fn v2(
+        method: &str,
+        authority: &str,
+        url: &'a str,
+        body_sha256: Option<[u8; 32]>,
+        nonce: Option<String>,
+        timestamp: u64,
+    ) -> Self {
+        Self {
+            version: 2,
+            method: method.to_ascii_uppercase(),
+            authority: authority.to_ascii_lowercase(),
+            url,
+            body_sha256,
+            nonce,
+            timestamp,
+        }
     }
 }
 
 impl From<AuthFactors<'_>> for Message {
     fn from(value: AuthFactors<'_>) -> Self {
         let mut hasher = Sha256::new();
+        if value.version >= 2 {
+            hasher.update([value.version]);
+            hasher.update(value.method.as_bytes());
+            hasher.update(b"\n");
+            hasher.update(value.authority.as_bytes());
+            hasher.update(b"\n");
+        }
         hasher.update(value.url.as_bytes());
+        if value.version >= 2 {
+            hasher.update(b"\n");
+        }
         hasher.update(value.timestamp.to_be_bytes());
+        if value.version >= 2 {
+            let nonce = value.nonce.as_deref().unwrap_or_default();
+            hasher.update((nonce.len() as u16).to_be_bytes());
+            hasher.update(nonce.as_bytes());
+        }
+        if let Some(body_sha256) = value.body_sha256 {
+            hasher.update(body_sha256);
+        }
         let digest = hasher.finalize().into();
         Message::from_digest(digest)
     }
@@ -55,16 +267,19 @@ impl From<AuthFactors<'_>> for Message {
 #[cfg(test)]
 mod test {
     use hex_literal::hex;
-    use secp256k1::{PublicKey, Secp256k1, SecretKey};
+    use ring::rand::SystemRandom;
+    use ring::signature::{EcdsaKeyPair, Ed25519KeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING};
+    use secp256k1::{Secp256k1, SecretKey};
+
+    use super::*;
 
-    use super::{AuthFactors, AuthIdentity};
     #[test]
     fn test() {
         let hex_secret = hex!("3f880ce0892ac66019804c80292d4e90a38aa70a9dabad3f4314bf050f492afc");
         let secret = SecretKey::from_slice(&hex_secret).unwrap();
         println!("{:?}", secret);
         let secp = Secp256k1::new();
-        let pub_key = PublicKey::from_secret_key(&secp, &secret);
+        let pub_key = secp256k1::PublicKey::from_secret_key(&secp, &secret);
 
         let url = "/api/v1/hello";
         let timestamp = 1619823600;
@@ -72,8 +287,147 @@ mod test {
         let factors = AuthFactors::new(url, timestamp);
         // let msg: Message = factors.into();
         // println!("{:?}", msg);
-        let signature = secp.sign_ecdsa(&factors.clone().into(), &secret);
+        let signature = secp
+            .sign_ecdsa(&factors.clone().into(), &secret)
+            .serialize_der();
         let identity = AuthIdentity::new(&pub_key, factors, &signature);
-        println!("{:?}", identity.verify());
+        assert_eq!(identity.verify(), Ok(()));
+    }
+
+    #[test]
+    fn factors_v2() {
+        let digest = |factors: AuthFactors| Message::from(factors);
+        let get = digest(AuthFactors::v2("get", "Example.com", "/foo", None, None, 1));
+        assert_eq!(
+            get,
+            digest(AuthFactors::v2("GET", "example.com", "/foo", None, None, 1))
+        );
+        assert_ne!(get, digest(AuthFactors::new("/foo", 1)));
+        assert_ne!(
+            get,
+            digest(AuthFactors::v2(
+                "DELETE",
+                "example.com",
+                "/foo",
+                None,
+                None,
+                1
+            ))
+        );
+        assert_ne!(
+            get,
+            digest(AuthFactors::v2("GET", "other.com", "/foo", None, None, 1))
+        );
+        assert_ne!(
+            get,
+            digest(AuthFactors::v2(
+                "GET",
+                "example.com",
+                "/foo",
+                Some([0; 32]),
+                None,
+                1
+            ))
+        );
+        let nonce = Some("5f2b8c1e".to_string());
+        assert_ne!(
+            get,
+            digest(AuthFactors::v2(
+                "GET",
+                "example.com",
+                "/foo",
+                None,
+                nonce,
+                1
+            ))
+        );
+    }
+
+    #[test]
+    fn key_types() {
+        let factors = AuthFactors::new("/api/v1/hello", 1619823600);
+        let message: Message = factors.clone().into();
+
+        let ed25519 = Ed25519KeyPair::from_seed_unchecked(&[7; 32]).unwrap();
+        let key: PublicKey = format!("ed25519:{}", encode_hex(ed25519.public_key().as_ref()))
+            .parse()
+            .unwrap();
+        let signature = ed25519.sign(message.as_ref());
+        let identity = AuthIdentity::new(key.verifier(), factors.clone(), signature.as_ref());
+        assert_eq!(identity.verify(), Ok(()));
+        let other = AuthIdentity::new(key.verifier(), AuthFactors::new("/", 1), signature.as_ref());
+        assert_eq!(other.verify(), Err(Error::Mismatch));
+
+        let rng = SystemRandom::new();
+        let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng).unwrap();
+        let p256 = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, pkcs8.as_ref(), &rng)
+            .unwrap();
+        let encoded = format!("p256:{}", encode_hex(p256.public_key().as_ref()));
+        let key: PublicKey = encoded.parse().unwrap();
+        assert_eq!(key.to_string(), encoded);
+        let signature = p256.sign(&rng, message.as_ref()).unwrap();
+        let identity = AuthIdentity::new(key.verifier(), factors, signature.as_ref());
+        assert_eq!(identity.verify(), Ok(()));
+
+        let legacy: PublicKey =
+            "039e70a683d711ab788433b4cabddbd10dce4bb1f29c67cc3219b325053b0f2f1c"
+                .parse()
+                .unwrap();
+        assert!(matches!(legacy, PublicKey::Secp256k1(_)));
+        assert_ne!(replay_key(&legacy, &message), replay_key(&key, &message));
+        assert!("rsa:00".parse::<PublicKey>().is_err());
+        assert!("ed25519:00".parse::<PublicKey>().is_err());
+    }
+
+    /// `(r, s)` of a P-256 signature as DER.
+    fn der(r: &[u8], s: &[u8]) -> Vec<u8> {
+        let integer = |n: &[u8]| {
+            let n = &n[n.iter().position(|b| *b != 0).unwrap_or(n.len() - 1)..];
+            let pad = n[0] & 0x80 != 0;
+            let mut out = vec![0x02, (n.len() + pad as usize) as u8];
+            out.extend(pad.then_some(0));
+            out.extend(n);
+            out
+        };
+        let body = [integer(r), integer(s)].concat();
+        [vec![0x30, body.len() as u8], body].concat()
+    }
+
+    #[test]
+    fn replay_of_reencoded_signature() {
+        const ORDER: [u8; 32] =
+            hex!("ffffffff00000000ffffffffffffffffbce6faada7179e84f3b9cac2fc632551");
+        let message: Message = AuthFactors::new("/api/v1/hello", 1619823600).into();
+        let rng = SystemRandom::new();
+        let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng).unwrap();
+        let p256 = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, pkcs8.as_ref(), &rng)
+            .unwrap();
+        let key: PublicKey = format!("p256:{}", encode_hex(p256.public_key().as_ref()))
+            .parse()
+            .unwrap();
+        let signature = p256.sign(&rng, message.as_ref()).unwrap();
+        let (r, s) = signature.as_ref().split_at(32);
+
+        // n - s signs the same message
+        let mut negated = [0u8; 32];
+        let mut borrow = 0;
+        for i in (0..32).rev() {
+            let diff = ORDER[i] as i16 - s[i] as i16 - borrow;
+            negated[i] = diff.rem_euclid(256) as u8;
+            borrow = (diff < 0) as i16;
+        }
+        let reencoded = [
+            signature.as_ref().to_vec(),
+            der(r, s),
+            [r, &negated].concat(),
+            der(r, &negated),
+        ];
+        for signature in &reencoded {
+            assert_eq!(key.verifier().verify(message.as_ref(), signature), Ok(()));
+        }
+        // every encoding is one request to the replay cache
+        let replayed = replay_key(&key, &message);
+        let other: Message = AuthFactors::new("/api/v1/hello", 1619823601).into();
+        assert_ne!(replay_key(&key, &other), replayed);
     }
 }
@@ -0,0 +1,318 @@
+//! Signing strings of the `X-Auth-Signature` header.
+//!
+//! The client signs the SHA-256 digest of a string built from the request
+//! according to the configured [`Profile`]:
+//!
+//! - `legacy`: the path followed by the big endian timestamp, as before.
+//!   With `X-Auth-Version: 2` the version byte, the method, the authority
+//!   and the path on lines of their own, the timestamp, `X-Auth-Nonce` and
+//!   the digest of `X-Auth-Content-SHA256` if sent, see [`AuthFactors`].
+//! - `sigv4-like`: like AWS SigV4 canonical requests
+//!   ```text
+//!   GET
+//!   /api/items
+//!   a=1&b=2
+//!   content-type:application/json
+//!   host:example.com
+//!
+//!   content-type;host
+//!   1700000000
+//!   ```
+//!   the query is sorted, header names are lowercased and sorted, runs of
+//!   whitespace in values are folded into one space.
+//! - `httpsig`: lines in the style of RFC 9421 signature bases, headers in
+//!   the configured order
+//!   ```text
+//!   "@method": GET
+//!   "@path": /api/items
+//!   "@query": ?b=2&a=1
+//!   "host": example.com
+//!   "@created": 1700000000
+//!   ```
+//! - `custom`: the method, path, `name:value` lines and the timestamp joined
+//!   by newlines, with the `lowercase`, `sort` and `fold_whitespace` rules
+//!   of the configuration.
+//!
+//! Header values are trimmed in every profile, a configured header missing
+//! from the request fails the verification.
+
+use secp256k1::Message;
+use sha2::{Digest, Sha256};
+
+use crate::auth_identity::{decode_hex, AuthFactors, HEADER_VERSION};
+use crate::config::{Canonicalization, Profile};
+use crate::hmac_signature::{HEADER_CONTENT_SHA256, HEADER_NONCE};
+
+/// Value of `name` in `headers`, multiple values joined by `, `.
+pub(crate) fn header_value(headers: &[(String, String)], name: &str) -> Option<String> {
+    let values: Vec<&str> = headers
+        .iter()
+        .filter(|(k, _)| k.eq_ignore_ascii_case(name))
+        .map(|(_, v)| v.trim())
+        .collect();
+    if values.is_empty() {
+        None
+    } else {
+        Some(values.join(", "))
+    }
+}
+
+pub(crate) fn fold_whitespace(value: &str) -> String {
+    value.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+pub(crate) fn sorted_query(query: &str) -> String {
+    let mut pairs: Vec<(&str, &str)> = query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| pair.split_once('=').unwrap_or((pair, "")))
+        .collect();
+    pairs.sort();
+    pairs
+        .iter()
+        .map(|(k, v)| format!("{}={}", k, v))
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+impl Canonicalization {
+    fn signed_headers(
+        &self,
+        headers: &[(String, String)],
+        lowercase: bool,
+        fold: bool,
+    ) -> Result<Vec<(String, String)>, String> {
+        self.headers
+            .iter()
+            .map(|name| {
+                let value = header_value(headers, name)
+                    .ok_or_else(|| format!("Missing signed header {}", name))?;
+                let name = if lowercase {
+                    name.to_ascii_lowercase()
+                } else {
+                    name.clone()
+                };
+                let value = if fold { fold_whitespace(&value) } else { value };
+                Ok((name, value))
+            })
+            .collect()
+    }
+
+    /// The string the client signs, `None` for the `legacy` profile which
+    /// hashes binary data instead.
+    pub fn signing_string(
+        &self,
+        headers: &[(String, String)],
+        timestamp: u64,
+    ) -> Result<Option<String>, String> {
+        let method = header_value(headers, ":method").unwrap_or_default();
+        let target = header_value(headers, ":path").unwrap_or_default();
+        let (path, query) = target.split_once('?').unwrap_or((&target, ""));
+
+        let string = match self.profile {
+            Profile::Legacy => return Ok(None),
+            Profile::Sigv4Like => {
+                let mut signed = self.signed_headers(headers, true, true)?;
+                signed.sort();
+                let names: Vec<&str> = signed.iter().map(|(k, _)| k.as_str()).collect();
+                let mut lines = vec![
+                    method.to_ascii_uppercase(),
+                    path.to_string(),
+                    sorted_query(query),
+                ];
+                lines.extend(signed.iter().map(|(k, v)| format!("{}:{}", k, v)));
+                lines.push(String::new());
+                lines.push(names.join(";"));
+                lines.push(timestamp.to_string());
+                lines.join("\n")
+            }
+            Profile::Httpsig => {
+                let mut lines = vec![
+                    format!("\"@method\": {}", method.to_ascii_uppercase()),
+                    format!("\"@path\": {}", path),
+                ];
+                if !query.is_empty() {
+                    lines.push(format!("\"@query\": ?{}", query));
+                }
+                lines.extend(
+                    self.signed_headers(headers, true, false)?
+                        .iter()
+                        .map(|(k, v)| format!("\"{}\": {}", k, v)),
+                );
+                lines.push(format!("\"@created\": {}", timestamp));
+                lines.join("\n")
+            }
+            Profile::Custom => {
+                let mut signed =
+                    self.signed_headers(headers, self.lowercase, self.fold_whitespace)?;
+                if self.sort {
+                    signed.sort();
+                }
+                let mut lines = vec![method, target.clone()];
+                lines.extend(signed.iter().map(|(k, v)| format!("{}:{}", k, v)));
+                lines.push(timestamp.to_string());
+                lines.join("\n")
+            }
+        };
+        Ok(Some(string))
+    }
+
+    /// What the `legacy` profile signs, in the version the client names.
+    fn factors<'a>(
+        &self,
+        headers: &[(String, String)],
+        path: &'a str,
+        timestamp: u64,
+    ) -> Result<AuthFactors<'a>, String> {
+        let version = match header_value(headers, HEADER_VERSION) {
+            Some(version) => version
+                .parse::<u8>()
+                .map_err(|_| format!("Invalid {}", HEADER_VERSION))?,
+            None => 1,
+        };
+        if version < self.min_version {
+            return Err(format!(
+                "{} {} is no longer accepted, sign version {}",
+                HEADER_VERSION, version, self.min_version
+            ));
+        }
+        match version {
+            1 => Ok(AuthFactors::new(path, timestamp)),
+            2 => {
+                let body_sha256 = header_value(headers, HEADER_CONTENT_SHA256)
+                    .map(|digest| {
+                        decode_hex(&digest)
+                            .ok()
+                            .and_then(|digest| digest.try_into().ok())
+                            .ok_or_else(|| {
+                                format!("Invalid {}, expect 64 hex digits", HEADER_CONTENT_SHA256)
+                            })
+                    })
+                    .transpose()?;
+                let method = header_value(headers, ":method").unwrap_or_default();
+                let authority = header_value(headers, ":authority").unwrap_or_default();
+                Ok(AuthFactors::v2(
+                    &method,
+                    &authority,
+                    path,
+                    body_sha256,
+                    header_value(headers, HEADER_NONCE),
+                    timestamp,
+                ))
+            }
+            _ => Err(format!("Unsupported {} {}", HEADER_VERSION, version)),
+        }
+    }
+
+    /// The digest verified against `X-Auth-Signature`.
+    pub fn message(
+        &self,
+        headers: &[(String, String)],
+        path: &str,
+        timestamp: u64,
+    ) -> Result<Message, String> {
+        let Some(string) = self.signing_string(headers, timestamp)? else {
+            return self.factors(headers, path, timestamp).map(Into::into);
+        };
+        log::debug!("signing string: {:?}", string);
+        let digest = Sha256::digest(string.as_bytes()).into();
+        Ok(Message::from_digest(digest))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn headers() -> Vec<(String, String)> {
+        [
+            (":method", "get"),
+            (":path", "/api/items?b=2&a=1"),
+            ("host", "example.com"),
+            ("Content-Type", "  application/json ;  charset=utf-8 "),
+        ]
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect()
+    }
+
+    fn canonicalization(profile: Profile) -> Canonicalization {
+        Canonicalization {
+            profile,
+            headers: vec!["Host".to_string(), "content-type".to_string()],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn profiles() {
+        let headers = headers();
+        assert_eq!(
+            canonicalization(Profile::Sigv4Like)
+                .signing_string(&headers, 1_700_000_000)
+                .unwrap()
+                .unwrap(),
+            "GET\n/api/items\na=1&b=2\n\
+             content-type:application/json ; charset=utf-8\nhost:example.com\n\n\
+             content-type;host\n1700000000"
+        );
+        assert_eq!(
+            canonicalization(Profile::Httpsig)
+                .signing_string(&headers, 1_700_000_000)
+                .unwrap()
+                .unwrap(),
+            "\"@method\": GET\n\"@path\": /api/items\n\"@query\": ?b=2&a=1\n\
+             \"host\": example.com\n\
+             \"content-type\": application/json ;  charset=utf-8\n\
+             \"@created\": 1700000000"
+        );
+        let custom = Canonicalization {
+            sort: true,
+            ..canonicalization(Profile::Custom)
+        };
+        assert_eq!(
+            custom.signing_string(&headers, 1).unwrap().unwrap(),
+            "get\n/api/items?b=2&a=1\n\
+             Host:example.com\ncontent-type:application/json ;  charset=utf-8\n1"
+        );
+        assert_eq!(
+            canonicalization(Profile::Legacy).signing_string(&headers, 1),
+            Ok(None)
+        );
+    }
+
+    #[test]
+    fn legacy_versions() {
+        let mut headers = headers();
+        let legacy = canonicalization(Profile::Legacy);
+        let v1 = legacy.message(&headers, "/api/items?b=2&a=1", 1).unwrap();
+        assert_eq!(v1, AuthFactors::new("/api/items?b=2&a=1", 1).into());
+
+        headers.push(("x-auth-version".to_string(), "2".to_string()));
+        let v2 = legacy.message(&headers, "/api/items?b=2&a=1", 1).unwrap();
+        assert_ne!(v1, v2);
+        headers.push(("x-auth-content-sha256".to_string(), "00".repeat(32)));
+        assert_ne!(
+            v2,
+            legacy.message(&headers, "/api/items?b=2&a=1", 1).unwrap()
+        );
+
+        let strict = Canonicalization {
+            min_version: 2,
+            ..canonicalization(Profile::Legacy)
+        };
+        assert!(strict.message(&[], "/", 1).is_err());
+        assert!(strict.message(&headers, "/", 1).is_ok());
+        let unsupported = vec![("x-auth-version".to_string(), "3".to_string())];
+        assert!(legacy.message(&unsupported, "/", 1).is_err());
+    }
+
+    #[test]
+    fn missing_signed_header() {
+        let headers = vec![(":path".to_string(), "/".to_string())];
+        assert_eq!(
+            canonicalization(Profile::Sigv4Like).signing_string(&headers, 1),
+            Err("Missing signed header Host".to_string())
+        );
+    }
+}
@@ -1,26 +1,232 @@
 use std::collections::HashMap;
 
+use pow_runtime::failure_mode::FailureMode;
 use pow_runtime::log_level::LogLevel;
-use pow_types::{cidr::CIDR, config::VirtualHost};
-use secp256k1::PublicKey;
+use pow_runtime::response::ErrorFormat;
+use pow_types::{cidr::CIDR, config::VirtualHost, route::normalize::PathNormalization};
 use serde::{Deserialize, Serialize};
 
+use crate::auth_identity::PublicKey;
+
 #[derive(Debug, Eq, PartialEq, Serialize, Deserialize)]
 pub struct Token {
     pub name: String,
     pub public_key: PublicKey,
+    #[serde(default)]
+    pub scope: Scope,
+}
+
+/// What a granted key may do, anything by default.
+#[derive(Debug, Default, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Scope {
+    /// Allowed methods, any when empty.
+    #[serde(default)]
+    pub methods: Vec<String>,
+    /// Allowed paths, `*` matches any characters. Any when empty.
+    #[serde(default)]
+    pub paths: Vec<String>,
+    /// Rate tier told to later filters in `tier_header`.
+    pub tier: Option<String>,
+}
+
+/// `path` matches `pattern`, where `*` stands for any characters.
+pub(crate) fn wildcard_match(pattern: &str, path: &str) -> bool {
+    let Some((head, rest)) = pattern.split_once('*') else {
+        return pattern == path;
+    };
+    let Some(mut remaining) = path.strip_prefix(head) else {
+        return false;
+    };
+    let mut parts: Vec<&str> = rest.split('*').collect();
+    let tail = parts.pop().unwrap_or_default();
+    for part in parts {
+        match remaining.find(part) {
+            Some(i) => remaining = &remaining[i + part.len()..],
+            None => return false,
+        }
+    }
+    remaining.len() >= tail.len() && remaining.ends_with(tail)
+}
+
+impl Scope {
+    /// Whether the key may send `method` to `path`, the query excluded.
+    pub fn allows(&self, method: &str, path: &str) -> bool {
+        let method_allowed =
+            self.methods.is_empty() || self.methods.iter().any(|m| m.eq_ignore_ascii_case(method));
+        let path_allowed =
+            self.paths.is_empty() || self.paths.iter().any(|p| wildcard_match(p, path));
+        method_allowed && path_allowed
+    }
+}
+
+/// A granted key.
+#[derive(Debug, Eq, PartialEq)]
+pub struct Grant {
+    pub name: String,
+    pub scope: Scope,
+}
+
+/// A JSON Web Key, only the members used to verify `RS256` and `ES256`.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Jwk {
+    pub kty: String,
+    pub kid: Option<String>,
+    /// Modulus and exponent of an `RSA` key.
+    pub n: Option<String>,
+    pub e: Option<String>,
+    /// Curve and point of an `EC` key, only `P-256` is supported.
+    pub crv: Option<String>,
+    pub x: Option<String>,
+    pub y: Option<String>,
+}
+
+fn default_jwks_path() -> String {
+    "/.well-known/jwks.json".to_string()
+}
+
+fn default_jwks_cache_for() -> u64 {
+    300
+}
+
+/// Where to fetch a JWK Set from.
+#[derive(Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct JwksSource {
+    /// Cluster name of the issuer.
+    pub upstream: String,
+    pub authority: String,
+    #[serde(default = "default_jwks_path")]
+    pub path: String,
+    /// Seconds the fetched keys are used before fetching them again.
+    #[serde(default = "default_jwks_cache_for")]
+    pub cache_for: u64,
+}
+
+fn default_leeway() -> u64 {
+    60
+}
+
+/// Accept bearer JWTs, see [`crate::jwt`].
+#[derive(Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct JwtSetting {
+    /// Accepted `iss` claims, any when empty.
+    #[serde(default)]
+    pub issuers: Vec<String>,
+    /// Accepted `aud` claims, a token must name one of them. Any when empty.
+    #[serde(default)]
+    pub audiences: Vec<String>,
+    /// Keys configured statically, used along with the fetched ones.
+    #[serde(default)]
+    pub keys: Vec<Jwk>,
+    pub jwks: Option<JwksSource>,
+    /// Seconds of clock skew tolerated on `exp` and `nbf`.
+    #[serde(default = "default_leeway")]
+    pub leeway: u64,
+}
+
+#[derive(Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct HmacKey {
+    /// The `Credential` of the client, the principal of its requests.
+    pub id: String,
+    pub secret: String,
+}
+
+/// Accept requests signed with shared secrets, see
+/// [`crate::hmac_signature`].
+#[derive(Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct HmacSetting {
+    pub keys: Vec<HmacKey>,
+    /// Headers every signature must cover besides `host` and the
+    /// `x-auth-*` ones.
+    #[serde(default)]
+    pub signed_headers: Vec<String>,
+    /// Seconds the `X-Auth-Date` may differ from the clock of the filter.
+    #[serde(default = "default_max_age")]
+    pub max_age: u64,
+}
+
+fn default_grants_path() -> String {
+    "/grants".to_string()
+}
+
+fn default_grants_ttl() -> u64 {
+    300
+}
+
+fn default_grants_stale_for() -> u64 {
+    3600
+}
+
+/// Fetch the grants from an identity service, see
+/// [`crate::remote_grants`].
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct RemoteGrantsSetting {
+    /// Cluster name of the identity service.
+    pub upstream: String,
+    pub authority: String,
+    #[serde(default = "default_grants_path")]
+    pub path: String,
+    /// Seconds fetched grants are used before fetching them again.
+    #[serde(default = "default_grants_ttl")]
+    pub ttl: u64,
+    /// Seconds after `ttl` the grants are still used while fetched again in
+    /// the background, or when the service fails.
+    #[serde(default = "default_grants_stale_for")]
+    pub stale_for: u64,
+}
+
+/// An identity of a peer certificate, `*` stands for any characters within
+/// a label of a DNS name or a segment of a URI, and any characters of a
+/// subject.
+#[derive(Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PeerId {
+    /// Any URI SAN.
+    Uri(String),
+    /// A URI SAN in the `spiffe` scheme.
+    Spiffe(String),
+    Dns(String),
+    Subject(String),
+}
+
+/// A workload granted by its certificate.
+#[derive(Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct MtlsPrincipal {
+    pub name: String,
+    #[serde(flatten)]
+    pub peer: PeerId,
+    #[serde(default)]
+    pub scope: Scope,
+}
+
+/// Grant mutual TLS peers, see [`crate::mtls`].
+#[derive(Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct MtlsSetting {
+    /// Matched in order, the first one is the principal of the request.
+    pub principals: Vec<MtlsPrincipal>,
 }
 
 #[derive(Debug, Eq, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "snake_case")]
 enum RawSetting {
     Grants(Vec<Token>),
+    Jwt(JwtSetting),
+    Hmac(HmacSetting),
+    Remote(RemoteGrantsSetting),
+    Mtls(MtlsSetting),
     Public,
 }
 
 #[derive(Debug, Eq, PartialEq)]
 pub enum Setting {
-    Grants(HashMap<PublicKey, String>),
+    Grants(HashMap<PublicKey, Grant>),
+    /// The `sub` claim of the token is the principal.
+    Jwt(JwtSetting),
+    /// The id of the key is the principal.
+    Hmac(HmacSetting),
+    /// Grants fetched from an identity service.
+    Remote(RemoteGrantsSetting),
+    /// The certificate of the peer is the only credential.
+    Mtls(MtlsSetting),
     Public,
 }
 
@@ -30,10 +236,18 @@ impl From<RawSetting> for Setting {
             RawSetting::Grants(grants_vec) => {
                 let mut grants = HashMap::new();
                 for token in grants_vec {
-                    grants.insert(token.public_key, token.name);
+                    let grant = Grant {
+                        name: token.name,
+                        scope: token.scope,
+                    };
+                    grants.insert(token.public_key, grant);
                 }
                 Setting::Grants(grants)
             }
+            RawSetting::Jwt(setting) => Setting::Jwt(setting),
+            RawSetting::Hmac(setting) => Setting::Hmac(setting),
+            RawSetting::Remote(setting) => Setting::Remote(setting),
+            RawSetting::Mtls(setting) => Setting::Mtls(setting),
             RawSetting::Public => Setting::Public,
         }
     }
@@ -48,9 +262,252 @@ impl<'de> Deserialize<'de> for Setting {
     }
 }
 
+#[derive(Debug, Eq, PartialEq, Deserialize)]
+pub struct RouteSetting {
+    #[serde(flatten)]
+    pub access: Setting,
+    /// Overrides the filter wide `failure_mode` for this route.
+    pub failure_mode: Option<FailureMode>,
+    /// Peers the request must come from, in addition to the `access`.
+    pub peers: Option<MtlsSetting>,
+}
+
+/// How the string signed with `X-Auth-Signature` is built, see
+/// [`crate::canonical`].
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Profile {
+    /// The path and the timestamp only.
+    #[default]
+    Legacy,
+    Sigv4Like,
+    Httpsig,
+    Custom,
+}
+
+#[derive(Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Canonicalization {
+    #[serde(default)]
+    pub profile: Profile,
+    /// Request headers covered by the signature, unused by `legacy`.
+    #[serde(default)]
+    pub headers: Vec<String>,
+    /// Rules of the `custom` profile.
+    #[serde(default)]
+    pub lowercase: bool,
+    #[serde(default)]
+    pub sort: bool,
+    #[serde(default)]
+    pub fold_whitespace: bool,
+    /// Lowest `X-Auth-Version` the `legacy` profile accepts, `2` rejects
+    /// signatures that cover neither the method nor the authority.
+    #[serde(default)]
+    pub min_version: u8,
+}
+
+fn default_components() -> Vec<String> {
+    vec![
+        "@method".to_string(),
+        "@authority".to_string(),
+        "@path".to_string(),
+    ]
+}
+
+fn default_max_age() -> u64 {
+    60
+}
+
+fn default_max_nonces() -> usize {
+    65_536
+}
+
+/// Accept RFC 9421 HTTP Message Signatures next to the `X-Auth-*` headers,
+/// see [`crate::http_signature`].
+#[derive(Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct HttpSignatureSetting {
+    /// Signature to verify, the first one of `Signature-Input` if absent.
+    pub label: Option<String>,
+    /// Components every signature must cover.
+    #[serde(default = "default_components")]
+    pub required_components: Vec<String>,
+    /// Seconds a signature is accepted for around its `created` parameter.
+    #[serde(default = "default_max_age")]
+    pub max_age: u64,
+}
+
+fn default_revocation_path() -> String {
+    "/revoked".to_string()
+}
+
+fn default_revocation_interval() -> u64 {
+    60
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct RevocationEndpoint {
+    /// Cluster name of the revocation service.
+    pub upstream: String,
+    pub authority: String,
+    #[serde(default = "default_revocation_path")]
+    pub path: String,
+}
+
+/// Reject keys of the revocation list, see [`crate::revocation`].
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct RevocationSetting {
+    /// Where to poll the list from. Without one the list is only read from
+    /// shared data, where something else pushes it.
+    pub endpoint: Option<RevocationEndpoint>,
+    /// Seconds between two polls.
+    #[serde(default = "default_revocation_interval")]
+    pub interval: u64,
+}
+
 #[derive(Debug, Eq, PartialEq, Serialize, Deserialize)]
 pub struct Config<T> {
     pub virtual_hosts: Vec<VirtualHost<T>>,
     pub whitelist: Option<Vec<CIDR>>,
     pub log_level: Option<LogLevel>,
+    /// Header carrying the name of the verified grant to later filters. Any
+    /// value sent by the client is removed.
+    pub principal_header: Option<String>,
+    /// Header carrying the `tier` of the verified grant's scope, removed
+    /// like the principal header.
+    pub tier_header: Option<String>,
+    /// Remove the signature headers of verified requests so upstreams never
+    /// see the raw credentials.
+    #[serde(default)]
+    pub strip_credentials: bool,
+    /// Applied to errors before a route is matched and on routes without their own.
+    #[serde(default)]
+    pub failure_mode: FailureMode,
+    #[serde(default)]
+    pub error_format: ErrorFormat,
+    /// Prefix of the problem `type` URIs, the reason code is appended.
+    pub problem_type_base: Option<String>,
+    #[serde(default)]
+    pub canonicalization: Canonicalization,
+    pub http_signatures: Option<HttpSignatureSetting>,
+    /// Seconds `X-Auth-Timestamp` may differ from the clock of the filter,
+    /// either way.
+    #[serde(default = "default_max_age")]
+    pub max_skew: u64,
+    /// Require an `X-Auth-Nonce` with `X-Auth-Signature`, so that a client
+    /// may send the same request twice within the window of `max_skew`.
+    /// Every signature is accepted once either way.
+    #[serde(default)]
+    pub require_nonce: bool,
+    /// Signatures remembered to reject a replay, at least the signed
+    /// requests of `2 * max_skew` seconds. The least recently used are
+    /// forgotten first past it.
+    #[serde(default = "default_max_nonces")]
+    pub max_nonces: usize,
+    pub revocation: Option<RevocationSetting>,
+    /// Applied to the path before routes are matched, see
+    /// [`pow_types::route::normalize`].
+    #[serde(default)]
+    pub path_normalization: PathNormalization,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn route_failure_mode() {
+        let routes: Vec<RouteSetting> = serde_yaml::from_str(
+            r#"
+- public: null
+- grants: []
+  failure_mode: fail_open
+"#,
+        )
+        .expect("failed to parse routes");
+        assert_eq!(routes[0].access, Setting::Public);
+        assert_eq!(routes[0].failure_mode, None);
+        assert_eq!(routes[1].access, Setting::Grants(HashMap::new()));
+        assert_eq!(routes[1].failure_mode, Some(FailureMode::FailOpen));
+    }
+
+    #[test]
+    fn route_peers() {
+        let route: RouteSetting = serde_yaml::from_str(
+            r#"
+grants: []
+peers:
+  principals:
+  - name: billing
+    spiffe: spiffe://cluster.local/ns/prod/sa/billing
+"#,
+        )
+        .expect("failed to parse route");
+        assert_eq!(route.access, Setting::Grants(HashMap::new()));
+        let peers = route.peers.expect("missing peers");
+        assert_eq!(
+            peers.principals[0].peer,
+            PeerId::Spiffe("spiffe://cluster.local/ns/prod/sa/billing".to_string())
+        );
+        assert_eq!(peers.principals[0].scope, Scope::default());
+
+        let route: RouteSetting = serde_yaml::from_str(
+            r#"
+mtls:
+  principals:
+  - name: ops
+    subject: CN=ops
+"#,
+        )
+        .expect("failed to parse route");
+        assert!(matches!(route.access, Setting::Mtls(_)));
+        assert_eq!(route.peers, None);
+    }
+
+    #[test]
+    fn scope() {
+        let scope: Scope = serde_yaml::from_str(
+            r#"
+methods: [GET, HEAD]
+paths: ["/api/*", "/status"]
+tier: basic
+"#,
+        )
+        .expect("failed to parse scope");
+        assert!(scope.allows("get", "/api/items/1"));
+        assert!(scope.allows("HEAD", "/status"));
+        assert!(!scope.allows("DELETE", "/api/items/1"));
+        assert!(!scope.allows("GET", "/admin"));
+        assert!(!scope.allows("GET", "/status/extra"));
+        assert!(Scope::default().allows("POST", "/admin"));
+        // scopes see the path normalized, like the routes and the upstream
+        let normalize = |path| PathNormalization::default().normalize(path).unwrap();
+        assert!(!scope.allows("GET", &normalize("/api/../admin")));
+        assert!(!scope.allows("GET", &normalize("/api/%2e%2e/admin")));
+        assert!(scope.allows("GET", &normalize("/api/./items/%31")));
+
+        assert!(wildcard_match("/users/*/posts", "/users/42/posts"));
+        assert!(!wildcard_match("/users/*/posts", "/users/42/comments"));
+        assert!(wildcard_match("*.json", "/a.json"));
+        assert!(!wildcard_match("/a*a", "/a"));
+    }
+
+    #[test]
+    fn route_jwt() {
+        let route: RouteSetting = serde_yaml::from_str(
+            r#"
+jwt:
+  issuers: ["https://auth.example.com"]
+  jwks:
+    upstream: outbound|443||auth.example.com
+    authority: auth.example.com
+"#,
+        )
+        .expect("failed to parse route");
+        let Setting::Jwt(setting) = route.access else {
+            panic!("expect a jwt route, got {:?}", route.access);
+        };
+        assert_eq!(setting.leeway, 60);
+        let jwks = setting.jwks.expect("missing jwks");
+        assert_eq!(jwks.path, "/.well-known/jwks.json");
+        assert_eq!(jwks.cache_for, 300);
+    }
 }
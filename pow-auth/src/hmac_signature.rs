@@ -0,0 +1,321 @@
+//! Shared secret request signing in the style of AWS SigV4.
+//!
+//! ```text
+//! Authorization: HMAC-SHA256 Credential=billing, SignedHeaders=host;x-auth-content-sha256;x-auth-date;x-auth-nonce, Signature=<hex>
+//! X-Auth-Date: 1700000000
+//! X-Auth-Nonce: 5f2b8c1e
+//! X-Auth-Content-SHA256: <hex of the SHA-256 digest of the body>
+//! ```
+//!
+//! The canonical request is the method, the path, the sorted query, the
+//! signed headers as lowercased `name:value` lines sorted by name, an empty
+//! line, the signed header names and the body digest, joined by newlines.
+//! The client signs
+//!
+//! ```text
+//! HMAC-SHA256
+//! <X-Auth-Date>
+//! <X-Auth-Nonce>
+//! <hex of the SHA-256 digest of the canonical request>
+//! ```
+//!
+//! with the secret of its key, unlike SigV4 there is no derived signing key.
+//! `host` is the `:authority` of the request. The filter only sees the
+//! headers, the body digest is the one the client declares: upstreams that
+//! care must compare it with the body they receive.
+//!
+//! Nonces are remembered for twice `max_age` by the filter, so a request is
+//! accepted once, see [`nonce_key`].
+
+use pow_types::bytearray32::ByteArray32;
+use ring::hmac;
+use sha2::{Digest, Sha256};
+
+use crate::canonical::{fold_whitespace, header_value, sorted_query};
+use crate::config::HmacSetting;
+use crate::jwt::HEADER_AUTHORIZATION;
+
+pub const HEADER_DATE: &str = "X-Auth-Date";
+pub const HEADER_NONCE: &str = "X-Auth-Nonce";
+pub const HEADER_CONTENT_SHA256: &str = "X-Auth-Content-SHA256";
+const ALGORITHM: &str = "HMAC-SHA256";
+
+/// Headers every signature must cover.
+const REQUIRED_HEADERS: [&str; 4] = [
+    "host",
+    "x-auth-content-sha256",
+    "x-auth-date",
+    "x-auth-nonce",
+];
+
+#[derive(Debug, PartialEq, Eq, thiserror::Error)]
+pub enum Error {
+    #[error("{0}")]
+    Missing(String),
+    #[error("{0}")]
+    Invalid(String),
+    #[error("{0}")]
+    Expired(String),
+    #[error("{0}")]
+    UnknownKey(String),
+}
+
+/// The parameters of an `HMAC-SHA256` authorization.
+#[derive(Debug, PartialEq, Eq)]
+struct Credentials {
+    key_id: String,
+    signed_headers: Vec<String>,
+    signature: ByteArray32,
+}
+
+fn parse_authorization(value: &str) -> Result<Credentials, Error> {
+    let params = value
+        .strip_prefix(ALGORITHM)
+        .filter(|params| params.starts_with(' '))
+        .ok_or_else(|| Error::Missing(format!("expect an {} authorization", ALGORITHM)))?;
+    let (mut key_id, mut signed_headers, mut signature) = (None, None, None);
+    for param in params.split(',') {
+        let (name, value) = param
+            .trim()
+            .split_once('=')
+            .ok_or_else(|| Error::Invalid(format!("invalid parameter {}", param.trim())))?;
+        match name {
+            "Credential" => key_id = Some(value.to_string()),
+            "SignedHeaders" => {
+                signed_headers = Some(value.split(';').map(str::to_ascii_lowercase).collect())
+            }
+            "Signature" => {
+                signature = Some(ByteArray32::try_from(value).map_err(|e| {
+                    Error::Invalid(format!("Invalid signature, expect 64 hex digits: {}", e))
+                })?)
+            }
+            _ => {}
+        }
+    }
+    let missing = |name: &str| Error::Missing(format!("Missing {} parameter", name));
+    Ok(Credentials {
+        key_id: key_id.ok_or_else(|| missing("Credential"))?,
+        signed_headers: signed_headers.ok_or_else(|| missing("SignedHeaders"))?,
+        signature: signature.ok_or_else(|| missing("Signature"))?,
+    })
+}
+
+/// The canonical request covering `signed_headers`, sorted by the caller.
+fn canonical_request(
+    headers: &[(String, String)],
+    signed_headers: &[String],
+    content_sha256: &str,
+) -> Result<String, Error> {
+    let method = header_value(headers, ":method").unwrap_or_default();
+    let target = header_value(headers, ":path").unwrap_or_default();
+    let (path, query) = target.split_once('?').unwrap_or((&target, ""));
+    let mut lines = vec![
+        method.to_ascii_uppercase(),
+        path.to_string(),
+        sorted_query(query),
+    ];
+    for name in signed_headers {
+        let source = if name == "host" { ":authority" } else { name };
+        let value = header_value(headers, source)
+            .ok_or_else(|| Error::Missing(format!("Missing signed header {}", name)))?;
+        lines.push(format!("{}:{}", name, fold_whitespace(&value)));
+    }
+    lines.push(String::new());
+    lines.push(signed_headers.join(";"));
+    lines.push(content_sha256.to_string());
+    Ok(lines.join("\n"))
+}
+
+/// What the client signs for `canonical_request`.
+pub fn string_to_sign(timestamp: u64, nonce: &str, canonical_request: &str) -> String {
+    format!(
+        "{}\n{}\n{}\n{:x}",
+        ALGORITHM,
+        timestamp,
+        nonce,
+        Sha256::digest(canonical_request.as_bytes())
+    )
+}
+
+/// A request whose signature is verified, its nonce is not checked yet.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Verified<'a> {
+    /// The id of the key, the principal of the request.
+    pub key_id: &'a str,
+    pub nonce: String,
+}
+
+/// Key of `nonce` in the replay cache, nonces are per key.
+pub fn nonce_key(verified: &Verified) -> String {
+    format!("hmac:{}:{}", verified.key_id, verified.nonce)
+}
+
+pub fn verify<'a>(
+    setting: &'a HmacSetting,
+    headers: &[(String, String)],
+    now: u64,
+) -> Result<Verified<'a>, Error> {
+    let missing = |name: &str| Error::Missing(format!("Missing {} in header", name));
+    let authorization =
+        header_value(headers, HEADER_AUTHORIZATION).ok_or_else(|| missing(HEADER_AUTHORIZATION))?;
+    let mut credentials = parse_authorization(&authorization)?;
+    credentials.signed_headers.sort();
+    credentials.signed_headers.dedup();
+
+    let required = REQUIRED_HEADERS
+        .iter()
+        .copied()
+        .chain(setting.signed_headers.iter().map(String::as_str));
+    for name in required {
+        if !credentials
+            .signed_headers
+            .iter()
+            .any(|signed| signed.eq_ignore_ascii_case(name))
+        {
+            return Err(Error::Invalid(format!("Signature does not cover {}", name)));
+        }
+    }
+
+    let timestamp: u64 = header_value(headers, HEADER_DATE)
+        .ok_or_else(|| missing(HEADER_DATE))?
+        .parse()
+        .map_err(|_| Error::Invalid("Invalid timestamp".to_string()))?;
+    if timestamp.abs_diff(now) > setting.max_age {
+        return Err(Error::Expired(
+            "Request timestamp is out of the accepted window".to_string(),
+        ));
+    }
+    let nonce = header_value(headers, HEADER_NONCE).ok_or_else(|| missing(HEADER_NONCE))?;
+    let content_sha256 = header_value(headers, HEADER_CONTENT_SHA256)
+        .ok_or_else(|| missing(HEADER_CONTENT_SHA256))?;
+
+    let key = setting
+        .keys
+        .iter()
+        .find(|key| key.id == credentials.key_id)
+        .ok_or_else(|| Error::UnknownKey(format!("Unknown key {}", credentials.key_id)))?;
+    let canonical = canonical_request(headers, &credentials.signed_headers, &content_sha256)?;
+    let message = string_to_sign(timestamp, &nonce, &canonical);
+    hmac::verify(
+        &hmac::Key::new(hmac::HMAC_SHA256, key.secret.as_bytes()),
+        message.as_bytes(),
+        credentials.signature.as_bytes(),
+    )
+    .map_err(|_| Error::Invalid("Failed to verify signature".to_string()))?;
+    Ok(Verified {
+        key_id: &key.id,
+        nonce,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::HmacKey;
+
+    const EMPTY_SHA256: &str = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+
+    fn sign(secret: &str, headers: &[(String, String)], names: &[&str]) -> String {
+        let names: Vec<String> = names.iter().map(|n| n.to_string()).collect();
+        let canonical = canonical_request(headers, &names, EMPTY_SHA256).unwrap();
+        let message = string_to_sign(1700000000, "5f2b8c1e", &canonical);
+        let tag = hmac::sign(
+            &hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes()),
+            message.as_bytes(),
+        );
+        let signature = ByteArray32::from(&<[u8; 32]>::try_from(tag.as_ref()).unwrap());
+        format!(
+            "HMAC-SHA256 Credential=billing, SignedHeaders={}, Signature={:x}",
+            names.join(";"),
+            signature
+        )
+    }
+
+    #[test]
+    fn canonical() {
+        let headers: Vec<(String, String)> = [
+            (":method", "get"),
+            (":authority", "api.example.com"),
+            (":path", "/items?b=2&a=1"),
+            ("x-auth-date", "1700000000"),
+        ]
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+        let names = vec!["host".to_string(), "x-auth-date".to_string()];
+        assert_eq!(
+            canonical_request(&headers, &names, EMPTY_SHA256).unwrap(),
+            format!(
+                "GET\n/items\na=1&b=2\nhost:api.example.com\nx-auth-date:1700000000\n\nhost;x-auth-date\n{}",
+                EMPTY_SHA256
+            )
+        );
+    }
+
+    #[test]
+    fn verify_signature() {
+        let setting = HmacSetting {
+            keys: vec![HmacKey {
+                id: "billing".to_string(),
+                secret: "s3cr3t".to_string(),
+            }],
+            signed_headers: vec!["content-type".to_string()],
+            max_age: 300,
+        };
+        let mut headers: Vec<(String, String)> = [
+            (":method", "POST"),
+            (":authority", "api.example.com"),
+            (":path", "/invoices?draft=1"),
+            ("content-type", "application/json"),
+            ("x-auth-date", "1700000000"),
+            ("x-auth-nonce", "5f2b8c1e"),
+            ("x-auth-content-sha256", EMPTY_SHA256),
+        ]
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+        let all = [
+            "content-type",
+            "host",
+            "x-auth-content-sha256",
+            "x-auth-date",
+            "x-auth-nonce",
+        ];
+        headers.push(("authorization".to_string(), sign("s3cr3t", &headers, &all)));
+        let verified = verify(&setting, &headers, 1700000100).unwrap();
+        assert_eq!(verified.key_id, "billing");
+        assert_eq!(nonce_key(&verified), "hmac:billing:5f2b8c1e");
+
+        assert!(matches!(
+            verify(&setting, &headers, 1700000301),
+            Err(Error::Expired(_))
+        ));
+
+        let mut tampered = headers.clone();
+        tampered[2].1 = "/invoices?draft=0".to_string();
+        assert!(matches!(
+            verify(&setting, &tampered, 1700000100),
+            Err(Error::Invalid(_))
+        ));
+
+        let mut other_secret = headers.clone();
+        other_secret.pop();
+        let authorization = sign("guess", &other_secret, &all);
+        other_secret.push(("authorization".to_string(), authorization));
+        assert!(matches!(
+            verify(&setting, &other_secret, 1700000100),
+            Err(Error::Invalid(_))
+        ));
+
+        let mut uncovered = headers.clone();
+        uncovered.pop();
+        let authorization = sign("s3cr3t", &uncovered, &all[1..]);
+        uncovered.push(("authorization".to_string(), authorization));
+        assert_eq!(
+            verify(&setting, &uncovered, 1700000100),
+            Err(Error::Invalid(
+                "Signature does not cover content-type".to_string()
+            ))
+        );
+    }
+}
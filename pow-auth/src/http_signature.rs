@@ -0,0 +1,407 @@
+//! RFC 9421 HTTP Message Signatures.
+//!
+//! ```text
+//! Signature-Input: sig1=("@method" "@authority" "@path");created=1700000000;keyid="Alice"
+//! Signature: sig1=:<base64 of the 64 byte compact ECDSA signature>:
+//! ```
+//!
+//! The key is a secp256k1 grant, `keyid` is either the name of the grant or
+//! its hex encoded public key. The only accepted `alg` is
+//! `ecdsa-secp256k1-sha256`, the signature covers the SHA-256 digest of the
+//! signature base. Derived components supported are `@method`, `@authority`,
+//! `@scheme`, `@target-uri`, `@path` and `@query`, component parameters are
+//! not.
+//!
+//! `created` must be within `max_age` of now either way, and `expires`, when
+//! given, ends the validity before that. A signature is accepted once, see
+//! [`replay_key`].
+
+use std::collections::HashMap;
+
+use base64::Engine;
+use secp256k1::{ecdsa::Signature, Message};
+use sha2::{Digest, Sha256};
+
+use crate::auth_identity::PublicKey;
+use crate::canonical::header_value;
+use crate::config::{Grant, HttpSignatureSetting};
+use crate::revocation::Revocations;
+
+pub const HEADER_SIGNATURE_INPUT: &str = "Signature-Input";
+pub const HEADER_SIGNATURE: &str = "Signature";
+const ALGORITHM: &str = "ecdsa-secp256k1-sha256";
+
+#[derive(Debug, PartialEq, Eq, thiserror::Error)]
+pub enum Error {
+    #[error("{0}")]
+    Missing(String),
+    #[error("{0}")]
+    Invalid(String),
+    #[error("{0}")]
+    Expired(String),
+    #[error("{0}")]
+    UnknownKey(String),
+}
+
+/// One member of `Signature-Input`.
+#[derive(Debug, PartialEq, Eq)]
+struct SignatureInput {
+    label: String,
+    components: Vec<String>,
+    params: HashMap<String, String>,
+    /// The serialized inner list and parameters, `@signature-params` of the
+    /// signature base.
+    raw: String,
+}
+
+/// Split a structured field dictionary into its members, respecting quoted
+/// strings and inner lists.
+fn split_members(value: &str) -> Vec<&str> {
+    let mut members = vec![];
+    let (mut start, mut quoted, mut depth) = (0, false, 0);
+    let mut chars = value.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '\\' if quoted => {
+                chars.next();
+            }
+            '"' => quoted = !quoted,
+            '(' if !quoted => depth += 1,
+            ')' if !quoted => depth -= 1,
+            ',' if !quoted && depth == 0 => {
+                members.push(value[start..i].trim());
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    members.push(value[start..].trim());
+    members.retain(|m| !m.is_empty());
+    members
+}
+
+fn unquote(value: &str) -> Result<String, String> {
+    let inner = value
+        .strip_prefix('"')
+        .and_then(|v| v.strip_suffix('"'))
+        .ok_or_else(|| format!("expect a quoted string, got {}", value))?;
+    Ok(inner.replace("\\\"", "\"").replace("\\\\", "\\"))
+}
+
+fn parse_input(member: &str) -> Result<SignatureInput, String> {
+    let (label, raw) = member
+        .split_once('=')
+        .ok_or_else(|| format!("invalid member {}", member))?;
+    let list = raw
+        .strip_prefix('(')
+        .ok_or_else(|| format!("expect an inner list for {}", label))?;
+    let (list, params) = list
+        .split_once(')')
+        .ok_or_else(|| format!("unterminated inner list for {}", label))?;
+    let components = list
+        .split_whitespace()
+        .map(|c| {
+            if c.contains(';') {
+                return Err(format!("component parameters are not supported: {}", c));
+            }
+            unquote(c)
+        })
+        .collect::<Result<_, _>>()?;
+    let params = params
+        .split(';')
+        .filter(|p| !p.is_empty())
+        .map(|p| {
+            let (k, v) = p.split_once('=').unwrap_or((p, "?1"));
+            let v = if v.starts_with('"') {
+                unquote(v)?
+            } else {
+                v.to_string()
+            };
+            Ok((k.to_string(), v))
+        })
+        .collect::<Result<_, String>>()?;
+    Ok(SignatureInput {
+        label: label.trim().to_string(),
+        components,
+        params,
+        raw: raw.trim().to_string(),
+    })
+}
+
+fn component_value(headers: &[(String, String)], component: &str) -> Result<String, String> {
+    let target = header_value(headers, ":path").unwrap_or_default();
+    let (path, query) = target.split_once('?').unwrap_or((&target, ""));
+    let pseudo = |name: &str| {
+        header_value(headers, name).ok_or_else(|| format!("missing {} for {}", name, component))
+    };
+    match component {
+        "@method" => Ok(pseudo(":method")?.to_ascii_uppercase()),
+        "@authority" => Ok(pseudo(":authority")?.to_ascii_lowercase()),
+        "@scheme" => Ok(pseudo(":scheme")?.to_ascii_lowercase()),
+        "@target-uri" => Ok(format!(
+            "{}://{}{}",
+            pseudo(":scheme")?.to_ascii_lowercase(),
+            pseudo(":authority")?.to_ascii_lowercase(),
+            target
+        )),
+        "@path" => Ok(path.to_string()),
+        "@query" => Ok(format!("?{}", query)),
+        derived if derived.starts_with('@') => {
+            Err(format!("unsupported derived component {}", derived))
+        }
+        field => {
+            header_value(headers, field).ok_or_else(|| format!("Missing signed header {}", field))
+        }
+    }
+}
+
+fn signature_base(headers: &[(String, String)], input: &SignatureInput) -> Result<String, String> {
+    let mut base = String::new();
+    for component in &input.components {
+        let value = component_value(headers, component)?;
+        base.push_str(&format!(
+            "\"{}\": {}\n",
+            component.to_ascii_lowercase(),
+            value
+        ));
+    }
+    base.push_str(&format!("\"@signature-params\": {}", input.raw));
+    Ok(base)
+}
+
+fn find_signature(value: &str, label: &str) -> Result<Signature, String> {
+    let encoded = split_members(value)
+        .into_iter()
+        .filter_map(|m| m.split_once('='))
+        .find(|(l, _)| l.trim() == label)
+        .map(|(_, v)| v.trim())
+        .ok_or_else(|| format!("Missing {} in {}", label, HEADER_SIGNATURE))?;
+    let encoded = encoded
+        .strip_prefix(':')
+        .and_then(|v| v.strip_suffix(':'))
+        .ok_or_else(|| format!("Invalid signature {}, expect a byte sequence", label))?;
+    let bytes = base64::engine::general_purpose::STANDARD
+        .decode(encoded)
+        .map_err(|e| format!("Invalid signature {}: {}", label, e))?;
+    Signature::from_compact(&bytes).map_err(|e| format!("Invalid signature {}: {}", label, e))
+}
+
+/// A request whose signature is verified, its replay is not checked yet.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Verified<'a> {
+    /// The grant whose key made the signature.
+    pub grant: &'a Grant,
+    /// SHA-256 digest of the signature base.
+    pub digest: [u8; 32],
+}
+
+/// Key of a verified signature in the replay cache. The signature base
+/// covers the signature parameters, so it is unique per signed request,
+/// while the signature itself may be re-encoded.
+pub fn replay_key(verified: &Verified) -> String {
+    let hex: String = verified
+        .digest
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    format!("httpsig:{}", hex)
+}
+
+/// Verify the signature of `headers` and return the grant whose key made it.
+pub fn verify<'a>(
+    setting: &HttpSignatureSetting,
+    grants: &'a HashMap<PublicKey, Grant>,
+    revocations: &Revocations,
+    headers: &[(String, String)],
+    now: u64,
+) -> Result<Verified<'a>, Error> {
+    let missing = |name: &str| Error::Missing(format!("Missing {} in header", name));
+    let inputs = header_value(headers, HEADER_SIGNATURE_INPUT)
+        .ok_or_else(|| missing(HEADER_SIGNATURE_INPUT))?;
+    let input = split_members(&inputs)
+        .into_iter()
+        .map(parse_input)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(Error::Invalid)?
+        .into_iter()
+        .find(|input| match &setting.label {
+            Some(label) => *label == input.label,
+            None => true,
+        })
+        .ok_or_else(|| Error::Missing(format!("Missing signature {:?}", setting.label)))?;
+
+    if let Some(uncovered) = setting
+        .required_components
+        .iter()
+        .find(|c| !input.components.iter().any(|covered| covered == *c))
+    {
+        return Err(Error::Invalid(format!(
+            "Signature does not cover {}",
+            uncovered
+        )));
+    }
+    if let Some(alg) = input.params.get("alg") {
+        if alg != ALGORITHM {
+            return Err(Error::Invalid(format!(
+                "Unsupported alg {}, expect {}",
+                alg, ALGORITHM
+            )));
+        }
+    }
+    let param = |name: &str| -> Result<Option<u64>, Error> {
+        input
+            .params
+            .get(name)
+            .map(|v| v.parse())
+            .transpose()
+            .map_err(|_| Error::Invalid(format!("Invalid {} parameter", name)))
+    };
+    let created =
+        param("created")?.ok_or_else(|| Error::Missing("Missing created parameter".to_string()))?;
+    if created.abs_diff(now) > setting.max_age {
+        return Err(Error::Expired(
+            "Signature is too old or too far in the future".to_string(),
+        ));
+    }
+    if param("expires")?.is_some_and(|expires| expires < now) {
+        return Err(Error::Expired("Signature is expired".to_string()));
+    }
+
+    let keyid = input
+        .params
+        .get("keyid")
+        .ok_or_else(|| Error::Missing("Missing keyid parameter".to_string()))?;
+    let (public_key, grant) = grants
+        .iter()
+        .find(|(key, grant)| {
+            grant.name == *keyid || keyid.parse::<PublicKey>().ok().as_ref() == Some(*key)
+        })
+        .ok_or_else(|| Error::UnknownKey(format!("Key {} not found in grants", keyid)))?;
+    if revocations.contains(public_key) {
+        return Err(Error::UnknownKey(format!("Key {} was revoked", keyid)));
+    }
+    let PublicKey::Secp256k1(public_key) = public_key else {
+        return Err(Error::Invalid(format!(
+            "Key {} is not a secp256k1 key",
+            keyid
+        )));
+    };
+
+    let signatures =
+        header_value(headers, HEADER_SIGNATURE).ok_or_else(|| missing(HEADER_SIGNATURE))?;
+    let signature = find_signature(&signatures, &input.label).map_err(Error::Invalid)?;
+    let base = signature_base(headers, &input).map_err(Error::Invalid)?;
+    log::debug!("signature base: {:?}", base);
+    let digest: [u8; 32] = Sha256::digest(base.as_bytes()).into();
+    let message = Message::from_digest(digest);
+    secp256k1::Secp256k1::verification_only()
+        .verify_ecdsa(&message, &signature, public_key)
+        .map_err(|e| Error::Invalid(format!("Failed to verify signature: {}", e)))?;
+    Ok(Verified { grant, digest })
+}
+
+#[cfg(test)]
+mod test {
+    use hex_literal::hex;
+    use secp256k1::{Secp256k1, SecretKey};
+
+    use super::*;
+
+    #[test]
+    fn parse_signature_input() {
+        let members = split_members(
+            r#"sig1=("@method" "@path");created=1;keyid="a,b", sig2=("host");created=2"#,
+        );
+        assert_eq!(members.len(), 2);
+        let input = parse_input(members[0]).unwrap();
+        assert_eq!(input.label, "sig1");
+        assert_eq!(input.components, vec!["@method", "@path"]);
+        assert_eq!(input.params.get("keyid").map(String::as_str), Some("a,b"));
+        assert_eq!(input.raw, r#"("@method" "@path");created=1;keyid="a,b""#);
+        assert!(parse_input(r#"sig1=("content-type";sf)"#).is_err());
+    }
+
+    #[test]
+    fn verify_signature() {
+        let secp = Secp256k1::new();
+        let secret = SecretKey::from_slice(&hex!(
+            "3f880ce0892ac66019804c80292d4e90a38aa70a9dabad3f4314bf050f492afc"
+        ))
+        .unwrap();
+        let public_key =
+            PublicKey::Secp256k1(secp256k1::PublicKey::from_secret_key(&secp, &secret));
+        let grants = HashMap::from([(
+            public_key,
+            Grant {
+                name: "Alice".to_string(),
+                scope: Default::default(),
+            },
+        )]);
+        let revocations = Revocations::default();
+        let setting = HttpSignatureSetting {
+            label: None,
+            required_components: vec!["@method".to_string(), "@path".to_string()],
+            max_age: 60,
+        };
+
+        let params = r#"("@method" "@authority" "@path" "@query");created=1000;keyid="Alice""#;
+        let mut headers: Vec<(String, String)> = [
+            (":method", "GET"),
+            (":authority", "Example.com"),
+            (":path", "/ip?x=1"),
+            ("signature-input", &format!("sig1={}", params)),
+        ]
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+
+        let base = format!(
+            "\"@method\": GET\n\"@authority\": example.com\n\"@path\": /ip\n\"@query\": ?x=1\n\"@signature-params\": {}",
+            params
+        );
+        let message = Message::from_digest(Sha256::digest(base.as_bytes()).into());
+        let signature = secp.sign_ecdsa(&message, &secret).serialize_compact();
+        let encoded = base64::engine::general_purpose::STANDARD.encode(signature);
+        headers.push(("signature".to_string(), format!("sig1=:{}:", encoded)));
+
+        assert_eq!(
+            verify(&setting, &grants, &revocations, &headers, 1030).map(|v| v.grant.name.as_str()),
+            Ok("Alice")
+        );
+        let expired = Err(Error::Expired(
+            "Signature is too old or too far in the future".to_string(),
+        ));
+        assert_eq!(
+            verify(&setting, &grants, &revocations, &headers, 1100),
+            expired
+        );
+        // created far after now
+        assert_eq!(
+            verify(&setting, &grants, &revocations, &headers, 900),
+            expired
+        );
+        // the replay key does not depend on the encoding of the signature
+        let verified = verify(&setting, &grants, &revocations, &headers, 1030).unwrap();
+        assert_eq!(
+            replay_key(&verified),
+            format!("httpsig:{:x}", Sha256::digest(base.as_bytes()))
+        );
+
+        headers[2].1 = "/ip?x=2".to_string();
+        assert!(matches!(
+            verify(&setting, &grants, &revocations, &headers, 1030),
+            Err(Error::Invalid(e)) if e.starts_with("Failed to verify signature")
+        ));
+
+        let setting = HttpSignatureSetting {
+            required_components: vec!["content-digest".to_string()],
+            ..setting
+        };
+        assert_eq!(
+            verify(&setting, &grants, &revocations, &headers, 1030),
+            Err(Error::Invalid(
+                "Signature does not cover content-digest".to_string()
+            ))
+        );
+    }
+}
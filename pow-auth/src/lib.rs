@@ -1,201 +1,240 @@
 pub mod auth_identity;
+pub mod canonical;
 pub mod config;
-
-use std::{net::SocketAddr, sync::Arc};
-
-use auth_identity::{AuthFactors, AuthIdentity};
-use config::{Config, Setting};
-use pow_runtime::{response::Response, Ctx, HttpHook, Runtime, RuntimeBox};
-use pow_types::{cidr::CIDR, config::Router};
-use proxy_wasm::{
-    traits::{Context, RootContext},
-    types::LogLevel,
+pub mod hmac_signature;
+pub mod http_signature;
+pub mod jwt;
+pub mod mtls;
+pub mod remote_grants;
+pub mod revocation;
+
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use auth_identity::{decode_hex, replay_key, AuthIdentity, PublicKey};
+use config::{
+    Canonicalization, Config, Grant, HmacSetting, HttpSignatureSetting, JwtSetting, MtlsPrincipal,
+    MtlsSetting, RevocationSetting, RouteSetting, Scope, Setting,
+};
+use mtls::Peer;
+use remote_grants::{GrantsRef, RemoteGrants};
+use revocation::Revocations;
+use pow_runtime::{
+    error::IntoResponse,
+    failure_mode::FailureMode,
+    filter::{Filter, FilterRoot},
+    filter_state::State,
+    kv_store::{self, Capacity, KVStore},
+    metrics,
+    response::{ErrorFormat, Problem, Response},
+    Ctx, HttpHook,
 };
-use secp256k1::{ecdsa::Signature, PublicKey};
+use pow_types::{config::Router, ip_trie::IpTrie, route::normalize::PathNormalization};
+use proxy_wasm::types::LogLevel;
 
 const HEADER_PUBLIC_KEY_NAME: &str = "X-Auth-PublicKey";
 const HEADER_SIGNATURE_NAME: &str = "X-Auth-Signature";
 const HEADER_TIMESTAMP_NAME: &str = "X-Auth-Timestamp";
 
-proxy_wasm::main! {{
-    proxy_wasm::set_log_level(LogLevel::Trace);
-    proxy_wasm::set_root_context(move |context_id| -> Box<dyn RootContext> {
-        Box::new(RuntimeBox::new(Plugin { _context_id: context_id, inner: None }))
-    });
-}}
-
-#[derive(Debug)]
-#[allow(dead_code)]
-enum Error {
-    Status {
-        reason: String,
-        status: proxy_wasm::types::Status,
-    },
-    Response(Response),
-    Other {
-        reason: String,
-        error: Box<dyn std::error::Error>,
-    },
+#[cfg(feature = "plugin")]
+pow_runtime::filter!(Inner, LogLevel::Trace);
+
+type Error = pow_runtime::error::Error<Rejection>;
+
+/// The filter made from a configuration, see [`Filter`].
+pub struct Inner {
+    context_id: u32,
+    router: Router<RouteSetting>,
+    whitelist: IpTrie,
+    principal_header: Option<String>,
+    tier_header: Option<String>,
+    strip_credentials: bool,
+    failure_mode: FailureMode,
+    error_format: ErrorFormat,
+    problem_type_base: Option<String>,
+    canonicalization: Canonicalization,
+    http_signatures: Option<HttpSignatureSetting>,
+    max_skew: u64,
+    require_nonce: bool,
+    /// Nonces and signatures of accepted requests, shared by the workers.
+    /// The time each remembered signature expires at.
+    nonces: KVStore<u64>,
+    revocations: Revocations,
+    revocation: Option<RevocationSetting>,
+    path_normalization: PathNormalization,
 }
 
-#[allow(dead_code)]
-impl Error {
-    fn status(reason: &str, status: proxy_wasm::types::Status) -> Self {
-        Self::Status {
-            reason: reason.to_owned(),
-            status,
-        }
+/// The root context of the filter, configured by
+/// [`Runtime::on_configure`](pow_runtime::Runtime::on_configure).
+pub type Plugin = FilterRoot<Inner>;
+
+impl Filter for Inner {
+    const NAME: &'static str = "Auth filter";
+    type Config = Config<RouteSetting>;
+    type Hook = Hook;
+
+    fn log_level(config: &Self::Config) -> Option<LogLevel> {
+        Some(config.log_level.map(Into::into).unwrap_or(LogLevel::Trace))
     }
 
-    fn response(response: Response) -> Self {
-        Self::Response(response)
+    fn configure(context_id: u32, mut config: Self::Config) -> Result<Self, String> {
+        let whitelist: IpTrie = config
+            .whitelist
+            .take()
+            .unwrap_or_default()
+            .into_iter()
+            .collect();
+
+        let principal_header = config.principal_header.take();
+
+        let router: Router<RouteSetting> = config
+            .virtual_hosts
+            .try_into()
+            .map_err(|e| format!("failed to convert configuration: {}", e))?;
+
+        Ok(Inner {
+            context_id,
+            router,
+            whitelist,
+            principal_header,
+            tier_header: config.tier_header.take(),
+            strip_credentials: config.strip_credentials,
+            failure_mode: config.failure_mode,
+            error_format: config.error_format,
+            problem_type_base: config.problem_type_base.take(),
+            canonicalization: std::mem::take(&mut config.canonicalization),
+            http_signatures: config.http_signatures.take(),
+            max_skew: config.max_skew,
+            require_nonce: config.require_nonce,
+            nonces: KVStore::indexed(context_id, "auth:nonce").capped(Capacity {
+                max_entries: config.max_nonces,
+                evicted_metric: "pow_auth.nonce.evicted".to_string(),
+            }),
+            revocations: Revocations::default(),
+            revocation: config.revocation.take(),
+            path_normalization: std::mem::take(&mut config.path_normalization),
+        })
     }
 
-    fn other(reason: &str, error: Box<dyn std::error::Error>) -> Self {
-        Self::Other {
-            reason: reason.to_owned(),
-            error,
+    fn configured(context_id: u32, state: &State<Self>) {
+        if let Some(revocation) = state.revocation.clone() {
+            revocation::spawn(context_id, state.downgrade(), revocation);
         }
     }
-}
 
-impl From<Error> for Response {
-    fn from(val: Error) -> Self {
-        match val {
-            Error::Response(response) => {
-                log::debug!("reject request with response, {:?}", response.code);
-                response
-            }
-            Error::Status { reason, status } => {
-                let msg = format!("{:?}: {}", status, reason);
-                log::warn!("failed hostcall with error, {}", msg);
-                Response {
-                    code: 500,
-                    headers: vec![("Content-Type".to_string(), "text/plain".to_string())],
-                    body: Some(msg.into_bytes()),
-                    trailers: vec![],
-                }
-            }
-            Error::Other { reason, error } => {
-                let msg = format!("{}: {}", error, reason);
-                log::warn!("failed unknow error, {}", msg);
-                Response {
-                    code: 500,
-                    headers: vec![("Content-Type".to_string(), "text/plain".to_string())],
-                    body: Some(msg.into_bytes()),
-                    trailers: vec![],
-                }
-            }
-        }
+    fn hook(ctx: Ctx, plugin: State<Self>) -> Hook {
+        Hook { ctx, plugin }
     }
 }
 
-struct Inner {
-    router: Router<Setting>,
-    whitelist: Vec<CIDR>,
+#[derive(Debug, serde::Serialize)]
+pub struct UnauthorizedResponse {
+    error: String,
+    message: String,
 }
 
-#[derive(Clone)]
-struct Plugin {
-    _context_id: u32,
-    inner: Option<Arc<Inner>>,
+/// Reason code of a rejection, the last segment of its problem `type`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Reason {
+    MissingCredentials,
+    InvalidCredentials,
+    RequestExpired,
+    NotGranted,
+    InvalidRequest,
+    Replayed,
+    OutOfScope,
+    UnknownPeer,
 }
 
-impl Context for Plugin {}
-impl Runtime for Plugin {
-    type Hook = Hook;
-
-    fn on_vm_start(&mut self, _vm_configuration_size: usize) -> bool {
-        log::info!("Auth filter starting...");
-        true
+impl Reason {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Reason::MissingCredentials => "missing-credentials",
+            Reason::InvalidCredentials => "invalid-credentials",
+            Reason::RequestExpired => "request-expired",
+            Reason::NotGranted => "not-granted",
+            Reason::InvalidRequest => "invalid-request",
+            Reason::Replayed => "request-replayed",
+            Reason::OutOfScope => "out-of-scope",
+            Reason::UnknownPeer => "unknown-peer",
+        }
     }
 
-    fn on_configure(&mut self, _configuration: Option<Vec<u8>>) -> bool {
-        log::info!("Auth filter configuring...");
-        let Some(config_bytes) = _configuration else {
-            log::error!("missing configuration");
-            return false;
-        };
-
-        let mut config: Config<Setting> = match serde_yaml::from_slice(&config_bytes) {
-            Ok(config) => config,
-            Err(e) => {
-                log::error!(
-                    "failed to parse configuration, {}\nraw config: {}",
-                    e,
-                    String::from_utf8(config_bytes)
-                        .expect("failed to convert raw config to string")
-                );
-                return false;
-            }
-        };
-
-        proxy_wasm::set_log_level(config.log_level.map(Into::into).unwrap_or(LogLevel::Trace));
+    fn title(&self) -> &'static str {
+        match self {
+            Reason::MissingCredentials => "Missing credentials",
+            Reason::InvalidCredentials => "Invalid credentials",
+            Reason::RequestExpired => "Request expired",
+            Reason::NotGranted => "Public key not granted",
+            Reason::InvalidRequest => "Invalid request",
+            Reason::Replayed => "Request replayed",
+            Reason::OutOfScope => "Outside the scope of the key",
+            Reason::UnknownPeer => "Peer certificate not granted",
+        }
+    }
+}
 
-        let whitelist = config.whitelist.take().unwrap_or_default();
+#[derive(Debug)]
+struct Rejection {
+    status: u32,
+    reason: Reason,
+    detail: String,
+}
 
-        let router: Router<Setting> = match config.virtual_hosts.try_into() {
-            Ok(router) => router,
-            Err(e) => {
-                log::error!(
-                    "failed to convert configuration: {}\n raw config: {}",
-                    e,
-                    String::from_utf8(config_bytes)
-                        .expect("failed to read raw config into utf8 string")
-                );
-                return false;
-            }
+/// The built-in JSON response.
+impl IntoResponse for Rejection {
+    fn into_response(self) -> Response {
+        if self.status == 403 {
+            let body = serde_json::json!({ "message": self.detail });
+            return Response::builder()
+                .status(403)
+                .body("text/json", body.to_string())
+                .build();
+        }
+        let body = UnauthorizedResponse {
+            error: self.detail,
+            message: "Lacks valid authentication credentials for the requested resource"
+                .to_string(),
         };
-
-        self.inner = Some(Arc::new(Inner { router, whitelist }));
-        log::info!("Auth filter configured...");
-        true
-    }
-
-    fn create_http_context(&self, _context_id: u32) -> Option<Self::Hook> {
-        Some(Hook {
-            ctx: Ctx::new(_context_id),
-            plugin: self.inner.clone().expect("plugin not configured"),
-        })
+        Response::builder().status(self.status).json(&body).build()
     }
 }
 
-#[derive(Debug, serde::Serialize)]
-pub struct UnauthorizedResponse {
-    error: String,
-    message: String,
+impl Rejection {
+    fn render(self, format: ErrorFormat, problem_type_base: Option<&str>) -> Response {
+        match format {
+            ErrorFormat::Json => self.into_response(),
+            ErrorFormat::ProblemJson => Problem::new(
+                problem_type_base,
+                self.reason.as_str(),
+                self.status,
+                self.reason.title(),
+                self.detail,
+            )
+            .into(),
+        }
+    }
 }
 
-fn unauthorized(error: &str) -> Error {
-    let body = UnauthorizedResponse {
-        error: error.to_owned(),
-        message: "Lacks valid authentication credentials for the requested resource".to_string(),
-    };
-    Error::response(Response {
-        code: 429,
-        headers: vec![("Content-Type".to_string(), "application/json".to_string())],
-        body: Some(
-            serde_json::to_string(&body)
-                .expect("failed to serialize response")
-                .into_bytes(),
-        ),
-        trailers: vec![],
+fn unauthorized(reason: Reason, error: &str) -> Error {
+    Error::Rejected(Rejection {
+        status: 429,
+        reason,
+        detail: error.to_owned(),
     })
 }
 
 fn forbidden(message: &str) -> Error {
-    let body = serde_json::json!({ "message": message });
-    Error::response(Response {
-        code: 403,
-        headers: vec![("Content-Type".to_string(), "text/json".to_string())],
-        body: Some(body.to_string().into_bytes()),
-        trailers: vec![],
+    Error::Rejected(Rejection {
+        status: 403,
+        reason: Reason::InvalidRequest,
+        detail: message.to_owned(),
     })
 }
 
 pub struct Hook {
     ctx: Ctx,
-    plugin: Arc<Inner>,
+    plugin: State<Inner>,
 }
 
 impl Hook {
@@ -203,109 +242,445 @@ impl Hook {
         self.ctx
             .get_client_address()
             .map_err(|s| Error::status("failed to get client address", s))?
-            .ok_or_else(|| forbidden("failed to get client address from request"))
+            .ok_or_else(|| {
+                Error::status(
+                    "missing client address",
+                    proxy_wasm::types::Status::NotFound,
+                )
+            })
     }
 
     fn get_header(&self, key: &str) -> Result<String, Error> {
         self.ctx
             .get_http_request_header(key)
-            .map_err(|s| Error::status(&format!("failed to get header: {}", key), s))?
+            .map_err(|s| Error::status(format!("failed to get header: {}", key), s))?
             .ok_or_else(|| forbidden(&format!("missing header: {}", key)))
     }
 
+    fn set_principal(&self, principal: Option<&str>) -> Result<(), Error> {
+        let Some(header) = &self.plugin.principal_header else {
+            return Ok(());
+        };
+        self.ctx
+            .set_http_request_header(header, principal)
+            .map_err(|s| Error::status("failed to set principal header", s))
+    }
+
+    fn set_tier(&self, tier: Option<&str>) -> Result<(), Error> {
+        let Some(header) = &self.plugin.tier_header else {
+            return Ok(());
+        };
+        self.ctx
+            .set_http_request_header(header, tier)
+            .map_err(|s| Error::status("failed to set tier header", s))
+    }
+
+    /// Rejects the request if outside the `scope` of the principal `name`.
+    /// Rejects requests to `route_path` outside `scope`, it is the normalized
+    /// path the routes are matched on, like the upstream serves it.
+    fn check_scope(
+        &self,
+        name: &str,
+        scope: &Scope,
+        headers: &[(String, String)],
+        route_path: &str,
+    ) -> Result<(), Error> {
+        let method = canonical::header_value(headers, ":method").unwrap_or_default();
+        let path = route_path.split_once('?').map_or(route_path, |(path, _)| path);
+        if !scope.allows(&method, path) {
+            return Err(Error::Rejected(Rejection {
+                status: 403,
+                reason: Reason::OutOfScope,
+                detail: format!("{} {} is outside the scope of {}", method, path, name),
+            }));
+        }
+        Ok(())
+    }
+
+    /// Called once the request is verified to be signed by `grant`'s key,
+    /// rejects it if outside the scope of the grant.
+    fn accept_grant(
+        &self,
+        grant: &Grant,
+        headers: &[(String, String)],
+        route_path: &str,
+    ) -> Result<(), Error> {
+        self.check_scope(&grant.name, &grant.scope, headers, route_path)?;
+        self.set_tier(grant.scope.tier.as_deref())?;
+        self.accept(&grant.name)
+    }
+
+    /// The principal of the peer certificate, rejects peers without a
+    /// certificate or not in `setting`.
+    fn verify_peer<'a>(&self, setting: &'a MtlsSetting) -> Result<&'a MtlsPrincipal, Error> {
+        let peer = Peer::read(&self.ctx)
+            .map_err(|s| Error::status("failed to read peer certificate", s))?
+            .ok_or_else(|| unauthorized(Reason::MissingCredentials, "Missing client certificate"))?;
+        let principal = setting.principal(&peer).ok_or_else(|| {
+            unauthorized(
+                Reason::UnknownPeer,
+                &format!("Peer {} not granted", peer.describe()),
+            )
+        })?;
+        log::debug!("verified peer {} as {}", peer.describe(), principal.name);
+        Ok(principal)
+    }
+
+    /// Called once the request is verified.
+    fn accept(&self, trusted_name: &str) -> Result<(), Error> {
+        if self.plugin.strip_credentials {
+            for header in [
+                HEADER_PUBLIC_KEY_NAME,
+                HEADER_SIGNATURE_NAME,
+                HEADER_TIMESTAMP_NAME,
+                auth_identity::HEADER_VERSION,
+                hmac_signature::HEADER_NONCE,
+                http_signature::HEADER_SIGNATURE_INPUT,
+                http_signature::HEADER_SIGNATURE,
+            ] {
+                self.ctx
+                    .remove_http_request_header(header)
+                    .map_err(|s| Error::status("failed to strip credentials", s))?;
+            }
+        }
+        self.set_principal(Some(trusted_name))
+    }
+
     fn get_path(&self) -> Result<String, Error> {
         self.ctx
             .get_http_request_path()
             .map_err(|s| Error::status("failed to get path", s))
     }
-}
 
-fn now() -> u64 {
-    std::time::SystemTime::now()
-        .duration_since(std::time::UNIX_EPOCH)
-        .expect("failed to get timestamp")
-        .as_secs()
+    fn get_authority(&self) -> Result<String, Error> {
+        let authority = self
+            .ctx
+            .authority()
+            .map_err(|s| Error::status("failed to get authority", s))?
+            .ok_or_else(|| forbidden("missing, malformed or conflicting authority"))?;
+        Ok(authority.to_string())
+    }
 }
 
-impl HttpHook for Hook {
-    fn filter_name() -> Option<&'static str> {
-        Some("auth")
-    }
+pub(crate) fn now() -> u64 {
+    pow_runtime::clock::unix_secs()
+}
 
-    async fn on_request_headers(
-        &self,
-        _num_headers: usize,
-        _end_of_stream: bool,
-    ) -> Result<(), impl Into<Response>> {
+impl Hook {
+    /// Decide on the request. Once a route is matched, its failure mode
+    /// replaces `failure_mode`.
+    async fn inspect(&self, failure_mode: &mut FailureMode) -> Result<(), Error> {
+        self.set_principal(None)?;
+        self.set_tier(None)?;
         let addr = self.get_client_addr()?;
         let addr: SocketAddr = addr
             .parse()
             .map_err(|s| forbidden(&format!("invalid client address {}: {}", s, addr)))?;
-        if self
-            .plugin
-            .whitelist
-            .iter()
-            .any(|cidr| cidr.contains(addr.ip()))
-        {
+        if self.plugin.whitelist.contains(addr.ip()) {
             return Ok(());
         }
 
-        let host = self.get_header(":authority")?;
+        let host = self.get_authority()?;
         let path = self.get_path()?;
 
         log::debug!("{} -> {}{}", addr, host, path);
 
-        let Some(found) = self.plugin.router.matches(&host, &path) else {
+        // signatures cover the path as sent, routes see it normalized
+        let route_path = self
+            .plugin
+            .path_normalization
+            .normalize(&path)
+            .map_err(|e| forbidden(&format!("{}: {}", e, path)))?;
+        let Some(found) = self.plugin.router.matches(&host, &route_path) else {
             log::debug!("no matched route found, skip auth check");
             return Ok(());
         };
+        if let Some(mode) = found.failure_mode {
+            *failure_mode = mode;
+        }
 
+        let headers = self
+            .ctx
+            .get_http_request_headers()
+            .map_err(|s| Error::status("failed to get headers", s))?;
+        if let Some(peers) = &found.peers {
+            let principal = self.verify_peer(peers)?;
+            self.check_scope(&principal.name, &principal.scope, &headers, &route_path)?;
+            // the credentials of the access, if any, name the principal
+            self.set_principal(Some(&principal.name))?;
+            self.set_tier(principal.scope.tier.as_deref())?;
+        }
+        if let Setting::Mtls(ref setting) = found.access {
+            let principal = self.verify_peer(setting)?;
+            self.check_scope(&principal.name, &principal.scope, &headers, &route_path)?;
+            self.set_tier(principal.scope.tier.as_deref())?;
+            return self.accept(&principal.name);
+        }
+        if let Setting::Jwt(ref setting) = found.access {
+            return self.verify_jwt(setting, &headers).await;
+        }
+        if let Setting::Hmac(ref setting) = found.access {
+            return self.verify_hmac(setting, &headers);
+        }
+        if let Some(setting) = &self.plugin.http_signatures {
+            if canonical::header_value(&headers, http_signature::HEADER_SIGNATURE_INPUT).is_some() {
+                let Some(grants) = self.grants(&found.access).await? else {
+                    return Ok(());
+                };
+                let verified = http_signature::verify(
+                    setting,
+                    &grants,
+                    &self.plugin.revocations,
+                    &headers,
+                    now(),
+                )
+                    .map_err(|e| {
+                        let reason = match e {
+                            http_signature::Error::Missing(_) => Reason::MissingCredentials,
+                            http_signature::Error::Invalid(_) => Reason::InvalidCredentials,
+                            http_signature::Error::Expired(_) => Reason::RequestExpired,
+                            http_signature::Error::UnknownKey(_) => Reason::NotGranted,
+                        };
+                        unauthorized(reason, &e.to_string())
+                    })?;
+                // a replay within the accepted window of `created` is caught
+                let ttl = Duration::from_secs(setting.max_age * 2);
+                if !self.first_use(&http_signature::replay_key(&verified), ttl)? {
+                    return Err(unauthorized(Reason::Replayed, "Signature was already used"));
+                }
+                log::debug!("verified http message signature of {}", verified.grant.name);
+                return self.accept_grant(verified.grant, &headers, &route_path);
+            }
+        }
 
-        let timestamp = self
-            .get_header(HEADER_TIMESTAMP_NAME)
-            .map_err(|_| unauthorized(&format!("Missing {} in header", HEADER_TIMESTAMP_NAME)))?;
+        let timestamp = self.get_header(HEADER_TIMESTAMP_NAME).map_err(|_| {
+            unauthorized(
+                Reason::MissingCredentials,
+                &format!("Missing {} in header", HEADER_TIMESTAMP_NAME),
+            )
+        })?;
 
         let timestamp = timestamp
             .parse::<u64>()
-            .map_err(|_| unauthorized("Invalid timestamp"))?;
+            .map_err(|_| unauthorized(Reason::InvalidCredentials, "Invalid timestamp"))?;
 
-        if timestamp + 60 < now() {
-            return Err(unauthorized("Request timestamp is too old"));
+        if timestamp.abs_diff(now()) > self.plugin.max_skew {
+            return Err(unauthorized(
+                Reason::RequestExpired,
+                "Request timestamp is too old or too far in the future",
+            ));
+        }
+        if self.plugin.require_nonce
+            && canonical::header_value(&headers, hmac_signature::HEADER_NONCE).is_none()
+        {
+            return Err(unauthorized(
+                Reason::MissingCredentials,
+                &format!("Missing {} in header", hmac_signature::HEADER_NONCE),
+            ));
         }
 
         let public_key: PublicKey = self
             .get_header(HEADER_PUBLIC_KEY_NAME)
-            .map_err(|_| unauthorized(&format!("Missing {} in header", HEADER_PUBLIC_KEY_NAME)))?
+            .map_err(|_| {
+                unauthorized(
+                    Reason::MissingCredentials,
+                    &format!("Missing {} in header", HEADER_PUBLIC_KEY_NAME),
+                )
+            })?
             .parse()
-            .map_err(|e| unauthorized(&format!("Invalid public key: {}", e)))?;
+            .map_err(|e| {
+                unauthorized(
+                    Reason::InvalidCredentials,
+                    &format!("Invalid public key: {}", e),
+                )
+            })?;
 
-        let Setting::Grants(ref grants) = *found else {
+        let Some(grants) = self.grants(&found.access).await? else {
             return Ok(());
         };
 
-        match grants.get(&public_key) {
-            Some(trusted_name) => {
-                log::debug!("found public key in grants: {}, continue...", trusted_name);
-            }
-            None => return Err(unauthorized("Public key not found in grants")),
+        let Some(grant) = grants.get(&public_key) else {
+            return Err(unauthorized(
+                Reason::NotGranted,
+                "Public key not found in grants",
+            ));
+        };
+        if self.plugin.revocations.contains(&public_key) {
+            return Err(unauthorized(Reason::NotGranted, "Public key was revoked"));
         }
+        log::debug!("found public key in grants: {}, continue...", grant.name);
 
-        let signature: Signature = self
+        let signature = self
             .get_header(HEADER_SIGNATURE_NAME)
-            .map_err(|_| unauthorized(&format!("Missing {} in header", HEADER_SIGNATURE_NAME)))?
-            .parse()
-            .map_err(|e| {
-                unauthorized(&format!(
-                    "Invalid signature, expect a DER format string: {}",
-                    e
-                ))
+            .map_err(|_| {
+                unauthorized(
+                    Reason::MissingCredentials,
+                    &format!("Missing {} in header", HEADER_SIGNATURE_NAME),
+                )
             })?;
+        let signature = decode_hex(&signature).map_err(|e| {
+            unauthorized(
+                Reason::InvalidCredentials,
+                &format!("Invalid signature, expect a hex string: {}", e),
+            )
+        })?;
+
+        let message = self
+            .plugin
+            .canonicalization
+            .message(&headers, &path, timestamp)
+            .map_err(|e| unauthorized(Reason::MissingCredentials, &e))?;
+        let replay = replay_key(&public_key, &message);
+        let auth_identity = AuthIdentity::new(public_key.verifier(), message, &signature);
+        auth_identity.verify().map_err(|e| {
+            unauthorized(
+                Reason::InvalidCredentials,
+                &format!("Failed to verify signature: {}", e),
+            )
+        })?;
+        // the timestamp is accepted for max_skew on either side of it
+        let ttl = Duration::from_secs(self.plugin.max_skew * 2);
+        if !self.first_use(&replay, ttl)? {
+            return Err(unauthorized(
+                Reason::Replayed,
+                "Signature was already used",
+            ));
+        }
+        self.accept_grant(grant, &headers, &route_path)
+    }
+
+    /// The grants of a route, `None` if the route admits requests otherwise.
+    async fn grants<'a>(&self, access: &'a Setting) -> Result<Option<GrantsRef<'a>>, Error> {
+        match access {
+            Setting::Grants(grants) => Ok(Some(GrantsRef::Static(grants))),
+            Setting::Remote(setting) => RemoteGrants::new(self.plugin.context_id, setting)
+                .get()
+                .await
+                .map(|grants| Some(GrantsRef::Fetched(grants)))
+                .map_err(|e| Error::other("failed to fetch grants", e)),
+            Setting::Jwt(_) | Setting::Hmac(_) | Setting::Mtls(_) | Setting::Public => Ok(None),
+        }
+    }
+
+    /// Remember `key` for `ttl`, whether it was not remembered yet. An
+    /// expired key counts as absent and is overwritten, the cap of the store
+    /// evicts the ones never seen again.
+    fn first_use(&self, key: &str, ttl: Duration) -> Result<bool, Error> {
+        let now = now();
+        let mut seen_before = false;
+        self.plugin
+            .nonces
+            .update(key, |expires_at| {
+                let expires_at = expires_at.filter(|&at| at > now);
+                seen_before = expires_at.is_some();
+                Ok::<_, kv_store::Error>(
+                    expires_at.unwrap_or_else(|| now.saturating_add(ttl.as_secs())),
+                )
+            })
+            .map_err(|e| Error::other("failed to record nonce", e))?;
+        Ok(!seen_before)
+    }
+
+    async fn verify_jwt(&self, setting: &JwtSetting, headers: &[(String, String)]) -> Result<(), Error> {
+        let to_rejection = |e: jwt::Error| {
+            let reason = match e {
+                jwt::Error::Missing(_) => Reason::MissingCredentials,
+                jwt::Error::Invalid(_) => Reason::InvalidCredentials,
+                jwt::Error::Expired(_) => Reason::RequestExpired,
+                jwt::Error::UnknownKey(_) => Reason::NotGranted,
+            };
+            unauthorized(reason, &e.to_string())
+        };
+        let bearer = jwt::bearer(headers).map_err(to_rejection)?;
+        let token = jwt::parse(&bearer).map_err(to_rejection)?;
+        let now = now();
+        let keys = jwt::keys(setting, now, false)
+            .await
+            .map_err(|e| Error::other("failed to fetch JWK Set", e))?;
+        let subject = match jwt::verify(setting, &keys, &token, now) {
+            // the issuer may have rotated its keys since they were fetched
+            Err(jwt::Error::UnknownKey(_)) if setting.jwks.is_some() => {
+                let keys = jwt::keys(setting, now, true)
+                    .await
+                    .map_err(|e| Error::other("failed to fetch JWK Set", e))?;
+                jwt::verify(setting, &keys, &token, now)
+            }
+            verified => verified,
+        }
+        .map_err(to_rejection)?;
+        log::debug!("verified bearer token of {}", subject);
+        if self.plugin.strip_credentials {
+            self.ctx
+                .remove_http_request_header(jwt::HEADER_AUTHORIZATION)
+                .map_err(|s| Error::status("failed to strip credentials", s))?;
+        }
+        self.accept(&subject)
+    }
 
-        let factors = AuthFactors::new(&path, timestamp);
-        let auth_identity = AuthIdentity::new(&public_key, factors, &signature);
-        auth_identity
-            .verify()
-            .map_err(|e| unauthorized(&format!("Failed to verify signature: {}", e)))
+    fn verify_hmac(&self, setting: &HmacSetting, headers: &[(String, String)]) -> Result<(), Error> {
+        let verified = hmac_signature::verify(setting, headers, now()).map_err(|e| {
+            let reason = match e {
+                hmac_signature::Error::Missing(_) => Reason::MissingCredentials,
+                hmac_signature::Error::Invalid(_) => Reason::InvalidCredentials,
+                hmac_signature::Error::Expired(_) => Reason::RequestExpired,
+                hmac_signature::Error::UnknownKey(_) => Reason::NotGranted,
+            };
+            unauthorized(reason, &e.to_string())
+        })?;
+
+        // a replay within the accepted window of the timestamp is caught
+        let ttl = Duration::from_secs(setting.max_age * 2);
+        if !self.first_use(&hmac_signature::nonce_key(&verified), ttl)? {
+            return Err(unauthorized(Reason::Replayed, "Nonce was already used"));
+        }
+
+        log::debug!("verified hmac signature of {}", verified.key_id);
+        if self.plugin.strip_credentials {
+            for header in [
+                jwt::HEADER_AUTHORIZATION,
+                hmac_signature::HEADER_DATE,
+                hmac_signature::HEADER_NONCE,
+            ] {
+                self.ctx
+                    .remove_http_request_header(header)
+                    .map_err(|s| Error::status("failed to strip credentials", s))?;
+            }
+        }
+        self.accept(verified.key_id)
+    }
+}
+
+impl HttpHook for Hook {
+    fn filter_name() -> Option<&'static str> {
+        Some("auth")
+    }
+
+    async fn on_request_headers(
+        &self,
+        _num_headers: usize,
+        _end_of_stream: bool,
+    ) -> Result<(), impl Into<Response>> {
+        let mut failure_mode = self.plugin.failure_mode;
+        match self.inspect(&mut failure_mode).await {
+            Err(e) if e.is_infrastructure() => {
+                metrics::increment_counter(
+                    &format!("pow_auth.infra_failure.{}", failure_mode.as_str()),
+                    1,
+                );
+                match failure_mode {
+                    FailureMode::FailOpen => {
+                        log::warn!("let request through on infrastructure error: {:?}", e);
+                        Ok(())
+                    }
+                    FailureMode::FailClosed => Err(e),
+                }
+            }
+            Err(Error::Rejected(rejection)) => Err(Error::response(rejection.render(
+                self.plugin.error_format,
+                self.plugin.problem_type_base.as_deref(),
+            ))),
+            ret => ret,
+        }
     }
 }
 
@@ -0,0 +1,201 @@
+//! Workload identities of mutual TLS peers.
+//!
+//! The host terminates TLS, the filter reads the verified certificate of
+//! the downstream peer from the connection properties of the proxy-wasm
+//! attribute specification:
+//!
+//! - `connection.uri_san_peer_certificate`, e.g. a SPIFFE ID
+//!   `spiffe://cluster.local/ns/default/sa/billing`
+//! - `connection.dns_san_peer_certificate`
+//! - `connection.subject_peer_certificate`
+//!
+//! Envoy reports the first SAN of each type only. A peer without any of
+//! them did not present a certificate.
+
+use pow_runtime::{property, Ctx};
+use proxy_wasm::types::Status;
+
+use crate::config::{wildcard_match, MtlsPrincipal, MtlsSetting, PeerId};
+
+const SPIFFE_SCHEME: &str = "spiffe://";
+
+/// Whether `value` matches `pattern` part by part, split at `separator`,
+/// so a `*` does not span more parts than it stands in.
+fn parts_match(pattern: &str, value: &str, separator: char) -> bool {
+    let mut patterns = pattern.split(separator);
+    let mut values = value.split(separator);
+    loop {
+        match (patterns.next(), values.next()) {
+            (Some(pattern), Some(value)) if wildcard_match(pattern, value) => {}
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
+
+/// The identities of the certificate of a peer.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Peer {
+    pub uri_san: Option<String>,
+    pub dns_san: Option<String>,
+    pub subject: Option<String>,
+}
+
+impl Peer {
+    /// The peer of the request of `ctx`, `None` without a certificate.
+    pub fn read(ctx: &Ctx) -> Result<Option<Self>, Status> {
+        let read = |path: &[&str]| -> Result<Option<String>, Status> {
+            let value = ctx.property(path)?.string();
+            Ok(value.filter(|v| !v.is_empty()))
+        };
+        let peer = Peer {
+            uri_san: read(property::URI_SAN_PEER_CERTIFICATE)?,
+            dns_san: read(property::DNS_SAN_PEER_CERTIFICATE)?,
+            subject: read(property::SUBJECT_PEER_CERTIFICATE)?,
+        };
+        if peer == Peer::default() {
+            return Ok(None);
+        }
+        Ok(Some(peer))
+    }
+
+    /// The SPIFFE ID of the peer, its URI SAN in the `spiffe` scheme.
+    pub fn spiffe_id(&self) -> Option<&str> {
+        self.uri_san
+            .as_deref()
+            .filter(|uri| uri.starts_with(SPIFFE_SCHEME))
+    }
+
+    /// The identity of the peer used in logs.
+    pub fn describe(&self) -> &str {
+        self.uri_san
+            .as_deref()
+            .or(self.dns_san.as_deref())
+            .or(self.subject.as_deref())
+            .unwrap_or_default()
+    }
+
+    fn matches(&self, id: &PeerId) -> bool {
+        match id {
+            PeerId::Uri(pattern) => self
+                .uri_san
+                .as_deref()
+                .is_some_and(|uri| parts_match(pattern, uri, '/')),
+            PeerId::Spiffe(pattern) => self
+                .spiffe_id()
+                .is_some_and(|id| parts_match(pattern, id, '/')),
+            PeerId::Dns(pattern) => self
+                .dns_san
+                .as_deref()
+                .is_some_and(|name| parts_match(pattern, name, '.')),
+            PeerId::Subject(pattern) => self
+                .subject
+                .as_deref()
+                .is_some_and(|subject| wildcard_match(pattern, subject)),
+        }
+    }
+}
+
+impl MtlsSetting {
+    /// The first principal `peer` is, if any.
+    pub fn principal(&self, peer: &Peer) -> Option<&MtlsPrincipal> {
+        self.principals
+            .iter()
+            .find(|principal| peer.matches(&principal.peer))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn match_principal() {
+        let setting: MtlsSetting = serde_yaml::from_str(
+            r#"
+principals:
+- name: billing
+  spiffe: spiffe://cluster.local/ns/*/sa/billing
+  scope:
+    methods: [GET]
+- name: legacy
+  dns: "*.legacy.svc.cluster.local"
+- name: ops
+  subject: CN=ops,O=example
+"#,
+        )
+        .expect("failed to parse mtls setting");
+
+        let billing = Peer {
+            uri_san: Some("spiffe://cluster.local/ns/prod/sa/billing".to_string()),
+            ..Peer::default()
+        };
+        let principal = setting.principal(&billing).expect("billing not matched");
+        assert_eq!(principal.name, "billing");
+        assert!(!principal.scope.allows("POST", "/"));
+
+        let other = Peer {
+            uri_san: Some("https://cluster.local/ns/prod/sa/billing".to_string()),
+            dns_san: Some("api.legacy.svc.cluster.local".to_string()),
+            ..Peer::default()
+        };
+        assert_eq!(other.spiffe_id(), None);
+        assert_eq!(setting.principal(&other).unwrap().name, "legacy");
+
+        let ops = Peer {
+            subject: Some("CN=ops,O=example".to_string()),
+            ..Peer::default()
+        };
+        assert_eq!(setting.principal(&ops).unwrap().name, "ops");
+        assert_eq!(ops.describe(), "CN=ops,O=example");
+
+        let stranger = Peer {
+            subject: Some("CN=stranger".to_string()),
+            ..Peer::default()
+        };
+        assert_eq!(setting.principal(&stranger), None);
+    }
+
+    #[test]
+    fn wildcards_stay_within_a_part() {
+        let setting: MtlsSetting = serde_yaml::from_str(
+            r#"
+principals:
+- name: billing
+  spiffe: spiffe://cluster.local/ns/*/sa/billing
+- name: legacy
+  dns: "*.legacy.svc.cluster.local"
+"#,
+        )
+        .expect("failed to parse mtls setting");
+        let spiffe = |id: &str| Peer {
+            uri_san: Some(id.to_string()),
+            ..Peer::default()
+        };
+        let dns = |name: &str| Peer {
+            dns_san: Some(name.to_string()),
+            ..Peer::default()
+        };
+
+        assert!(setting
+            .principal(&dns("api.legacy.svc.cluster.local"))
+            .is_some());
+        assert_eq!(
+            setting.principal(&dns("a.b.evil.legacy.svc.cluster.local")),
+            None
+        );
+        assert_eq!(setting.principal(&dns("legacy.svc.cluster.local")), None);
+
+        assert!(setting
+            .principal(&spiffe("spiffe://cluster.local/ns/prod/sa/billing"))
+            .is_some());
+        assert_eq!(
+            setting.principal(&spiffe("spiffe://cluster.local/ns/prod/sa/x/sa/billing")),
+            None
+        );
+        assert_eq!(
+            setting.principal(&spiffe("spiffe://cluster.local/ns/a/b/sa/billing")),
+            None
+        );
+    }
+}
@@ -0,0 +1,223 @@
+//! Grants fetched from an identity service.
+//!
+//! A route with `remote` access gets its grants from `GET path` of the
+//! upstream, answered with the list of [`Token`]s as JSON, the same as the
+//! `grants` of a route. The list is shared between the workers in shared
+//! data and each worker keeps the decoded grants until they are `ttl`
+//! seconds old. Older grants are still used for `stale_for` seconds while
+//! one worker fetches them again in the background; only without grants
+//! or with grants older than that a request waits for the fetch.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::ops::Deref;
+use std::rc::Rc;
+
+use pow_runtime::http::{self, Client};
+use pow_runtime::kv_store::{Error, KVStore};
+use pow_runtime::spawn_local;
+use serde::{Deserialize, Serialize};
+
+use crate::auth_identity::PublicKey;
+use crate::config::{Grant, RemoteGrantsSetting, Token};
+use crate::now;
+
+/// Seconds between two background fetches of the same grants by any worker.
+const REFRESH_BACKOFF: u64 = 10;
+
+type Grants = HashMap<PublicKey, Grant>;
+
+/// The grants of a route, configured or fetched.
+pub enum GrantsRef<'a> {
+    Static(&'a Grants),
+    Fetched(Rc<Grants>),
+}
+
+impl Deref for GrantsRef<'_> {
+    type Target = Grants;
+
+    fn deref(&self) -> &Self::Target {
+        match self {
+            GrantsRef::Static(grants) => grants,
+            GrantsRef::Fetched(grants) => grants,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Shared {
+    fetched_at: u64,
+    tokens: Vec<Token>,
+}
+
+struct Local {
+    fetched_at: u64,
+    grants: Rc<Grants>,
+}
+
+thread_local! {
+    /// Decoded grants of this worker by source.
+    static LOCAL: RefCell<HashMap<String, Local>> = RefCell::new(HashMap::new());
+}
+
+fn into_grants(tokens: Vec<Token>) -> Grants {
+    tokens
+        .into_iter()
+        .map(|token| {
+            let grant = Grant {
+                name: token.name,
+                scope: token.scope,
+            };
+            (token.public_key, grant)
+        })
+        .collect()
+}
+
+pub struct RemoteGrants<'a> {
+    setting: &'a RemoteGrantsSetting,
+    context_id: u32,
+    source: String,
+}
+
+impl<'a> RemoteGrants<'a> {
+    pub fn new(context_id: u32, setting: &'a RemoteGrantsSetting) -> Self {
+        Self {
+            setting,
+            context_id,
+            source: format!("{}{}", setting.upstream, setting.path),
+        }
+    }
+
+    fn shared(&self) -> KVStore<Shared> {
+        KVStore::new(self.context_id, "auth:grants")
+    }
+
+    /// The newest grants of this worker or in shared data, with their age.
+    fn cached(&self, now: u64) -> Option<(u64, Rc<Grants>)> {
+        let local = LOCAL.with(|local| {
+            local
+                .borrow()
+                .get(&self.source)
+                .map(|local| (local.fetched_at, local.grants.clone()))
+        });
+        if let Some((fetched_at, grants)) = &local {
+            if now.saturating_sub(*fetched_at) < self.setting.ttl {
+                return Some((now - fetched_at, grants.clone()));
+            }
+        }
+        let shared = self
+            .shared()
+            .get(&self.source)
+            .inspect_err(|e| log::warn!("failed to read shared grants: {}", e))
+            .ok()
+            .flatten();
+        let local_fetched_at = local.as_ref().map(|(fetched_at, _)| *fetched_at);
+        match shared {
+            Some(shared) if Some(shared.fetched_at) > local_fetched_at => {
+                let grants = Rc::new(into_grants(shared.tokens));
+                self.keep(shared.fetched_at, grants.clone());
+                Some((now.saturating_sub(shared.fetched_at), grants))
+            }
+            _ => local.map(|(fetched_at, grants)| (now.saturating_sub(fetched_at), grants)),
+        }
+    }
+
+    fn keep(&self, fetched_at: u64, grants: Rc<Grants>) {
+        LOCAL.with(|local| {
+            local
+                .borrow_mut()
+                .insert(self.source.clone(), Local { fetched_at, grants })
+        });
+    }
+
+    async fn fetch(&self, now: u64) -> Result<Rc<Grants>, http::Error> {
+        log::debug!("fetching grants from {}", self.source);
+        let tokens: Vec<Token> = Client::new(&self.setting.upstream, &self.setting.authority)
+            .get_json(&self.setting.path)
+            .await?;
+        let shared = Shared {
+            fetched_at: now,
+            tokens,
+        };
+        if let Err(e) = self.shared().put(&self.source, &shared) {
+            log::warn!("failed to share grants: {}", e);
+        }
+        let grants = Rc::new(into_grants(shared.tokens));
+        self.keep(now, grants.clone());
+        Ok(grants)
+    }
+
+    /// Whether this worker fetches the stale grants, at most one worker does
+    /// every few seconds.
+    fn take_turn(&self, now: u64) -> bool {
+        let turn: KVStore<u64> = KVStore::new(self.context_id, "auth:grants:refresh");
+        let mut mine = false;
+        let ret = turn.update(&self.source, |last| {
+            let last = last.unwrap_or(0);
+            mine = last + REFRESH_BACKOFF <= now;
+            Ok::<_, Error>(if mine { now } else { last })
+        });
+        if let Err(e) = ret {
+            log::warn!("failed to take grants refresh turn: {}", e);
+            return false;
+        }
+        mine
+    }
+
+    pub async fn get(&self) -> Result<Rc<Grants>, http::Error> {
+        let now = now();
+        match self.cached(now) {
+            Some((age, grants)) if age < self.setting.ttl => Ok(grants),
+            Some((age, grants)) if age < self.setting.ttl + self.setting.stale_for => {
+                if self.take_turn(now) {
+                    let (context_id, setting) = (self.context_id, self.setting.clone());
+                    spawn_local(async move {
+                        let remote = RemoteGrants::new(context_id, &setting);
+                        if let Err(e) = remote.fetch(now).await {
+                            log::warn!("failed to refresh grants: {}", e);
+                        }
+                    });
+                }
+                Ok(grants)
+            }
+            stale => match self.fetch(now).await {
+                Ok(grants) => Ok(grants),
+                Err(e) => {
+                    let Some((_, grants)) = stale else {
+                        return Err(e);
+                    };
+                    log::warn!("failed to fetch grants, using stale ones: {}", e);
+                    Ok(grants)
+                }
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn remote_tokens() {
+        let tokens: Vec<Token> = serde_json::from_str(
+            r#"[
+                {
+                    "name": "billing",
+                    "public_key": "039e70a683d711ab788433b4cabddbd10dce4bb1f29c67cc3219b325053b0f2f1c",
+                    "scope": { "methods": ["GET"] }
+                }
+            ]"#,
+        )
+        .unwrap();
+        let grants = into_grants(tokens);
+        let key: PublicKey = "039e70a683d711ab788433b4cabddbd10dce4bb1f29c67cc3219b325053b0f2f1c"
+            .parse()
+            .unwrap();
+        assert_eq!(grants[&key].name, "billing");
+        assert!(!grants[&key].scope.allows("POST", "/"));
+
+        let grants = GrantsRef::Fetched(Rc::new(grants));
+        assert_eq!(grants.len(), 1);
+    }
+}
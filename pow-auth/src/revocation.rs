@@ -0,0 +1,123 @@
+//! Revoked keys, without redeploying the grants.
+//!
+//! The revocation list lives in shared data so one worker polls it for the
+//! whole VM, or anything else with access to the shared data, e.g. another
+//! plugin, pushes it. Every worker reads the shared list into a local set
+//! each second, so a revoked key fails within the poll interval. The
+//! endpoint answers `GET` with [`Revoked`] as JSON.
+
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::time::Duration;
+
+use pow_runtime::filter_state::WeakState;
+use pow_runtime::http::Client;
+use pow_runtime::kv_store::{Error, KVStore};
+use pow_runtime::spawn_local;
+use pow_runtime::timeout::sleep;
+use serde::{Deserialize, Serialize};
+
+use crate::auth_identity::PublicKey;
+use crate::config::RevocationSetting;
+use crate::{now, Inner};
+
+/// Key of the list in shared data.
+pub const SHARED_KEY: &str = "auth:revoked";
+
+#[derive(Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Revoked {
+    pub keys: Vec<PublicKey>,
+}
+
+/// The local copy of the revocation list of a worker.
+#[derive(Debug, Default)]
+pub struct Revocations(RefCell<HashSet<PublicKey>>);
+
+impl Revocations {
+    pub fn contains(&self, key: &PublicKey) -> bool {
+        self.0.borrow().contains(key)
+    }
+
+    fn replace(&self, revoked: Revoked) {
+        let keys: HashSet<_> = revoked.keys.into_iter().collect();
+        if keys.len() != self.0.borrow().len() {
+            log::info!("{} keys revoked", keys.len());
+        }
+        *self.0.borrow_mut() = keys;
+    }
+}
+
+/// Start reading, and polling if an endpoint is set, the revocation list
+/// until the epoch behind `plugin` is torn down.
+pub(crate) fn spawn(context_id: u32, plugin: WeakState<Inner>, setting: RevocationSetting) {
+    let shared: KVStore<Revoked> = KVStore::new(context_id, SHARED_KEY);
+    let turn: KVStore<u64> = KVStore::new(context_id, "auth:revoked:last");
+    spawn_local(async move {
+        loop {
+            let Some(plugin) = plugin.upgrade() else {
+                log::info!("exit revocation loop");
+                break;
+            };
+            if let Some(endpoint) = &setting.endpoint {
+                if take_turn(&turn, setting.interval) {
+                    let client = Client::new(&endpoint.upstream, &endpoint.authority);
+                    match client.get_json::<Revoked>(&endpoint.path).await {
+                        Ok(revoked) => {
+                            if let Err(e) = shared.put("", &revoked) {
+                                log::warn!("failed to share revocation list: {}", e);
+                            }
+                        }
+                        Err(e) => log::warn!("failed to poll revocation list: {}", e),
+                    }
+                }
+            }
+            match shared.get("") {
+                Ok(revoked) => plugin.revocations.replace(revoked.unwrap_or_default()),
+                Err(e) => log::warn!("failed to read revocation list: {}", e),
+            }
+            drop(plugin);
+            sleep(Duration::from_secs(1)).await;
+        }
+    });
+}
+
+/// Only one worker polls per interval.
+fn take_turn(turn: &KVStore<u64>, interval: u64) -> bool {
+    let now = now();
+    let mut mine = false;
+    let ret = turn.update("", |last| {
+        let last = last.unwrap_or(0);
+        mine = last + interval <= now;
+        Ok::<_, Error>(if mine { now } else { last })
+    });
+    if let Err(e) = ret {
+        log::warn!("failed to take revocation poll turn: {}", e);
+        return false;
+    }
+    mine
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn revoked_list() {
+        let revoked: Revoked = serde_json::from_str(
+            r#"{"keys": [
+                "039e70a683d711ab788433b4cabddbd10dce4bb1f29c67cc3219b325053b0f2f1c",
+                "ed25519:ea4a6c63e29c520abef5507b132ec5f9954776aebebe7b92421eea691446d22c"
+            ]}"#,
+        )
+        .unwrap();
+        let revocations = Revocations::default();
+        revocations.replace(revoked);
+        let key: PublicKey = "039e70a683d711ab788433b4cabddbd10dce4bb1f29c67cc3219b325053b0f2f1c"
+            .parse()
+            .unwrap();
+        assert!(revocations.contains(&key));
+
+        revocations.replace(Revoked::default());
+        assert!(!revocations.contains(&key));
+    }
+}
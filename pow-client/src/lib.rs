@@ -0,0 +1,362 @@
+//! A reqwest middleware solving the PoW challenges of the filter.
+//!
+//! A client with [`PowMiddleware`] retries a request with a solution when
+//! the filter answers it with a challenge, so callers only see the final
+//! response:
+//!
+//! ```no_run
+//! # async fn example() -> Result<(), reqwest_middleware::Error> {
+//! let client = reqwest_middleware::ClientBuilder::new(reqwest::Client::new())
+//!     .with(pow_client::PowMiddleware::new().concurrency(4))
+//!     .build();
+//! let response = client.get("https://example.com/api/orders").send().await?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Solving blocks a thread, so it runs on the blocking pool of tokio, at
+//! most `concurrency` challenges at a time. Answers to 429 other than a
+//! challenge, e.g. an exceeded quota, and requests with a body that cannot
+//! be sent twice are passed through. The middleware fails with an [`Error`]
+//! in a [`reqwest_middleware::Error::Middleware`].
+
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use http::Extensions;
+use pow_types::bytearray32::{ByteArray32, FixedByteArray};
+use pow_types::nonce::{Nonces, Partition, Search};
+use pow_types::protocol::{self, Version};
+use pow_types::sha256::Midstate;
+use reqwest::header::{HeaderValue, HOST};
+use reqwest::{Request, Response, StatusCode, Url};
+use reqwest_middleware::{Middleware, Next};
+use tokio::sync::Semaphore;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("still challenged after {attempts} solutions")]
+    Unsolved { attempts: usize },
+    #[error("failed to solve: {0}")]
+    Solver(#[from] tokio::task::JoinError),
+}
+
+/// The fields of a 429 answer with a challenge, in either of its formats.
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct Challenge {
+    pub current: ByteArray32,
+    pub difficulty: ByteArray32,
+    /// Absent from filters predating protocol versions.
+    pub version: Option<Version>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Solution {
+    pub base: ByteArray32,
+    pub timestamp: u64,
+    pub nonce: [u8; 8],
+    pub version: Version,
+}
+
+impl Solution {
+    /// The request headers carrying the solution.
+    pub fn headers(&self) -> [(&'static str, String); 4] {
+        [
+            (protocol::BASE, format!("{:x}", self.base)),
+            (protocol::TIMESTAMP, self.timestamp.to_string()),
+            (
+                protocol::NONCE,
+                format!("{:x}", FixedByteArray::from(&self.nonce)),
+            ),
+            (protocol::VERSION, u8::from(self.version).to_string()),
+        ]
+    }
+}
+
+/// Solve `challenge` for a request to `host` and `path`, blocking until a
+/// solution is found.
+pub fn solve(challenge: &Challenge, host: &str, path: &str) -> Solution {
+    let version = challenge.version.unwrap_or(Version::V1);
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("failed to get timestamp")
+        .as_secs();
+    let midstate = Midstate::new(&version.pre_image(&challenge.current, timestamp, host, path));
+    let mut nonces = Nonces::new(Search::Sequential, Partition::default());
+    let nonce = nonces
+        .search(&midstate, &challenge.difficulty, u64::MAX)
+        .expect("the nonces are endless");
+    Solution {
+        base: challenge.current,
+        timestamp,
+        nonce,
+        version,
+    }
+}
+
+/// The `:authority` of a request to `url`.
+fn authority(url: &Url) -> String {
+    let host = url.host_str().unwrap_or_default();
+    match url.port() {
+        Some(port) => format!("{}:{}", host, port),
+        None => host.to_string(),
+    }
+}
+
+fn path_and_query(url: &Url) -> String {
+    match url.query() {
+        Some(query) => format!("{}?{}", url.path(), query),
+        None => url.path().to_string(),
+    }
+}
+
+/// The challenge in `response`, or the response as it was.
+async fn challenge(response: Response) -> reqwest::Result<Result<Challenge, Response>> {
+    let status = response.status();
+    let version = response.version();
+    let headers = response.headers().clone();
+    let body = response.bytes().await?;
+    if let Ok(challenge) = serde_json::from_slice(&body) {
+        return Ok(Ok(challenge));
+    }
+    // rebuilt from its parts, the response no longer knows its URL
+    let mut response = http::Response::new(body);
+    *response.status_mut() = status;
+    *response.version_mut() = version;
+    *response.headers_mut() = headers;
+    Ok(Err(response.into()))
+}
+
+pub struct PowMiddleware {
+    permits: Arc<Semaphore>,
+    max_attempts: usize,
+}
+
+impl Default for PowMiddleware {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PowMiddleware {
+    /// Solve as many challenges at a time as there are cores, each request
+    /// up to 3 times.
+    pub fn new() -> Self {
+        let cores = std::thread::available_parallelism().map_or(1, usize::from);
+        PowMiddleware {
+            permits: Arc::new(Semaphore::new(cores)),
+            max_attempts: 3,
+        }
+    }
+
+    /// Solve at most `concurrency` challenges at a time.
+    pub fn concurrency(mut self, concurrency: usize) -> Self {
+        self.permits = Arc::new(Semaphore::new(concurrency.max(1)));
+        self
+    }
+
+    /// Give up on a request when it is still challenged after `attempts`
+    /// solutions, as the filter raises the difficulty with the rate.
+    pub fn max_attempts(mut self, attempts: usize) -> Self {
+        self.max_attempts = attempts;
+        self
+    }
+}
+
+#[async_trait::async_trait]
+impl Middleware for PowMiddleware {
+    async fn handle(
+        &self,
+        mut req: Request,
+        extensions: &mut Extensions,
+        next: Next<'_>,
+    ) -> reqwest_middleware::Result<Response> {
+        let host = match req.headers().get(HOST).and_then(|host| host.to_str().ok()) {
+            Some(host) => host.to_string(),
+            None => authority(req.url()),
+        };
+        let path = path_and_query(req.url());
+        let mut attempts = 0;
+        loop {
+            let retry = req.try_clone();
+            let response = next.clone().run(req, extensions).await?;
+            if response.status() != StatusCode::TOO_MANY_REQUESTS {
+                return Ok(response);
+            }
+            let Some(retry) = retry else {
+                return Ok(response);
+            };
+            let challenge = match challenge(response).await? {
+                Ok(challenge) => challenge,
+                Err(response) => return Ok(response),
+            };
+            if attempts == self.max_attempts {
+                return Err(reqwest_middleware::Error::middleware(Error::Unsolved {
+                    attempts,
+                }));
+            }
+            attempts += 1;
+
+            let permit = self
+                .permits
+                .clone()
+                .acquire_owned()
+                .await
+                .expect("the semaphore is never closed");
+            let (host, path) = (host.clone(), path.clone());
+            let solution = tokio::task::spawn_blocking(move || {
+                let _permit = permit;
+                solve(&challenge, &host, &path)
+            })
+            .await
+            .map_err(|e| reqwest_middleware::Error::middleware(Error::from(e)))?;
+
+            req = retry;
+            for (name, value) in solution.headers() {
+                let value =
+                    HeaderValue::try_from(value).expect("hex and decimals are valid headers");
+                req.headers_mut().insert(name, value);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    const CURRENT: &str = "000000000000000000015beb8839f715806b45a0895367e702b14e782afccfdd";
+
+    fn valid(
+        headers: &[(String, String)],
+        host: &str,
+        path: &str,
+        difficulty: &ByteArray32,
+    ) -> bool {
+        let header = |name: &str| {
+            headers
+                .iter()
+                .find(|(key, _)| key.eq_ignore_ascii_case(name))
+                .map(|(_, value)| value.as_str())
+        };
+        let (Some(base), Some(timestamp), Some(nonce)) = (
+            header(protocol::BASE),
+            header(protocol::TIMESTAMP),
+            header(protocol::NONCE),
+        ) else {
+            return false;
+        };
+        let version = Version::from_header(header(protocol::VERSION)).unwrap();
+        let base = ByteArray32::try_from(base).unwrap();
+        let nonce = FixedByteArray::<8>::try_from(nonce).unwrap();
+        let pre_image = version.pre_image(&base, timestamp.parse().unwrap(), host, path);
+        protocol::is_solution(&pre_image, difficulty, nonce.as_bytes())
+    }
+
+    /// Challenge requests to `/ip` until solved, answer `/quota` with a 429
+    /// without a challenge and `/hard` with challenges no one solves in time.
+    async fn serve(listener: TcpListener, requests: Arc<AtomicUsize>) {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            requests.fetch_add(1, Ordering::SeqCst);
+            let mut buf = Vec::new();
+            while !buf.ends_with(b"\r\n\r\n") {
+                let mut byte = [0];
+                stream.read_exact(&mut byte).await.unwrap();
+                buf.push(byte[0]);
+            }
+            let head = String::from_utf8(buf).unwrap();
+            let mut lines = head.lines();
+            let path = lines.next().unwrap().split(' ').nth(1).unwrap().to_string();
+            let headers: Vec<(String, String)> = lines
+                .filter_map(|line| line.split_once(": "))
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect();
+            let host = headers
+                .iter()
+                .find(|(k, _)| k.eq_ignore_ascii_case("host"))
+                .map(|(_, v)| v.clone())
+                .unwrap();
+
+            let easy = "000fffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff";
+            let hard = "0000000000000000000000000000000000000000000000000000000000000000";
+            let difficulty = if path == "/hard" { hard } else { easy };
+            let (status, body) = if path == "/quota" {
+                (
+                    "429 Too Many Requests",
+                    r#"{"error": "quota exceeded"}"#.to_string(),
+                )
+            } else if valid(
+                &headers,
+                &host,
+                &path,
+                &ByteArray32::try_from(difficulty).unwrap(),
+            ) {
+                ("200 OK", "solved".to_string())
+            } else {
+                (
+                    "429 Too Many Requests",
+                    format!(
+                        r#"{{"current": "{}", "difficulty": "{}", "version": 2}}"#,
+                        CURRENT, difficulty
+                    ),
+                )
+            };
+            let response = format!(
+                "HTTP/1.1 {}\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+                status,
+                body.len(),
+                body
+            );
+            stream.write_all(response.as_bytes()).await.unwrap();
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn middleware() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(AtomicUsize::new(0));
+        tokio::spawn(serve(listener, requests.clone()));
+
+        let client = reqwest_middleware::ClientBuilder::new(reqwest::Client::new())
+            .with(PowMiddleware::new().concurrency(2).max_attempts(1))
+            .build();
+
+        let response = client
+            .get(format!("{}/ip?address=1", base))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.text().await.unwrap(), "solved");
+        assert_eq!(requests.swap(0, Ordering::SeqCst), 2);
+
+        let response = client.get(format!("{}/quota", base)).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(
+            response.text().await.unwrap(),
+            r#"{"error": "quota exceeded"}"#
+        );
+        assert_eq!(requests.swap(0, Ordering::SeqCst), 1);
+
+        let client = reqwest_middleware::ClientBuilder::new(reqwest::Client::new())
+            .with(PowMiddleware::new().max_attempts(0))
+            .build();
+        let err = client
+            .get(format!("{}/hard", base))
+            .send()
+            .await
+            .unwrap_err();
+        let reqwest_middleware::Error::Middleware(err) = err else {
+            panic!("unexpected error {:?}", err);
+        };
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::Unsolved { attempts: 0 })
+        ));
+    }
+}
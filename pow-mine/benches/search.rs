@@ -0,0 +1,33 @@
+//! Time to solve a challenge with random and sequential nonces, at a
+//! difficulty of 4096 expected hashes. Each iteration solves a new
+//! challenge, so the variance of the work shows in the spread.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use pow_mine::nonce::{Nonces, Partition, Search};
+use pow_types::bytearray32::ByteArray32;
+use pow_types::protocol::{is_solution, Version};
+
+fn search(c: &mut Criterion) {
+    let current = ByteArray32::from(&[0xab; 32]);
+    let mut difficulty = [0xff; 32];
+    difficulty[0] = 0;
+    difficulty[1] = 0x0f;
+    let difficulty = ByteArray32::from(&difficulty);
+
+    let mut group = c.benchmark_group("search");
+    for search in [Search::Random, Search::Sequential] {
+        let mut timestamp = 0;
+        group.bench_function(BenchmarkId::from_parameter(format!("{:?}", search)), |b| {
+            b.iter(|| {
+                timestamp += 1;
+                let pre_image = Version::V2.pre_image(&current, timestamp, "example.com", "/ip");
+                Nonces::new(search, Partition::default())
+                    .find(|nonce| is_solution(&pre_image, &difficulty, nonce))
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, search);
+criterion_main!(benches);
@@ -1,24 +1,35 @@
+import wasm_bindgen, {startup, mine_parallel} from './pkg/pow_mine.js'
+
 async function main() {
-	const worker = new Worker('./worker.js', { type: 'module' })
+	await wasm_bindgen('./pkg/pow_mine_bg.wasm')
+	startup()
 
+	let mining = null
 	const mineButton = document.getElementById('mine')
+	const cancelButton = document.getElementById('cancel')
 	mineButton.onclick = async () => {
 		mineButton.disabled = true
+		cancelButton.disabled = false
 		const difficulty = document.getElementById('difficulty').value
 		const path = document.getElementById('path').value
+		const host = document.getElementById('host').value
 		const current = document.getElementById('current').value
+		const workers = Number(document.getElementById('workers').value) || undefined
 		const timestamp = new Date().getTime() / 1000 | 0
-		worker.postMessage({ difficulty, path, current, timestamp })
-
-		worker.onmessage = event => {
+		try {
+			mining = mine_parallel({ difficulty, path, host, current, timestamp, version: 2 }, { workers })
+			const solution = await mining.result()
+			document.getElementById('nonce').innerText = JSON.stringify(solution)
+			document.getElementById('error').innerText = ''
+		} catch (e) {
+			document.getElementById('error').innerText = e.toString()
+		} finally {
+			mining = null
 			mineButton.disabled = false
-			if (event.data.ok) {
-				document.getElementById('nonce').innerText = JSON.stringify(event.data.ok)
-			} else {
-				document.getElementById('error').innerText = event.data.err.toString()
-			}
+			cancelButton.disabled = true
 		}
 	}
+	cancelButton.onclick = () => mining?.cancel()
 }
 
-main()
\ No newline at end of file
+main()
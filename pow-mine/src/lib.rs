@@ -1,10 +1,18 @@
+mod parallel;
+mod progress;
 mod utils;
 
-use sha2::Digest;
-use pow_types::bytearray32::ByteArray32;
+use pow_types::bytearray32::{ByteArray32, FixedByteArray};
+use pow_types::protocol::Version;
+use pow_types::sha256::Midstate;
+use pow_types::nonce::{Nonces, Partition, Search};
 use wasm_bindgen::prelude::*;
 use serde_wasm_bindgen::{from_value, to_value};
 
+pub use pow_types::nonce;
+pub use parallel::{mine_parallel, serve, Mining};
+pub use progress::mine_with_progress;
+
 
 fn init_log() {
     #[cfg(feature = "console_log")]
@@ -16,12 +24,27 @@ pub fn startup() {
     init_log();
 }
 
-#[derive(Debug, serde::Deserialize)]
+fn default_version() -> Version {
+    Version::V1
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 struct MineArgs {
     path: String,
+    /// Required by version 2, the host the request is sent to.
+    #[serde(default)]
+    host: String,
     current: ByteArray32,
     difficulty: ByteArray32,
     timestamp: u64,
+    /// The `version` of the challenge, version 1 for callers predating it.
+    #[serde(default = "default_version")]
+    version: Version,
+    #[serde(default)]
+    partition: Partition,
+    /// `random` or `sequential`, see [`nonce`].
+    #[serde(default)]
+    search: Search,
 }
 
 #[derive(Debug, serde::Serialize)]
@@ -32,6 +55,8 @@ struct MineResult {
     timestamp: String,
     #[serde(rename = "X-PoW-Base")]
     base: String,
+    #[serde(rename = "X-PoW-Version")]
+    version: String,
 }
 
 #[wasm_bindgen]
@@ -49,44 +74,65 @@ pub fn mine(args: JsValue) -> Result<JsValue, JsError> {
     }
 }
 
+impl MineArgs {
+    fn pre_image(&self) -> Vec<u8> {
+        self.version.pre_image(&self.current, self.timestamp, &self.host, &self.path)
+    }
+
+    fn midstate(&self) -> Midstate {
+        Midstate::new(&self.pre_image())
+    }
+
+    fn nonces(&self) -> Nonces {
+        Nonces::new(self.search, self.partition)
+    }
+
+    fn solution(&self, nonce: &[u8; 8]) -> MineResult {
+        let hex_nonce = format!("{:x}", FixedByteArray::from(nonce));
+        log::debug!("found nonce: {}", hex_nonce);
+        MineResult {
+            nonce: hex_nonce,
+            timestamp: self.timestamp.to_string(),
+            base: format!("{:x}", self.current),
+            version: u8::from(self.version).to_string(),
+        }
+    }
+}
+
 fn mine_impl(args: MineArgs) -> MineResult {
-    let mut data = args.current.as_bytes().to_vec();
-    data.extend(args.timestamp.to_be_bytes());
-    data.extend(args.path.as_bytes());
+    let midstate = args.midstate();
+    let mut nonces = args.nonces();
     loop {
-        let nonce = rand::random::<[u8; 8]>();
-        if valid_nonce(&data, args.difficulty, &nonce) {
-            let hex_nonce = format!("{:x}", LowerHexSlice(&nonce));
-            log::debug!("found nonce: {}", hex_nonce);
-            return MineResult {
-                nonce: hex_nonce,
-                timestamp: args.timestamp.to_string(),
-                base: format!("{:x}", LowerHexSlice(args.current.as_bytes())),
-            }
+        if let Some(nonce) = nonces.search(&midstate, &args.difficulty, u64::MAX) {
+            return args.solution(&nonce)
         }
     }
 }
 
 
-fn valid_nonce(data: &[u8], difficulty: ByteArray32, nonce: &[u8]) -> bool {
-    let mut hasher = sha2::Sha256::new();
-    hasher.update(data);
-    hasher.update(nonce);
-    let hash = hasher.finalize();
-    let slice: &[u8; 32] = &hash.into();
-    let target: ByteArray32 = slice.into();
-    target <= difficulty
-}
-
-struct LowerHexSlice<'a, T>(&'a [T]);
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pow_types::protocol;
 
-impl<T> std::fmt::LowerHex for LowerHexSlice<'_, T>
-where
-    T: std::fmt::LowerHex, {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        for byte in self.0 {
-            write!(f, "{:02x}", byte)?;
-        }
-        Ok(())
+    #[test]
+    fn partitioned() {
+        let mut difficulty = [0xff; 32];
+        difficulty[0] = 0x0f;
+        let args = MineArgs {
+            path: "/ip".to_string(),
+            host: "example.com".to_string(),
+            current: ByteArray32::from(&[0xab; 32]),
+            difficulty: ByteArray32::from(&difficulty),
+            timestamp: 1718000000,
+            version: Version::V2,
+            partition: Partition { index: 2, count: 3 },
+            search: Search::Sequential,
+        };
+        let result = mine_impl(args.clone());
+        let nonce = u64::from_str_radix(&result.nonce, 16).unwrap();
+        assert_eq!(nonce % 3, 2);
+        assert_eq!(result.version, "2");
+        assert!(protocol::is_solution(&args.pre_image(), &args.difficulty, &nonce.to_be_bytes()));
     }
 }
@@ -0,0 +1,216 @@
+//! Mining across Web Workers.
+//!
+//! [`mine`](crate::mine) blocks the thread it runs on until it finds a
+//! solution. [`mine_parallel`] instead starts Web Workers running [`serve`],
+//! gives each a partition of the nonce space and returns at once; the result
+//! of the [`Mining`] resolves with the first solution found, after which all
+//! workers are terminated.
+//!
+//! A worker announces that it is ready with `"ready"`, is sent the arguments
+//! of `mine` and answers with `{ok: result}` or `{err: message}`. Its script
+//! only has to initialize the module and call `serve`:
+//!
+//! ```js
+//! import init, { startup, serve } from './pkg/pow_mine.js'
+//!
+//! await init('./pkg/pow_mine_bg.wasm')
+//! startup()
+//! serve()
+//! ```
+
+use js_sys::{Array, Function, Promise, Reflect};
+use serde_wasm_bindgen::{from_value, to_value};
+use wasm_bindgen::prelude::*;
+use wasm_bindgen_futures::{future_to_promise, JsFuture};
+use web_sys::{DedicatedWorkerGlobalScope, MessageEvent, Worker, WorkerOptions, WorkerType};
+
+use crate::nonce::Partition;
+use crate::{mine_impl, MineArgs, MineResult};
+
+const DEFAULT_SCRIPT: &str = "./worker.js";
+
+#[derive(Debug, Default, serde::Deserialize)]
+struct ParallelOptions {
+    /// `navigator.hardwareConcurrency` by default.
+    workers: Option<u64>,
+    /// The module script of the workers, `./worker.js` by default.
+    script: Option<String>,
+}
+
+#[derive(Debug, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Answer {
+    Ready,
+    Ok(MineResult),
+    Err(String),
+}
+
+/// A mining in progress.
+#[wasm_bindgen]
+pub struct Mining {
+    workers: Vec<Worker>,
+    result: Promise,
+    cancel: Function,
+}
+
+#[wasm_bindgen]
+impl Mining {
+    /// Resolves with the solution, as `mine` returns it. Rejects when a
+    /// worker fails or the mining is cancelled.
+    pub fn result(&self) -> Promise {
+        self.result.clone()
+    }
+
+    /// Terminate the workers and reject the result, unless it is settled.
+    pub fn cancel(&self) {
+        terminate(&self.workers);
+        let reason = JsValue::from(JsError::new("mining cancelled"));
+        let _ = self.cancel.call1(&JsValue::NULL, &reason);
+    }
+}
+
+fn terminate(workers: &[Worker]) {
+    for worker in workers {
+        worker.terminate();
+    }
+}
+
+fn hardware_concurrency() -> u64 {
+    web_sys::window()
+        .map(|window| window.navigator().hardware_concurrency() as u64)
+        .filter(|concurrency| *concurrency > 0)
+        .unwrap_or(4)
+}
+
+/// Resolves with the next message of `worker`, rejects on its next error.
+fn next_message(worker: &Worker) -> Promise {
+    Promise::new(&mut |resolve, reject| {
+        worker.set_onmessage(Some(&resolve));
+        worker.set_onerror(Some(&reject));
+    })
+}
+
+/// Mine with the arguments of `mine` across Web Workers, with the options
+/// `{workers, script}`.
+#[wasm_bindgen]
+pub fn mine_parallel(args: JsValue, options: JsValue) -> Result<Mining, JsError> {
+    let args: MineArgs = match from_value(args) {
+        Ok(args) => args,
+        Err(err) => return Err(JsError::new(&format!("{}", err))),
+    };
+    let options: ParallelOptions = if options.is_undefined() || options.is_null() {
+        ParallelOptions::default()
+    } else {
+        match from_value(options) {
+            Ok(options) => options,
+            Err(err) => return Err(JsError::new(&format!("{}", err))),
+        }
+    };
+    let count = options.workers.unwrap_or_else(hardware_concurrency).max(1);
+    let script = options.script.as_deref().unwrap_or(DEFAULT_SCRIPT);
+
+    let mut tasks = Vec::with_capacity(count as usize);
+    for index in 0..count {
+        let task = MineArgs {
+            partition: Partition { index, count },
+            ..args.clone()
+        };
+        match to_value(&task) {
+            Ok(task) => tasks.push(task),
+            Err(err) => return Err(JsError::new(&format!("{}", err))),
+        }
+    }
+
+    let worker_options = WorkerOptions::new();
+    worker_options.set_type(WorkerType::Module);
+    let mut workers = Vec::with_capacity(tasks.len());
+    for _ in 0..count {
+        match Worker::new_with_options(script, &worker_options) {
+            Ok(worker) => workers.push(worker),
+            Err(err) => {
+                terminate(&workers);
+                return Err(JsError::new(&format!("failed to start worker: {:?}", err)));
+            }
+        }
+    }
+
+    let mut cancel = None;
+    let cancelled = Promise::new(&mut |_, reject| cancel = Some(reject));
+    let running = workers.clone();
+    let result = future_to_promise(async move {
+        let result = run(&running, tasks, cancelled).await;
+        terminate(&running);
+        result
+    });
+    Ok(Mining {
+        workers,
+        result,
+        cancel: cancel.expect("promise executor runs synchronously"),
+    })
+}
+
+async fn run(
+    workers: &[Worker],
+    tasks: Vec<JsValue>,
+    cancelled: Promise,
+) -> Result<JsValue, JsValue> {
+    let ready: Array = workers.iter().map(next_message).collect();
+    JsFuture::from(Promise::race(&Array::of2(
+        &Promise::all(&ready),
+        &cancelled,
+    )))
+    .await?;
+
+    let answers: Array = workers.iter().map(next_message).collect();
+    answers.push(&cancelled);
+    for (worker, task) in workers.iter().zip(tasks) {
+        worker.post_message(&task)?;
+    }
+    let answer = JsFuture::from(Promise::race(&answers))
+        .await?
+        .unchecked_into::<MessageEvent>()
+        .data();
+    let ok = Reflect::get(&answer, &JsValue::from_str("ok"))?;
+    if ok.is_undefined() {
+        return Err(Reflect::get(&answer, &JsValue::from_str("err"))?);
+    }
+    Ok(ok)
+}
+
+/// Serve the tasks of [`mine_parallel`], called once in a worker script.
+#[wasm_bindgen]
+pub fn serve() -> Result<(), JsError> {
+    let Ok(scope) = js_sys::global().dyn_into::<DedicatedWorkerGlobalScope>() else {
+        return Err(JsError::new("serve must be called in a dedicated worker"));
+    };
+    let reply = scope.clone();
+    let onmessage = Closure::<dyn FnMut(MessageEvent)>::new(move |event: MessageEvent| {
+        let answer = match from_value::<MineArgs>(event.data()) {
+            Ok(args) => Answer::Ok(mine_impl(args)),
+            Err(err) => Answer::Err(err.to_string()),
+        };
+        match to_value(&answer) {
+            Ok(answer) => {
+                if let Err(err) = reply.post_message(&answer) {
+                    log::error!("failed to answer: {:?}", err);
+                }
+            }
+            Err(err) => log::error!("failed to serialize answer: {}", err),
+        }
+    });
+    scope.set_onmessage(Some(onmessage.as_ref().unchecked_ref()));
+    // the handler lives as long as the worker
+    onmessage.forget();
+
+    let ready = match to_value(&Answer::Ready) {
+        Ok(ready) => ready,
+        Err(err) => return Err(JsError::new(&format!("{}", err))),
+    };
+    if let Err(err) = scope.post_message(&ready) {
+        return Err(JsError::new(&format!(
+            "failed to announce worker: {:?}",
+            err
+        )));
+    }
+    Ok(())
+}
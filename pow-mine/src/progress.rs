@@ -0,0 +1,121 @@
+//! Mining with progress reports and cancellation.
+//!
+//! [`mine_with_progress`] searches in slices of [`SLICE_MS`] and yields to
+//! the event loop between them, so the page stays responsive while it mines.
+//! After each slice `on_progress` is called with the [`Progress`], and once
+//! the `abort` signal is aborted the mining is rejected with its reason.
+
+use js_sys::{Date, Function, Promise};
+use pow_types::bytearray32::ByteArray32;
+use serde_wasm_bindgen::{from_value, to_value};
+use wasm_bindgen::prelude::*;
+use wasm_bindgen_futures::JsFuture;
+use web_sys::AbortSignal;
+
+use crate::MineArgs;
+
+/// How long to search before reporting progress and yielding.
+const SLICE_MS: f64 = 100.0;
+/// Hashes between checks of the time.
+const BATCH: u64 = 1024;
+
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(js_name = setTimeout)]
+    fn set_timeout(handler: &Function, timeout: i32);
+}
+
+#[derive(Debug, serde::Serialize)]
+struct Progress {
+    hashes: u64,
+    elapsed_ms: f64,
+    /// Hashes per second.
+    hash_rate: f64,
+    /// The number of hashes it takes to find a solution on average.
+    expected_hashes: f64,
+    /// The expected time until a solution is found. Every nonce is as likely
+    /// to be a solution as the last, so it does not shrink as hashes are tried.
+    eta_ms: f64,
+}
+
+impl Progress {
+    fn new(hashes: u64, elapsed_ms: f64, expected_hashes: f64) -> Self {
+        let hash_rate = hashes as f64 * 1000.0 / elapsed_ms.max(1.0);
+        Progress {
+            hashes,
+            elapsed_ms,
+            hash_rate,
+            expected_hashes,
+            eta_ms: expected_hashes * 1000.0 / hash_rate.max(1.0),
+        }
+    }
+}
+
+/// 2^256 / (difficulty + 1), as a hash is a solution with the probability
+/// of being at most the difficulty.
+fn expected_hashes(difficulty: &ByteArray32) -> f64 {
+    let difficulty = difficulty
+        .as_bytes()
+        .iter()
+        .fold(0f64, |acc, byte| acc * 256.0 + *byte as f64);
+    2f64.powi(256) / (difficulty + 1.0)
+}
+
+/// Let the event loop run, so abort events and rendering are not held up.
+async fn yield_now() -> Result<(), JsValue> {
+    let timeout = Promise::new(&mut |resolve, _| set_timeout(&resolve, 0));
+    JsFuture::from(timeout).await.map(|_| ())
+}
+
+/// Mine with the arguments of `mine` without blocking the thread, reporting
+/// progress to `on_progress` and stopping once `abort` is aborted.
+#[wasm_bindgen]
+pub async fn mine_with_progress(
+    args: JsValue,
+    on_progress: Option<Function>,
+    abort: Option<AbortSignal>,
+) -> Result<JsValue, JsValue> {
+    let args: MineArgs = match from_value(args) {
+        Ok(args) => args,
+        Err(err) => return Err(JsError::new(&format!("{}", err)).into()),
+    };
+    let midstate = args.midstate();
+    let mut nonces = args.nonces();
+    let expected_hashes = expected_hashes(&args.difficulty);
+    let start = Date::now();
+    let mut hashes = 0;
+    loop {
+        if let Some(abort) = abort.as_ref().filter(|abort| abort.aborted()) {
+            return Err(abort.reason());
+        }
+        let slice = Date::now();
+        while Date::now() - slice < SLICE_MS {
+            if let Some(nonce) = nonces.search(&midstate, &args.difficulty, BATCH) {
+                return Ok(to_value(&args.solution(&nonce))?);
+            }
+            hashes += BATCH;
+        }
+        if let Some(on_progress) = &on_progress {
+            let progress = Progress::new(hashes, Date::now() - start, expected_hashes);
+            on_progress.call1(&JsValue::NULL, &to_value(&progress)?)?;
+        }
+        yield_now().await?;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn estimates() {
+        assert_eq!(expected_hashes(&ByteArray32::from(&[0xff; 32])), 1.0);
+        let mut difficulty = [0xff; 32];
+        difficulty[..2].copy_from_slice(&[0, 0]);
+        assert_eq!(expected_hashes(&ByteArray32::from(&difficulty)), 65536.0);
+
+        let progress = Progress::new(200_000, 2000.0, 1_000_000.0);
+        assert_eq!(progress.hash_rate, 100_000.0);
+        assert_eq!(progress.eta_ms, 10_000.0);
+    }
+}
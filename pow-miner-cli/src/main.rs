@@ -0,0 +1,273 @@
+//! Solve the PoW challenges of the filter natively, on all cores.
+//!
+//! The challenge is the JSON body of a 429 answer, read from stdin or
+//! fetched from `--url`:
+//!
+//! ```sh
+//! curl -s https://example.com/api/orders | pow-miner --host example.com --path /api/orders
+//! pow-miner --url https://example.com/api/orders
+//! ```
+//!
+//! The solution is printed as request headers, one per line, or as a JSON
+//! object with `--json`. How long it took is reported on stderr.
+
+use std::io::Read;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use clap::{Parser, ValueEnum};
+use pow_types::bytearray32::{ByteArray32, FixedByteArray};
+use pow_types::nonce::{Nonces, Partition, Search};
+use pow_types::protocol::{self, Version};
+use pow_types::sha256::Midstate;
+use rayon::prelude::*;
+use reqwest::{StatusCode, Url};
+
+/// Hashes a worker tries between checks whether another found a solution.
+const BATCH: u64 = 4096;
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum SearchArg {
+    Random,
+    Sequential,
+}
+
+impl From<SearchArg> for Search {
+    fn from(search: SearchArg) -> Self {
+        match search {
+            SearchArg::Random => Search::Random,
+            SearchArg::Sequential => Search::Sequential,
+        }
+    }
+}
+
+#[derive(Debug, Parser)]
+#[command(
+    name = "pow-miner",
+    version,
+    about = "Solve a PoW challenge on all cores"
+)]
+struct Args {
+    /// Fetch the challenge from this URL instead of reading it from stdin.
+    #[arg(long)]
+    url: Option<Url>,
+    /// The path of the challenged request with its query string, the path
+    /// of `--url` by default.
+    #[arg(long)]
+    path: Option<String>,
+    /// The host of the challenged request, the host of `--url` by default.
+    /// Sent as the `Host` header when fetching.
+    #[arg(long)]
+    host: Option<String>,
+    /// Worker threads, one per core by default.
+    #[arg(long)]
+    threads: Option<usize>,
+    #[arg(long, value_enum, default_value = "sequential")]
+    search: SearchArg,
+    /// Print the solution as a JSON object of headers.
+    #[arg(long)]
+    json: bool,
+}
+
+#[derive(Debug, thiserror::Error)]
+enum Error {
+    #[error("{0} answered {1} instead of a challenge")]
+    NoChallenge(Url, StatusCode),
+    #[error("the path of the challenged request is unknown, pass --path")]
+    MissingPath,
+    #[error("version {0} challenges are bound to the host, pass --host")]
+    MissingHost(u8),
+    #[error("invalid challenge: {0}")]
+    Challenge(#[from] serde_json::Error),
+    #[error(transparent)]
+    Http(#[from] reqwest::Error),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    ThreadPool(#[from] rayon::ThreadPoolBuildError),
+}
+
+/// The fields of a 429 answer this needs, in either of its formats.
+#[derive(Debug, serde::Deserialize)]
+struct Challenge {
+    current: ByteArray32,
+    difficulty: ByteArray32,
+    /// Absent from filters predating protocol versions.
+    version: Option<Version>,
+}
+
+struct Solution {
+    nonce: [u8; 8],
+    hashes: u64,
+    elapsed: Duration,
+}
+
+fn fetch(url: &Url, host: Option<&str>) -> Result<Challenge, Error> {
+    let mut request = reqwest::blocking::Client::new().get(url.clone());
+    if let Some(host) = host {
+        request = request.header(reqwest::header::HOST, host);
+    }
+    let response = request.send()?;
+    let status = response.status();
+    if status != StatusCode::TOO_MANY_REQUESTS {
+        return Err(Error::NoChallenge(url.clone(), status));
+    }
+    Ok(serde_json::from_str(&response.text()?)?)
+}
+
+/// The `:authority` a client sends for `url`.
+fn authority(url: &Url) -> Option<String> {
+    let host = url.host_str()?;
+    Some(match url.port() {
+        Some(port) => format!("{}:{}", host, port),
+        None => host.to_string(),
+    })
+}
+
+fn path_and_query(url: &Url) -> String {
+    match url.query() {
+        Some(query) => format!("{}?{}", url.path(), query),
+        None => url.path().to_string(),
+    }
+}
+
+fn solve(
+    challenge: &Challenge,
+    pre_image: &[u8],
+    threads: usize,
+    search: Search,
+) -> Result<Solution, Error> {
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .build()?;
+    let count = pool.current_num_threads() as u64;
+    let midstate = Midstate::new(pre_image);
+    let found = AtomicBool::new(false);
+    let hashes = AtomicU64::new(0);
+    let start = Instant::now();
+
+    let nonce = pool.install(|| {
+        (0..count).into_par_iter().find_map_any(|index| {
+            let mut nonces = Nonces::new(search, Partition { index, count });
+            while !found.load(Ordering::Relaxed) {
+                if let Some(nonce) = nonces.search(&midstate, &challenge.difficulty, BATCH) {
+                    found.store(true, Ordering::Relaxed);
+                    return Some(nonce);
+                }
+                hashes.fetch_add(BATCH, Ordering::Relaxed);
+            }
+            None
+        })
+    });
+
+    Ok(Solution {
+        nonce: nonce.expect("workers only stop once a solution is found"),
+        hashes: hashes.load(Ordering::Relaxed),
+        elapsed: start.elapsed(),
+    })
+}
+
+fn run(args: Args) -> Result<(), Error> {
+    let challenge = match &args.url {
+        Some(url) => fetch(url, args.host.as_deref())?,
+        None => {
+            let mut body = String::new();
+            std::io::stdin().read_to_string(&mut body)?;
+            serde_json::from_str(&body)?
+        }
+    };
+    let version = challenge.version.unwrap_or(Version::V1);
+    let path = match (args.path, &args.url) {
+        (Some(path), _) => path,
+        (None, Some(url)) => path_and_query(url),
+        (None, None) => return Err(Error::MissingPath),
+    };
+    let host = match (args.host, &args.url) {
+        (Some(host), _) => host,
+        (None, Some(url)) => authority(url).unwrap_or_default(),
+        (None, None) if version == Version::V1 => String::new(),
+        (None, None) => return Err(Error::MissingHost(version.into())),
+    };
+
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("failed to get timestamp")
+        .as_secs();
+    let pre_image = version.pre_image(&challenge.current, timestamp, &host, &path);
+    let threads = args.threads.unwrap_or(0);
+    let solution = solve(&challenge, &pre_image, threads, args.search.into())?;
+    eprintln!(
+        "solved in {:.2?} after about {} hashes, {:.0} H/s",
+        solution.elapsed,
+        solution.hashes,
+        solution.hashes as f64 / solution.elapsed.as_secs_f64().max(1e-3)
+    );
+
+    let headers = [
+        (protocol::BASE, format!("{:x}", challenge.current)),
+        (protocol::TIMESTAMP, timestamp.to_string()),
+        (
+            protocol::NONCE,
+            format!("{:x}", FixedByteArray::from(&solution.nonce)),
+        ),
+        (protocol::VERSION, u8::from(version).to_string()),
+    ];
+    if args.json {
+        let object: serde_json::Map<String, serde_json::Value> = headers
+            .into_iter()
+            .map(|(name, value)| (name.to_string(), value.into()))
+            .collect();
+        println!("{}", serde_json::Value::Object(object));
+    } else {
+        for (name, value) in headers {
+            println!("{}: {}", name, value);
+        }
+    }
+    Ok(())
+}
+
+fn main() {
+    if let Err(e) = run(Args::parse()) {
+        eprintln!("error: {}", e);
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn solves() {
+        let challenge: Challenge = serde_json::from_str(
+            r#"{
+                "current": "000000000000000000015beb8839f715806b45a0895367e702b14e782afccfdd",
+                "difficulty": "000fffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff",
+                "version": 2,
+                "error": "Too Many Requests",
+                "message": "Access restriction triggered"
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(challenge.version, Some(Version::V2));
+        let pre_image = Version::V2.pre_image(&challenge.current, 1718000000, "example.com", "/ip");
+        for search in [Search::Random, Search::Sequential] {
+            let solution = solve(&challenge, &pre_image, 3, search).unwrap();
+            assert!(protocol::is_solution(
+                &pre_image,
+                &challenge.difficulty,
+                &solution.nonce
+            ));
+        }
+    }
+
+    #[test]
+    fn request_of_url() {
+        let url: Url = "http://localhost:10000/ip?address=bc1p".parse().unwrap();
+        assert_eq!(authority(&url).as_deref(), Some("localhost:10000"));
+        assert_eq!(path_and_query(&url), "/ip?address=bc1p");
+        let url: Url = "https://example.com/".parse().unwrap();
+        assert_eq!(authority(&url).as_deref(), Some("example.com"));
+        assert_eq!(path_and_query(&url), "/");
+    }
+}
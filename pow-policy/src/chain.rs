@@ -0,0 +1,119 @@
+//! Evaluation of a [`Policy`] tree.
+//!
+//! The leaves are checked by [`Steps`] one after another, in the order of
+//! the configuration, so a step only runs when its result matters: `all`
+//! stops at the first rejection and `any` at the first pass. Steps may have
+//! side effects, e.g. the PoW filter counts every request it lets through.
+
+use std::future::Future;
+use std::pin::Pin;
+
+use pow_runtime::response::Response;
+
+use crate::config::Policy;
+
+/// Checks the leaves of a policy for one request.
+pub trait Steps {
+    /// Only called with `auth`, `pow` and `rate_limit` policies.
+    fn check(&self, step: &Policy) -> impl Future<Output = Result<(), Response>>;
+}
+
+/// Pass or reject the request with the first decisive response.
+pub fn evaluate<'a, S: Steps>(
+    policy: &'a Policy,
+    steps: &'a S,
+) -> Pin<Box<dyn Future<Output = Result<(), Response>> + 'a>> {
+    Box::pin(async move {
+        match policy {
+            Policy::All(policies) => {
+                for policy in policies {
+                    evaluate(policy, steps).await?;
+                }
+                Ok(())
+            }
+            Policy::Any(policies) => {
+                let mut rejection = None;
+                for policy in policies {
+                    match evaluate(policy, steps).await {
+                        Ok(()) => return Ok(()),
+                        Err(response) => rejection = Some(response),
+                    }
+                }
+                rejection.map_or(Ok(()), Err)
+            }
+            step => steps.check(step).await,
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::RefCell;
+
+    use futures::executor::block_on;
+
+    use super::*;
+
+    /// Rejects the steps in `rejected` with their index as status.
+    struct Recorder {
+        rejected: Vec<Policy>,
+        checked: RefCell<Vec<String>>,
+    }
+
+    impl Steps for Recorder {
+        async fn check(&self, step: &Policy) -> Result<(), Response> {
+            self.checked.borrow_mut().push(format!("{:?}", step));
+            match self.rejected.iter().position(|rejected| rejected == step) {
+                Some(i) => Err(Response::builder().status(400 + i as u32).build()),
+                None => Ok(()),
+            }
+        }
+    }
+
+    fn run(policy: &Policy, rejected: Vec<Policy>) -> (Result<(), u32>, Vec<String>) {
+        let recorder = Recorder {
+            rejected,
+            checked: RefCell::new(vec![]),
+        };
+        let ret = block_on(evaluate(policy, &recorder)).map_err(|response| response.code);
+        (ret, recorder.checked.into_inner())
+    }
+
+    #[test]
+    fn all_stops_at_first_rejection() {
+        let policy = Policy::All(vec![Policy::Auth, Policy::Pow]);
+        assert_eq!(
+            run(&policy, vec![]),
+            (Ok(()), vec!["Auth".into(), "Pow".into()])
+        );
+        assert_eq!(
+            run(&policy, vec![Policy::Auth]),
+            (Err(400), vec!["Auth".to_string()])
+        );
+    }
+
+    #[test]
+    fn any_stops_at_first_pass() {
+        let policy = Policy::Any(vec![Policy::Auth, Policy::Pow]);
+        assert_eq!(run(&policy, vec![]), (Ok(()), vec!["Auth".to_string()]));
+        assert_eq!(
+            run(&policy, vec![Policy::Auth]),
+            (Ok(()), vec!["Auth".into(), "Pow".into()])
+        );
+        // the PoW challenge tells the client what to do next
+        assert_eq!(run(&policy, vec![Policy::Auth, Policy::Pow]).0, Err(401));
+        assert_eq!(run(&Policy::Any(vec![]), vec![]), (Ok(()), vec![]));
+    }
+
+    #[test]
+    fn nested() {
+        let policy = Policy::All(vec![
+            Policy::Any(vec![Policy::Auth, Policy::Pow]),
+            Policy::Auth,
+        ]);
+        assert_eq!(
+            run(&policy, vec![Policy::Auth]),
+            (Err(400), vec!["Auth".into(), "Pow".into(), "Auth".into()])
+        );
+    }
+}
@@ -0,0 +1,145 @@
+use pow_runtime::failure_mode::FailureMode;
+use pow_runtime::log_level::LogLevel;
+use pow_types::config::VirtualHost;
+use pow_waf::config::{FingerprintSource, RateLimit, RateLimitKey};
+use serde::{Deserialize, Serialize};
+
+/// What a request must pass, see [`crate::chain`].
+#[derive(Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Policy {
+    /// Every policy in order, the first rejection rejects the request.
+    All(Vec<Policy>),
+    /// The first policy that passes, in order. Without one the rejection of
+    /// the last policy rejects the request, an empty list passes.
+    Any(Vec<Policy>),
+    /// The embedded auth filter.
+    Auth,
+    /// The embedded PoW filter.
+    Pow,
+    /// At most this many requests per key, rejected with 429 beyond.
+    RateLimit(RateLimitPolicy),
+}
+
+#[derive(Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct RateLimitPolicy {
+    #[serde(flatten)]
+    pub limit: RateLimit,
+    /// The client address by default, `auth_identity` reads the
+    /// `principal_header`.
+    #[serde(default)]
+    pub key: RateLimitKey,
+}
+
+impl Policy {
+    /// Whether `step` is part of the policy.
+    pub fn uses(&self, step: &Policy) -> bool {
+        match self {
+            Policy::All(policies) | Policy::Any(policies) => {
+                policies.iter().any(|policy| policy.uses(step))
+            }
+            policy => policy == step,
+        }
+    }
+}
+
+#[derive(Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct RouteSetting {
+    #[serde(with = "serde_yaml::with::singleton_map_recursive")]
+    pub policy: Policy,
+    /// Overrides the filter wide `failure_mode` for this route.
+    pub failure_mode: Option<FailureMode>,
+}
+
+fn default_principal_header() -> String {
+    "X-Auth-Principal".to_string()
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct Config<T> {
+    pub virtual_hosts: Vec<VirtualHost<T>>,
+    pub log_level: Option<LogLevel>,
+    /// Configuration of the auth filter, required by the `auth` policy.
+    /// Its own routes still decide what it checks.
+    pub auth: Option<serde_yaml::Value>,
+    /// Configuration of the PoW filter, required by the `pow` policy.
+    pub pow: Option<serde_yaml::Value>,
+    /// Must match `principal_header` of the auth configuration for the
+    /// `auth_identity` rate limit key.
+    #[serde(default = "default_principal_header")]
+    pub principal_header: String,
+    /// Where to read TLS fingerprints from for the `tls_fingerprint` rate
+    /// limit key, without one it falls back to the client address.
+    pub fingerprint: Option<FingerprintSource>,
+    /// Applied to errors of the `rate_limit` policy on routes without their
+    /// own, the embedded filters have their own.
+    #[serde(default)]
+    pub failure_mode: FailureMode,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pow_waf::config::TimeUnit;
+
+    #[test]
+    fn route_policy() {
+        let route: RouteSetting = serde_yaml::from_str(
+            r#"
+policy:
+  all:
+  - auth
+  - any:
+    - pow
+    - rate_limit:
+        unit: minute
+        requests_per_unit: 60
+        key: auth_identity
+"#,
+        )
+        .expect("failed to parse route");
+        let rate_limit = Policy::RateLimit(RateLimitPolicy {
+            limit: RateLimit {
+                unit: TimeUnit::Minute,
+                requests_per_unit: 60,
+            },
+            key: RateLimitKey::AuthIdentity,
+        });
+        assert_eq!(
+            route.policy,
+            Policy::All(vec![
+                Policy::Auth,
+                Policy::Any(vec![Policy::Pow, rate_limit]),
+            ])
+        );
+        assert!(route.policy.uses(&Policy::Auth));
+        assert!(route.policy.uses(&Policy::Pow));
+        assert!(!Policy::Any(vec![Policy::Auth]).uses(&Policy::Pow));
+    }
+
+    #[test]
+    fn config() {
+        let config: Config<RouteSetting> = serde_yaml::from_str(
+            r#"
+auth:
+  virtual_hosts: []
+virtual_hosts:
+- host: api.example.com
+  routes:
+  - path: /
+    policy:
+      any: [auth, pow]
+    failure_mode: fail_open
+"#,
+        )
+        .expect("failed to parse config");
+        let route = &config.virtual_hosts[0].routes[0];
+        assert_eq!(
+            route.config.policy,
+            Policy::Any(vec![Policy::Auth, Policy::Pow])
+        );
+        assert_eq!(route.config.failure_mode, Some(FailureMode::FailOpen));
+        assert!(config.auth.is_some());
+        assert_eq!(config.pow, None);
+    }
+}
@@ -0,0 +1,470 @@
+//! The auth and PoW filters in one filter, combined per route by a
+//! [`Policy`](config::Policy) instead of by their order in the proxy.
+//!
+//! ```yaml
+//! auth: { ... }   # configuration of pow-auth
+//! pow: { ... }    # configuration of pow-waf
+//! virtual_hosts:
+//! - host: api.example.com
+//!   routes:
+//!   - path: /
+//!     policy:
+//!       any:
+//!       - auth
+//!       - all:
+//!         - rate_limit: { unit: minute, requests_per_unit: 600 }
+//!         - pow
+//! ```
+//!
+//! Requests no route matches are let through.
+
+pub mod chain;
+pub mod config;
+
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use chain::Steps;
+use config::{Config, Policy, RateLimitPolicy, RouteSetting};
+use pow_runtime::counter_bucket::CounterBucket;
+use pow_runtime::failure_mode::FailureMode;
+use pow_runtime::filter::Filter;
+use pow_runtime::filter_state::State;
+use pow_runtime::response::{Response, ResponseHeaders};
+use pow_runtime::stream_info::StreamInfo;
+use pow_runtime::{metrics, singleton, Ctx, HttpHook, Runtime};
+use pow_types::config::Router;
+use pow_waf::config::{FingerprintSource, RateLimitKey};
+use pow_waf::fingerprint::Fingerprint;
+use proxy_wasm::types::LogLevel;
+
+pow_runtime::filter!(Inner, LogLevel::Trace);
+
+struct Inner {
+    router: Router<RouteSetting>,
+    counter_bucket: CounterBucket,
+    failure_mode: FailureMode,
+    principal_header: String,
+    fingerprint: Option<FingerprintSource>,
+    /// The embedded filters, configured anew with each configuration.
+    auth: Option<pow_auth::Plugin>,
+    pow: Option<pow_waf::Plugin>,
+}
+
+/// Configure an embedded filter with its section of the configuration.
+fn configure<R: Runtime>(
+    name: &str,
+    plugin: &mut R,
+    section: &serde_yaml::Value,
+) -> Result<(), String> {
+    let bytes = serde_yaml::to_string(section)
+        .map_err(|e| format!("failed to serialize {} configuration: {}", name, e))?
+        .into_bytes();
+    if !plugin.on_configure(Some(bytes)) {
+        return Err(format!("invalid {} configuration", name));
+    }
+    Ok(())
+}
+
+impl Filter for Inner {
+    const NAME: &'static str = "Policy filter";
+    type Config = Config<RouteSetting>;
+    type Hook = Hook;
+
+    fn configure(context_id: u32, config: Self::Config) -> Result<Self, String> {
+        let uses = |step: &Policy| {
+            config.virtual_hosts.iter().any(|virtual_host| {
+                let mut routes: Vec<_> = virtual_host.routes.iter().collect();
+                while let Some(route) = routes.pop() {
+                    if route.config.policy.uses(step) {
+                        return true;
+                    }
+                    routes.extend(route.children.iter().flatten());
+                }
+                false
+            })
+        };
+        if config.auth.is_none() && uses(&Policy::Auth) {
+            return Err("a route uses the auth policy, but auth is not configured".to_string());
+        }
+        if config.pow.is_none() && uses(&Policy::Pow) {
+            return Err("a route uses the pow policy, but pow is not configured".to_string());
+        }
+
+        // the embedded filters set the log level of their own configuration
+        let mut auth = None;
+        if let Some(section) = &config.auth {
+            let mut plugin = pow_auth::Plugin::new(context_id);
+            configure("auth", &mut plugin, section)?;
+            auth = Some(plugin);
+        }
+        let mut pow = None;
+        if let Some(section) = &config.pow {
+            let mut plugin = pow_waf::Plugin::new(context_id);
+            configure("pow", &mut plugin, section)?;
+            pow = Some(plugin);
+        }
+        proxy_wasm::set_log_level(config.log_level.map(Into::into).unwrap_or(LogLevel::Trace));
+
+        let router: Router<RouteSetting> = config
+            .virtual_hosts
+            .try_into()
+            .map_err(|e| format!("failed to convert configuration: {}", e))?;
+        Ok(Inner {
+            router,
+            counter_bucket: singleton::get_or_init("policy:rate_limit", || {
+                CounterBucket::new(context_id, "policy:rate_limit")
+            }),
+            failure_mode: config.failure_mode,
+            principal_header: config.principal_header,
+            fingerprint: config.fingerprint,
+            auth,
+            pow,
+        })
+    }
+
+    fn hook(ctx: Ctx, plugin: State<Self>) -> Hook {
+        let context_id = ctx.id();
+        Hook {
+            auth: plugin
+                .auth
+                .as_ref()
+                .and_then(|auth| auth.create_http_context(context_id)),
+            pow: plugin
+                .pow
+                .as_ref()
+                .and_then(|pow| pow.create_http_context(context_id)),
+            ctx,
+            plugin,
+            response_headers: ResponseHeaders::default(),
+        }
+    }
+}
+
+pub struct Hook {
+    ctx: Ctx,
+    plugin: State<Inner>,
+    auth: Option<pow_auth::Hook>,
+    pow: Option<pow_waf::Hook>,
+    response_headers: ResponseHeaders,
+}
+
+/// The request the steps of a route are checked for.
+struct Request<'a> {
+    hook: &'a Hook,
+    num_headers: usize,
+    end_of_stream: bool,
+    addr: SocketAddr,
+    /// Counter key of the route, without the bucket and subject.
+    route: String,
+    failure_mode: FailureMode,
+}
+
+impl Steps for Request<'_> {
+    async fn check(&self, step: &Policy) -> Result<(), Response> {
+        let hook = self.hook;
+        match step {
+            Policy::Auth => {
+                let auth = hook.auth.as_ref().expect("auth policy without auth filter");
+                auth.on_request_headers(self.num_headers, self.end_of_stream)
+                    .await
+                    .map_err(Into::into)
+            }
+            Policy::Pow => {
+                let pow = hook.pow.as_ref().expect("pow policy without pow filter");
+                pow.on_request_headers(self.num_headers, self.end_of_stream)
+                    .await
+                    .map_err(Into::into)
+            }
+            Policy::RateLimit(rate_limit) => self.rate_limit(rate_limit),
+            Policy::All(_) | Policy::Any(_) => unreachable!("evaluated by the chain"),
+        }
+    }
+}
+
+impl Request<'_> {
+    fn rate_limit(&self, policy: &RateLimitPolicy) -> Result<(), Response> {
+        let plugin = &self.hook.plugin;
+        let fingerprint = match (&policy.key, &plugin.fingerprint) {
+            (RateLimitKey::TlsFingerprint, Some(source)) => {
+                match Fingerprint::lookup(&self.hook.ctx, source) {
+                    Ok(fingerprint) => fingerprint.id(),
+                    Err(s) => {
+                        return self
+                            .infra_failure(&format!("{:?}: failed to read TLS fingerprint", s))
+                    }
+                }
+            }
+            _ => None,
+        };
+        let subject =
+            policy
+                .key
+                .subject(self.addr.ip(), &plugin.principal_header, fingerprint, |name| {
+                    self.hook.ctx.get_http_request_header(name)
+                });
+        let subject = match subject {
+            Ok(subject) => subject,
+            Err(s) => return self.infra_failure(&format!("{:?}: failed to get rate limit key", s)),
+        };
+        let rate_limit = &policy.limit;
+        let key = format!("{}:{}:{}", subject, rate_limit.current_bucket(), self.route);
+        let counter = match plugin.counter_bucket.get(&key) {
+            Ok(counter) => counter,
+            Err(e) => return self.infra_failure(&format!("failed to get counter: {}", e)),
+        };
+        let limit = rate_limit.requests_per_bucket(1);
+        if counter >= limit {
+            let reset = rate_limit.bucket_reset_shrunk(1);
+            let body = serde_json::json!({ "error": "Too many requests" });
+            return Err(Response::builder()
+                .status(429)
+                .header("Retry-After", reset.to_string())
+                .body("application/json", body.to_string())
+                .build());
+        }
+        plugin.counter_bucket.inc(&key, 1);
+        Ok(())
+    }
+
+    fn infra_failure(&self, error: &str) -> Result<(), Response> {
+        metrics::increment_counter(
+            &format!("pow_policy.infra_failure.{}", self.failure_mode.as_str()),
+            1,
+        );
+        match self.failure_mode {
+            FailureMode::FailOpen => {
+                log::warn!("let request through on infrastructure error: {}", error);
+                Ok(())
+            }
+            FailureMode::FailClosed => Err(Response::builder().status(500).text(error).build()),
+        }
+    }
+}
+
+impl Hook {
+    fn request_header(&self, key: &str) -> Result<String, Response> {
+        self.ctx
+            .get_http_request_header(key)
+            .map_err(|s| {
+                Response::builder()
+                    .status(500)
+                    .text(format!("{:?}: failed to get header {}", s, key))
+                    .build()
+            })?
+            .ok_or_else(|| {
+                Response::builder()
+                    .status(403)
+                    .text(format!("missing header: {}", key))
+                    .build()
+            })
+    }
+
+    fn client_address(&self) -> Result<SocketAddr, Response> {
+        let addr = self
+            .ctx
+            .get_client_address()
+            .map_err(|s| {
+                Response::builder()
+                    .status(500)
+                    .text(format!("{:?}: failed to get client address", s))
+                    .build()
+            })?
+            .unwrap_or_default();
+        addr.parse().map_err(|e| {
+            Response::builder()
+                .status(403)
+                .text(format!("invalid client address {}: {}", addr, e))
+                .build()
+        })
+    }
+}
+
+impl HttpHook for Hook {
+    fn filter_name() -> Option<&'static str> {
+        Some("Policy")
+    }
+
+    /// The body limits of the PoW routes apply under a policy too.
+    fn on_request_body(&self, body_size: usize, end_of_stream: bool) -> Result<(), Response> {
+        match &self.pow {
+            Some(pow) => pow.on_request_body(body_size, end_of_stream),
+            None => Ok(()),
+        }
+    }
+
+    fn request_deadline(&self) -> Option<Duration> {
+        self.pow.as_ref().and_then(HttpHook::request_deadline)
+    }
+
+    fn on_log(&self, info: &StreamInfo) {
+        // the embedded filters count requests out of flight once done
+        if let Some(auth) = &self.auth {
+            auth.on_log(info);
+        }
+        if let Some(pow) = &self.pow {
+            pow.on_log(info);
+        }
+    }
+
+    fn response_headers(&self) -> Vec<(String, String)> {
+        let mut headers = self.response_headers.take();
+        if let Some(auth) = &self.auth {
+            headers.extend(auth.response_headers());
+        }
+        if let Some(pow) = &self.pow {
+            headers.extend(pow.response_headers());
+        }
+        headers
+    }
+
+    async fn on_request_headers(
+        &self,
+        num_headers: usize,
+        end_of_stream: bool,
+    ) -> Result<(), impl Into<Response>> {
+        let host = self.request_header(":authority")?;
+        let path = self.request_header(":path")?;
+        let Some(found) = self.plugin.router.matches(&host, &path) else {
+            log::debug!("no matched route found, skip policy");
+            return Ok(());
+        };
+        let addr = self.client_address()?;
+        let request = Request {
+            hook: self,
+            num_headers,
+            end_of_stream,
+            addr,
+            route: format!("{}{}", host, found.pattern()),
+            failure_mode: found.failure_mode.unwrap_or(self.plugin.failure_mode),
+        };
+        chain::evaluate(&found.policy, &request).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::ops::Range;
+    use std::time::Duration;
+
+    use pow_runtime::filter::FilterRoot;
+    use pow_runtime::RuntimeBox;
+    use proxy_wasm::traits::RootContext;
+    use test_host::{HttpResponse, Vm, HOST};
+
+    use super::Inner;
+
+    const CONFIG: &str = r#"
+pow:
+  difficulty: 0
+  mempool_upstream_name: mempool
+  virtual_hosts:
+  - host: example.com
+    routes:
+    - path: /
+      rate_limit: { unit: minute, requests_per_unit: 600 }
+      concurrency: { max_in_flight: 1 }
+virtual_hosts:
+- host: example.com
+  routes:
+  - path: /
+    policy: pow
+"#;
+
+    const GET: &[(&str, &str)] = &[
+        (":method", "GET"),
+        (":authority", "example.com"),
+        (":path", "/"),
+    ];
+
+    fn root(context_id: u32) -> Box<dyn RootContext> {
+        Box::new(RuntimeBox::new(FilterRoot::<Inner>::new(context_id)))
+    }
+
+    /// Answer the block hash poll, and give the requests of `contexts` a
+    /// client address.
+    fn serve(contexts: Range<u32>) {
+        HOST.with(|host| {
+            let mut host = host.borrow_mut();
+            let tip = "00000000000000000002a7c4c1e48d76c5a37902165a270156b7a8d72728a054";
+            host.responses
+                .insert("mempool".to_string(), HttpResponse::new(200, tip));
+            for context_id in contexts {
+                host.properties.insert(
+                    (context_id, "source.address".to_string()),
+                    b"203.0.113.7:4242".to_vec(),
+                );
+            }
+        });
+    }
+
+    #[test]
+    fn in_flight_through_policy() {
+        let mut vm = Vm::start(root);
+        assert!(vm.configure(CONFIG.as_bytes()));
+        serve(2..6);
+        // the latest block hash arrives
+        vm.tick();
+        let rejected = |context_id| {
+            HOST.with(|host| host.borrow().local_responses.contains(&(context_id, 429)))
+        };
+
+        let first = vm.request(GET);
+        let second = vm.request(GET);
+        vm.tick();
+        assert!(!rejected(first));
+        assert!(rejected(second));
+
+        // both are counted out once done, the next request is in flight alone
+        vm.done(first);
+        vm.done(second);
+        let third = vm.request(GET);
+        vm.tick();
+        assert!(!rejected(third));
+        assert!(HOST.with(|host| host.borrow().continued.contains(&third)));
+        // write the counted requests while the host is still up
+        vm.run_for(Duration::from_millis(1100));
+    }
+
+    #[test]
+    fn rules_see_the_normalized_path() {
+        let config = r#"
+pow:
+  difficulty: 0
+  mempool_upstream_name: mempool
+  rules:
+  - name: wp_login
+    targets: [path]
+    pattern: ^/wp-login
+  virtual_hosts:
+  - host: example.com
+    routes:
+    - path: /*
+      rate_limit: { unit: minute, requests_per_unit: 600 }
+virtual_hosts:
+- host: example.com
+  routes:
+  - path: /*
+    policy: pow
+"#;
+        let mut vm = Vm::start(root);
+        assert!(vm.configure(config.as_bytes()));
+        serve(2..5);
+        vm.tick();
+        let blocked = |context_id| {
+            HOST.with(|host| host.borrow().local_responses.contains(&(context_id, 403)))
+        };
+
+        for path in ["/wp-login.php", "/x/../wp-login.php", "/x/%2e%2e/wp-login.php"] {
+            let request = vm.request(&[
+                (":method", "GET"),
+                (":authority", "example.com"),
+                (":path", path),
+            ]);
+            vm.tick();
+            assert!(blocked(request), "{} was let through", path);
+            vm.done(request);
+        }
+        vm.run_for(Duration::from_millis(1100));
+    }
+}
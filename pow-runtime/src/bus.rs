@@ -0,0 +1,287 @@
+//! Typed messages between contexts over host shared queues.
+//!
+//! A topic is a shared queue carrying [`Codec`] encoded messages. Any context
+//! may [`publish`](MessageBus::publish) to it, a [`Subscription`] dequeues
+//! the messages on the VM that registered the topic, woken through
+//! `on_queue_ready` like [`lock`](crate::lock) waiters. The host hands each
+//! message to one dequeuer only, so subscribe to a topic once per VM.
+//!
+//! A [`Broadcast`] delivers every message to every subscribing worker instead,
+//! each subscriber registers a queue of its own and lists it in shared data.
+
+use std::marker::PhantomData;
+
+use proxy_wasm::hostcalls;
+use proxy_wasm::types::Status;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::capabilities;
+use crate::codec::{Codec, DefaultCodec};
+use crate::kv_store::{self, KVStore};
+use crate::lock::{queue_ready, QueueId};
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("Status: [{status:?}]: {description}")]
+    Status { status: Status, description: String },
+    #[error("Failed to decode/encode message: {0}")]
+    Codec(#[from] Box<dyn std::error::Error>),
+}
+
+impl Error {
+    pub fn status(status: Status, description: impl Into<String>) -> Self {
+        Self::Status {
+            status,
+            description: description.into(),
+        }
+    }
+}
+
+impl From<kv_store::Error> for Error {
+    fn from(e: kv_store::Error) -> Self {
+        match e {
+            kv_store::Error::Status {
+                status,
+                description,
+            } => Self::Status {
+                status,
+                description,
+            },
+            kv_store::Error::Codec(e) => Self::Codec(e),
+        }
+    }
+}
+
+/// A topic of messages of type `T` encoded with `C`, see [`crate::codec`].
+pub struct MessageBus<T, C = DefaultCodec> {
+    queue_id: QueueId,
+    _phantom: PhantomData<(T, C)>,
+}
+
+impl<T, C> Clone for MessageBus<T, C> {
+    fn clone(&self) -> Self {
+        Self {
+            queue_id: self.queue_id,
+            _phantom: PhantomData,
+        }
+    }
+}
+
+impl<T, C> MessageBus<T, C>
+where
+    T: Serialize + DeserializeOwned,
+    C: Codec,
+{
+    /// Register the topic `name` of this VM, or get it if already registered.
+    pub fn register_topic(name: &str) -> Result<Self, Error> {
+        if !capabilities::get().shared_queue {
+            return Err(Error::status(
+                Status::InternalFailure,
+                format!("host lacks shared queues for topic {}", name),
+            ));
+        }
+        let queue_id = hostcalls::register_shared_queue(name)
+            .map_err(|s| Error::status(s, format!("failed to register topic {}", name)))?;
+        Ok(Self {
+            queue_id: QueueId(queue_id),
+            _phantom: PhantomData,
+        })
+    }
+
+    /// The topic `name` registered by the VM `vm_id`, to publish to it.
+    pub fn resolve_topic(vm_id: &str, name: &str) -> Result<Option<Self>, Error> {
+        let queue_id = hostcalls::resolve_shared_queue(vm_id, name)
+            .map_err(|s| Error::status(s, format!("failed to resolve topic {}", name)))?;
+        Ok(queue_id.map(|queue_id| Self {
+            queue_id: QueueId(queue_id),
+            _phantom: PhantomData,
+        }))
+    }
+
+    pub fn publish(&self, message: &T) -> Result<(), Error> {
+        let bytes = C::encode(message).map_err(|e| Error::Codec(e.into()))?;
+        hostcalls::enqueue_shared_queue(self.queue_id.0, Some(&bytes))
+            .map_err(|s| Error::status(s, "failed to publish message"))
+    }
+
+    pub fn subscribe(&self) -> Subscription<T, C> {
+        Subscription {
+            queue_id: self.queue_id,
+            _phantom: PhantomData,
+        }
+    }
+}
+
+pub struct Subscription<T, C = DefaultCodec> {
+    queue_id: QueueId,
+    _phantom: PhantomData<(T, C)>,
+}
+
+impl<T, C> Subscription<T, C>
+where
+    T: DeserializeOwned,
+    C: Codec,
+{
+    /// The next message, waiting for one to be published if the topic is
+    /// empty. A message that fails to decode is returned as an error and
+    /// dropped.
+    pub async fn next(&mut self) -> Result<T, Error> {
+        loop {
+            let message = hostcalls::dequeue_shared_queue(self.queue_id.0)
+                .map_err(|s| Error::status(s, "failed to dequeue message"))?;
+            match message {
+                Some(bytes) => return C::decode(&bytes).map_err(|e| Error::Codec(e.into())),
+                None => queue_ready(self.queue_id).await,
+            }
+        }
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Subscribers {
+    /// Suffix of the next subscriber queue name.
+    next: u32,
+    queues: Vec<u32>,
+}
+
+/// A topic whose messages reach every subscriber, not just one per VM.
+pub struct Broadcast<T, C = DefaultCodec> {
+    name: String,
+    subscribers: KVStore<Subscribers, C>,
+    _phantom: PhantomData<T>,
+}
+
+impl<T, C> Broadcast<T, C>
+where
+    T: Serialize + DeserializeOwned,
+    C: Codec,
+{
+    pub fn new(context_id: u32, name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            subscribers: KVStore::new(context_id, &format!("{}:subscribers", name)),
+            _phantom: PhantomData,
+        }
+    }
+
+    /// Register a queue of this worker's own and list it as a subscriber.
+    pub fn subscribe(&self) -> Result<Subscription<T, C>, Error> {
+        let suffix = self
+            .subscribers
+            .update("", |subscribers| {
+                let mut subscribers = subscribers.unwrap_or_default();
+                subscribers.next += 1;
+                Ok::<_, Error>(subscribers)
+            })?
+            .next;
+        let bus = MessageBus::<T, C>::register_topic(&format!("{}.{}", self.name, suffix))?;
+        self.subscribers.update("", |subscribers| {
+            let mut subscribers = subscribers.unwrap_or_default();
+            subscribers.queues.push(bus.queue_id.0);
+            Ok::<_, Error>(subscribers)
+        })?;
+        Ok(bus.subscribe())
+    }
+
+    /// Enqueue `message` to every subscriber, including this worker if it
+    /// subscribed, and return how many were reached. Queues the host no
+    /// longer knows, e.g. of a VM that was replaced, are unlisted.
+    pub fn publish(&self, message: &T) -> Result<usize, Error> {
+        let bytes = C::encode(message).map_err(|e| Error::Codec(e.into()))?;
+        let subscribers = self.subscribers.get("")?.unwrap_or_default();
+        let mut gone = vec![];
+        for &queue_id in &subscribers.queues {
+            match hostcalls::enqueue_shared_queue(queue_id, Some(&bytes)) {
+                Ok(()) => {}
+                Err(Status::NotFound) => gone.push(queue_id),
+                Err(s) => return Err(Error::status(s, "failed to publish message")),
+            }
+        }
+        if !gone.is_empty() {
+            self.subscribers.update("", |subscribers| {
+                let mut subscribers = subscribers.unwrap_or_default();
+                subscribers
+                    .queues
+                    .retain(|queue_id| !gone.contains(queue_id));
+                Ok::<_, Error>(subscribers)
+            })?;
+        }
+        Ok(subscribers.queues.len() - gone.len())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use test_host::HOST;
+
+    use super::*;
+    use crate::lock::wake_tasks;
+    use crate::{queue, spawn_local};
+
+    fn tick() {
+        queue::QUEUE.with(|queue| queue.on_tick());
+    }
+
+    #[test]
+    fn publish_subscribe() {
+        let bus: MessageBus<String> = MessageBus::register_topic("blocks").unwrap();
+        let again = MessageBus::<String>::register_topic("blocks").unwrap();
+        assert_eq!(again.queue_id.0, bus.queue_id.0);
+        let received = Rc::new(RefCell::new(vec![]));
+
+        let mut subscription = bus.subscribe();
+        let sink = received.clone();
+        spawn_local(async move {
+            while let Ok(message) = subscription.next().await {
+                sink.borrow_mut().push(message);
+            }
+        });
+        tick();
+        assert!(received.borrow().is_empty());
+
+        bus.publish(&"000000a1".to_string()).unwrap();
+        bus.publish(&"000000b2".to_string()).unwrap();
+        tick();
+        assert!(received.borrow().is_empty(), "waits for on_queue_ready");
+
+        wake_tasks(bus.queue_id);
+        tick();
+        assert_eq!(*received.borrow(), vec!["000000a1", "000000b2"]);
+    }
+
+    #[test]
+    fn broadcast() {
+        let topic: Broadcast<String> = Broadcast::new(1, "blocks");
+        let mut workers = vec![topic.subscribe().unwrap(), topic.subscribe().unwrap()];
+        assert_ne!(workers[0].queue_id.0, workers[1].queue_id.0);
+
+        assert_eq!(topic.publish(&"000000a1".to_string()).unwrap(), 2);
+        for worker in &workers {
+            wake_tasks(worker.queue_id);
+        }
+        let received = Rc::new(RefCell::new(vec![]));
+        for mut subscription in workers.drain(..) {
+            let sink = received.clone();
+            spawn_local(async move {
+                if let Ok(message) = subscription.next().await {
+                    sink.borrow_mut().push(message);
+                }
+            });
+        }
+        tick();
+        assert_eq!(*received.borrow(), vec!["000000a1", "000000a1"]);
+
+        // a replaced VM's queue is gone from the host
+        HOST.with(|host| {
+            host.borrow_mut()
+                .queues
+                .retain(|name, _| name != "blocks.1")
+        });
+        assert_eq!(topic.publish(&"000000b2".to_string()).unwrap(), 1);
+        assert_eq!(topic.publish(&"000000c3".to_string()).unwrap(), 1);
+    }
+}
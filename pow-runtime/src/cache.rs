@@ -0,0 +1,175 @@
+//! A read-through cache of a [`KVStore`] on each worker, for keys read on
+//! every request but rarely written, like a kill switch.
+//!
+//! Values are kept for a `ttl` and dropped early when any worker writes
+//! them: writes through a [`CachedKVStore`] broadcast the key on the topic
+//! `<prefix>:invalidate`, see [`Broadcast`]. Without shared queues the cache
+//! only expires, so a write takes up to `ttl` to reach the other workers.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::{Rc, Weak};
+use std::time::Duration;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::bus::{self, Broadcast};
+use crate::codec::{Codec, DefaultCodec};
+use crate::kv_store::{Error, KVStore};
+use crate::timeout::Deadline;
+use crate::{capabilities, spawn_local};
+
+/// Cached values by key, absent ones included.
+type Entries<V> = HashMap<String, (Option<V>, Deadline)>;
+
+pub struct CachedKVStore<V, C = DefaultCodec> {
+    store: KVStore<V, C>,
+    ttl: Duration,
+    entries: Rc<RefCell<Entries<V>>>,
+    topic: Option<Broadcast<String, C>>,
+}
+
+impl<V, C> CachedKVStore<V, C>
+where
+    V: Serialize + DeserializeOwned + Clone + 'static,
+    C: Codec + 'static,
+{
+    pub fn new(context_id: u32, prefix: &str, ttl: Duration) -> Self {
+        let entries = Rc::new(RefCell::new(HashMap::new()));
+        let topic = if capabilities::get().shared_queue {
+            let topic = Broadcast::new(context_id, &format!("{}:invalidate", prefix));
+            match topic.subscribe() {
+                Ok(subscription) => {
+                    spawn_local(invalidate(subscription, Rc::downgrade(&entries)));
+                    Some(topic)
+                }
+                Err(e) => {
+                    log::warn!("failed to subscribe to {} writes: {}", prefix, e);
+                    None
+                }
+            }
+        } else {
+            capabilities::degraded(
+                "shared queues",
+                "cached values are read again only when they expire",
+            );
+            None
+        };
+        Self {
+            store: KVStore::new(context_id, prefix),
+            ttl,
+            entries,
+            topic,
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Result<Option<V>, Error> {
+        if let Some((value, deadline)) = self.entries.borrow().get(key) {
+            if !deadline.is_expired() {
+                return Ok(value.clone());
+            }
+        }
+        let value = self.store.get(key)?;
+        self.entries
+            .borrow_mut()
+            .insert(key.to_string(), (value.clone(), Deadline::after(self.ttl)));
+        Ok(value)
+    }
+
+    pub fn put(&self, key: &str, value: &V) -> Result<(), Error> {
+        self.store.put(key, value)?;
+        self.invalidate(key);
+        Ok(())
+    }
+
+    pub fn remove(&self, key: &str) -> Result<(), Error> {
+        self.store.remove(key)?;
+        self.invalidate(key);
+        Ok(())
+    }
+
+    /// See [`KVStore::update`].
+    pub fn update<F, E>(&self, key: &str, f: F) -> Result<V, E>
+    where
+        F: FnMut(Option<V>) -> Result<V, E>,
+        E: From<Error>,
+    {
+        let value = self.store.update(key, f)?;
+        self.invalidate(key);
+        Ok(value)
+    }
+
+    /// Drop `key` from the cache of every worker.
+    pub fn invalidate(&self, key: &str) {
+        self.entries.borrow_mut().remove(key);
+        let Some(topic) = &self.topic else {
+            return;
+        };
+        if let Err(e) = topic.publish(&key.to_string()) {
+            log::warn!("failed to announce write of {}: {}", key, e);
+        }
+    }
+}
+
+async fn invalidate<V, C>(
+    mut subscription: bus::Subscription<String, C>,
+    entries: Weak<RefCell<Entries<V>>>,
+) where
+    C: Codec,
+{
+    loop {
+        let written = subscription.next().await;
+        let Some(entries) = entries.upgrade() else {
+            break;
+        };
+        match written {
+            Ok(key) => {
+                entries.borrow_mut().remove(&key);
+            }
+            Err(e @ bus::Error::Codec(_)) => {
+                log::warn!("dropped write announcement: {}", e);
+                entries.borrow_mut().clear();
+            }
+            Err(e) => {
+                log::warn!("stop listening for write announcements: {}", e);
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use test_host::HOST;
+
+    use super::*;
+    use crate::lock::{wake_tasks, QueueId};
+    use crate::queue;
+
+    fn deliver() {
+        let queues: Vec<u32> = HOST.with(|host| host.borrow().queues.values().copied().collect());
+        for queue_id in queues {
+            wake_tasks(QueueId(queue_id));
+        }
+        queue::QUEUE.with(|queue| queue.on_tick());
+    }
+
+    #[test]
+    fn invalidated_by_writes() {
+        let worker: CachedKVStore<bool> = CachedKVStore::new(1, "switch", Duration::from_secs(60));
+        let other: CachedKVStore<bool> = CachedKVStore::new(1, "switch", Duration::from_secs(60));
+        assert_eq!(worker.get("").unwrap(), None);
+        KVStore::<bool>::new(1, "switch").put("", &false).unwrap();
+        assert_eq!(worker.get("").unwrap(), None, "served from the cache");
+
+        other.put("", &true).unwrap();
+        assert_eq!(
+            worker.get("").unwrap(),
+            None,
+            "until the write is announced"
+        );
+        deliver();
+        assert_eq!(worker.get("").unwrap(), Some(true));
+    }
+}
@@ -0,0 +1,154 @@
+//! Host capabilities, probed once on VM start.
+//!
+//! proxy-wasm hosts differ in what they implement, older Envoy releases and
+//! other proxies answer some hostcalls with `Unimplemented`, a status the SDK
+//! does not know and panics on. [`probe`] tries each family of hostcalls
+//! through the raw ABI, the runtime checks [`get`] before using one and logs
+//! once how the feature degrades when the host lacks it, see [`crate::host`].
+
+use std::cell::{Cell, RefCell};
+use std::collections::HashSet;
+use std::fmt::Display;
+use std::ptr::null_mut;
+
+use proxy_wasm::types::MetricType;
+
+use crate::host::{abi, free, implemented, STATUS_OK};
+
+const PROBE_NAME: &str = "pow_runtime.capabilities";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capabilities {
+    /// Lets hooks act on their request after resuming from a tick.
+    pub effective_context: bool,
+    /// Wakes lock waiters and listeners across workers.
+    pub shared_queue: bool,
+    /// Request and plugin properties, e.g. the client address.
+    pub properties: bool,
+    pub foreign_functions: bool,
+    pub metrics: bool,
+}
+
+/// Everything is assumed available until probed.
+impl Default for Capabilities {
+    fn default() -> Self {
+        Self {
+            effective_context: true,
+            shared_queue: true,
+            properties: true,
+            foreign_functions: true,
+            metrics: true,
+        }
+    }
+}
+
+impl Display for Capabilities {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let yes_no = |available: bool| if available { "yes" } else { "no" };
+        write!(
+            f,
+            "effective_context={} shared_queue={} properties={} foreign_functions={} metrics={}",
+            yes_no(self.effective_context),
+            yes_no(self.shared_queue),
+            yes_no(self.properties),
+            yes_no(self.foreign_functions),
+            yes_no(self.metrics)
+        )
+    }
+}
+
+thread_local! {
+    static CAPABILITIES: Cell<Option<Capabilities>> = const { Cell::new(None) };
+    static DEGRADED: RefCell<HashSet<&'static str>> = RefCell::new(HashSet::new());
+}
+
+/// Probe the host and remember the result for [`get`].
+pub fn probe() -> Capabilities {
+    // no context has id 0, a host with effective contexts rejects it
+    let effective_context = implemented(unsafe { abi::proxy_set_effective_context(0) });
+
+    let mut id = 0;
+    let shared_queue =
+        unsafe { abi::proxy_register_shared_queue(PROBE_NAME.as_ptr(), PROBE_NAME.len(), &mut id) }
+            == STATUS_OK;
+
+    let path = b"plugin_root_id";
+    let (mut data, mut size) = (null_mut(), 0);
+    let properties = unsafe {
+        let status = abi::proxy_get_property(path.as_ptr(), path.len(), &mut data, &mut size);
+        free(data, size);
+        implemented(status)
+    };
+
+    // no host defines it, a host with foreign functions answers NotFound
+    let (mut data, mut size) = (null_mut(), 0);
+    let foreign_functions = unsafe {
+        let status = abi::proxy_call_foreign_function(
+            PROBE_NAME.as_ptr(),
+            PROBE_NAME.len(),
+            null_mut(),
+            0,
+            &mut data,
+            &mut size,
+        );
+        free(data, size);
+        implemented(status)
+    };
+
+    let metrics = unsafe {
+        abi::proxy_define_metric(
+            MetricType::Gauge,
+            PROBE_NAME.as_ptr(),
+            PROBE_NAME.len(),
+            &mut id,
+        )
+    } == STATUS_OK;
+
+    let capabilities = Capabilities {
+        effective_context,
+        shared_queue,
+        properties,
+        foreign_functions,
+        metrics,
+    };
+    CAPABILITIES.with(|cell| cell.set(Some(capabilities)));
+    capabilities
+}
+
+/// The probed capabilities, see [`Capabilities::default`] before the probe.
+pub fn get() -> Capabilities {
+    CAPABILITIES.with(|cell| cell.get()).unwrap_or_default()
+}
+
+/// Record a capability found missing after the probe.
+pub(crate) fn update(f: impl FnOnce(&mut Capabilities)) {
+    let mut capabilities = get();
+    f(&mut capabilities);
+    CAPABILITIES.with(|cell| cell.set(Some(capabilities)));
+}
+
+/// Log, once per `consequence`, that the host lacks `capability` and what
+/// happens instead.
+pub fn degraded(capability: &str, consequence: &'static str) {
+    if DEGRADED.with(|logged| logged.borrow_mut().insert(consequence)) {
+        log::warn!("host lacks {}, {}", capability, consequence);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn banner() {
+        assert_eq!(get(), Capabilities::default());
+        let capabilities = Capabilities {
+            foreign_functions: false,
+            ..Default::default()
+        };
+        assert_eq!(
+            capabilities.to_string(),
+            "effective_context=yes shared_queue=yes properties=yes foreign_functions=no metrics=yes"
+        );
+    }
+}
@@ -0,0 +1,129 @@
+//! Where the runtime and filters get the time from, see [`now`].
+//!
+//! In wasm every reading is a hostcall to the [`HostClock`]. Tests install a
+//! [`TestClock`] on their thread instead and move it by hand, so expiries,
+//! rate limit windows and timestamp checks run deterministically:
+//!
+//! ```ignore
+//! let clock = TestClock::new(UNIX_EPOCH + Duration::from_secs(1_700_000_000));
+//! let _guard = clock::install(clock.clone());
+//! clock.advance(Duration::from_secs(61));
+//! ```
+
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub trait Clock {
+    fn now(&self) -> SystemTime;
+}
+
+/// The time of the proxy, the system time in native builds, which have no
+/// host unless linked with one.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct HostClock;
+
+impl Clock for HostClock {
+    #[cfg(target_arch = "wasm32")]
+    fn now(&self) -> SystemTime {
+        proxy_wasm::hostcalls::get_current_time().unwrap_or_else(|_| SystemTime::now())
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// A clock that only moves when told to, shared by its clones.
+#[derive(Debug, Clone)]
+pub struct TestClock(Rc<Cell<SystemTime>>);
+
+impl TestClock {
+    pub fn new(at: SystemTime) -> Self {
+        Self(Rc::new(Cell::new(at)))
+    }
+
+    pub fn set(&self, at: SystemTime) {
+        self.0.set(at);
+    }
+
+    pub fn advance(&self, by: Duration) {
+        self.0.set(self.0.get() + by);
+    }
+}
+
+impl Clock for TestClock {
+    fn now(&self) -> SystemTime {
+        self.0.get()
+    }
+}
+
+thread_local! {
+    static CLOCK: RefCell<Rc<dyn Clock>> = RefCell::new(Rc::new(HostClock));
+}
+
+/// Restores the clock installed before, see [`install`].
+#[must_use = "the clock is restored when the guard is dropped"]
+pub struct Installed {
+    previous: Option<Rc<dyn Clock>>,
+}
+
+impl Drop for Installed {
+    fn drop(&mut self) {
+        if let Some(previous) = self.previous.take() {
+            let _ = CLOCK.try_with(|clock| *clock.borrow_mut() = previous);
+        }
+    }
+}
+
+/// Read the time from `clock` on this thread until the guard is dropped.
+pub fn install(clock: impl Clock + 'static) -> Installed {
+    let previous = CLOCK.with(|current| current.replace(Rc::new(clock)));
+    Installed {
+        previous: Some(previous),
+    }
+}
+
+pub fn now() -> SystemTime {
+    CLOCK.with(|clock| clock.borrow().now())
+}
+
+/// Seconds since the Unix epoch.
+pub fn unix_secs() -> u64 {
+    unix_millis() / 1000
+}
+
+/// Milliseconds since the Unix epoch.
+pub fn unix_millis() -> u64 {
+    now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+/// Time passed since `earlier`, zero if the clock went back.
+pub fn since(earlier: SystemTime) -> Duration {
+    now().duration_since(earlier).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_clock() {
+        let clock = TestClock::new(UNIX_EPOCH + Duration::from_secs(1_700_000_000));
+        {
+            let _guard = install(clock.clone());
+            assert_eq!(unix_secs(), 1_700_000_000);
+            clock.advance(Duration::from_millis(1_500));
+            assert_eq!(unix_millis(), 1_700_000_001_500);
+            assert_eq!(
+                since(UNIX_EPOCH + Duration::from_secs(1_700_000_000)),
+                Duration::from_millis(1_500)
+            );
+        }
+        assert!(unix_secs() > 1_700_000_000 + 86_400);
+    }
+}
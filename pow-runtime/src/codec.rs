@@ -1,40 +1,110 @@
-use serde::{de::DeserializeOwned, Serialize};
+//! How shared data and bus messages are turned into bytes.
+//!
+//! Every [`KVStore`](crate::kv_store::KVStore),
+//! [`SharedDataLock`](crate::lock::SharedDataLock) and bus picks its codec by
+//! a type parameter, [`DefaultCodec`] unless named, so stores with different
+//! codecs live side by side in one binary:
+//!
+//! ```ignore
+//! let sessions: KVStore<Session, JsonCodec> = KVStore::new(context_id, "session:");
+//! let counters: KVStore<u64> = KVStore::new(context_id, "counter:");
+//! ```
+//!
+//! The codec is part of the format of the shared data, VMs sharing a key must
+//! agree on it.
 
+use serde::{de::DeserializeOwned, Serialize};
 
 pub trait Codec {
-	type Error;
-	fn encode(&self) -> Result<Vec<u8>, Self::Error>;
-	fn decode(value: &[u8]) -> Result<Self, Self::Error> where Self: Sized;
+	type Error: std::error::Error + 'static;
+	fn encode<V: Serialize>(value: &V) -> Result<Vec<u8>, Self::Error>;
+	fn decode<V: DeserializeOwned>(value: &[u8]) -> Result<V, Self::Error>;
 }
 
-#[cfg(feature = "serde_json")]
-impl <V> Codec for V
-where
-	V: Serialize + DeserializeOwned 
-{
+/// JSON, readable when inspecting shared data.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct JsonCodec;
+
+impl Codec for JsonCodec {
 	type Error = serde_json::Error;
-	
-	fn encode(&self) -> Result<Vec<u8>, Self::Error> {
-			serde_json::to_vec(self)
+
+	fn encode<V: Serialize>(value: &V) -> Result<Vec<u8>, Self::Error> {
+		serde_json::to_vec(value)
 	}
-	
-	fn decode(value: &[u8]) -> Result<Self, Self::Error> where Self: Sized {
-			serde_json::from_slice(value)
+
+	fn decode<V: DeserializeOwned>(value: &[u8]) -> Result<V, Self::Error> {
+		serde_json::from_slice(value)
 	}
 }
 
 #[cfg(feature = "bincode")]
-impl <V> Codec for V
-where
-	V: Serialize + DeserializeOwned 
-{
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BincodeCodec;
+
+#[cfg(feature = "bincode")]
+impl Codec for BincodeCodec {
 	type Error = bincode::Error;
-	
-	fn encode(&self) -> Result<Vec<u8>, Self::Error> {
-			bincode::serialize(self)
+
+	fn encode<V: Serialize>(value: &V) -> Result<Vec<u8>, Self::Error> {
+		bincode::serialize(value)
+	}
+
+	fn decode<V: DeserializeOwned>(value: &[u8]) -> Result<V, Self::Error> {
+		bincode::deserialize(value)
+	}
+}
+
+/// Varint encoded, the most compact of the codecs.
+#[cfg(feature = "postcard")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PostcardCodec;
+
+#[cfg(feature = "postcard")]
+impl Codec for PostcardCodec {
+	type Error = postcard::Error;
+
+	fn encode<V: Serialize>(value: &V) -> Result<Vec<u8>, Self::Error> {
+		postcard::to_allocvec(value)
+	}
+
+	fn decode<V: DeserializeOwned>(value: &[u8]) -> Result<V, Self::Error> {
+		postcard::from_bytes(value)
 	}
-	
-	fn decode(value: &[u8]) -> Result<Self, Self::Error> where Self: Sized {
-			bincode::deserialize(value)
+}
+
+/// The codec of stores that do not name one: JSON with the `serde_json`
+/// feature, otherwise bincode with the `bincode` feature, JSON without
+/// either.
+#[cfg(any(feature = "serde_json", not(feature = "bincode")))]
+pub type DefaultCodec = JsonCodec;
+#[cfg(all(feature = "bincode", not(feature = "serde_json")))]
+pub type DefaultCodec = BincodeCodec;
+
+#[cfg(test)]
+mod test {
+	use serde::Deserialize;
+
+	use super::*;
+
+	#[derive(Debug, PartialEq, Serialize, Deserialize)]
+	struct Session {
+		id: String,
+		hits: u64,
+	}
+
+	fn round_trip<C: Codec>() -> Vec<u8> {
+		let session = Session { id: "s1".to_string(), hits: 300 };
+		let bytes = C::encode(&session).unwrap();
+		assert_eq!(C::decode::<Session>(&bytes).unwrap(), session);
+		bytes
+	}
+
+	#[test]
+	fn codecs() {
+		assert_eq!(round_trip::<JsonCodec>(), br#"{"id":"s1","hits":300}"#);
+		#[cfg(feature = "bincode")]
+		assert_eq!(round_trip::<BincodeCodec>().len(), 8 + 2 + 8);
+		#[cfg(feature = "postcard")]
+		assert_eq!(round_trip::<PostcardCodec>().len(), 1 + 2 + 2);
 	}
 }
@@ -1,8 +1,10 @@
-use std::{collections::HashMap, sync::{Arc, Mutex}, time::Duration};
+use std::{collections::HashMap, sync::{Arc, Mutex, Weak}, time::{Duration, SystemTime}};
 
 use thiserror::Error;
 
-use super::{kv_store::ExpiringKVStore, spawn_local, timeout::sleep};
+use super::clock;
+use super::kv_store::{Capacity, ExpiringKVStore};
+use super::{memory::{entry_size, Account}, spawn_local, timeout::sleep};
 
 
 #[derive(Clone)]
@@ -10,10 +12,56 @@ pub struct CounterBucket {
     inner: Arc<Mutex<Inner>>,
 }
 
+struct Slot {
+    value: u64,
+    touched: u64,
+}
+
 struct Inner {
     pub store: ExpiringKVStore<u64>,
-    pub buffer: HashMap<String, u64>,
-    pub stop: bool,
+    /// How long a counter is kept after its first write, forever if `None`.
+    pub ttl: Option<Duration>,
+    pub buffer: HashMap<String, Slot>,
+    pub account: Account,
+    pub clock: u64,
+    pub flushed_at: Option<SystemTime>,
+}
+
+impl Inner {
+    fn write(&mut self, key: &str, value: u64) {
+        let mut first = false;
+        let written = self.store.update(key, |old| {
+            first = old.is_none();
+            Ok::<_, Error>(old.unwrap_or(0) + value)
+        });
+        if let (Ok(_), true, Some(ttl)) = (written, first, self.ttl) {
+            let _ = self.store.enqueue_expires(key, ttl);
+        }
+    }
+
+    /// Past the cap, flush the least recently counted keys early until the
+    /// buffer is back to 3/4 of the cap.
+    fn evict(&mut self) -> usize {
+        let mut excess = self.account.excess_over(0.75);
+        let mut keys: Vec<(u64, String)> = self.buffer
+            .iter()
+            .map(|(key, slot)| (slot.touched, key.clone()))
+            .collect();
+        keys.sort_unstable();
+        let mut evicted = 0;
+        for (_, key) in keys {
+            if excess == 0 {
+                break;
+            }
+            let Some(slot) = self.buffer.remove(&key) else { continue };
+            self.write(&key, slot.value);
+            let size = entry_size::<Slot>(&key);
+            self.account.release(size);
+            excess = excess.saturating_sub(size);
+            evicted += 1;
+        }
+        evicted
+    }
 }
 
 #[derive(Debug, Error)]
@@ -22,65 +70,174 @@ pub enum Error {
     KV(#[from] super::kv_store::Error),
 }
 
-impl Drop for CounterBucket {
+/// The background task ends with the last handle, the increments since its
+/// last flush are written then.
+impl Drop for Inner {
     fn drop(&mut self) {
-        let mut lock = self.inner.lock().expect("failed to lock inner");
-        lock.stop = true;
+        let buffer: Vec<(String, Slot)> = self.buffer.drain().collect();
+        for (key, slot) in buffer {
+            self.write(&key, slot.value);
+        }
     }
 }
 
 impl CounterBucket {
     pub fn new(context_id: u32, prefix: &str) -> Self {
+        Self::with_account(context_id, prefix, Account::new(format!("{}.buffer_bytes", prefix), None))
+    }
+
+    /// Like [`new`](Self::new), the buffered increments are accounted to
+    /// `account` and flushed early when it exceeds its cap.
+    pub fn with_account(context_id: u32, prefix: &str, account: Account) -> Self {
+        Self::start(ExpiringKVStore::new(context_id, prefix), None, account)
+    }
+
+    /// Like [`with_account`](Self::with_account), the counters are indexed
+    /// for [`list_keys`](Self::list_keys) and [`clear`](Self::clear) and
+    /// expire `ttl` after their first write, so the index does not grow
+    /// without bound. With a `capacity` the least recently written counters
+    /// are evicted before they expire.
+    pub fn indexed(
+        context_id: u32,
+        prefix: &str,
+        account: Account,
+        ttl: Duration,
+        capacity: Option<Capacity>,
+    ) -> Self {
+        let mut store = ExpiringKVStore::indexed(context_id, prefix);
+        if let Some(capacity) = capacity {
+            store = store.capped(capacity);
+        }
+        Self::start(store, Some(ttl), account)
+    }
+
+    fn start(store: ExpiringKVStore<u64>, ttl: Option<Duration>, account: Account) -> Self {
         let ret = Self {
             inner: Arc::new(Mutex::new(Inner {
-                store: ExpiringKVStore::new(context_id, prefix),
+                store,
+                ttl,
                 buffer: HashMap::new(),
-                stop: false,
+                account,
+                clock: 0,
+                flushed_at: None,
             }))
         };
-        let ret_clone = ret.clone();
-        spawn_local(async move {
-            ret_clone.background_task().await
-        });
+        spawn_local(Self::background_task(Arc::downgrade(&ret.inner)));
         ret
     }
 
-    fn clone(&self) -> Self {
-        Self {
-            inner: self.inner.clone()
-        }
-    }
-
     pub fn inc(&self, key: &str, value: u64) {
         let mut inner = self.inner.lock().expect("failed to lock inner");
-        let counter = inner.buffer.entry(key.to_string()).or_insert(0);
-        *counter += value;
+        inner.clock += 1;
+        let touched = inner.clock;
+        match inner.buffer.get_mut(key) {
+            Some(slot) => {
+                slot.value += value;
+                slot.touched = touched;
+            }
+            None => {
+                inner.buffer.insert(key.to_string(), Slot { value, touched });
+                inner.account.charge(entry_size::<Slot>(key));
+                if inner.account.excess() > 0 {
+                    let evicted = inner.evict();
+                    log::debug!("counter buffer over its cap, flushed {} keys early", evicted);
+                }
+            }
+        }
     }
 
     pub fn get(&self, key: &str) -> Result<u64, Error> {
         let inner = self.inner.lock().expect("failed to lock inner");
         let counter = inner.store.get(key)?.unwrap_or(0);
-        let delta = inner.buffer.get(key).copied().unwrap_or(0);
+        let delta = inner.buffer.get(key).map(|slot| slot.value).unwrap_or(0);
         Ok(counter + delta)
     }
 
+    /// Raise the written counter of `key` to at least `value`, so counts
+    /// learned from elsewhere are adopted at most once however often they
+    /// are repeated.
+    pub fn raise(&self, key: &str, value: u64) -> Result<(), Error> {
+        let inner = self.inner.lock().expect("failed to lock inner");
+        let mut first = false;
+        inner.store.update(key, |old| {
+            first = old.is_none();
+            Ok::<_, Error>(old.unwrap_or(0).max(value))
+        })?;
+        if let (true, Some(ttl)) = (first, inner.ttl) {
+            inner.store.enqueue_expires(key, ttl)?;
+        }
+        Ok(())
+    }
+
     pub fn flush(&self) -> usize {
         let mut inner = self.inner.lock().expect("failed to lock inner");
-        let buffer: Vec<(String, u64)> = inner.buffer.drain().collect();
+        let buffer: Vec<(String, Slot)> = inner.buffer.drain().collect();
         let len = buffer.len();
-        for (key, value) in buffer {
-            let _ = inner.store.update(&key, |old| old.unwrap_or(0) + value);
+        for (key, slot) in buffer {
+            inner.write(&key, slot.value);
         }
+        inner.account.set(0);
+        inner.flushed_at = Some(clock::now());
         len
     }
 
-    pub async fn background_task(&self) {
+    /// Keys of counters starting with `prefix`, written or buffered, see
+    /// [`indexed`](Self::indexed).
+    pub fn list_keys(&self, prefix: &str) -> Result<Vec<String>, Error> {
+        let inner = self.inner.lock().expect("failed to lock inner");
+        let mut keys = inner.store.list_keys(prefix)?;
+        keys.extend(
+            inner.buffer
+                .keys()
+                .filter(|key| key.starts_with(prefix))
+                .cloned(),
+        );
+        keys.sort_unstable();
+        keys.dedup();
+        Ok(keys)
+    }
+
+    /// Reset the counters starting with `prefix`, including increments of
+    /// this worker not written yet, returns how many were written.
+    pub fn clear(&self, prefix: &str) -> Result<usize, Error> {
+        let mut inner = self.inner.lock().expect("failed to lock inner");
+        let buffered: Vec<String> = inner.buffer
+            .keys()
+            .filter(|key| key.starts_with(prefix))
+            .cloned()
+            .collect();
+        for key in buffered {
+            inner.buffer.remove(&key);
+            inner.account.release(entry_size::<Slot>(&key));
+        }
+        Ok(inner.store.clear(prefix)?)
+    }
+
+    /// Keys with increments not written to shared data yet.
+    pub fn pending(&self) -> usize {
+        self.inner.lock().expect("failed to lock inner").buffer.len()
+    }
+
+    /// How long increments have been buffered since the last flush, `None`
+    /// before the first one.
+    pub fn flush_lag(&self) -> Option<Duration> {
+        let flushed_at = self.inner.lock().expect("failed to lock inner").flushed_at?;
+        Some(clock::since(flushed_at))
+    }
+
+    async fn background_task(inner: Weak<Mutex<Inner>>) {
         loop {
             sleep(Duration::from_secs(1)).await;
-            let _flushed = self.flush();
-            if self.inner.lock().expect("failed to lock inner").stop {
+            let Some(inner) = inner.upgrade() else {
                 break;
+            };
+            let bucket = Self { inner };
+            {
+                // the peak of the last second, before the flush empties the buffer
+                let mut inner = bucket.inner.lock().expect("failed to lock inner");
+                inner.account.report();
             }
+            let _flushed = bucket.flush();
         }
     }
 }
@@ -0,0 +1,213 @@
+//! Why a hook stopped a request, shared by the filters so they answer the
+//! same way: a response of their own, a rejection rendered by the filter, or
+//! an internal failure answered with a `500`.
+//!
+//! Responses made here carry an [`ErrorBody`], e.g.
+//! `{"error": "forbidden", "message": "address denied"}`.
+
+use std::convert::Infallible;
+use std::fmt::Debug;
+
+use proxy_wasm::types::Status;
+use serde::{Deserialize, Serialize};
+
+use crate::response::{Problem, Response, ResponseBuilder};
+
+/// Renders a rejection as the response sent downstream.
+pub trait IntoResponse {
+    fn into_response(self) -> Response;
+}
+
+impl IntoResponse for Response {
+    fn into_response(self) -> Response {
+        self
+    }
+}
+
+impl IntoResponse for Problem {
+    fn into_response(self) -> Response {
+        self.into()
+    }
+}
+
+impl IntoResponse for Infallible {
+    fn into_response(self) -> Response {
+        match self {}
+    }
+}
+
+/// The JSON body of the responses made by [`Error`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ErrorBody {
+    /// Stable code of the error, e.g. `too_many_requests`.
+    pub error: String,
+    pub message: String,
+}
+
+impl ErrorBody {
+    fn response(status: u32, error: &str, message: impl Into<String>) -> ResponseBuilder {
+        let body = ErrorBody {
+            error: error.to_string(),
+            message: message.into(),
+        };
+        Response::builder().status(status).json(&body)
+    }
+}
+
+/// `R` is the rejection type of a filter, none by default.
+#[derive(Debug)]
+pub enum Error<R = Infallible> {
+    Status {
+        reason: String,
+        status: Status,
+    },
+    Response(Response),
+    Rejected(R),
+    Other {
+        reason: String,
+        error: Box<dyn std::error::Error>,
+    },
+}
+
+impl<R> Error<R> {
+    pub fn status(reason: impl Into<String>, status: Status) -> Self {
+        Error::Status {
+            reason: reason.into(),
+            status,
+        }
+    }
+
+    pub fn response(response: Response) -> Self {
+        Error::Response(response)
+    }
+
+    pub fn other(reason: impl Into<String>, error: impl Into<Box<dyn std::error::Error>>) -> Self {
+        Error::Other {
+            reason: reason.into(),
+            error: error.into(),
+        }
+    }
+
+    pub fn forbidden(message: impl Into<String>) -> Self {
+        Error::Response(ErrorBody::response(403, "forbidden", message).build())
+    }
+
+    pub fn unauthorized(message: impl Into<String>) -> Self {
+        Error::Response(ErrorBody::response(401, "unauthorized", message).build())
+    }
+
+    /// With a `Retry-After` of `retry_after` seconds if given.
+    pub fn too_many_requests(message: impl Into<String>, retry_after: Option<u64>) -> Self {
+        let mut response = ErrorBody::response(429, "too_many_requests", message);
+        if let Some(seconds) = retry_after {
+            response = response.header("Retry-After", seconds.to_string());
+        }
+        Error::Response(response.build())
+    }
+
+    /// A failure without an underlying error, answered with a `500`.
+    pub fn internal(reason: impl Into<String>) -> Self {
+        Error::other(reason, "internal error")
+    }
+
+    /// Whether the request failed on the filter rather than being rejected.
+    pub fn is_infrastructure(&self) -> bool {
+        !matches!(self, Error::Response(_) | Error::Rejected(_))
+    }
+}
+
+impl<R> From<Response> for Error<R> {
+    fn from(response: Response) -> Self {
+        Error::Response(response)
+    }
+}
+
+impl<R: IntoResponse + Debug> IntoResponse for Error<R> {
+    fn into_response(self) -> Response {
+        match self {
+            Error::Response(response) => {
+                log::debug!("reject request with response, {:?}", response.code);
+                response
+            }
+            Error::Rejected(rejection) => {
+                log::debug!("reject request, {:?}", rejection);
+                rejection.into_response()
+            }
+            Error::Status { reason, status } => {
+                let msg = format!("{:?}: {}", status, reason);
+                log::warn!("failed hostcall with error, {}", msg);
+                ErrorBody::response(500, "internal", msg).build()
+            }
+            Error::Other { reason, error } => {
+                let msg = format!("{}: {}", error, reason);
+                log::warn!("failed with error, {}", msg);
+                ErrorBody::response(500, "internal", msg).build()
+            }
+        }
+    }
+}
+
+impl<R: IntoResponse + Debug> From<Error<R>> for Response {
+    fn from(error: Error<R>) -> Self {
+        error.into_response()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn body(response: &Response) -> ErrorBody {
+        serde_json::from_slice(response.body.as_deref().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn error_response() {
+        let error: Error = Error::status("failed to get header", Status::NotFound);
+        let response = Response::from(error);
+        assert_eq!(response.code, 500);
+        assert_eq!(
+            body(&response),
+            ErrorBody {
+                error: "internal".to_string(),
+                message: "NotFound: failed to get header".to_string(),
+            }
+        );
+        let error: Error = Error::from(Response::builder().status(204).build());
+        let response = Response::from(error);
+        assert_eq!(response.code, 204);
+
+        let error: Error = Error::too_many_requests("slow down", Some(30));
+        assert!(!error.is_infrastructure());
+        let response = Response::from(error);
+        assert_eq!(response.code, 429);
+        assert!(response
+            .headers
+            .contains(&("Retry-After".to_string(), "30".to_string())));
+        assert_eq!(body(&response).error, "too_many_requests");
+
+        let error: Error = Error::internal("no upstream");
+        assert!(error.is_infrastructure());
+        assert_eq!(
+            body(&error.into_response()).message,
+            "internal error: no upstream"
+        );
+    }
+
+    #[test]
+    fn rejection() {
+        let error = Error::Rejected(Problem::new(
+            None,
+            "denied",
+            403,
+            "Denied",
+            "address denied",
+        ));
+        let response = Response::from(error);
+        assert_eq!(response.code, 403);
+        assert!(response.headers.contains(&(
+            "Content-Type".to_string(),
+            "application/problem+json".to_string()
+        )));
+    }
+}
@@ -0,0 +1,22 @@
+use serde::{Deserialize, Serialize};
+
+/// What a filter does when it cannot reach a decision because of an
+/// infrastructure error, e.g. a failed hostcall or unreadable shared data.
+#[derive(Debug, Default, Eq, PartialEq, Serialize, Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum FailureMode {
+    /// Let the request through.
+    FailOpen,
+    /// Reject the request with the error.
+    #[default]
+    FailClosed,
+}
+
+impl FailureMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FailureMode::FailOpen => "fail_open",
+            FailureMode::FailClosed => "fail_closed",
+        }
+    }
+}
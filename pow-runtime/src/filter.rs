@@ -0,0 +1,252 @@
+//! Scaffolding of a filter, so a new one only writes its state and hook:
+//! the root context, parsing the YAML (or JSON) configuration into a
+//! [`FilterState`] epoch per configuration. Hooks fail with an
+//! [`Error`](crate::error::Error). A filter of raw connections returns
+//! [`ContextType::StreamContext`] from [`Filter::context_type`] and creates
+//! its contexts in [`Filter::stream_context`].
+//!
+//! ```ignore
+//! #[derive(Deserialize)]
+//! struct GreeterConfig {
+//!     greeting: String,
+//! }
+//!
+//! struct Greeter {
+//!     greeting: String,
+//! }
+//!
+//! impl Filter for Greeter {
+//!     const NAME: &'static str = "greeter";
+//!     type Config = GreeterConfig;
+//!     type Hook = GreeterHook;
+//!
+//!     fn configure(_context_id: u32, config: GreeterConfig) -> Result<Self, String> {
+//!         Ok(Greeter { greeting: config.greeting })
+//!     }
+//!
+//!     fn hook(ctx: Ctx, state: State<Self>) -> GreeterHook {
+//!         GreeterHook { ctx, state }
+//!     }
+//! }
+//!
+//! pow_runtime::filter!(Greeter);
+//! ```
+
+use proxy_wasm::traits::{Context, StreamContext};
+use proxy_wasm::types::{ContextType, LogLevel};
+use serde::de::DeserializeOwned;
+
+use crate::filter_state::{FilterState, State};
+use crate::{Ctx, HttpHook, Runtime};
+
+/// The state of a filter made from each configuration.
+pub trait Filter: Sized + 'static {
+    /// Names the filter in logs.
+    const NAME: &'static str;
+    type Config: DeserializeOwned;
+    type Hook: HttpHook + 'static;
+
+    /// The log level to switch to with `config`, left as is if `None`.
+    fn log_level(_config: &Self::Config) -> Option<LogLevel> {
+        None
+    }
+
+    /// An error rejects the configuration, the current one stays in effect.
+    fn configure(context_id: u32, config: Self::Config) -> Result<Self, String>;
+
+    /// Called once `state` is in effect, e.g. to spawn the tasks of the epoch.
+    fn configured(_context_id: u32, _state: &State<Self>) {}
+
+    fn hook(ctx: Ctx, state: State<Self>) -> Self::Hook;
+
+    /// Wrap a [`StreamHook`](crate::stream::StreamHook) in a
+    /// [`StreamHookHolder`](crate::stream::StreamHookHolder).
+    fn stream_context(_context_id: u32, _state: State<Self>) -> Option<Box<dyn StreamContext>> {
+        None
+    }
+
+    fn context_type(&self) -> ContextType {
+        ContextType::HttpContext
+    }
+}
+
+/// The root context of a [`Filter`], see [`filter!`](crate::filter!).
+pub struct FilterRoot<F> {
+    context_id: u32,
+    state: FilterState<F>,
+}
+
+impl<F: Filter> FilterRoot<F> {
+    pub fn new(context_id: u32) -> Self {
+        Self {
+            context_id,
+            state: FilterState::new(),
+        }
+    }
+
+    pub fn state(&self) -> &FilterState<F> {
+        &self.state
+    }
+}
+
+impl<F> Context for FilterRoot<F> {}
+
+impl<F: Filter> Runtime for FilterRoot<F> {
+    type Hook = F::Hook;
+
+    fn on_vm_start(&mut self, _vm_configuration_size: usize) -> bool {
+        log::info!("{} starting...", F::NAME);
+        true
+    }
+
+    fn on_configure(&mut self, configuration: Option<Vec<u8>>) -> bool {
+        let Some(bytes) = configuration else {
+            log::error!("{}: missing configuration", F::NAME);
+            return false;
+        };
+        let config: F::Config = match serde_yaml::from_slice(&bytes) {
+            Ok(config) => config,
+            Err(e) => {
+                log::error!("{}: failed to parse configuration: {}", F::NAME, e);
+                return false;
+            }
+        };
+        if let Some(level) = F::log_level(&config) {
+            proxy_wasm::set_log_level(level);
+        }
+        match F::configure(self.context_id, config) {
+            Ok(state) => {
+                let state = self.state.swap(state);
+                F::configured(self.context_id, &state);
+                log::info!("{} configured, epoch {}", F::NAME, state.epoch());
+                true
+            }
+            Err(e) => {
+                log::error!("{}: failed to configure: {}", F::NAME, e);
+                false
+            }
+        }
+    }
+
+    fn create_http_context(&self, context_id: u32) -> Option<F::Hook> {
+        let Some(state) = self.state.enter() else {
+            log::warn!("{}: request before the first configuration", F::NAME);
+            return None;
+        };
+        Some(F::hook(Ctx::new(context_id), state))
+    }
+
+    fn create_stream_context(&self, context_id: u32) -> Option<Box<dyn StreamContext>> {
+        let Some(state) = self.state.enter() else {
+            log::warn!("{}: connection before the first configuration", F::NAME);
+            return None;
+        };
+        F::stream_context(context_id, state)
+    }
+
+    fn get_type(&self) -> Option<ContextType> {
+        let context_type = self.state.enter().map(|state| state.context_type());
+        Some(context_type.unwrap_or(ContextType::HttpContext))
+    }
+}
+
+/// The entry point of a wasm module with the [`Filter`] `$filter` as its
+/// root context, logging at `$level` (`Info` if left out) until configured.
+#[macro_export]
+macro_rules! filter {
+    ($filter:ty) => {
+        $crate::filter!($filter, $crate::__proxy_wasm::types::LogLevel::Info);
+    };
+    ($filter:ty, $level:expr) => {
+        $crate::__proxy_wasm::main! {{
+            $crate::__proxy_wasm::set_log_level($level);
+            $crate::__proxy_wasm::set_root_context(
+                |context_id| -> Box<dyn $crate::__proxy_wasm::traits::RootContext> {
+                    Box::new($crate::RuntimeBox::new(
+                        $crate::filter::FilterRoot::<$filter>::new(context_id),
+                    ))
+                },
+            );
+        }}
+    };
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::Cell;
+
+    use serde::Deserialize;
+
+    use super::*;
+    use crate::error::Error;
+    use crate::response::Response;
+
+    #[derive(Deserialize)]
+    struct GreeterConfig {
+        greeting: String,
+    }
+
+    struct Greeter {
+        greeting: String,
+        configured: Cell<bool>,
+    }
+
+    struct GreeterHook {
+        state: State<Greeter>,
+    }
+
+    impl HttpHook for GreeterHook {
+        async fn on_request_headers(
+            &self,
+            _num_headers: usize,
+            _end_of_stream: bool,
+        ) -> Result<(), impl Into<Response>> {
+            let greeting = self.state.greeting.clone();
+            Err::<(), Error>(Error::response(Response::builder().text(greeting).build()))
+        }
+    }
+
+    impl Filter for Greeter {
+        const NAME: &'static str = "greeter";
+        type Config = GreeterConfig;
+        type Hook = GreeterHook;
+
+        fn configure(_context_id: u32, config: GreeterConfig) -> Result<Self, String> {
+            if config.greeting.is_empty() {
+                return Err("empty greeting".to_string());
+            }
+            Ok(Greeter {
+                greeting: config.greeting,
+                configured: Cell::new(false),
+            })
+        }
+
+        fn configured(_context_id: u32, state: &State<Self>) {
+            state.configured.set(true);
+        }
+
+        fn hook(_ctx: Ctx, state: State<Self>) -> GreeterHook {
+            GreeterHook { state }
+        }
+    }
+
+    #[test]
+    fn configure() {
+        let mut root = FilterRoot::<Greeter>::new(1);
+        assert!(root.create_http_context(2).is_none());
+        assert!(!root.on_configure(None));
+        assert!(!root.on_configure(Some(b"greeting: [".to_vec())));
+
+        assert!(root.on_configure(Some(b"greeting: hello".to_vec())));
+        assert!(!root.on_configure(Some(br#"{"greeting": ""}"#.to_vec())));
+        let hook = root.create_http_context(2).unwrap();
+        assert_eq!(hook.state.epoch(), 1);
+        assert_eq!(hook.state.greeting, "hello");
+        assert!(hook.state.configured.get());
+        assert_eq!(root.get_type(), Some(ContextType::HttpContext));
+        assert!(root.create_stream_context(3).is_none());
+
+        assert!(root.on_configure(Some(br#"{"greeting": "hi"}"#.to_vec())));
+        assert_eq!(root.state().epoch(), Some(2));
+    }
+}
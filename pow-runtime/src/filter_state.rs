@@ -0,0 +1,169 @@
+//! Configured state of a filter, swapped on reconfigure.
+//!
+//! Every configuration is an epoch. A hook enters the current epoch when its
+//! context is created and holds it until the context ends, so one request is
+//! served by one configuration from start to end. [`FilterState::swap`] makes
+//! the new epoch current right away and retires the old one, which is torn
+//! down (dropped) on the root context once the last request holding it has
+//! finished, instead of inside whichever hook happens to release it last.
+
+use std::cell::{Cell, RefCell};
+use std::ops::Deref;
+use std::sync::{Arc, Weak};
+
+#[derive(Debug)]
+struct Epoch<T> {
+    id: u64,
+    state: T,
+}
+
+/// A hook's handle on the epoch it entered.
+#[derive(Debug)]
+pub struct State<T> {
+    epoch: Arc<Epoch<T>>,
+}
+
+impl<T> Clone for State<T> {
+    fn clone(&self) -> Self {
+        Self {
+            epoch: self.epoch.clone(),
+        }
+    }
+}
+
+impl<T> Deref for State<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.epoch.state
+    }
+}
+
+impl<T> State<T> {
+    pub fn epoch(&self) -> u64 {
+        self.epoch.id
+    }
+
+    /// A handle which does not keep the epoch from being torn down, for
+    /// background tasks that should stop with it.
+    pub fn downgrade(&self) -> WeakState<T> {
+        WeakState {
+            epoch: Arc::downgrade(&self.epoch),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct WeakState<T> {
+    epoch: Weak<Epoch<T>>,
+}
+
+impl<T> WeakState<T> {
+    /// `None` once the epoch is torn down.
+    pub fn upgrade(&self) -> Option<State<T>> {
+        self.epoch.upgrade().map(|epoch| State { epoch })
+    }
+}
+
+#[derive(Debug)]
+pub struct FilterState<T> {
+    next_id: Cell<u64>,
+    current: RefCell<Option<Arc<Epoch<T>>>>,
+    retired: RefCell<Vec<Arc<Epoch<T>>>>,
+}
+
+impl<T> Default for FilterState<T> {
+    fn default() -> Self {
+        Self {
+            next_id: Cell::new(1),
+            current: RefCell::new(None),
+            retired: RefCell::new(vec![]),
+        }
+    }
+}
+
+impl<T> FilterState<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Make `state` the current epoch and retire the previous one.
+    pub fn swap(&self, state: T) -> State<T> {
+        let id = self.next_id.get();
+        self.next_id.set(id + 1);
+        let epoch = Arc::new(Epoch { id, state });
+        if let Some(previous) = self.current.replace(Some(epoch.clone())) {
+            log::info!("retire filter state epoch {}", previous.id);
+            self.retired.borrow_mut().push(previous);
+        }
+        self.collect();
+        State { epoch }
+    }
+
+    /// Enter the current epoch, `None` before the first [`swap`](Self::swap).
+    pub fn enter(&self) -> Option<State<T>> {
+        self.collect();
+        let epoch = self.current.borrow().clone()?;
+        Some(State { epoch })
+    }
+
+    pub fn epoch(&self) -> Option<u64> {
+        self.current.borrow().as_ref().map(|epoch| epoch.id)
+    }
+
+    /// Tear down the retired epochs no request holds anymore, returns how
+    /// many are still draining.
+    pub fn collect(&self) -> usize {
+        let drained: Vec<_> = {
+            let mut retired = self.retired.borrow_mut();
+            let (drained, draining) = retired
+                .drain(..)
+                .partition(|epoch| Arc::strong_count(epoch) == 1);
+            *retired = draining;
+            drained
+        };
+        for epoch in drained {
+            log::info!("tear down filter state epoch {}", epoch.id);
+            drop(epoch);
+        }
+        self.retired.borrow().len()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    struct Teardown<'a>(&'a Cell<u32>);
+
+    impl Drop for Teardown<'_> {
+        fn drop(&mut self) {
+            self.0.set(self.0.get() + 1);
+        }
+    }
+
+    #[test]
+    fn old_epoch_drains() {
+        let dropped = Cell::new(0);
+        let state = FilterState::new();
+        assert!(state.enter().is_none());
+
+        state.swap(Teardown(&dropped));
+        let request = state.enter().expect("configured");
+        let background = request.downgrade();
+        assert_eq!(request.epoch(), 1);
+
+        state.swap(Teardown(&dropped));
+        assert_eq!(state.epoch(), Some(2));
+        assert_eq!(state.enter().map(|s| s.epoch()), Some(2));
+        assert_eq!(state.collect(), 1, "request still in flight");
+        assert_eq!(dropped.get(), 0);
+        assert!(background.upgrade().is_some());
+
+        drop(request);
+        assert_eq!(dropped.get(), 0, "torn down by the filter, not the hook");
+        assert_eq!(state.collect(), 0);
+        assert_eq!(dropped.get(), 1);
+        assert!(background.upgrade().is_none());
+    }
+}
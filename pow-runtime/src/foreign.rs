@@ -0,0 +1,259 @@
+//! Foreign functions, the extensions a host adds to the ABI, e.g. Envoy's
+//! `set_envoy_filter_state` or the bridges of its Lua and Go filters.
+//!
+//! [`call`] runs a function that answers right away. A function doing its
+//! work later answers with the id of an event instead, a little-endian
+//! `u32`, and the host emits `proxy_on_foreign_function` with that id and
+//! the result once done, which the SDK dispatches to `on_foreign_function`
+//! of the context and the contexts of the runtime hand to [`on_event`]. [`call_async`] returns a [`ForeignCall`] resolving
+//! with the result:
+//!
+//! ```no_run
+//! # async fn example() -> Result<(), pow_runtime::foreign::Error> {
+//! let verdict = pow_runtime::foreign::call_async("bot_score", b"10.0.0.1")?.await;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Hosts that never emit the event leave the call pending, bound it with a
+//! timeout.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::ptr::null_mut;
+use std::rc::Rc;
+use std::task::{Context, Poll, Waker};
+
+use crate::capabilities;
+use crate::host::{abi, free, STATUS_OK};
+
+const STATUS_NOT_FOUND: u32 = 1;
+const STATUS_UNIMPLEMENTED: u32 = 12;
+/// The buffer of `proxy_on_foreign_function`, `BufferType::CallData`.
+const BUFFER_CALL_DATA: u32 = 8;
+
+#[derive(Debug, thiserror::Error, Eq, PartialEq)]
+pub enum Error {
+    #[error("host lacks foreign functions")]
+    Unimplemented,
+    #[error("no foreign function {0}")]
+    NotFound(String),
+    #[error("foreign function {name} failed with status {status}")]
+    Failed { name: String, status: u32 },
+    #[error("foreign function {0} answered no event id")]
+    NoEventId(String),
+}
+
+/// Call the foreign function `name` with `arguments`, its result if any.
+pub fn call(name: &str, arguments: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+    if !capabilities::get().foreign_functions {
+        return Err(Error::Unimplemented);
+    }
+    let (mut data, mut size) = (null_mut(), 0);
+    let status = unsafe {
+        abi::proxy_call_foreign_function(
+            name.as_ptr(),
+            name.len(),
+            arguments.as_ptr(),
+            arguments.len(),
+            &mut data,
+            &mut size,
+        )
+    };
+    match status {
+        STATUS_OK if data.is_null() => Ok(None),
+        STATUS_OK => Ok(Some(unsafe { Vec::from_raw_parts(data, size, size) })),
+        STATUS_NOT_FOUND => Err(Error::NotFound(name.to_string())),
+        STATUS_UNIMPLEMENTED => {
+            capabilities::update(|capabilities| capabilities.foreign_functions = false);
+            Err(Error::Unimplemented)
+        }
+        status => {
+            unsafe { free(data, size) };
+            Err(Error::Failed {
+                name: name.to_string(),
+                status,
+            })
+        }
+    }
+}
+
+enum State {
+    Pending(Option<Waker>),
+    Done(Vec<u8>),
+    Gone,
+}
+
+thread_local! {
+    static PENDING: RefCell<HashMap<u32, Rc<RefCell<State>>>> = RefCell::new(HashMap::new());
+}
+
+/// The result of a foreign function, delivered by the event of its id.
+pub struct ForeignCall {
+    id: u32,
+    state: Rc<RefCell<State>>,
+}
+
+impl ForeignCall {
+    /// Wait for the event `id` of a function called some other way.
+    pub fn event(id: u32) -> Self {
+        let state = Rc::new(RefCell::new(State::Pending(None)));
+        let replaced =
+            PENDING.with(|pending| pending.borrow_mut().insert(id, state.clone()));
+        if replaced.is_some() {
+            log::warn!("foreign function event {} awaited twice", id);
+        }
+        ForeignCall { id, state }
+    }
+}
+
+impl Future for ForeignCall {
+    type Output = Vec<u8>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut state = self.state.borrow_mut();
+        match std::mem::replace(&mut *state, State::Gone) {
+            State::Pending(_) => {
+                *state = State::Pending(Some(cx.waker().clone()));
+                Poll::Pending
+            }
+            State::Done(data) => Poll::Ready(data),
+            State::Gone => panic!("polling a resolved foreign call"),
+        }
+    }
+}
+
+impl Drop for ForeignCall {
+    fn drop(&mut self) {
+        if matches!(*self.state.borrow(), State::Pending(_)) {
+            PENDING.with(|pending| {
+                let mut pending = pending.borrow_mut();
+                if pending
+                    .get(&self.id)
+                    .is_some_and(|state| Rc::ptr_eq(state, &self.state))
+                {
+                    pending.remove(&self.id);
+                }
+            });
+        }
+    }
+}
+
+/// Call the foreign function `name`, which answers the id of the event
+/// reporting its result.
+pub fn call_async(name: &str, arguments: &[u8]) -> Result<ForeignCall, Error> {
+    let id = call(name, arguments)?
+        .and_then(|result| <[u8; 4]>::try_from(result).ok())
+        .ok_or_else(|| Error::NoEventId(name.to_string()))?;
+    Ok(ForeignCall::event(u32::from_le_bytes(id)))
+}
+
+/// Resolve the call waiting for event `function_id`.
+fn complete(function_id: u32, data: Vec<u8>) {
+    let Some(state) = PENDING.with(|pending| pending.borrow_mut().remove(&function_id)) else {
+        log::debug!("foreign function event {} awaited by no call", function_id);
+        return;
+    };
+    let previous = state.replace(State::Done(data));
+    if let State::Pending(Some(waker)) = previous {
+        waker.wake();
+    }
+}
+
+/// Resolve the call waiting for the event `function_id` the host emitted
+/// with `data_size` bytes of result.
+pub(crate) fn on_event(function_id: u32, data_size: usize) {
+    let (mut data, mut size) = (null_mut(), 0);
+    let status = unsafe {
+        abi::proxy_get_buffer_bytes(BUFFER_CALL_DATA, 0, data_size, &mut data, &mut size)
+    };
+    let data = match status {
+        STATUS_OK if !data.is_null() => unsafe { Vec::from_raw_parts(data, size, size) },
+        STATUS_OK => vec![],
+        status => {
+            log::warn!(
+                "failed to read the data of foreign function event {}: status {}",
+                function_id,
+                status
+            );
+            unsafe { free(data, size) };
+            return;
+        }
+    };
+    complete(function_id, data);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use test_host::HOST;
+
+    #[test]
+    fn sync() {
+        HOST.with(|host| {
+            host.borrow_mut()
+                .foreign_functions
+                .insert("compress".to_string(), b"small".to_vec())
+        });
+        assert_eq!(call("compress", b"large").unwrap(), Some(b"small".to_vec()));
+        assert_eq!(
+            call("decompress", b""),
+            Err(Error::NotFound("decompress".to_string()))
+        );
+        assert_eq!(
+            HOST.with(|host| host.borrow().foreign_calls.clone()),
+            vec![
+                ("compress".to_string(), b"large".to_vec()),
+                ("decompress".to_string(), vec![])
+            ]
+        );
+    }
+
+    #[test]
+    fn event() {
+        HOST.with(|host| {
+            host.borrow_mut()
+                .foreign_functions
+                .insert("bot_score".to_string(), 7u32.to_le_bytes().to_vec())
+        });
+        let score = Rc::new(RefCell::new(None));
+        let call = call_async("bot_score", b"10.0.0.1").unwrap();
+        let result = score.clone();
+        crate::spawn_local(async move {
+            *result.borrow_mut() = Some(call.await);
+        });
+        crate::queue::QUEUE.with(|queue| queue.on_tick());
+        assert_eq!(*score.borrow(), None);
+
+        // another event, then the one of the call
+        for (id, data) in [(3, b"other"), (7, b"human")] {
+            HOST.with(|host| host.borrow_mut().call_data = Some(data.to_vec()));
+            on_event(id, data.len());
+        }
+        crate::queue::QUEUE.with(|queue| queue.on_tick());
+        assert_eq!(*score.borrow(), Some(b"human".to_vec()));
+
+        assert_eq!(
+            call_async("missing", b"").err(),
+            Some(Error::NotFound("missing".to_string()))
+        );
+        HOST.with(|host| {
+            host.borrow_mut()
+                .foreign_functions
+                .insert("now".to_string(), vec![])
+        });
+        assert_eq!(
+            call_async("now", b"").err(),
+            Some(Error::NoEventId("now".to_string()))
+        );
+    }
+
+    #[test]
+    fn dropped() {
+        let call = ForeignCall::event(9);
+        drop(call);
+        assert!(PENDING.with(|pending| pending.borrow().is_empty()));
+    }
+}
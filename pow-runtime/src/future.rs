@@ -0,0 +1,200 @@
+//! Combinators for the futures this runtime runs.
+//!
+//! The executor is single threaded, so unlike `futures::future` nothing here
+//! asks for `Send`: futures holding an `Rc` or a [`Ctx`](crate::Ctx) borrow
+//! can be joined and raced like any other. Every pending future is polled
+//! again whenever the combinator is, which is cheap with the handful of
+//! hostcalls a hook waits on.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use pin_project_lite::pin_project;
+
+use crate::timeout::{sleep, Timer};
+
+/// Future returned by [`join_all`].
+pub struct JoinAll<F: Future> {
+    futures: Vec<Option<Pin<Box<F>>>>,
+    outputs: Vec<Option<F::Output>>,
+}
+
+/// The futures are boxed and outputs are never pinned.
+impl<F: Future> Unpin for JoinAll<F> {}
+
+impl<F: Future> Future for JoinAll<F> {
+    type Output = Vec<F::Output>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let mut pending = false;
+        for (slot, output) in this.futures.iter_mut().zip(this.outputs.iter_mut()) {
+            let Some(future) = slot else { continue };
+            match future.as_mut().poll(cx) {
+                Poll::Ready(value) => {
+                    *output = Some(value);
+                    *slot = None;
+                }
+                Poll::Pending => pending = true,
+            }
+        }
+        if pending {
+            return Poll::Pending;
+        }
+        let outputs = std::mem::take(&mut this.outputs);
+        Poll::Ready(
+            outputs
+                .into_iter()
+                .map(|output| output.expect("polling a resolved join_all"))
+                .collect(),
+        )
+    }
+}
+
+/// Wait for all `futures`, their outputs in the order given.
+pub fn join_all<F: Future>(futures: impl IntoIterator<Item = F>) -> JoinAll<F> {
+    let futures: Vec<_> = futures.into_iter().map(|f| Some(Box::pin(f))).collect();
+    let outputs = futures.iter().map(|_| None).collect();
+    JoinAll { futures, outputs }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Either<A, B> {
+    Left(A),
+    Right(B),
+}
+
+pin_project! {
+    /// Future returned by [`select2`].
+    pub struct Select2<A, B> {
+        #[pin]
+        left: A,
+        #[pin]
+        right: B,
+    }
+}
+
+impl<A: Future, B: Future> Future for Select2<A, B> {
+    type Output = Either<A::Output, B::Output>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        if let Poll::Ready(value) = this.left.poll(cx) {
+            return Poll::Ready(Either::Left(value));
+        }
+        if let Poll::Ready(value) = this.right.poll(cx) {
+            return Poll::Ready(Either::Right(value));
+        }
+        Poll::Pending
+    }
+}
+
+/// The output of whichever future finishes first, `left` when both are
+/// ready on the same poll. The other future is dropped with the combinator.
+pub fn select2<A: Future, B: Future>(left: A, right: B) -> Select2<A, B> {
+    Select2 { left, right }
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("future timed out after {0:?}")]
+pub struct Elapsed(pub Duration);
+
+pin_project! {
+    /// Future returned by [`race_with_timeout`].
+    pub struct RaceWithTimeout<F> {
+        #[pin]
+        race: Select2<F, Timer>,
+        duration: Duration,
+    }
+}
+
+impl<F: Future> Future for RaceWithTimeout<F> {
+    type Output = Result<F::Output, Elapsed>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        match this.race.poll(cx) {
+            Poll::Ready(Either::Left(value)) => Poll::Ready(Ok(value)),
+            Poll::Ready(Either::Right(())) => Poll::Ready(Err(Elapsed(*this.duration))),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+/// `future`, or [`Elapsed`] if it is not done within `duration`. Unlike
+/// [`timeout`](crate::timeout::timeout) the output may be of any type.
+pub fn race_with_timeout<F: Future>(future: F, duration: Duration) -> RaceWithTimeout<F> {
+    RaceWithTimeout {
+        race: select2(future, sleep(duration)),
+        duration,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::promise::Promise;
+    use crate::response::Response;
+    use crate::{queue, spawn_local};
+
+    fn tick() {
+        queue::QUEUE.with(|queue| queue.on_tick());
+    }
+
+    fn code(result: Result<Response, ()>) -> Option<u32> {
+        result.ok().map(|response| response.code)
+    }
+
+    #[test]
+    fn combinators() {
+        let promises: Vec<_> = (0..3).map(|_| Promise::pending()).collect();
+        let joined = Rc::new(RefCell::new(None));
+        let selected = Rc::new(RefCell::new(None));
+
+        let (calls, sink) = (promises.clone(), joined.clone());
+        spawn_local(async move {
+            let results = join_all(calls).await;
+            *sink.borrow_mut() = Some(results.into_iter().map(code).collect::<Vec<_>>());
+        });
+        // holds an `Rc` across the await, so the future is not `Send`
+        let rejected = Promise::pending();
+        let (local, sink) = (Rc::new(rejected.clone()), selected.clone());
+        spawn_local(async move {
+            let winner = select2((*local).clone(), std::future::pending::<()>()).await;
+            *sink.borrow_mut() = Some(matches!(winner, Either::Left(Err(()))));
+        });
+        tick();
+        assert!(joined.borrow().is_none());
+
+        promises[2].resolve(Response::builder().build());
+        promises[1].reject();
+        rejected.reject();
+        tick();
+        assert_eq!(*selected.borrow(), Some(true));
+        assert!(joined.borrow().is_none());
+
+        promises[0].resolve(Response::builder().build());
+        tick();
+        assert_eq!(*joined.borrow(), Some(vec![Some(200), None, Some(200)]));
+    }
+
+    #[test]
+    fn timeout() {
+        let result = Rc::new(RefCell::new(None));
+        let sink = result.clone();
+        spawn_local(async move {
+            let elapsed = race_with_timeout(Promise::pending(), Duration::ZERO).await;
+            *sink.borrow_mut() = Some(elapsed.map(code).map_err(|e| e.to_string()));
+        });
+        tick();
+        assert_eq!(
+            *result.borrow(),
+            Some(Err("future timed out after 0ns".to_string()))
+        );
+    }
+}
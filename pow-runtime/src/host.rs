@@ -0,0 +1,200 @@
+//! Hostcalls whose behavior differs between proxy-wasm hosts.
+//!
+//! The runtime goes through this module wherever hosts disagree, so filters
+//! built on it are not tied to Envoy:
+//!
+//! - [`set_effective_context`] is a no-op on hosts without it. Hooks then
+//!   only act on their request from within its own callbacks, not after
+//!   resuming from a tick.
+//! - [`property`] reads a well-known property from the first path the host
+//!   answers, hosts with other paths are supported by registering an
+//!   [`alias`].
+//! - Properties read as missing on hosts without them.
+//!
+//! Calls are made through the raw ABI where the SDK would panic on a status
+//! it does not know, e.g. `Unimplemented`.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use proxy_wasm::hostcalls;
+use proxy_wasm::types::Status;
+
+use crate::capabilities;
+
+pub(crate) const STATUS_OK: u32 = 0;
+const STATUS_NOT_FOUND: u32 = 1;
+const STATUS_BAD_ARGUMENT: u32 = 2;
+const STATUS_UNIMPLEMENTED: u32 = 12;
+
+/// Declared with raw statuses, the SDK's `Status` has no variant for
+/// `Unimplemented`.
+#[allow(clashing_extern_declarations)]
+pub(crate) mod abi {
+    use proxy_wasm::types::MetricType;
+
+    extern "C" {
+        pub fn proxy_set_effective_context(context_id: u32) -> u32;
+        pub fn proxy_register_shared_queue(
+            name_data: *const u8,
+            name_size: usize,
+            return_id: *mut u32,
+        ) -> u32;
+        pub fn proxy_get_property(
+            path_data: *const u8,
+            path_size: usize,
+            return_value_data: *mut *mut u8,
+            return_value_size: *mut usize,
+        ) -> u32;
+        pub fn proxy_call_foreign_function(
+            function_name_data: *const u8,
+            function_name_size: usize,
+            arguments_data: *const u8,
+            arguments_size: usize,
+            results_data: *mut *mut u8,
+            results_size: *mut usize,
+        ) -> u32;
+        pub fn proxy_get_buffer_bytes(
+            buffer_type: u32,
+            start: usize,
+            max_size: usize,
+            return_buffer_data: *mut *mut u8,
+            return_buffer_size: *mut usize,
+        ) -> u32;
+        pub fn proxy_define_metric(
+            metric_type: MetricType,
+            name_data: *const u8,
+            name_size: usize,
+            return_id: *mut u32,
+        ) -> u32;
+    }
+}
+
+/// Whether the host implements the call that answered `status`.
+pub(crate) fn implemented(status: u32) -> bool {
+    status != STATUS_UNIMPLEMENTED
+}
+
+/// Give back memory the host allocated in the VM for a result.
+pub(crate) unsafe fn free(data: *mut u8, size: usize) {
+    if !data.is_null() {
+        drop(Vec::from_raw_parts(data, size, size));
+    }
+}
+
+pub fn set_effective_context(context_id: u32) -> Result<(), Status> {
+    if !capabilities::get().effective_context {
+        capabilities::degraded(
+            "set_effective_context",
+            "hooks act on the context of the running callback",
+        );
+        return Ok(());
+    }
+    match unsafe { abi::proxy_set_effective_context(context_id) } {
+        STATUS_OK => Ok(()),
+        STATUS_NOT_FOUND => Err(Status::NotFound),
+        STATUS_BAD_ARGUMENT => Err(Status::BadArgument),
+        STATUS_UNIMPLEMENTED => {
+            capabilities::update(|capabilities| capabilities.effective_context = false);
+            set_effective_context(context_id)
+        }
+        status => {
+            log::warn!("unexpected status {} of set_effective_context", status);
+            Err(Status::InternalFailure)
+        }
+    }
+}
+
+/// A property of the current context, `None` on hosts without properties.
+pub fn get_property(path: Vec<&str>) -> Result<Option<Vec<u8>>, Status> {
+    if !capabilities::get().properties {
+        capabilities::degraded("properties", "properties read as missing");
+        return Ok(None);
+    }
+    hostcalls::get_property(path)
+}
+
+/// Properties the runtime reads, with the paths hosts are known to use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Property {
+    /// `ip:port` of the downstream peer.
+    ClientAddress,
+}
+
+impl Property {
+    /// The path of the proxy-wasm attribute specification, used by Envoy.
+    fn default_path(&self) -> &'static [&'static str] {
+        match self {
+            Property::ClientAddress => &["source", "address"],
+        }
+    }
+}
+
+thread_local! {
+    static ALIASES: RefCell<HashMap<Property, Vec<Vec<String>>>> = RefCell::new(HashMap::new());
+}
+
+/// Also look `property` up under `path`, after the paths registered before
+/// and the default one.
+pub fn alias(property: Property, path: &[&str]) {
+    let path = path.iter().map(|part| part.to_string()).collect();
+    ALIASES.with(|aliases| aliases.borrow_mut().entry(property).or_default().push(path));
+}
+
+/// `property` of `context_id` from the first path the host has a value for.
+pub fn property(context_id: u32, property: Property) -> Result<Option<Vec<u8>>, Status> {
+    if let Some(value) = crate::property::get(context_id, property.default_path())?.into_bytes() {
+        return Ok(Some(value));
+    }
+    let aliases = ALIASES.with(|aliases| aliases.borrow().get(&property).cloned());
+    for path in aliases.unwrap_or_default() {
+        let path: Vec<&str> = path.iter().map(String::as_str).collect();
+        if let Some(value) = crate::property::get(context_id, &path)?.into_bytes() {
+            return Ok(Some(value));
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use test_host::HOST;
+    use crate::Ctx;
+
+    fn client_address(context_id: u32) -> Option<String> {
+        Ctx::new(context_id)
+            .get_client_address()
+            .expect("client address")
+    }
+
+    #[test]
+    fn envoy() {
+        HOST.with(|host| {
+            host.borrow_mut()
+                .properties
+                .insert((3, "source.address".to_string()), b"10.0.0.1:4000".to_vec())
+        });
+        assert_eq!(client_address(3), Some("10.0.0.1:4000".to_string()));
+        assert_eq!(client_address(4), None);
+        assert!(capabilities::get().effective_context);
+    }
+
+    #[test]
+    fn without_effective_context() {
+        HOST.with(|host| {
+            let mut host = host.borrow_mut();
+            host.effective_context_unimplemented = true;
+            // a host without effective contexts only knows the running one
+            host.properties.insert(
+                (0, "connection.remote_address".to_string()),
+                b"[2001:db8::1]:4000".to_vec(),
+            );
+        });
+        assert_eq!(client_address(3), None);
+        assert!(!capabilities::get().effective_context);
+
+        alias(Property::ClientAddress, &["connection", "remote_address"]);
+        assert_eq!(client_address(3), Some("[2001:db8::1]:4000".to_string()));
+    }
+}
@@ -0,0 +1,400 @@
+//! JSON over [`http_call`](crate::http_call).
+//!
+//! A [`Client`] builds the pseudo-headers of a call to one upstream, checks
+//! the status code and decodes the body, so callers deal with a typed value
+//! or an [`Error`] instead of header tuples and a bare [`Response`].
+//! Transient failures are retried as the client's [`RetryPolicy`] allows.
+
+use std::future::Future;
+use std::time::Duration;
+
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use proxy_wasm::types::Status;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::http_call;
+use crate::response::Response;
+use crate::timeout::sleep;
+use crate::upstream;
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("failed to dispatch call to {upstream}: {status:?}, check the upstream exists")]
+    Dispatch { upstream: String, status: Status },
+    #[error("call to {0} failed or timed out")]
+    Failed(String),
+    #[error("{upstream} answered {code}")]
+    Code {
+        upstream: String,
+        code: u32,
+        body: Option<Vec<u8>>,
+    },
+    #[error("invalid body from {upstream}: {source}")]
+    Body {
+        upstream: String,
+        #[source]
+        source: serde_json::Error,
+    },
+    #[error("no upstream {0} is configured")]
+    UnknownUpstream(String),
+    #[error("circuit to upstream {0} is open")]
+    CircuitOpen(String),
+}
+
+impl Error {
+    /// Whether another attempt may succeed: the call failed, e.g. was reset
+    /// or timed out, or was answered with a status of a class in `retry_on`.
+    fn is_transient(&self, retry_on: &[u32]) -> bool {
+        match self {
+            Error::Failed(_) => true,
+            Error::Code { code, .. } => retry_on.contains(&(code / 100)),
+            Error::Dispatch { .. }
+            | Error::Body { .. }
+            | Error::UnknownUpstream(_)
+            | Error::CircuitOpen(_) => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Attempts including the first one, `1` never retries.
+    pub max_attempts: u32,
+    /// Wait before the first retry, doubled for every further one.
+    pub backoff: Duration,
+    pub max_backoff: Duration,
+    /// Status classes to retry, e.g. `5` for `5xx`.
+    pub retry_on: Vec<u32>,
+    /// Also retry methods that are not idempotent, e.g. `POST`, which the
+    /// upstream may have applied before failing.
+    pub non_idempotent: bool,
+}
+
+/// A single attempt.
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 1,
+            backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(2),
+            retry_on: vec![5],
+            non_idempotent: false,
+        }
+    }
+}
+
+/// Run `attempt` until it succeeds, fails for good or the attempts of
+/// `policy` are used up. Only `idempotent` requests are retried unless the
+/// policy says otherwise.
+pub async fn with_retry<T, F, Fut>(
+    policy: &RetryPolicy,
+    idempotent: bool,
+    mut attempt: F,
+) -> Result<T, Error>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, Error>>,
+{
+    let max_attempts = match idempotent || policy.non_idempotent {
+        true => policy.max_attempts.max(1),
+        false => 1,
+    };
+    let mut backoff = policy.backoff;
+    for attempts in 1.. {
+        match attempt().await {
+            Err(e) if attempts < max_attempts && e.is_transient(&policy.retry_on) => {
+                log::debug!("retry in {:?} after attempt {}: {}", backoff, attempts, e);
+                sleep(backoff).await;
+                backoff = (backoff * 2).min(policy.max_backoff);
+            }
+            result => return result,
+        }
+    }
+    unreachable!("attempts are bounded")
+}
+
+/// Calls to the cluster `upstream`, for the host `authority`.
+#[derive(Debug, Clone)]
+pub struct Client {
+    upstream: String,
+    authority: String,
+    timeout: Duration,
+    retry: RetryPolicy,
+    /// Name in the [`upstream`](crate::upstream) registry, whose health the
+    /// attempts report to.
+    registered: Option<String>,
+}
+
+impl Client {
+    pub fn new(upstream: impl Into<String>, authority: impl Into<String>) -> Self {
+        Self {
+            upstream: upstream.into(),
+            authority: authority.into(),
+            timeout: DEFAULT_TIMEOUT,
+            retry: RetryPolicy::default(),
+            registered: None,
+        }
+    }
+
+    pub(crate) fn registered(mut self, name: &str) -> Self {
+        self.registered = Some(name.to_string());
+        self
+    }
+
+    /// A single attempt unless set.
+    pub fn retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// The host answers a call that takes longer with a failure.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// The body of a `2xx` response to `GET path`.
+    pub async fn get(&self, path: &str) -> Result<Vec<u8>, Error> {
+        let response = self.call("GET", path, None, "*/*").await?;
+        Ok(response.body.unwrap_or_default())
+    }
+
+    pub async fn get_json<T: DeserializeOwned>(&self, path: &str) -> Result<T, Error> {
+        let response = self.call("GET", path, None, "application/json").await?;
+        self.decode(response)
+    }
+
+    pub async fn post_json<B: Serialize, T: DeserializeOwned>(
+        &self,
+        path: &str,
+        body: &B,
+    ) -> Result<T, Error> {
+        let body = serde_json::to_vec(body).map_err(|source| Error::Body {
+            upstream: self.upstream.clone(),
+            source,
+        })?;
+        let response = self
+            .call("POST", path, Some(("application/json", &body)), "application/json")
+            .await?;
+        self.decode(response)
+    }
+
+    /// `POST` the `form` URL-encoded, as verification APIs of CAPTCHAs and
+    /// OAuth servers expect, and decode the JSON answer.
+    pub async fn post_form<T: DeserializeOwned>(
+        &self,
+        path: &str,
+        form: &[(&str, &str)],
+    ) -> Result<T, Error> {
+        let body = encode_form(form);
+        let response = self
+            .call(
+                "POST",
+                path,
+                Some(("application/x-www-form-urlencoded", body.as_bytes())),
+                "application/json",
+            )
+            .await?;
+        self.decode(response)
+    }
+
+    async fn call(
+        &self,
+        method: &str,
+        path: &str,
+        body: Option<(&str, &[u8])>,
+        accept: &str,
+    ) -> Result<Response, Error> {
+        let idempotent = matches!(method, "GET" | "HEAD" | "PUT" | "DELETE" | "OPTIONS");
+        with_retry(&self.retry, idempotent, || async {
+            let Some(name) = &self.registered else {
+                return self.attempt(method, path, body, accept).await;
+            };
+            if !upstream::allow(name) {
+                return Err(Error::CircuitOpen(name.clone()));
+            }
+            let result = self.attempt(method, path, body, accept).await;
+            // a 4xx is the request's fault, not the upstream's
+            let failed = matches!(&result, Err(Error::Failed(_)))
+                || matches!(&result, Err(Error::Code { code, .. }) if *code >= 500);
+            upstream::report(name, !failed);
+            result
+        })
+        .await
+    }
+
+    async fn attempt(
+        &self,
+        method: &str,
+        path: &str,
+        body: Option<(&str, &[u8])>,
+        accept: &str,
+    ) -> Result<Response, Error> {
+        let mut headers = vec![
+            (":method", method),
+            (":path", path),
+            (":authority", self.authority.as_str()),
+            (":scheme", "https"),
+            ("accept", accept),
+        ];
+        if let Some((content_type, _)) = body {
+            headers.push(("content-type", content_type));
+        }
+        let body = body.map(|(_, body)| body);
+        let response = http_call(&self.upstream, headers, body, vec![], self.timeout)
+            .map_err(|status| Error::Dispatch {
+                upstream: self.upstream.clone(),
+                status,
+            })?
+            .await
+            .map_err(|()| Error::Failed(self.upstream.clone()))?;
+        if !(200..300).contains(&response.code) {
+            return Err(Error::Code {
+                upstream: self.upstream.clone(),
+                code: response.code,
+                body: response.body,
+            });
+        }
+        Ok(response)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, response: Response) -> Result<T, Error> {
+        serde_json::from_slice(response.body.as_deref().unwrap_or_default()).map_err(|source| {
+            Error::Body {
+                upstream: self.upstream.clone(),
+                source,
+            }
+        })
+    }
+}
+
+fn encode_form(form: &[(&str, &str)]) -> String {
+    form.iter()
+        .map(|(k, v)| {
+            format!(
+                "{}={}",
+                utf8_percent_encode(k, NON_ALPHANUMERIC),
+                utf8_percent_encode(v, NON_ALPHANUMERIC)
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use serde::Deserialize;
+    use test_host::HOST;
+
+    use super::*;
+    use crate::promise::PENDINGS;
+    use crate::{queue, spawn_local};
+
+    #[derive(Debug, PartialEq, Deserialize)]
+    struct Block {
+        height: u64,
+    }
+
+    fn respond(token: u32, code: u32, body: &str) {
+        let promise = PENDINGS.with(|pendings| pendings.remove(&token)).unwrap();
+        promise.resolve(Response {
+            code,
+            headers: vec![],
+            body: Some(body.as_bytes().to_vec()),
+            trailers: vec![],
+        });
+        queue::QUEUE.with(|queue| queue.on_tick());
+    }
+
+    #[test]
+    fn json() {
+        let client = Client::new("mempool", "mempool.space");
+        let results = Rc::new(RefCell::new(vec![]));
+        for _ in 0..2 {
+            let (client, sink) = (client.clone(), results.clone());
+            spawn_local(async move {
+                let block = client.get_json::<Block>("/api/blocks/tip").await;
+                sink.borrow_mut().push(block.map_err(|e| e.to_string()));
+            });
+        }
+        queue::QUEUE.with(|queue| queue.on_tick());
+
+        let calls = HOST.with(|host| host.borrow().http_calls.clone());
+        assert_eq!(calls.len(), 2);
+        assert_eq!(calls[0].0, "mempool");
+        assert!(calls[0]
+            .1
+            .contains(&(":authority".to_string(), "mempool.space".to_string())));
+
+        respond(1, 200, r#"{"height": 840000}"#);
+        respond(2, 503, "overloaded");
+        assert_eq!(
+            *results.borrow(),
+            vec![
+                Ok(Block { height: 840000 }),
+                Err("mempool answered 503".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn retry() {
+        let client = Client::new("mempool", "mempool.space").retry(RetryPolicy {
+            max_attempts: 3,
+            backoff: Duration::ZERO,
+            ..Default::default()
+        });
+        let results = Rc::new(RefCell::new(vec![]));
+        let (get, sink) = (client.clone(), results.clone());
+        spawn_local(async move {
+            let block = get.get_json::<Block>("/api/blocks/tip").await;
+            sink.borrow_mut().push(block.map_err(|e| e.to_string()));
+        });
+        let (post, sink) = (client, results.clone());
+        spawn_local(async move {
+            let block = post.post_json::<_, Block>("/api/blocks", &1).await;
+            sink.borrow_mut().push(block.map_err(|e| e.to_string()));
+        });
+        queue::QUEUE.with(|queue| queue.on_tick());
+
+        respond(1, 502, "bad gateway");
+        // not idempotent, the upstream may have acted on it
+        respond(2, 502, "bad gateway");
+        assert_eq!(
+            *results.borrow(),
+            vec![Err("mempool answered 502".to_string())]
+        );
+
+        respond(3, 200, r#"{"height": 840001}"#);
+        assert_eq!(results.borrow()[1], Ok(Block { height: 840001 }));
+        assert_eq!(HOST.with(|host| host.borrow().http_calls.len()), 3);
+    }
+
+    #[test]
+    fn form() {
+        assert_eq!(
+            encode_form(&[("secret", "s3cr3t"), ("response", "a b&c=d")]),
+            "secret=s3cr3t&response=a%20b%26c%3Dd"
+        );
+        let client = Client::new("captcha", "hcaptcha.com");
+        spawn_local(async move {
+            let _ = client
+                .post_form::<serde_json::Value>("/siteverify", &[("response", "token")])
+                .await;
+        });
+        queue::QUEUE.with(|queue| queue.on_tick());
+        let calls = HOST.with(|host| host.borrow().http_calls.clone());
+        let (_, headers, body) = calls.last().unwrap();
+        assert!(headers.contains(&(
+            "content-type".to_string(),
+            "application/x-www-form-urlencoded".to_string()
+        )));
+        assert_eq!(body.as_deref(), Some(&b"response=token"[..]));
+    }
+}
@@ -1,9 +1,14 @@
-use std::{collections::VecDeque, marker::PhantomData, time::Duration};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::{Mutex, MutexGuard};
+use std::{marker::PhantomData, time::Duration};
 
 use proxy_wasm::{hostcalls, types::Status};
 use serde::{Deserialize, Serialize};
 
-use super::codec::Codec;
+use serde::de::DeserializeOwned;
+
+use super::codec::{Codec, DefaultCodec};
+use super::{clock, host, metrics};
 
 pub struct LowLevelKVStore {
     context_id: u32,
@@ -17,19 +22,19 @@ impl LowLevelKVStore {
     }
 
     pub fn put(&self, key: &str, value: &[u8]) -> Result<(), Status> {
-        hostcalls::set_effective_context(self.context_id)?;
+        host::set_effective_context(self.context_id)?;
         hostcalls::set_shared_data(key, Some(value), None)?;
         Ok(())
     }
 
     pub fn get(&self, key: &str) -> Result<Option<Vec<u8>>, Status> {
-        hostcalls::set_effective_context(self.context_id)?;
+        host::set_effective_context(self.context_id)?;
         let (value, _) = hostcalls::get_shared_data(key)?;
         Ok(value)
     }
 
     pub fn remove(&self, key: &str) -> Result<(), Status> {
-        hostcalls::set_effective_context(self.context_id)?;
+        host::set_effective_context(self.context_id)?;
         loop {
             let (value, cas) = hostcalls::get_shared_data(key)?;
             if value.is_none() {
@@ -43,27 +48,231 @@ impl LowLevelKVStore {
         }
     }
 
-    pub fn update<F>(&self, key: &str, mut f: F) -> Result<Vec<u8>, Status>
+    /// Replace the value of `key` by what `f` makes of it, retried while other
+    /// workers change it in between. An error of `f` leaves it as it is.
+    pub fn update<F, E>(&self, key: &str, mut f: F) -> Result<Vec<u8>, E>
+    where
+        F: FnMut(Option<Vec<u8>>) -> Result<Vec<u8>, E>,
+        E: From<Status>,
+    {
+        let new_value = self.update_or_remove(key, |value| f(value).map(Some))?;
+        Ok(new_value.expect("updated to a value"))
+    }
+
+    /// Like [`update`](Self::update), `f` returning `None` removes the key.
+    pub fn update_or_remove<F, E>(&self, key: &str, mut f: F) -> Result<Option<Vec<u8>>, E>
     where
-        F: FnMut(Option<Vec<u8>>) -> Vec<u8>,
+        F: FnMut(Option<Vec<u8>>) -> Result<Option<Vec<u8>>, E>,
+        E: From<Status>,
     {
-        hostcalls::set_effective_context(self.context_id)?;
+        host::set_effective_context(self.context_id)?;
         loop {
             let (value, cas) = hostcalls::get_shared_data(key)?;
-            let new_value = f(value);
-            match hostcalls::set_shared_data(key, Some(&new_value), cas) {
+            let new_value = f(value)?;
+            match hostcalls::set_shared_data(key, new_value.as_deref(), cas) {
                 Ok(()) => return Ok(new_value),
                 Err(Status::CasMismatch) => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+}
+
+/// Fields of several values kept in one shared data entry, so a request
+/// reading or writing them together makes one hostcall instead of one per
+/// value, see [`LowLevelKVStore::get_fields`].
+///
+/// Encoded as an index of the fields, a little endian `u16` count and per
+/// field its `u16` name length, name and `u32` value length, followed by the
+/// values in the order of the index. An empty entry is an empty record.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Record {
+    fields: BTreeMap<String, Vec<u8>>,
+}
+
+impl Record {
+    pub fn get(&self, field: &str) -> Option<&[u8]> {
+        self.fields.get(field).map(Vec::as_slice)
+    }
+
+    pub fn insert(&mut self, field: &str, value: &[u8]) {
+        self.fields.insert(field.to_string(), value.to_vec());
+    }
+
+    pub fn remove(&mut self, field: &str) -> Option<Vec<u8>> {
+        self.fields.remove(field)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&(self.fields.len() as u16).to_le_bytes());
+        for (name, value) in &self.fields {
+            bytes.extend_from_slice(&(name.len() as u16).to_le_bytes());
+            bytes.extend_from_slice(name.as_bytes());
+            bytes.extend_from_slice(&(value.len() as u32).to_le_bytes());
+        }
+        for value in self.fields.values() {
+            bytes.extend_from_slice(value);
+        }
+        bytes
+    }
+
+    /// `ParseFailure` for an entry that is not a record.
+    pub fn decode(bytes: &[u8]) -> Result<Self, Status> {
+        fn take<'a>(bytes: &mut &'a [u8], n: usize) -> Result<&'a [u8], Status> {
+            if bytes.len() < n {
+                return Err(Status::ParseFailure);
+            }
+            let (head, rest) = bytes.split_at(n);
+            *bytes = rest;
+            Ok(head)
+        }
+        let mut record = Record::default();
+        if bytes.is_empty() {
+            return Ok(record);
+        }
+        let mut bytes = bytes;
+        let count = u16::from_le_bytes(take(&mut bytes, 2)?.try_into().unwrap());
+        let mut index = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let len = u16::from_le_bytes(take(&mut bytes, 2)?.try_into().unwrap());
+            let name = std::str::from_utf8(take(&mut bytes, len as usize)?)
+                .map_err(|_| Status::ParseFailure)?;
+            let len = u32::from_le_bytes(take(&mut bytes, 4)?.try_into().unwrap());
+            index.push((name.to_string(), len as usize));
+        }
+        for (name, len) in index {
+            let value = take(&mut bytes, len)?;
+            record.fields.insert(name, value.to_vec());
+        }
+        if !bytes.is_empty() {
+            return Err(Status::ParseFailure);
+        }
+        Ok(record)
+    }
+}
+
+impl LowLevelKVStore {
+    /// The `fields` of the [`Record`] at `key`, in one hostcall.
+    pub fn get_fields(&self, key: &str, fields: &[&str]) -> Result<Vec<Option<Vec<u8>>>, Status> {
+        let record = self.get_record(key)?;
+        Ok(fields
+            .iter()
+            .map(|field| record.get(field).map(<[u8]>::to_vec))
+            .collect())
+    }
+
+    pub fn get_record(&self, key: &str) -> Result<Record, Status> {
+        match self.get(key)? {
+            Some(bytes) => Record::decode(&bytes),
+            None => Ok(Record::default()),
+        }
+    }
+
+    /// Set the `fields` of the [`Record`] at `key`, `None` removes one, and
+    /// keep its other fields. One read and one write unless it races.
+    pub fn put_fields(&self, key: &str, fields: &[(&str, Option<&[u8]>)]) -> Result<(), Status> {
+        host::set_effective_context(self.context_id)?;
+        loop {
+            let (value, cas) = hostcalls::get_shared_data(key)?;
+            let mut record = match value {
+                Some(bytes) => Record::decode(&bytes)?,
+                None => Record::default(),
+            };
+            for (field, value) in fields {
+                match value {
+                    Some(value) => record.insert(field, value),
+                    None => {
+                        record.remove(field);
+                    }
+                }
+            }
+            match hostcalls::set_shared_data(key, Some(&record.encode()), cas) {
+                Ok(()) => return Ok(()),
+                Err(Status::CasMismatch) => continue,
                 Err(e) => return Err(e),
             }
         }
     }
 }
 
-pub struct KVStore<V> {
+/// Values of type `V` encoded with `C`, see [`crate::codec`].
+pub struct KVStore<V, C = DefaultCodec> {
     low_level: LowLevelKVStore,
     prefix: String,
-    _phantom: PhantomData<V>,
+    index: Option<Box<KVStore<Index, C>>>,
+    capacity: Option<Capacity>,
+    /// Keys this worker listed in the index, with when, so writing them
+    /// again does not read the index, see [`TOUCH_EVERY`].
+    touched: Mutex<HashMap<String, u64>>,
+    _phantom: PhantomData<(V, C)>,
+}
+
+/// The most entries an [indexed](KVStore::indexed) store keeps, see
+/// [`KVStore::capped`].
+#[derive(Debug, Clone)]
+pub struct Capacity {
+    pub max_entries: usize,
+    /// Counter of the evicted entries.
+    pub evicted_metric: String,
+}
+
+/// Seconds a key's last write may be off in the index, so a key written over
+/// and over does not rewrite the index every time.
+const TOUCH_EVERY: u64 = 10;
+
+/// Keys per shard of the index of a capped store, each write reads and
+/// eviction sorts only the shard of its key.
+const KEYS_PER_SHARD: usize = 1024;
+
+/// Shards of the index of a store without a cap.
+const UNCAPPED_SHARDS: usize = 16;
+
+/// Most keys a worker remembers having listed, see `KVStore::touched`.
+const TOUCHED_KEYS: usize = 4096;
+
+/// FNV-1a, the same in every worker unlike the hashers of `std`.
+fn shard_hash(key: &str) -> u64 {
+    key.bytes().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// The keys of an [indexed](KVStore::indexed) store with the time of their
+/// last write, shared data has no way to enumerate them.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Index {
+    keys: BTreeMap<String, u64>,
+}
+
+impl Index {
+    /// Drop the least recently written keys but `keep` past `max_entries`.
+    fn evict(&mut self, max_entries: usize, keep: &str) -> Vec<String> {
+        let excess = self.keys.len().saturating_sub(max_entries);
+        if excess == 0 {
+            return vec![];
+        }
+        let mut oldest: Vec<(u64, &String)> = self
+            .keys
+            .iter()
+            .filter(|(key, _)| key.as_str() != keep)
+            .map(|(key, touched)| (*touched, key))
+            .collect();
+        oldest.sort_unstable();
+        let evicted: Vec<String> = oldest
+            .into_iter()
+            .take(excess)
+            .map(|(_, key)| key.clone())
+            .collect();
+        for key in &evicted {
+            self.keys.remove(key);
+        }
+        evicted
+    }
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -77,6 +286,18 @@ pub enum Error {
     Codec(#[from] Box<dyn std::error::Error>),
 }
 
+/// Why [`KVStore::update`] gave up, the host or the update.
+enum Aborted<E> {
+    Status(Status),
+    Update(E),
+}
+
+impl<E> From<Status> for Aborted<E> {
+    fn from(status: Status) -> Self {
+        Self::Status(status)
+    }
+}
+
 impl Error {
     pub fn status(status: Status, description: impl Into<String>) -> Self {
         Self::Status {
@@ -86,58 +307,260 @@ impl Error {
     }
 }
 
-impl <V: Codec> KVStore<V>
+impl <V, C> KVStore<V, C>
 where 
-    V::Error: Into<Box<dyn std::error::Error>>
+    V: Serialize + DeserializeOwned,
+    C: Codec,
 {
     pub fn new(context_id: u32, prefix: &str) -> Self {
         Self {
             low_level: LowLevelKVStore::new(context_id),
             prefix: prefix.to_string(),
+            index: None,
+            capacity: None,
+            touched: Mutex::default(),
             _phantom: PhantomData,
         }
     }
 
+    /// Like [`new`](Self::new), the keys are also listed in an index under
+    /// `<prefix>:index:<shard>` for [`list_keys`](Self::list_keys) and
+    /// [`clear`](Self::clear), at the cost of a write to it per added or
+    /// removed key. A worker writing a key it listed in the last ten seconds
+    /// does not read the index, so a key another worker evicted meanwhile is
+    /// written back unlisted until its next write after that.
+    pub fn indexed(context_id: u32, prefix: &str) -> Self {
+        Self {
+            index: Some(Box::new(KVStore::new(context_id, &format!("{}:index:", prefix)))),
+            ..Self::new(context_id, prefix)
+        }
+    }
+
+    /// Keep about `capacity.max_entries` entries of an indexed store,
+    /// evicting the least recently written of a shard of the index when a
+    /// new key is added to it. Only writes count as use, and only to ten
+    /// seconds. Changing the cap reshards the index, the keys listed before
+    /// are left out of it.
+    pub fn capped(self, capacity: Capacity) -> Self {
+        Self {
+            capacity: Some(capacity),
+            ..self
+        }
+    }
+
+    /// The keys starting with `prefix`, in order.
+    pub fn list_keys(&self, prefix: &str) -> Result<Vec<String>, Error> {
+        let index = self.index()?;
+        let mut keys = vec![];
+        for shard in 0..self.shards() {
+            let shard = index.get(&shard.to_string())?.unwrap_or_default();
+            keys.extend(
+                shard
+                    .keys
+                    .range(prefix.to_string()..)
+                    .map(|(key, _)| key)
+                    .take_while(|key| key.starts_with(prefix))
+                    .cloned(),
+            );
+        }
+        keys.sort_unstable();
+        Ok(keys)
+    }
+
+    /// Remove the keys starting with `prefix`, returns how many.
+    pub fn clear(&self, prefix: &str) -> Result<usize, Error> {
+        let keys = self.list_keys(prefix)?;
+        for key in &keys {
+            self.remove(key)?;
+        }
+        Ok(keys.len())
+    }
+
+    fn index(&self) -> Result<&KVStore<Index, C>, Error> {
+        self.index.as_deref().ok_or_else(|| {
+            Error::status(
+                Status::BadArgument,
+                format!("store {} keeps no index", self.prefix),
+            )
+        })
+    }
+
+    fn shards(&self) -> usize {
+        match &self.capacity {
+            Some(capacity) => capacity.max_entries.div_ceil(KEYS_PER_SHARD).max(1),
+            None => UNCAPPED_SHARDS,
+        }
+    }
+
+    /// The shard of the index listing `key`.
+    fn shard(&self, key: &str) -> String {
+        (shard_hash(key) % self.shards() as u64).to_string()
+    }
+
+    fn touched(&self) -> MutexGuard<'_, HashMap<String, u64>> {
+        self.touched.lock().expect("failed to lock touched keys")
+    }
+
+    /// Remember `key` as listed at `now` by this worker.
+    fn touch(&self, key: &str, now: u64) {
+        let mut touched = self.touched();
+        if touched.len() >= TOUCHED_KEYS {
+            touched.retain(|_, at| *at + TOUCH_EVERY > now);
+            if touched.len() >= TOUCHED_KEYS {
+                touched.clear();
+            }
+        }
+        touched.insert(key.to_string(), now);
+    }
+
+    fn list(&self, key: &str) -> Result<(), Error> {
+        let Some(index) = &self.index else {
+            return Ok(());
+        };
+        let now = clock::unix_secs();
+        let fresh = |touched: u64| touched + TOUCH_EVERY > now;
+        if self.touched().get(key).is_some_and(|at| fresh(*at)) {
+            return Ok(());
+        }
+        let shard = self.shard(key);
+        let listed = index.get(&shard)?;
+        if let Some(touched) = listed.and_then(|index| index.keys.get(key).copied()) {
+            if fresh(touched) {
+                self.touch(key, touched);
+                return Ok(());
+            }
+        }
+        let mut evicted = vec![];
+        index.update(&shard, |index| {
+            let mut index = index.unwrap_or_default();
+            index.keys.insert(key.to_string(), now);
+            if let Some(capacity) = &self.capacity {
+                let max_entries = capacity.max_entries.div_ceil(self.shards());
+                evicted = index.evict(max_entries, key);
+            }
+            Ok::<_, Error>(index)
+        })?;
+        self.touch(key, now);
+        let Some(capacity) = self.capacity.as_ref().filter(|_| !evicted.is_empty()) else {
+            return Ok(());
+        };
+        for key in &evicted {
+            self.touched().remove(key);
+            self.low_level
+                .remove(&format!("{}{}", self.prefix, key))
+                .map_err(|s| Error::status(s, "failed to evict value"))?;
+        }
+        log::debug!("store {} over capacity, evicted {} keys", self.prefix, evicted.len());
+        metrics::increment_counter(&capacity.evicted_metric, evicted.len() as i64);
+        Ok(())
+    }
+
+    fn unlist(&self, key: &str) -> Result<(), Error> {
+        let Some(index) = &self.index else {
+            return Ok(());
+        };
+        self.touched().remove(key);
+        let shard = self.shard(key);
+        let listed = index.get(&shard)?;
+        if !listed.is_some_and(|index| index.keys.contains_key(key)) {
+            return Ok(());
+        }
+        index.update(&shard, |index| {
+            let mut index = index.unwrap_or_default();
+            index.keys.remove(key);
+            Ok::<_, Error>(index)
+        })?;
+        Ok(())
+    }
+
     pub fn get(&self, key: &str) -> Result<Option<V>, Error> {
         let value = self.low_level
             .get(&format!("{}{}", self.prefix, key))
             .map_err(|s| Error::status(s, "failed to get value"))?;
 
-        match value {
+        // a removed key is left empty by the host
+        match value.filter(|v| !v.is_empty()) {
             Some(v) => Ok(Some(
-                V::decode(&v).map_err(|e| Error::Codec(e.into()))?
+                C::decode(&v).map_err(|e| Error::Codec(e.into()))?
             )),
             None => Ok(None),
         }
     }
 
     pub fn put(&self, key: &str, value: &V) -> Result<(), Error> {
-        let encoded = value.encode().map_err(|e| Error::Codec(e.into()))?;
+        let encoded = C::encode(value).map_err(|e| Error::Codec(e.into()))?;
         self.low_level
             .put(&format!("{}{}", self.prefix, key), &encoded)
-            .map_err(|s| Error::status(s, "failed to put value"))
+            .map_err(|s| Error::status(s, "failed to put value"))?;
+        self.list(key)
     }
 
     pub fn remove(&self, key: &str) -> Result<(), Error> {
         self.low_level
             .remove(&format!("{}{}", self.prefix, key))
-            .map_err(|s| Error::status(s, "failed to remove value"))
+            .map_err(|s| Error::status(s, "failed to remove value"))?;
+        self.unlist(key)
     }
 
-    pub fn update<F>(&self, key: &str, mut f: F) -> Result<V, Error>
+    /// Replace the value of `key` by what `f` makes of it, see
+    /// [`LowLevelKVStore::update`].
+    ///
+    /// A value that no longer decodes, e.g. written by an older version, is
+    /// quarantined: logged, kept aside under `<key>:quarantined` for
+    /// inspection and replaced as if there was none, so one corrupt entry does
+    /// not fail every update of it.
+    pub fn update<F, E>(&self, key: &str, mut f: F) -> Result<V, E>
     where
-        F: FnMut(Option<V>) -> V,
+        F: FnMut(Option<V>) -> Result<V, E>,
+        E: From<Error>,
     {
+        let value = self.update_or_remove(key, |old_value| f(old_value).map(Some))?;
+        Ok(value.expect("updated to a value"))
+    }
+
+    /// Like [`update`](Self::update), `f` returning `None` removes the key.
+    pub fn update_or_remove<F, E>(&self, key: &str, mut f: F) -> Result<Option<V>, E>
+    where
+        F: FnMut(Option<V>) -> Result<Option<V>, E>,
+        E: From<Error>,
+    {
+        let full_key = format!("{}{}", self.prefix, key);
         let value = self.low_level
-            .update(&format!("{}{}", self.prefix, key), |old_value| {
-                let new_value = f(old_value.map(|v| {
-                    V::decode(&v).map_err(|e| Error::Codec(e.into())).unwrap()
-                }));
-                new_value.encode().map_err(|e| Error::Codec(e.into())).unwrap()
+            .update_or_remove(&full_key, |old_value| {
+                let old_value = old_value.filter(|v| !v.is_empty());
+                let old_value = match old_value.map(|v| (C::decode(&v), v)) {
+                    Some((Ok(old_value), _)) => Some(old_value),
+                    Some((Err(e), raw)) => {
+                        self.quarantine(&full_key, &raw, &e);
+                        None
+                    }
+                    None => None,
+                };
+                let Some(new_value) = f(old_value).map_err(Aborted::Update)? else {
+                    return Ok(None);
+                };
+                C::encode(&new_value)
+                    .map(Some)
+                    .map_err(|e| Aborted::Update(Error::Codec(e.into()).into()))
             })
-            .map_err(|s| Error::status(s, "failed to update value"))?;
+            .map_err(|e| match e {
+                Aborted::Status(s) => Error::status(s, "failed to update value").into(),
+                Aborted::Update(e) => e,
+            })?;
+        let Some(value) = value else {
+            self.unlist(key)?;
+            return Ok(None);
+        };
+        self.list(key)?;
+
+        Ok(Some(C::decode(&value).map_err(|e| Error::Codec(e.into()))?))
+    }
 
-        V::decode(&value).map_err(|e| Error::Codec(e.into()))
+    fn quarantine(&self, key: &str, raw: &[u8], e: &C::Error) {
+        log::warn!("quarantined corrupt value of {}: {}", key, e);
+        if let Err(s) = self.low_level.put(&format!("{}:quarantined", key), raw) {
+            log::warn!("failed to keep corrupt value of {} aside: {:?}", key, s);
+        }
     }
 }
 
@@ -160,10 +583,7 @@ impl Expirations {
     }
 
     fn now() -> u64 {
-        std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .unwrap()
-            .as_secs()
+        crate::clock::unix_secs()
     }
 
     fn pop_expired(&mut self) -> Vec<String> {
@@ -180,15 +600,15 @@ impl Expirations {
     }
 }
 
-pub struct ExpiringKVStore<V> {
-    store: KVStore<V>,
-    expirations: KVStore<Expirations>
+pub struct ExpiringKVStore<V, C = DefaultCodec> {
+    store: KVStore<V, C>,
+    expirations: KVStore<Expirations, C>
 }
 
-impl <V> ExpiringKVStore<V>
+impl <V, C> ExpiringKVStore<V, C>
 where 
-    V: Codec,
-    V::Error: Into<Box<dyn std::error::Error>>
+    V: Serialize + DeserializeOwned,
+    C: Codec,
 {
     pub fn new(context_id: u32, prefix: &str) -> Self {
         Self {
@@ -197,6 +617,31 @@ where
         }
     }
 
+    /// Like [`new`](Self::new) with an index of the keys, see
+    /// [`KVStore::indexed`]. Expired keys leave the index when collected.
+    pub fn indexed(context_id: u32, prefix: &str) -> Self {
+        Self {
+            store: KVStore::indexed(context_id, prefix),
+            expirations: KVStore::new(context_id, &format!("{}:expirations", prefix)),
+        }
+    }
+
+    /// See [`KVStore::capped`].
+    pub fn capped(self, capacity: Capacity) -> Self {
+        Self {
+            store: self.store.capped(capacity),
+            ..self
+        }
+    }
+
+    pub fn list_keys(&self, prefix: &str) -> Result<Vec<String>, Error> {
+        self.store.list_keys(prefix)
+    }
+
+    pub fn clear(&self, prefix: &str) -> Result<usize, Error> {
+        self.store.clear(prefix)
+    }
+
     pub fn get(&self, key: &str) -> Result<Option<V>, Error> {
         self.store.get(key)
     }
@@ -210,30 +655,31 @@ where
         self.store.remove(key)
     }
 
-    pub fn update<F>(&self, key: &str, f: F) -> Result<V, Error>
+    pub fn update<F, E>(&self, key: &str, f: F) -> Result<V, E>
     where
-        F: FnMut(Option<V>) -> V,
+        F: FnMut(Option<V>) -> Result<V, E>,
+        E: From<Error>,
     {
         self.store.update(key, f)
     }
 
     pub fn enqueue_expires(&self, key: &str, ttl: Duration) -> Result<(), Error> {
-        let _ = self.expirations.update("", |expirations| {
+        self.expirations.update("", |expirations| {
             let mut expirations = expirations.unwrap_or_else(Expirations::new);
             expirations.push(key.to_string(), ttl);
-            expirations
+            Ok::<_, Error>(expirations)
         })?;
         self.gc()
     }
 
     pub fn gc(&self) -> Result<(), Error> {
         let mut expired = vec![];
-        let _ = self.expirations.update("", |expirations| {
+        self.expirations.update("", |expirations| {
             let Some(mut expirations) = expirations else {
-                return Expirations::new();
+                return Ok::<_, Error>(Expirations::new());
             };
             expired = expirations.pop_expired();
-            expirations
+            Ok(expirations)
         })?;
 
         for key in expired {
@@ -243,3 +689,198 @@ where
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::*;
+    use crate::clock::{self, TestClock};
+
+    #[test]
+    fn expirations() {
+        let clock = TestClock::new(UNIX_EPOCH + Duration::from_secs(1_000));
+        let _guard = clock::install(clock.clone());
+        let mut expirations = Expirations::new();
+        expirations.push("b".to_string(), Duration::from_secs(20));
+        expirations.push("a".to_string(), Duration::from_secs(10));
+        assert!(expirations.pop_expired().is_empty());
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(expirations.pop_expired(), vec!["a".to_string()]);
+        clock.advance(Duration::from_secs(15));
+        assert_eq!(expirations.pop_expired(), vec!["b".to_string()]);
+    }
+
+    #[test]
+    fn record_encoding() {
+        let mut record = Record::default();
+        record.insert("counter", &7u64.to_le_bytes());
+        record.insert("session", b"abc");
+        record.insert("blocked", b"");
+        let bytes = record.encode();
+        assert_eq!(Record::decode(&bytes), Ok(record));
+        assert_eq!(Record::decode(&[]), Ok(Record::default()));
+        assert_eq!(Record::decode(&bytes[..bytes.len() - 1]), Err(Status::ParseFailure));
+        assert_eq!(Record::decode(&[bytes.as_slice(), b"x"].concat()), Err(Status::ParseFailure));
+    }
+
+    #[test]
+    fn fields() {
+        let store = LowLevelKVStore::new(0);
+        store
+            .put_fields("client:10.0.0.1", &[("counter", Some(b"1")), ("session", Some(b"s1"))])
+            .unwrap();
+        store
+            .put_fields("client:10.0.0.1", &[("counter", Some(b"2")), ("session", None)])
+            .unwrap();
+        let fields = store
+            .get_fields("client:10.0.0.1", &["counter", "session", "blocked"])
+            .unwrap();
+        assert_eq!(fields, vec![Some(b"2".to_vec()), None, None]);
+        let writes = test_host::HOST.with(|host| host.borrow().shared_data["client:10.0.0.1"].1);
+        assert_eq!(writes, 2);
+        assert!(store.get_record("client:10.0.0.2").unwrap().is_empty());
+    }
+
+    #[test]
+    fn update_errors() {
+        let store: KVStore<u64> = KVStore::new(0, "update:");
+        store.put("a", &1).unwrap();
+        let failed = store.update("a", |_| Err(Error::status(Status::BadArgument, "refused")));
+        assert!(matches!(failed, Err(Error::Status { status: Status::BadArgument, .. })));
+        assert_eq!(store.get("a").unwrap(), Some(1));
+
+        let low_level = LowLevelKVStore::new(0);
+        low_level.put("update:b", b"bad").unwrap();
+        assert!(matches!(store.get("b"), Err(Error::Codec(_))));
+        let updated = store.update("b", |old| Ok::<_, Error>(old.unwrap_or(0) + 1));
+        assert_eq!(updated.unwrap(), 1);
+        assert_eq!(
+            low_level.get("update:b:quarantined").unwrap(),
+            Some(b"bad".to_vec())
+        );
+    }
+
+    #[test]
+    fn update_or_remove() {
+        let store: KVStore<u64> = KVStore::indexed(0, "counted:");
+        let count_out = |old: Option<u64>| Ok::<_, Error>(old.filter(|n| *n > 1).map(|n| n - 1));
+        store.put("a", &2).unwrap();
+        assert_eq!(store.update_or_remove("a", count_out).unwrap(), Some(1));
+        assert_eq!(store.list_keys("").unwrap(), ["a"]);
+        assert_eq!(store.update_or_remove("a", count_out).unwrap(), None);
+        assert_eq!(store.get("a").unwrap(), None);
+        assert!(store.list_keys("").unwrap().is_empty());
+        assert_eq!(store.update_or_remove("b", count_out).unwrap(), None);
+    }
+
+    #[test]
+    fn index() {
+        let store: KVStore<u64> = KVStore::indexed(0, "indexed:");
+        store.put("b:1", &1).unwrap();
+        store.put("a:1", &1).unwrap();
+        store.update("a:2", |old| Ok::<_, Error>(old.unwrap_or(0) + 1)).unwrap();
+        store.put("a:1", &2).unwrap();
+        assert_eq!(store.list_keys("").unwrap(), ["a:1", "a:2", "b:1"]);
+        assert_eq!(store.list_keys("a:").unwrap(), ["a:1", "a:2"]);
+
+        assert_eq!(store.clear("a:").unwrap(), 2);
+        assert_eq!(store.get("a:1").unwrap(), None);
+        assert_eq!(store.get("b:1").unwrap(), Some(1));
+        store.remove("b:1").unwrap();
+        assert!(store.list_keys("").unwrap().is_empty());
+
+        let unindexed: KVStore<u64> = KVStore::new(0, "unindexed:");
+        assert!(unindexed.list_keys("").is_err());
+    }
+
+    #[test]
+    fn capacity() {
+        let clock = TestClock::new(UNIX_EPOCH + Duration::from_secs(1_000));
+        let _guard = clock::install(clock.clone());
+        let store: KVStore<u64> = KVStore::indexed(0, "capped:").capped(Capacity {
+            max_entries: 2,
+            evicted_metric: "capped.evicted".to_string(),
+        });
+        store.put("a", &1).unwrap();
+        clock.advance(Duration::from_secs(1));
+        store.put("b", &1).unwrap();
+        clock.advance(Duration::from_secs(TOUCH_EVERY));
+        // a written again is more recent than b
+        store.update("a", |old| Ok::<_, Error>(old.unwrap_or(0) + 1)).unwrap();
+        store.put("c", &1).unwrap();
+        assert_eq!(store.list_keys("").unwrap(), ["a", "c"]);
+        assert_eq!(store.get("a").unwrap(), Some(2));
+        assert_eq!(store.get("b").unwrap(), None);
+    }
+
+    #[test]
+    fn touched_keys() {
+        let clock = TestClock::new(UNIX_EPOCH + Duration::from_secs(1_000));
+        let _guard = clock::install(clock.clone());
+        let store: KVStore<u64> = KVStore::indexed(0, "touched:");
+        store.put("a", &1).unwrap();
+        let shard = format!("touched::index:{}", store.shard("a"));
+        let low_level = LowLevelKVStore::new(0);
+        low_level.remove(&shard).unwrap();
+        // listed by this worker a moment ago, the index is not read
+        store.put("a", &2).unwrap();
+        assert!(store.list_keys("").unwrap().is_empty());
+        clock.advance(Duration::from_secs(TOUCH_EVERY));
+        store.put("a", &3).unwrap();
+        assert_eq!(store.list_keys("").unwrap(), ["a"]);
+
+        // listed by another worker a moment ago, the index is not written
+        let writes = || test_host::HOST.with(|host| host.borrow().shared_data[&shard].1);
+        let before = writes();
+        let other: KVStore<u64> = KVStore::indexed(0, "touched:");
+        other.put("a", &4).unwrap();
+        assert_eq!(writes(), before);
+    }
+
+    #[test]
+    fn shards() {
+        let store: KVStore<u64> = KVStore::indexed(0, "sharded:").capped(Capacity {
+            max_entries: 3 * KEYS_PER_SHARD,
+            evicted_metric: "sharded.evicted".to_string(),
+        });
+        assert_eq!(store.shards(), 3);
+        let keys: Vec<String> = (0..30).map(|i| format!("k{:02}", i)).collect();
+        for key in &keys {
+            store.put(key, &1).unwrap();
+        }
+        assert_eq!(store.list_keys("").unwrap(), keys);
+        let shards: Vec<_> = (0..3)
+            .map(|shard| {
+                let index: KVStore<Index> = KVStore::new(0, "sharded::index:");
+                index.get(&shard.to_string()).unwrap().unwrap().keys.len()
+            })
+            .collect();
+        assert_eq!(shards.iter().sum::<usize>(), 30);
+        assert!(shards.iter().all(|keys| *keys < 30));
+        assert_eq!(store.clear("k1").unwrap(), 10);
+        assert_eq!(store.list_keys("").unwrap().len(), 20);
+    }
+
+    #[test]
+    fn codec_per_store() {
+        use crate::codec::JsonCodec;
+
+        let json: KVStore<Vec<u32>, JsonCodec> = KVStore::new(0, "json:");
+        json.put("a", &vec![1, 2]).unwrap();
+        let raw = test_host::HOST.with(|host| host.borrow().shared_data["json:a"].0.clone());
+        assert_eq!(raw, b"[1,2]");
+        assert_eq!(json.get("a").unwrap(), Some(vec![1, 2]));
+
+        #[cfg(feature = "bincode")]
+        {
+            let bincode: KVStore<Vec<u32>, crate::codec::BincodeCodec> =
+                KVStore::new(0, "bincode:");
+            bincode.put("a", &vec![1, 2]).unwrap();
+            assert_eq!(bincode.get("a").unwrap(), Some(vec![1, 2]));
+            // the codecs do not read each other's entries
+            let other: KVStore<Vec<u32>, JsonCodec> = KVStore::new(0, "bincode:");
+            assert!(matches!(other.get("a"), Err(Error::Codec(_))));
+        }
+    }
+}
@@ -1,27 +1,60 @@
 pub mod task {
     mod singlethread;
     pub(crate) use singlethread::*;
+    pub use singlethread::{stats, Stats};
 }
+pub mod bus;
+pub mod cache;
+pub mod capabilities;
+pub mod clock;
 pub mod codec;
 pub mod counter_bucket;
+pub mod error;
+pub mod failure_mode;
+pub mod filter;
+pub mod filter_state;
+pub mod foreign;
+pub mod future;
+pub mod host;
+pub mod http;
 pub mod kv_store;
 pub mod lock;
 pub mod log_level;
+pub mod memory;
+pub mod metrics;
 pub mod promise;
+pub mod property;
+pub mod pseudo;
+pub mod query;
 pub mod queue;
 pub mod response;
+pub mod scratch;
+pub mod singleton;
+pub mod stream;
+pub mod stream_info;
 pub mod timeout;
+pub mod trace;
+pub mod upstream;
 
-use std::{future::Future, rc::Rc, time::Duration};
+#[doc(hidden)]
+pub use proxy_wasm as __proxy_wasm;
+
+use std::{
+    cell::Cell,
+    future::Future,
+    rc::Rc,
+    time::{Duration, SystemTime},
+};
 
 use lock::{wake_tasks, QueueId};
 use promise::{Promise, PENDINGS};
 use proxy_wasm::{
     hostcalls,
-    traits::{Context, HttpContext, RootContext},
-    types::{Action, Status},
+    traits::{Context, HttpContext, RootContext, StreamContext},
+    types::{Action, ContextType, MapType, Status},
 };
 use response::Response;
+use timeout::{sleep, Deadline};
 
 /// Runs a Rust `Future` on the current thread.
 ///
@@ -55,6 +88,32 @@ pub fn http_call(
     Ok(promise)
 }
 
+/// Whether `headers` ask to switch protocols, e.g. to WebSocket: HTTP/1.1
+/// `Connection: upgrade` with an `Upgrade` header, or an HTTP/2 extended
+/// CONNECT with a `:protocol`.
+///
+/// Hooks see upgrades like any other request. Once resumed, the host may
+/// answer with `101 Switching Protocols`, which passes through
+/// [`HttpHook::response_headers`] like a regular response.
+pub fn is_upgrade(headers: &[(String, String)]) -> bool {
+    let get = |name: &str| {
+        headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    };
+    let connection_upgrade = get("connection")
+        .map(|value| {
+            value
+                .split(',')
+                .any(|token| token.trim().eq_ignore_ascii_case("upgrade"))
+        })
+        .unwrap_or(false);
+    let extended_connect =
+        get(pseudo::METHOD) == Some("CONNECT") && get(pseudo::PROTOCOL).is_some();
+    (connection_upgrade && get("upgrade").is_some()) || extended_connect
+}
+
 pub trait Runtime: Context {
     type Hook: HttpHook + 'static;
     fn on_vm_start(&mut self, _vm_configuration_size: usize) -> bool {
@@ -66,6 +125,26 @@ pub trait Runtime: Context {
     }
 
     fn create_http_context(&self, _context_id: u32) -> Option<Self::Hook>;
+
+    /// Only consulted when `get_type` returns `ContextType::StreamContext`,
+    /// wrap a [`stream::StreamHook`] in a [`stream::StreamHookHolder`].
+    fn create_stream_context(&self, _context_id: u32) -> Option<Box<dyn StreamContext>> {
+        None
+    }
+
+    fn get_type(&self) -> Option<ContextType> {
+        Some(ContextType::HttpContext)
+    }
+
+    /// Call a host extension whose result arrives with a later event, see
+    /// [`foreign`]. Hooks call [`foreign::call_async`] directly.
+    fn call_foreign_function_async(
+        &self,
+        name: &str,
+        arguments: &[u8],
+    ) -> Result<foreign::ForeignCall, foreign::Error> {
+        foreign::call_async(name, arguments)
+    }
 }
 
 pub struct RuntimeBox<R: Runtime> {
@@ -104,11 +183,22 @@ impl<R: Runtime> Context for RuntimeBox<R> {
             promise.resolve(response);
         }
     }
+
+    fn on_foreign_function(&mut self, function_id: u32, arguments_size: usize) {
+        foreign::on_event(function_id, arguments_size)
+    }
 }
 
 impl<R: Runtime> RootContext for RuntimeBox<R> {
     fn on_vm_start(&mut self, _vm_configuration_size: usize) -> bool {
         self.set_tick_period(Duration::from_millis(1));
+        trace::seed(self.get_current_time());
+        let capabilities = capabilities::probe();
+        log::info!(
+            "pow-runtime {} started, host capabilities: {}",
+            env!("CARGO_PKG_VERSION"),
+            capabilities
+        );
         self.inner.on_vm_start(_vm_configuration_size)
     }
 
@@ -130,11 +220,26 @@ impl<R: Runtime> RootContext for RuntimeBox<R> {
         Some(Box::new(HookHolder::<R::Hook>::new(_context_id, hook)))
     }
 
-    fn get_type(&self) -> Option<proxy_wasm::types::ContextType> {
-        Some(proxy_wasm::types::ContextType::HttpContext)
+    fn create_stream_context(&self, _context_id: u32) -> Option<Box<dyn StreamContext>> {
+        self.inner.create_stream_context(_context_id)
+    }
+
+    fn get_type(&self) -> Option<ContextType> {
+        self.inner.get_type()
     }
 }
 
+fn get_client_address(context_id: u32) -> Result<Option<String>, Status> {
+    let Some(raw_property) = host::property(context_id, host::Property::ClientAddress)? else {
+        return Ok(None);
+    };
+    let addr = String::from_utf8(raw_property).map_err(|e| {
+        log::warn!("failed to parse client address: {}", e);
+        Status::InternalFailure
+    })?;
+    Ok(Some(addr))
+}
+
 #[derive(Clone, Copy)]
 pub struct Ctx {
     id: u32,
@@ -149,51 +254,191 @@ impl Ctx {
         Self { id }
     }
 
+    /// The id of the HTTP context.
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
     pub fn get_client_address(&self) -> Result<Option<String>, Status> {
-        hostcalls::set_effective_context(self.id)?;
-        let Some(raw_property) = hostcalls::get_property(vec!["source", "address"])? else {
-            return Ok(None);
-        };
-        let addr = String::from_utf8(raw_property).map_err(|e| {
-            log::warn!("failed to parse client address: {}", e);
-            Status::InternalFailure
-        })?;
-        Ok(Some(addr))
+        get_client_address(self.id)
     }
     pub fn get_http_request_headers(&self) -> Result<Vec<(String, String)>, Status> {
-        hostcalls::set_effective_context(self.id)?;
+        host::set_effective_context(self.id)?;
         Ok(HttpContext::get_http_request_headers(self))
     }
 
     pub fn get_http_request_header(&self, key: &str) -> Result<Option<String>, Status> {
-        hostcalls::set_effective_context(self.id)?;
+        host::set_effective_context(self.id)?;
         Ok(HttpContext::get_http_request_header(self, key))
     }
 
+    /// Replace a request header, `None` removes it. Like the other request
+    /// header mutations it may be called from the async hook, and is only
+    /// effective before the request is resumed.
+    pub fn set_http_request_header(&self, key: &str, value: Option<&str>) -> Result<(), Status> {
+        host::set_effective_context(self.id)?;
+        hostcalls::set_map_value(MapType::HttpRequestHeaders, key, value)
+    }
+
+    /// Append a value, keeping the existing values of the header.
+    pub fn add_http_request_header(&self, key: &str, value: &str) -> Result<(), Status> {
+        host::set_effective_context(self.id)?;
+        hostcalls::add_map_value(MapType::HttpRequestHeaders, key, value)
+    }
+
+    pub fn remove_http_request_header(&self, key: &str) -> Result<(), Status> {
+        self.set_http_request_header(key, None)
+    }
+
+    pub fn is_upgrade_request(&self) -> Result<bool, Status> {
+        Ok(is_upgrade(&self.get_http_request_headers()?))
+    }
+
     pub fn get_http_request_trailers(&self) -> Result<Vec<(String, String)>, Status> {
-        hostcalls::set_effective_context(self.id)?;
+        host::set_effective_context(self.id)?;
         Ok(HttpContext::get_http_request_trailers(self))
     }
 
     fn continue_request(&self) -> Result<(), Status> {
-        hostcalls::set_effective_context(self.id)?;
+        host::set_effective_context(self.id)?;
         hostcalls::resume_http_request()
     }
 
-    fn reject_request(
+    fn reject_request(&self, response: &Response) -> Result<(), Status> {
+        let headers = response
+            .headers
+            .iter()
+            .map(|(k, v)| (k.as_str(), v.as_str()))
+            .collect();
+        host::set_effective_context(self.id)?;
+        hostcalls::send_http_response(response.code, headers, response.body.as_deref())
+    }
+
+    /// [`http_call`] on behalf of the request, which carries its trace
+    /// headers (see [`trace`]) unless `headers` sets them. The latency is
+    /// logged with the decision on the request.
+    pub async fn http_call(
         &self,
-        status: u32,
+        upstream: &str,
         headers: Vec<(&str, &str)>,
         body: Option<&[u8]>,
-    ) -> Result<(), Status> {
-        hostcalls::set_effective_context(self.id)?;
-        hostcalls::send_http_response(status, headers, body)
+        trailers: Vec<(&str, &str)>,
+        timeout: Duration,
+    ) -> Result<Result<Response, ()>, Status> {
+        let request_headers = self.get_http_request_headers()?;
+        let propagated = trace::propagate(&request_headers, self.id as u64);
+        let mut headers = headers;
+        for (key, value) in &propagated {
+            if !headers.iter().any(|(k, _)| k.eq_ignore_ascii_case(key)) {
+                headers.push((key, value));
+            }
+        }
+        let path = headers
+            .iter()
+            .find(|(k, _)| *k == ":path")
+            .map(|(_, v)| v.to_string())
+            .unwrap_or_default();
+        let start = hostcalls::get_current_time()?;
+        let response = http_call(upstream, headers, body, trailers, timeout)?.await;
+        let elapsed = hostcalls::get_current_time()?
+            .duration_since(start)
+            .unwrap_or_default();
+        trace::record(
+            self.id,
+            trace::Subrequest {
+                upstream: upstream.to_string(),
+                path,
+                status: response.as_ref().ok().map(|r| r.code.to_string()),
+                elapsed,
+            },
+        );
+        Ok(response)
     }
 
+    /// The trace the request belongs to, see [`trace::TraceContext::parse`].
+    pub fn trace_context(&self) -> Result<Option<trace::TraceContext>, Status> {
+        let headers = self.get_http_request_headers()?;
+        Ok(trace::TraceContext::parse(&headers))
+    }
+
+    /// `None` on hosts without properties, see [`host`].
+    pub fn get_property(&self, path: Vec<&str>) -> Result<Option<Vec<u8>>, Status> {
+        Ok(self.property(&path)?.into_bytes())
+    }
+
+    /// The property at `path`, read from the host once per request, see
+    /// [`property`].
+    pub fn property(&self, path: &[&str]) -> Result<property::Value, Status> {
+        property::get(self.id, path)
+    }
+
+    /// The TLS version of the connection, e.g. `TLSv1.3`.
+    pub fn tls_version(&self) -> Result<Option<String>, Status> {
+        Ok(self.property(property::TLS_VERSION)?.string())
+    }
+
+    /// The SNI the client sent.
+    pub fn requested_server_name(&self) -> Result<Option<String>, Status> {
+        Ok(self.property(property::REQUESTED_SERVER_NAME)?.string())
+    }
+
+    /// The subject of the certificate the peer presented.
+    pub fn peer_subject(&self) -> Result<Option<String>, Status> {
+        Ok(self.property(property::SUBJECT_PEER_CERTIFICATE)?.string())
+    }
+
+    pub fn request_time(&self) -> Result<Option<SystemTime>, Status> {
+        Ok(self.property(property::REQUEST_TIME)?.timestamp())
+    }
+
+    /// The `:path`, `BadArgument` for a CONNECT, which has none, see
+    /// [`pseudo`].
     pub fn get_http_request_path(&self) -> Result<String, Status> {
-        self.get_http_request_header(":path")?
+        self.get_http_request_header(pseudo::PATH)?
             .ok_or(Status::BadArgument)
     }
+
+    pub fn method(&self) -> Result<Option<pseudo::Method>, Status> {
+        let method = self.get_http_request_header(pseudo::METHOD)?;
+        Ok(method.as_deref().map(pseudo::Method::parse))
+    }
+
+    /// `None` for a CONNECT that is not an extended one.
+    pub fn scheme(&self) -> Result<Option<pseudo::Scheme>, Status> {
+        let scheme = self.get_http_request_header(pseudo::SCHEME)?;
+        Ok(scheme.as_deref().map(pseudo::Scheme::parse))
+    }
+
+    /// The `:authority`, or `host` without one, `None` when absent or the
+    /// values disagree, see [`pseudo::Authority::select`].
+    pub fn authority(&self) -> Result<Option<pseudo::Authority>, Status> {
+        let headers = self.get_http_request_headers()?;
+        let values = |name: &'static str| {
+            headers
+                .iter()
+                .filter(move |(k, _)| k.eq_ignore_ascii_case(name))
+                .map(|(_, v)| v.as_str())
+        };
+        Ok(pseudo::Authority::select(
+            values(pseudo::AUTHORITY),
+            values("host"),
+        ))
+    }
+
+    /// The `:protocol` of an extended CONNECT, e.g. `connect-udp`.
+    pub fn protocol(&self) -> Result<Option<String>, Status> {
+        self.get_http_request_header(pseudo::PROTOCOL)
+    }
+
+    /// Values kept for the rest of the request, see [`scratch`].
+    pub fn scratch(&self) -> scratch::Scratch {
+        scratch::Scratch::new(self.id)
+    }
+
+    /// The parameters of the query string of the `:path`, see [`query`].
+    pub fn get_query_params(&self) -> Result<query::QueryParams, Status> {
+        Ok(query::QueryParams::parse(&self.get_http_request_path()?))
+    }
 }
 
 pub trait HttpHook {
@@ -201,53 +446,156 @@ pub trait HttpHook {
         None
     }
 
+    /// The future runs on the VM's single thread, it may hold `Rc` based
+    /// types like [`Promise`] or a lock guard across awaits.
     fn on_request_headers(
         &self,
         _num_headers: usize,
         _end_of_stream: bool,
-    ) -> impl Future<Output = Result<(), impl Into<Response>>> + Send;
+    ) -> impl Future<Output = Result<(), impl Into<Response>>>;
+
+    /// Headers added to the response once its headers arrive, typically
+    /// collected in a [`ResponseHeaders`](response::ResponseHeaders) of the
+    /// hook while handling the request.
+    fn response_headers(&self) -> Vec<(String, String)> {
+        vec![]
+    }
+
+    /// Called once the stream is done, with how the response ended. Values
+    /// the request hook kept in the [`scratch`] of its context are still
+    /// there.
+    fn on_log(&self, _info: &stream_info::StreamInfo) {}
+
+    /// Called for every chunk of the request body, `body_size` bytes long.
+    /// Chunks arriving while [`HttpHook::on_request_headers`] is pending are
+    /// held back and, once it lets the request through, passed in one call
+    /// before the request resumes. An error answers the request with the
+    /// response right away.
+    fn on_request_body(&self, _body_size: usize, _end_of_stream: bool) -> Result<(), Response> {
+        Ok(())
+    }
+
+    /// How long a request may take to arrive, from the creation of its
+    /// stream to its end or until it is let through upstream. A request
+    /// still held back by then, e.g. one trickling its body while the hook
+    /// decides, is answered with a `408`, which ends the stream. Upgrade
+    /// requests have no deadline, their streams stay open.
+    fn request_deadline(&self) -> Option<Duration> {
+        None
+    }
 }
 
 pub struct HookHolder<H: HttpHook + 'static> {
     context: Ctx,
     inner: Rc<H>,
+    deadline: Option<Deadline>,
+    /// Whether the request arrived to its end, was let through upstream, or
+    /// the stream is gone.
+    arrived: Rc<Cell<bool>>,
+    /// Whether the request hook decided on the request.
+    decided: Rc<Cell<bool>>,
+    /// The size of the body the host buffered until then, and whether it is
+    /// all of it, checked once the request hook lets the request through.
+    buffered: Rc<Cell<Option<(usize, bool)>>>,
 }
 
 impl<H: HttpHook> HookHolder<H> {
     pub fn new(context_id: u32, inner: H) -> Self {
         Self {
             context: Ctx::new(context_id),
+            deadline: inner.request_deadline().map(Deadline::after),
             inner: Rc::new(inner),
+            arrived: Rc::new(Cell::new(false)),
+            decided: Rc::new(Cell::new(false)),
+            buffered: Rc::new(Cell::new(None)),
         }
     }
+
+    /// Answer with a `408` unless the request arrived by the deadline.
+    fn arm_deadline(&self) {
+        let Some(deadline) = self.deadline else {
+            return;
+        };
+        let ctx = self.context;
+        let arrived = self.arrived.clone();
+        spawn_local(async move {
+            sleep(deadline.remaining()).await;
+            if arrived.get() {
+                return;
+            }
+            log::info!("request of context {} did not arrive in time", ctx.id);
+            let response = Response::builder().status(408).build();
+            if let Err(e) = ctx.reject_request(&response) {
+                log::warn!("failed to time out http request: {:?}", e);
+            }
+        });
+    }
 }
 
-impl<H: HttpHook> Context for HookHolder<H> {}
+impl<H: HttpHook> Drop for HookHolder<H> {
+    fn drop(&mut self) {
+        self.arrived.set(true);
+        property::forget(self.context.id);
+        scratch::forget(self.context.id);
+    }
+}
+
+impl<H: HttpHook> Context for HookHolder<H> {
+    fn on_foreign_function(&mut self, function_id: u32, arguments_size: usize) {
+        foreign::on_event(function_id, arguments_size)
+    }
+}
 
 impl<H: HttpHook> HttpContext for HookHolder<H> {
     fn on_http_request_trailers(&mut self, _num_trailers: usize) -> Action {
+        self.arrived.set(true);
         let all = self.get_http_request_trailers();
         log::info!("all trailers: {:?}", all);
         Action::Continue
     }
     fn on_http_request_headers(&mut self, _num_headers: usize, _end_of_stream: bool) -> Action {
         log::debug!("on_http_request_headers");
+        match _end_of_stream {
+            true => self.arrived.set(true),
+            // the stream of an upgraded request never ends
+            false if self.context.is_upgrade_request().unwrap_or(false) => {}
+            false => self.arm_deadline(),
+        }
         let hook = self.inner.clone();
         let ctx = self.context;
+        let arrived = self.arrived.clone();
+        let decided = self.decided.clone();
+        let buffered = self.buffered.clone();
         spawn_local(async move {
             let res = hook.on_request_headers(_num_headers, _end_of_stream).await;
+            decided.set(true);
+            let res = res
+                .map_err(Into::into)
+                .and_then(|()| match buffered.take() {
+                    Some((body_size, end_of_stream)) => {
+                        hook.on_request_body(body_size, end_of_stream)
+                    }
+                    None => Ok(()),
+                });
+            let subrequests = trace::take(ctx.id)
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join(", ");
             let ret = match res {
-                Ok(()) => ctx.continue_request(),
+                Ok(()) => {
+                    log::debug!("continue http request, subrequests: [{}]", subrequests);
+                    // streaming the rest upstream, e.g. a gRPC client stream
+                    arrived.set(true);
+                    ctx.continue_request()
+                }
                 Err(resp) => {
-                    let resp = resp.into();
-                    let code = resp.code;
-                    let headers: Vec<(&str, &str)> = resp
-                        .headers
-                        .iter()
-                        .map(|(k, v)| (k.as_str(), v.as_str()))
-                        .collect();
-                    log::debug!("reject http request");
-                    ctx.reject_request(code, headers, resp.body.as_deref())
+                    log::debug!(
+                        "reject http request with {}, subrequests: [{}]",
+                        resp.code,
+                        subrequests
+                    );
+                    ctx.reject_request(&resp)
                 }
             };
             if let Err(e) = ret {
@@ -257,6 +605,27 @@ impl<H: HttpHook> HttpContext for HookHolder<H> {
         Action::Pause
     }
 
+    fn on_http_request_body(&mut self, body_size: usize, end_of_stream: bool) -> Action {
+        if end_of_stream {
+            self.arrived.set(true);
+        }
+        if !self.decided.get() {
+            // the host buffers the body, reporting all of it with each chunk
+            self.buffered.set(Some((body_size, end_of_stream)));
+            return Action::Pause;
+        }
+        match self.inner.on_request_body(body_size, end_of_stream) {
+            Ok(()) => Action::Continue,
+            Err(resp) => {
+                log::debug!("reject http request body with {}", resp.code);
+                if let Err(e) = self.context.reject_request(&resp) {
+                    log::warn!("failed to reject http request body: {:?}", e);
+                }
+                Action::Pause
+            }
+        }
+    }
+
     fn on_http_response_headers(&mut self, _num_headers: usize, _end_of_stream: bool) -> Action {
         log::debug!("on_http_response_headers");
         if let Some(name) = H::filter_name() {
@@ -268,6 +637,291 @@ impl<H: HttpHook> HttpContext for HookHolder<H> {
                 None => self.set_http_response_header("X-Filter-Name", Some(name)),
             }
         }
+        for (key, value) in self.inner.response_headers() {
+            self.add_http_response_header(&key, &value);
+        }
         Action::Continue
     }
+
+    fn on_log(&mut self) {
+        self.inner.on_log(&stream_info::StreamInfo::new(self.context));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use test_host::HOST;
+
+    fn headers(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    fn tick() {
+        queue::QUEUE.with(|queue| queue.on_tick());
+    }
+
+    #[test]
+    fn upgrade() {
+        assert!(is_upgrade(&headers(&[
+            ("Connection", "keep-alive, Upgrade"),
+            ("Upgrade", "websocket"),
+        ])));
+        assert!(is_upgrade(&headers(&[
+            (":method", "CONNECT"),
+            (":protocol", "websocket"),
+        ])));
+        assert!(!is_upgrade(&headers(&[("connection", "upgrade")])));
+        assert!(!is_upgrade(&headers(&[
+            ("connection", "keep-alive"),
+            ("upgrade", "h2c"),
+        ])));
+        assert!(!is_upgrade(&headers(&[(":method", "CONNECT")])));
+    }
+
+    /// Waits for the test to settle `decision`, like a hook waiting on a call.
+    struct Deferred {
+        ctx: Ctx,
+        decision: Promise,
+    }
+
+    impl HttpHook for Deferred {
+        fn filter_name() -> Option<&'static str> {
+            Some("Test")
+        }
+
+        async fn on_request_headers(
+            &self,
+            _num_headers: usize,
+            _end_of_stream: bool,
+        ) -> Result<(), impl Into<Response>> {
+            assert_eq!(self.ctx.is_upgrade_request(), Ok(true));
+            match self.decision.clone().await {
+                Ok(_) => Ok(()),
+                Err(()) => Err(Response::builder().status(403)),
+            }
+        }
+
+        fn response_headers(&self) -> Vec<(String, String)> {
+            vec![("X-Test".to_string(), "upgraded".to_string())]
+        }
+    }
+
+    fn upgrade_request(context_id: u32) -> (HookHolder<Deferred>, Promise) {
+        HOST.with(|host| {
+            host.borrow_mut().request_headers.insert(
+                context_id,
+                headers(&[
+                    (":method", "GET"),
+                    (":path", "/ws"),
+                    ("connection", "Upgrade"),
+                    ("upgrade", "websocket"),
+                ]),
+            )
+        });
+        let decision = Promise::pending();
+        let hook = Deferred {
+            ctx: Ctx::new(context_id),
+            decision: decision.clone(),
+        };
+        (HookHolder::new(context_id, hook), decision)
+    }
+
+    #[test]
+    fn resume_switching_protocols() {
+        let (mut holder, decision) = upgrade_request(7);
+        assert_eq!(holder.on_http_request_headers(4, true), Action::Pause);
+        tick();
+        assert!(HOST.with(|host| host.borrow().continued.is_empty()));
+
+        decision.resolve(Response::builder().build());
+        tick();
+        assert_eq!(HOST.with(|host| host.borrow().continued.clone()), vec![7]);
+
+        HOST.with(|host| {
+            host.borrow_mut()
+                .response_headers
+                .insert(7, headers(&[(":status", "101"), ("upgrade", "websocket")]))
+        });
+        host::set_effective_context(7).unwrap();
+        assert_eq!(holder.on_http_response_headers(2, false), Action::Continue);
+        assert_eq!(
+            HOST.with(|host| host.borrow().response_headers[&7].clone()),
+            headers(&[
+                (":status", "101"),
+                ("upgrade", "websocket"),
+                ("X-Filter-Name", "Test"),
+                ("X-Test", "upgraded"),
+            ])
+        );
+    }
+
+    #[test]
+    fn reject_upgrade() {
+        let (mut holder, decision) = upgrade_request(8);
+        assert_eq!(holder.on_http_request_headers(4, true), Action::Pause);
+        decision.reject();
+        tick();
+        HOST.with(|host| {
+            let host = host.borrow();
+            assert!(host.continued.is_empty());
+            assert_eq!(host.local_responses, vec![(8, 403)]);
+        });
+    }
+
+    /// Rejects bodies longer than `max` bytes, deciding on the headers after
+    /// `delay`.
+    struct BodyLimit {
+        max: usize,
+        delay: Duration,
+        received: std::cell::Cell<usize>,
+    }
+
+    impl BodyLimit {
+        fn new(max: usize, delay: Duration) -> Self {
+            Self {
+                max,
+                delay,
+                received: Default::default(),
+            }
+        }
+    }
+
+    impl HttpHook for BodyLimit {
+        async fn on_request_headers(
+            &self,
+            _num_headers: usize,
+            _end_of_stream: bool,
+        ) -> Result<(), impl Into<Response>> {
+            sleep(self.delay).await;
+            Ok::<_, Response>(())
+        }
+
+        fn on_request_body(&self, body_size: usize, _end_of_stream: bool) -> Result<(), Response> {
+            self.received.set(self.received.get() + body_size);
+            match self.received.get() > self.max {
+                true => Err(Response::builder().status(413).build()),
+                false => Ok(()),
+            }
+        }
+    }
+
+    #[test]
+    fn reject_body() {
+        let mut holder = HookHolder::new(9, BodyLimit::new(10, Duration::ZERO));
+        assert_eq!(holder.on_http_request_headers(2, false), Action::Pause);
+        tick();
+        HOST.with(|host| assert_eq!(host.borrow().continued, vec![9]));
+        assert_eq!(holder.on_http_request_body(6, false), Action::Continue);
+        assert_eq!(holder.on_http_request_body(6, true), Action::Pause);
+        HOST.with(|host| assert_eq!(host.borrow().local_responses, vec![(9, 413)]));
+    }
+
+    #[test]
+    fn body_before_decision() {
+        let clock = clock::TestClock::new(std::time::UNIX_EPOCH + Duration::from_secs(10_000));
+        let _guard = clock::install(clock.clone());
+        let delay = Duration::from_secs(1);
+        let mut large = HookHolder::new(12, BodyLimit::new(10, delay));
+        let mut small = HookHolder::new(13, BodyLimit::new(10, delay));
+        for holder in [&mut large, &mut small] {
+            assert_eq!(holder.on_http_request_headers(2, false), Action::Pause);
+        }
+        tick();
+        // held back until the decision, the host reports the whole buffer
+        assert_eq!(large.on_http_request_body(6, false), Action::Pause);
+        assert_eq!(large.on_http_request_body(12, true), Action::Pause);
+        assert_eq!(small.on_http_request_body(8, false), Action::Pause);
+        HOST.with(|host| {
+            let host = host.borrow();
+            assert!(host.continued.is_empty());
+            assert!(host.local_responses.is_empty());
+        });
+        clock.advance(Duration::from_secs(2));
+        tick();
+        HOST.with(|host| {
+            let host = host.borrow();
+            assert_eq!(host.local_responses, vec![(12, 413)]);
+            assert_eq!(host.continued, vec![13]);
+        });
+        assert_eq!(small.on_http_request_body(4, true), Action::Pause);
+        HOST.with(|host| assert_eq!(host.borrow().local_responses, vec![(12, 413), (13, 413)]));
+    }
+
+    /// Lets requests through after `delay`, unless they are not there within
+    /// 5 seconds.
+    struct Trickle {
+        delay: Duration,
+    }
+
+    impl HttpHook for Trickle {
+        async fn on_request_headers(
+            &self,
+            _num_headers: usize,
+            _end_of_stream: bool,
+        ) -> Result<(), impl Into<Response>> {
+            sleep(self.delay).await;
+            Ok::<_, Response>(())
+        }
+
+        fn request_deadline(&self) -> Option<Duration> {
+            Some(Duration::from_secs(5))
+        }
+    }
+
+    #[test]
+    fn request_deadline() {
+        let clock = clock::TestClock::new(std::time::UNIX_EPOCH + Duration::from_secs(10_000));
+        let _guard = clock::install(clock.clone());
+        let held = || Trickle {
+            delay: Duration::from_secs(10),
+        };
+        let mut slow = HookHolder::new(10, held());
+        let mut fast = HookHolder::new(11, held());
+        let mut streaming = HookHolder::new(
+            12,
+            Trickle {
+                delay: Duration::ZERO,
+            },
+        );
+        HOST.with(|host| {
+            host.borrow_mut().request_headers.insert(
+                13,
+                headers(&[
+                    (":method", "GET"),
+                    (":path", "/ws"),
+                    ("connection", "Upgrade"),
+                    ("upgrade", "websocket"),
+                ]),
+            )
+        });
+        let mut upgraded = HookHolder::new(13, held());
+        for holder in [&mut slow, &mut fast] {
+            assert_eq!(holder.on_http_request_headers(2, false), Action::Pause);
+        }
+        assert_eq!(streaming.on_http_request_headers(2, false), Action::Pause);
+        assert_eq!(upgraded.on_http_request_headers(4, false), Action::Pause);
+        tick();
+        HOST.with(|host| assert_eq!(host.borrow().continued, vec![12]));
+        assert_eq!(slow.on_http_request_body(10, false), Action::Pause);
+        assert_eq!(fast.on_http_request_body(10, true), Action::Pause);
+        assert_eq!(streaming.on_http_request_body(10, false), Action::Continue);
+        clock.advance(Duration::from_secs(4));
+        tick();
+        HOST.with(|host| assert!(host.borrow().local_responses.is_empty()));
+        clock.advance(Duration::from_secs(2));
+        tick();
+        HOST.with(|host| assert_eq!(host.borrow().local_responses, vec![(10, 408)]));
+        // the upgraded stream outlives the deadline and is let through
+        clock.advance(Duration::from_secs(5));
+        tick();
+        HOST.with(|host| {
+            let host = host.borrow();
+            assert_eq!(host.local_responses, vec![(10, 408)]);
+            assert!(host.continued.contains(&13));
+        });
+    }
 }
@@ -1,5 +1,5 @@
 use std::any::type_name;
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 use std::collections::{HashMap, VecDeque};
 use std::fmt::Debug;
 use std::future::Future;
@@ -13,7 +13,8 @@ use proxy_wasm::types::Status;
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 
-use super::codec::Codec;
+use super::capabilities;
+use super::codec::{Codec, DefaultCodec};
 
 #[derive(Clone, Copy, PartialEq, Eq, Hash)]
 pub struct QueueId(pub u32);
@@ -62,18 +63,70 @@ pub(crate) fn wake_tasks(queue_id: QueueId) {
     });
 }
 
+/// Resolves the next time the host reports `queue_id` ready, i.e. someone
+/// enqueued to it. Messages are left in the queue for the caller to dequeue.
+pub fn queue_ready(queue_id: QueueId) -> QueueReady {
+    QueueReady {
+        queue_id,
+        registered: false,
+    }
+}
+
+pub struct QueueReady {
+    queue_id: QueueId,
+    registered: bool,
+}
+
+impl Future for QueueReady {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        if this.registered {
+            return Poll::Ready(());
+        }
+        this.registered = true;
+        push_task(this.queue_id, cx.waker().clone());
+        Poll::Pending
+    }
+}
+
 thread_local! {
     pub(crate) static QUEUE_MAP: QueueMap = QueueMap::new();
+    static CONTENTION: Cell<Contention> = const {
+        Cell::new(Contention { acquired: 0, contended: 0 })
+    };
+}
+
+/// How often the locks of this VM had to wait for another holder.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Contention {
+    /// Locks acquired since the VM started.
+    pub acquired: u64,
+    /// Attempts that found the lock held or lost a race for it.
+    pub contended: u64,
+}
+
+/// The lock statistics of this VM.
+pub fn contention() -> Contention {
+    CONTENTION.with(Cell::get)
+}
+
+fn count(f: impl FnOnce(&mut Contention)) {
+    CONTENTION.with(|contention| {
+        let mut value = contention.get();
+        f(&mut value);
+        contention.set(value);
+    });
 }
 
 #[derive(Debug, Serialize, Deserialize)]
-struct Store<T: Codec> {
+struct Store<T> {
     state: StoreState,
     data: T
 }
 
 #[derive(Debug, Serialize, Deserialize)]
-#[cfg_attr(feature = "serde_json", serde(tag = "type"))]
 enum StoreState {
     Unlocked,
     Locked {
@@ -83,7 +136,7 @@ enum StoreState {
     },
 }
 
-impl <T: Codec> Store<T> {
+impl <T> Store<T> {
     fn new(data: T) -> Self {
         Store {
             state: StoreState::Unlocked,
@@ -126,19 +179,18 @@ pub enum Error {
     #[error("shared data is locked")]
     Locked,
 
-    #[cfg(feature = "bincode")]
-    #[error("failed to encode/decode shared data: {0}")]
-    Codec(#[from] bincode::Error),
-
-    #[cfg(feature = "serde_json")]
     #[error("failed to encode/decode shared data: {0}")]
-    Codec(#[from] serde_json::Error),
+    Codec(Box<dyn std::error::Error>),
 }
 
 impl Error {
     fn status(reason: String, status: proxy_wasm::types::Status) -> Self {
         Error::Status { reason, status }
     }
+
+    fn codec(error: impl std::error::Error + 'static) -> Self {
+        Error::Codec(Box::new(error))
+    }
 }
 
 /// A structure that represents a lock on shared data.
@@ -150,12 +202,14 @@ impl Error {
 /// # Type Parameters
 ///
 /// * `S` - The type of the shared data that this lock protects.
-pub struct SharedDataLock<S> {
+/// * `C` - How it is encoded, see [`crate::codec`].
+pub struct SharedDataLock<S, C = DefaultCodec> {
     context_id: u32,
-    queue_id: QueueId,
+    /// `None` on hosts without shared queues, waiters then retry every tick.
+    queue_id: Option<QueueId>,
     /// A unique key associated with the shared data type.
     key: &'static str,
-    _phantom: PhantomData<S>,
+    _phantom: PhantomData<(S, C)>,
 }
 
 /// A guard that provides temporary access to the shared data
@@ -164,19 +218,19 @@ pub struct SharedDataLock<S> {
 /// The lock is released when this guard is dropped, ensuring
 /// that the shared data is safely accessible while the guard
 /// is in scope.
-pub struct SharedDataLockGuard<'a, S> 
+pub struct SharedDataLockGuard<'a, S, C: Codec = DefaultCodec>
 where 
     S: Serialize + DeserializeOwned
 {
-    lock: &'a SharedDataLock<S>,
+    lock: &'a SharedDataLock<S, C>,
     store: Store<S>,
 }
 
-impl<'a, S> SharedDataLockGuard<'a, S> 
+impl<'a, S, C: Codec> SharedDataLockGuard<'a, S, C>
 where 
     S: Serialize + DeserializeOwned
 {
-    fn new(lock: &'a SharedDataLock<S>, store: Store<S>) -> Self {
+    fn new(lock: &'a SharedDataLock<S, C>, store: Store<S>) -> Self {
         SharedDataLockGuard {
             lock,
             store,
@@ -184,17 +238,17 @@ where
     }
 }
 
-impl <S> Drop for SharedDataLockGuard<'_, S> 
+impl <S, C: Codec> Drop for SharedDataLockGuard<'_, S, C>
 where
     S: Serialize + DeserializeOwned
 {
     fn drop(&mut self) {
-        set_and_unlock_shared_data(self.lock.key, self.lock.queue_id, &mut self.store)
+        set_and_unlock_shared_data::<S, C>(self.lock.key, self.lock.queue_id, &mut self.store)
             .expect("failed to unlock shared data");
     }
 }
 
-impl <S> Deref for SharedDataLockGuard<'_, S> 
+impl <S, C: Codec> Deref for SharedDataLockGuard<'_, S, C>
 where 
     S: Serialize + DeserializeOwned
 {
@@ -205,7 +259,7 @@ where
     }
 }
 
-impl <S> DerefMut for SharedDataLockGuard<'_, S> 
+impl <S, C: Codec> DerefMut for SharedDataLockGuard<'_, S, C>
 where 
     S: Serialize + DeserializeOwned
 {
@@ -214,12 +268,17 @@ where
     }
 }
 
-impl<S: 'static> SharedDataLock<S> {
+impl<S: 'static, C: Codec> SharedDataLock<S, C> {
     /// Create a new lock for the given shared data.
     pub fn new(context_id: u32) -> Self {
         let key = type_name::<S>();
-        let queue_id = QueueId(hostcalls::register_shared_queue(key)
-            .expect("failed to register shared queue"));
+        let queue_id = if capabilities::get().shared_queue {
+            Some(QueueId(hostcalls::register_shared_queue(key)
+                .expect("failed to register shared queue")))
+        } else {
+            capabilities::degraded("shared queues", "shared data lock waiters retry every tick");
+            None
+        };
         SharedDataLock {
             context_id,
             queue_id,
@@ -233,7 +292,7 @@ impl<S: 'static> SharedDataLock<S> {
         S: Serialize + DeserializeOwned 
     {
         let store = Store::new(data);
-        let raw = &store.encode()
+        let raw = &C::encode(&store)
             .expect("failed to serialize shared data");
 
         match hostcalls::set_shared_data(self.key, Some(raw), None) {
@@ -244,13 +303,13 @@ impl<S: 'static> SharedDataLock<S> {
     }
 
     /// Acquire a lock on the shared data.
-    pub fn lock(&self) -> TryLock<S> {
+    pub fn lock(&self) -> TryLock<'_, S, C> {
         TryLock { lock: self, gone: false }
     }
 
     pub fn read(&self) -> Result<S, Error> 
     where S: Serialize + DeserializeOwned  {
-        match get_shared_data::<Store<S>>(self.key) {
+        match get_shared_data::<Store<S>, C>(self.key) {
             Ok((Some(store), _)) => Ok(store.data),
             Ok((None, _)) => Err(Error::Uninitialized),
             Err(err) => Err(err),
@@ -260,16 +319,16 @@ impl<S: 'static> SharedDataLock<S> {
 
 
 
-pub struct TryLock<'a, S> {
-    lock: &'a SharedDataLock<S>,
+pub struct TryLock<'a, S, C = DefaultCodec> {
+    lock: &'a SharedDataLock<S, C>,
     gone: bool,
 }
 
-impl<'a, S> Future for TryLock<'a, S> 
+impl<'a, S, C: Codec> Future for TryLock<'a, S, C>
 where 
     S: Serialize + DeserializeOwned + Debug
 {
-    type Output = Result<SharedDataLockGuard<'a, S>, Error>;
+    type Output = Result<SharedDataLockGuard<'a, S, C>, Error>;
 
     fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
         let this = self.get_mut();
@@ -278,14 +337,19 @@ where
             panic!("polling a resolved promise");
         }
 
-        let res = get_and_lock_shared_data(this.lock.key, this.lock.context_id); // todo: change me
+        let res = get_and_lock_shared_data::<S, C>(this.lock.key, this.lock.context_id); // todo: change me
         match res {
             Ok(store) => {
                 this.gone = true;
+                count(|contention| contention.acquired += 1);
                 Poll::Ready(Ok(SharedDataLockGuard::new(this.lock, store)))
             }
             Err(Error::CasMismatch | Error::Locked) => {
-                push_task(this.lock.queue_id, cx.waker().clone());
+                count(|contention| contention.contended += 1);
+                match this.lock.queue_id {
+                    Some(queue_id) => push_task(queue_id, cx.waker().clone()),
+                    None => cx.waker().wake_by_ref(),
+                }
                 Poll::Pending
             }
             Err(err) => {
@@ -296,22 +360,27 @@ where
     }
 }
 
-pub fn get_shared_data<T: Serialize + DeserializeOwned>(key: &str) -> Result<(Option<T>, Option<u32>), Error> {
+pub fn get_shared_data<T, C>(key: &str) -> Result<(Option<T>, Option<u32>), Error>
+where
+    T: Serialize + DeserializeOwned,
+    C: Codec,
+{
     let (raw, cas) = hostcalls::get_shared_data(key)
         .map_err(|status| Error::status("failed to get shared data".to_string(), status))?;
 
     match raw {
         None => Ok((None, cas)),
         Some(vec) => {
-            let data = T::decode(&vec)?;
+            let data = C::decode(&vec).map_err(Error::codec)?;
             Ok((Some(data), cas))
         }
     }
 }
 
-fn get_and_lock_shared_data<T>(key: &str, holder: u32) -> Result<Store<T>, Error> 
+fn get_and_lock_shared_data<T, C>(key: &str, holder: u32) -> Result<Store<T>, Error>
 where 
-    T: Serialize + DeserializeOwned + Debug
+    T: Serialize + DeserializeOwned + Debug,
+    C: Codec,
 {
     let (raw, cas) = hostcalls::get_shared_data(key)
         .map_err(|status| Error::status("failed to get shared data".to_string(), status))?;
@@ -330,14 +399,14 @@ where
         });
     };
 
-    let mut store: Store<T> = Store::<T>::decode(&vec)?;
+    let mut store: Store<T> = C::decode(&vec).map_err(Error::codec)?;
 
     if store.is_locked() {
         return Err(Error::Locked);
     }
 
     store.turn_lock(holder, cas);
-    let raw = &store.encode()?;
+    let raw = &C::encode(&store).map_err(Error::codec)?;
     let Err(status) = hostcalls::set_shared_data(key, Some(raw), Some(cas)) else {
         return Ok(store)
     };
@@ -349,23 +418,31 @@ where
     Err(err)
 }
 
-fn set_and_unlock_shared_data<T>(key: &str, queue_id: QueueId, store: &mut Store<T>) -> Result<(), Error> 
+fn set_and_unlock_shared_data<T, C>(
+    key: &str,
+    queue_id: Option<QueueId>,
+    store: &mut Store<T>,
+) -> Result<(), Error>
 where 
-    T: Serialize + DeserializeOwned {
+    T: Serialize + DeserializeOwned,
+    C: Codec,
+{
     if let StoreState::Unlocked = &store.state {
         log::error!("???");
         return Ok(())
     };
 
     store.turn_unlock();
-    let raw = &store.encode()?;
+    let raw = &C::encode(store).map_err(Error::codec)?;
 
     loop {
         let (_, cas) = hostcalls::get_shared_data(key)
             .map_err(|status| Error::status("failed to get cas when unlock data".to_string(), status))?;
         let Err(status) = hostcalls::set_shared_data(key, Some(raw), cas) else {
-            hostcalls::enqueue_shared_queue(queue_id.0, None) // TODO: change me
-                .map_err(|status| Error::status("failed to enqueue shared queue".to_string(), status))?;
+            if let Some(queue_id) = queue_id {
+                hostcalls::enqueue_shared_queue(queue_id.0, None) // TODO: change me
+                    .map_err(|status| Error::status("failed to enqueue shared queue".to_string(), status))?;
+            }
             return Ok(())
         };
 
@@ -377,24 +454,22 @@ where
 }
 
 fn current_timestamp() -> u64 {
-    std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)
-        .expect("failed to get timestamp")
-        .as_secs()
+    crate::clock::unix_secs()
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::codec::JsonCodec;
 
     #[derive(Debug, Serialize, Deserialize)]
     struct Wukong {
         name: String
     }
     
-    #[cfg(feature = "serde_json")]
     #[test]
     fn test_shared_data_lock() {
-        let json = "{\"state\":{\"type\":\"Unlocked\"},\"data\":{\"name\":\"Sun\"}}";
-        let _data: Store<Wukong> = serde_json::from_str(json).expect("failed to deserialize shared data");
+        let json = br#"{"state":"Unlocked","data":{"name":"Sun"}}"#;
+        let _data: Store<Wukong> = JsonCodec::decode(json).expect("failed to deserialize shared data");
     }
 }
\ No newline at end of file
@@ -0,0 +1,105 @@
+//! Approximate accounting of the memory held by in-VM caches.
+//!
+//! Every cache of a filter keeps an [`Account`] of the bytes it holds, which
+//! is published as a gauge so operators can see the filter's share of the
+//! wasm memory. An account may have a cap, the cache is expected to evict its
+//! least recently used entries while [`Account::excess`] is non-zero.
+//!
+//! Sizes count the entries and their heap allocations, not the allocator or
+//! hash table overhead, so treat caps as an order of magnitude.
+
+use std::mem::size_of;
+
+use crate::metrics;
+
+#[derive(Debug)]
+pub struct Account {
+    gauge: String,
+    cap: Option<usize>,
+    bytes: usize,
+    reported: Option<usize>,
+}
+
+impl Account {
+    pub fn new(gauge: impl Into<String>, cap: Option<usize>) -> Self {
+        Self {
+            gauge: gauge.into(),
+            cap,
+            bytes: 0,
+            reported: None,
+        }
+    }
+
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    pub fn cap(&self) -> Option<usize> {
+        self.cap
+    }
+
+    pub fn charge(&mut self, bytes: usize) {
+        self.bytes += bytes;
+    }
+
+    pub fn release(&mut self, bytes: usize) {
+        self.bytes = self.bytes.saturating_sub(bytes);
+    }
+
+    pub fn set(&mut self, bytes: usize) {
+        self.bytes = bytes;
+    }
+
+    /// Bytes above the cap, always 0 without one.
+    pub fn excess(&self) -> usize {
+        match self.cap {
+            Some(cap) => self.bytes.saturating_sub(cap),
+            None => 0,
+        }
+    }
+
+    /// Bytes to free to get back to `fraction` of the cap, so evicting in
+    /// batches does not run on every insert once the cap is reached.
+    pub fn excess_over(&self, fraction: f64) -> usize {
+        match self.cap {
+            Some(cap) => self.bytes.saturating_sub((cap as f64 * fraction) as usize),
+            None => 0,
+        }
+    }
+
+    /// Record the gauge if the usage changed since the last report.
+    pub fn report(&mut self) {
+        if self.reported != Some(self.bytes) {
+            metrics::record_gauge(&self.gauge, self.bytes as u64);
+            self.reported = Some(self.bytes);
+        }
+    }
+}
+
+/// Approximate bytes of a map entry with a `String` key and a `V` value.
+pub fn entry_size<V>(key: &str) -> usize {
+    size_of::<String>() + key.len() + size_of::<V>()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn cap() {
+        let mut account = Account::new("test.memory", Some(100));
+        account.charge(80);
+        assert_eq!(account.excess(), 0);
+        account.charge(40);
+        assert_eq!(account.excess(), 20);
+        assert_eq!(account.excess_over(0.75), 45);
+        account.release(200);
+        assert_eq!(account.bytes(), 0);
+
+        let mut unbounded = Account::new("test.memory", None);
+        unbounded.set(usize::MAX);
+        assert_eq!(unbounded.excess(), 0);
+        assert_eq!(unbounded.excess_over(0.5), 0);
+        assert_eq!(entry_size::<u64>("key"), size_of::<String>() + 3 + 8);
+    }
+}
@@ -0,0 +1,53 @@
+use std::{cell::RefCell, collections::HashMap};
+
+use proxy_wasm::{hostcalls, types::MetricType};
+
+use crate::capabilities;
+
+thread_local! {
+    static METRICS: RefCell<HashMap<String, u32>> = RefCell::new(HashMap::new());
+}
+
+/// Look up the id of `name`, defining the metric on first use.
+fn metric_id(metric_type: MetricType, name: &str) -> Option<u32> {
+    if !capabilities::get().metrics {
+        capabilities::degraded("metrics", "metrics are not recorded");
+        return None;
+    }
+    METRICS.with(|metrics| {
+        if let Some(id) = metrics.borrow().get(name) {
+            return Some(*id);
+        }
+        match hostcalls::define_metric(metric_type, name) {
+            Ok(id) => {
+                metrics.borrow_mut().insert(name.to_string(), id);
+                Some(id)
+            }
+            Err(e) => {
+                log::debug!("failed to define metric {}: {:?}", name, e);
+                None
+            }
+        }
+    })
+}
+
+/// Add `offset` to the counter `name`. Metrics are best effort, failures are
+/// only logged.
+pub fn increment_counter(name: &str, offset: i64) {
+    let Some(id) = metric_id(MetricType::Counter, name) else {
+        return;
+    };
+    if let Err(e) = hostcalls::increment_metric(id, offset) {
+        log::debug!("failed to increment metric {}: {:?}", name, e);
+    }
+}
+
+/// Set the gauge `name` to `value`.
+pub fn record_gauge(name: &str, value: u64) {
+    let Some(id) = metric_id(MetricType::Gauge, name) else {
+        return;
+    };
+    if let Err(e) = hostcalls::record_metric(id, value) {
+        log::debug!("failed to record metric {}: {:?}", name, e);
+    }
+}
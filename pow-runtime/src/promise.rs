@@ -28,15 +28,19 @@ impl Promise {
         }
     }
 
-    pub fn resolve(&self, response: Response) {
-        let old = self.inner.replace(InnerPromise::Resolved(response));
+    fn settle(&self, state: InnerPromise) {
+        let old = self.inner.replace(state);
         if let InnerPromise::Pending(Some(waker)) = old {
             waker.wake();
         }
     }
 
+    pub fn resolve(&self, response: Response) {
+        self.settle(InnerPromise::Resolved(response));
+    }
+
     pub fn reject(&self) {
-        self.inner.replace(InnerPromise::Rejected);
+        self.settle(InnerPromise::Rejected);
     }
 }
 
@@ -51,12 +55,12 @@ impl Future for Promise {
             }
             Poll::Pending
         } else if let InnerPromise::Rejected = *inner {
-            return Poll::Ready(Err(()));
+            Poll::Ready(Err(()))
         } else if let InnerPromise::Gone(()) = *inner {
             panic!("polling a resolved promise");
         } else {
             match std::mem::replace(&mut *inner, InnerPromise::Gone(())) {
-                InnerPromise::Resolved(response) => return Poll::Ready(Ok(response)),
+                InnerPromise::Resolved(response) => Poll::Ready(Ok(response)),
                 _ => unreachable!(),
             }
         }
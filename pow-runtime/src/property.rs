@@ -0,0 +1,175 @@
+//! Properties of a request or connection, read through [`Ctx::property`].
+//!
+//! A property is read from the host once per context, later reads of the
+//! same path are answered from memory until the context is deleted. Values
+//! are encoded as the proxy-wasm attribute specification says: strings as
+//! UTF-8, integers as little-endian `i64`, timestamps and durations as
+//! nanoseconds in an `i64`.
+//!
+//! Hosts without properties read every property as missing, see
+//! [`crate::host`].
+//!
+//! [`Ctx::property`]: crate::Ctx::property
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use proxy_wasm::types::Status;
+
+use crate::host;
+
+/// E.g. `TLSv1.3`, absent on plain connections.
+pub const TLS_VERSION: &[&str] = &["connection", "tls_version"];
+/// The SNI of TLS connections.
+pub const REQUESTED_SERVER_NAME: &[&str] = &["connection", "requested_server_name"];
+pub const SUBJECT_PEER_CERTIFICATE: &[&str] = &["connection", "subject_peer_certificate"];
+pub const URI_SAN_PEER_CERTIFICATE: &[&str] = &["connection", "uri_san_peer_certificate"];
+pub const DNS_SAN_PEER_CERTIFICATE: &[&str] = &["connection", "dns_san_peer_certificate"];
+/// When the first byte of the request arrived.
+pub const REQUEST_TIME: &[&str] = &["request", "time"];
+/// Until the end of the stream, known once it is logged.
+pub const REQUEST_DURATION: &[&str] = &["request", "duration"];
+pub const RESPONSE_CODE: &[&str] = &["response", "code"];
+/// Bytes of the response, headers included.
+pub const RESPONSE_TOTAL_SIZE: &[&str] = &["response", "total_size"];
+
+type Cache = HashMap<Vec<String>, Option<Vec<u8>>>;
+
+thread_local! {
+    static CACHE: RefCell<HashMap<u32, Cache>> = RefCell::new(HashMap::new());
+}
+
+/// The property at `path` of `context_id`.
+pub(crate) fn get(context_id: u32, path: &[&str]) -> Result<Value, Status> {
+    let key: Vec<String> = path.iter().map(|part| part.to_string()).collect();
+    let cached = CACHE.with(|cache| {
+        cache
+            .borrow()
+            .get(&context_id)
+            .and_then(|properties| properties.get(&key).cloned())
+    });
+    if let Some(value) = cached {
+        return Ok(Value(value));
+    }
+    host::set_effective_context(context_id)?;
+    let value = host::get_property(path.to_vec())?;
+    CACHE.with(|cache| {
+        cache
+            .borrow_mut()
+            .entry(context_id)
+            .or_default()
+            .insert(key, value.clone())
+    });
+    Ok(Value(value))
+}
+
+/// Drop the properties read for `context_id`, once it is deleted.
+pub(crate) fn forget(context_id: u32) {
+    // contexts still alive when the thread exits are dropped after the cache
+    let _ = CACHE.try_with(|cache| cache.borrow_mut().remove(&context_id));
+}
+
+/// A property as the host encoded it, `None` when missing.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Value(Option<Vec<u8>>);
+
+impl Value {
+    pub fn bytes(&self) -> Option<&[u8]> {
+        self.0.as_deref()
+    }
+
+    pub fn into_bytes(self) -> Option<Vec<u8>> {
+        self.0
+    }
+
+    /// `None` also when the value is not UTF-8.
+    pub fn string(&self) -> Option<String> {
+        String::from_utf8(self.0.clone()?).ok()
+    }
+
+    pub fn int(&self) -> Option<i64> {
+        Some(i64::from_le_bytes(self.0.as_deref()?.try_into().ok()?))
+    }
+
+    pub fn bool(&self) -> Option<bool> {
+        match self.0.as_deref()? {
+            [byte] => Some(*byte != 0),
+            _ => None,
+        }
+    }
+
+    pub fn timestamp(&self) -> Option<SystemTime> {
+        let nanos = self.int()?;
+        let offset = Duration::from_nanos(nanos.unsigned_abs());
+        match nanos >= 0 {
+            true => UNIX_EPOCH.checked_add(offset),
+            false => UNIX_EPOCH.checked_sub(offset),
+        }
+    }
+
+    pub fn duration(&self) -> Option<Duration> {
+        Some(Duration::from_nanos(self.int()?.try_into().ok()?))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Ctx;
+    use test_host::HOST;
+
+    fn set(context_id: u32, path: &str, value: &[u8]) {
+        HOST.with(|host| {
+            host.borrow_mut()
+                .properties
+                .insert((context_id, path.to_string()), value.to_vec())
+        });
+    }
+
+    #[test]
+    fn typed() {
+        let nanos: i64 = 1_718_000_000_123_456_789;
+        set(5, "connection.tls_version", b"TLSv1.3");
+        set(5, "request.time", &nanos.to_le_bytes());
+        set(5, "request.duration", &2_000_000i64.to_le_bytes());
+        set(5, "connection.mtls", &[1]);
+        let ctx = Ctx::new(5);
+        assert_eq!(ctx.tls_version().unwrap(), Some("TLSv1.3".to_string()));
+        assert_eq!(
+            ctx.request_time().unwrap(),
+            Some(UNIX_EPOCH + Duration::from_nanos(nanos as u64))
+        );
+        let duration = ctx.property(&["request", "duration"]).unwrap();
+        assert_eq!(duration.duration(), Some(Duration::from_millis(2)));
+        assert_eq!(duration.string(), None);
+        assert_eq!(ctx.property(&["connection", "mtls"]).unwrap().bool(), Some(true));
+        assert_eq!(ctx.requested_server_name().unwrap(), None);
+        assert_eq!(ctx.property(TLS_VERSION).unwrap().int(), None);
+    }
+
+    #[test]
+    fn memoized() {
+        set(6, "connection.requested_server_name", b"api.example.com");
+        let ctx = Ctx::new(6);
+        assert_eq!(
+            ctx.requested_server_name().unwrap(),
+            Some("api.example.com".to_string())
+        );
+        assert_eq!(ctx.peer_subject().unwrap(), None);
+        set(6, "connection.requested_server_name", b"other.example.com");
+        set(6, "connection.subject_peer_certificate", b"CN=ops");
+        assert_eq!(
+            ctx.requested_server_name().unwrap(),
+            Some("api.example.com".to_string())
+        );
+        assert_eq!(ctx.peer_subject().unwrap(), None);
+
+        forget(6);
+        assert_eq!(
+            ctx.requested_server_name().unwrap(),
+            Some("other.example.com".to_string())
+        );
+        assert_eq!(ctx.peer_subject().unwrap(), Some("CN=ops".to_string()));
+    }
+}
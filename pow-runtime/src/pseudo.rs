@@ -0,0 +1,254 @@
+//! Typed request pseudo headers, see [`Ctx::method`], [`Ctx::scheme`] and
+//! [`Ctx::authority`].
+//!
+//! Hosts present HTTP/1.1, HTTP/2 and HTTP/3 requests alike with the HTTP/2
+//! pseudo headers, but not always all of them:
+//!
+//! - an HTTP/3 (and some HTTP/1.1) request may carry `host` instead of
+//!   `:authority`, which is used as a fallback. A repeated or comma joined
+//!   authority is only accepted when all values agree, otherwise the request
+//!   has none, so routing cannot be steered by the one a filter happens to see.
+//! - a CONNECT has no `:scheme` or `:path`, its `:authority` is the target.
+//!   An extended CONNECT (RFC 8441, RFC 9220) has both and a `:protocol`,
+//!   e.g. `websocket` or `connect-udp` (RFC 9298), whose target is in the
+//!   `:path` and whose `:authority` is the proxy itself.
+//!
+//! [`Ctx::method`]: crate::Ctx::method
+//! [`Ctx::scheme`]: crate::Ctx::scheme
+//! [`Ctx::authority`]: crate::Ctx::authority
+
+use std::fmt;
+
+pub const METHOD: &str = ":method";
+pub const SCHEME: &str = ":scheme";
+pub const AUTHORITY: &str = ":authority";
+pub const PATH: &str = ":path";
+pub const PROTOCOL: &str = ":protocol";
+
+/// Methods are case sensitive, `get` is [`Method::Other`].
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Method {
+    Get,
+    Head,
+    Post,
+    Put,
+    Delete,
+    Connect,
+    Options,
+    Trace,
+    Patch,
+    Other(String),
+}
+
+impl Method {
+    pub fn parse(value: &str) -> Self {
+        match value {
+            "GET" => Method::Get,
+            "HEAD" => Method::Head,
+            "POST" => Method::Post,
+            "PUT" => Method::Put,
+            "DELETE" => Method::Delete,
+            "CONNECT" => Method::Connect,
+            "OPTIONS" => Method::Options,
+            "TRACE" => Method::Trace,
+            "PATCH" => Method::Patch,
+            other => Method::Other(other.to_string()),
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        match self {
+            Method::Get => "GET",
+            Method::Head => "HEAD",
+            Method::Post => "POST",
+            Method::Put => "PUT",
+            Method::Delete => "DELETE",
+            Method::Connect => "CONNECT",
+            Method::Options => "OPTIONS",
+            Method::Trace => "TRACE",
+            Method::Patch => "PATCH",
+            Method::Other(other) => other,
+        }
+    }
+}
+
+impl fmt::Display for Method {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Schemes are case insensitive and kept lowercase.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Scheme {
+    Http,
+    Https,
+    Other(String),
+}
+
+impl Scheme {
+    pub fn parse(value: &str) -> Self {
+        match value.to_ascii_lowercase().as_str() {
+            "http" => Scheme::Http,
+            "https" => Scheme::Https,
+            other => Scheme::Other(other.to_string()),
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        match self {
+            Scheme::Http => "http",
+            Scheme::Https => "https",
+            Scheme::Other(other) => other,
+        }
+    }
+}
+
+impl fmt::Display for Scheme {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// `host[:port]` of a request, the host lowercase and an IPv6 literal
+/// without its brackets.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Authority {
+    host: String,
+    port: Option<u16>,
+}
+
+impl Authority {
+    /// `None` for an empty host, a bad port or userinfo, which HTTP/2 and
+    /// HTTP/3 forbid.
+    pub fn parse(value: &str) -> Option<Self> {
+        let value = value.trim();
+        if value.contains('@') {
+            return None;
+        }
+        let (host, port) = match value.strip_prefix('[') {
+            Some(rest) => {
+                let (host, rest) = rest.split_once(']')?;
+                match rest {
+                    "" => (host, None),
+                    _ => (host, Some(rest.strip_prefix(':')?)),
+                }
+            }
+            None => match value.rsplit_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (value, None),
+            },
+        };
+        if host.is_empty() || host.contains(|c: char| c.is_ascii_whitespace() || c == '/') {
+            return None;
+        }
+        let port = match port {
+            Some(port) => Some(port.parse().ok()?),
+            None => None,
+        };
+        Some(Self {
+            host: host.to_ascii_lowercase(),
+            port,
+        })
+    }
+
+    /// The authority of a request with these `:authority` and `host` values:
+    /// `:authority` if present, `host` otherwise.
+    pub fn select<'a>(
+        authority: impl IntoIterator<Item = &'a str>,
+        host: impl IntoIterator<Item = &'a str>,
+    ) -> Option<Self> {
+        let agreed = |values: Vec<&str>| {
+            let mut parsed = values
+                .iter()
+                .flat_map(|value| value.split(','))
+                .map(Self::parse);
+            let first = parsed.next()?;
+            parsed
+                .all(|other| other == first)
+                .then_some(first)
+                .flatten()
+        };
+        let authority: Vec<&str> = authority.into_iter().collect();
+        if !authority.is_empty() {
+            return agreed(authority);
+        }
+        agreed(host.into_iter().collect())
+    }
+
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    pub fn port(&self) -> Option<u16> {
+        self.port
+    }
+}
+
+impl fmt::Display for Authority {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.host.contains(':') {
+            write!(f, "[{}]", self.host)?;
+        } else {
+            f.write_str(&self.host)?;
+        }
+        match self.port {
+            Some(port) => write!(f, ":{}", port),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn method_and_scheme() {
+        assert_eq!(Method::parse("GET"), Method::Get);
+        assert_eq!(Method::parse("get"), Method::Other("get".to_string()));
+        assert_eq!(Method::parse("PROPFIND").as_str(), "PROPFIND");
+        assert_eq!(Scheme::parse("HTTPS"), Scheme::Https);
+        assert_eq!(Scheme::parse("masque").to_string(), "masque");
+    }
+
+    #[test]
+    fn authority() {
+        let parse = |value| Authority::parse(value).map(|a| a.to_string());
+        assert_eq!(parse("Example.COM"), Some("example.com".to_string()));
+        assert_eq!(
+            parse("example.com:8443"),
+            Some("example.com:8443".to_string())
+        );
+        assert_eq!(
+            parse("[2001:DB8::1]:443"),
+            Some("[2001:db8::1]:443".to_string())
+        );
+        assert_eq!(parse("[::1]"), Some("[::1]".to_string()));
+        assert_eq!(Authority::parse("[::1]:80").unwrap().host(), "::1");
+        assert_eq!(parse("user@example.com"), None);
+        assert_eq!(parse("example.com:http"), None);
+        assert_eq!(parse(":443"), None);
+        assert_eq!(parse("[::1"), None);
+        assert_eq!(parse(""), None);
+    }
+
+    #[test]
+    fn select() {
+        let select = |authority: &[&str], host: &[&str]| {
+            Authority::select(authority.iter().copied(), host.iter().copied())
+                .map(|a| a.to_string())
+        };
+        let example = Some("example.com".to_string());
+        assert_eq!(select(&["example.com"], &["other.com"]), example);
+        // HTTP/3 with `host` only
+        assert_eq!(select(&[], &["Example.com"]), example);
+        assert_eq!(select(&["example.com", "EXAMPLE.com"], &[]), example);
+        assert_eq!(select(&["example.com, evil.com"], &[]), None);
+        assert_eq!(
+            select(&["example.com"; 2], &["example.com,evil.com"]),
+            example
+        );
+        assert_eq!(select(&[], &[]), None);
+    }
+}
@@ -0,0 +1,108 @@
+//! Query string parameters of a request, see [`Ctx::get_query_params`].
+//!
+//! Keys and values are decoded like `application/x-www-form-urlencoded`:
+//! `+` is a space and percent-encodings are decoded, invalid UTF-8 replaced.
+//! A parameter may repeat, `?tag=a&tag=b`, the order of the query string is
+//! kept.
+//!
+//! [`Ctx::get_query_params`]: crate::Ctx::get_query_params
+
+use std::str::FromStr;
+
+use percent_encoding::percent_decode_str;
+
+fn decode(s: &str) -> String {
+    percent_decode_str(&s.replace('+', " "))
+        .decode_utf8_lossy()
+        .into_owned()
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct QueryParams(Vec<(String, String)>);
+
+impl QueryParams {
+    /// The parameters of the query string of `path`, a `:path` header. A
+    /// parameter without `=` has an empty value.
+    pub fn parse(path: &str) -> Self {
+        let Some((_, query)) = path.split_once('?') else {
+            return Self::default();
+        };
+        // a fragment is never sent, but cut it off anyway
+        let query = query.split_once('#').map_or(query, |(query, _)| query);
+        QueryParams(
+            query
+                .split('&')
+                .filter(|pair| !pair.is_empty())
+                .map(|pair| {
+                    let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+                    (decode(key), decode(value))
+                })
+                .collect(),
+        )
+    }
+
+    /// The first value of `key`.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Every value of `key`, in order.
+    pub fn get_all<'a>(&'a self, key: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.0
+            .iter()
+            .filter(move |(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// The first value of `key` parsed as a `T`, `Ok(None)` when absent.
+    pub fn get_as<T: FromStr>(&self, key: &str) -> Result<Option<T>, T::Err> {
+        self.get(key).map(str::parse).transpose()
+    }
+
+    /// Whether `key` is present, with or without a value.
+    pub fn contains(&self, key: &str) -> bool {
+        self.get(key).is_some()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.0.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let params = QueryParams::parse("/api?key=a%2Bb&tag=x&limit=10&tag=y+z&flag&&=v#top");
+        assert_eq!(params.get("key"), Some("a+b"));
+        assert_eq!(params.get("tag"), Some("x"));
+        assert_eq!(params.get_all("tag").collect::<Vec<_>>(), vec!["x", "y z"]);
+        assert_eq!(params.get_as::<u32>("limit"), Ok(Some(10)));
+        assert_eq!(params.get_as::<u32>("missing"), Ok(None));
+        assert!(params.get_as::<u32>("key").is_err());
+        assert!(params.contains("flag"));
+        assert_eq!(params.get("flag"), Some(""));
+        assert_eq!(params.get(""), Some("v"));
+        assert_eq!(
+            params.iter().map(|(k, _)| k).collect::<Vec<_>>(),
+            vec!["key", "tag", "limit", "tag", "flag", ""]
+        );
+
+        assert!(QueryParams::parse("/api").is_empty());
+        assert!(QueryParams::parse("/api?").is_empty());
+        assert_eq!(QueryParams::parse("/?caf%C3%A9=%FF").get("café"), Some("\u{FFFD}"));
+    }
+}
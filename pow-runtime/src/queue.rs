@@ -1,12 +1,34 @@
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 use std::collections::VecDeque;
 use std::rc::Rc;
+use std::time::Duration;
+
+use crate::timeout::Deadline;
+
+/// How much work a single tick may do before the remaining tasks are
+/// deferred to the next one, so a burst of ready tasks does not hold the
+/// worker inside one callback.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PollBudget {
+    pub max_tasks: usize,
+    pub max_time: Duration,
+}
+
+impl Default for PollBudget {
+    fn default() -> Self {
+        Self {
+            max_tasks: 1024,
+            max_time: Duration::from_millis(5),
+        }
+    }
+}
 
 struct QueueState {
     // The queue of Tasks which are to be run in order. In practice this is all the
     // synchronous work of futures, and each `Task` represents calling `poll` on
     // a future "at the right time".
     tasks: RefCell<VecDeque<Rc<crate::task::Task>>>,
+    budget: Cell<PollBudget>,
 }
 
 impl QueueState {
@@ -14,13 +36,23 @@ impl QueueState {
         // Stop when all tasks that have been scheduled before this tick have been run.
         // Tasks that are scheduled while running tasks will run on the next tick.
         let mut task_count_left = self.tasks.borrow().len();
+        let budget = self.budget.get();
+        let deadline = Deadline::after(budget.max_time);
+        let mut ran = 0;
         while task_count_left > 0 {
+            // Tasks left over stay at the front and run first on the next tick
+            if ran >= budget.max_tasks || deadline.is_expired() {
+                log::debug!("poll budget exhausted after {} tasks, deferring {}", ran, task_count_left);
+                crate::task::budget_exhausted();
+                break;
+            }
             task_count_left -= 1;
             let task = match self.tasks.borrow_mut().pop_front() {
                 Some(task) => task,
                 None => break,
             };
             task.run();
+            ran += 1;
         }
 
         // All of the Tasks have been run, so it's now possible to schedule the
@@ -49,6 +81,7 @@ impl Queue {
     fn new() -> Self {
         let state = Rc::new(QueueState {
             tasks: RefCell::new(VecDeque::new()),
+            budget: Cell::new(PollBudget::default()),
         });
 
         Self {
@@ -65,9 +98,41 @@ impl Queue {
 
 		pub fn on_tick(&self) {
 			self.state.run_all();
+			crate::task::end_tick();
 		}
 }
 
 thread_local! {
     pub(crate) static QUEUE: Queue = Queue::new();
+}
+
+/// Limit the work of each tick of this VM, see [`PollBudget`].
+pub fn set_poll_budget(budget: PollBudget) {
+    QUEUE.with(|queue| queue.state.budget.set(budget));
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::spawn_local;
+
+    #[test]
+    fn budget_defers_to_next_tick() {
+        set_poll_budget(PollBudget {
+            max_tasks: 2,
+            ..Default::default()
+        });
+        let ran = Rc::new(Cell::new(0));
+        for _ in 0..5 {
+            let ran = ran.clone();
+            spawn_local(async move { ran.set(ran.get() + 1) });
+        }
+
+        QUEUE.with(|queue| queue.on_tick());
+        assert_eq!(ran.get(), 2);
+        QUEUE.with(|queue| queue.on_tick());
+        QUEUE.with(|queue| queue.on_tick());
+        assert_eq!(ran.get(), 5);
+        assert_eq!(crate::task::stats().budget_exhausted, 2);
+    }
 }
\ No newline at end of file
@@ -1,7 +1,242 @@
+use std::sync::Mutex;
+
+use serde::{Deserialize, Serialize};
+
 #[derive(Debug)]
 pub struct Response {
     pub code: u32,
     pub headers: Vec<(String, String)>,
     pub body: Option<Vec<u8>>,
     pub trailers: Vec<(String, String)>,
-}
\ No newline at end of file
+}
+
+impl Response {
+    /// A `200` response without headers or body.
+    pub fn builder() -> ResponseBuilder {
+        ResponseBuilder {
+            response: Response {
+                code: 200,
+                headers: vec![],
+                body: None,
+                trailers: vec![],
+            },
+        }
+    }
+}
+
+/// Built with [`Response::builder`], the body setters also set `Content-Type`.
+#[derive(Debug)]
+pub struct ResponseBuilder {
+    response: Response,
+}
+
+impl ResponseBuilder {
+    pub fn status(mut self, code: u32) -> Self {
+        self.response.code = code;
+        self
+    }
+
+    /// Append a header, headers with the same name are kept.
+    pub fn header(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.response.headers.push((key.into(), value.into()));
+        self
+    }
+
+    /// Set the body and replace any `Content-Type` header.
+    pub fn body(mut self, content_type: impl Into<String>, body: impl Into<Vec<u8>>) -> Self {
+        self.response
+            .headers
+            .retain(|(k, _)| !k.eq_ignore_ascii_case("content-type"));
+        self.response
+            .headers
+            .insert(0, ("Content-Type".to_string(), content_type.into()));
+        self.response.body = Some(body.into());
+        self
+    }
+
+    pub fn text(self, body: impl Into<String>) -> Self {
+        self.body("text/plain", body.into())
+    }
+
+    pub fn html(self, body: impl Into<String>) -> Self {
+        self.body("text/html; charset=utf-8", body.into())
+    }
+
+    /// Serialize `body` as `application/json`.
+    pub fn json(self, body: &impl Serialize) -> Self {
+        let body = serde_json::to_vec(body).expect("failed to serialize response");
+        self.body("application/json", body)
+    }
+
+    /// Serialize `problem` as `application/problem+json` with its status.
+    pub fn problem(self, problem: &Problem) -> Self {
+        let body = serde_json::to_vec(problem).expect("failed to serialize problem");
+        self.status(problem.status)
+            .body("application/problem+json", body)
+    }
+
+    pub fn build(self) -> Response {
+        self.response
+    }
+}
+
+impl From<ResponseBuilder> for Response {
+    fn from(builder: ResponseBuilder) -> Self {
+        builder.build()
+    }
+}
+
+impl From<serde_json::Value> for Response {
+    fn from(value: serde_json::Value) -> Self {
+        Response::builder().json(&value).build()
+    }
+}
+
+/// Headers a hook decides on while handling the request and adds to the
+/// response, see [`HttpHook::response_headers`](crate::HttpHook::response_headers).
+#[derive(Debug, Default)]
+pub struct ResponseHeaders {
+    headers: Mutex<Vec<(String, String)>>,
+}
+
+impl ResponseHeaders {
+    pub fn push(&self, key: impl Into<String>, value: impl Into<String>) {
+        self.headers
+            .lock()
+            .expect("failed to lock response headers")
+            .push((key.into(), value.into()));
+    }
+
+    pub fn take(&self) -> Vec<(String, String)> {
+        std::mem::take(
+            &mut *self
+                .headers
+                .lock()
+                .expect("failed to lock response headers"),
+        )
+    }
+}
+
+/// How a filter renders the requests it rejects.
+#[derive(Debug, Default, Eq, PartialEq, Serialize, Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorFormat {
+    /// The filter's own JSON bodies.
+    #[default]
+    Json,
+    /// [RFC 9457](https://www.rfc-editor.org/rfc/rfc9457) problem details.
+    ProblemJson,
+}
+
+/// Prefix of the problem `type` URIs unless configured otherwise.
+pub const DEFAULT_PROBLEM_TYPE_BASE: &str = "urn:pow:problem:";
+
+/// RFC 9457 problem details, the `type` is the configured base followed by
+/// the reason code of the rejection.
+#[derive(Debug, Serialize)]
+pub struct Problem {
+    #[serde(rename = "type")]
+    pub type_uri: String,
+    pub title: String,
+    pub status: u32,
+    pub detail: String,
+    #[serde(flatten)]
+    pub extensions: serde_json::Map<String, serde_json::Value>,
+}
+
+impl Problem {
+    pub fn new(
+        type_base: Option<&str>,
+        reason: &str,
+        status: u32,
+        title: impl Into<String>,
+        detail: impl Into<String>,
+    ) -> Self {
+        Problem {
+            type_uri: format!(
+                "{}{}",
+                type_base.unwrap_or(DEFAULT_PROBLEM_TYPE_BASE),
+                reason
+            ),
+            title: title.into(),
+            status,
+            detail: detail.into(),
+            extensions: serde_json::Map::new(),
+        }
+    }
+
+    /// Add a member next to the standard ones.
+    pub fn extension(mut self, key: impl Into<String>, value: impl Serialize) -> Self {
+        let value = serde_json::to_value(value).expect("failed to serialize problem extension");
+        self.extensions.insert(key.into(), value);
+        self
+    }
+}
+
+impl From<Problem> for Response {
+    fn from(problem: Problem) -> Self {
+        Response::builder().problem(&problem).build()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn builder() {
+        let response = Response::builder()
+            .header("Content-Type", "text/plain")
+            .header("X-Request-Id", "abc")
+            .status(429)
+            .json(&serde_json::json!({ "message": "slow down" }))
+            .build();
+        assert_eq!(response.code, 429);
+        assert_eq!(
+            response.headers,
+            vec![
+                ("Content-Type".to_string(), "application/json".to_string()),
+                ("X-Request-Id".to_string(), "abc".to_string()),
+            ]
+        );
+        assert_eq!(
+            response.body.as_deref(),
+            Some(&br#"{"message":"slow down"}"#[..])
+        );
+
+        let response = Response::from(
+            Problem::new(
+                None,
+                "region-blocked",
+                403,
+                "Region blocked",
+                "KP is blocked",
+            )
+            .extension("request_id", "abc"),
+        );
+        assert_eq!(response.code, 403);
+        assert_eq!(
+            response.headers,
+            vec![(
+                "Content-Type".to_string(),
+                "application/problem+json".to_string()
+            )]
+        );
+        let body: serde_json::Value =
+            serde_json::from_slice(response.body.as_deref().unwrap()).unwrap();
+        assert_eq!(
+            body,
+            serde_json::json!({
+                "type": "urn:pow:problem:region-blocked",
+                "title": "Region blocked",
+                "status": 403,
+                "detail": "KP is blocked",
+                "request_id": "abc",
+            })
+        );
+
+        let response = Response::from(serde_json::json!([]));
+        assert_eq!(response.code, 200);
+        assert_eq!(response.body.as_deref(), Some(&b"[]"[..]));
+    }
+}
@@ -0,0 +1,166 @@
+//! Values a hook keeps for the rest of a request, see [`Ctx::scratch`].
+//!
+//! The request hook runs as a detached future while the response callbacks
+//! come later through the same [`HookHolder`], the scratch of a context is
+//! how they share e.g. the matched route or whether the client was
+//! challenged. Values are keyed by their type, so filters wrap them in
+//! types of their own:
+//!
+//! ```ignore
+//! struct Challenged(u64);
+//!
+//! ctx.scratch().insert(Challenged(difficulty));
+//! // later, in `response_headers`
+//! if let Some(Challenged(difficulty)) = ctx.scratch().get() { ... }
+//! ```
+//!
+//! The scratch is dropped with the context.
+//!
+//! [`Ctx::scratch`]: crate::Ctx::scratch
+//! [`HookHolder`]: crate::HookHolder
+
+use std::any::{Any, TypeId};
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+type Values = HashMap<TypeId, Box<dyn Any>>;
+
+thread_local! {
+    static SCRATCH: RefCell<HashMap<u32, Values>> = RefCell::new(HashMap::new());
+}
+
+/// The scratch of one context.
+#[derive(Debug, Clone, Copy)]
+pub struct Scratch {
+    context_id: u32,
+}
+
+impl Scratch {
+    pub(crate) fn new(context_id: u32) -> Self {
+        Self { context_id }
+    }
+
+    /// Store `value`, returning the previous value of its type.
+    pub fn insert<T: 'static>(&self, value: T) -> Option<T> {
+        let previous = SCRATCH.with(|scratch| {
+            scratch
+                .borrow_mut()
+                .entry(self.context_id)
+                .or_default()
+                .insert(TypeId::of::<T>(), Box::new(value))
+        });
+        previous.and_then(|previous| previous.downcast().ok().map(|b| *b))
+    }
+
+    /// A copy of the value of type `T`.
+    pub fn get<T: Clone + 'static>(&self) -> Option<T> {
+        SCRATCH.with(|scratch| {
+            scratch
+                .borrow()
+                .get(&self.context_id)?
+                .get(&TypeId::of::<T>())?
+                .downcast_ref::<T>()
+                .cloned()
+        })
+    }
+
+    pub fn contains<T: 'static>(&self) -> bool {
+        SCRATCH.with(|scratch| {
+            scratch
+                .borrow()
+                .get(&self.context_id)
+                .is_some_and(|values| values.contains_key(&TypeId::of::<T>()))
+        })
+    }
+
+    pub fn remove<T: 'static>(&self) -> Option<T> {
+        let value = SCRATCH.with(|scratch| {
+            scratch
+                .borrow_mut()
+                .get_mut(&self.context_id)?
+                .remove(&TypeId::of::<T>())
+        });
+        value.and_then(|value| value.downcast().ok().map(|b| *b))
+    }
+}
+
+/// Drop the scratch of `context_id`, once it is deleted.
+pub(crate) fn forget(context_id: u32) {
+    // the values may themselves be dropped after the thread local
+    let values = SCRATCH
+        .try_with(|scratch| scratch.borrow_mut().remove(&context_id))
+        .ok()
+        .flatten();
+    drop(values);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::response::Response;
+    use crate::{queue, Ctx, HookHolder, HttpHook};
+    use proxy_wasm::traits::HttpContext;
+
+    #[derive(Debug, Clone, PartialEq)]
+    struct Route(String);
+
+    #[derive(Debug, Clone, PartialEq)]
+    struct Challenged(u64);
+
+    #[test]
+    fn typed() {
+        let scratch = Ctx::new(11).scratch();
+        assert_eq!(scratch.get::<Route>(), None);
+        assert_eq!(scratch.insert(Route("/api".to_string())), None);
+        assert_eq!(scratch.insert(Challenged(1000)), None);
+        assert_eq!(
+            scratch.insert(Route("/login".to_string())),
+            Some(Route("/api".to_string()))
+        );
+        assert_eq!(scratch.get(), Some(Route("/login".to_string())));
+        assert_eq!(Ctx::new(12).scratch().get::<Route>(), None);
+
+        assert_eq!(scratch.remove(), Some(Challenged(1000)));
+        assert!(!scratch.contains::<Challenged>());
+        forget(11);
+        assert!(!scratch.contains::<Route>());
+    }
+
+    struct Recording {
+        ctx: Ctx,
+    }
+
+    impl HttpHook for Recording {
+        async fn on_request_headers(
+            &self,
+            _num_headers: usize,
+            _end_of_stream: bool,
+        ) -> Result<(), impl Into<Response>> {
+            self.ctx.scratch().insert(Challenged(1000));
+            Ok::<(), Response>(())
+        }
+
+        fn response_headers(&self) -> Vec<(String, String)> {
+            match self.ctx.scratch().get() {
+                Some(Challenged(difficulty)) => {
+                    vec![("X-Challenged".to_string(), difficulty.to_string())]
+                }
+                None => vec![],
+            }
+        }
+    }
+
+    #[test]
+    fn shared_with_response() {
+        let ctx = Ctx::new(13);
+        let mut holder = HookHolder::new(13, Recording { ctx });
+        holder.on_http_request_headers(0, true);
+        queue::QUEUE.with(|queue| queue.on_tick());
+        assert_eq!(
+            holder.inner.response_headers(),
+            vec![("X-Challenged".to_string(), "1000".to_string())]
+        );
+        drop(holder);
+        assert!(!ctx.scratch().contains::<Challenged>());
+    }
+}
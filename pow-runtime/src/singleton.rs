@@ -0,0 +1,108 @@
+//! One instance per VM of subsystems with background tasks, e.g. a
+//! [`CounterBucket`](crate::counter_bucket::CounterBucket), which a root
+//! context would otherwise start again on every reconfiguration.
+//!
+//! Instances are keyed by type and name and live as long as the VM:
+//!
+//! ```ignore
+//! let bucket = singleton::get_or_init("rate_limit", || CounterBucket::new(context_id, "rate_limit"));
+//! ```
+
+use std::any::{Any, TypeId};
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+struct Instance {
+    setting: Box<dyn Any>,
+    value: Box<dyn Any>,
+}
+
+thread_local! {
+    static INSTANCES: RefCell<HashMap<(TypeId, String), Instance>> = RefCell::new(HashMap::new());
+}
+
+/// The instance of `T` named `name`, made by `init` on first use.
+pub fn get_or_init<T>(name: &str, init: impl FnOnce() -> T) -> T
+where
+    T: Clone + 'static,
+{
+    configured(name, (), |_| init())
+}
+
+/// Like [`get_or_init`], made again by `init` when `setting` differs from
+/// the one the instance was made with. The replaced instance is dropped from
+/// the registry, its users keep it until they drop it too.
+pub fn configured<T, S>(name: &str, setting: S, init: impl FnOnce(&S) -> T) -> T
+where
+    T: Clone + 'static,
+    S: PartialEq + 'static,
+{
+    let key = (TypeId::of::<T>(), name.to_string());
+    let found = INSTANCES.with(|instances| {
+        let instances = instances.borrow();
+        let instance = instances.get(&key)?;
+        if instance.setting.downcast_ref::<S>() != Some(&setting) {
+            return None;
+        }
+        instance.value.downcast_ref::<T>().cloned()
+    });
+    if let Some(found) = found {
+        return found;
+    }
+    // outside the borrow, `init` may get instances of its own
+    let value = init(&setting);
+    let instance = Instance {
+        setting: Box::new(setting),
+        value: Box::new(value.clone()),
+    };
+    let replaced = INSTANCES.with(|instances| instances.borrow_mut().insert(key, instance));
+    if replaced.is_some() {
+        log::debug!("{} reconfigured, replaced its instance", name);
+    }
+    drop(replaced);
+    value
+}
+
+/// Drop the instance of `T` named `name` from the registry.
+pub fn remove<T: 'static>(name: &str) {
+    let removed = INSTANCES.with(|instances| {
+        instances
+            .borrow_mut()
+            .remove(&(TypeId::of::<T>(), name.to_string()))
+    });
+    drop(removed);
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    use super::*;
+
+    #[test]
+    fn once_per_setting() {
+        let made = Rc::new(Cell::new(0));
+        let make = |setting: &u32| {
+            made.set(made.get() + 1);
+            Rc::new(*setting)
+        };
+        let first = configured("bucket", 1, make);
+        let again = configured("bucket", 1, make);
+        assert!(Rc::ptr_eq(&first, &again));
+        assert_eq!(made.get(), 1);
+        drop(again);
+
+        // another type or name is another instance
+        assert_eq!(get_or_init("bucket", || 7u64), 7);
+        assert_eq!(*configured("other", 1, make), 1);
+        assert_eq!(made.get(), 2);
+
+        let reconfigured = configured("bucket", 2, make);
+        assert_eq!(*reconfigured, 2);
+        assert_eq!(Rc::strong_count(&first), 1, "replaced");
+
+        remove::<Rc<u32>>("bucket");
+        assert_eq!(Rc::strong_count(&reconfigured), 1);
+    }
+}
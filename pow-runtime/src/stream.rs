@@ -0,0 +1,263 @@
+use std::{cell::Cell, convert::Infallible, fmt::Display, future::Future, pin::Pin, rc::Rc};
+
+use proxy_wasm::{
+    hostcalls,
+    traits::{Context, StreamContext},
+    types::{Action, BufferType, PeerType, Status},
+};
+
+/// What a [`StreamHook`] wants to happen with the data it has inspected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StreamFlow {
+    /// Keep the data buffered, the hook needs more bytes before it can decide.
+    /// The hook is called again with the whole buffer when more data arrives.
+    NeedMore,
+    /// Release the buffered data and keep inspecting the following data.
+    Continue,
+    /// Release the buffered data and let the rest of this direction through
+    /// without calling the hook again.
+    Passthrough,
+}
+
+#[derive(Clone, Copy)]
+pub struct StreamCtx {
+    id: u32,
+}
+
+impl Context for StreamCtx {}
+
+impl StreamContext for StreamCtx {}
+
+impl StreamCtx {
+    pub fn new(id: u32) -> Self {
+        Self { id }
+    }
+
+    pub fn get_client_address(&self) -> Result<Option<String>, Status> {
+        crate::get_client_address(self.id)
+    }
+
+    /// The property at `path`, read from the host once per connection, see
+    /// [`crate::property`].
+    pub fn property(&self, path: &[&str]) -> Result<crate::property::Value, Status> {
+        crate::property::get(self.id, path)
+    }
+
+    /// Values kept for the rest of the connection, see [`crate::scratch`].
+    pub fn scratch(&self) -> crate::scratch::Scratch {
+        crate::scratch::Scratch::new(self.id)
+    }
+
+    /// Replace `size` bytes starting at `start` of the buffered downstream data.
+    ///
+    /// The host only exposes the buffer while a data callback is running, so
+    /// this must be called before the hook suspends.
+    pub fn set_downstream_data(
+        &self,
+        start: usize,
+        size: usize,
+        value: &[u8],
+    ) -> Result<(), Status> {
+        crate::host::set_effective_context(self.id)?;
+        hostcalls::set_buffer(BufferType::DownstreamData, start, size, value)
+    }
+
+    /// Replace `size` bytes starting at `start` of the buffered upstream data.
+    ///
+    /// Same restriction as [`StreamCtx::set_downstream_data`].
+    pub fn set_upstream_data(&self, start: usize, size: usize, value: &[u8]) -> Result<(), Status> {
+        crate::host::set_effective_context(self.id)?;
+        hostcalls::set_buffer(BufferType::UpstreamData, start, size, value)
+    }
+
+    pub fn close_downstream(&self) -> Result<(), Status> {
+        crate::host::set_effective_context(self.id)?;
+        hostcalls::close_downstream()
+    }
+
+    pub fn close_upstream(&self) -> Result<(), Status> {
+        crate::host::set_effective_context(self.id)?;
+        hostcalls::close_upstream()
+    }
+
+    fn resume(&self, direction: Direction) -> Result<(), Status> {
+        crate::host::set_effective_context(self.id)?;
+        match direction {
+            Direction::Downstream => hostcalls::resume_downstream(),
+            Direction::Upstream => hostcalls::resume_upstream(),
+        }
+    }
+
+    fn close(&self, direction: Direction) -> Result<(), Status> {
+        match direction {
+            Direction::Downstream => self.close_downstream(),
+            Direction::Upstream => self.close_upstream(),
+        }
+    }
+}
+
+pub trait StreamHook {
+    /// Inspect the data received from the client.
+    ///
+    /// `data` is everything buffered so far for this direction. Returning an
+    /// error closes the connection.
+    fn on_downstream_data(
+        &self,
+        data: Vec<u8>,
+        end_of_stream: bool,
+    ) -> impl Future<Output = Result<StreamFlow, impl Display>>;
+
+    /// Inspect the data received from the upstream service, passed through by default.
+    fn on_upstream_data(
+        &self,
+        _data: Vec<u8>,
+        _end_of_stream: bool,
+    ) -> impl Future<Output = Result<StreamFlow, impl Display>> {
+        async { Ok::<_, Infallible>(StreamFlow::Passthrough) }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Direction {
+    Downstream,
+    Upstream,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Gate {
+    Inspect,
+    Pending,
+    Passthrough,
+    Closed,
+}
+
+type HookFuture = Pin<Box<dyn Future<Output = Result<StreamFlow, String>>>>;
+
+pub struct StreamHookHolder<H: StreamHook + 'static> {
+    context: StreamCtx,
+    inner: Rc<H>,
+    downstream: Rc<Cell<Gate>>,
+    upstream: Rc<Cell<Gate>>,
+}
+
+impl<H: StreamHook> StreamHookHolder<H> {
+    pub fn new(context_id: u32, inner: H) -> Self {
+        Self {
+            context: StreamCtx::new(context_id),
+            inner: Rc::new(inner),
+            downstream: Rc::new(Cell::new(Gate::Inspect)),
+            upstream: Rc::new(Cell::new(Gate::Inspect)),
+        }
+    }
+
+    /// Run the hook future right away, so a hook that never suspends can still
+    /// touch the buffers, and only fall back to pausing when it does suspend.
+    fn dispatch(&self, direction: Direction, future: HookFuture) -> Action {
+        let gate = match direction {
+            Direction::Downstream => self.downstream.clone(),
+            Direction::Upstream => self.upstream.clone(),
+        };
+        match gate.get() {
+            Gate::Passthrough => return Action::Continue,
+            Gate::Pending | Gate::Closed => return Action::Pause,
+            Gate::Inspect => {}
+        }
+
+        gate.set(Gate::Pending);
+        let ctx = self.context;
+        let inline = Rc::new(Cell::new(true));
+        let flow: Rc<Cell<Option<StreamFlow>>> = Rc::new(Cell::new(None));
+        {
+            let gate = gate.clone();
+            let inline = inline.clone();
+            let flow = flow.clone();
+            crate::task::Task::spawn_now(Box::pin(async move {
+                let next = match future.await {
+                    Ok(f) => {
+                        flow.set(Some(f));
+                        match f {
+                            StreamFlow::Passthrough => Gate::Passthrough,
+                            StreamFlow::NeedMore | StreamFlow::Continue => Gate::Inspect,
+                        }
+                    }
+                    Err(reason) => {
+                        log::warn!("close {:?} connection: {}", direction, reason);
+                        if let Err(e) = ctx.close(direction) {
+                            log::warn!("failed to close connection: {:?}", e);
+                        }
+                        Gate::Closed
+                    }
+                };
+                gate.set(next);
+                if inline.get() || next == Gate::Closed || flow.get() == Some(StreamFlow::NeedMore)
+                {
+                    return;
+                }
+                if let Err(e) = ctx.resume(direction) {
+                    log::warn!("failed to resume {:?} connection: {:?}", direction, e);
+                }
+            }));
+        }
+        inline.set(false);
+
+        match flow.get() {
+            Some(StreamFlow::Continue | StreamFlow::Passthrough) => Action::Continue,
+            _ => Action::Pause,
+        }
+    }
+}
+
+impl<H: StreamHook> Drop for StreamHookHolder<H> {
+    fn drop(&mut self) {
+        crate::property::forget(self.context.id);
+        crate::scratch::forget(self.context.id);
+    }
+}
+
+impl<H: StreamHook> Context for StreamHookHolder<H> {
+    fn on_foreign_function(&mut self, function_id: u32, arguments_size: usize) {
+        crate::foreign::on_event(function_id, arguments_size)
+    }
+}
+
+impl<H: StreamHook> StreamContext for StreamHookHolder<H> {
+    fn on_downstream_data(&mut self, data_size: usize, end_of_stream: bool) -> Action {
+        if self.downstream.get() == Gate::Passthrough {
+            return Action::Continue;
+        }
+        let data = self.get_downstream_data(0, data_size).unwrap_or_default();
+        let hook = self.inner.clone();
+        self.dispatch(
+            Direction::Downstream,
+            Box::pin(async move {
+                hook.on_downstream_data(data, end_of_stream)
+                    .await
+                    .map_err(|e| e.to_string())
+            }),
+        )
+    }
+
+    fn on_upstream_data(&mut self, data_size: usize, end_of_stream: bool) -> Action {
+        if self.upstream.get() == Gate::Passthrough {
+            return Action::Continue;
+        }
+        let data = self.get_upstream_data(0, data_size).unwrap_or_default();
+        let hook = self.inner.clone();
+        self.dispatch(
+            Direction::Upstream,
+            Box::pin(async move {
+                hook.on_upstream_data(data, end_of_stream)
+                    .await
+                    .map_err(|e| e.to_string())
+            }),
+        )
+    }
+
+    fn on_downstream_close(&mut self, _peer_type: PeerType) {
+        self.downstream.set(Gate::Closed);
+    }
+
+    fn on_upstream_close(&mut self, _peer_type: PeerType) {
+        self.upstream.set(Gate::Closed);
+    }
+}
@@ -0,0 +1,106 @@
+//! The final outcome of a request, passed to [`HttpHook::on_log`].
+//!
+//! Read from properties on demand, hosts without them report every field as
+//! unknown.
+//!
+//! [`HttpHook::on_log`]: crate::HttpHook::on_log
+
+use std::time::Duration;
+
+use proxy_wasm::types::Status;
+
+use crate::property;
+use crate::Ctx;
+
+pub struct StreamInfo {
+    ctx: Ctx,
+}
+
+impl StreamInfo {
+    pub(crate) fn new(ctx: Ctx) -> Self {
+        Self { ctx }
+    }
+
+    /// The status code sent downstream, also for local replies.
+    pub fn response_code(&self) -> Result<Option<u16>, Status> {
+        let code = self.ctx.property(property::RESPONSE_CODE)?.int();
+        Ok(code.and_then(|code| code.try_into().ok()))
+    }
+
+    /// Bytes of the response sent downstream, headers included.
+    pub fn bytes_sent(&self) -> Result<Option<u64>, Status> {
+        let size = self.ctx.property(property::RESPONSE_TOTAL_SIZE)?.int();
+        Ok(size.and_then(|size| size.try_into().ok()))
+    }
+
+    /// From the first byte of the request to the last of the response.
+    pub fn duration(&self) -> Result<Option<Duration>, Status> {
+        Ok(self.ctx.property(property::REQUEST_DURATION)?.duration())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::RefCell;
+
+    use proxy_wasm::traits::HttpContext;
+    use test_host::HOST;
+
+    use super::*;
+    use crate::response::Response;
+    use crate::{HookHolder, HttpHook};
+
+    type Logged = (Option<u16>, Option<u64>, Option<Duration>);
+
+    #[derive(Default)]
+    struct Logging {
+        logged: RefCell<Vec<Logged>>,
+    }
+
+    impl HttpHook for Logging {
+        async fn on_request_headers(
+            &self,
+            _num_headers: usize,
+            _end_of_stream: bool,
+        ) -> Result<(), impl Into<Response>> {
+            Ok::<(), Response>(())
+        }
+
+        fn on_log(&self, info: &StreamInfo) {
+            let info = (
+                info.response_code().unwrap(),
+                info.bytes_sent().unwrap(),
+                info.duration().unwrap(),
+            );
+            self.logged.borrow_mut().push(info);
+        }
+    }
+
+    #[test]
+    fn on_log() {
+        HOST.with(|host| {
+            let properties = &mut host.borrow_mut().properties;
+            properties.insert(
+                (14, "response.code".to_string()),
+                503i64.to_le_bytes().to_vec(),
+            );
+            properties.insert(
+                (14, "response.total_size".to_string()),
+                1234i64.to_le_bytes().to_vec(),
+            );
+            properties.insert(
+                (14, "request.duration".to_string()),
+                25_000_000i64.to_le_bytes().to_vec(),
+            );
+        });
+        let mut holder = HookHolder::new(14, Logging::default());
+        holder.on_log();
+        let mut unknown = HookHolder::new(15, Logging::default());
+        unknown.on_log();
+        assert_eq!(
+            holder.inner.logged.take(),
+            vec![(Some(503), Some(1234), Some(Duration::from_millis(25)))]
+        );
+        assert_eq!(unknown.inner.logged.take(), vec![(None, None, None)]);
+    }
+}
@@ -4,6 +4,98 @@ use std::mem::ManuallyDrop;
 use std::pin::Pin;
 use std::rc::Rc;
 use std::task::{Context, RawWaker, RawWakerVTable, Waker};
+use std::time::Duration;
+
+use crate::metrics;
+use crate::timeout::monotonic;
+
+/// How often [`end_tick`] reports the executor metrics.
+const REPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Executor statistics, reported as `pow_runtime.executor.*` metrics.
+///
+/// A task that sleeps on a [`Timer`](crate::timeout::Timer) wakes itself on
+/// every tick, so a `wakeups_per_tick` close to `pending` points at tasks
+/// busy waiting rather than waiting on the host.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Stats {
+    /// Tasks spawned since the VM started.
+    pub spawned: u64,
+    /// Tasks spawned and not finished yet.
+    pub pending: u64,
+    /// Most wakeups between two ticks since the last report.
+    pub wakeups_per_tick: u64,
+    /// Longest poll of a single task since the last report.
+    pub longest_poll: Duration,
+    /// Ticks cut short by the [`PollBudget`](crate::queue::PollBudget).
+    pub budget_exhausted: u64,
+}
+
+#[derive(Default)]
+struct Instrumentation {
+    stats: Stats,
+    tick_wakeups: u64,
+    /// The [`monotonic`] time of the last report.
+    reported_at: Option<Duration>,
+    reported_spawned: u64,
+    reported_budget_exhausted: u64,
+}
+
+thread_local! {
+    static INSTRUMENTATION: RefCell<Instrumentation> = RefCell::new(Instrumentation::default());
+}
+
+fn instrument(f: impl FnOnce(&mut Instrumentation)) {
+    INSTRUMENTATION.with(|instrumentation| f(&mut instrumentation.borrow_mut()));
+}
+
+/// The executor statistics of this VM.
+pub fn stats() -> Stats {
+    INSTRUMENTATION.with(|instrumentation| instrumentation.borrow().stats)
+}
+
+pub(crate) fn budget_exhausted() {
+    instrument(|i| i.stats.budget_exhausted += 1);
+}
+
+/// Account the wakeups of the tick that just ran and report the metrics
+/// once per [`REPORT_INTERVAL`].
+pub(crate) fn end_tick() {
+    instrument(|i| {
+        i.stats.wakeups_per_tick = i.stats.wakeups_per_tick.max(i.tick_wakeups);
+        i.tick_wakeups = 0;
+        let now = monotonic();
+        let Some(reported_at) = i.reported_at else {
+            i.reported_at = Some(now);
+            return;
+        };
+        if now - reported_at < REPORT_INTERVAL {
+            return;
+        }
+        metrics::increment_counter(
+            "pow_runtime.executor.spawned",
+            (i.stats.spawned - i.reported_spawned) as i64,
+        );
+        metrics::increment_counter(
+            "pow_runtime.executor.budget_exhausted",
+            (i.stats.budget_exhausted - i.reported_budget_exhausted) as i64,
+        );
+        metrics::record_gauge("pow_runtime.executor.pending", i.stats.pending);
+        metrics::record_gauge(
+            "pow_runtime.executor.wakeups_per_tick",
+            i.stats.wakeups_per_tick,
+        );
+        metrics::record_gauge(
+            "pow_runtime.executor.longest_poll_us",
+            i.stats.longest_poll.as_micros() as u64,
+        );
+        i.reported_at = Some(now);
+        i.reported_spawned = i.stats.spawned;
+        i.reported_budget_exhausted = i.stats.budget_exhausted;
+        i.stats.wakeups_per_tick = 0;
+        i.stats.longest_poll = Duration::ZERO;
+    });
+}
 
 struct Inner {
     future: Pin<Box<dyn Future<Output = ()> + 'static>>,
@@ -31,11 +123,37 @@ impl Task {
         let waker = unsafe { Waker::from_raw(Task::into_raw_waker(Rc::clone(&this))) };
 
         *this.inner.borrow_mut() = Some(Inner { future, waker });
+        Self::spawned();
 
         crate::queue::QUEUE.with(|queue| queue.schedule_task(this));
     }
 
+    /// Like `spawn`, but polls the future once right away instead of waiting
+    /// for the next tick. Anything the future does before its first
+    /// suspension point happens inside the calling host callback.
+    pub(crate) fn spawn_now(future: Pin<Box<dyn Future<Output = ()> + 'static>>) {
+        let this = Rc::new(Self {
+            inner: RefCell::new(None),
+            is_queued: Cell::new(false),
+        });
+
+        let waker = unsafe { Waker::from_raw(Task::into_raw_waker(Rc::clone(&this))) };
+
+        *this.inner.borrow_mut() = Some(Inner { future, waker });
+        Self::spawned();
+
+        this.run();
+    }
+
+    fn spawned() {
+        instrument(|i| {
+            i.stats.spawned += 1;
+            i.stats.pending += 1;
+        });
+    }
+
     fn force_wake(this: Rc<Self>) {
+        instrument(|i| i.tick_wakeups += 1);
         crate::queue::QUEUE.with(|queue| {
             queue.push_task(this);
         });
@@ -112,10 +230,18 @@ impl Task {
         // the run queue.
         self.is_queued.set(false);
 
+        let started = monotonic();
         let poll = {
             let mut cx = Context::from_waker(&inner.waker);
             inner.future.as_mut().poll(&mut cx)
         };
+        let elapsed = monotonic() - started;
+        instrument(|i| {
+            i.stats.longest_poll = i.stats.longest_poll.max(elapsed);
+            if poll.is_ready() {
+                i.stats.pending -= 1;
+            }
+        });
 
         // If a future has finished (`Ready`) then clean up resources associated
         // with the future ASAP. This ensures that we don't keep anything extra
@@ -127,4 +253,33 @@ impl Task {
             *borrow = None;
         }
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::queue;
+    use crate::timeout::sleep;
+
+    fn tick() {
+        queue::QUEUE.with(|queue| queue.on_tick());
+    }
+
+    #[test]
+    fn busy_wait_shows_in_wakeups() {
+        Task::spawn(Box::pin(async {}));
+        Task::spawn(Box::pin(async {
+            sleep(Duration::from_secs(60)).await;
+        }));
+        assert_eq!(stats().pending, 2);
+
+        for _ in 0..3 {
+            tick();
+        }
+        let stats = stats();
+        assert_eq!((stats.spawned, stats.pending), (2, 1));
+        assert_eq!(
+            stats.wakeups_per_tick, 1,
+            "the timer wakes itself every tick"
+        );
+    }
+}
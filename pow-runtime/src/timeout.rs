@@ -1,17 +1,65 @@
-use std::{future::Future, io, pin::Pin, task::{Context, Poll}};
+//! Sleeps and timeouts on the single thread executor.
+//!
+//! `std::time::Instant` is not available on every wasm target, so the time
+//! is read from the [`clock`](crate::clock), the host's current time. A
+//! wall clock may be set back, [`monotonic`] ignores such steps, so a
+//! [`Deadline`] never moves away. A step forward cannot be told apart from
+//! time passing and ends pending deadlines early.
+
+use std::{cell::Cell, future::Future, io, pin::Pin, task::{Context, Poll}};
+use std::time::{Duration, SystemTime};
 use pin_project_lite::pin_project;
 use std::io::Result;
 
+use crate::clock;
+
+thread_local! {
+    /// The last clock reading and the time passed up to it.
+    static MONOTONIC: Cell<(Option<SystemTime>, Duration)> =
+        const { Cell::new((None, Duration::ZERO)) };
+}
+
+/// Time passed on this thread since its first reading, never decreasing.
+pub fn monotonic() -> Duration {
+    let now = clock::now();
+    MONOTONIC.with(|monotonic| {
+        let (last, passed) = monotonic.get();
+        let step = last.map_or(Duration::ZERO, |last| now.duration_since(last).unwrap_or_default());
+        let passed = passed + step;
+        monotonic.set((Some(now), passed));
+        passed
+    })
+}
+
+/// A point in [`monotonic`] time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Deadline(Duration);
+
+impl Deadline {
+    pub fn after(duration: Duration) -> Self {
+        Self(monotonic() + duration)
+    }
+
+    pub fn is_expired(&self) -> bool {
+        monotonic() >= self.0
+    }
+
+    /// Zero once expired.
+    pub fn remaining(&self) -> Duration {
+        self.0.saturating_sub(monotonic())
+    }
+}
+
 #[derive(Debug)]
 pub struct Timer {
     // The time at which the timeout will expire
-    expiry: std::time::Instant,
+    expiry: Deadline,
 }
 
 impl Timer {
     fn new(duration: std::time::Duration) -> Self {
         Self {
-            expiry: std::time::Instant::now() + duration,
+            expiry: Deadline::after(duration),
         }
     }
 }
@@ -20,7 +68,7 @@ impl Future for Timer {
     type Output = ();
 
     fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-        if std::time::Instant::now() >= self.expiry {
+        if self.expiry.is_expired() {
             Poll::Ready(())
         } else {
             cx.waker().wake_by_ref();
@@ -78,4 +126,49 @@ where
         future,
         timeout: Timer::new(duration),
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod test {
+    use std::task::Waker;
+    use std::time::UNIX_EPOCH;
+
+    use super::*;
+    use crate::clock::TestClock;
+
+    #[test]
+    fn clock_steps() {
+        let clock = TestClock::new(UNIX_EPOCH + Duration::from_secs(10_000));
+        let _guard = clock::install(clock.clone());
+        let deadline = Deadline::after(Duration::from_secs(10));
+        clock.advance(Duration::from_secs(4));
+        assert_eq!(deadline.remaining(), Duration::from_secs(6));
+        // set back an hour, the deadline stays as far
+        clock.set(UNIX_EPOCH + Duration::from_secs(10_004 - 3_600));
+        assert_eq!(deadline.remaining(), Duration::from_secs(6));
+        clock.advance(Duration::from_secs(6));
+        assert!(deadline.is_expired());
+    }
+
+    #[test]
+    fn sleep_and_timeout() {
+        let clock = TestClock::new(UNIX_EPOCH + Duration::from_secs(10_000));
+        let _guard = clock::install(clock.clone());
+        let mut cx = Context::from_waker(Waker::noop());
+        let mut timer = Box::pin(sleep(Duration::from_secs(5)));
+        let mut never = Box::pin(timeout(
+            std::future::pending::<Result<()>>(),
+            Duration::from_secs(2),
+        ));
+        assert!(timer.as_mut().poll(&mut cx).is_pending());
+        assert!(never.as_mut().poll(&mut cx).is_pending());
+        clock.advance(Duration::from_secs(2));
+        assert!(timer.as_mut().poll(&mut cx).is_pending());
+        match never.as_mut().poll(&mut cx) {
+            Poll::Ready(Err(e)) => assert_eq!(e.kind(), io::ErrorKind::TimedOut),
+            other => panic!("{:?}", other),
+        }
+        clock.advance(Duration::from_secs(3));
+        assert!(timer.as_mut().poll(&mut cx).is_ready());
+    }
+}
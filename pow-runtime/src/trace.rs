@@ -0,0 +1,336 @@
+//! Tracing of the calls a hook makes while handling a request.
+//!
+//! [`Ctx::http_call`](crate::Ctx::http_call) copies the trace context of the
+//! downstream request into the call. W3C `traceparent` and B3 `x-b3-spanid`
+//! get a fresh span id (with B3 `x-b3-parentspanid` pointing at the request's
+//! span) so tracers show the call as a child span, the other headers are
+//! copied as is. The latency of each call is recorded and logged with the
+//! decision on the request.
+//!
+//! [`TraceContext`] reads the trace a request belongs to, so filters can tag
+//! their logs with its id or report spans of their own to a collector.
+
+use std::cell::{Cell, RefCell};
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::hash::{BuildHasher, Hasher};
+use std::time::{Duration, SystemTime};
+
+/// Request headers copied into calls, matched case-insensitively.
+pub const PROPAGATED_HEADERS: &[&str] = &[
+    "x-request-id",
+    "traceparent",
+    "tracestate",
+    "b3",
+    "x-b3-traceid",
+    "x-b3-spanid",
+    "x-b3-parentspanid",
+    "x-b3-sampled",
+    "x-b3-flags",
+    "x-cloud-trace-context",
+];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Subrequest {
+    pub upstream: String,
+    pub path: String,
+    /// `None` when the call failed or timed out.
+    pub status: Option<String>,
+    pub elapsed: Duration,
+}
+
+impl Display for Subrequest {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}{} {} in {}ms",
+            self.upstream,
+            self.path,
+            self.status.as_deref().unwrap_or("failed"),
+            self.elapsed.as_millis()
+        )
+    }
+}
+
+thread_local! {
+    static SUBREQUESTS: RefCell<HashMap<u32, Vec<Subrequest>>> = RefCell::new(HashMap::new());
+    static SPAN_SEED: Cell<u64> = const { Cell::new(0x9e37_79b9_7f4a_7c15) };
+}
+
+pub(crate) fn record(context_id: u32, subrequest: Subrequest) {
+    SUBREQUESTS.with(|subrequests| {
+        subrequests
+            .borrow_mut()
+            .entry(context_id)
+            .or_default()
+            .push(subrequest)
+    });
+}
+
+/// The calls recorded for `context_id`, forgetting them.
+pub fn take(context_id: u32) -> Vec<Subrequest> {
+    SUBREQUESTS.with(|subrequests| {
+        subrequests
+            .borrow_mut()
+            .remove(&context_id)
+            .unwrap_or_default()
+    })
+}
+
+/// Seed the span ids of this VM once it starts, so workers and proxies
+/// started together do not emit the same ids. The entropy comes from the
+/// keys of `RandomState`, which WASI hosts draw with `random_get`, mixed with
+/// the start time.
+pub(crate) fn seed(started: SystemTime) {
+    let mut hasher = RandomState::new().build_hasher();
+    let nanos = started
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    hasher.write_u128(nanos);
+    let entropy = hasher.finish();
+    SPAN_SEED.with(|seed| seed.set(seed.get() ^ entropy));
+}
+
+/// A new non-zero span id as 16 hex digits (splitmix64 of a per-VM counter
+/// mixed with `salt`).
+pub fn span_id(salt: u64) -> String {
+    let seed = SPAN_SEED.with(|seed| {
+        let next = seed.get().wrapping_add(0x9e37_79b9_7f4a_7c15);
+        seed.set(next);
+        next
+    });
+    let mut z = seed ^ salt;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^= z >> 31;
+    format!("{:016x}", z.max(1))
+}
+
+/// The trace a request belongs to, as its caller sent it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceContext {
+    /// 32 hex digits, 64-bit B3 trace ids are padded with zeros.
+    pub trace_id: String,
+    /// The span of the caller, 16 hex digits.
+    pub span_id: String,
+    /// `None` when the caller deferred the sampling decision.
+    pub sampled: Option<bool>,
+}
+
+fn is_id(value: &str, len: usize) -> bool {
+    value.len() == len
+        && value
+            .bytes()
+            .all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+        && value.bytes().any(|b| b != b'0')
+}
+
+impl TraceContext {
+    fn new(trace_id: &str, span_id: &str, sampled: Option<bool>) -> Option<Self> {
+        let trace_id = match trace_id.len() {
+            16 => format!("{:0>32}", trace_id),
+            _ => trace_id.to_string(),
+        };
+        (is_id(&trace_id, 32) && is_id(span_id, 16)).then(|| Self {
+            trace_id,
+            span_id: span_id.to_string(),
+            sampled,
+        })
+    }
+
+    /// The context of a request with `headers`, from W3C `traceparent`, B3
+    /// single header `b3` or B3 `x-b3-*` headers, in this order. `None` when
+    /// none of them carries valid ids.
+    pub fn parse(headers: &[(String, String)]) -> Option<Self> {
+        let get = |name: &str| {
+            headers
+                .iter()
+                .find(|(k, _)| k.eq_ignore_ascii_case(name))
+                .map(|(_, v)| v.trim())
+        };
+        let b3_sampled = |value: &str| match value {
+            "1" | "d" | "true" => Some(true),
+            "0" | "false" => Some(false),
+            _ => None,
+        };
+        let traceparent = get("traceparent").and_then(|value| {
+            match value.split('-').collect::<Vec<_>>().as_slice() {
+                [version, trace_id, span_id, flags] if *version != "ff" => {
+                    let flags = u8::from_str_radix(flags, 16).ok()?;
+                    Self::new(trace_id, span_id, Some(flags & 1 == 1))
+                }
+                _ => None,
+            }
+        });
+        // trace_id-span_id[-sampled[-parent_span_id]]
+        let b3 = || {
+            let value = get("b3")?;
+            let mut parts = value.split('-');
+            let (trace_id, span_id) = (parts.next()?, parts.next()?);
+            Self::new(trace_id, span_id, parts.next().and_then(b3_sampled))
+        };
+        let multi = || {
+            let sampled = match get("x-b3-flags") {
+                Some("1") => Some(true),
+                _ => get("x-b3-sampled").and_then(b3_sampled),
+            };
+            Self::new(get("x-b3-traceid")?, get("x-b3-spanid")?, sampled)
+        };
+        traceparent.or_else(b3).or_else(multi)
+    }
+}
+
+/// The trace headers of a call made on behalf of a request with `headers`.
+pub fn propagate(headers: &[(String, String)], salt: u64) -> Vec<(String, String)> {
+    let get = |name: &str| {
+        headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    };
+    let child = span_id(salt);
+    PROPAGATED_HEADERS
+        .iter()
+        .filter_map(|name| {
+            let value = get(name)?;
+            let value = match *name {
+                // version-trace_id-parent_id-flags
+                "traceparent" => {
+                    let parts: Vec<&str> = value.split('-').collect();
+                    match parts.as_slice() {
+                        [version, trace_id, _, flags] => {
+                            format!("{}-{}-{}-{}", version, trace_id, child, flags)
+                        }
+                        _ => value.to_string(),
+                    }
+                }
+                "x-b3-spanid" => child.clone(),
+                "x-b3-parentspanid" => get("x-b3-spanid")?.to_string(),
+                _ => value.to_string(),
+            };
+            Some((name.to_string(), value))
+        })
+        .chain(
+            // a root span of B3 has no parent header to rewrite
+            get("x-b3-spanid")
+                .filter(|_| get("x-b3-parentspanid").is_none())
+                .map(|span| ("x-b3-parentspanid".to_string(), span.to_string())),
+        )
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn headers(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn child_span() {
+        let propagated = propagate(
+            &headers(&[
+                (":path", "/ip"),
+                ("X-Request-Id", "abc"),
+                (
+                    "traceparent",
+                    "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+                ),
+                ("x-b3-traceid", "4bf92f3577b34da6a3ce929d0e0e4736"),
+                ("x-b3-spanid", "00f067aa0ba902b7"),
+            ]),
+            7,
+        );
+        let get = |name: &str| {
+            propagated
+                .iter()
+                .find(|(k, _)| k == name)
+                .map(|(_, v)| v.clone())
+                .unwrap()
+        };
+        assert_eq!(propagated.len(), 5);
+        assert_eq!(get("x-request-id"), "abc");
+        let traceparent = get("traceparent");
+        assert!(traceparent.starts_with("00-4bf92f3577b34da6a3ce929d0e0e4736-"));
+        assert!(traceparent.ends_with("-01"));
+        let child = &traceparent[36..52];
+        assert_ne!(child, "00f067aa0ba902b7");
+        assert_eq!(get("x-b3-spanid"), child);
+        assert_eq!(get("x-b3-parentspanid"), "00f067aa0ba902b7");
+    }
+
+    #[test]
+    fn seeded_span_ids() {
+        let next = || SPAN_SEED.with(|seed| seed.get());
+        let before = next();
+        seed(SystemTime::UNIX_EPOCH);
+        let once = next();
+        assert_ne!(once, before);
+        seed(SystemTime::UNIX_EPOCH);
+        assert_ne!(next(), once);
+        assert_ne!(span_id(0), span_id(0));
+    }
+
+    #[test]
+    fn trace_context() {
+        let w3c = TraceContext::parse(&headers(&[(
+            "Traceparent",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+        )]))
+        .unwrap();
+        assert_eq!(w3c.trace_id, "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(w3c.span_id, "00f067aa0ba902b7");
+        assert_eq!(w3c.sampled, Some(true));
+
+        let b3 = TraceContext::parse(&headers(&[(
+            "b3",
+            "80f198ee56343ba864fe8b2a57d3eff7-e457b5a2e4d86bd1-0",
+        )]))
+        .unwrap();
+        assert_eq!(b3.trace_id, "80f198ee56343ba864fe8b2a57d3eff7");
+        assert_eq!(b3.sampled, Some(false));
+
+        let multi = TraceContext::parse(&headers(&[
+            ("x-b3-traceid", "64fe8b2a57d3eff7"),
+            ("x-b3-spanid", "e457b5a2e4d86bd1"),
+        ]))
+        .unwrap();
+        assert_eq!(multi.trace_id, "000000000000000064fe8b2a57d3eff7");
+        assert_eq!(multi.sampled, None);
+
+        // an invalid traceparent falls back to B3
+        let fallback = TraceContext::parse(&headers(&[
+            (
+                "traceparent",
+                "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+            ),
+            ("b3", "80f198ee56343ba864fe8b2a57d3eff7-e457b5a2e4d86bd1"),
+        ]))
+        .unwrap();
+        assert_eq!(fallback.span_id, "e457b5a2e4d86bd1");
+
+        assert_eq!(TraceContext::parse(&headers(&[("b3", "0")])), None);
+        assert_eq!(TraceContext::parse(&headers(&[(":path", "/")])), None);
+    }
+
+    #[test]
+    fn records_per_context() {
+        let call = Subrequest {
+            upstream: "identity".to_string(),
+            path: "/lookup".to_string(),
+            status: Some("200".to_string()),
+            elapsed: Duration::from_millis(12),
+        };
+        record(1, call.clone());
+        assert_eq!(take(2), vec![]);
+        assert_eq!(take(1), vec![call.clone()]);
+        assert_eq!(take(1), vec![]);
+        assert_eq!(call.to_string(), "identity/lookup 200 in 12ms");
+    }
+}
@@ -0,0 +1,219 @@
+//! Named upstreams and their health.
+//!
+//! Plugins [`configure`] their upstreams once from the plugin configuration
+//! and get a ready [`Client`] by name, instead of passing cluster names and
+//! authorities around. Every attempt of a client from the registry reports
+//! its outcome here, an upstream with a circuit breaker that keeps failing
+//! is skipped for a while: calls fail fast with [`Error::CircuitOpen`]
+//! rather than queueing behind the host's timeout.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::http::{Client, Error, RetryPolicy};
+use crate::timeout::Deadline;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UpstreamSetting {
+    /// Cluster name the host dispatches calls to.
+    pub cluster: String,
+    pub authority: String,
+    /// Milliseconds before the host fails a call.
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+    #[serde(default)]
+    pub retry: RetrySetting,
+    pub circuit_breaker: Option<CircuitBreakerSetting>,
+}
+
+fn default_timeout() -> u64 {
+    5000
+}
+
+/// See [`RetryPolicy`], a single attempt by default.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RetrySetting {
+    pub max_attempts: u32,
+    /// Milliseconds before the first retry.
+    pub backoff: u64,
+    /// Milliseconds between retries at most.
+    pub max_backoff: u64,
+    pub retry_on: Vec<u32>,
+    pub non_idempotent: bool,
+}
+
+impl Default for RetrySetting {
+    fn default() -> Self {
+        let policy = RetryPolicy::default();
+        Self {
+            max_attempts: policy.max_attempts,
+            backoff: policy.backoff.as_millis() as u64,
+            max_backoff: policy.max_backoff.as_millis() as u64,
+            retry_on: policy.retry_on,
+            non_idempotent: policy.non_idempotent,
+        }
+    }
+}
+
+impl From<&RetrySetting> for RetryPolicy {
+    fn from(setting: &RetrySetting) -> Self {
+        Self {
+            max_attempts: setting.max_attempts,
+            backoff: Duration::from_millis(setting.backoff),
+            max_backoff: Duration::from_millis(setting.max_backoff),
+            retry_on: setting.retry_on.clone(),
+            non_idempotent: setting.non_idempotent,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CircuitBreakerSetting {
+    /// Consecutive failed attempts that open the circuit.
+    pub failures: u32,
+    /// Seconds calls fail fast once the circuit opened, the next call after
+    /// that is let through and closes it again if it succeeds.
+    pub open_for: u64,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Health {
+    /// Failed attempts since the last success.
+    pub consecutive_failures: u32,
+    /// Calls fail fast until then.
+    pub open_until: Option<Deadline>,
+}
+
+struct Upstream {
+    setting: UpstreamSetting,
+    health: Health,
+}
+
+thread_local! {
+    static UPSTREAMS: RefCell<HashMap<String, Upstream>> = RefCell::new(HashMap::new());
+}
+
+/// Replace the registered upstreams, upstreams kept by name keep their
+/// health.
+pub fn configure(upstreams: impl IntoIterator<Item = (String, UpstreamSetting)>) {
+    UPSTREAMS.with(|registry| {
+        let mut registry = registry.borrow_mut();
+        let mut previous = std::mem::take(&mut *registry);
+        for (name, setting) in upstreams {
+            let health = previous
+                .remove(&name)
+                .map(|upstream| upstream.health)
+                .unwrap_or_default();
+            registry.insert(name, Upstream { setting, health });
+        }
+    });
+}
+
+/// A client for the upstream registered as `name`.
+pub fn client(name: &str) -> Result<Client, Error> {
+    UPSTREAMS.with(|registry| {
+        let registry = registry.borrow();
+        let upstream = registry
+            .get(name)
+            .ok_or_else(|| Error::UnknownUpstream(name.to_string()))?;
+        let setting = &upstream.setting;
+        Ok(Client::new(&setting.cluster, &setting.authority)
+            .timeout(Duration::from_millis(setting.timeout))
+            .retry(RetryPolicy::from(&setting.retry))
+            .registered(name))
+    })
+}
+
+pub fn health(name: &str) -> Option<Health> {
+    UPSTREAMS.with(|registry| registry.borrow().get(name).map(|upstream| upstream.health))
+}
+
+/// Whether calls to `name` may go out, i.e. its circuit is not open.
+pub(crate) fn allow(name: &str) -> bool {
+    let open_until = health(name).and_then(|health| health.open_until);
+    open_until.is_none_or(|open_until| open_until.is_expired())
+}
+
+/// Record the outcome of an attempt, opening the circuit of `name` once its
+/// failures reach the threshold.
+pub(crate) fn report(name: &str, ok: bool) {
+    UPSTREAMS.with(|registry| {
+        let mut registry = registry.borrow_mut();
+        let Some(upstream) = registry.get_mut(name) else {
+            return;
+        };
+        let health = &mut upstream.health;
+        if ok {
+            if health.open_until.is_some() {
+                log::info!("upstream {} recovered, circuit closed", name);
+            }
+            *health = Health::default();
+            return;
+        }
+        health.consecutive_failures += 1;
+        let Some(breaker) = upstream.setting.circuit_breaker else {
+            return;
+        };
+        if health.consecutive_failures >= breaker.failures {
+            log::warn!(
+                "upstream {} failed {} times in a row, circuit open for {}s",
+                name,
+                health.consecutive_failures,
+                breaker.open_for
+            );
+            health.open_until = Some(Deadline::after(Duration::from_secs(breaker.open_for)));
+        }
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::UNIX_EPOCH;
+
+    use super::*;
+    use crate::clock::{self, TestClock};
+
+    #[test]
+    fn circuit_breaker() {
+        let clock = TestClock::new(UNIX_EPOCH + Duration::from_secs(1_700_000_000));
+        let _guard = clock::install(clock.clone());
+        let setting: UpstreamSetting = serde_json::from_str(
+            r#"{
+                "cluster": "outbound|443||mempool.space",
+                "authority": "mempool.space",
+                "retry": { "max_attempts": 3 },
+                "circuit_breaker": { "failures": 2, "open_for": 30 }
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(setting.timeout, 5000);
+        assert_eq!(RetryPolicy::from(&setting.retry).max_attempts, 3);
+        configure(HashMap::from([("mempool".to_string(), setting)]));
+        assert!(client("mempool").is_ok());
+        assert!(matches!(client("gossip"), Err(Error::UnknownUpstream(_))));
+
+        report("mempool", false);
+        assert!(allow("mempool"));
+        report("mempool", false);
+        assert!(!allow("mempool"));
+
+        // reconfiguring keeps the health of the upstream
+        let setting = UPSTREAMS.with(|r| r.borrow()["mempool"].setting.clone());
+        configure(HashMap::from([("mempool".to_string(), setting)]));
+        assert!(!allow("mempool"));
+
+        // the circuit lets a call through once open_for passed
+        clock.advance(Duration::from_secs(29));
+        assert!(!allow("mempool"));
+        clock.advance(Duration::from_secs(1));
+        assert!(allow("mempool"));
+
+        report("mempool", true);
+        assert!(allow("mempool"));
+        assert_eq!(health("mempool"), Some(Health::default()));
+    }
+}
@@ -0,0 +1,301 @@
+//! Filters built on the runtime, loaded into the test host and driven
+//! through the callbacks Envoy would make.
+
+use std::rc::Rc;
+use std::time::Duration;
+
+use pow_runtime::counter_bucket::CounterBucket;
+use pow_runtime::foreign;
+use pow_runtime::lock::SharedDataLock;
+use pow_runtime::response::Response;
+use pow_runtime::{Ctx, HttpHook, Runtime, RuntimeBox};
+use proxy_wasm::traits::{Context, RootContext};
+use serde::{Deserialize, Serialize};
+use test_host::{HttpResponse, Vm, HOST};
+
+const GET: &[(&str, &str)] = &[(":method", "GET"), (":path", "/orders")];
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Admitted {
+    count: u64,
+}
+
+/// Asks the `auth` upstream about each request while holding the lock on
+/// the admitted count, so requests are checked one at a time.
+struct Gate;
+
+impl Context for Gate {}
+
+impl Runtime for Gate {
+    type Hook = Check;
+
+    fn on_vm_start(&mut self, _vm_configuration_size: usize) -> bool {
+        SharedDataLock::<Admitted>::new(0)
+            .initial(Admitted::default())
+            .is_ok()
+    }
+
+    fn create_http_context(&self, context_id: u32) -> Option<Check> {
+        Some(Check {
+            ctx: Ctx::new(context_id),
+            admitted: SharedDataLock::new(context_id),
+        })
+    }
+}
+
+struct Check {
+    ctx: Ctx,
+    admitted: SharedDataLock<Admitted>,
+}
+
+impl HttpHook for Check {
+    fn filter_name() -> Option<&'static str> {
+        Some("Gate")
+    }
+
+    async fn on_request_headers(
+        &self,
+        _num_headers: usize,
+        _end_of_stream: bool,
+    ) -> Result<(), impl Into<Response>> {
+        let mut admitted = self.admitted.lock().await.expect("failed to lock");
+        let headers = vec![
+            (":method", "GET"),
+            (":path", "/check"),
+            (":authority", "auth"),
+        ];
+        let response = self
+            .ctx
+            .http_call("auth", headers, None, vec![], Duration::from_secs(1))
+            .await
+            .expect("failed to dispatch");
+        match response {
+            Ok(response) if response.code == 200 => {
+                admitted.count += 1;
+                Ok(())
+            }
+            _ => Err(Response::builder().status(403)),
+        }
+    }
+}
+
+fn gate(_context_id: u32) -> Box<dyn RootContext> {
+    Box::new(RuntimeBox::new(Gate))
+}
+
+fn admitted() -> u64 {
+    SharedDataLock::<Admitted>::new(0).read().unwrap().count
+}
+
+#[test]
+fn decides_on_call_response() {
+    let mut vm = Vm::start(gate);
+    HOST.with(|host| {
+        host.borrow_mut()
+            .responses
+            .insert("auth".to_string(), HttpResponse::new(200, "ok"))
+    });
+    let allowed = vm.request(&[
+        (":method", "GET"),
+        (":path", "/orders"),
+        (
+            "traceparent",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+        ),
+    ]);
+    vm.tick();
+    vm.tick();
+    HOST.with(|host| {
+        let host = host.borrow();
+        assert_eq!(host.continued, vec![allowed]);
+        let (upstream, headers, _) = &host.http_calls[0];
+        assert_eq!(upstream, "auth");
+        // the call is a child span of the request
+        let (_, traceparent) = headers.iter().find(|(k, _)| k == "traceparent").unwrap();
+        assert!(traceparent.starts_with("00-4bf92f3577b34da6a3ce929d0e0e4736-"));
+        assert!(!traceparent.contains("00f067aa0ba902b7"));
+    });
+    vm.response(allowed, &[(":status", "200")]);
+    assert!(HOST.with(|host| host.borrow().response_headers[&allowed]
+        .contains(&("X-Filter-Name".to_string(), "Gate".to_string()))));
+    vm.done(allowed);
+
+    HOST.with(|host| {
+        host.borrow_mut()
+            .responses
+            .insert("auth".to_string(), HttpResponse::new(503, "unavailable"))
+    });
+    let denied = vm.request(GET);
+    vm.tick();
+    vm.tick();
+    assert_eq!(
+        HOST.with(|host| host.borrow().local_responses.clone()),
+        vec![(denied, 403)]
+    );
+    assert_eq!(admitted(), 1);
+}
+
+#[test]
+fn lock_serializes_requests() {
+    let mut vm = Vm::start(gate);
+    let first = vm.request(GET);
+    let second = vm.request(GET);
+    vm.tick();
+    vm.tick();
+    // the second waits for the lock, the first for its call
+    assert_eq!(
+        HOST.with(|host| host.borrow().pending_calls.clone()),
+        vec![1]
+    );
+
+    vm.respond(1, Some(HttpResponse::new(200, "ok")));
+    vm.tick();
+    assert_eq!(
+        HOST.with(|host| host.borrow().continued.clone()),
+        vec![first]
+    );
+    // the unlock is reported on the next tick
+    assert!(HOST.with(|host| host.borrow().pending_calls.is_empty()));
+    vm.tick();
+    assert_eq!(
+        HOST.with(|host| host.borrow().pending_calls.clone()),
+        vec![2]
+    );
+
+    // a failed call
+    vm.respond(2, None);
+    vm.tick();
+    HOST.with(|host| {
+        let host = host.borrow();
+        assert_eq!(host.continued, vec![first]);
+        assert_eq!(host.local_responses, vec![(second, 403)]);
+    });
+    assert_eq!(admitted(), 1);
+}
+
+/// Counts requests by path.
+struct Counter;
+
+impl Context for Counter {}
+
+impl Runtime for Counter {
+    type Hook = Count;
+
+    fn create_http_context(&self, context_id: u32) -> Option<Count> {
+        thread_local! {
+            static BUCKET: Rc<CounterBucket> = Rc::new(CounterBucket::new(1, "requests"));
+        }
+        Some(Count {
+            ctx: Ctx::new(context_id),
+            bucket: BUCKET.with(Rc::clone),
+        })
+    }
+}
+
+struct Count {
+    ctx: Ctx,
+    bucket: Rc<CounterBucket>,
+}
+
+impl HttpHook for Count {
+    async fn on_request_headers(
+        &self,
+        _num_headers: usize,
+        _end_of_stream: bool,
+    ) -> Result<(), impl Into<Response>> {
+        let path = self.ctx.get_http_request_path().expect("path");
+        self.bucket.inc(&path, 1);
+        Ok::<_, Response>(())
+    }
+}
+
+fn counter(_context_id: u32) -> Box<dyn RootContext> {
+    Box::new(RuntimeBox::new(Counter))
+}
+
+#[test]
+fn counter_bucket_flushes_every_second() {
+    let mut vm = Vm::start(counter);
+    let requests: Vec<u32> = (0..3).map(|_| vm.request(GET)).collect();
+    vm.tick();
+    assert_eq!(HOST.with(|host| host.borrow().continued.clone()), requests);
+
+    // another filter sharing the counts only sees them once flushed
+    let shared = CounterBucket::new(1, "requests");
+    assert_eq!(shared.get("/orders").unwrap(), 0);
+    vm.run_for(Duration::from_millis(1100));
+    assert_eq!(shared.get("/orders").unwrap(), 3);
+}
+
+#[test]
+fn counter_bucket_raise_is_idempotent() {
+    let mut vm = Vm::start(counter);
+    vm.request(GET);
+    vm.run_for(Duration::from_millis(1100));
+
+    let shared = CounterBucket::new(1, "requests");
+    shared.raise("/orders", 5).unwrap();
+    shared.raise("/orders", 5).unwrap();
+    assert_eq!(shared.get("/orders").unwrap(), 5);
+    // a lower count learned elsewhere leaves the counter as it is
+    shared.raise("/orders", 2).unwrap();
+    assert_eq!(shared.get("/orders").unwrap(), 5);
+
+    vm.request(GET);
+    vm.run_for(Duration::from_millis(1100));
+    assert_eq!(shared.get("/orders").unwrap(), 6);
+}
+
+/// Asks the host for a bot score, which the host reports with an event.
+struct Scoring;
+
+impl Context for Scoring {}
+
+impl Runtime for Scoring {
+    type Hook = Score;
+
+    fn create_http_context(&self, _context_id: u32) -> Option<Score> {
+        Some(Score)
+    }
+}
+
+struct Score;
+
+impl HttpHook for Score {
+    async fn on_request_headers(
+        &self,
+        _num_headers: usize,
+        _end_of_stream: bool,
+    ) -> Result<(), impl Into<Response>> {
+        let call = foreign::call_async("bot_score", b"10.0.0.1").expect("failed to call");
+        match call.await.as_slice() {
+            b"human" => Ok(()),
+            _ => Err(Response::builder().status(403)),
+        }
+    }
+}
+
+fn scoring(_context_id: u32) -> Box<dyn RootContext> {
+    Box::new(RuntimeBox::new(Scoring))
+}
+
+#[test]
+fn foreign_function_event() {
+    let mut vm = Vm::start(scoring);
+    HOST.with(|host| {
+        host.borrow_mut()
+            .foreign_functions
+            .insert("bot_score".to_string(), 7u32.to_le_bytes().to_vec())
+    });
+    let request = vm.request(GET);
+    vm.tick();
+    assert!(HOST.with(|host| host.borrow().continued.is_empty()));
+
+    // the SDK dispatches the event to the context the host names
+    vm.foreign_function_event(request, 7, b"human");
+    vm.tick();
+    assert_eq!(
+        HOST.with(|host| host.borrow().continued.clone()),
+        vec![request]
+    );
+}
@@ -0,0 +1,38 @@
+//! Hashing four nonces of a version 2 pre-image with `sha2`, with a
+//! midstate one at a time and with a midstate in lanes. To measure the
+//! vectors, build for `wasm32-wasip1` with the `simd` feature and
+//! `+simd128`, and run the benchmark in a WASI runtime.
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use pow_types::bytearray32::ByteArray32;
+use pow_types::protocol::Version;
+use pow_types::sha256::Midstate;
+use sha2::{Digest, Sha256};
+
+fn sha256(c: &mut Criterion) {
+    let current = ByteArray32::from(&[0xab; 32]);
+    let pre_image = Version::V2.pre_image(&current, 1718000000, "example.com", "/api/orders?id=1");
+    let nonces = [[1; 8], [2; 8], [3; 8], [4; 8]];
+    let midstate = Midstate::new(&pre_image);
+
+    let mut group = c.benchmark_group("sha256x4");
+    group.bench_function("sha2", |b| {
+        b.iter(|| {
+            black_box(&nonces).map(|nonce| -> [u8; 32] {
+                Sha256::new()
+                    .chain_update(&pre_image)
+                    .chain_update(nonce)
+                    .finalize()
+                    .into()
+            })
+        })
+    });
+    group.bench_function("midstate", |b| {
+        b.iter(|| black_box(&nonces).map(|nonce| midstate.finish(&nonce)))
+    });
+    group.bench_function("lanes", |b| b.iter(|| midstate.finish4(black_box(&nonces))));
+    group.finish();
+}
+
+criterion_group!(benches, sha256);
+criterion_main!(benches);
@@ -0,0 +1,55 @@
+//! Adds arbitrary route paths to a router and matches arbitrary request
+//! paths against them:
+//!
+//! ```sh
+//! cd pow-types/fuzz && cargo +nightly fuzz run route
+//! ```
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use pow_types::config::{Route, Router, VirtualHost};
+
+fn router(paths: &[String]) -> Option<Router<usize>> {
+    let routes = paths
+        .iter()
+        .enumerate()
+        .map(|(id, path)| Route {
+            path: path.clone(),
+            config: id,
+            children: None,
+        })
+        .collect();
+    let host = VirtualHost {
+        host: "*".to_string(),
+        routes,
+    };
+    Router::try_from(vec![host]).ok()
+}
+
+fuzz_target!(|input: (Vec<String>, Vec<String>)| {
+    let (candidates, requests) = input;
+    // a router is built from valid paths only, so add them one at a time
+    let mut paths: Vec<String> = vec![];
+    for path in candidates.into_iter().take(16) {
+        paths.push(path);
+        if router(&paths).is_none() {
+            paths.pop();
+        }
+    }
+    let Some(router) = router(&paths) else {
+        return;
+    };
+
+    for (id, path) in paths.iter().enumerate() {
+        let literal = !path.is_empty() && !path.contains([':', '*', '<']);
+        if literal {
+            let found = router.matches("fuzz", path).expect("a literal path matches itself");
+            assert_eq!(*found, id, "{} matches {}", path, found.pattern());
+        }
+    }
+    for request in &requests {
+        if let Some(found) = router.matches("fuzz", request) {
+            assert_eq!(found.pattern(), paths[*found], "{}", request);
+        }
+    }
+});
@@ -3,7 +3,7 @@ use std::{fmt::Display, net::IpAddr, str::FromStr};
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Debug, Clone, Eq, PartialEq)]
 pub enum CIDR {
     V4([u8; 4], u8),
     V6([u16; 8], u8),
@@ -126,6 +126,16 @@ impl FromStr for CIDR {
     }
 }
 
+/// The single address block, `/32` for IPv4 and `/128` for IPv6.
+impl From<IpAddr> for CIDR {
+    fn from(ip: IpAddr) -> Self {
+        match ip {
+            IpAddr::V4(ip) => CIDR::V4(ip.octets(), 32),
+            IpAddr::V6(ip) => CIDR::V6(ip.segments(), 128),
+        }
+    }
+}
+
 impl Serialize for CIDR {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
@@ -149,13 +159,13 @@ impl CIDR {
     pub fn contains(&self, ip: IpAddr) -> bool {
         match (self, ip) {
             (CIDR::V4(cidr, prefix), IpAddr::V4(ip)) => {
-                let mask = u32::MAX << (32 - prefix);
+                let mask = u32::MAX.checked_shl(32 - *prefix as u32).unwrap_or(0);
                 let cidr = u32::from_be_bytes(*cidr);
                 let ip = u32::from_be_bytes(ip.octets());
                 (cidr & mask) == (ip & mask)
             }
             (CIDR::V6(cidr, prefix), IpAddr::V6(ip)) => {
-                let mask = u128::MAX << (128 - prefix);
+                let mask = u128::MAX.checked_shl(128 - *prefix as u32).unwrap_or(0);
                 let cidr = u128::from_be_bytes(Self::u16s_to_u8s(*cidr));
                 let ip = u128::from_be_bytes(Self::u16s_to_u8s(ip.segments()));
                 (cidr & mask) == (ip & mask)
@@ -167,8 +177,8 @@ impl CIDR {
     fn u16s_to_u8s(input: [u16; 8]) -> [u8; 16] {
         let mut output = [0u8; 16];
         for (i, &item) in input.iter().enumerate() {
-            output[i * 2] = (item & 0xFF) as u8; // Lower byte
-            output[i * 2 + 1] = (item >> 8) as u8; // Upper byte
+            output[i * 2] = (item >> 8) as u8; // Upper byte
+            output[i * 2 + 1] = (item & 0xFF) as u8; // Lower byte
         }
         output
     }
@@ -1,4 +1,5 @@
 use std::ops::Deref;
+use std::sync::Arc;
 
 use regex::Regex;
 use serde::{Deserialize, Serialize};
@@ -23,6 +24,85 @@ pub struct Route<T> {
     pub children: Option<Vec<Route<T>>>,
 }
 
+/// Settings a child route takes from its parent where it sets none, so
+/// defaults can be declared once on a parent like `/api`.
+pub trait Inherit {
+    fn inherit(&mut self, parent: &Self);
+}
+
+/// Mappings are merged key by key, any other value of the child is kept.
+#[cfg(feature = "yaml")]
+impl Inherit for serde_yaml::Value {
+    fn inherit(&mut self, parent: &Self) {
+        let (serde_yaml::Value::Mapping(child), serde_yaml::Value::Mapping(parent)) =
+            (self, parent)
+        else {
+            return;
+        };
+        for (key, value) in parent {
+            match child.get_mut(key) {
+                Some(own) => own.inherit(value),
+                None => {
+                    child.insert(key.clone(), value.clone());
+                }
+            }
+        }
+    }
+}
+
+impl<T> VirtualHost<T> {
+    /// Pass the settings of every route down to its children, see [`Inherit`].
+    pub fn inherit(&mut self)
+    where
+        T: Inherit,
+    {
+        for route in &mut self.routes {
+            route.inherit();
+        }
+    }
+
+    pub fn try_map<U, E>(self, mut f: impl FnMut(T) -> Result<U, E>) -> Result<VirtualHost<U>, E> {
+        let routes = self
+            .routes
+            .into_iter()
+            .map(|route| route.try_map(&mut f))
+            .collect::<Result<_, _>>()?;
+        Ok(VirtualHost {
+            host: self.host,
+            routes,
+        })
+    }
+}
+
+impl<T> Route<T> {
+    fn inherit(&mut self)
+    where
+        T: Inherit,
+    {
+        for child in self.children.iter_mut().flatten() {
+            child.config.inherit(&self.config);
+            child.inherit();
+        }
+    }
+
+    fn try_map<U, E>(self, f: &mut impl FnMut(T) -> Result<U, E>) -> Result<Route<U>, E> {
+        let children = match self.children {
+            Some(children) => Some(
+                children
+                    .into_iter()
+                    .map(|child| child.try_map(f))
+                    .collect::<Result<_, _>>()?,
+            ),
+            None => None,
+        };
+        Ok(Route {
+            path: self.path,
+            config: f(self.config)?,
+            children,
+        })
+    }
+}
+
 impl<T> TryFrom<Vec<VirtualHost<T>>> for Router<T> {
     type Error = RouteError;
 
@@ -31,7 +111,7 @@ impl<T> TryFrom<Vec<VirtualHost<T>>> for Router<T> {
         for virtual_host in value.into_iter() {
             let mut radix = RadixTree::default();
             for route in virtual_host.routes {
-                radix_add_all(&mut radix, &route.path, route.config, route.children)?;
+                radix_add_all(&mut radix, None, &route.path, route.config, route.children)?;
             }
             trie.add(&virtual_host.host, radix)?;
         }
@@ -40,19 +120,31 @@ impl<T> TryFrom<Vec<VirtualHost<T>>> for Router<T> {
 }
 
 fn radix_add_all<T>(
-    radix: &mut RadixTree<T>,
+    radix: &mut RadixTree<Arc<Entry<T>>>,
+    parent: Option<Arc<Entry<T>>>,
     path: &str,
     config: T,
     children: Option<Vec<Route<T>>>,
 ) -> Result<(), RouteError> {
-    radix.add(path, config)?;
+    let entry = Arc::new(Entry {
+        pattern: path.to_string(),
+        config,
+        parent,
+    });
+    radix.add(path, entry.clone())?;
     let Some(children) = children else {
         return Ok(());
     };
 
     for child in children {
         let path = normalize_path(&format!("{}/{}", path, child.path));
-        radix_add_all(radix, &path, child.config, child.children)?;
+        radix_add_all(
+            radix,
+            Some(entry.clone()),
+            &path,
+            child.config,
+            child.children,
+        )?;
     }
     Ok(())
 }
@@ -66,26 +158,64 @@ fn normalize_path(path: &str) -> String {
     path
 }
 
-pub struct Router<T>(Trie<RadixTree<T>>);
+/// A route with the one it is nested in.
+struct Entry<T> {
+    pattern: String,
+    config: T,
+    parent: Option<Arc<Entry<T>>>,
+}
+
+pub struct Router<T>(Trie<RadixTree<Arc<Entry<T>>>>);
+
+pub struct Found<'a, T>(Matches<'a, Arc<Entry<T>>>);
 
-pub struct Found<'a, T>(Matches<'a, T>);
+/// A route of the chain a request matched, see [`Found::chain`].
+#[derive(Debug, PartialEq)]
+pub struct Matched<'a, T> {
+    pub pattern: &'a str,
+    pub config: &'a T,
+}
 
 impl<'a, T> Found<'a, T> {
     pub fn pattern(&self) -> &str {
         &self.0.data.pattern
     }
+
+    /// The routes the matched one is nested in, its parent first.
+    pub fn ancestors(&self) -> impl Iterator<Item = Matched<'_, T>> {
+        let mut next = self.0.data.data.parent.as_deref();
+        std::iter::from_fn(move || {
+            let entry = next?;
+            next = entry.parent.as_deref();
+            Some(Matched {
+                pattern: &entry.pattern,
+                config: &entry.config,
+            })
+        })
+    }
+
+    /// The matched route and its ancestors, from the top level route down.
+    pub fn chain(&self) -> Vec<Matched<'_, T>> {
+        let mut chain: Vec<_> = self.ancestors().collect();
+        chain.reverse();
+        chain.push(Matched {
+            pattern: self.pattern(),
+            config: self,
+        });
+        chain
+    }
 }
 
 impl<T> Deref for Found<'_, T> {
     type Target = T;
 
     fn deref(&self) -> &Self::Target {
-        &self.0.data.data
+        &self.0.data.data.config
     }
 }
 
 impl<T> Router<T> {
-    pub fn matches(&self, domain: &str, path: &str) -> Option<Found<T>> {
+    pub fn matches(&self, domain: &str, path: &str) -> Option<Found<'_, T>> {
         let route = self.0.matches(domain)?;
         route.matches(path).map(|matches| Found(matches))
     }
@@ -141,6 +271,54 @@ mod test {
         println!("{:?}", found.clone());
     }
 
+    #[cfg(feature = "yaml")]
+    #[test]
+    fn inherit_from_parents() {
+        let config_str = r#"
+  - host: "example.com"
+    routes:
+      - path: "/api"
+        rate_limit:
+          unit: minute
+          requests_per_unit: 50
+        rollout: 10
+        children:
+          - path: "/users"
+            rate_limit:
+              requests_per_unit: 100
+            children:
+              - path: "/:id"
+                rollout: 20
+        "#;
+
+        let mut config: Vec<VirtualHost<serde_yaml::Value>> =
+            serde_yaml::from_str(config_str).expect("failed to parse config");
+        for host in &mut config {
+            host.inherit();
+        }
+        let route: Router<serde_yaml::Value> = config.try_into().expect("failed to convert config");
+
+        let found = route
+            .matches("example.com", "/api/users/114514")
+            .expect("route not found");
+        assert_eq!(found.pattern(), "/api/users/:id");
+        let expected: serde_yaml::Value = serde_yaml::from_str(
+            "{rate_limit: {unit: minute, requests_per_unit: 100}, rollout: 20}",
+        )
+        .unwrap();
+        assert_eq!(*found, expected);
+
+        let patterns: Vec<_> = found.ancestors().map(|route| route.pattern).collect();
+        assert_eq!(patterns, vec!["/api/users", "/api"]);
+        let chain = found.chain();
+        assert_eq!(chain.len(), 3);
+        assert_eq!(chain[0].config["rollout"], 10);
+        assert_eq!(chain[2].pattern, "/api/users/:id");
+
+        let found = route.matches("example.com", "/api").unwrap();
+        assert_eq!(found.ancestors().count(), 0);
+    }
+
     #[test]
     fn cidr_contains() {
         let cidr: CIDR = "192.168.0.0/24".parse().unwrap();
@@ -0,0 +1,225 @@
+use std::net::IpAddr;
+
+use crate::cidr::CIDR;
+
+#[derive(Debug)]
+struct Node<T> {
+    children: [Option<usize>; 2],
+    data: Option<T>,
+}
+
+impl<T> Default for Node<T> {
+    fn default() -> Self {
+        Self {
+            children: [None, None],
+            data: None,
+        }
+    }
+}
+
+/// Binary trie over the leading `width` bits of a key, nodes are stored in
+/// a flat arena to keep lookups cache friendly.
+#[derive(Debug)]
+struct BitTrie<T> {
+    width: u8,
+    nodes: Vec<Node<T>>,
+    len: usize,
+}
+
+impl<T> BitTrie<T> {
+    fn new(width: u8) -> Self {
+        Self {
+            width,
+            nodes: vec![Node::default()],
+            len: 0,
+        }
+    }
+
+    fn bit(&self, key: u128, depth: u8) -> usize {
+        ((key >> (self.width - 1 - depth)) & 1) as usize
+    }
+
+    fn insert(&mut self, key: u128, prefix: u8, data: T) -> Option<T> {
+        let mut index = 0;
+        for depth in 0..prefix {
+            let bit = self.bit(key, depth);
+            index = match self.nodes[index].children[bit] {
+                Some(child) => child,
+                None => {
+                    self.nodes.push(Node::default());
+                    let child = self.nodes.len() - 1;
+                    self.nodes[index].children[bit] = Some(child);
+                    child
+                }
+            };
+        }
+        let previous = self.nodes[index].data.replace(data);
+        if previous.is_none() {
+            self.len += 1;
+        }
+        previous
+    }
+
+    fn longest_match(&self, key: u128) -> Option<&T> {
+        let mut index = 0;
+        let mut best = self.nodes[index].data.as_ref();
+        for depth in 0..self.width {
+            let Some(child) = self.nodes[index].children[self.bit(key, depth)] else {
+                break;
+            };
+            index = child;
+            if let Some(data) = &self.nodes[index].data {
+                best = Some(data);
+            }
+        }
+        best
+    }
+}
+
+/// Longest-prefix-match table of CIDR blocks with separate IPv4 and IPv6
+/// tries, so a lookup costs at most 32 or 128 steps regardless of how many
+/// blocks are stored.
+#[derive(Debug)]
+pub struct IpTrie<T = ()> {
+    v4: BitTrie<T>,
+    v6: BitTrie<T>,
+}
+
+impl<T> Default for IpTrie<T> {
+    fn default() -> Self {
+        Self {
+            v4: BitTrie::new(32),
+            v6: BitTrie::new(128),
+        }
+    }
+}
+
+impl<T> IpTrie<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Insert a block, returning the data previously stored for the exact same block.
+    pub fn insert(&mut self, cidr: &CIDR, data: T) -> Option<T> {
+        match cidr {
+            CIDR::V4(ip, prefix) => self
+                .v4
+                .insert(u32::from_be_bytes(*ip) as u128, *prefix, data),
+            CIDR::V6(ip, prefix) => {
+                let ip = std::net::Ipv6Addr::from(*ip);
+                self.v6.insert(u128::from(ip), *prefix, data)
+            }
+        }
+    }
+
+    /// The data of the most specific block containing `ip`.
+    pub fn longest_match(&self, ip: IpAddr) -> Option<&T> {
+        match ip {
+            IpAddr::V4(ip) => self.v4.longest_match(u32::from(ip) as u128),
+            IpAddr::V6(ip) => self.v6.longest_match(u128::from(ip)),
+        }
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        self.longest_match(ip).is_some()
+    }
+
+    pub fn len(&self) -> usize {
+        self.v4.len + self.v6.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Approximate bytes held by the node arenas.
+    pub fn heap_size(&self) -> usize {
+        (self.v4.nodes.capacity() + self.v6.nodes.capacity()) * std::mem::size_of::<Node<T>>()
+    }
+}
+
+impl FromIterator<CIDR> for IpTrie {
+    fn from_iter<I: IntoIterator<Item = CIDR>>(iter: I) -> Self {
+        let mut trie = IpTrie::new();
+        for cidr in iter {
+            trie.insert(&cidr, ());
+        }
+        trie
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn trie(cidrs: &[(&str, u32)]) -> IpTrie<u32> {
+        let mut trie = IpTrie::new();
+        for (cidr, id) in cidrs {
+            trie.insert(&cidr.parse().unwrap(), *id);
+        }
+        trie
+    }
+
+    #[test]
+    fn longest_match_v4() {
+        let trie = trie(&[
+            ("10.0.0.0/8", 1),
+            ("10.1.0.0/16", 2),
+            ("10.1.2.3/32", 3),
+            ("192.168.0.0/24", 4),
+        ]);
+        assert_eq!(trie.longest_match("10.2.0.1".parse().unwrap()), Some(&1));
+        assert_eq!(trie.longest_match("10.1.9.9".parse().unwrap()), Some(&2));
+        assert_eq!(trie.longest_match("10.1.2.3".parse().unwrap()), Some(&3));
+        assert_eq!(
+            trie.longest_match("192.168.0.250".parse().unwrap()),
+            Some(&4)
+        );
+        assert_eq!(trie.longest_match("192.168.10.250".parse().unwrap()), None);
+        assert_eq!(trie.longest_match("2001:db8::1".parse().unwrap()), None);
+        assert_eq!(trie.len(), 4);
+    }
+
+    #[test]
+    fn longest_match_v6() {
+        let trie = trie(&[("2001:db8::/32", 1), ("2001:db8:abc0::/44", 2), ("::/0", 3)]);
+        assert_eq!(trie.longest_match("2001:db8::1".parse().unwrap()), Some(&1));
+        assert_eq!(
+            trie.longest_match("2001:db8:abcd::1".parse().unwrap()),
+            Some(&2)
+        );
+        assert_eq!(
+            trie.longest_match("2001:db8:abd0::1".parse().unwrap()),
+            Some(&1)
+        );
+        assert_eq!(trie.longest_match("fe80::1".parse().unwrap()), Some(&3));
+        assert_eq!(trie.longest_match("127.0.0.1".parse().unwrap()), None);
+    }
+
+    #[test]
+    fn agrees_with_cidr_contains() {
+        let ips: Vec<IpAddr> = [
+            "46.3.255.1",
+            "46.3.224.1",
+            "2001:db8:1203::1",
+            "2001:db8:1204::1",
+        ]
+        .iter()
+        .map(|s| s.parse().unwrap())
+        .collect();
+        for cidr in ["46.3.240.0/20", "2001:db8:1200::/38", "0.0.0.0/0", "::/0"] {
+            let cidr: CIDR = cidr.parse().unwrap();
+            let mut trie = IpTrie::new();
+            trie.insert(&cidr, ());
+            for ip in &ips {
+                assert_eq!(
+                    trie.contains(*ip),
+                    cidr.contains(*ip),
+                    "{} contains {}",
+                    cidr,
+                    ip
+                );
+            }
+        }
+    }
+}
@@ -1,4 +1,8 @@
 pub mod bytearray32;
 pub mod cidr;
 pub mod config;
+pub mod ip_trie;
+pub mod nonce;
+pub mod protocol;
 pub mod route;
+pub mod sha256;
@@ -0,0 +1,122 @@
+//! The nonces tried by a miner.
+//!
+//! The 8-byte nonce space is split among `count` workers, the worker at
+//! `index` trying the nonces `n` with `n % count == index`. A `random`
+//! search samples its partition, so it may try a nonce twice; a `sequential`
+//! search counts through it from `index` in strides of `count` and never
+//! does. Either way a nonce solves the challenge with the same probability,
+//! so the expected work is the same while no nonces repeat.
+
+use serde::{Deserialize, Serialize};
+
+use crate::bytearray32::ByteArray32;
+use crate::sha256::Midstate;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Search {
+    #[default]
+    Random,
+    Sequential,
+}
+
+/// The share of the nonce space searched by one of `count` workers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Partition {
+    pub index: u64,
+    pub count: u64,
+}
+
+impl Default for Partition {
+    fn default() -> Self {
+        Partition { index: 0, count: 1 }
+    }
+}
+
+/// An endless iterator over the nonces of a partition.
+#[derive(Debug, Clone)]
+pub struct Nonces {
+    search: Search,
+    index: u64,
+    count: u64,
+    next: u64,
+}
+
+impl Nonces {
+    pub fn new(search: Search, partition: Partition) -> Self {
+        let count = partition.count.max(1);
+        let index = partition.index % count;
+        Nonces {
+            search,
+            index,
+            count,
+            next: index,
+        }
+    }
+}
+
+impl Nonces {
+    /// Try about `hashes` of the nonces after the pre-image of `midstate`,
+    /// four at a time, for one whose hash is at most `difficulty`.
+    pub fn search(
+        &mut self,
+        midstate: &Midstate,
+        difficulty: &ByteArray32,
+        hashes: u64,
+    ) -> Option<[u8; 8]> {
+        let mut tried = 0u64;
+        while tried < hashes {
+            let batch: [[u8; 8]; 4] = std::array::from_fn(|_| self.next().unwrap_or_default());
+            let found = midstate
+                .finish4(&batch)
+                .iter()
+                .position(|hash| ByteArray32::from(hash) <= *difficulty);
+            if let Some(lane) = found {
+                return Some(batch[lane]);
+            }
+            tried = tried.saturating_add(4);
+        }
+        None
+    }
+}
+
+impl Iterator for Nonces {
+    type Item = [u8; 8];
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let nonce = match self.search {
+            Search::Random => {
+                rand::random::<u64>() % (u64::MAX / self.count) * self.count + self.index
+            }
+            Search::Sequential => {
+                let nonce = self.next;
+                self.next = self.next.wrapping_add(self.count);
+                nonce
+            }
+        };
+        Some(nonce.to_be_bytes())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn partitions() {
+        let partition = Partition { index: 2, count: 3 };
+        let sequential: Vec<u64> = Nonces::new(Search::Sequential, partition)
+            .take(3)
+            .map(u64::from_be_bytes)
+            .collect();
+        assert_eq!(sequential, vec![2, 5, 8]);
+        assert!(Nonces::new(Search::Random, partition)
+            .take(100)
+            .all(|nonce| u64::from_be_bytes(nonce) % 3 == 2));
+        let whole: Vec<u64> = Nonces::new(Search::Sequential, Partition::default())
+            .take(2)
+            .map(u64::from_be_bytes)
+            .collect();
+        assert_eq!(whole, vec![0, 1]);
+    }
+}
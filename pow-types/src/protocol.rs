@@ -0,0 +1,146 @@
+//! The protocol between the filter and the clients solving its challenges.
+//!
+//! A challenge names the `current` block hash, the `difficulty` and the
+//! latest `version` the filter accepts. A solution is sent in request
+//! headers: the challenged hash as [`BASE`], the seconds since the epoch
+//! when mining started as [`TIMESTAMP`], the [`NONCE`] and, since version 2,
+//! the [`VERSION`]. It is valid when the SHA-256 of the pre-image followed by
+//! the nonce is at most the difficulty, both compared as big endian numbers.
+//!
+//! | version | pre-image                                                        |
+//! |---------|------------------------------------------------------------------|
+//! | 1       | base, timestamp, path                                            |
+//! | 2       | `pow/v2`, base, timestamp, host length, host, path               |
+//!
+//! The base is 32 bytes, the timestamp a big endian `u64` and the host length
+//! a big endian `u16`. Host and path are the `:authority` and `:path`,
+//! including the query string, exactly as sent. Version 2 binds a solution
+//! to the host, so it cannot be replayed against another virtual host.
+//! Solutions without a version header are version 1, so clients predating
+//! it keep working until the filter stops accepting them.
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::bytearray32::ByteArray32;
+
+/// Hex hash of the block the challenge named as `current`.
+pub const BASE: &str = "X-PoW-Base";
+/// Seconds since the epoch, in decimal.
+pub const TIMESTAMP: &str = "X-PoW-Timestamp";
+/// Hex nonce.
+pub const NONCE: &str = "X-PoW-Nonce";
+/// Decimal [`Version`], 1 when absent.
+pub const VERSION: &str = "X-PoW-Version";
+
+const V2_TAG: &[u8] = b"pow/v2";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(try_from = "u8", into = "u8")]
+pub enum Version {
+    V1 = 1,
+    V2 = 2,
+}
+
+#[derive(Debug, PartialEq, Eq, thiserror::Error)]
+#[error("unknown protocol version {0}")]
+pub struct UnknownVersion(pub String);
+
+impl TryFrom<u8> for Version {
+    type Error = UnknownVersion;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            1 => Ok(Version::V1),
+            2 => Ok(Version::V2),
+            _ => Err(UnknownVersion(value.to_string())),
+        }
+    }
+}
+
+impl From<Version> for u8 {
+    fn from(version: Version) -> Self {
+        version as u8
+    }
+}
+
+impl Version {
+    pub const LATEST: Version = Version::V2;
+
+    /// The version of a solution by its [`VERSION`] header.
+    pub fn from_header(value: Option<&str>) -> Result<Self, UnknownVersion> {
+        let Some(value) = value else {
+            return Ok(Version::V1);
+        };
+        value
+            .trim()
+            .parse::<u8>()
+            .map_err(|_| UnknownVersion(value.to_string()))?
+            .try_into()
+    }
+
+    pub fn pre_image(&self, base: &ByteArray32, timestamp: u64, host: &str, path: &str) -> Vec<u8> {
+        let mut data = Vec::with_capacity(V2_TAG.len() + 32 + 8 + 2 + host.len() + path.len());
+        if *self == Version::V2 {
+            data.extend(V2_TAG);
+        }
+        data.extend(base.as_bytes());
+        data.extend(timestamp.to_be_bytes());
+        if *self == Version::V2 {
+            let host = &host.as_bytes()[..host.len().min(u16::MAX as usize)];
+            data.extend((host.len() as u16).to_be_bytes());
+            data.extend(host);
+        }
+        data.extend(path.as_bytes());
+        data
+    }
+}
+
+/// Whether `nonce` solves the challenge of `pre_image` at `difficulty`.
+pub fn is_solution(pre_image: &[u8], difficulty: &ByteArray32, nonce: &[u8]) -> bool {
+    let mut hasher = Sha256::new();
+    hasher.update(pre_image);
+    hasher.update(nonce);
+    let hash: [u8; 32] = hasher.finalize().into();
+    ByteArray32::from(&hash) <= *difficulty
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn versions() {
+        assert_eq!(Version::from_header(None), Ok(Version::V1));
+        assert_eq!(Version::from_header(Some(" 2")), Ok(Version::V2));
+        assert_eq!(
+            Version::from_header(Some("3")),
+            Err(UnknownVersion("3".to_string()))
+        );
+        assert!(Version::from_header(Some("two")).is_err());
+        assert!(Version::V1 < Version::LATEST);
+    }
+
+    #[test]
+    fn pre_images() {
+        let base = ByteArray32::from(&[0xab; 32]);
+        let v1 = Version::V1.pre_image(&base, 1, "example.com", "/a?b");
+        assert_eq!(v1.len(), 32 + 8 + 4);
+        assert_eq!(&v1[32..40], &[0, 0, 0, 0, 0, 0, 0, 1]);
+        assert!(v1.ends_with(b"/a?b"));
+
+        let v2 = Version::V2.pre_image(&base, 1, "example.com", "/a?b");
+        assert!(v2.starts_with(b"pow/v2"));
+        assert_eq!(&v2[46..48], &[0, 11]);
+        assert!(v2.ends_with(b"example.com/a?b"));
+        assert_ne!(v2, Version::V2.pre_image(&base, 1, "example.org", "/a?b"));
+    }
+
+    #[test]
+    fn solutions() {
+        let easiest = ByteArray32::from(&[0xff; 32]);
+        let hardest = ByteArray32::from(&[0; 32]);
+        assert!(is_solution(b"data", &easiest, b"nonce"));
+        assert!(!is_solution(b"data", &hardest, b"nonce"));
+    }
+}
@@ -1,3 +1,4 @@
+pub mod normalize;
 pub(crate) mod radix_tree;
 pub(crate) mod trie;
 
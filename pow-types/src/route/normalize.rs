@@ -0,0 +1,210 @@
+//! Normalization of request paths before routes are matched, so that
+//! `/%61dmin` and `/static/../admin` cannot slip past the routes of `/admin`
+//! while the upstream serves them as `/admin`.
+//!
+//! Percent-encodings are decoded at most once: `%2561` stays `%2561`. Paths
+//! decoding to a NUL or to invalid UTF-8, which includes over-long encodings
+//! such as `%C0%AE` for `.`, are rejected. The query string is kept as it is.
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, thiserror::Error, Eq, PartialEq)]
+pub enum PathError {
+    #[error("encoded NUL in path")]
+    Nul,
+    #[error("path is not UTF-8 once decoded")]
+    InvalidEncoding,
+    #[error("encoded slash in path")]
+    EscapedSlash,
+}
+
+/// What to do with `%2F` in paths.
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EscapedSlashes {
+    /// Match it as part of a segment.
+    #[default]
+    Keep,
+    /// Match it as a separator, only use this when the upstream does too.
+    Decode,
+    /// Reject the request.
+    Reject,
+}
+
+/// The normalization policy, every step but `merge_slashes` is on by default.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PathNormalization {
+    /// Decode percent-encoded unreserved characters, e.g. `%61` to `a`.
+    pub decode_unreserved: bool,
+    /// Resolve `.` and `..` segments, `..` never climbs above the root.
+    pub remove_dot_segments: bool,
+    /// Replace runs of `/` by a single one.
+    pub merge_slashes: bool,
+    pub escaped_slashes: EscapedSlashes,
+}
+
+impl Default for PathNormalization {
+    fn default() -> Self {
+        PathNormalization {
+            decode_unreserved: true,
+            remove_dot_segments: true,
+            merge_slashes: false,
+            escaped_slashes: EscapedSlashes::Keep,
+        }
+    }
+}
+
+fn hex(byte: u8) -> Option<u8> {
+    (byte as char).to_digit(16).map(|digit| digit as u8)
+}
+
+fn is_unreserved(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'.' | b'_' | b'~')
+}
+
+/// RFC 3986 section 5.2.4, on a path starting with `/`.
+fn remove_dot_segments(path: &str) -> String {
+    let mut segments: Vec<&str> = vec![];
+    let mut parts = path[1..].split('/').peekable();
+    while let Some(part) = parts.next() {
+        let last = parts.peek().is_none();
+        match part {
+            "." => {}
+            ".." => {
+                segments.pop();
+            }
+            part => {
+                segments.push(part);
+                continue;
+            }
+        }
+        // `/a/..` ends in a slash, like `/a/`
+        if last {
+            segments.push("");
+        }
+    }
+    format!("/{}", segments.join("/"))
+}
+
+impl PathNormalization {
+    /// Normalize the path of a `:path`, the query string is left untouched.
+    pub fn normalize(&self, path: &str) -> Result<String, PathError> {
+        let (path, query) = match path.split_once('?') {
+            Some((path, query)) => (path, Some(query)),
+            None => (path, None),
+        };
+        let decoded: Vec<u8> = percent_encoding::percent_decode_str(path).collect();
+        if decoded.contains(&0) {
+            return Err(PathError::Nul);
+        }
+        if std::str::from_utf8(&decoded).is_err() {
+            return Err(PathError::InvalidEncoding);
+        }
+
+        let bytes = path.as_bytes();
+        let mut normalized = String::with_capacity(path.len());
+        let mut i = 0;
+        while i < bytes.len() {
+            let encoded = match bytes[i..] {
+                [b'%', high, low, ..] => hex(high).zip(hex(low)).map(|(h, l)| h << 4 | l),
+                _ => None,
+            };
+            let Some(byte) = encoded else {
+                // `path` is a str, a multi-byte character is copied whole
+                let len = path[i..].chars().next().map_or(1, char::len_utf8);
+                normalized.push_str(&path[i..i + len]);
+                i += len;
+                continue;
+            };
+            i += 3;
+            if byte == b'/' {
+                match self.escaped_slashes {
+                    EscapedSlashes::Keep => normalized.push_str("%2F"),
+                    EscapedSlashes::Decode => normalized.push('/'),
+                    EscapedSlashes::Reject => return Err(PathError::EscapedSlash),
+                }
+            } else if self.decode_unreserved && is_unreserved(byte) {
+                normalized.push(byte as char);
+            } else {
+                normalized.push_str(&format!("%{:02X}", byte));
+            }
+        }
+
+        if self.merge_slashes {
+            let mut merged = String::with_capacity(normalized.len());
+            for c in normalized.chars() {
+                if !(c == '/' && merged.ends_with('/')) {
+                    merged.push(c);
+                }
+            }
+            normalized = merged;
+        }
+        if self.remove_dot_segments && normalized.starts_with('/') {
+            normalized = remove_dot_segments(&normalized);
+        }
+        if let Some(query) = query {
+            normalized.push('?');
+            normalized.push_str(query);
+        }
+        Ok(normalized)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_normalize() {
+        let policy = PathNormalization::default();
+        let normalize = |path: &str| policy.normalize(path);
+        assert_eq!(normalize("/%61dmin").unwrap(), "/admin");
+        assert_eq!(normalize("/a/../admin").unwrap(), "/admin");
+        assert_eq!(normalize("/%2e%2E/admin").unwrap(), "/admin");
+        assert_eq!(normalize("/a/./b/.").unwrap(), "/a/b/");
+        assert_eq!(normalize("/a/b/..").unwrap(), "/a/");
+        assert_eq!(normalize("/../../admin").unwrap(), "/admin");
+        assert_eq!(normalize("/a/..b/c").unwrap(), "/a/..b/c");
+        assert_eq!(normalize("/admin?next=/../x").unwrap(), "/admin?next=/../x");
+        // decoded once, the rest is kept encoded with upper case digits
+        assert_eq!(normalize("/%2561dmin").unwrap(), "/%2561dmin");
+        assert_eq!(normalize("/a%2fb%3f").unwrap(), "/a%2Fb%3F");
+        assert_eq!(normalize("/caf%C3%A9/ü").unwrap(), "/caf%C3%A9/ü");
+        assert_eq!(normalize("/a//b").unwrap(), "/a//b");
+        assert_eq!(normalize("/100%").unwrap(), "/100%");
+        assert_eq!(normalize("*").unwrap(), "*");
+
+        assert_eq!(normalize("/admin%00.png"), Err(PathError::Nul));
+        assert_eq!(normalize("/%C0%AE%C0%AE/admin"), Err(PathError::InvalidEncoding));
+        assert_eq!(normalize("/%FF"), Err(PathError::InvalidEncoding));
+    }
+
+    #[test]
+    fn test_policy() {
+        let policy: PathNormalization = serde_yaml::from_str(
+            r#"
+merge_slashes: true
+escaped_slashes: decode
+"#,
+        )
+        .unwrap();
+        assert!(policy.decode_unreserved && policy.remove_dot_segments);
+        assert_eq!(policy.normalize("/a//b%2F..//c").unwrap(), "/a/c");
+
+        let policy = PathNormalization {
+            escaped_slashes: EscapedSlashes::Reject,
+            ..Default::default()
+        };
+        assert_eq!(policy.normalize("/a%2Fb"), Err(PathError::EscapedSlash));
+
+        let off = PathNormalization {
+            decode_unreserved: false,
+            remove_dot_segments: false,
+            merge_slashes: false,
+            escaped_slashes: EscapedSlashes::Keep,
+        };
+        assert_eq!(off.normalize("/a/../%61").unwrap(), "/a/../%61");
+        assert_eq!(off.normalize("/%00"), Err(PathError::Nul));
+    }
+}
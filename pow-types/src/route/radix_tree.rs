@@ -142,7 +142,8 @@ impl PathRegex {
 			let re_str = std::str::from_utf8(re_bytes).ok()?;
 			Some(PathRegex {
 					re_str: re_str.to_string(),
-					re: Regex::new(re_str).ok()?,
+					// matched against the rest of the path, from where it starts
+					re: Regex::new(&format!("^(?:{})", re_str)).ok()?,
 			})
 	}
 }
@@ -237,7 +238,7 @@ impl<T> Node<T> {
 											re: None,
 											param_children: ::std::mem::take(&mut child.param_children),
 											catch_all_child: child.catch_all_child.take(),
-											regex_children: vec![],
+											regex_children: ::std::mem::take(&mut child.regex_children),
 											data: child.data.take(),
 									};
 
@@ -327,19 +328,22 @@ impl<T> Node<T> {
 	}
 
 	fn insert_catch_all_child(&mut self, name: Option<&[u8]>, data: NodeData<T>) -> bool {
-			self.catch_all_child
-					.replace(Box::new(Node {
-							node_type: NodeType::CatchAll,
-							name: name.unwrap_or_default().to_vec(),
-							children: vec![],
-							indices: vec![],
-							re: None,
-							param_children: vec![],
-							catch_all_child: None,
-							regex_children: vec![],
-							data: Some(data),
-					}))
-					.is_none()
+			// a duplicate leaves the existing route in place
+			if self.catch_all_child.is_some() {
+					return false;
+			}
+			self.catch_all_child = Some(Box::new(Node {
+					node_type: NodeType::CatchAll,
+					name: name.unwrap_or_default().to_vec(),
+					children: vec![],
+					indices: vec![],
+					re: None,
+					param_children: vec![],
+					catch_all_child: None,
+					regex_children: vec![],
+					data: Some(data),
+			}));
+			true
 	}
 
 	fn insert_regex_child(
@@ -381,13 +385,16 @@ impl<T> Node<T> {
 			params: &mut SmallVec<[(&'b [u8], &'b [u8]); 8]>,
 	) -> Option<&'a NodeData<T>> {
 			if path.is_empty() {
-					return if let Some(catch_all_child) = &self.catch_all_child {
-							if !catch_all_child.name.is_empty() {
-									params.push((&catch_all_child.name, path));
+					// the route ending here is more specific than an empty rest
+					return match (&self.data, &self.catch_all_child) {
+							(Some(data), _) => Some(data),
+							(None, Some(catch_all_child)) => {
+									if !catch_all_child.name.is_empty() {
+											params.push((&catch_all_child.name, path));
+									}
+									catch_all_child.data.as_ref()
 							}
-							catch_all_child.data.as_ref()
-					} else {
-							self.data.as_ref()
+							(None, None) => None,
 					};
 			}
 
@@ -505,7 +512,7 @@ impl<T> RadixTree<T> {
 			}
 	}
 
-	pub(crate) fn matches(&self, path: &str) -> Option<Matches<T>> {
+	pub(crate) fn matches(&self, path: &str) -> Option<Matches<'_, T>> {
 			if path.is_empty() {
 					return None;
 			}
@@ -535,6 +542,8 @@ impl<T> RadixTree<T> {
 
 #[cfg(test)]
 mod tests {
+	use proptest::prelude::*;
+
 	use super::*;
 
 	#[test]
@@ -1251,4 +1260,166 @@ mod tests {
 			assert_eq!(matches.params[0].0, "id");
 			assert_eq!(matches.params[0].1, "你好");
 	}
-}
\ No newline at end of file
+
+	#[test]
+	fn test_overlapping_routes() {
+			let mut tree = RadixTree::default();
+			tree.add("/a/:id<\\d+>", 1).unwrap();
+			// splits the node the regex hangs off
+			tree.add("/", 2).unwrap();
+			tree.add("/b/", 3).unwrap();
+			tree.add("/b/*rest", 4).unwrap();
+			assert!(tree.add("/b/*other", 5).is_err());
+			tree.add("/c/:n<\\d+>/*rest", 6).unwrap();
+
+			assert_eq!(tree.matches("/a/12").unwrap().data.data, 1);
+			assert!(tree.matches("/12").is_none());
+			assert_eq!(tree.matches("/b/").unwrap().data.data, 3);
+			assert_eq!(tree.matches("/b/c").unwrap().data.data, 4);
+			// a regex matches from where the rest of the path starts
+			assert!(tree.matches("/c/x/1").is_none());
+			assert_eq!(tree.matches("/c/1/x").unwrap().data.data, 6);
+	}
+
+	/// A segment of a generated route, between slashes.
+	#[derive(Debug, Clone, PartialEq)]
+	enum Part {
+			Static(&'static str),
+			Param,
+			Regex,
+			/// Only last.
+			CatchAll,
+	}
+
+	/// Overlapping names, digits that regexes also match and trailing
+	/// slashes, as `Static("")` last.
+	const NAMES: &[&str] = &["a", "ab", "abc", "b", "1", "12"];
+
+	fn route_strategy() -> impl Strategy<Value = Vec<Part>> {
+			let part = prop_oneof![
+					3 => prop::sample::select(NAMES).prop_map(Part::Static),
+					1 => Just(Part::Param),
+					1 => Just(Part::Regex),
+			];
+			let last = prop_oneof![
+					Just(None),
+					Just(Some(Part::CatchAll)),
+					Just(Some(Part::Static(""))),
+			];
+			(prop::collection::vec(part, 0..4), last).prop_map(|(mut parts, last)| {
+					parts.extend(last);
+					if parts.is_empty() {
+							parts.push(Part::Static(""));
+					}
+					parts
+			})
+	}
+
+	/// Params are named by position, so routes share the nodes of their
+	/// common prefix.
+	fn render(route: &[Part]) -> String {
+			let segments: Vec<String> = route
+					.iter()
+					.enumerate()
+					.map(|(i, part)| match part {
+							Part::Static(name) => name.to_string(),
+							Part::Param => format!(":p{}", i),
+							Part::Regex => format!(":r{}<\\d+>", i),
+							Part::CatchAll => "*rest".to_string(),
+					})
+					.collect();
+			format!("/{}", segments.join("/"))
+	}
+
+	/// How specifically `route` matches `path`, segment by segment, and the
+	/// params it captures. Literals are more specific than regexes, than
+	/// params, than the rest of the path.
+	fn specificity(route: &[Part], path: &str) -> Option<(Vec<u8>, PathParams)> {
+			let segments: Vec<&str> = path[1..].split('/').collect();
+			let mut ranks = vec![];
+			let mut params = vec![];
+			for (i, part) in route.iter().enumerate() {
+					let segment = *segments.get(i)?;
+					match part {
+							Part::Static(name) if *name == segment => ranks.push(3),
+							Part::Regex if !segment.is_empty() && segment.bytes().all(|b| b.is_ascii_digit()) => {
+									ranks.push(2);
+									params.push((format!("r{}", i), segment.to_string()));
+							}
+							Part::Param if !segment.is_empty() => {
+									ranks.push(1);
+									params.push((format!("p{}", i), segment.to_string()));
+							}
+							Part::CatchAll => {
+									ranks.push(0);
+									params.push(("rest".to_string(), segments[i..].join("/")));
+									return Some((ranks, params));
+							}
+							_ => return None,
+					}
+			}
+			(segments.len() == route.len()).then_some((ranks, params))
+	}
+
+	fn path_strategy() -> impl Strategy<Value = String> {
+			let segment = prop::sample::select(&[
+					"a", "ab", "abc", "abcd", "b", "1", "12", "123", "a1", "x", "",
+			][..]);
+			prop::collection::vec(segment, 1..5).prop_map(|segments| {
+					// empty segments only as a trailing slash
+					let last = segments.len() - 1;
+					let segments: Vec<&str> = segments
+							.into_iter()
+							.enumerate()
+							.filter(|(i, segment)| *i == last || !segment.is_empty())
+							.map(|(_, segment)| segment)
+							.collect();
+					format!("/{}", segments.join("/"))
+			})
+	}
+
+	proptest! {
+			#[test]
+			fn most_specific_route_matches(
+					routes in prop::collection::vec(route_strategy(), 1..16),
+					paths in prop::collection::vec(path_strategy(), 1..16),
+			) {
+					let mut tree = RadixTree::default();
+					let mut added: Vec<(String, &[Part])> = vec![];
+					for route in &routes {
+							let pattern = render(route);
+							let duplicate = added.iter().any(|(added, _)| *added == pattern);
+							match tree.add(&pattern, added.len()) {
+									Ok(()) => added.push((pattern, route)),
+									Err(RouteError::Duplicate(_)) => prop_assert!(duplicate, "{}", pattern),
+									Err(err) => prop_assert!(false, "{}", err),
+							}
+					}
+
+					for (pattern, route) in &added {
+							if route.iter().all(|part| matches!(part, Part::Static(_))) {
+									let found = tree.matches(pattern);
+									prop_assert_eq!(found.map(|found| found.data.data), added.iter().position(|(p, _)| p == pattern));
+							}
+					}
+
+					for path in &paths {
+							let expected = added
+									.iter()
+									.enumerate()
+									.filter_map(|(id, (_, route))| Some((specificity(route, path)?, id)))
+									.max_by(|((a, _), _), ((b, _), _)| a.cmp(b));
+							let found = tree.matches(path);
+							match expected {
+									Some(((_, params), id)) => {
+											let found = found.unwrap_or_else(|| panic!("{} matches {}", path, added[id].0));
+											prop_assert_eq!(&*found.data.pattern, added[id].0.as_str(), "{}", path);
+											prop_assert_eq!(found.data.data, id);
+											prop_assert_eq!(found.params, params);
+									}
+									None => prop_assert!(found.is_none(), "{} matches nothing", path),
+							}
+					}
+			}
+	}
+}
@@ -58,7 +58,11 @@ impl<T> Trie<T> {
 
 			let node = match segment {
 					"+" => parent_node.plus_child.get_or_insert_with(Box::default),
-					"*" => return parent_node.star_child.replace(data).is_none(),
+					"*" if parent_node.star_child.is_some() => return false,
+					"*" => {
+							parent_node.star_child = Some(data);
+							return true;
+					}
 					_ => parent_node
 							.named_children
 							.entry(segment.to_string())
@@ -112,6 +116,8 @@ impl<T> Trie<T> {
 
 #[cfg(test)]
 mod tests {
+	use proptest::prelude::*;
+
 	use super::*;
 
 	#[test]
@@ -256,4 +262,93 @@ mod tests {
 					assert_eq!(tree.matches(domain), id);
 			}
 	}
-}
\ No newline at end of file
+
+	/// A label of a generated pattern, `Star` only leftmost.
+	#[derive(Debug, Clone, PartialEq)]
+	enum Label {
+			Named(&'static str),
+			Plus,
+			Star,
+	}
+
+	const LABELS: &[&str] = &["a", "b", "example", "com"];
+
+	fn pattern_strategy() -> impl Strategy<Value = Vec<Label>> {
+			let label = prop_oneof![
+					3 => prop::sample::select(LABELS).prop_map(Label::Named),
+					1 => Just(Label::Plus),
+			];
+			(any::<bool>(), prop::collection::vec(label, 0..4)).prop_map(|(star, mut labels)| {
+					if star || labels.is_empty() {
+							labels.insert(0, Label::Star);
+					}
+					labels
+			})
+	}
+
+	fn render(pattern: &[Label]) -> String {
+			pattern
+					.iter()
+					.map(|label| match label {
+							Label::Named(name) => name,
+							Label::Plus => "+",
+							Label::Star => "*",
+					})
+					.collect::<Vec<_>>()
+					.join(".")
+	}
+
+	/// How specifically `pattern` matches `domain`, label by label from the
+	/// right. Names are more specific than `+`, than `*`, which stands for
+	/// one or more labels.
+	fn specificity(pattern: &[Label], domain: &str) -> Option<Vec<u8>> {
+			let mut labels = domain.split('.').rev();
+			let mut ranks = vec![];
+			for label in pattern.iter().rev() {
+					match (label, labels.next()) {
+							(Label::Named(name), Some(domain)) if *name == domain => ranks.push(2),
+							(Label::Plus, Some(_)) => ranks.push(1),
+							(Label::Star, Some(_)) => {
+									ranks.push(0);
+									return Some(ranks);
+							}
+							_ => return None,
+					}
+			}
+			labels.next().is_none().then_some(ranks)
+	}
+
+	fn domain_strategy() -> impl Strategy<Value = String> {
+			let label = prop::sample::select(&["a", "b", "c", "example", "com"][..]);
+			prop::collection::vec(label, 1..5).prop_map(|labels| labels.join("."))
+	}
+
+	proptest! {
+			#[test]
+			fn most_specific_pattern_matches(
+					patterns in prop::collection::vec(pattern_strategy(), 1..16),
+					domains in prop::collection::vec(domain_strategy(), 1..16),
+			) {
+					let mut trie = Trie::default();
+					let mut added: Vec<(String, &[Label])> = vec![];
+					for pattern in &patterns {
+							let rendered = render(pattern);
+							let duplicate = added.iter().any(|(added, _)| *added == rendered);
+							match trie.add(&rendered, added.len()) {
+									Ok(()) => added.push((rendered, pattern)),
+									Err(_) => prop_assert!(duplicate, "{}", rendered),
+							}
+					}
+
+					for domain in &domains {
+							let expected = added
+									.iter()
+									.enumerate()
+									.filter_map(|(id, (_, pattern))| Some((specificity(pattern, domain)?, id)))
+									.max()
+									.map(|(_, id)| id);
+							prop_assert_eq!(trie.matches(domain).copied(), expected, "{}", domain);
+					}
+			}
+	}
+}
@@ -0,0 +1,427 @@
+//! Allow/deny list managed at runtime through the admin routes.
+//!
+//! Entries live in shared data so every worker sees the same list. Each worker
+//! keeps a compiled [`IpTrie`] which is rebuilt right after a local change and
+//! by a background task once a second, so a change made on one worker takes
+//! effect everywhere within that interval. The size of the trie is reported
+//! as the `pow_waf.memory.access_list` gauge, it is not capped since evicting
+//! entries would lift bans.
+//!
+//! A removed entry leaves a [`Tombstone`] behind, which [`crate::gossip`]
+//! shares with the other proxies so they remove their copy too, and which
+//! keeps the copies of the entry they still send from being merged back.
+
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use pow_runtime::kv_store::{Error, KVStore};
+use pow_runtime::memory::Account;
+use pow_runtime::spawn_local;
+use pow_runtime::timeout::sleep;
+use pow_types::cidr::CIDR;
+use pow_types::ip_trie::IpTrie;
+use serde::{Deserialize, Serialize};
+
+use crate::now;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Rule {
+    /// Skip the PoW check, like the static whitelist.
+    Allow,
+    /// Reject every request with 403.
+    Deny,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Entry {
+    pub cidr: CIDR,
+    pub rule: Rule,
+    /// Unix timestamp in seconds after which the entry is dropped.
+    pub expires_at: Option<u64>,
+    /// Unix timestamp in seconds the entry was set at, on the proxy it was
+    /// set on.
+    #[serde(default)]
+    pub added_at: u64,
+}
+
+impl Entry {
+    fn is_live(&self, now: u64) -> bool {
+        self.expires_at.filter(|at| *at <= now).is_none()
+    }
+}
+
+/// Seconds a tombstone is kept for at most, long enough for every proxy to
+/// learn about the removal.
+const TOMBSTONE_TTL: u64 = 7 * 86_400;
+
+/// The removal of the entry for a block, entries for it set before are not
+/// merged from other proxies.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Tombstone {
+    pub cidr: CIDR,
+    /// Unix timestamp in seconds the entry was removed at.
+    pub removed_at: u64,
+    /// Unix timestamp in seconds after which the tombstone is dropped, when
+    /// the removed entry would have expired or after [`TOMBSTONE_TTL`].
+    pub expires_at: u64,
+}
+
+impl Tombstone {
+    fn buries(&self, entry: &Entry) -> bool {
+        self.cidr == entry.cidr && entry.added_at <= self.removed_at
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Entries {
+    version: u64,
+    list: Vec<Entry>,
+    #[serde(default)]
+    tombstones: Vec<Tombstone>,
+}
+
+impl Entries {
+    /// Insert `entry`, replacing any entry for the same block.
+    fn upsert(&mut self, entry: Entry, now: u64) {
+        self.list.retain(|e| e.cidr != entry.cidr && e.is_live(now));
+        self.tombstones
+            .retain(|t| t.cidr != entry.cidr && now < t.expires_at);
+        self.list.push(entry);
+        self.version += 1;
+    }
+
+    fn remove(&mut self, cidr: &CIDR, now: u64) -> bool {
+        let removed = self.list.iter().find(|e| &e.cidr == cidr);
+        let expires_at = removed
+            .and_then(|e| e.expires_at)
+            .unwrap_or(u64::MAX)
+            .min(now.saturating_add(TOMBSTONE_TTL));
+        let removed = removed.is_some_and(|e| e.is_live(now));
+        self.list.retain(|e| &e.cidr != cidr && e.is_live(now));
+        self.bury(
+            Tombstone {
+                cidr: cidr.clone(),
+                removed_at: now,
+                expires_at,
+            },
+            now,
+        );
+        self.version += 1;
+        removed
+    }
+
+    /// Keep `tombstone` unless a later one for the block is kept already.
+    fn bury(&mut self, tombstone: Tombstone, now: u64) {
+        self.tombstones.retain(|t| {
+            now < t.expires_at && (t.cidr != tombstone.cidr || t.removed_at > tombstone.removed_at)
+        });
+        if !self.tombstones.iter().any(|t| t.cidr == tombstone.cidr) {
+            self.tombstones.push(tombstone);
+        }
+    }
+
+    /// Remove the entries buried by the live `tombstones`, then add the live
+    /// `remote` entries for blocks without a local entry or a later
+    /// tombstone, returns how many entries were added or removed.
+    fn merge(&mut self, remote: Vec<Entry>, tombstones: Vec<Tombstone>, now: u64) -> usize {
+        let mut changed = 0;
+        for tombstone in tombstones {
+            if tombstone.expires_at <= now {
+                continue;
+            }
+            let len = self.list.len();
+            self.list.retain(|e| !tombstone.buries(e));
+            changed += len - self.list.len();
+            self.bury(tombstone, now);
+        }
+        for entry in remote {
+            if !entry.is_live(now)
+                || self.list.iter().any(|e| e.cidr == entry.cidr)
+                || self.tombstones.iter().any(|t| t.buries(&entry))
+            {
+                continue;
+            }
+            self.list.push(entry);
+            changed += 1;
+        }
+        if changed > 0 {
+            self.version += 1;
+        }
+        changed
+    }
+
+    /// Build the lookup table of live entries, together with the time the
+    /// earliest of them expires.
+    fn compile(&self, now: u64) -> (IpTrie<Rule>, Option<u64>) {
+        let mut trie = IpTrie::new();
+        let mut next_expiry: Option<u64> = None;
+        for entry in self.list.iter().filter(|e| e.is_live(now)) {
+            trie.insert(&entry.cidr, entry.rule);
+            if let Some(at) = entry.expires_at {
+                next_expiry = Some(next_expiry.map_or(at, |next| next.min(at)));
+            }
+        }
+        (trie, next_expiry)
+    }
+}
+
+struct Inner {
+    store: KVStore<Entries>,
+    version: Option<u64>,
+    trie: IpTrie<Rule>,
+    next_expiry: Option<u64>,
+    account: Account,
+    stop: bool,
+}
+
+impl Inner {
+    fn refresh(&mut self) -> Result<(), Error> {
+        let entries = self.store.get("")?.unwrap_or_default();
+        let now = now();
+        let expired = self.next_expiry.is_some_and(|at| at <= now);
+        if self.version == Some(entries.version) && !expired {
+            return Ok(());
+        }
+        (self.trie, self.next_expiry) = entries.compile(now);
+        self.version = Some(entries.version);
+        self.account.set(self.trie.heap_size());
+        Ok(())
+    }
+}
+
+pub struct AccessList {
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl Drop for AccessList {
+    fn drop(&mut self) {
+        let mut lock = self.inner.lock().expect("failed to lock inner");
+        lock.stop = true;
+    }
+}
+
+impl AccessList {
+    pub fn new(context_id: u32, key: &str) -> Self {
+        let inner = Arc::new(Mutex::new(Inner {
+            store: KVStore::new(context_id, key),
+            version: None,
+            trie: IpTrie::new(),
+            next_expiry: None,
+            account: Account::new("pow_waf.memory.access_list", None),
+            stop: false,
+        }));
+        let background = inner.clone();
+        spawn_local(async move {
+            loop {
+                {
+                    let mut inner = background.lock().expect("failed to lock inner");
+                    if inner.stop {
+                        break;
+                    }
+                    if let Err(e) = inner.refresh() {
+                        log::warn!("failed to refresh access list: {}", e);
+                    }
+                    inner.account.report();
+                }
+                sleep(Duration::from_secs(1)).await;
+            }
+        });
+        Self { inner }
+    }
+
+    /// The rule of the most specific entry containing `ip`.
+    pub fn check(&self, ip: IpAddr) -> Option<Rule> {
+        let inner = self.inner.lock().expect("failed to lock inner");
+        inner.trie.longest_match(ip).copied()
+    }
+
+    /// Live entries as currently stored in shared data.
+    pub fn entries(&self) -> Result<Vec<Entry>, Error> {
+        let inner = self.inner.lock().expect("failed to lock inner");
+        let entries = inner.store.get("")?.unwrap_or_default();
+        let now = now();
+        Ok(entries
+            .list
+            .into_iter()
+            .filter(|e| e.is_live(now))
+            .collect())
+    }
+
+    pub fn insert(&self, entry: Entry) -> Result<(), Error> {
+        let mut inner = self.inner.lock().expect("failed to lock inner");
+        let now = now();
+        inner.store.update("", |entries| {
+            let mut entries = entries.unwrap_or_default();
+            entries.upsert(entry.clone(), now);
+            Ok::<_, Error>(entries)
+        })?;
+        inner.refresh()
+    }
+
+    /// Live tombstones as currently stored in shared data.
+    pub fn tombstones(&self) -> Result<Vec<Tombstone>, Error> {
+        let inner = self.inner.lock().expect("failed to lock inner");
+        let entries = inner.store.get("")?.unwrap_or_default();
+        let now = now();
+        Ok(entries
+            .tombstones
+            .into_iter()
+            .filter(|t| now < t.expires_at)
+            .collect())
+    }
+
+    /// Add entries and removals learned from other proxies, see
+    /// [`crate::gossip`].
+    pub fn merge(&self, remote: Vec<Entry>, tombstones: Vec<Tombstone>) -> Result<(), Error> {
+        let mut inner = self.inner.lock().expect("failed to lock inner");
+        let now = now();
+        let mut changed = 0;
+        inner.store.update("", |entries| {
+            let mut entries = entries.unwrap_or_default();
+            changed = entries.merge(remote.clone(), tombstones.clone(), now);
+            Ok::<_, Error>(entries)
+        })?;
+        if changed > 0 {
+            log::info!("merged {} remote access list changes", changed);
+            inner.refresh()?;
+        }
+        Ok(())
+    }
+
+    /// Remove the entry for exactly `cidr` and leave a tombstone for it,
+    /// returns whether one existed.
+    pub fn remove(&self, cidr: &CIDR) -> Result<bool, Error> {
+        let mut inner = self.inner.lock().expect("failed to lock inner");
+        let now = now();
+        let mut removed = false;
+        inner.store.update("", |entries| {
+            let mut entries = entries.unwrap_or_default();
+            removed = entries.remove(cidr, now);
+            Ok::<_, Error>(entries)
+        })?;
+        inner.refresh()?;
+        Ok(removed)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn entry(cidr: &str, rule: Rule, expires_at: Option<u64>) -> Entry {
+        Entry {
+            cidr: cidr.parse().unwrap(),
+            rule,
+            expires_at,
+            added_at: 0,
+        }
+    }
+
+    #[test]
+    fn upsert_replaces_same_block() {
+        let mut entries = Entries::default();
+        entries.upsert(entry("10.0.0.0/8", Rule::Deny, None), 100);
+        entries.upsert(entry("10.1.0.0/16", Rule::Allow, None), 100);
+        entries.upsert(entry("10.0.0.0/8", Rule::Allow, Some(200)), 100);
+        assert_eq!(entries.version, 3);
+        assert_eq!(
+            entries.list,
+            vec![
+                entry("10.1.0.0/16", Rule::Allow, None),
+                entry("10.0.0.0/8", Rule::Allow, Some(200)),
+            ]
+        );
+
+        assert!(entries.remove(&"10.1.0.0/16".parse().unwrap(), 100));
+        assert!(!entries.remove(&"10.1.0.0/16".parse().unwrap(), 100));
+        assert_eq!(entries.version, 5);
+        assert_eq!(entries.tombstones.len(), 1);
+        // setting the block again lifts its tombstone
+        entries.upsert(entry("10.1.0.0/16", Rule::Deny, None), 100);
+        assert!(entries.tombstones.is_empty());
+    }
+
+    #[test]
+    fn merge_keeps_local_entries() {
+        let mut entries = Entries::default();
+        entries.upsert(entry("10.0.0.0/8", Rule::Allow, None), 100);
+        let added = entries.merge(
+            vec![
+                entry("10.0.0.0/8", Rule::Deny, None),
+                entry("192.168.0.0/16", Rule::Deny, Some(200)),
+                entry("172.16.0.0/12", Rule::Deny, Some(50)),
+            ],
+            vec![],
+            100,
+        );
+        assert_eq!(added, 1);
+        assert_eq!(entries.version, 2);
+        assert_eq!(
+            entries.list,
+            vec![
+                entry("10.0.0.0/8", Rule::Allow, None),
+                entry("192.168.0.0/16", Rule::Deny, Some(200)),
+            ]
+        );
+        assert_eq!(entries.merge(vec![], vec![], 100), 0);
+        assert_eq!(entries.version, 2);
+    }
+
+    #[test]
+    fn removals_propagate() {
+        let ban = |added_at| Entry {
+            added_at,
+            ..entry("203.0.113.0/24", Rule::Deny, Some(1_000))
+        };
+        let mut local = Entries::default();
+        local.upsert(ban(100), 100);
+        assert!(local.remove(&"203.0.113.0/24".parse().unwrap(), 150));
+        assert_eq!(local.tombstones[0].expires_at, 1_000);
+
+        // a stale digest still carrying the ban does not resurrect it
+        assert_eq!(local.merge(vec![ban(100)], vec![], 200), 0);
+        assert!(local.list.is_empty());
+        // a ban set again after the removal is merged
+        assert_eq!(local.merge(vec![ban(180)], vec![], 200), 1);
+
+        // the tombstone removes the copy of a peer
+        let mut peer = Entries::default();
+        peer.merge(vec![ban(100)], vec![], 120);
+        let removed = peer.merge(vec![], local.tombstones.clone(), 200);
+        assert_eq!(removed, 1);
+        assert!(peer.list.is_empty());
+        assert_eq!(peer.merge(vec![ban(100)], vec![], 200), 0);
+        // and is dropped once the ban would have expired
+        assert_eq!(peer.merge(vec![ban(100)], vec![], 1_000), 0);
+        peer.upsert(entry("10.0.0.0/8", Rule::Deny, None), 1_000);
+        assert!(peer.tombstones.is_empty());
+    }
+
+    #[test]
+    fn compile_skips_expired_entries() {
+        let mut entries = Entries::default();
+        entries.upsert(entry("10.0.0.0/8", Rule::Deny, None), 100);
+        entries.upsert(entry("10.1.0.0/16", Rule::Allow, Some(150)), 100);
+        entries.upsert(entry("192.168.0.0/16", Rule::Deny, Some(300)), 100);
+
+        let (trie, next_expiry) = entries.compile(100);
+        assert_eq!(
+            trie.longest_match("10.1.0.1".parse().unwrap()),
+            Some(&Rule::Allow)
+        );
+        assert_eq!(next_expiry, Some(150));
+
+        let (trie, next_expiry) = entries.compile(150);
+        assert_eq!(
+            trie.longest_match("10.1.0.1".parse().unwrap()),
+            Some(&Rule::Deny)
+        );
+        assert_eq!(
+            trie.longest_match("192.168.1.1".parse().unwrap()),
+            Some(&Rule::Deny)
+        );
+        assert_eq!(next_expiry, Some(300));
+    }
+}
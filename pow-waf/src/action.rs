@@ -0,0 +1,157 @@
+//! Edge actions of a route, see [`Setting::actions`].
+//!
+//! Once a request matched a route, the first of its actions that applies is
+//! taken: a `redirect` or `respond` action answers the request before any
+//! check, an `upstream` action sets the cluster header and lets the request
+//! be filtered as usual. An action applies to the requests carrying the
+//! header of its `when` condition, and to a `percent` of the clients. Like
+//! the [`rollout`](crate::rollout) the share is stable per client, but each
+//! action draws its own, so `percent: 50` on two actions splits the clients
+//! 50/25/25 between the first, the second and none.
+//!
+//! Every action taken is counted in `pow_waf.action.<name>`.
+//!
+//! [`Setting::actions`]: crate::config::Setting::actions
+
+use std::net::IpAddr;
+
+use pow_runtime::metrics;
+use pow_runtime::response::Response;
+
+use crate::config::{ActionKind, RouteAction};
+use crate::rollout;
+
+/// The first of `actions` of `route` applying to a request of `ip`, reading
+/// headers with `header`.
+pub fn select<'a, E>(
+    actions: &'a [RouteAction],
+    ip: IpAddr,
+    route: &str,
+    mut header: impl FnMut(&str) -> Result<Option<String>, E>,
+) -> Result<Option<&'a RouteAction>, E> {
+    for action in actions {
+        if let Some(when) = &action.when {
+            let Some(value) = header(&when.header)? else {
+                continue;
+            };
+            if when
+                .value
+                .as_ref()
+                .is_some_and(|expected| *expected != value)
+            {
+                continue;
+            }
+        }
+        let salt = format!("{}:{}", route, action.name);
+        let drawn = action
+            .percent
+            .is_none_or(|percent| rollout::hash(ip, &salt) % 100 < u64::from(percent));
+        if drawn {
+            return Ok(Some(action));
+        }
+    }
+    Ok(None)
+}
+
+/// Count that `action` was taken.
+pub fn count(action: &RouteAction) {
+    metrics::increment_counter(&format!("pow_waf.action.{}", action.name), 1);
+}
+
+impl RouteAction {
+    /// The answer to a request of `path`, `None` if the request goes on.
+    pub fn response(&self, path: &str) -> Option<Response> {
+        match &self.kind {
+            ActionKind::Redirect { location, status } => Some(
+                Response::builder()
+                    .status(*status)
+                    .header("location", location.replace("{path}", path))
+                    .build(),
+            ),
+            ActionKind::Respond {
+                status,
+                content_type,
+                body,
+            } => Some(
+                Response::builder()
+                    .status(*status)
+                    .body(content_type.as_str(), body.as_str())
+                    .build(),
+            ),
+            ActionKind::Upstream { .. } => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::ActionCondition;
+
+    fn action(name: &str, percent: Option<u8>, when: Option<ActionCondition>) -> RouteAction {
+        RouteAction {
+            name: name.to_string(),
+            percent,
+            when,
+            kind: ActionKind::Redirect {
+                location: "https://example.org{path}".to_string(),
+                status: 301,
+            },
+        }
+    }
+
+    #[test]
+    fn first_applying_action() {
+        let canary = ActionCondition {
+            header: "x-canary".to_string(),
+            value: Some("1".to_string()),
+        };
+        let actions = vec![
+            action("canary", None, Some(canary)),
+            action("half", Some(50), None),
+            action("rest", None, None),
+        ];
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let selected = |canary: Option<&str>, ip| {
+            select(&actions, ip, "example.com/api", |_| {
+                Ok::<_, ()>(canary.map(str::to_string))
+            })
+            .unwrap()
+            .map(|action| action.name.as_str())
+        };
+        assert_eq!(selected(Some("1"), ip), Some("canary"));
+        assert_ne!(selected(Some("0"), ip), Some("canary"));
+
+        let ips: Vec<IpAddr> = (0..2000u32)
+            .map(|i| IpAddr::from((0x0a00_0000 + i * 7).to_be_bytes()))
+            .collect();
+        let half = ips
+            .iter()
+            .filter(|ip| selected(None, **ip) == Some("half"))
+            .count() as f64
+            / ips.len() as f64;
+        assert!((0.45..0.55).contains(&half));
+        assert!(ips.iter().all(|ip| selected(None, *ip).is_some()));
+    }
+
+    #[test]
+    fn redirect_keeps_path() {
+        let response = action("moved", None, None).response("/a?b=c").unwrap();
+        assert_eq!(response.code, 301);
+        assert_eq!(
+            response.headers,
+            vec![(
+                "location".to_string(),
+                "https://example.org/a?b=c".to_string()
+            )]
+        );
+        let upstream = RouteAction {
+            kind: ActionKind::Upstream {
+                cluster: "canary".to_string(),
+                header: "x-upstream-cluster".to_string(),
+            },
+            ..action("upstream", None, None)
+        };
+        assert!(upstream.response("/").is_none());
+    }
+}
@@ -0,0 +1,435 @@
+//! Operator endpoints served by the filter itself under
+//! [`AdminSetting::prefix`](crate::config::AdminSetting::prefix).
+//!
+//! The filter does not authenticate these requests, put the auth filter in
+//! front of it with grants on the prefix.
+//!
+//! ```text
+//! GET    /__pow/admin/blocklist
+//! POST   /__pow/admin/blocklist?cidr=203.0.113.0/24&rule=deny&ttl=3600
+//! DELETE /__pow/admin/blocklist?cidr=203.0.113.7
+//! GET    /__pow/admin/blocklist/export?format=nginx
+//! GET    /__pow/admin/kill_switch
+//! GET    /__pow/admin/rate_limit?route=example.com/api/*
+//! DELETE /__pow/admin/rate_limit?route=example.com/api/*&subject=203.0.113.7
+//! ```
+//!
+//! `cidr` also accepts a bare address, `rule` defaults to `deny` and `ttl` (in
+//! seconds) is optional.
+//!
+//! The export lists the active bans for other layers (CDN, firewall) to
+//! ingest, `format` is one of
+//!
+//! - `json` (default): `{"generated_at": .., "bans": [{"cidr": .., "expires_at": .., "ttl": ..}]}`
+//! - `envoy`: a list of `{"address_prefix": .., "prefix_len": ..}`, as used by
+//!   the `CidrRange` of the RBAC and IP tagging filters
+//! - `nginx`: `deny <cidr>;` lines to `include` in a server block
+//!
+//! The kill switch is described in [`crate::kill_switch`].
+//!
+//! `rate_limit` counts or resets the rate limit counters of a route, its host
+//! and pattern as in the configuration, or of one subject (client address,
+//! principal or header value) on it. Without `route` it covers all routes.
+
+use std::net::IpAddr;
+use std::time::Duration;
+
+use pow_runtime::counter_bucket::CounterBucket;
+use pow_runtime::lock::Contention;
+use pow_runtime::query::QueryParams;
+use pow_runtime::response::Response;
+use pow_runtime::task::Stats;
+use pow_types::cidr::CIDR;
+
+use crate::access_list::{AccessList, Entry, Rule};
+use crate::kill_switch::{Engagement, KillSwitch};
+use crate::{now, Error};
+
+fn json(code: u32, body: serde_json::Value) -> Response {
+    Response::builder().status(code).json(&body).build()
+}
+
+fn bad_request(message: String) -> Response {
+    json(400, serde_json::json!({ "message": message }))
+}
+
+fn parse_cidr(value: &str) -> Result<CIDR, Response> {
+    if let Ok(ip) = value.parse::<IpAddr>() {
+        return Ok(ip.into());
+    }
+    value
+        .parse()
+        .map_err(|e| bad_request(format!("invalid cidr {}: {}", value, e)))
+}
+
+fn parse_rule(value: Option<&str>) -> Result<Rule, Response> {
+    match value {
+        None | Some("deny") => Ok(Rule::Deny),
+        Some("allow") => Ok(Rule::Allow),
+        Some(other) => Err(bad_request(format!(
+            "invalid rule {}, expect allow or deny",
+            other
+        ))),
+    }
+}
+
+fn blocklist(
+    access_list: &AccessList,
+    method: &str,
+    query: &QueryParams,
+) -> Result<Response, Response> {
+    let store_error = |e: pow_runtime::kv_store::Error| {
+        Response::from(Error::other("failed to access blocklist", e.to_string()))
+    };
+    if method == "GET" {
+        let entries = access_list.entries().map_err(store_error)?;
+        return Ok(json(200, serde_json::json!({ "entries": entries })));
+    }
+
+    let cidr = query
+        .get("cidr")
+        .ok_or_else(|| bad_request("missing cidr".to_string()))?;
+    let cidr = parse_cidr(cidr)?;
+    match method {
+        "POST" | "PUT" => {
+            let ttl = query
+                .get_as::<u64>("ttl")
+                .map_err(|e| bad_request(format!("invalid ttl: {}", e)))?;
+            let now = now();
+            let entry = Entry {
+                cidr,
+                rule: parse_rule(query.get("rule"))?,
+                expires_at: ttl.map(|ttl| now + ttl),
+                added_at: now,
+            };
+            log::info!(
+                "admin: set {:?} {} until {:?}",
+                entry.rule,
+                entry.cidr,
+                entry.expires_at
+            );
+            access_list.insert(entry.clone()).map_err(store_error)?;
+            Ok(json(200, serde_json::json!({ "entry": entry })))
+        }
+        "DELETE" => {
+            if !access_list.remove(&cidr).map_err(store_error)? {
+                return Err(json(
+                    404,
+                    serde_json::json!({ "message": "entry not found" }),
+                ));
+            }
+            log::info!("admin: removed {}", cidr);
+            Ok(json(200, serde_json::json!({ "removed": cidr })))
+        }
+        _ => Err(json(
+            405,
+            serde_json::json!({ "message": "method not allowed" }),
+        )),
+    }
+}
+
+fn kill_switch(
+    switch: &KillSwitch,
+    method: &str,
+    query: &QueryParams,
+) -> Result<Response, Response> {
+    let store_error = |e: pow_runtime::kv_store::Error| {
+        Response::from(Error::other("failed to access kill switch", e.to_string()))
+    };
+    let now = now();
+    match method {
+        "GET" => {
+            let engagement = switch.get(now).map_err(store_error)?;
+            Ok(json(
+                200,
+                serde_json::json!({ "engaged": engagement.is_some(), "engagement": engagement }),
+            ))
+        }
+        "POST" | "PUT" => {
+            let ttl = query
+                .get_as::<u64>("ttl")
+                .map_err(|e| bad_request(format!("invalid ttl: {}", e)))?;
+            let engagement = Engagement {
+                reason: query.get("reason").map(str::to_string),
+                since: now,
+                expires_at: ttl.map(|ttl| now + ttl),
+            };
+            log::warn!(
+                "admin: kill switch engaged ({:?}) until {:?}, enforcement is off",
+                engagement.reason,
+                engagement.expires_at
+            );
+            switch.engage(&engagement).map_err(store_error)?;
+            Ok(json(
+                200,
+                serde_json::json!({ "engaged": true, "engagement": engagement }),
+            ))
+        }
+        "DELETE" => {
+            switch.release().map_err(store_error)?;
+            log::warn!("admin: kill switch released, enforcement is on");
+            Ok(json(200, serde_json::json!({ "engaged": false })))
+        }
+        _ => Err(json(
+            405,
+            serde_json::json!({ "message": "method not allowed" }),
+        )),
+    }
+}
+
+fn rate_limit(
+    counters: &CounterBucket,
+    method: &str,
+    query: &QueryParams,
+) -> Result<Response, Response> {
+    let store_error = |e: pow_runtime::counter_bucket::Error| {
+        Response::from(Error::other("failed to access rate limit counters", e.to_string()))
+    };
+    let prefix = match (query.get("route"), query.get("subject")) {
+        (Some(route), Some(subject)) => format!("{}:{}:", route, subject),
+        (Some(route), None) => format!("{}:", route),
+        (None, Some(_)) => return Err(bad_request("subject without route".to_string())),
+        (None, None) => String::new(),
+    };
+    match method {
+        "GET" => {
+            let keys = counters.list_keys(&prefix).map_err(store_error)?;
+            Ok(json(200, serde_json::json!({ "counters": keys.len() })))
+        }
+        "DELETE" => {
+            let cleared = counters.clear(&prefix).map_err(store_error)?;
+            log::info!("admin: cleared {} rate limit counters of {:?}", cleared, prefix);
+            Ok(json(200, serde_json::json!({ "cleared": cleared })))
+        }
+        _ => Err(json(
+            405,
+            serde_json::json!({ "message": "method not allowed" }),
+        )),
+    }
+}
+
+fn export(entries: Vec<Entry>, format: Option<&str>, now: u64) -> Result<Response, Response> {
+    let bans = entries.into_iter().filter(|e| e.rule == Rule::Deny);
+    match format {
+        None | Some("json") => {
+            let bans: Vec<_> = bans
+                .map(|e| {
+                    serde_json::json!({
+                        "cidr": e.cidr,
+                        "expires_at": e.expires_at,
+                        "ttl": e.expires_at.map(|at| at.saturating_sub(now)),
+                    })
+                })
+                .collect();
+            Ok(json(
+                200,
+                serde_json::json!({ "generated_at": now, "bans": bans }),
+            ))
+        }
+        Some("envoy") => {
+            let ranges: Vec<_> = bans
+                .map(|e| {
+                    let cidr = e.cidr.to_string();
+                    let (prefix, len) = cidr.split_once('/').unwrap_or((&cidr, ""));
+                    serde_json::json!({
+                        "address_prefix": prefix,
+                        "prefix_len": len.parse::<u8>().unwrap_or_default(),
+                    })
+                })
+                .collect();
+            Ok(json(200, serde_json::Value::from(ranges)))
+        }
+        Some("nginx") => {
+            let lines: Vec<_> = bans
+                .map(|e| match e.expires_at {
+                    Some(at) => format!("deny {}; # expires at {}\n", e.cidr, at),
+                    None => format!("deny {};\n", e.cidr),
+                })
+                .collect();
+            let header = format!("# {} active bans, generated at {}\n", lines.len(), now);
+            Ok(Response::builder().text(header + &lines.concat()).build())
+        }
+        Some(other) => Err(bad_request(format!(
+            "invalid format {}, expect json, envoy or nginx",
+            other
+        ))),
+    }
+}
+
+/// A snapshot of the plugin on the worker serving the status request.
+pub(crate) struct Health {
+    /// Generation of the configuration, bumped on every reload.
+    pub epoch: u64,
+    pub latest_hash: Option<String>,
+    pub seed_age: Option<Duration>,
+    pub pending_counters: usize,
+    pub flush_lag: Option<Duration>,
+    pub locks: Contention,
+    pub executor: Stats,
+}
+
+/// The health of the plugin served on `status_path`, e.g.
+///
+/// ```text
+/// {"version": "0.1.0", "config_epoch": 2,
+///  "chain": {"latest_hash": "0000..", "seed_age_secs": 312},
+///  "counters": {"pending": 17, "flush_lag_ms": 420},
+///  "locks": {"acquired": 1024, "contended": 3},
+///  "executor": {"spawned": 5120, "pending": 4, "longest_poll_us": 180, "budget_exhausted": 0}}
+/// ```
+///
+/// Unknown ages are `null`, e.g. before the first block hash arrived.
+pub(crate) fn status(health: Health) -> Response {
+    json(
+        200,
+        serde_json::json!({
+            "version": env!("CARGO_PKG_VERSION"),
+            "config_epoch": health.epoch,
+            "chain": {
+                "latest_hash": health.latest_hash,
+                "seed_age_secs": health.seed_age.map(|age| age.as_secs()),
+            },
+            "counters": {
+                "pending": health.pending_counters,
+                "flush_lag_ms": health.flush_lag.map(|lag| lag.as_millis() as u64),
+            },
+            "locks": {
+                "acquired": health.locks.acquired,
+                "contended": health.locks.contended,
+            },
+            "executor": {
+                "spawned": health.executor.spawned,
+                "pending": health.executor.pending,
+                "longest_poll_us": health.executor.longest_poll.as_micros() as u64,
+                "budget_exhausted": health.executor.budget_exhausted,
+            },
+        }),
+    )
+}
+
+/// Serve an admin request, `path` is the request path with the prefix stripped.
+pub(crate) fn handle(
+    access_list: &AccessList,
+    switch: &KillSwitch,
+    counters: &CounterBucket,
+    method: &str,
+    path: &str,
+) -> Response {
+    let query = QueryParams::parse(path);
+    let path = path.split_once('?').map_or(path, |(path, _)| path);
+    let ret = match path {
+        "/blocklist" => blocklist(access_list, method, &query),
+        "/kill_switch" => kill_switch(switch, method, &query),
+        "/rate_limit" => rate_limit(counters, method, &query),
+        "/blocklist/export" if method == "GET" => access_list
+            .entries()
+            .map_err(|e| Response::from(Error::other("failed to read blocklist", e.to_string())))
+            .and_then(|entries| export(entries, query.get("format"), now())),
+        _ => Err(json(404, serde_json::json!({ "message": "not found" }))),
+    };
+    ret.unwrap_or_else(|e| e)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn query_and_cidr() {
+        let query = QueryParams::parse(
+            "/kill_switch?cidr=2001%3Adb8%3A%3A%2F32&rule=allow&ttl&reason=under+attack",
+        );
+        assert_eq!(query.get("cidr"), Some("2001:db8::/32"));
+        assert_eq!(query.get("ttl"), Some(""));
+        assert_eq!(query.get("reason"), Some("under attack"));
+        assert_eq!(parse_rule(query.get("rule")).ok(), Some(Rule::Allow));
+        assert_eq!(parse_rule(None).ok(), Some(Rule::Deny));
+
+        assert_eq!(
+            parse_cidr("203.0.113.7").ok(),
+            Some("203.0.113.7/32".parse().unwrap())
+        );
+        assert_eq!(
+            parse_cidr("2001:db8::/32").ok(),
+            Some("2001:db8::/32".parse().unwrap())
+        );
+        assert_eq!(parse_cidr("203.0.113.7/33").map_err(|r| r.code), Err(400));
+    }
+
+    #[test]
+    fn status_snapshot() {
+        let response = status(Health {
+            epoch: 2,
+            latest_hash: None,
+            seed_age: None,
+            pending_counters: 17,
+            flush_lag: Some(Duration::from_millis(420)),
+            locks: Contention {
+                acquired: 10,
+                contended: 3,
+            },
+            executor: Stats::default(),
+        });
+        assert_eq!(response.code, 200);
+        let body: serde_json::Value = serde_json::from_slice(&response.body.unwrap()).unwrap();
+        assert_eq!(body["version"], env!("CARGO_PKG_VERSION"));
+        assert_eq!(body["config_epoch"], 2);
+        assert_eq!(body["chain"]["seed_age_secs"], serde_json::Value::Null);
+        assert_eq!(body["counters"]["flush_lag_ms"], 420);
+        assert_eq!(body["locks"]["contended"], 3);
+        assert_eq!(body["executor"]["pending"], 0);
+    }
+
+    #[test]
+    fn export_formats() {
+        let entries = vec![
+            Entry {
+                cidr: "203.0.113.0/24".parse().unwrap(),
+                rule: Rule::Deny,
+                expires_at: Some(1_060),
+                added_at: 1_000,
+            },
+            Entry {
+                cidr: "2001:db8::/32".parse().unwrap(),
+                rule: Rule::Deny,
+                expires_at: None,
+                added_at: 1_000,
+            },
+            Entry {
+                cidr: "198.51.100.7/32".parse().unwrap(),
+                rule: Rule::Allow,
+                expires_at: None,
+                added_at: 1_000,
+            },
+        ];
+        let body = |format: &str| {
+            let response = export(entries.clone(), Some(format), 1_000).expect("failed to export");
+            String::from_utf8(response.body.unwrap()).unwrap()
+        };
+
+        let feed: serde_json::Value = serde_json::from_str(&body("json")).unwrap();
+        assert_eq!(feed["bans"].as_array().map(Vec::len), Some(2));
+        assert_eq!(feed["bans"][0]["ttl"], 60);
+        assert_eq!(feed["bans"][1]["ttl"], serde_json::Value::Null);
+
+        let ranges: serde_json::Value = serde_json::from_str(&body("envoy")).unwrap();
+        assert_eq!(
+            ranges,
+            serde_json::json!([
+                { "address_prefix": "203.0.113.0", "prefix_len": 24 },
+                { "address_prefix": "2001:db8::", "prefix_len": 32 },
+            ])
+        );
+
+        assert_eq!(
+            body("nginx"),
+            "# 2 active bans, generated at 1000\n\
+             deny 203.0.113.0/24; # expires at 1060\n\
+             deny 2001:db8::/32;\n"
+        );
+        assert_eq!(
+            export(entries, Some("iptables"), 0).err().map(|r| r.code),
+            Some(400)
+        );
+    }
+}
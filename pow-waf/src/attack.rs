@@ -0,0 +1,301 @@
+//! Global attack detection.
+//!
+//! Every worker counts the requests it challenged and the solutions it
+//! rejected, and once a second merges them into a sliding window kept in
+//! shared data. When the totals of the window cross the configured thresholds
+//! the worker marks the plugin as under attack until the cool-down ends and
+//! announces it on a [`Broadcast`] topic, so every other worker raises its
+//! difficulty floor right away instead of on its next poll. Each worker
+//! listens with one subscription for the life of its VM, whatever the
+//! detectors of its configurations.
+
+use std::cell::Cell;
+use std::collections::VecDeque;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use pow_runtime::bus::{self, Broadcast};
+use pow_runtime::capabilities;
+use pow_runtime::kv_store::{Error, KVStore};
+use pow_runtime::singleton;
+use pow_runtime::spawn_local;
+use pow_runtime::timeout::sleep;
+use serde::{Deserialize, Serialize};
+
+use crate::config::AttackSetting;
+use crate::now;
+
+const TOPIC: &str = "pow_waf.attack";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+struct Slot {
+    second: u64,
+    challenged: u64,
+    failed: u64,
+}
+
+/// Per second counters of the last `window` seconds.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Window {
+    slots: VecDeque<Slot>,
+}
+
+impl Window {
+    fn add(&mut self, now: u64, window: u64, challenged: u64, failed: u64) {
+        while self
+            .slots
+            .front()
+            .is_some_and(|slot| slot.second + window <= now)
+        {
+            self.slots.pop_front();
+        }
+        match self.slots.back_mut() {
+            Some(slot) if slot.second == now => {
+                slot.challenged += challenged;
+                slot.failed += failed;
+            }
+            _ => self.slots.push_back(Slot {
+                second: now,
+                challenged,
+                failed,
+            }),
+        }
+    }
+
+    fn totals(&self) -> (u64, u64) {
+        self.slots.iter().fold((0, 0), |(c, f), slot| {
+            (c + slot.challenged, f + slot.failed)
+        })
+    }
+}
+
+fn is_tripped(setting: &AttackSetting, challenged: u64, failed: u64) -> bool {
+    setting.challenged.is_some_and(|limit| challenged >= limit)
+        || setting.failed.is_some_and(|limit| failed >= limit)
+}
+
+struct Inner {
+    setting: AttackSetting,
+    window: KVStore<Window>,
+    until: KVStore<u64>,
+    /// Announces the end of a new mitigation.
+    bus: Option<Broadcast<u64>>,
+    challenged: u64,
+    failed: u64,
+    active_until: u64,
+    stop: bool,
+}
+
+impl Inner {
+    /// Merge the local counters into the shared window and trip if needed.
+    fn flush(&mut self) -> Result<(), Error> {
+        let now = now();
+        let (challenged, failed) = (self.challenged, self.failed);
+        self.challenged = 0;
+        self.failed = 0;
+        let window = self.setting.window;
+        let window = self.window.update("", |w| {
+            let mut w = w.unwrap_or_default();
+            w.add(now, window, challenged, failed);
+            Ok::<_, Error>(w)
+        })?;
+        let (challenged, failed) = window.totals();
+        if is_tripped(&self.setting, challenged, failed) {
+            self.trip(now, challenged, failed)?;
+        }
+        Ok(())
+    }
+
+    fn trip(&mut self, now: u64, challenged: u64, failed: u64) -> Result<(), Error> {
+        let until = now + self.setting.cool_down;
+        let previous = self.until.get("")?.unwrap_or(0);
+        self.until.put("", &until.max(previous))?;
+        self.active_until = until.max(previous);
+        if previous <= now {
+            log::warn!(
+                "attack detected, challenged: {}, failed: {}, raise difficulty floor to {} until {}",
+                challenged,
+                failed,
+                self.setting.difficulty_floor,
+                until
+            );
+            if let Some(bus) = &self.bus {
+                match bus.publish(&until) {
+                    Ok(workers) => log::debug!("announced attack to {} workers", workers),
+                    Err(e) => log::warn!("failed to announce attack: {}", e),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn refresh(&mut self) -> Result<(), Error> {
+        self.active_until = self.until.get("")?.unwrap_or(0);
+        Ok(())
+    }
+}
+
+/// The end of the latest mitigation announced to this worker, kept by its
+/// one listener.
+fn announced(bus: &Broadcast<u64>) -> Option<Rc<Cell<u64>>> {
+    singleton::get_or_init(TOPIC, || {
+        let mut subscription = bus
+            .subscribe()
+            .inspect_err(|e| log::warn!("failed to subscribe to attack topic: {}", e))
+            .ok()?;
+        let announced = Rc::new(Cell::new(0));
+        let sink = announced.clone();
+        spawn_local(async move {
+            loop {
+                match subscription.next().await {
+                    Ok(until) => sink.set(sink.get().max(until)),
+                    Err(e @ bus::Error::Codec(_)) => {
+                        log::warn!("dropped attack announcement: {}", e)
+                    }
+                    Err(e) => {
+                        log::warn!("stop listening for attack announcements: {}", e);
+                        break;
+                    }
+                }
+            }
+        });
+        Some(announced)
+    })
+}
+
+pub struct AttackDetector {
+    inner: Arc<Mutex<Inner>>,
+    announced: Option<Rc<Cell<u64>>>,
+}
+
+impl Drop for AttackDetector {
+    fn drop(&mut self) {
+        let mut lock = self.inner.lock().expect("failed to lock inner");
+        lock.stop = true;
+    }
+}
+
+impl AttackDetector {
+    pub fn new(context_id: u32, setting: AttackSetting) -> Self {
+        let bus = if capabilities::get().shared_queue {
+            Some(Broadcast::new(context_id, TOPIC))
+        } else {
+            capabilities::degraded(
+                "shared queues",
+                "workers pick up attack mode within a second instead of right away",
+            );
+            None
+        };
+        let announced = bus.as_ref().and_then(announced);
+        let inner = Arc::new(Mutex::new(Inner {
+            setting,
+            window: KVStore::new(context_id, "attack:window"),
+            until: KVStore::new(context_id, "attack:until"),
+            bus,
+            challenged: 0,
+            failed: 0,
+            active_until: 0,
+            stop: false,
+        }));
+
+        let background = inner.clone();
+        spawn_local(async move {
+            loop {
+                {
+                    let mut inner = background.lock().expect("failed to lock inner");
+                    if inner.stop {
+                        break;
+                    }
+                    if let Err(e) = inner.flush().and_then(|_| inner.refresh()) {
+                        log::warn!("failed to update attack window: {}", e);
+                    }
+                }
+                sleep(Duration::from_secs(1)).await;
+            }
+        });
+
+        Self { inner, announced }
+    }
+
+    /// A matched request had to solve a challenge.
+    pub fn record_challenged(&self) {
+        self.inner.lock().expect("failed to lock inner").challenged += 1;
+    }
+
+    /// A submitted solution was rejected.
+    pub fn record_failed(&self) {
+        self.inner.lock().expect("failed to lock inner").failed += 1;
+    }
+
+    /// Unix timestamp the current mitigation ends at, in the past when idle.
+    pub fn active_until(&self) -> u64 {
+        let announced = self.announced.as_ref().map_or(0, |cell| cell.get());
+        let inner = self.inner.lock().expect("failed to lock inner");
+        inner.active_until.max(announced)
+    }
+
+    /// Extend the mitigation to `until` as reported by another proxy.
+    pub fn adopt(&self, until: u64) -> Result<(), Error> {
+        let active_until = self.active_until();
+        let mut inner = self.inner.lock().expect("failed to lock inner");
+        if until <= active_until || until <= now() {
+            return Ok(());
+        }
+        let until = inner
+            .until
+            .update("", |previous| Ok::<_, Error>(previous.unwrap_or(0).max(until)))?;
+        log::warn!("attack reported by a peer, mitigation until {}", until);
+        inner.active_until = until;
+        Ok(())
+    }
+
+    /// The difficulty floor and bucket shrink factor while under attack.
+    pub fn mitigation(&self) -> Option<(u64, u64)> {
+        if self.active_until() <= now() {
+            return None;
+        }
+        let inner = self.inner.lock().expect("failed to lock inner");
+        Some((inner.setting.difficulty_floor, inner.setting.bucket_shrink))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn window_slides() {
+        let mut window = Window::default();
+        window.add(100, 10, 3, 1);
+        window.add(100, 10, 2, 0);
+        window.add(105, 10, 4, 2);
+        assert_eq!(window.totals(), (9, 3));
+        assert_eq!(window.slots.len(), 2);
+
+        window.add(110, 10, 1, 0);
+        assert_eq!(window.totals(), (5, 2));
+        window.add(200, 10, 0, 0);
+        assert_eq!(window.totals(), (0, 0));
+    }
+
+    #[test]
+    fn trips_on_either_threshold() {
+        let setting = AttackSetting {
+            window: 10,
+            challenged: Some(100),
+            failed: None,
+            difficulty_floor: 1000,
+            bucket_shrink: 1,
+            cool_down: 60,
+        };
+        assert!(!is_tripped(&setting, 99, 1_000));
+        assert!(is_tripped(&setting, 100, 0));
+
+        let setting = AttackSetting {
+            failed: Some(10),
+            ..setting
+        };
+        assert!(is_tripped(&setting, 0, 10));
+    }
+}
@@ -0,0 +1,103 @@
+//! CAPTCHAs instead of PoW challenges from a difficulty on, since extreme
+//! difficulties punish legitimate low-power devices more than attackers.
+//!
+//! From the `ceiling` on, a client is answered with a CAPTCHA challenge
+//! carrying the `site_key` instead of a PoW challenge. It renders the widget
+//! and repeats the request with the token in the `token_header`, which the
+//! filter checks with the verification API of the provider. Tokens are
+//! single use, so a solved CAPTCHA lets the client through for `pass_for`
+//! seconds, kept in shared data by its rate limit subject.
+
+use std::time::Duration;
+
+use pow_runtime::http::{self, Client};
+use pow_runtime::kv_store::{Error, ExpiringKVStore};
+use serde::Deserialize;
+
+use crate::config::CaptchaSetting;
+
+/// The answer of hCaptcha, reCAPTCHA and Turnstile alike.
+#[derive(Debug, Deserialize)]
+struct Verification {
+    success: bool,
+    #[serde(rename = "error-codes", default)]
+    error_codes: Vec<String>,
+}
+
+pub struct Captcha {
+    setting: CaptchaSetting,
+    client: Client,
+    /// Until when a subject is let through, in seconds since the epoch.
+    passes: ExpiringKVStore<u64>,
+}
+
+impl Captcha {
+    pub fn new(context_id: u32, setting: CaptchaSetting) -> Self {
+        Self {
+            client: Client::new(&setting.upstream, &setting.authority),
+            passes: ExpiringKVStore::new(context_id, "captcha"),
+            setting,
+        }
+    }
+
+    pub fn setting(&self) -> &CaptchaSetting {
+        &self.setting
+    }
+
+    /// Whether a request of `difficulty` is challenged with a CAPTCHA.
+    pub fn applies(&self, difficulty: u64) -> bool {
+        difficulty >= self.setting.ceiling
+    }
+
+    pub fn has_pass(&self, subject: &str, now: u64) -> Result<bool, Error> {
+        Ok(self.passes.get(subject)?.is_some_and(|until| until > now))
+    }
+
+    /// Let `subject` through for `pass_for` seconds.
+    pub fn grant_pass(&self, subject: &str, now: u64) -> Result<(), Error> {
+        let pass_for = self.setting.pass_for;
+        if pass_for == 0 {
+            return Ok(());
+        }
+        self.passes
+            .put(subject, &(now + pass_for), Duration::from_secs(pass_for))
+    }
+
+    /// Check `token`, solved by the client at `remote_ip`. A rejected token
+    /// is told apart from a failed call by the error codes of the provider.
+    pub async fn verify(
+        &self,
+        token: &str,
+        remote_ip: &str,
+    ) -> Result<Result<(), String>, http::Error> {
+        let form = [
+            ("secret", self.setting.secret.as_str()),
+            ("response", token),
+            ("remoteip", remote_ip),
+        ];
+        let verification: Verification = self.client.post_form(&self.setting.path, &form).await?;
+        Ok(match verification.success {
+            true => Ok(()),
+            false => Err(verification.error_codes.join(",")),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn verification() {
+        let rejected: Verification = serde_json::from_str(
+            r#"{"success": false, "error-codes": ["invalid-input-response"]}"#,
+        )
+        .unwrap();
+        assert!(!rejected.success);
+        assert_eq!(rejected.error_codes, vec!["invalid-input-response"]);
+        let passed: Verification =
+            serde_json::from_str(r#"{"success": true, "hostname": "example.com"}"#).unwrap();
+        assert!(passed.success);
+        assert!(passed.error_codes.is_empty());
+    }
+}
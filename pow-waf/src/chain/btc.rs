@@ -1,20 +1,38 @@
-use std::{collections::VecDeque, time::Duration};
+use std::{collections::VecDeque, time::{Duration, SystemTime}};
 use std::sync::{Arc, RwLock};
 
 use log::{debug, warn};
-use proxy_wasm::types::Status;
 
+use pow_runtime::bus::{self, Broadcast};
+use pow_runtime::capabilities;
+use pow_runtime::clock;
+use pow_runtime::http;
 use pow_runtime::lock::SharedDataLock;
-use pow_runtime::{http_call, spawn_local};
+use pow_runtime::spawn_local;
 use pow_runtime::timeout::sleep;
+use pow_runtime::upstream::{self, RetrySetting, UpstreamSetting};
 
+/// Name of the mempool.space upstream in the plugin configuration.
+pub const UPSTREAM: &str = "mempool";
+
+/// The worker that fetched a new block hash broadcasts the recent hash list
+/// on this topic, so the other workers update their copy right away instead
+/// of reading shared data on every check.
+const TOPIC: &str = "pow_waf.chain";
+
+#[derive(Clone)]
 pub struct BTC {
     inner: Arc<Inner>
 }
 
 pub struct Inner {
-    upstream_name: String,
     recent_hash_list: SharedDataLock<VecDeque<String>>,
+    topic: Option<Broadcast<VecDeque<String>>>,
+    /// This worker's copy of the recent hash list while subscribed to the
+    /// topic, `None` reads shared data instead.
+    cache: Option<RwLock<VecDeque<String>>>,
+    /// The latest hash and when this worker first saw it.
+    seen: RwLock<Option<(String, SystemTime)>>,
     state: RwLock<State>,
 }
 
@@ -26,17 +44,54 @@ enum State {
 }
 
 impl BTC {
-    pub fn new(upstream_name: String) -> Self 
+    /// The upstream configured by `mempool_upstream_name` alone.
+    pub fn upstream(cluster: String) -> UpstreamSetting {
+        UpstreamSetting {
+            cluster,
+            authority: "mempool.space".to_string(),
+            timeout: 10_000,
+            // a 502 would otherwise leave the hashes stale until the next poll
+            retry: RetrySetting {
+                max_attempts: 3,
+                backoff: 500,
+                ..Default::default()
+            },
+            circuit_breaker: None,
+        }
+    }
+
+    pub fn new(context_id: u32) -> Self 
     {
-        let recent_hash_list = SharedDataLock::new(0);
+        let recent_hash_list = SharedDataLock::new(context_id);
         if let Err(e) = recent_hash_list.initial(VecDeque::new()) {
             log::info!("failed to initialize shared data: {:?}", e);
         }
 
+        let topic = if capabilities::get().shared_queue {
+            Some(Broadcast::new(context_id, TOPIC))
+        } else {
+            capabilities::degraded(
+                "shared queues",
+                "workers read the block hashes from shared data on every check",
+            );
+            None
+        };
+        // subscribe before reading the list, so no broadcast is missed in between
+        let subscription = topic.as_ref().and_then(|topic| {
+            topic.subscribe()
+                .inspect_err(|e| warn!("failed to subscribe to block hashes: {}", e))
+                .ok()
+        });
+        let cache = subscription.as_ref().map(|_| {
+            RwLock::new(recent_hash_list.read().unwrap_or_default())
+        });
+
         let ret = Self {
             inner: Arc::new(Inner {
-                upstream_name,
                 recent_hash_list,
+                topic,
+                cache,
+                seen: RwLock::new(None),
                 state: RwLock::new(State::Initial),
             })
         };
@@ -46,28 +101,70 @@ impl BTC {
             ret_clone.start().await;
         });
 
+        if let Some(mut subscription) = subscription {
+            let ret_clone = ret.clone();
+            spawn_local(async move {
+                loop {
+                    let announced = subscription.next().await;
+                    if State::Stopped == *ret_clone.inner.state.read().expect("failed to read state") {
+                        break;
+                    }
+                    match announced {
+                        Ok(list) => ret_clone.cache(list),
+                        Err(e @ bus::Error::Codec(_)) => warn!("dropped block hashes: {}", e),
+                        Err(e) => {
+                            warn!("stop listening for block hashes: {}", e);
+                            break;
+                        }
+                    }
+                }
+            });
+        }
+
         ret
     }
 
-    fn clone(&self) -> Self {
-        Self {
-            inner: self.inner.clone()
+    fn recent_hash_list(&self) -> VecDeque<String> {
+        match &self.inner.cache {
+            Some(cache) => cache.read().expect("failed to read cache").clone(),
+            None => self.inner
+                .recent_hash_list
+                .read()
+                .expect("failed to read recent hash list"),
+        }
+    }
+
+    fn saw(&self, latest: Option<&String>) {
+        let Some(latest) = latest else { return };
+        let mut seen = self.inner.seen.write().expect("failed to write seen");
+        if seen.as_ref().map(|(hash, _)| hash) != Some(latest) {
+            *seen = Some((latest.clone(), clock::now()));
+        }
+    }
+
+    /// How long the latest hash has been the seed of challenges, as far as
+    /// this worker knows.
+    pub fn seed_age(&self) -> Option<Duration> {
+        let seen = self.inner.seen.read().expect("failed to read seen");
+        let (_, at) = seen.as_ref()?;
+        Some(clock::since(*at))
+    }
+
+    fn cache(&self, list: VecDeque<String>) {
+        self.saw(list.front());
+        if let Some(cache) = &self.inner.cache {
+            debug!("cache block hashes: {:?}", list);
+            *cache.write().expect("failed to write cache") = list;
         }
     }
 
     pub fn check_in_list(&self, hash: &str) -> bool {
-        self.inner
-            .recent_hash_list
-            .read()
-            .expect("failed to read recent hash list")
+        self.recent_hash_list()
             .contains(&hash.to_string())
     }
 
     pub fn get_latest_hash(&self) -> Option<String> {
-        self.inner
-            .recent_hash_list
-            .read()
-            .expect("failed to read recent hash list")
+        self.recent_hash_list()
             .front()
             .cloned()
     }
@@ -101,41 +198,13 @@ impl BTC {
         *self.inner.state.write().expect("failed to write state") = state;
     }
 
-    async fn update_latest_hash(&self) -> Result<(), Status>
+    async fn update_latest_hash(&self) -> Result<(), http::Error>
     {
         debug!("fetching latest block hash from mempool.space");
-        let response = http_call(
-            &self.inner.upstream_name,
-            vec![
-                (":method", "GET"),
-                (":path", "/api/blocks/tip/hash"),
-                (":authority", "mempool.space"),
-                (":schema", "https"),
-                ("accept", "application/json"),
-            ],
-            None,
-            Vec::with_capacity(0),
-            Duration::from_secs(10),
-        )
-        .inspect_err(|&e| {
-            log::error!("failed to make http call: {:?}, please check the upstream {} exists", e, "mempool.space");
-        })?
-        .await
-        .map_err(|_| Status::InternalFailure)?;
-        
+        let body = upstream::client(UPSTREAM)?.get("/api/blocks/tip/hash").await?;
         debug!("receive mempool.space response");
 
-        let Some(body) = response.body else {
-            warn!("empty response body");
-            return Err(Status::InternalFailure);
-        };
-
-        let body_str = String::from_utf8(body)
-            .map_err(|e| {
-                warn!("invalid response body: {}", e);
-                Status::InternalFailure
-            })?;
-
+        let body_str = String::from_utf8_lossy(&body).into_owned();
         if body_str.len() != 64 {
             warn!("invalid block hash: {}", body_str);
             return Ok(())
@@ -144,6 +213,7 @@ impl BTC {
         let mut recent_hash_list = self.inner.recent_hash_list.lock().await.expect("failed to write recent hash list");
         debug!("response body: {}", body_str);
         if recent_hash_list.contains(&body_str) {
+            self.saw(recent_hash_list.front());
             return Ok(());
         }
 
@@ -155,6 +225,16 @@ impl BTC {
             let _: Vec<_> = recent_hash_list.drain(2..).collect();
         }
 
+        let list = recent_hash_list.clone();
+        drop(recent_hash_list);
+        self.cache(list.clone());
+        if let Some(topic) = &self.inner.topic {
+            match topic.publish(&list) {
+                Ok(workers) => debug!("broadcast new block hash to {} workers", workers),
+                Err(e) => warn!("failed to broadcast new block hash: {}", e),
+            }
+        }
+
         Ok(())
     }
 
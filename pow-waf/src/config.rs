@@ -1,7 +1,15 @@
+use pow_runtime::clock;
+use pow_runtime::failure_mode::FailureMode;
 use pow_runtime::log_level::LogLevel;
+use pow_runtime::response::ErrorFormat;
+use pow_runtime::upstream::UpstreamSetting;
 use pow_types::cidr::CIDR;
 use pow_types::config::VirtualHost;
+use pow_types::protocol::Version;
+use pow_types::route::normalize::PathNormalization;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::net::IpAddr;
 
 #[derive(Debug, Eq, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "snake_case")]
@@ -31,25 +39,1237 @@ pub struct RateLimit {
 
 impl RateLimit {
     pub fn current_bucket(&self) -> u64 {
-        let unit: u64 = self.unit.as_secs();
-        let timestamp = std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .expect("failed to get timestamp")
-            .as_secs();
-        timestamp / unit
+        self.current_bucket_shrunk(1)
+    }
+
+    /// Like [`RateLimit::current_bucket`] with the window divided by `shrink`.
+    pub fn current_bucket_shrunk(&self, shrink: u64) -> u64 {
+        let unit: u64 = (self.unit.as_secs() / shrink.max(1)).max(1);
+        clock::unix_secs() / unit
+    }
+
+    /// Allowance of a window shrunk by `shrink`, keeping the average rate.
+    /// Seconds until the current bucket ends.
+    pub fn bucket_reset_shrunk(&self, shrink: u64) -> u64 {
+        let unit: u64 = (self.unit.as_secs() / shrink.max(1)).max(1);
+        unit - clock::unix_secs() % unit
+    }
+
+    pub fn requests_per_bucket(&self, shrink: u64) -> u64 {
+        (self.requests_per_unit as u64 / shrink.max(1)).max(1)
+    }
+}
+
+/// What rate limit counters are kept per. Requests without the identity or
+/// header are counted per client address.
+#[derive(Debug, Default, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RateLimitKey {
+    #[default]
+    Ip,
+    /// The principal the auth filter verified, read from the
+    /// `principal_header` of `priority_classes`, `X-Auth-Principal` without
+    /// them. Clients behind one NAT share no quota, one client's addresses do.
+    AuthIdentity,
+    /// The value of a request header. Only use headers set by a filter in
+    /// front of this one, clients can pick any other value.
+    Header(String),
+    /// The TLS fingerprint read from `fingerprint`, shared by every client
+    /// of one TLS stack, whatever its address.
+    TlsFingerprint,
+}
+
+impl RateLimitKey {
+    /// The subject of the counters of a request from `ip` with the TLS
+    /// `fingerprint`, `header` reads a request header.
+    pub fn subject<E>(
+        &self,
+        ip: IpAddr,
+        principal_header: &str,
+        fingerprint: Option<String>,
+        header: impl Fn(&str) -> Result<Option<String>, E>,
+    ) -> Result<String, E> {
+        let value = match self {
+            RateLimitKey::Ip => None,
+            RateLimitKey::AuthIdentity => header(principal_header)?
+                .filter(|p| !p.is_empty())
+                .map(|p| format!("principal={}", p)),
+            RateLimitKey::Header(name) => header(name)?
+                .filter(|v| !v.is_empty())
+                .map(|v| format!("header={}", v)),
+            RateLimitKey::TlsFingerprint => fingerprint,
+        };
+        Ok(value.unwrap_or_else(|| ip.to_string()))
     }
 }
 
+fn default_weight() -> u64 {
+    1
+}
+
 #[derive(Debug, Eq, PartialEq, Serialize, Deserialize)]
 pub struct Setting {
     pub rate_limit: RateLimit,
+    /// Overrides the filter wide `rate_limit_key` for this route.
+    #[serde(default, with = "serde_yaml::with::singleton_map")]
+    pub rate_limit_key: Option<RateLimitKey>,
+    /// Budget a request of this route consumes from its rate limit, so
+    /// expensive endpoints run out sooner than cheap ones.
+    #[serde(default = "default_weight")]
+    pub weight: u64,
+    /// Overrides `weight` per method, e.g. `POST: 10`.
+    #[serde(default)]
+    pub method_weights: BTreeMap<String, u64>,
+    /// Caps the requests a client has in flight on the route at once.
+    pub concurrency: Option<ConcurrencySetting>,
+    /// Rejects larger requests with a `413`, see [`crate::limits`].
+    pub size_limits: Option<SizeLimits>,
+    /// Per region overrides, the first matching policy applies.
+    #[serde(default)]
+    pub regions: Vec<RegionPolicy>,
+    /// Overrides the filter wide `failure_mode` for this route.
+    pub failure_mode: Option<FailureMode>,
+    /// Overrides the filter wide `upgrade` policy for this route.
+    pub upgrade: Option<UpgradePolicy>,
+    /// Replaces the filter `difficulty` of this route by a tuned one.
+    pub auto_tune: Option<AutoTuneSetting>,
+    /// Percent of clients PoW is enforced on, all when absent, see
+    /// [`crate::rollout`]. Blocks and rate limit counters apply to everyone.
+    pub rollout: Option<u8>,
+    /// Challenge experiments, see [`crate::experiment`].
+    #[serde(default)]
+    pub variants: Vec<ChallengeVariant>,
+    /// Edge actions of the route, see [`crate::action`].
+    #[serde(default)]
+    pub actions: Vec<RouteAction>,
+}
+
+/// Limits on the size of a request, none when absent.
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+pub struct SizeLimits {
+    /// Headers of a request, pseudo-headers included.
+    pub max_header_count: Option<usize>,
+    /// Bytes of one header, name and value.
+    pub max_header_size: Option<usize>,
+    /// Bytes of the body, by its `content-length` and as received.
+    pub max_body_size: Option<u64>,
+}
+
+/// What happens to a request beyond `max_in_flight`.
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConcurrencyAction {
+    /// Answer with a `429`.
+    #[default]
+    Reject,
+    /// Raise the difficulty to at least the base level of the route.
+    Challenge,
+}
+
+/// See [`crate::in_flight`].
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct ConcurrencySetting {
+    /// Requests of one rate limit subject in flight at once.
+    pub max_in_flight: u32,
+    #[serde(default)]
+    pub exceeded: ConcurrencyAction,
+}
+
+impl Setting {
+    /// The budget a request with `method` consumes, at least 1.
+    pub fn weight(&self, method: &str) -> u64 {
+        self.method_weights
+            .get(method)
+            .copied()
+            .unwrap_or(self.weight)
+            .max(1)
+    }
+}
+
+fn default_redirect_status() -> u32 {
+    302
+}
+
+fn default_upstream_header() -> String {
+    "x-upstream-cluster".to_string()
+}
+
+/// What a route does with a request besides filtering it.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ActionKind {
+    /// Answer with a redirect to `location`, where `{path}` stands for the
+    /// path and query string of the request.
+    Redirect {
+        location: String,
+        #[serde(default = "default_redirect_status")]
+        status: u32,
+    },
+    /// Answer with a fixed response.
+    Respond {
+        status: u32,
+        #[serde(default = "default_content_type")]
+        content_type: String,
+        #[serde(default)]
+        body: String,
+    },
+    /// Set `header` to `cluster` for a `cluster_header` route of Envoy, the
+    /// request is filtered as usual.
+    Upstream {
+        cluster: String,
+        #[serde(default = "default_upstream_header")]
+        header: String,
+    },
+}
+
+/// Restricts an action to requests carrying a header.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct ActionCondition {
+    pub header: String,
+    /// The exact value of the header, any value when absent.
+    pub value: Option<String>,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct RouteAction {
+    /// Tags the metrics and logs of the action.
+    pub name: String,
+    /// Percent of clients the action applies to, all when absent.
+    pub percent: Option<u8>,
+    pub when: Option<ActionCondition>,
+    #[serde(flatten)]
+    pub kind: ActionKind,
+}
+
+fn default_variant_weight() -> u32 {
+    1
+}
+
+/// How the clients of one arm of an experiment are challenged.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct ChallengeVariant {
+    /// Tags the metrics and logs of the variant.
+    pub name: String,
+    /// Share of the clients relative to the other variants.
+    #[serde(default = "default_variant_weight")]
+    pub weight: u32,
+    /// Replaces the base level of the route, tuned or not.
+    pub difficulty: Option<u64>,
+    /// Challenge with the CAPTCHA instead of PoW, when one is configured.
+    #[serde(default)]
+    pub captcha: bool,
+}
+
+fn default_hysteresis() -> u64 {
+    25
+}
+
+fn default_tune_samples() -> usize {
+    50
+}
+
+/// Tune the difficulty of a route to the time clients take to solve it, see
+/// [`crate::tune`].
+#[derive(Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct AutoTuneSetting {
+    /// Median solve time to aim for, in milliseconds.
+    pub target_ms: u64,
+    pub min_difficulty: u64,
+    pub max_difficulty: u64,
+    /// Percent the median may be off the target before the difficulty changes.
+    #[serde(default = "default_hysteresis")]
+    pub hysteresis: u64,
+    /// Solve times per adjustment.
+    #[serde(default = "default_tune_samples")]
+    pub samples: usize,
+    /// HMAC key of the issue times of challenges, proxies with the same one
+    /// sample the solutions of challenges issued by each other.
+    pub secret: String,
+}
+
+fn default_min_protocol_version() -> Version {
+    Version::V1
+}
+
+fn default_target_param() -> String {
+    "path".to_string()
+}
+
+/// Serves the challenge of another path, see [`crate::endpoint`].
+#[derive(Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct ChallengeEndpoint {
+    /// Query parameter with the path to challenge, `/` when absent.
+    #[serde(default = "default_target_param")]
+    pub target_param: String,
+}
+
+/// What to do with requests switching protocols, e.g. WebSocket handshakes.
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UpgradePolicy {
+    /// Let the upgrade through without a PoW check.
+    Allow,
+    /// Reject the upgrade with 403.
+    Deny,
+    /// Check the handshake like any other request. Browsers cannot add the
+    /// PoW headers to a WebSocket handshake, only use this for clients that can.
+    #[default]
+    Challenge,
+}
+
+#[derive(Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RegionAction {
+    /// Reject the request with 403.
+    Block,
+    /// Always require at least this difficulty level.
+    MinDifficulty(u64),
+}
+
+/// Matches clients by the country or ASN provided by [`GeoSource`].
+#[derive(Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct RegionPolicy {
+    /// ISO 3166-1 alpha-2 codes, case insensitive.
+    #[serde(default)]
+    pub countries: Vec<String>,
+    #[serde(default)]
+    pub asns: Vec<u32>,
+    #[serde(flatten)]
+    pub action: RegionAction,
+}
+
+/// Where to read the client's country and ASN from, either a request header
+/// set by a geo filter in front of this one or a host property such as
+/// dynamic metadata. Headers win when both are configured.
+#[derive(Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct GeoSource {
+    pub country_header: Option<String>,
+    pub asn_header: Option<String>,
+    /// Property path, e.g. `[metadata, filter_metadata, geoip, country]`.
+    pub country_property: Option<Vec<String>>,
+    pub asn_property: Option<Vec<String>>,
+}
+
+/// Where to read the TLS fingerprints of the client from, see
+/// [`crate::fingerprint`]. Headers win when both are configured.
+#[derive(Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct FingerprintSource {
+    pub ja3_header: Option<String>,
+    pub ja4_header: Option<String>,
+    /// Property path, e.g. `[connection, ja3_hash]`.
+    pub ja3_property: Option<Vec<String>>,
+    pub ja4_property: Option<Vec<String>>,
+}
+
+/// Connection-level PoW for raw TCP upstreams, see [`crate::tcp`].
+#[derive(Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct TcpSetting {
+    /// Difficulty level every new connection has to solve, 0 disables the check.
+    pub difficulty: u64,
+}
+
+fn default_bucket_shrink() -> u64 {
+    1
+}
+
+/// Global attack detection, see [`crate::attack`].
+#[derive(Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct AttackSetting {
+    /// Length of the sliding window in seconds.
+    pub window: u64,
+    /// Trips when this many requests were challenged within the window.
+    pub challenged: Option<u64>,
+    /// Trips when this many submitted solutions were rejected within the window.
+    pub failed: Option<u64>,
+    /// Minimum difficulty level of every matched route while under attack.
+    pub difficulty_floor: u64,
+    /// Divides rate limit windows and their allowances while under attack,
+    /// so bursts get challenged sooner.
+    #[serde(default = "default_bucket_shrink")]
+    pub bucket_shrink: u64,
+    /// Seconds the mitigation stays in place after the detector last tripped.
+    pub cool_down: u64,
+}
+
+fn default_gossip_path() -> String {
+    "/gossip".to_string()
+}
+
+fn default_gossip_interval() -> u64 {
+    10
+}
+
+fn default_gossip_hot_keys() -> usize {
+    32
+}
+
+/// Exchange bans, hot keys and attack state with other proxies through a
+/// relay, see
+/// [`crate::gossip`].
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct GossipSetting {
+    /// Cluster name of the relay.
+    pub upstream: String,
+    pub authority: String,
+    #[serde(default = "default_gossip_path")]
+    pub path: String,
+    /// Seconds between two exchanges.
+    #[serde(default = "default_gossip_interval")]
+    pub interval: u64,
+    /// Identifies this proxy to the relay, e.g. the region name.
+    pub node: String,
+    /// How many of the busiest rate limit counters to share.
+    #[serde(default = "default_gossip_hot_keys")]
+    pub hot_keys: usize,
+}
+
+fn default_resolver_path() -> String {
+    "/resolve".to_string()
+}
+
+fn default_crawler_verdict() -> u64 {
+    86_400
+}
+
+/// A crawler identified by its `User-Agent`.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct CrawlerSetting {
+    /// Tags the metrics and logs of the crawler, e.g. `googlebot`.
+    pub name: String,
+    /// Regex matched against the `User-Agent` header.
+    pub user_agent: String,
+    /// Domains the reverse DNS name of its addresses ends with, e.g.
+    /// `googlebot.com`.
+    pub domains: Vec<String>,
+}
+
+/// Let crawlers through once their address is verified, see
+/// [`crate::crawler`].
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct CrawlersSetting {
+    /// Cluster name of a DNS over HTTPS resolver with the JSON API, e.g. of
+    /// `dns.google`.
+    pub upstream: String,
+    pub authority: String,
+    #[serde(default = "default_resolver_path")]
+    pub path: String,
+    pub crawlers: Vec<CrawlerSetting>,
+    /// Seconds the verdict on an address is kept.
+    #[serde(default = "default_crawler_verdict")]
+    pub verdict_for: u64,
+}
+
+/// The parts of a request a rule looks at, see [`crate::rules`].
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RuleTarget {
+    /// The path without the query string, normalized and percent-decoded.
+    Path,
+    /// The query string, percent-decoded.
+    Query,
+    /// A request header, rules on absent headers do not match.
+    Header(String),
+}
+
+/// Built-in patterns of common injection attacks.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Signature {
+    Sqli,
+    Xss,
+}
+
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RuleAction {
+    /// Reject the request with 403.
+    #[default]
+    Block,
+    /// Always require at least this difficulty level.
+    MinDifficulty(u64),
+    /// Only count the match.
+    Log,
+}
+
+/// Matches requests by a regex or a [`Signature`], exactly one of both.
+#[derive(Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct RuleSetting {
+    /// Names the `pow_waf.rule.<name>.<action>` metric of matches.
+    pub name: String,
+    #[serde(with = "serde_yaml::with::singleton_map_recursive")]
+    pub targets: Vec<RuleTarget>,
+    pub pattern: Option<String>,
+    pub signature: Option<Signature>,
+    #[serde(default, with = "serde_yaml::with::singleton_map")]
+    pub action: RuleAction,
+}
+
+fn default_captcha_path() -> String {
+    "/siteverify".to_string()
+}
+
+fn default_captcha_header() -> String {
+    "X-Captcha-Token".to_string()
+}
+
+fn default_captcha_pass() -> u64 {
+    600
+}
+
+/// Challenge with a CAPTCHA instead of PoW from a difficulty on, see
+/// [`crate::captcha`].
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct CaptchaSetting {
+    /// Difficulty level from which clients solve a CAPTCHA instead.
+    pub ceiling: u64,
+    /// Cluster name of the verification API, e.g. of hCaptcha, reCAPTCHA or
+    /// Turnstile.
+    pub upstream: String,
+    pub authority: String,
+    #[serde(default = "default_captcha_path")]
+    pub path: String,
+    pub secret: String,
+    /// Public key of the widget, handed to the client with the challenge.
+    pub site_key: String,
+    /// Request header carrying the token of a solved CAPTCHA.
+    #[serde(default = "default_captcha_header")]
+    pub token_header: String,
+    /// Seconds a solved CAPTCHA lets the client through, 0 verifies a token
+    /// with every request.
+    #[serde(default = "default_captcha_pass")]
+    pub pass_for: u64,
+}
+
+fn default_pass_cookie_name() -> String {
+    "__pow_pass".to_string()
+}
+
+/// Let clients that solved a challenge through for a while on a signed
+/// cookie, see [`crate::cookie`].
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct PassCookieSetting {
+    #[serde(default = "default_pass_cookie_name")]
+    pub name: String,
+    /// HMAC key, proxies with the same one accept the cookies of each other.
+    pub secret: String,
+    /// Seconds a solution lets the client through.
+    pub pass_for: u64,
+}
+
+fn default_signature_header() -> String {
+    "X-PoW-Signature".to_string()
+}
+
+/// Sign the requests let through for the upstream, see [`crate::signature`].
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct SignatureSetting {
+    #[serde(default = "default_signature_header")]
+    pub header: String,
+    /// HMAC key shared with the upstream services.
+    pub secret: String,
+    /// Names the key in the signature, so upstreams can rotate keys.
+    pub key_id: Option<String>,
+}
+
+fn default_traces_path() -> String {
+    "/v1/traces".to_string()
+}
+
+fn default_span_interval() -> u64 {
+    5
+}
+
+fn default_max_spans() -> usize {
+    2048
+}
+
+fn default_service_name() -> String {
+    "pow-waf".to_string()
+}
+
+/// Span events of traced requests, see [`crate::telemetry`].
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct TracingSetting {
+    /// Cluster name of the OTLP/HTTP collector.
+    pub upstream: String,
+    pub authority: String,
+    #[serde(default = "default_traces_path")]
+    pub path: String,
+    /// Seconds between two exports of a worker.
+    #[serde(default = "default_span_interval")]
+    pub interval: u64,
+    /// Spans a worker keeps until its next export.
+    #[serde(default = "default_max_spans")]
+    pub max_spans: usize,
+    #[serde(default = "default_service_name")]
+    pub service_name: String,
+}
+
+fn default_score_window() -> u64 {
+    600
+}
+
+/// Points of clients from a region, see [`crate::score`].
+#[derive(Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct RegionScore {
+    /// ISO 3166-1 alpha-2 codes, case insensitive.
+    #[serde(default)]
+    pub countries: Vec<String>,
+    #[serde(default)]
+    pub asns: Vec<u32>,
+    pub points: u64,
+}
+
+/// Points of clients with a TLS fingerprint, see [`crate::fingerprint`].
+#[derive(Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct FingerprintScore {
+    #[serde(default)]
+    pub ja3: Vec<String>,
+    #[serde(default)]
+    pub ja4: Vec<String>,
+    pub points: u64,
+}
+
+/// Per client risk scoring, see [`crate::score`].
+#[derive(Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct ScoringSetting {
+    /// Seconds the points a client earned by its behaviour are kept.
+    #[serde(default = "default_score_window")]
+    pub window: u64,
+    /// Points per request beyond the rate limit of its route.
+    #[serde(default)]
+    pub rate_overage: u64,
+    /// Points per rejected solution.
+    #[serde(default)]
+    pub invalid_nonce: u64,
+    /// Points per header the request lacks, e.g. `user-agent`.
+    #[serde(default)]
+    pub missing_headers: BTreeMap<String, u64>,
+    /// Only the first matching region counts, requires `geo`.
+    #[serde(default)]
+    pub regions: Vec<RegionScore>,
+    /// Only the first matching fingerprint counts, requires `fingerprint`.
+    #[serde(default)]
+    pub fingerprints: Vec<FingerprintScore>,
+    /// Score from which requests are challenged regardless of their rate.
+    pub challenge: Option<u64>,
+    /// Score from which requests are rejected with 403.
+    pub block: Option<u64>,
+    /// Difficulty level of challenged clients, the filter `difficulty` by
+    /// default.
+    pub challenge_difficulty: Option<u64>,
+}
+
+fn default_reputation_penalty() -> u64 {
+    10
+}
+
+fn default_reputation_half_life() -> u64 {
+    3_600
+}
+
+fn default_reputation_max_multiplier() -> u64 {
+    800
+}
+
+fn default_reputation_clients() -> usize {
+    100_000
+}
+
+/// Difficulty multipliers of clients that keep exceeding their rate limits,
+/// see [`crate::reputation`].
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct ReputationSetting {
+    /// Percent added to the multiplier per request beyond a rate limit.
+    #[serde(default = "default_reputation_penalty")]
+    pub penalty: u64,
+    /// Seconds for the added percent to decay by half.
+    #[serde(default = "default_reputation_half_life")]
+    pub half_life: u64,
+    /// Cap of the multiplier in percent, 800 for eight times the difficulty.
+    #[serde(default = "default_reputation_max_multiplier")]
+    pub max_multiplier: u64,
+    /// Subjects remembered, the least recently penalized are forgotten first.
+    #[serde(default = "default_reputation_clients")]
+    pub max_clients: usize,
+}
+
+fn default_trap_ban() -> u64 {
+    86_400
+}
+
+/// Paths no legitimate client requests, see [`crate::honeypot`].
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct HoneypotSetting {
+    /// Matched against the normalized path without the query string, a
+    /// trailing `*` matches any rest, e.g. `/wp-login.php` or `/.git/*`.
+    pub traps: Vec<String>,
+    /// Seconds a trapped client is denied.
+    #[serde(default = "default_trap_ban")]
+    pub ban_for: u64,
+}
+
+fn default_penalty_window() -> u64 {
+    600
+}
+
+/// Reject clients with too many invalid solutions for a while, see
+/// [`crate::penalty`].
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct PenaltyBoxSetting {
+    /// Rejected solutions within the `window` that put a client in the box.
+    pub failures: u32,
+    /// Seconds rejected solutions are counted.
+    #[serde(default = "default_penalty_window")]
+    pub window: u64,
+    /// Seconds a client stays in the box.
+    pub duration: u64,
+    /// Milliseconds the answer to a boxed client is held back, answered
+    /// right away when absent.
+    pub tarpit: Option<u64>,
+}
+
+fn default_health_path() -> String {
+    "/healthz".to_string()
+}
+
+fn default_health_timeout() -> u64 {
+    1_000
+}
+
+fn default_health_interval() -> u64 {
+    5
+}
+
+fn default_trip_after() -> u32 {
+    3
+}
+
+fn default_recover_after() -> u32 {
+    3
+}
+
+/// Where the saturation of the upstream is read from.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HealthSource {
+    /// Saturated when `GET path` fails, is answered other than `2xx` or
+    /// takes longer than `timeout` milliseconds.
+    Endpoint {
+        /// Cluster name of the upstream.
+        upstream: String,
+        authority: String,
+        #[serde(default = "default_health_path")]
+        path: String,
+        #[serde(default = "default_health_timeout")]
+        timeout: u64,
+    },
+    /// Saturated when the number at the property `path`, e.g. a stat of the
+    /// upstream cluster the host exposes, reaches `threshold`.
+    Property { path: Vec<String>, threshold: i64 },
+}
+
+/// Raise difficulty and tighten rate limits while the upstream is
+/// saturated, see [`crate::health`].
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct HealthSetting {
+    /// Either `endpoint` or `property`.
+    #[serde(flatten)]
+    pub source: HealthSource,
+    /// Seconds between two checks.
+    #[serde(default = "default_health_interval")]
+    pub interval: u64,
+    /// Consecutive saturated checks that start shedding load.
+    #[serde(default = "default_trip_after")]
+    pub trip_after: u32,
+    /// Consecutive healthy checks that stop shedding load.
+    #[serde(default = "default_recover_after")]
+    pub recover_after: u32,
+    /// Minimum difficulty level of every matched route while shedding.
+    pub difficulty_floor: u64,
+    /// Divides rate limit windows and their allowances while shedding.
+    #[serde(default = "default_bucket_shrink")]
+    pub bucket_shrink: u64,
+}
+
+fn default_usage_path() -> String {
+    "/usage".to_string()
+}
+
+fn default_export_interval() -> u64 {
+    60
+}
+
+/// Where every worker posts the usage it counted, see [`crate::quota`].
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct QuotaExport {
+    /// Cluster name of the billing service.
+    pub upstream: String,
+    pub authority: String,
+    #[serde(default = "default_usage_path")]
+    pub path: String,
+    /// Seconds between two exports of a worker.
+    #[serde(default = "default_export_interval")]
+    pub interval: u64,
+}
+
+/// Requests per rate limit subject and calendar period, see
+/// [`crate::quota`].
+#[derive(Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct QuotaSetting {
+    pub daily: Option<u64>,
+    pub monthly: Option<u64>,
+    /// Response header telling the least quota left, e.g.
+    /// `X-Quota-Remaining`.
+    pub remaining_header: Option<String>,
+    pub export: Option<QuotaExport>,
+}
+
+fn default_content_type() -> String {
+    "text/html; charset=utf-8".to_string()
+}
+
+/// A rejection page, see [`crate::template`] for the placeholders.
+#[derive(Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct ResponseTemplate {
+    /// Defaults to the status of the rejection.
+    pub status: Option<u32>,
+    #[serde(default = "default_content_type")]
+    pub content_type: String,
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    pub body: String,
+}
+
+/// When a request gets the HTML challenge page instead of JSON.
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Negotiation {
+    /// `Accept` rates `text/html` above `application/json`.
+    #[default]
+    Accept,
+    /// Like `accept`, and the browser loads a top-level document, by
+    /// `Sec-Fetch-Dest`.
+    Navigate,
+}
+
+/// How the challenge page hands the solution to the filter.
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Submission {
+    /// Fetch the page again with the solution headers and write it out.
+    #[default]
+    Fetch,
+    /// Store the solution in a cookie and reload, see [`crate::interstitial`].
+    Cookie,
+}
+
+fn default_page_title() -> String {
+    "Checking your browser".to_string()
+}
+
+fn default_assets_prefix() -> String {
+    "/__pow/assets".to_string()
+}
+
+/// Serve challenges to browsers as pages solving them, see [`crate::page`].
+#[derive(Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct ChallengePageSetting {
+    /// URL of `pow_mine.js` of the pow-mine package, with `pow_mine_bg.wasm`
+    /// beside it. The embedded package when absent, see
+    /// [`crate::interstitial`].
+    pub loader: Option<String>,
+    /// URL of the module script of the mining workers, the embedded one or
+    /// `./worker.js` relative to the page when absent.
+    pub worker: Option<String>,
+    /// Path the embedded pow-mine package is served under.
+    #[serde(default = "default_assets_prefix")]
+    pub assets_prefix: String,
+    #[serde(default = "default_page_title")]
+    pub title: String,
+    #[serde(default)]
+    pub negotiation: Negotiation,
+    #[serde(default)]
+    pub submit: Submission,
+}
+
+impl ChallengePageSetting {
+    /// URL of the loader, `None` without one or the embedded package.
+    pub fn loader(&self) -> Option<String> {
+        self.loader.clone().or_else(|| {
+            crate::interstitial::EMBEDDED.then(|| format!("{}/pow_mine.js", self.assets_prefix))
+        })
+    }
+
+    pub fn worker(&self) -> Option<String> {
+        self.worker.clone().or_else(|| {
+            crate::interstitial::EMBEDDED.then(|| format!("{}/worker.js", self.assets_prefix))
+        })
+    }
+}
+
+fn default_cors_max_age() -> u64 {
+    600
+}
+
+/// Let browsers on other origins solve challenges, see [`crate::cors`].
+#[derive(Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct CorsSetting {
+    /// Origins such as `https://app.example.com`, `*` for any.
+    pub allow_origins: Vec<String>,
+    /// Allow requests with cookies, the origin is echoed instead of `*`.
+    #[serde(default)]
+    pub allow_credentials: bool,
+    /// Request headers allowed besides the solution headers.
+    #[serde(default)]
+    pub allow_headers: Vec<String>,
+    /// Seconds browsers cache a preflight.
+    #[serde(default = "default_cors_max_age")]
+    pub max_age: u64,
+}
+
+/// Replace the built-in JSON bodies, absent entries keep them.
+#[derive(Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct ResponseTemplates {
+    pub too_many_requests: Option<ResponseTemplate>,
+    pub forbidden: Option<ResponseTemplate>,
+    /// CAPTCHA challenges, `too_many_requests` when absent.
+    pub captcha: Option<ResponseTemplate>,
+    /// Requests beyond the `size_limits` of their route.
+    pub payload_too_large: Option<ResponseTemplate>,
+}
+
+pub(crate) fn default_principal_header() -> String {
+    "X-Auth-Principal".to_string()
+}
+
+/// Policy for a request verified by the auth filter.
+#[derive(Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct PriorityClass {
+    /// Let the request through without a PoW check.
+    #[serde(default)]
+    pub skip_pow: bool,
+    /// Replaces the rate limit of the matched route, counted per principal
+    /// instead of per client address.
+    pub rate_limit: Option<RateLimit>,
+}
+
+/// Maps the principal the auth filter attaches to a request to a
+/// [`PriorityClass`].
+#[derive(Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct PriorityClasses {
+    /// Must match `principal_header` of the auth filter, which has to run
+    /// first so clients cannot set the header themselves.
+    #[serde(default = "default_principal_header")]
+    pub principal_header: String,
+    pub authenticated: PriorityClass,
+}
+
+/// Caps in bytes of the in-VM caches, see [`pow_runtime::memory`]. Usage is
+/// reported as `pow_waf.memory.*` gauges whether capped or not. Shared data
+/// is capped in entries instead.
+#[derive(Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct MemorySetting {
+    /// Rate limit increments not yet flushed to shared data, the least
+    /// recently counted keys are flushed early past the cap.
+    pub counter_buffer: Option<usize>,
+    /// Rate limit counters kept in shared data, the least recently written
+    /// are evicted past the cap and counted in `pow_waf.rate_limit.evicted`.
+    pub rate_limit_entries: Option<usize>,
+    /// In flight counters kept in shared data, the least recently written
+    /// are evicted past the cap and counted in `pow_waf.in_flight.evicted`.
+    pub in_flight_entries: Option<usize>,
+}
+
+fn default_admin_prefix() -> String {
+    "/__pow/admin".to_string()
+}
+
+/// Operator endpoints, see [`crate::admin`].
+#[derive(Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct AdminSetting {
+    /// Path prefix of the admin routes on every host, protect it with the auth filter.
+    #[serde(default = "default_admin_prefix")]
+    pub prefix: String,
 }
 
 #[derive(Debug, Eq, PartialEq, Serialize, Deserialize)]
 pub struct Config<T> {
+    /// Child routes inherit the settings they omit from their parent, so a
+    /// child of `/api` may set only `rate_limit.requests_per_unit`.
+    #[serde(default = "Vec::new")]
     pub virtual_hosts: Vec<VirtualHost<T>>,
     pub whitelist: Option<Vec<CIDR>>,
     pub difficulty: u64,
     pub log_level: Option<LogLevel>,
-    pub mempool_upstream_name: String,
+    /// Cluster of mempool.space, shorthand for an `upstreams` entry named
+    /// `mempool` when there is none.
+    pub mempool_upstream_name: Option<String>,
+    /// Upstreams by name, the chain sources call `mempool`.
+    #[serde(default)]
+    pub upstreams: BTreeMap<String, UpstreamSetting>,
+    /// When present the filter runs as a network filter instead of an HTTP filter.
+    pub tcp: Option<TcpSetting>,
+    /// Serve the admin routes, disabled when absent.
+    pub admin: Option<AdminSetting>,
+    /// Serve the health of the plugin on this path of every host, e.g.
+    /// `/__pow/status`, see [`crate::admin::status`]. Disabled when absent.
+    pub status_path: Option<String>,
+    /// CORS for protected routes and challenge endpoints, none when absent.
+    pub cors: Option<CorsSetting>,
+    /// Set to `true` on requests with a valid solution, e.g. `X-PoW-Verified`.
+    /// Any value sent by the client is removed.
+    pub verified_header: Option<String>,
+    /// Tell clients their quota with `RateLimit-Limit`, `RateLimit-Remaining`
+    /// and `RateLimit-Reset` response headers on matched routes, challenges
+    /// also carry `Retry-After`.
+    #[serde(default)]
+    pub rate_limit_headers: bool,
+    /// Applied to routes without their own, a principal with a priority
+    /// class is always counted by its name.
+    #[serde(default, with = "serde_yaml::with::singleton_map")]
+    pub rate_limit_key: RateLimitKey,
+    pub priority_classes: Option<PriorityClasses>,
+    pub attack: Option<AttackSetting>,
+    /// Required for route `regions` to match anything.
+    pub geo: Option<GeoSource>,
+    /// Required for `tls_fingerprint` rate limit keys and fingerprint scores.
+    pub fingerprint: Option<FingerprintSource>,
+    pub gossip: Option<GossipSetting>,
+    pub quota: Option<QuotaSetting>,
+    pub scoring: Option<ScoringSetting>,
+    pub reputation: Option<ReputationSetting>,
+    pub penalty_box: Option<PenaltyBoxSetting>,
+    /// Sheds load while the upstream is saturated.
+    pub health: Option<HealthSetting>,
+    pub honeypot: Option<HoneypotSetting>,
+    /// Milliseconds a request may take to arrive, from the creation of its
+    /// stream to its end or until it is let through. Slower ones, e.g. of
+    /// clients trickling their body during a check, are answered with a
+    /// `408`. Upgrade requests are exempt.
+    pub request_deadline: Option<u64>,
+    /// Checked in order before rate limits and PoW, see [`crate::rules`].
+    /// `min_difficulty` only applies on matched routes.
+    #[serde(default)]
+    pub rules: Vec<RuleSetting>,
+    pub captcha: Option<CaptchaSetting>,
+    /// Verified crawlers skip PoW, impostors are challenged as usual.
+    pub crawlers: Option<CrawlersSetting>,
+    /// Solutions let clients through for a while, CAPTCHAs keep their own
+    /// `pass_for`.
+    pub pass_cookie: Option<PassCookieSetting>,
+    /// Signs the requests let through, so upstreams can tell them from
+    /// requests sent from inside the mesh.
+    pub signature: Option<SignatureSetting>,
+    /// Reports the decision on traced requests to an OTLP/HTTP collector.
+    pub tracing: Option<TracingSetting>,
+    /// Paths serving challenges per virtual host, matched without the query
+    /// string.
+    #[serde(default)]
+    pub challenge_endpoints: Vec<VirtualHost<ChallengeEndpoint>>,
+    /// Solutions of older protocol versions are challenged again, see
+    /// [`pow_types::protocol`].
+    #[serde(default = "default_min_protocol_version")]
+    pub min_protocol_version: Version,
+    /// Applied to errors before a route is matched and on routes without their own.
+    #[serde(default)]
+    pub failure_mode: FailureMode,
+    /// Applied to upgrade requests on routes without their own policy, only
+    /// `deny` applies to requests no route matches.
+    #[serde(default)]
+    pub upgrade: UpgradePolicy,
+    /// Templates win over `error_format` for the rejections they cover.
+    #[serde(default)]
+    pub responses: ResponseTemplates,
+    #[serde(default)]
+    pub error_format: ErrorFormat,
+    /// Answer browsers with a page solving the challenge, absent keeps the
+    /// `error_format` for everyone.
+    pub challenge_page: Option<ChallengePageSetting>,
+    /// Prefix of the problem `type` URIs, the reason code is appended.
+    pub problem_type_base: Option<String>,
+    #[serde(default)]
+    pub memory: MemorySetting,
+    /// Applied to the path before routes, challenge endpoints and the admin
+    /// prefix are matched, see [`pow_types::route::normalize`].
+    #[serde(default)]
+    pub path_normalization: PathNormalization,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pow_runtime::clock::TestClock;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn rate_limit_window() {
+        let clock = TestClock::new(UNIX_EPOCH + Duration::from_secs(1_700_000_020));
+        let _guard = clock::install(clock.clone());
+        let rate_limit = RateLimit {
+            unit: TimeUnit::Minute,
+            requests_per_unit: 100,
+        };
+        assert_eq!(rate_limit.current_bucket(), 28_333_333);
+        assert_eq!(rate_limit.bucket_reset_shrunk(1), 20);
+        assert_eq!(rate_limit.bucket_reset_shrunk(4), 5);
+        clock.advance(Duration::from_secs(20));
+        assert_eq!(rate_limit.current_bucket(), 28_333_334);
+        assert_eq!(rate_limit.bucket_reset_shrunk(1), 60);
+    }
+
+    #[test]
+    fn routes_inherit_settings() {
+        let mut host: VirtualHost<serde_yaml::Value> = serde_yaml::from_str(
+            r#"
+host: example.com
+routes:
+  - path: /api
+    rate_limit: {unit: minute, requests_per_unit: 50}
+    failure_mode: fail_closed
+    children:
+      - path: /search
+        rate_limit: {requests_per_unit: 10}
+"#,
+        )
+        .unwrap();
+        host.inherit();
+        let host = host.try_map(serde_yaml::from_value::<Setting>).unwrap();
+        let child = &host.routes[0].children.as_ref().unwrap()[0].config;
+        assert_eq!(
+            child.rate_limit,
+            RateLimit {
+                unit: TimeUnit::Minute,
+                requests_per_unit: 10,
+            }
+        );
+        assert_eq!(child.failure_mode, Some(FailureMode::FailClosed));
+    }
+
+    #[test]
+    fn request_weights() {
+        let setting: Setting = serde_yaml::from_str(
+            r#"
+rate_limit:
+  unit: minute
+  requests_per_unit: 100
+weight: 5
+concurrency:
+  max_in_flight: 4
+method_weights:
+  GET: 1
+  DELETE: 0
+"#,
+        )
+        .unwrap();
+        assert_eq!(setting.weight("GET"), 1);
+        assert_eq!(setting.weight("POST"), 5);
+        assert_eq!(setting.weight("DELETE"), 1);
+        assert_eq!(
+            setting.concurrency,
+            Some(ConcurrencySetting {
+                max_in_flight: 4,
+                exceeded: ConcurrencyAction::Reject,
+            })
+        );
+    }
+
+    #[test]
+    fn route_actions() {
+        let setting: Setting = serde_yaml::from_str(
+            r#"
+rate_limit: {unit: minute, requests_per_unit: 60}
+actions:
+  - name: canary
+    percent: 5
+    when: {header: x-beta}
+    upstream: {cluster: canary}
+  - name: maintenance
+    respond: {status: 503, body: back soon}
+"#,
+        )
+        .unwrap();
+        assert_eq!(
+            setting.actions[0].kind,
+            ActionKind::Upstream {
+                cluster: "canary".to_string(),
+                header: "x-upstream-cluster".to_string(),
+            }
+        );
+        assert_eq!(setting.actions[0].when.as_ref().unwrap().value, None);
+        assert_eq!(
+            setting.actions[1].kind,
+            ActionKind::Respond {
+                status: 503,
+                content_type: "text/html; charset=utf-8".to_string(),
+                body: "back soon".to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn rate_limit_subject() {
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let header = |name: &str| -> Result<Option<String>, ()> {
+            Ok(match name {
+                "X-Auth-Principal" => Some("billing".to_string()),
+                "X-Tenant" => Some(String::new()),
+                _ => None,
+            })
+        };
+        let fingerprint = Some("ja4=t13d1516h2_8daaf6152771_02713d6af862".to_string());
+        let subject = |key: RateLimitKey| {
+            key.subject(ip, "X-Auth-Principal", fingerprint.clone(), header)
+                .unwrap()
+        };
+        assert_eq!(subject(RateLimitKey::Ip), "10.0.0.1");
+        assert_eq!(subject(RateLimitKey::AuthIdentity), "principal=billing");
+        assert_eq!(
+            subject(RateLimitKey::Header("X-Auth-Principal".to_string())),
+            "header=billing"
+        );
+        assert_eq!(subject(RateLimitKey::Header("X-Tenant".to_string())), "10.0.0.1");
+        assert_eq!(
+            subject(RateLimitKey::TlsFingerprint),
+            "ja4=t13d1516h2_8daaf6152771_02713d6af862"
+        );
+        assert_eq!(
+            RateLimitKey::TlsFingerprint.subject(ip, "X-Auth-Principal", None, header),
+            Ok("10.0.0.1".to_string())
+        );
+
+        let setting: Setting = serde_yaml::from_str(
+            r#"
+rate_limit:
+  unit: minute
+  requests_per_unit: 60
+rate_limit_key:
+  header: X-Tenant
+"#,
+        )
+        .unwrap();
+        assert_eq!(
+            setting.rate_limit_key,
+            Some(RateLimitKey::Header("X-Tenant".to_string()))
+        );
+        let config: Config<Setting> = serde_yaml::from_str(
+            r#"
+difficulty: 1000
+rate_limit_key: auth_identity
+"#,
+        )
+        .unwrap();
+        assert_eq!(config.rate_limit_key, RateLimitKey::AuthIdentity);
+        let config: Config<Setting> = serde_yaml::from_str(
+            r#"
+difficulty: 1000
+rate_limit_key: tls_fingerprint
+fingerprint:
+  ja4_header: X-JA4
+"#,
+        )
+        .unwrap();
+        assert_eq!(config.rate_limit_key, RateLimitKey::TlsFingerprint);
+        assert_eq!(config.fingerprint.unwrap().ja4_header.as_deref(), Some("X-JA4"));
+    }
 }
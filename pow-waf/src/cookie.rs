@@ -0,0 +1,143 @@
+//! Passes in signed cookies, so a client that solved a challenge is let
+//! through for `pass_for` seconds without shared data, by every proxy
+//! sharing the `secret`.
+//!
+//! The cookie holds until when and up to which difficulty level the pass
+//! holds, and an HMAC-SHA256 of both together with the host and the rate
+//! limit subject of the request, `until.level.mac`:
+//!
+//! ```text
+//! Set-Cookie: __pow_pass=1718000600.100000.5d41402abc4b2a76b9719d911017c592...; Max-Age=600; Path=/; Secure; HttpOnly; SameSite=Lax
+//! ```
+//!
+//! A cookie copied to another client or host does not verify, and a request
+//! harder than the solved level is challenged again.
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::config::PassCookieSetting;
+
+/// The value of the cookie `name` in a `Cookie` header.
+pub fn get<'a>(header: &'a str, name: &str) -> Option<&'a str> {
+    header.split(';').find_map(|pair| {
+        let (key, value) = pair.trim().split_once('=')?;
+        (key == name).then(|| value.trim_matches('"'))
+    })
+}
+
+pub struct PassCookie {
+    setting: PassCookieSetting,
+}
+
+impl PassCookie {
+    pub fn new(setting: PassCookieSetting) -> Self {
+        Self { setting }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.setting.name
+    }
+
+    fn mac(&self, until: u64, level: u64, host: &str, subject: &str) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(self.setting.secret.as_bytes())
+            .expect("HMAC takes keys of any length");
+        // length-prefixed, a host and subject cannot sign for another split
+        let (until, level) = (until.to_string(), level.to_string());
+        for field in [until.as_str(), level.as_str(), host, subject] {
+            mac.update(&(field.len() as u64).to_be_bytes());
+            mac.update(field.as_bytes());
+        }
+        mac
+    }
+
+    /// The `Set-Cookie` value letting `subject` through on `host` up to
+    /// `level` for `pass_for` seconds from `now`.
+    pub fn issue(&self, level: u64, host: &str, subject: &str, now: u64) -> String {
+        let until = now + self.setting.pass_for;
+        let tag = self.mac(until, level, host, subject).finalize().into_bytes();
+        format!(
+            "{}={}.{}.{}; Max-Age={}; Path=/; Secure; HttpOnly; SameSite=Lax",
+            self.setting.name,
+            until,
+            level,
+            hex::encode(tag),
+            self.setting.pass_for
+        )
+    }
+
+    /// Whether `value` lets `subject` through on `host` at `level` at `now`.
+    pub fn verify(&self, value: &str, level: u64, host: &str, subject: &str, now: u64) -> bool {
+        let mut parts = value.splitn(3, '.');
+        let (Some(until), Some(passed), Some(tag)) = (parts.next(), parts.next(), parts.next())
+        else {
+            return false;
+        };
+        let (Ok(until), Ok(passed), Ok(tag)) = (until.parse(), passed.parse(), hex::decode(tag))
+        else {
+            return false;
+        };
+        until > now
+            && level <= passed
+            && self
+                .mac(until, passed, host, subject)
+                .verify_slice(&tag)
+                .is_ok()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn pass_cookie() -> PassCookie {
+        PassCookie::new(
+            serde_yaml::from_str(
+                r#"
+secret: s3cret
+pass_for: 600
+"#,
+            )
+            .unwrap(),
+        )
+    }
+
+    #[test]
+    fn parse() {
+        let header = "theme=dark; __pow_pass=\"1.2.ab\";session=x";
+        assert_eq!(get(header, "__pow_pass"), Some("1.2.ab"));
+        assert_eq!(get(header, "session"), Some("x"));
+        assert_eq!(get(header, "missing"), None);
+        assert_eq!(get("", "session"), None);
+    }
+
+    #[test]
+    fn issue_and_verify() {
+        let cookie = pass_cookie();
+        let set_cookie = cookie.issue(1000, "example.com", "10.0.0.1", 100);
+        assert!(set_cookie.ends_with("; Max-Age=600; Path=/; Secure; HttpOnly; SameSite=Lax"));
+        let value = get(&set_cookie, "__pow_pass").unwrap();
+        assert!(value.starts_with("700.1000."));
+
+        assert!(cookie.verify(value, 1000, "example.com", "10.0.0.1", 699));
+        assert!(cookie.verify(value, 10, "example.com", "10.0.0.1", 100));
+        assert!(!cookie.verify(value, 1001, "example.com", "10.0.0.1", 100));
+        assert!(!cookie.verify(value, 1000, "example.com", "10.0.0.1", 700));
+        assert!(!cookie.verify(value, 1000, "example.org", "10.0.0.1", 100));
+        assert!(!cookie.verify(value, 1000, "example.com", "10.0.0.2", 100));
+
+        let forged = value.replacen("700.1000", "700.9000", 1);
+        assert!(!cookie.verify(&forged, 1000, "example.com", "10.0.0.1", 100));
+        assert!(!cookie.verify("700.1000", 1000, "example.com", "10.0.0.1", 100));
+        assert!(!cookie.verify("700.1000.zz", 1000, "example.com", "10.0.0.1", 100));
+    }
+
+    #[test]
+    fn fields_do_not_run_together() {
+        let cookie = pass_cookie();
+        let set_cookie = cookie.issue(1000, "example.com|a", "b", 100);
+        let value = get(&set_cookie, "__pow_pass").unwrap();
+        assert!(cookie.verify(value, 1000, "example.com|a", "b", 100));
+        assert!(!cookie.verify(value, 1000, "example.com", "a|b", 100));
+    }
+}
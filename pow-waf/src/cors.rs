@@ -0,0 +1,193 @@
+//! CORS for APIs called from other origins, see [`CorsSetting`].
+//!
+//! Browsers preflight cross-origin requests carrying the `X-PoW-*` headers,
+//! the filter answers preflights of protected routes and challenge endpoints
+//! itself, allowing the solution headers. The responses of the filter, the
+//! challenge and rejections, get the allowed origin and expose `Retry-After`,
+//! the rate limit headers and the issue time of the challenge, so the client
+//! can read the challenge instead of failing on an opaque CORS error.
+//!
+//! Responses of the upstream keep its own CORS headers.
+
+use pow_runtime::response::Response;
+use pow_types::protocol;
+
+use crate::config::CorsSetting;
+use crate::tune;
+
+/// Response headers a client reads to solve or back off.
+const EXPOSE_HEADERS: [&str; 5] = [
+    "Retry-After",
+    "RateLimit-Limit",
+    "RateLimit-Remaining",
+    "RateLimit-Reset",
+    tune::ISSUED_HEADER,
+];
+
+pub struct Cors {
+    setting: CorsSetting,
+    allow_headers: String,
+    expose_headers: String,
+}
+
+impl Cors {
+    /// `quota_header` is exposed besides the rate limit headers.
+    pub fn new(setting: CorsSetting, quota_header: Option<&str>) -> Self {
+        let allow_headers = [
+            protocol::VERSION,
+            protocol::TIMESTAMP,
+            protocol::BASE,
+            protocol::NONCE,
+            tune::ISSUED_HEADER,
+        ]
+        .into_iter()
+        .chain(setting.allow_headers.iter().map(String::as_str))
+        .collect::<Vec<_>>()
+        .join(", ");
+        let expose_headers = EXPOSE_HEADERS
+            .into_iter()
+            .chain(quota_header)
+            .collect::<Vec<_>>()
+            .join(", ");
+        Self {
+            setting,
+            allow_headers,
+            expose_headers,
+        }
+    }
+
+    /// The `Access-Control-Allow-Origin` for `origin`, `None` if not allowed.
+    fn allow_origin<'a>(&self, origin: &'a str) -> Option<&'a str> {
+        let any = self.setting.allow_origins.iter().any(|o| o == "*");
+        if any && !self.setting.allow_credentials {
+            return Some("*");
+        }
+        let listed = self.setting.allow_origins.iter().any(|o| o == origin);
+        (any || listed).then_some(origin)
+    }
+
+    fn common(&self, origin: &str) -> Vec<(String, String)> {
+        let mut headers = vec![("Vary".to_string(), "Origin".to_string())];
+        if let Some(allowed) = self.allow_origin(origin) {
+            headers.push((
+                "Access-Control-Allow-Origin".to_string(),
+                allowed.to_string(),
+            ));
+            if self.setting.allow_credentials {
+                headers.push((
+                    "Access-Control-Allow-Credentials".to_string(),
+                    "true".to_string(),
+                ));
+            }
+        }
+        headers
+    }
+
+    /// Headers of a response of the filter to a request from `origin`.
+    pub fn headers(&self, origin: &str) -> Vec<(String, String)> {
+        let mut headers = self.common(origin);
+        if self.allow_origin(origin).is_some() {
+            headers.push((
+                "Access-Control-Expose-Headers".to_string(),
+                self.expose_headers.clone(),
+            ));
+        }
+        headers
+    }
+
+    /// The answer to a preflight of `method` from `origin`, without CORS
+    /// headers when the origin is not allowed.
+    pub fn preflight(&self, origin: &str, method: &str) -> Response {
+        let mut response = Response::builder().status(204).build();
+        response.headers = self.common(origin);
+        if self.allow_origin(origin).is_some() {
+            response.headers.extend([
+                (
+                    "Access-Control-Allow-Methods".to_string(),
+                    method.to_string(),
+                ),
+                (
+                    "Access-Control-Allow-Headers".to_string(),
+                    self.allow_headers.clone(),
+                ),
+                (
+                    "Access-Control-Max-Age".to_string(),
+                    self.setting.max_age.to_string(),
+                ),
+            ]);
+        }
+        response
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn cors(origins: &[&str], allow_credentials: bool) -> Cors {
+        let setting = CorsSetting {
+            allow_origins: origins.iter().map(|o| o.to_string()).collect(),
+            allow_credentials,
+            allow_headers: vec!["Authorization".to_string()],
+            max_age: 600,
+        };
+        Cors::new(setting, Some("X-Quota-Remaining"))
+    }
+
+    fn header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+        headers
+            .iter()
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    #[test]
+    fn origins() {
+        let app = "https://app.example.com";
+        let listed = cors(&[app], false);
+        let headers = listed.headers(app);
+        assert_eq!(header(&headers, "Access-Control-Allow-Origin"), Some(app));
+        assert_eq!(
+            header(&headers, "Access-Control-Expose-Headers"),
+            Some(concat!(
+                "Retry-After, RateLimit-Limit, RateLimit-Remaining, RateLimit-Reset, ",
+                "X-PoW-Issued, X-Quota-Remaining"
+            ))
+        );
+        let headers = listed.headers("https://evil.example");
+        assert_eq!(header(&headers, "Access-Control-Allow-Origin"), None);
+        assert_eq!(header(&headers, "Vary"), Some("Origin"));
+
+        let headers = cors(&["*"], false).headers(app);
+        assert_eq!(header(&headers, "Access-Control-Allow-Origin"), Some("*"));
+        let headers = cors(&["*"], true).headers(app);
+        assert_eq!(header(&headers, "Access-Control-Allow-Origin"), Some(app));
+        assert_eq!(
+            header(&headers, "Access-Control-Allow-Credentials"),
+            Some("true")
+        );
+    }
+
+    #[test]
+    fn preflight() {
+        let app = "https://app.example.com";
+        let response = cors(&[app], false).preflight(app, "POST");
+        assert_eq!(response.code, 204);
+        assert_eq!(
+            header(&response.headers, "Access-Control-Allow-Headers"),
+            Some(concat!(
+                "X-PoW-Version, X-PoW-Timestamp, X-PoW-Base, X-PoW-Nonce, X-PoW-Issued, ",
+                "Authorization"
+            ))
+        );
+        assert_eq!(
+            header(&response.headers, "Access-Control-Allow-Methods"),
+            Some("POST")
+        );
+        let response = cors(&[app], false).preflight("https://evil.example", "POST");
+        assert_eq!(
+            header(&response.headers, "Access-Control-Allow-Headers"),
+            None
+        );
+    }
+}
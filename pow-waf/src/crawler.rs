@@ -0,0 +1,201 @@
+//! Verified crawlers skip PoW, since search engine bots cannot solve it.
+//!
+//! A request whose `User-Agent` matches a configured crawler is verified by
+//! its address the way the search engines document it: the reverse DNS name
+//! of the address must be under one of the crawler's domains, and that name
+//! must resolve back to the address. Lookups go through a DNS over HTTPS
+//! resolver with the JSON API, e.g. `dns.google` or `cloudflare-dns.com`.
+//!
+//! The verdict on an address is kept in shared data for `verdict_for`
+//! seconds, so impostors are challenged as usual without a lookup on every
+//! request. Verdicts are counted in `pow_waf.crawler.<name>.verified` and
+//! `.impostor`.
+
+use std::net::IpAddr;
+use std::time::Duration;
+
+use pow_runtime::http::{self, Client};
+use pow_runtime::kv_store::{Error, ExpiringKVStore};
+use regex::Regex;
+use serde::Deserialize;
+
+use crate::config::{CrawlerSetting, CrawlersSetting};
+
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_AAAA: u16 = 28;
+
+#[derive(Debug, Deserialize)]
+struct Answer {
+    #[serde(rename = "type")]
+    kind: u16,
+    data: String,
+}
+
+/// The answer of a DNS over HTTPS resolver.
+#[derive(Debug, Deserialize)]
+struct Resolution {
+    #[serde(rename = "Status")]
+    status: u32,
+    #[serde(rename = "Answer", default)]
+    answer: Vec<Answer>,
+}
+
+impl Resolution {
+    /// The records of type `kind`, empty unless the lookup succeeded.
+    fn records(&self, kind: u16) -> impl Iterator<Item = &str> {
+        self.answer
+            .iter()
+            .filter(move |answer| self.status == 0 && answer.kind == kind)
+            .map(|answer| answer.data.trim_end_matches('.'))
+    }
+}
+
+/// The name of the PTR record of `ip`.
+fn reverse_name(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, c, d] = ip.octets();
+            format!("{}.{}.{}.{}.in-addr.arpa", d, c, b, a)
+        }
+        IpAddr::V6(ip) => {
+            let mut name = String::new();
+            for byte in ip.octets().iter().rev() {
+                name.push_str(&format!("{:x}.{:x}.", byte & 0xf, byte >> 4));
+            }
+            name + "ip6.arpa"
+        }
+    }
+}
+
+/// Whether `host` is one of `domains` or under one of them.
+fn under(host: &str, domains: &[String]) -> bool {
+    let host = host.to_ascii_lowercase();
+    domains.iter().any(|domain| {
+        let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+        host == domain
+            || host
+                .strip_suffix(&domain)
+                .is_some_and(|rest| rest.ends_with('.'))
+    })
+}
+
+pub struct Crawlers {
+    setting: CrawlersSetting,
+    user_agents: Vec<Regex>,
+    client: Client,
+    /// Whether an address is the crawler it claims, by `<name>:<address>`.
+    verdicts: ExpiringKVStore<bool>,
+}
+
+impl Crawlers {
+    pub fn new(context_id: u32, setting: CrawlersSetting) -> Result<Self, regex::Error> {
+        let user_agents = setting
+            .crawlers
+            .iter()
+            .map(|crawler| Regex::new(&crawler.user_agent))
+            .collect::<Result<_, _>>()?;
+        Ok(Self {
+            user_agents,
+            client: Client::new(&setting.upstream, &setting.authority),
+            verdicts: ExpiringKVStore::new(context_id, "crawler"),
+            setting,
+        })
+    }
+
+    /// The crawler `user_agent` claims to be, if any.
+    pub fn claimed(&self, user_agent: &str) -> Option<&CrawlerSetting> {
+        self.user_agents
+            .iter()
+            .position(|regex| regex.is_match(user_agent))
+            .map(|i| &self.setting.crawlers[i])
+    }
+
+    /// The kept verdict on `ip` claiming to be `crawler`.
+    pub fn verdict(&self, crawler: &CrawlerSetting, ip: IpAddr) -> Result<Option<bool>, Error> {
+        self.verdicts.get(&format!("{}:{}", crawler.name, ip))
+    }
+
+    pub fn remember(
+        &self,
+        crawler: &CrawlerSetting,
+        ip: IpAddr,
+        verified: bool,
+    ) -> Result<(), Error> {
+        let key = format!("{}:{}", crawler.name, ip);
+        self.verdicts.put(
+            &key,
+            &verified,
+            Duration::from_secs(self.setting.verdict_for),
+        )
+    }
+
+    /// Look up whether `ip` is `crawler`, by its reverse DNS name confirmed
+    /// by a forward lookup.
+    pub async fn verify(&self, crawler: &CrawlerSetting, ip: IpAddr) -> Result<bool, http::Error> {
+        let reverse = self.resolve(&reverse_name(ip), "PTR").await?;
+        let Some(host) = reverse
+            .records(TYPE_PTR)
+            .find(|host| under(host, &crawler.domains))
+        else {
+            return Ok(false);
+        };
+        let (kind, name) = match ip {
+            IpAddr::V4(_) => (TYPE_A, "A"),
+            IpAddr::V6(_) => (TYPE_AAAA, "AAAA"),
+        };
+        let forward = self.resolve(host, name).await?;
+        let confirmed = forward
+            .records(kind)
+            .any(|address| address.parse::<IpAddr>() == Ok(ip));
+        Ok(confirmed)
+    }
+
+    async fn resolve(&self, name: &str, kind: &str) -> Result<Resolution, http::Error> {
+        let path = format!("{}?name={}&type={}", self.setting.path, name, kind);
+        self.client.get_json(&path).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn reverse_names() {
+        assert_eq!(
+            reverse_name("66.249.66.1".parse().unwrap()),
+            "1.66.249.66.in-addr.arpa"
+        );
+        let name = reverse_name("2001:db8::567:89ab".parse().unwrap());
+        assert!(name.starts_with("b.a.9.8.7.6.5.0.0.0.0.0"));
+        assert!(name.ends_with("0.0.0.0.8.b.d.0.1.0.0.2.ip6.arpa"));
+    }
+
+    #[test]
+    fn domains() {
+        let domains = vec!["googlebot.com".to_string(), "google.com.".to_string()];
+        assert!(under("crawl-66-249-66-1.googlebot.com", &domains));
+        assert!(under("Rate-Limited-Proxy.Google.com", &domains));
+        assert!(under("googlebot.com", &domains));
+        assert!(!under("evilgooglebot.com", &domains));
+        assert!(!under("googlebot.com.evil.net", &domains));
+    }
+
+    #[test]
+    fn resolution() {
+        let resolution: Resolution = serde_json::from_str(
+            r#"{"Status": 0, "Answer": [
+                {"name": "1.66.249.66.in-addr.arpa.", "type": 12, "TTL": 300,
+                 "data": "crawl-66-249-66-1.googlebot.com."},
+                {"name": "x", "type": 5, "TTL": 300, "data": "alias."}
+            ]}"#,
+        )
+        .unwrap();
+        let hosts: Vec<_> = resolution.records(TYPE_PTR).collect();
+        assert_eq!(hosts, vec!["crawl-66-249-66-1.googlebot.com"]);
+
+        let missing: Resolution = serde_json::from_str(r#"{"Status": 3}"#).unwrap();
+        assert_eq!(missing.records(TYPE_A).count(), 0);
+    }
+}
@@ -0,0 +1,60 @@
+//! Challenge endpoints.
+//!
+//! Clients that know they will be challenged can fetch the challenge of a
+//! path up front, e.g. `GET /.well-known/pow?path=/api/orders`, instead of
+//! sending the request twice. The target is checked as a request of the same
+//! client to the same host would be, without counting it:
+//!
+//! ```json
+//! {
+//!   "target": "/api/orders",
+//!   "current": "00000000000000000002...",
+//!   "difficulty": "0000a7c5ac471b4784...",
+//!   "level": 100000,
+//!   "version": 2,
+//!   "issued_at": 1718000000,
+//!   "expires_at": 1718000060
+//! }
+//! ```
+//!
+//! `difficulty` is absent when the target requires no solution. Solutions
+//! must be submitted before `expires_at`, and with the `current` hash while
+//! it is among the recent blocks.
+
+use pow_runtime::query::QueryParams;
+use pow_types::bytearray32::ByteArray32;
+use pow_types::protocol::Version;
+use serde::Serialize;
+
+#[derive(Debug, Serialize)]
+pub struct Challenge {
+    pub target: String,
+    pub current: ByteArray32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub difficulty: Option<ByteArray32>,
+    pub level: u64,
+    pub version: Version,
+    pub issued_at: u64,
+    pub expires_at: u64,
+}
+
+/// The decoded value of `param` in the query string of `path`.
+pub fn target(path: &str, param: &str) -> Option<String> {
+    QueryParams::parse(path).get(param).map(str::to_string)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn target_param() {
+        assert_eq!(
+            target("/.well-known/pow?v=2&path=%2Fapi%2Forders%3Fid%3D1", "path"),
+            Some("/api/orders?id=1".to_string())
+        );
+        assert_eq!(target("/.well-known/pow?paths=/x", "path"), None);
+        assert_eq!(target("/.well-known/pow", "path"), None);
+        assert_eq!(target("/pow?path", "path"), Some(String::new()));
+    }
+}
@@ -0,0 +1,86 @@
+//! Challenge experiments, see [`Setting::variants`].
+//!
+//! A route with variants assigns every client to one of them by a hash of its
+//! address and the route, in proportion to their weights. Like the
+//! [`rollout`](crate::rollout) the assignment is stable, but independent of
+//! it and of the experiments on other routes.
+//!
+//! The variant is logged with the decision on the request and counted in
+//! `pow_waf.variant.<name>.challenged`, `.solved` and `.failed`, a high
+//! share of challenged clients that never solve points at abandonment.
+//!
+//! [`Setting::variants`]: crate::config::Setting::variants
+
+use std::net::IpAddr;
+
+use pow_runtime::metrics;
+
+use crate::config::ChallengeVariant;
+use crate::rollout;
+
+/// The variant of `route` that `ip` is assigned to, `None` without variants.
+pub fn assign<'a>(
+    variants: &'a [ChallengeVariant],
+    ip: IpAddr,
+    route: &str,
+) -> Option<&'a ChallengeVariant> {
+    let total: u64 = variants.iter().map(|v| u64::from(v.weight)).sum();
+    if total == 0 {
+        return None;
+    }
+    let mut point = rollout::hash(ip, route) % total;
+    variants.iter().find(|variant| {
+        let weight = u64::from(variant.weight);
+        if point < weight {
+            return true;
+        }
+        point -= weight;
+        false
+    })
+}
+
+/// Count `outcome` of a request of `variant`, if any.
+pub fn count(variant: Option<&ChallengeVariant>, outcome: &str) {
+    if let Some(variant) = variant {
+        metrics::increment_counter(&format!("pow_waf.variant.{}.{}", variant.name, outcome), 1);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn variant(name: &str, weight: u32) -> ChallengeVariant {
+        ChallengeVariant {
+            name: name.to_string(),
+            weight,
+            difficulty: None,
+            captcha: false,
+        }
+    }
+
+    #[test]
+    fn weighted_and_stable() {
+        let variants = vec![variant("control", 3), variant("hard", 1), variant("off", 0)];
+        let ips: Vec<IpAddr> = (0..4000u32)
+            .map(|i| IpAddr::from((0x0a00_0000 + i * 7).to_be_bytes()))
+            .collect();
+        let share = |name: &str| {
+            ips.iter()
+                .filter(|ip| assign(&variants, **ip, "example.com/api").unwrap().name == name)
+                .count() as f64
+                / ips.len() as f64
+        };
+        assert!((0.70..0.80).contains(&share("control")));
+        assert!((0.20..0.30).contains(&share("hard")));
+        assert_eq!(share("off"), 0.0);
+
+        let ip = ips[0];
+        assert_eq!(
+            assign(&variants, ip, "example.com/api"),
+            assign(&variants, ip, "example.com/api")
+        );
+        assert_eq!(assign(&[], ip, "example.com/api"), None);
+        assert_eq!(assign(&[variant("off", 0)], ip, "example.com/api"), None);
+    }
+}
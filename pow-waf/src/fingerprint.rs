@@ -0,0 +1,82 @@
+//! TLS fingerprints of clients, for rate limit keys and risk scores.
+//!
+//! JA3 and JA4 summarize the ClientHello of a connection: TLS versions,
+//! cipher suites, extensions and ALPN. Clients of one bot farm share a TLS
+//! stack, and so a fingerprint, whatever user agent they claim. The filter
+//! sees no handshake, it reads the fingerprints from a header set by a
+//! filter in front of it or from a host property, see [`FingerprintSource`].
+
+use pow_runtime::Ctx;
+use proxy_wasm::types::Status;
+
+use crate::config::FingerprintSource;
+use crate::geo;
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Fingerprint {
+    /// The MD5 of the JA3 string, in hex.
+    pub ja3: Option<String>,
+    /// E.g. `t13d1516h2_8daaf6152771_02713d6af862`.
+    pub ja4: Option<String>,
+}
+
+impl Fingerprint {
+    pub fn lookup(ctx: &Ctx, source: &FingerprintSource) -> Result<Self, Status> {
+        let normalize = |value: Option<String>| {
+            value
+                .map(|v| v.trim().to_ascii_lowercase())
+                .filter(|v| !v.is_empty())
+        };
+        Ok(Fingerprint {
+            ja3: normalize(geo::read(ctx, &source.ja3_header, &source.ja3_property)?),
+            ja4: normalize(geo::read(ctx, &source.ja4_header, &source.ja4_property)?),
+        })
+    }
+
+    /// Names the fingerprint in rate limit subjects, JA4 when known as it
+    /// is stable across the extension order browsers randomize.
+    pub fn id(&self) -> Option<String> {
+        match (&self.ja4, &self.ja3) {
+            (Some(ja4), _) => Some(format!("ja4={}", ja4)),
+            (None, Some(ja3)) => Some(format!("ja3={}", ja3)),
+            (None, None) => None,
+        }
+    }
+
+    /// Whether the fingerprint is one of `ja3` or `ja4`, case insensitive.
+    pub fn within(&self, ja3: &[String], ja4: &[String]) -> bool {
+        let within = |value: &Option<String>, list: &[String]| {
+            value
+                .as_ref()
+                .is_some_and(|value| list.iter().any(|v| v.eq_ignore_ascii_case(value)))
+        };
+        within(&self.ja3, ja3) || within(&self.ja4, ja4)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn id_and_within() {
+        let ja3 = "e7d705a3286e19ea42f587b344ee6865".to_string();
+        let ja4 = "t13d1516h2_8daaf6152771_02713d6af862".to_string();
+        let both = Fingerprint {
+            ja3: Some(ja3.clone()),
+            ja4: Some(ja4.clone()),
+        };
+        assert_eq!(both.id(), Some(format!("ja4={}", ja4)));
+        let only_ja3 = Fingerprint {
+            ja3: Some(ja3.clone()),
+            ja4: None,
+        };
+        assert_eq!(only_ja3.id(), Some(format!("ja3={}", ja3)));
+        assert_eq!(Fingerprint::default().id(), None);
+
+        assert!(both.within(&[], &[ja4.to_uppercase()]));
+        assert!(only_ja3.within(&[ja3], &[]));
+        assert!(!only_ja3.within(&[], &[ja4]));
+        assert!(!Fingerprint::default().within(&[], &[]));
+    }
+}
@@ -0,0 +1,120 @@
+//! Country and ASN of the client, for the `regions` of a route.
+//!
+//! The filter does no lookups itself, it relies on a geo filter in front of
+//! it (or the host) to provide them, see [`GeoSource`].
+
+use pow_runtime::Ctx;
+use proxy_wasm::types::Status;
+
+use crate::config::{GeoSource, RegionAction, RegionPolicy};
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Geo {
+    pub country: Option<String>,
+    pub asn: Option<u32>,
+}
+
+impl Geo {
+    pub fn lookup(ctx: &Ctx, source: &GeoSource) -> Result<Self, Status> {
+        let country = read(ctx, &source.country_header, &source.country_property)?;
+        let asn = read(ctx, &source.asn_header, &source.asn_property)?;
+        Ok(Geo {
+            country: country.map(|c| c.trim().to_ascii_uppercase()),
+            asn: asn.and_then(|a| parse_asn(&a)),
+        })
+    }
+}
+
+/// A header, or the property at `property` when the header is absent.
+pub(crate) fn read(
+    ctx: &Ctx,
+    header: &Option<String>,
+    property: &Option<Vec<String>>,
+) -> Result<Option<String>, Status> {
+    if let Some(header) = header {
+        if let Some(value) = ctx.get_http_request_header(header)? {
+            return Ok(Some(value));
+        }
+    }
+    let Some(path) = property else {
+        return Ok(None);
+    };
+    let path: Vec<&str> = path.iter().map(String::as_str).collect();
+    Ok(ctx.property(&path)?.string())
+}
+
+/// Accepts both `13335` and `AS13335`.
+fn parse_asn(value: &str) -> Option<u32> {
+    let value = value.trim();
+    let digits = value
+        .strip_prefix("AS")
+        .or_else(|| value.strip_prefix("as"))
+        .unwrap_or(value);
+    digits.parse().ok()
+}
+
+impl Geo {
+    /// Whether the client is in one of `countries` or `asns`.
+    pub fn within(&self, countries: &[String], asns: &[u32]) -> bool {
+        let country = self.country.as_ref().is_some_and(|country| {
+            countries.iter().any(|c| c.eq_ignore_ascii_case(country))
+        });
+        let asn = self.asn.is_some_and(|asn| asns.contains(&asn));
+        country || asn
+    }
+}
+
+impl RegionPolicy {
+    pub fn matches(&self, geo: &Geo) -> bool {
+        geo.within(&self.countries, &self.asns)
+    }
+}
+
+/// The action of the first policy matching `geo`.
+pub fn region_action<'a>(policies: &'a [RegionPolicy], geo: &Geo) -> Option<&'a RegionAction> {
+    policies
+        .iter()
+        .find(|policy| policy.matches(geo))
+        .map(|policy| &policy.action)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn first_matching_policy_wins() {
+        let policies: Vec<RegionPolicy> = serde_yaml::from_str(
+            r#"
+- countries: [kp]
+  block: null
+- countries: [CN, RU]
+  asns: [4134]
+  min_difficulty: 50000
+"#,
+        )
+        .expect("failed to parse policies");
+
+        let geo = |country: Option<&str>, asn: Option<u32>| Geo {
+            country: country.map(str::to_string),
+            asn,
+        };
+        assert_eq!(
+            region_action(&policies, &geo(Some("KP"), None)),
+            Some(&RegionAction::Block)
+        );
+        assert_eq!(
+            region_action(&policies, &geo(Some("US"), Some(4134))),
+            Some(&RegionAction::MinDifficulty(50000))
+        );
+        assert_eq!(region_action(&policies, &geo(Some("US"), Some(1))), None);
+        assert_eq!(region_action(&policies, &Geo::default()), None);
+    }
+
+    #[test]
+    fn asn_formats() {
+        assert_eq!(parse_asn("13335"), Some(13335));
+        assert_eq!(parse_asn("AS13335"), Some(13335));
+        assert_eq!(parse_asn("unknown"), None);
+    }
+}
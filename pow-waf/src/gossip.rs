@@ -0,0 +1,245 @@
+//! Approximate coordination between proxies in different regions.
+//!
+//! Every `interval` one worker posts a digest of the local deny entries, the
+//! `hot_keys` busiest rate limit counters and the attack state to the relay,
+//! which answers with the digest merged from all nodes. Remote bans are added
+//! to the local access list unless the block already has a local entry or was
+//! unbanned since, remote unbans remove the local bans set before them, a
+//! remote hot key raises the local counter to its count, and a remote attack
+//! extends the local mitigation. Counters are raised rather than added to, so
+//! the merged digest may carry the counts of this node back without counting
+//! them twice, and a client spread over the regions is limited by the busiest
+//! of them. The relay is any HTTP service accepting and returning [`Digest`]
+//! as JSON.
+
+use std::time::Duration;
+
+use pow_runtime::filter_state::WeakState;
+use pow_runtime::kv_store::{Error, KVStore};
+use pow_runtime::timeout::sleep;
+use pow_runtime::{http_call, spawn_local};
+use proxy_wasm::types::Status;
+use serde::{Deserialize, Serialize};
+
+use crate::access_list::{Entry, Rule, Tombstone};
+use crate::config::GossipSetting;
+use crate::{now, Inner};
+
+#[derive(Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Digest {
+    pub node: String,
+    #[serde(default)]
+    pub bans: Vec<Entry>,
+    /// Bans removed through the admin API, see [`Tombstone`].
+    #[serde(default)]
+    pub unbans: Vec<Tombstone>,
+    /// The busiest rate limit counters, most counted first.
+    #[serde(default)]
+    pub hot: Vec<HotKey>,
+    /// Unix timestamp the attack mitigation ends at, 0 when idle.
+    #[serde(default)]
+    pub attack_until: u64,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HotKey {
+    pub key: String,
+    pub count: u64,
+}
+
+/// The `n` most counted of `counters`, ties broken by key.
+fn hottest(counters: impl IntoIterator<Item = (String, u64)>, n: usize) -> Vec<HotKey> {
+    let mut hot: Vec<HotKey> = counters
+        .into_iter()
+        .filter(|(_, count)| *count > 0)
+        .map(|(key, count)| HotKey { key, count })
+        .collect();
+    hot.sort_unstable_by(|a, b| b.count.cmp(&a.count).then_with(|| a.key.cmp(&b.key)));
+    hot.truncate(n);
+    hot
+}
+
+/// Start exchanging digests until the epoch behind `plugin` is torn down.
+pub(crate) fn spawn(context_id: u32, plugin: WeakState<Inner>, setting: GossipSetting) {
+    let turn: KVStore<u64> = KVStore::new(context_id, "gossip:last");
+    spawn_local(async move {
+        loop {
+            sleep(Duration::from_secs(1)).await;
+            let Some(plugin) = plugin.upgrade() else {
+                log::info!("exit gossip loop");
+                break;
+            };
+            if !take_turn(&turn, setting.interval) {
+                continue;
+            }
+            if let Err(e) = exchange(&plugin, &setting).await {
+                log::warn!(
+                    "failed to exchange gossip with {}: {:?}",
+                    setting.upstream,
+                    e
+                );
+            }
+        }
+    });
+}
+
+/// Only one worker takes a turn per interval.
+pub(crate) fn take_turn(turn: &KVStore<u64>, interval: u64) -> bool {
+    let now = now();
+    let mut mine = false;
+    let ret = turn.update("", |last| {
+        let last = last.unwrap_or(0);
+        mine = last + interval <= now;
+        Ok::<_, Error>(if mine { now } else { last })
+    });
+    if let Err(e) = ret {
+        log::warn!("failed to take turn: {}", e);
+        return false;
+    }
+    mine
+}
+
+fn hot_keys(plugin: &Inner, n: usize) -> Vec<HotKey> {
+    if n == 0 {
+        return vec![];
+    }
+    let counter_bucket = &plugin.counter_bucket;
+    let keys = counter_bucket
+        .list_keys("")
+        .inspect_err(|e| log::warn!("failed to list rate limit counters: {}", e))
+        .unwrap_or_default();
+    let counters = keys.into_iter().filter_map(|key| {
+        let count = counter_bucket
+            .get(&key)
+            .inspect_err(|e| log::warn!("failed to get counter {}: {}", key, e))
+            .ok()?;
+        Some((key, count))
+    });
+    hottest(counters, n)
+}
+
+fn local_digest(plugin: &Inner, setting: &GossipSetting) -> Digest {
+    let bans = plugin
+        .access_list
+        .entries()
+        .inspect_err(|e| log::warn!("failed to read access list: {}", e))
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|e| e.rule == Rule::Deny)
+        .collect();
+    let unbans = plugin
+        .access_list
+        .tombstones()
+        .inspect_err(|e| log::warn!("failed to read access list: {}", e))
+        .unwrap_or_default();
+    let attack_until = plugin.attack.as_ref().map_or(0, |a| a.active_until());
+    Digest {
+        node: setting.node.clone(),
+        bans,
+        unbans,
+        hot: hot_keys(plugin, setting.hot_keys),
+        attack_until,
+    }
+}
+
+async fn exchange(plugin: &Inner, setting: &GossipSetting) -> Result<(), Status> {
+    let body =
+        serde_json::to_vec(&local_digest(plugin, setting)).map_err(|_| Status::InternalFailure)?;
+    let response = http_call(
+        &setting.upstream,
+        vec![
+            (":method", "POST"),
+            (":path", &setting.path),
+            (":authority", &setting.authority),
+            ("content-type", "application/json"),
+        ],
+        Some(&body),
+        vec![],
+        Duration::from_secs(5),
+    )?
+    .await
+    .map_err(|_| Status::InternalFailure)?;
+
+    let status = response
+        .headers
+        .iter()
+        .find(|(k, _)| k == ":status")
+        .map(|(_, v)| v.as_str());
+    if status != Some("200") {
+        log::warn!("relay answered with status {:?}", status);
+        return Err(Status::InternalFailure);
+    }
+    let merged: Digest = response
+        .body
+        .as_deref()
+        .map(serde_json::from_slice)
+        .transpose()
+        .map_err(|e| {
+            log::warn!("invalid gossip digest: {}", e);
+            Status::ParseFailure
+        })?
+        .unwrap_or_default();
+
+    let bans = merged
+        .bans
+        .into_iter()
+        .filter(|e| e.rule == Rule::Deny)
+        .collect();
+    if let Err(e) = plugin.access_list.merge(bans, merged.unbans) {
+        log::warn!("failed to merge remote bans: {}", e);
+    }
+    for hot in merged.hot.iter().take(setting.hot_keys) {
+        if let Err(e) = plugin.counter_bucket.raise(&hot.key, hot.count) {
+            log::warn!("failed to adopt remote counter {}: {}", hot.key, e);
+        }
+    }
+    if let Some(attack) = &plugin.attack {
+        if let Err(e) = attack.adopt(merged.attack_until) {
+            log::warn!("failed to adopt remote attack state: {}", e);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{hottest, Digest, HotKey};
+
+    #[test]
+    fn digest_json() {
+        let digest: Digest = serde_json::from_str(
+            r#"{"node":"eu","bans":[{"cidr":"203.0.113.0/24","rule":"deny","expires_at":1700000000}]}"#,
+        )
+        .expect("failed to parse digest");
+        assert_eq!(digest.attack_until, 0);
+        assert!(digest.hot.is_empty());
+        assert_eq!(digest.bans[0].cidr, "203.0.113.0/24".parse().unwrap());
+        assert_eq!(digest.bans[0].expires_at, Some(1_700_000_000));
+    }
+
+    #[test]
+    fn hottest_counters() {
+        let counters = [("a", 3), ("b", 9), ("c", 0), ("d", 3), ("e", 1)]
+            .map(|(key, count)| (key.to_string(), count));
+        let hot = |key: &str, count| HotKey {
+            key: key.to_string(),
+            count,
+        };
+        assert_eq!(
+            hottest(counters.clone(), 3),
+            vec![hot("b", 9), hot("a", 3), hot("d", 3)]
+        );
+        // idle counters are left out
+        assert_eq!(hottest(counters.clone(), 10).len(), 4);
+        assert!(hottest(counters, 0).is_empty());
+
+        let digest: Digest = serde_json::from_str(
+            r#"{"node":"eu","hot":[{"key":"example.com/login:203.0.113.7:42","count":120}]}"#,
+        )
+        .expect("failed to parse digest");
+        assert_eq!(
+            digest.hot,
+            vec![hot("example.com/login:203.0.113.7:42", 120)]
+        );
+    }
+}
@@ -0,0 +1,236 @@
+//! Load shedding tied to the health of the upstream.
+//!
+//! Every `interval` one worker checks the upstream, either by polling its
+//! health endpoint or by reading a number the host keeps about it, and
+//! records the outcome in a circuit breaker kept in shared data. After
+//! `trip_after` saturated checks in a row the breaker opens: every worker
+//! raises the difficulty floor of matched routes and shrinks their rate limit
+//! buckets, as under attack, until `recover_after` healthy checks in a row
+//! close it again. The state is reported as the `pow_waf.upstream.saturated`
+//! gauge, 1 while shedding.
+
+use std::cell::Cell;
+use std::time::Duration;
+
+use pow_runtime::filter_state::WeakState;
+use pow_runtime::host;
+use pow_runtime::http::Client;
+use pow_runtime::kv_store::{Error, KVStore};
+use pow_runtime::metrics;
+use pow_runtime::spawn_local;
+use pow_runtime::timeout::sleep;
+use serde::{Deserialize, Serialize};
+
+use crate::config::{HealthSetting, HealthSource};
+use crate::gossip::take_turn;
+use crate::Inner;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+struct Breaker {
+    /// Shedding load.
+    open: bool,
+    /// Consecutive checks disagreeing with the state.
+    streak: u32,
+}
+
+impl Breaker {
+    /// Record a check, returns whether the breaker flipped.
+    fn record(&mut self, saturated: bool, setting: &HealthSetting) -> bool {
+        if saturated == self.open {
+            self.streak = 0;
+            return false;
+        }
+        self.streak += 1;
+        let needed = match self.open {
+            true => setting.recover_after,
+            false => setting.trip_after,
+        };
+        if self.streak < needed.max(1) {
+            return false;
+        }
+        self.open = saturated;
+        self.streak = 0;
+        true
+    }
+}
+
+/// A number read from a property, encoded as an integer or a decimal string.
+fn load(bytes: &[u8]) -> Option<i64> {
+    if let Some(load) = std::str::from_utf8(bytes)
+        .ok()
+        .and_then(|s| s.trim().parse().ok())
+    {
+        return Some(load);
+    }
+    Some(i64::from_le_bytes(bytes.try_into().ok()?))
+}
+
+pub struct UpstreamHealth {
+    setting: HealthSetting,
+    client: Option<Client>,
+    breaker: KVStore<Breaker>,
+    turn: KVStore<u64>,
+    /// Whether this worker sheds load, refreshed every second.
+    open: Cell<bool>,
+}
+
+impl UpstreamHealth {
+    pub fn new(context_id: u32, setting: HealthSetting) -> Self {
+        let client = match &setting.source {
+            HealthSource::Endpoint {
+                upstream,
+                authority,
+                timeout,
+                ..
+            } => Some(Client::new(upstream, authority).timeout(Duration::from_millis(*timeout))),
+            HealthSource::Property { .. } => None,
+        };
+        Self {
+            setting,
+            client,
+            breaker: KVStore::new(context_id, "health:breaker"),
+            turn: KVStore::new(context_id, "health:last"),
+            open: Cell::new(false),
+        }
+    }
+
+    /// The difficulty floor and bucket shrink factor while shedding load.
+    pub fn shedding(&self) -> Option<(u64, u64)> {
+        self.open
+            .get()
+            .then_some((self.setting.difficulty_floor, self.setting.bucket_shrink))
+    }
+
+    async fn saturated(&self) -> bool {
+        match (&self.setting.source, &self.client) {
+            (HealthSource::Endpoint { path, .. }, Some(client)) => match client.get(path).await {
+                Ok(_) => false,
+                Err(e) => {
+                    log::info!("upstream health check failed: {}", e);
+                    true
+                }
+            },
+            (HealthSource::Property { path, threshold }, _) => {
+                let path = path.iter().map(String::as_str).collect();
+                match host::get_property(path) {
+                    Ok(value) => value
+                        .as_deref()
+                        .and_then(load)
+                        .is_some_and(|load| load >= *threshold),
+                    Err(e) => {
+                        log::warn!("failed to read upstream load: {:?}", e);
+                        false
+                    }
+                }
+            }
+            (HealthSource::Endpoint { .. }, None) => false,
+        }
+    }
+
+    /// Check the upstream on this worker's turn, then pick up the state.
+    async fn tick(&self) -> Result<(), Error> {
+        if take_turn(&self.turn, self.setting.interval) {
+            let saturated = self.saturated().await;
+            let mut flipped = false;
+            let breaker = self.breaker.update("", |breaker| {
+                let mut breaker = breaker.unwrap_or_default();
+                flipped = breaker.record(saturated, &self.setting);
+                Ok::<_, Error>(breaker)
+            })?;
+            match (flipped, breaker.open) {
+                (true, true) => log::warn!(
+                    "upstream saturated, raise difficulty floor to {}",
+                    self.setting.difficulty_floor
+                ),
+                (true, false) => log::info!("upstream recovered, stop shedding load"),
+                (false, _) => {}
+            }
+        }
+        let open = self.breaker.get("")?.is_some_and(|breaker| breaker.open);
+        if self.open.replace(open) != open {
+            metrics::record_gauge("pow_waf.upstream.saturated", u64::from(open));
+        }
+        Ok(())
+    }
+}
+
+/// Check the upstream until the epoch behind `plugin` is torn down.
+pub(crate) fn spawn(plugin: WeakState<Inner>) {
+    spawn_local(async move {
+        loop {
+            sleep(Duration::from_secs(1)).await;
+            let Some(plugin) = plugin.upgrade() else {
+                log::info!("exit health loop");
+                break;
+            };
+            let Some(health) = &plugin.health else {
+                break;
+            };
+            if let Err(e) = health.tick().await {
+                log::warn!("failed to update upstream health: {}", e);
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn setting() -> HealthSetting {
+        serde_yaml::from_str(
+            r#"
+endpoint:
+  upstream: backend
+  authority: backend.local
+trip_after: 2
+recover_after: 3
+difficulty_floor: 5000
+"#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn defaults() {
+        let setting = setting();
+        assert_eq!(
+            setting.source,
+            HealthSource::Endpoint {
+                upstream: "backend".to_string(),
+                authority: "backend.local".to_string(),
+                path: "/healthz".to_string(),
+                timeout: 1_000,
+            }
+        );
+        assert_eq!(setting.interval, 5);
+        assert_eq!(setting.bucket_shrink, 1);
+    }
+
+    #[test]
+    fn breaker_hysteresis() {
+        let setting = setting();
+        let mut breaker = Breaker::default();
+        assert!(!breaker.record(true, &setting));
+        assert!(!breaker.record(false, &setting));
+        assert!(!breaker.record(true, &setting));
+        assert!(breaker.record(true, &setting));
+        assert!(breaker.open);
+
+        assert!(!breaker.record(false, &setting));
+        assert!(!breaker.record(false, &setting));
+        assert!(!breaker.record(true, &setting));
+        assert!(!breaker.record(false, &setting));
+        assert!(!breaker.record(false, &setting));
+        assert!(breaker.record(false, &setting));
+        assert!(!breaker.open);
+    }
+
+    #[test]
+    fn property_load() {
+        assert_eq!(load(b"42"), Some(42));
+        assert_eq!(load(b" 7\n"), Some(7));
+        assert_eq!(load(&1_234i64.to_le_bytes()), Some(1_234));
+        assert_eq!(load(b"busy"), None);
+    }
+}
@@ -0,0 +1,77 @@
+//! Trap paths that feed the access list.
+//!
+//! No legitimate client requests the traps, e.g. `/wp-login.php` on a site
+//! without WordPress, so a client that does is scanning. Its address is
+//! denied in the [`AccessList`] for `ban_for` seconds, which every worker
+//! picks up and [`gossip`](crate::gossip) spreads to the other proxies.
+//! Clients on the allow list or the whitelist are not trapped.
+//!
+//! Every trapped client is logged with a `honeypot:` prefix, like the changes
+//! made through the admin routes, and counted in `pow_waf.honeypot.trapped`.
+//!
+//! [`AccessList`]: crate::access_list::AccessList
+
+use std::net::IpAddr;
+
+use crate::access_list::{Entry, Rule};
+use crate::config::HoneypotSetting;
+
+pub struct Honeypot {
+    setting: HoneypotSetting,
+}
+
+impl Honeypot {
+    pub fn new(setting: HoneypotSetting) -> Self {
+        Self { setting }
+    }
+
+    /// The trap `path` falls into, if any.
+    pub fn trap(&self, path: &str) -> Option<&str> {
+        self.setting
+            .traps
+            .iter()
+            .find(|trap| match trap.strip_suffix('*') {
+                Some(prefix) => path.starts_with(prefix),
+                None => path == trap.as_str(),
+            })
+            .map(String::as_str)
+    }
+
+    /// The entry denying a client of `ip` trapped at `now`.
+    pub fn ban(&self, ip: IpAddr, now: u64) -> Entry {
+        Entry {
+            cidr: ip.into(),
+            rule: Rule::Deny,
+            expires_at: Some(now + self.setting.ban_for),
+            added_at: now,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn traps() {
+        let honeypot = Honeypot::new(
+            serde_yaml::from_str(
+                r#"
+traps:
+  - /wp-login.php
+  - /.git/*
+"#,
+            )
+            .unwrap(),
+        );
+        assert_eq!(honeypot.trap("/wp-login.php"), Some("/wp-login.php"));
+        assert_eq!(honeypot.trap("/.git/config"), Some("/.git/*"));
+        assert_eq!(honeypot.trap("/wp-login.php.bak"), None);
+        assert_eq!(honeypot.trap("/blog"), None);
+
+        let entry = honeypot.ban("203.0.113.7".parse().unwrap(), 1_000);
+        assert_eq!(entry.cidr, "203.0.113.7/32".parse().unwrap());
+        assert_eq!(entry.rule, Rule::Deny);
+        assert_eq!(entry.expires_at, Some(87_400));
+    }
+}
@@ -0,0 +1,96 @@
+//! Concurrent requests per client, see [`ConcurrencySetting`].
+//!
+//! A request of a route with a `concurrency` cap is counted in once its rate
+//! limit subject is known and counted out in the log phase, however it ended.
+//! The counters are kept in shared data, so the cap holds across workers,
+//! and removed once no request of their key is in flight.
+//!
+//! A worker torn down with requests in flight never counts them out, so a
+//! counter left untouched for [`STALE_AFTER`] seconds starts over from zero.
+//! Routes serving longer requests, e.g. streams, should not be capped.
+//!
+//! [`ConcurrencySetting`]: crate::config::ConcurrencySetting
+
+use pow_runtime::kv_store::{Capacity, Error, KVStore};
+use serde::{Deserialize, Serialize};
+
+/// Seconds after which an untouched counter is taken as leaked.
+pub const STALE_AFTER: u64 = 600;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+struct Slot {
+    count: u32,
+    /// Unix timestamp in seconds of the last change.
+    at: u64,
+}
+
+impl Slot {
+    fn live(&self, now: u64) -> u32 {
+        match now.saturating_sub(self.at) > STALE_AFTER {
+            true => 0,
+            false => self.count,
+        }
+    }
+}
+
+/// The counter key of a request, kept in the scratch of its context until
+/// it is counted out.
+#[derive(Debug, Clone)]
+pub struct Counted(pub String);
+
+pub struct InFlight {
+    store: KVStore<Slot>,
+}
+
+impl InFlight {
+    pub fn new(context_id: u32, max_entries: Option<usize>) -> Self {
+        // only a cap needs the index, to find the counters to evict
+        let store = match max_entries {
+            Some(max_entries) => KVStore::indexed(context_id, "in_flight").capped(Capacity {
+                max_entries,
+                evicted_metric: "pow_waf.in_flight.evicted".to_string(),
+            }),
+            None => KVStore::new(context_id, "in_flight"),
+        };
+        Self { store }
+    }
+
+    /// Count a request of `key` in, returns how many were in flight before.
+    pub fn acquire(&self, key: &str, now: u64) -> Result<u32, Error> {
+        let mut before = 0;
+        self.store.update(key, |slot| {
+            before = slot.map_or(0, |slot| slot.live(now));
+            Ok::<_, Error>(Slot {
+                count: before + 1,
+                at: now,
+            })
+        })?;
+        Ok(before)
+    }
+
+    /// Count a request of `key` out once it is done, the last one removes
+    /// the counter.
+    pub fn release(&self, key: &str, now: u64) -> Result<(), Error> {
+        self.store.update_or_remove(key, |slot| {
+            let count = slot.map_or(0, |slot| slot.live(now)).saturating_sub(1);
+            Ok::<_, Error>((count > 0).then_some(Slot { count, at: now }))
+        })?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn stale_counts() {
+        let slot = Slot {
+            count: 3,
+            at: 1_000,
+        };
+        assert_eq!(slot.live(1_000), 3);
+        assert_eq!(slot.live(1_000 + STALE_AFTER), 3);
+        assert_eq!(slot.live(1_001 + STALE_AFTER), 0);
+    }
+}
@@ -0,0 +1,105 @@
+//! The "checking your browser" flow, without a frontend of its own.
+//!
+//! With `submit: cookie` the [challenge page](crate::page) stores the
+//! solution in the [`SOLUTION_COOKIE`] of the challenged path and reloads it,
+//! the filter reads the solution from the cookie when the request has no
+//! `X-PoW-*` headers and clears it once verified. The value holds the
+//! headers in order, `version.timestamp.base.nonce`.
+//!
+//! Built with the `embedded-miner` feature, the filter also serves the
+//! pow-mine package itself under `assets_prefix`, so the page needs no
+//! `loader`. The package is the output of
+//! `wasm-pack build --target web pow-mine`, its directory is passed at build
+//! time in `POW_MINE_PKG`:
+//!
+//! ```text
+//! POW_MINE_PKG=$PWD/pow-mine/pkg cargo build -p pow-waf --features embedded-miner
+//! ```
+
+use pow_runtime::response::Response;
+use pow_types::protocol;
+
+pub const SOLUTION_COOKIE: &str = "__pow_solution";
+
+/// Whether the filter serves the pow-mine package itself.
+pub const EMBEDDED: bool = cfg!(feature = "embedded-miner");
+
+const FIELDS: [&str; 4] = [
+    protocol::VERSION,
+    protocol::TIMESTAMP,
+    protocol::BASE,
+    protocol::NONCE,
+];
+
+/// The solution header `name` in the value of the solution cookie.
+pub fn field<'a>(cookie: &'a str, name: &str) -> Option<&'a str> {
+    let index = FIELDS.iter().position(|field| field.eq_ignore_ascii_case(name))?;
+    let values: Vec<&str> = cookie.split('.').collect();
+    match values.as_slice() {
+        [_, _, _, _] => Some(values[index]).filter(|value| !value.is_empty()),
+        _ => None,
+    }
+}
+
+/// Clears the solution cookie of `path` once it is used up.
+pub fn clear(path: &str) -> String {
+    let path = path.split_once('?').map_or(path, |(path, _)| path);
+    format!("{}=; Max-Age=0; Path={}", SOLUTION_COOKIE, path)
+}
+
+#[cfg(feature = "embedded-miner")]
+mod assets {
+    pub const LOADER: &str = include_str!(concat!(env!("POW_MINE_PKG"), "/pow_mine.js"));
+    pub const WASM: &[u8] = include_bytes!(concat!(env!("POW_MINE_PKG"), "/pow_mine_bg.wasm"));
+}
+
+/// The worker script of `mine_parallel`, next to the loader.
+const WORKER: &str = "import init, { startup, serve } from './pow_mine.js';
+await init();
+startup();
+serve();
+";
+
+/// An asset of the embedded pow-mine package, `name` is the path below
+/// `assets_prefix`.
+pub fn asset(name: &str) -> Option<Response> {
+    let builder = Response::builder().header("Cache-Control", "public, max-age=86400");
+    let builder = match name {
+        "/worker.js" if EMBEDDED => builder.body("text/javascript", WORKER),
+        #[cfg(feature = "embedded-miner")]
+        "/pow_mine.js" => builder.body("text/javascript", assets::LOADER),
+        #[cfg(feature = "embedded-miner")]
+        "/pow_mine_bg.wasm" => builder.body("application/wasm", assets::WASM),
+        _ => return None,
+    };
+    Some(builder.build())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn solution_cookie() {
+        let value = "2.1718000000.00000000000000000002a7c4.00ff";
+        assert_eq!(field(value, "X-PoW-Version"), Some("2"));
+        assert_eq!(field(value, "x-pow-timestamp"), Some("1718000000"));
+        assert_eq!(field(value, protocol::BASE), Some("00000000000000000002a7c4"));
+        assert_eq!(field(value, protocol::NONCE), Some("00ff"));
+        assert_eq!(field(value, "X-Other"), None);
+        assert_eq!(field("2.1718000000.00ff", protocol::NONCE), None);
+        assert_eq!(field("2..base.00ff", protocol::TIMESTAMP), None);
+
+        assert_eq!(
+            clear("/login?next=/"),
+            "__pow_solution=; Max-Age=0; Path=/login"
+        );
+    }
+
+    #[test]
+    fn assets() {
+        assert_eq!(asset("/worker.js").is_some(), EMBEDDED);
+        assert_eq!(asset("/pow_mine.js").is_some(), EMBEDDED);
+        assert!(asset("/other.js").is_none());
+    }
+}
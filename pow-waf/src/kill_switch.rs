@@ -0,0 +1,94 @@
+//! Turn enforcement off on every worker at once, without a config push.
+//!
+//! The switch lives in shared data, cached on each worker and dropped from
+//! every cache when flipped, so a flip through the admin route takes effect
+//! with the next request on all workers, within a second on hosts without
+//! shared queues:
+//!
+//! ```text
+//! POST   /__pow/admin/kill_switch?reason=incident-42&ttl=900
+//! GET    /__pow/admin/kill_switch
+//! DELETE /__pow/admin/kill_switch
+//! ```
+//!
+//! While engaged requests go through unchecked, only the admin and status
+//! routes are served. A `ttl` (in seconds) releases the switch by itself, so a
+//! forgotten one does not leave the service unprotected. The state is
+//! reported as the `pow_waf.kill_switch` gauge, 1 while engaged.
+
+use std::cell::Cell;
+use std::time::Duration;
+
+use pow_runtime::cache::CachedKVStore;
+use pow_runtime::kv_store::Error;
+use pow_runtime::metrics;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Engagement {
+    pub reason: Option<String>,
+    /// Unix timestamp in seconds.
+    pub since: u64,
+    /// Unix timestamp in seconds after which the switch is released.
+    pub expires_at: Option<u64>,
+}
+
+impl Engagement {
+    fn is_live(&self, now: u64) -> bool {
+        self.expires_at.filter(|at| *at <= now).is_none()
+    }
+}
+
+pub struct KillSwitch {
+    store: CachedKVStore<Engagement>,
+    /// What this worker last reported, to record the gauge on changes only.
+    reported: Cell<Option<bool>>,
+}
+
+impl KillSwitch {
+    pub fn new(context_id: u32, key: &str) -> Self {
+        Self {
+            store: CachedKVStore::new(context_id, key, Duration::from_secs(1)),
+            reported: Cell::new(None),
+        }
+    }
+
+    /// The live engagement of the switch, `None` while enforcing.
+    pub fn get(&self, now: u64) -> Result<Option<Engagement>, Error> {
+        let engagement = self.store.get("")?.filter(|e| e.is_live(now));
+        let engaged = engagement.is_some();
+        if self.reported.replace(Some(engaged)) != Some(engaged) {
+            metrics::record_gauge("pow_waf.kill_switch", u64::from(engaged));
+        }
+        Ok(engagement)
+    }
+
+    pub fn engage(&self, engagement: &Engagement) -> Result<(), Error> {
+        self.store.put("", engagement)
+    }
+
+    pub fn release(&self) -> Result<(), Error> {
+        self.store.remove("")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn expiry() {
+        let engagement = Engagement {
+            reason: Some("incident-42".to_string()),
+            since: 1_000,
+            expires_at: Some(1_900),
+        };
+        assert!(engagement.is_live(1_899));
+        assert!(!engagement.is_live(1_900));
+        assert!(Engagement {
+            expires_at: None,
+            ..engagement
+        }
+        .is_live(u64::MAX));
+    }
+}
@@ -1,118 +1,381 @@
+pub mod access_list;
+pub mod action;
+pub mod admin;
+pub mod attack;
+pub mod captcha;
 pub mod chain;
 pub mod config;
+pub mod cookie;
+pub mod cors;
+pub mod crawler;
+pub mod endpoint;
+pub mod experiment;
+pub mod fingerprint;
+pub mod geo;
+pub mod gossip;
+pub mod health;
+pub mod honeypot;
+pub mod in_flight;
+pub mod interstitial;
+pub mod kill_switch;
+pub mod limits;
+pub mod page;
+pub mod penalty;
+pub mod quota;
+pub mod reputation;
+pub mod rollout;
+pub mod rules;
+pub mod score;
+pub mod signature;
+pub mod tcp;
+pub mod telemetry;
+pub mod template;
+pub mod tune;
 
+use access_list::{AccessList, Rule};
+use attack::AttackDetector;
+use captcha::Captcha;
 use chain::btc::BTC;
+use config::ActionKind;
+use config::AdminSetting;
+use config::AutoTuneSetting;
+use config::ChallengeEndpoint;
+use config::ChallengePageSetting;
+use config::ChallengeVariant;
+use config::ConcurrencyAction;
+use config::ConcurrencySetting;
 use config::Config;
+use config::FingerprintSource;
+use config::GeoSource;
+use config::GossipSetting;
+use config::PriorityClass;
+use config::PriorityClasses;
+use config::QuotaExport;
+use config::RateLimitKey;
+use config::RegionAction;
+use config::ResponseTemplates;
 use config::Setting;
-use log::info;
+use config::SizeLimits;
+use config::TcpSetting;
+use config::TracingSetting;
+use config::UpgradePolicy;
+use cookie::PassCookie;
+use cors::Cors;
+use crawler::Crawlers;
+use health::UpstreamHealth;
+use honeypot::Honeypot;
+use in_flight::{Counted, InFlight};
+use kill_switch::KillSwitch;
+use limits::BodyLimit;
+use penalty::PenaltyBox;
+use pow_runtime::clock;
 use pow_runtime::counter_bucket::CounterBucket;
-use pow_runtime::response::Response;
+use pow_runtime::failure_mode::FailureMode;
+use pow_runtime::kv_store::Capacity;
+use pow_runtime::filter::{Filter, FilterRoot};
+use pow_runtime::filter_state::State;
+use pow_runtime::memory::Account;
+use pow_runtime::metrics;
+use pow_runtime::pseudo::Method;
+use pow_runtime::response::{ErrorFormat, Response, ResponseHeaders};
+use pow_runtime::singleton;
+use pow_runtime::stream::StreamHookHolder;
+use pow_runtime::stream_info::StreamInfo;
+use pow_runtime::timeout::sleep;
+use pow_runtime::upstream;
 use pow_runtime::Ctx;
 use pow_runtime::HttpHook;
-use pow_runtime::{Runtime, RuntimeBox};
 use pow_types::bytearray32::ByteArray32;
-use pow_types::cidr::CIDR;
-use pow_types::config::Router;
+use pow_types::config::{Found, Router};
+use pow_types::ip_trie::IpTrie;
+use pow_types::protocol::{self, Version};
+use pow_types::route::normalize::PathNormalization;
 use proxy_wasm::traits::*;
 use proxy_wasm::types::*;
-use sha2::Digest;
+use quota::Quota;
+use reputation::Reputation;
+use rules::Rules;
+use score::{Scorer, Verdict};
+use signature::Signer;
+use std::cell::Cell;
 use std::net::SocketAddr;
-use std::sync::Arc;
+use std::time::Duration;
+use tcp::TcpHook;
+use telemetry::{Spans, TraceTag, Traced};
+use template::{Reason, Rejection};
+use tune::Tuner;
 
-proxy_wasm::main! {{
-    proxy_wasm::set_log_level(LogLevel::Trace);
-    proxy_wasm::set_root_context(move |context_id| -> Box<dyn RootContext> {
-        Box::new(RuntimeBox::new(Plugin { context_id, inner: None }))
-    });
-}}
+#[cfg(feature = "plugin")]
+pow_runtime::filter!(Inner, LogLevel::Trace);
 
-struct Inner {
+/// The filter made from a configuration, see [`Filter`].
+pub struct Inner {
     btc: BTC,
     router: Router<Setting>,
     counter_bucket: CounterBucket,
-    whitelist: Vec<CIDR>,
+    in_flight: InFlight,
+    whitelist: IpTrie,
+    access_list: AccessList,
+    kill_switch: KillSwitch,
     difficulty: u64,
+    tcp: Option<TcpSetting>,
+    admin: Option<AdminSetting>,
+    status_path: Option<String>,
+    cors: Option<Cors>,
+    priority_classes: Option<PriorityClasses>,
+    attack: Option<AttackDetector>,
+    geo: Option<GeoSource>,
+    fingerprint: Option<FingerprintSource>,
+    failure_mode: FailureMode,
+    responses: ResponseTemplates,
+    challenge_page: Option<ChallengePageSetting>,
+    verified_header: Option<String>,
+    rate_limit_headers: bool,
+    rate_limit_key: RateLimitKey,
+    error_format: ErrorFormat,
+    problem_type_base: Option<String>,
+    upgrade: UpgradePolicy,
+    quota: Option<Quota>,
+    quota_header: Option<String>,
+    quota_export: Option<QuotaExport>,
+    scorer: Option<Scorer>,
+    reputation: Option<Reputation>,
+    penalty_box: Option<PenaltyBox>,
+    health: Option<UpstreamHealth>,
+    request_deadline: Option<Duration>,
+    honeypot: Option<Honeypot>,
+    signer: Option<Signer>,
+    spans: Option<Spans>,
+    tracing: Option<TracingSetting>,
+    gossip: Option<GossipSetting>,
+    rules: Rules,
+    captcha: Option<Captcha>,
+    crawlers: Option<Crawlers>,
+    pass_cookie: Option<PassCookie>,
+    tuner: Tuner,
+    challenge_endpoints: Router<ChallengeEndpoint>,
+    min_protocol_version: Version,
+    path_normalization: PathNormalization,
 }
 
-#[derive(Clone)]
-struct Plugin {
-    context_id: u32,
-    inner: Option<Arc<Inner>>,
-}
+/// The root context of the filter, configured by
+/// [`Runtime::on_configure`](pow_runtime::Runtime::on_configure).
+pub type Plugin = FilterRoot<Inner>;
 
-impl Context for Plugin {}
-impl Runtime for Plugin {
+impl Filter for Inner {
+    const NAME: &'static str = "PoW filter";
+    type Config = Config<serde_yaml::Value>;
     type Hook = Hook;
-    fn on_vm_start(&mut self, _vm_configuration_size: usize) -> bool {
-        info!("PoW filter starting...");
-        true
-    }
 
-    fn on_configure(&mut self, configuration: Option<Vec<u8>>) -> bool {
-        info!("PoW filter configuring...");
-        let Some(config_bytes) = configuration else {
-            return false;
-        };
+    fn log_level(config: &Self::Config) -> Option<LogLevel> {
+        Some(config.log_level.map(Into::into).unwrap_or(LogLevel::Trace))
+    }
 
-        let mut config: Config<Setting> = match serde_yaml::from_slice(&config_bytes) {
-            Ok(config) => config,
-            Err(e) => {
-                log::error!(
-                    "failed to parse configuration: {}\n raw config: {}",
-                    e,
-                    String::from_utf8(config_bytes)
-                        .expect("failed to read raw config into utf8 string")
-                );
-                return false;
-            }
+    fn configure(context_id: u32, mut config: Self::Config) -> Result<Self, String> {
+        let whitelist: IpTrie = config
+            .whitelist
+            .take()
+            .unwrap_or_default()
+            .into_iter()
+            .collect();
+        let difficulty = config.difficulty;
+        let mut upstreams = std::mem::take(&mut config.upstreams);
+        if let Some(cluster) = config.mempool_upstream_name.take() {
+            upstreams
+                .entry(chain::btc::UPSTREAM.to_string())
+                .or_insert_with(|| BTC::upstream(cluster));
+        }
+        if !upstreams.contains_key(chain::btc::UPSTREAM) {
+            return Err(format!(
+                "no {} upstream, set mempool_upstream_name or upstreams.{}",
+                chain::btc::UPSTREAM,
+                chain::btc::UPSTREAM
+            ));
+        }
+        upstream::configure(upstreams);
+        let tcp = config.tcp.take();
+        let admin = config.admin.take();
+        let priority_classes = config.priority_classes.take();
+        let attack = config
+            .attack
+            .take()
+            .map(|setting| AttackDetector::new(context_id, setting));
+        let geo = config.geo.take();
+        let quota_setting = config.quota.take();
+        let tracing = config.tracing.take();
+        let rules = Rules::new(std::mem::take(&mut config.rules))
+            .map_err(|e| format!("invalid rules: {}", e))?;
+        let crawlers = match config.crawlers.take() {
+            Some(setting) => Some(
+                Crawlers::new(context_id, setting)
+                    .map_err(|e| format!("invalid crawlers: {}", e))?,
+            ),
+            None => None,
         };
+        let scorer = config
+            .scoring
+            .take()
+            .map(|setting| Scorer::new(context_id, setting, difficulty));
+        let quota = quota_setting
+            .as_ref()
+            .map(|setting| Quota::new(context_id, setting));
+        let quota_header = quota_setting
+            .as_ref()
+            .and_then(|setting| setting.remaining_header.clone());
+        let cors = config
+            .cors
+            .take()
+            .map(|setting| Cors::new(setting, quota_header.as_deref()));
+        let responses = std::mem::take(&mut config.responses);
+        let challenge_page = config.challenge_page.take();
+        if challenge_page.as_ref().is_some_and(|page| page.loader().is_none()) {
+            return Err(
+                "challenge_page needs a loader without the embedded-miner feature".to_string(),
+            );
+        }
+        let problem_type_base = config.problem_type_base.take();
 
-        proxy_wasm::set_log_level(
-            config
-                .log_level
-                .map(|l| l.into())
-                .unwrap_or(LogLevel::Trace),
-        );
-
-        let whitelist = config.whitelist.take().unwrap_or_default();
-        let difficulty = config.difficulty;
-        let mempool_upstream_name = config.mempool_upstream_name.clone();
+        let challenge_endpoints = std::mem::take(&mut config.challenge_endpoints);
+        let challenge_endpoints: Router<ChallengeEndpoint> = challenge_endpoints
+            .try_into()
+            .map_err(|e| format!("failed to convert challenge endpoints: {}", e))?;
 
-        let router: Router<Setting> = match config.virtual_hosts.try_into() {
-            Ok(router) => router,
-            Err(e) => {
-                log::error!(
-                    "failed to convert configuration: {}\n raw config: {}",
-                    e,
-                    String::from_utf8(config_bytes)
-                        .expect("failed to read raw config into utf8 string")
-                );
-                return false;
-            }
-        };
+        let virtual_hosts = std::mem::take(&mut config.virtual_hosts)
+            .into_iter()
+            .map(|mut host| {
+                host.inherit();
+                host.try_map(serde_yaml::from_value::<Setting>)
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| format!("invalid route setting: {}", e))?;
+        let router: Router<Setting> = virtual_hosts
+            .try_into()
+            .map_err(|e| format!("failed to convert configuration: {}", e))?;
 
-        self.inner = Some(Arc::new(Inner {
-            btc: BTC::new(mempool_upstream_name),
+        Ok(Inner {
+            // polled and flushed once per VM, not once per configuration
+            btc: singleton::get_or_init("pow_waf.btc", || BTC::new(context_id)),
             router,
-            counter_bucket: CounterBucket::new(self.context_id, "rate_limit"),
+            counter_bucket: singleton::configured(
+                "pow_waf.rate_limit",
+                (config.memory.counter_buffer, config.memory.rate_limit_entries),
+                |&(counter_buffer, rate_limit_entries)| {
+                    CounterBucket::indexed(
+                        context_id,
+                        "rate_limit",
+                        Account::new("pow_waf.memory.counter_buffer", counter_buffer),
+                        RATE_LIMIT_TTL,
+                        rate_limit_entries.map(|max_entries| Capacity {
+                            max_entries,
+                            evicted_metric: "pow_waf.rate_limit.evicted".to_string(),
+                        }),
+                    )
+                },
+            ),
+            in_flight: InFlight::new(context_id, config.memory.in_flight_entries),
             whitelist,
+            access_list: AccessList::new(context_id, "access_list"),
+            kill_switch: KillSwitch::new(context_id, "kill_switch"),
             difficulty,
-        }));
-        info!("PoW filter configured");
-        true
+            tcp,
+            admin,
+            status_path: config.status_path.take(),
+            cors,
+            priority_classes,
+            attack,
+            geo,
+            fingerprint: config.fingerprint.take(),
+            failure_mode: config.failure_mode,
+            responses,
+            challenge_page,
+            verified_header: config.verified_header.take(),
+            rate_limit_headers: config.rate_limit_headers,
+            rate_limit_key: std::mem::take(&mut config.rate_limit_key),
+            error_format: config.error_format,
+            problem_type_base,
+            upgrade: config.upgrade,
+            quota,
+            quota_header,
+            quota_export: quota_setting.and_then(|setting| setting.export),
+            scorer,
+            reputation: config
+                .reputation
+                .take()
+                .map(|setting| Reputation::new(context_id, setting)),
+            penalty_box: config
+                .penalty_box
+                .take()
+                .map(|setting| PenaltyBox::new(context_id, setting)),
+            health: config
+                .health
+                .take()
+                .map(|setting| UpstreamHealth::new(context_id, setting)),
+            request_deadline: config.request_deadline.map(Duration::from_millis),
+            honeypot: config.honeypot.take().map(Honeypot::new),
+            signer: config.signature.take().map(Signer::new),
+            spans: tracing.clone().map(Spans::new),
+            tracing,
+            gossip: config.gossip.take(),
+            rules,
+            captcha: config
+                .captcha
+                .take()
+                .map(|setting| Captcha::new(context_id, setting)),
+            crawlers,
+            pass_cookie: config.pass_cookie.take().map(PassCookie::new),
+            tuner: Tuner::new(context_id),
+            challenge_endpoints,
+            min_protocol_version: config.min_protocol_version,
+            path_normalization: std::mem::take(&mut config.path_normalization),
+        })
     }
 
-    fn create_http_context(&self, _context_id: u32) -> Option<Self::Hook> {
-        Some(Hook {
-            ctx: Ctx::new(_context_id),
-            plugin: self.inner.clone().expect("plugin not initialized"),
-        })
+    fn configured(context_id: u32, inner: &State<Self>) {
+        if let Some(gossip) = inner.gossip.clone() {
+            gossip::spawn(context_id, inner.downgrade(), gossip);
+        }
+        if inner.health.is_some() {
+            health::spawn(inner.downgrade());
+        }
+        if let Some(export) = inner.quota_export.clone() {
+            quota::spawn_export(inner.downgrade(), export);
+        }
+        if let Some(setting) = inner.tracing.clone() {
+            telemetry::spawn_export(inner.downgrade(), setting);
+        }
+    }
+
+    fn hook(ctx: Ctx, plugin: State<Self>) -> Hook {
+        Hook {
+            ctx,
+            plugin,
+            response_headers: ResponseHeaders::default(),
+            body_received: Cell::new(0),
+        }
+    }
+
+    fn stream_context(context_id: u32, plugin: State<Self>) -> Option<Box<dyn StreamContext>> {
+        let hook = TcpHook {
+            ctx: pow_runtime::stream::StreamCtx::new(context_id),
+            plugin,
+        };
+        Some(Box::new(StreamHookHolder::new(context_id, hook)))
+    }
+
+    fn context_type(&self) -> ContextType {
+        match self.tcp {
+            Some(_) => ContextType::StreamContext,
+            None => ContextType::HttpContext,
+        }
     }
 }
 
 pub struct Hook {
     ctx: Ctx,
-    plugin: Arc<Inner>,
+    plugin: State<Inner>,
+    response_headers: ResponseHeaders,
+    /// Bytes of the request body received so far.
+    body_received: Cell<u64>,
 }
 
 fn transform_u64_to_u8_array(mut value: u64) -> [u8; 8] {
@@ -134,123 +397,65 @@ fn get_difficulty(level: u64) -> ByteArray32 {
     (&difficulty).into()
 }
 
-#[derive(serde::Serialize)]
-struct DifficultyResponse {
-    current: ByteArray32,
-    difficulty: ByteArray32,
-    error: String,
-    message: String,
-}
+type Error = pow_runtime::error::Error<Rejection>;
 
-#[derive(Debug)]
-enum Error {
-    Status {
-        reason: String,
-        status: proxy_wasm::types::Status,
-    },
-    Response(Response),
-    #[allow(dead_code)]
-    Other {
-        reason: String,
-        error: Box<dyn std::error::Error>,
-    },
-}
+// Draft IETF RateLimit header fields, the reset is in seconds like `Retry-After`.
+const RATE_LIMIT_LIMIT: &str = "RateLimit-Limit";
+const RATE_LIMIT_REMAINING: &str = "RateLimit-Remaining";
+const RATE_LIMIT_RESET: &str = "RateLimit-Reset";
 
-impl Error {
-    fn status(reason: impl Into<String>, status: proxy_wasm::types::Status) -> Self {
-        Error::Status {
-            reason: reason.into(),
-            status,
-        }
-    }
+/// Rate limit counters, keyed `<host><route pattern>:<subject>:<bucket>`, are
+/// kept past the longest bucket, a day.
+const RATE_LIMIT_TTL: Duration = Duration::from_secs(2 * 86_400);
 
-    fn response(response: Response) -> Self {
-        Error::Response(response)
-    }
-
-    #[allow(dead_code)]
-    fn other(reason: impl Into<String>, error: impl Into<Box<dyn std::error::Error>>) -> Self {
-        Error::Other {
-            reason: reason.into(),
-            error: error.into(),
-        }
-    }
-}
+/// Seconds a solution is accepted after its `X-PoW-Timestamp`, or the
+/// timestamp of its TCP handshake frame.
+pub(crate) const CHALLENGE_TTL: u64 = 60;
 
-impl From<Error> for Response {
-    fn from(val: Error) -> Self {
-        match val {
-            Error::Response(response) => {
-                log::debug!("reject request with response, {:?}", response.code);
-                response
-            }
-            Error::Status { reason, status } => {
-                let msg = format!("{:?}: {}", status, reason);
-                log::warn!("failed hostcall with error, {}", msg);
-                Response {
-                    code: 500,
-                    headers: vec![("Content-Type".to_string(), "text/plain".to_string())],
-                    body: Some(msg.into_bytes()),
-                    trailers: vec![],
-                }
-            }
-            Error::Other { reason, error } => {
-                let msg = format!("{}: {}", error, reason);
-                log::warn!("failed unknow error, {}", msg);
-                Response {
-                    code: 500,
-                    headers: vec![("Content-Type".to_string(), "text/plain".to_string())],
-                    body: Some(msg.into_bytes()),
-                    trailers: vec![],
-                }
-            }
-        }
-    }
-}
+/// Seconds the timestamp of a client may be ahead of the clock.
+pub(crate) const MAX_CLOCK_SKEW: u64 = 5;
 
-fn too_many_request(current: ByteArray32, difficulty: u64, error: String) -> Error {
-    let target = get_difficulty(difficulty);
-    let body = DifficultyResponse {
+fn too_many_request(reason: Reason, current: ByteArray32, difficulty: u64, error: String) -> Error {
+    Error::Rejected(Rejection::TooManyRequests {
+        reason,
         current,
-        difficulty: target,
+        difficulty: get_difficulty(difficulty),
         error,
-        message: "Access restriction triggered".to_string(),
-    };
-    Error::response(Response {
-        code: 429,
-        headers: vec![("Content-Type".to_string(), "application/json".to_string())],
-        body: Some(
-            serde_json::to_string(&body)
-                .expect("failed to serialize difficulty")
-                .into_bytes(),
-        ),
-        trailers: vec![],
     })
 }
 
-fn forbidden(message: String) -> Error {
-    let body = serde_json::json!({ "message": message });
-    Error::response(Response {
-        code: 403,
-        headers: vec![("Content-Type".to_string(), "text/json".to_string())],
-        body: Some(body.to_string().into_bytes()),
-        trailers: vec![],
-    })
+fn forbidden(reason: Reason, message: String) -> Error {
+    Error::Rejected(Rejection::Forbidden { reason, message })
 }
 
 impl Hook {
-    fn get_header(&self, key: &str) -> Result<String, Error> {
+    fn get_method(&self) -> Result<Method, Error> {
         self.ctx
-            .get_http_request_header(key)
-            .map_err(|s| Error::status(format!("failed to get header: {}", key), s))?
-            .ok_or_else(|| forbidden(format!("missing header: {}", key)))
+            .method()
+            .map_err(|s| Error::status("failed to get method", s))?
+            .ok_or_else(|| forbidden(Reason::InvalidRequest, "missing method".to_string()))
+    }
+
+    /// The host the solution covers and routes are matched on.
+    fn get_authority(&self) -> Result<String, Error> {
+        let authority = self
+            .ctx
+            .authority()
+            .map_err(|s| Error::status("failed to get authority", s))?
+            .ok_or_else(|| {
+                forbidden(
+                    Reason::InvalidRequest,
+                    "missing, malformed or conflicting authority".to_string(),
+                )
+            })?;
+        Ok(authority.to_string())
     }
 
     fn get_client_address(&self) -> Result<String, Error> {
         self.ctx
             .get_client_address()
             .map_err(|s| Error::status("failed to get client address", s))?
-            .ok_or_else(|| forbidden("failed to get client address from request".to_string()))
+            .ok_or_else(|| Error::status("missing client address", Status::NotFound))
     }
 
     fn get_current_hash(&self) -> Result<ByteArray32, Error> {
@@ -268,138 +473,1294 @@ impl Hook {
             .map_err(|s| Error::status("failed to get path", s))
     }
 
+    /// `path` as routes are matched against it.
+    fn normalize_path(&self, path: &str) -> Result<String, Error> {
+        self.plugin
+            .path_normalization
+            .normalize(path)
+            .map_err(|e| forbidden(Reason::InvalidRequest, format!("{}: {}", e, path)))
+    }
+
+    /// The principal set by the auth filter and the class it maps to.
+    fn get_principal(&self) -> Result<Option<(String, &PriorityClass)>, Error> {
+        let Some(classes) = &self.plugin.priority_classes else {
+            return Ok(None);
+        };
+        let principal = self
+            .ctx
+            .get_http_request_header(&classes.principal_header)
+            .map_err(|s| Error::status("failed to get principal header", s))?;
+        Ok(principal
+            .filter(|p| !p.is_empty())
+            .map(|p| (p, &classes.authenticated)))
+    }
+
+    /// What the counters of a request without a priority class are kept per.
+    fn get_subject(&self, key: Option<&RateLimitKey>, addr: SocketAddr) -> Result<String, Error> {
+        let principal_header = self
+            .plugin
+            .priority_classes
+            .as_ref()
+            .map_or_else(config::default_principal_header, |classes| {
+                classes.principal_header.clone()
+            });
+        let key = key.unwrap_or(&self.plugin.rate_limit_key);
+        let fingerprint = match key {
+            RateLimitKey::TlsFingerprint => self.get_fingerprint()?.and_then(|f| f.id()),
+            _ => None,
+        };
+        key.subject(addr.ip(), &principal_header, fingerprint, |name| {
+            self.ctx.get_http_request_header(name)
+        })
+        .map_err(|s| Error::status("failed to get rate limit key", s))
+    }
+
+    /// The page setting when the request gets the challenge page.
+    fn challenge_page(&self) -> Option<&ChallengePageSetting> {
+        let setting = self.plugin.challenge_page.as_ref()?;
+        let header = |name: &str| self.ctx.get_http_request_header(name).ok().flatten();
+        let method = self.ctx.method().ok().flatten()?;
+        let negotiated = page::negotiate(
+            setting,
+            &method,
+            header("accept").as_deref(),
+            header("sec-fetch-dest").as_deref(),
+        );
+        negotiated.then_some(setting)
+    }
+
+    /// The answer to a CORS preflight of a protected route or challenge
+    /// endpoint, which would otherwise be challenged itself.
+    fn preflight(
+        &self,
+        host: &str,
+        endpoint_path: &str,
+        route_path: &str,
+    ) -> Result<Option<Response>, Error> {
+        let Some(cors) = &self.plugin.cors else {
+            return Ok(None);
+        };
+        if self.get_method()? != Method::Options {
+            return Ok(None);
+        }
+        let header = |name: &str| self.ctx.get_http_request_header(name).ok().flatten();
+        let (Some(origin), Some(method)) = (
+            header("origin"),
+            header("access-control-request-method"),
+        ) else {
+            return Ok(None);
+        };
+        let protected = self
+            .plugin
+            .challenge_endpoints
+            .matches(host, endpoint_path)
+            .is_some()
+            || self.plugin.router.matches(host, route_path).is_some();
+        Ok(protected.then(|| cors.preflight(&origin, &method)))
+    }
+
+    /// CORS headers of a response of the filter itself.
+    fn add_cors(&self, response: &mut Response) {
+        let Some(cors) = &self.plugin.cors else {
+            return;
+        };
+        if let Ok(Some(origin)) = self.ctx.get_http_request_header("origin") {
+            response.headers.extend(cors.headers(&origin));
+        }
+    }
+
+    fn health(&self) -> admin::Health {
+        admin::Health {
+            epoch: self.plugin.epoch(),
+            latest_hash: self.plugin.btc.get_latest_hash(),
+            seed_age: self.plugin.btc.seed_age(),
+            pending_counters: self.plugin.counter_bucket.pending(),
+            flush_lag: self.plugin.counter_bucket.flush_lag(),
+            locks: pow_runtime::lock::contention(),
+            executor: pow_runtime::task::stats(),
+        }
+    }
+
+    /// The TLS fingerprint of the client, `None` without a `fingerprint`
+    /// source.
+    fn get_fingerprint(&self) -> Result<Option<fingerprint::Fingerprint>, Error> {
+        let Some(source) = &self.plugin.fingerprint else {
+            return Ok(None);
+        };
+        let fingerprint = fingerprint::Fingerprint::lookup(&self.ctx, source)
+            .map_err(|s| Error::status("failed to read TLS fingerprint", s))?;
+        log::debug!("client TLS fingerprint: {:?}", fingerprint);
+        Ok(Some(fingerprint))
+    }
+
+    /// Reject `subject` once it has used up a quota.
+    fn check_quota(&self, subject: &str) -> Result<(), Error> {
+        let Some(quota) = &self.plugin.quota else {
+            return Ok(());
+        };
+        let now = now();
+        let exhausted = quota
+            .exhausted(subject, now)
+            .map_err(|e| Error::other("failed to get quota", e))?;
+        match exhausted {
+            Some(period) => Err(Error::Rejected(Rejection::QuotaExceeded {
+                error: format!("{} quota exceeded", period.as_str()),
+                retry_after: period.remaining_secs(now),
+            })),
+            None => Ok(()),
+        }
+    }
+
+    /// Count a request let through against the quota of `subject`.
+    fn consume_quota(&self, subject: &str) -> Result<(), Error> {
+        let Some(quota) = &self.plugin.quota else {
+            return Ok(());
+        };
+        let remaining = quota
+            .consume(subject, now())
+            .map_err(|e| Error::other("failed to count quota", e))?;
+        if let Some(header) = &self.plugin.quota_header {
+            self.response_headers.push(header, remaining.to_string());
+        }
+        Ok(())
+    }
+
+    /// Whether the request carries a pass cookie letting `subject` through
+    /// on `host` at `difficulty`.
+    fn has_pass_cookie(&self, difficulty: u64, host: &str, subject: &str) -> Result<bool, Error> {
+        let Some(pass_cookie) = &self.plugin.pass_cookie else {
+            return Ok(false);
+        };
+        let header = self
+            .ctx
+            .get_http_request_header("cookie")
+            .map_err(|s| Error::status("failed to get header: cookie", s))?;
+        let value = header
+            .as_deref()
+            .and_then(|header| cookie::get(header, pass_cookie.name()));
+        Ok(value.is_some_and(|value| pass_cookie.verify(value, difficulty, host, subject, now())))
+    }
+
+    /// Deny the client once it requests a trap path, see [`honeypot`].
+    fn check_honeypot(&self, addr: SocketAddr, path: &str) -> Result<(), Error> {
+        let Some(honeypot) = &self.plugin.honeypot else {
+            return Ok(());
+        };
+        let Some(trap) = honeypot.trap(path) else {
+            return Ok(());
+        };
+        let entry = honeypot.ban(addr.ip(), now());
+        log::warn!(
+            "honeypot: {} requested {} (trap {}), deny until {:?}{}",
+            addr.ip(),
+            path,
+            trap,
+            entry.expires_at,
+            self.trace_tag()
+        );
+        metrics::increment_counter("pow_waf.honeypot.trapped", 1);
+        self.plugin
+            .access_list
+            .insert(entry)
+            .map_err(|e| Error::other("failed to deny trapped client", e))?;
+        Err(forbidden(
+            Reason::AddressBlocked,
+            format!("client address {} is blocked", addr.ip()),
+        ))
+    }
+
+    /// Reject the client while it is in the penalty box, after the tarpit.
+    async fn check_penalty_box(&self, addr: SocketAddr) -> Result<(), Error> {
+        let Some(penalty_box) = &self.plugin.penalty_box else {
+            return Ok(());
+        };
+        let now = now();
+        let until = penalty_box
+            .until(&addr.ip().to_string(), now)
+            .map_err(|e| Error::other("failed to get penalty box", e))?;
+        let Some(until) = until else {
+            return Ok(());
+        };
+        if let Some(delay) = penalty_box.tarpit() {
+            sleep(delay).await;
+        }
+        metrics::increment_counter("pow_waf.penalty_box.rejected", 1);
+        Err(Error::Rejected(Rejection::Penalized {
+            error: "too many invalid solutions".to_string(),
+            retry_after: until - now,
+        }))
+    }
+
+    /// Whether the client is the crawler its `User-Agent` claims, verified
+    /// by its address. A failed verification is logged and challenged.
+    async fn is_verified_crawler(&self, addr: SocketAddr) -> Result<bool, Error> {
+        let Some(crawlers) = &self.plugin.crawlers else {
+            return Ok(false);
+        };
+        let Some(user_agent) = self
+            .ctx
+            .get_http_request_header("user-agent")
+            .map_err(|s| Error::status("failed to get user agent", s))?
+        else {
+            return Ok(false);
+        };
+        let Some(crawler) = crawlers.claimed(&user_agent) else {
+            return Ok(false);
+        };
+        let ip = addr.ip();
+        let cached = crawlers
+            .verdict(crawler, ip)
+            .map_err(|e| Error::other("failed to get crawler verdict", e))?;
+        if let Some(verified) = cached {
+            return Ok(verified);
+        }
+        let verified = match crawlers.verify(crawler, ip).await {
+            Ok(verified) => verified,
+            Err(e) => {
+                log::warn!("failed to verify {} at {}, challenge it: {}", crawler.name, ip, e);
+                return Ok(false);
+            }
+        };
+        let outcome = if verified { "verified" } else { "impostor" };
+        log::info!("{} at {}: {}", crawler.name, ip, outcome);
+        metrics::increment_counter(&format!("pow_waf.crawler.{}.{}", crawler.name, outcome), 1);
+        crawlers
+            .remember(crawler, ip, verified)
+            .map_err(|e| Error::other("failed to keep crawler verdict", e))?;
+        Ok(verified)
+    }
+
+    /// Let the request through once its client solved a CAPTCHA.
+    async fn check_captcha(
+        &self,
+        captcha: &Captcha,
+        subject: &str,
+        addr: SocketAddr,
+    ) -> Result<(), Error> {
+        let now = now();
+        let passed = captcha
+            .has_pass(subject, now)
+            .map_err(|e| Error::other("failed to get captcha pass", e))?;
+        if passed {
+            return Ok(());
+        }
+        let setting = captcha.setting();
+        let challenge = |reason: Reason, error: String| {
+            Error::Rejected(Rejection::Captcha {
+                reason,
+                site_key: setting.site_key.clone(),
+                token_header: setting.token_header.clone(),
+                error,
+            })
+        };
+        let Some(token) = self
+            .ctx
+            .get_http_request_header(&setting.token_header)
+            .map_err(|s| Error::status("failed to get captcha token", s))?
+        else {
+            return Err(challenge(
+                Reason::CaptchaRequired,
+                format!("Missing {} in header", setting.token_header),
+            ));
+        };
+        let verified = captcha
+            .verify(&token, &addr.ip().to_string())
+            .await
+            .map_err(|e| Error::other("failed to verify captcha", e))?;
+        match verified {
+            Ok(()) => captcha
+                .grant_pass(subject, now)
+                .map_err(|e| Error::other("failed to grant captcha pass", e)),
+            Err(codes) => {
+                if let Some(attack) = &self.plugin.attack {
+                    attack.record_failed();
+                }
+                Err(challenge(
+                    Reason::CaptchaFailed,
+                    format!("CAPTCHA rejected: {}", codes),
+                ))
+            }
+        }
+    }
+
+    /// Apply the WAF rules, the difficulty level they require at least.
+    fn check_rules(&self, path: &str) -> Result<u64, Error> {
+        if self.plugin.rules.is_empty() {
+            return Ok(0);
+        }
+        let outcome = self
+            .plugin
+            .rules
+            .check(path, |name| self.ctx.get_http_request_header(name))
+            .map_err(|s| Error::status("failed to get request headers", s))?;
+        for (name, action) in &outcome.matched {
+            log::info!(
+                "request matched rule {} ({}){}",
+                name,
+                action.as_str(),
+                self.trace_tag()
+            );
+            metrics::increment_counter(&format!("pow_waf.rule.{}.{}", name, action.as_str()), 1);
+        }
+        if let Some(name) = outcome.blocked_by() {
+            return Err(forbidden(
+                Reason::RuleMatched,
+                format!("request blocked by rule {}", name),
+            ));
+        }
+        Ok(outcome.min_difficulty())
+    }
+
+    /// Take the edge action of `found` applying to the request, if any.
+    fn take_action(
+        &self,
+        found: &Found<'_, Setting>,
+        addr: SocketAddr,
+        host: &str,
+        path: &str,
+    ) -> Result<(), Error> {
+        let route = format!("{}{}", host, found.pattern());
+        let action = action::select(&found.actions, addr.ip(), &route, |name| {
+            self.ctx.get_http_request_header(name)
+        })
+        .map_err(|s| Error::status("failed to get request headers", s))?;
+        let Some(action) = action else {
+            return Ok(());
+        };
+        log::debug!("{} -> {}: action {}", addr, route, action.name);
+        action::count(action);
+        if let Some(response) = action.response(path) {
+            return Err(Error::response(response));
+        }
+        if let ActionKind::Upstream { cluster, header } = &action.kind {
+            self.ctx
+                .set_http_request_header(header, Some(cluster))
+                .map_err(|s| Error::status("failed to set upstream header", s))?;
+        }
+        Ok(())
+    }
+
+    /// Score the client, after counting a request beyond the rate limit
+    /// when `over_limit`.
+    fn assess(&self, addr: SocketAddr, over_limit: bool) -> Result<Verdict, Error> {
+        let Some(scorer) = &self.plugin.scorer else {
+            return Ok(Verdict::Allow);
+        };
+        let client = addr.ip().to_string();
+        if over_limit {
+            scorer
+                .rate_overage(&client)
+                .map_err(|e| Error::other("failed to record risk", e))?;
+        }
+        let geo = match &self.plugin.geo {
+            Some(source) if scorer.needs_geo() => Some(
+                geo::Geo::lookup(&self.ctx, source)
+                    .map_err(|s| Error::status("failed to read client region", s))?,
+            ),
+            _ => None,
+        };
+        let fingerprint = match scorer.needs_fingerprint() {
+            true => self.get_fingerprint()?,
+            false => None,
+        };
+        let points = scorer
+            .request_points(
+                |name| self.ctx.get_http_request_header(name).map(|v| v.is_none()),
+                geo.as_ref(),
+                fingerprint.as_ref(),
+            )
+            .map_err(|s| Error::status("failed to get request headers", s))?;
+        let history = scorer
+            .history(&client)
+            .map_err(|e| Error::other("failed to get risk", e))?;
+        let verdict = scorer.verdict(points + history);
+        log::debug!("client {} risk score {}: {:?}", client, points + history, verdict);
+        if verdict != Verdict::Allow {
+            metrics::increment_counter(&format!("pow_waf.risk.{}", verdict.as_str()), 1);
+        }
+        Ok(verdict)
+    }
+
+    fn get_region_action<'a>(
+        &self,
+        policies: &'a [config::RegionPolicy],
+    ) -> Result<Option<&'a RegionAction>, Error> {
+        let Some(source) = &self.plugin.geo else {
+            return Ok(None);
+        };
+        if policies.is_empty() {
+            return Ok(None);
+        }
+        let geo = geo::Geo::lookup(&self.ctx, source)
+            .map_err(|s| Error::status("failed to read client region", s))?;
+        log::debug!("client region: {:?}", geo);
+        Ok(geo::region_action(policies, &geo))
+    }
+
+    /// The solution header `name`, or its field in the solution cookie.
+    fn get_solution(&self, name: &str) -> Result<Option<String>, Error> {
+        let header = self
+            .ctx
+            .get_http_request_header(name)
+            .map_err(|s| Error::status(format!("failed to get header: {}", name), s))?;
+        if header.is_some() {
+            return Ok(header);
+        }
+        Ok(self.solution_cookie()?.and_then(|value| {
+            interstitial::field(&value, name).map(str::to_string)
+        }))
+    }
+
+    /// The solution cookie, when the challenge page submits by cookie.
+    fn solution_cookie(&self) -> Result<Option<String>, Error> {
+        let submits = self.plugin.challenge_page.as_ref();
+        if submits.is_none_or(|page| page.submit != config::Submission::Cookie) {
+            return Ok(None);
+        }
+        let header = self
+            .ctx
+            .get_http_request_header("cookie")
+            .map_err(|s| Error::status("failed to get header: cookie", s))?;
+        Ok(header
+            .as_deref()
+            .and_then(|header| cookie::get(header, interstitial::SOLUTION_COOKIE))
+            .map(str::to_string))
+    }
+
+    /// Sample the time since the challenge of `route` was issued, as if solved
+    /// at the tuned level `base`, if the request carries a valid ticket.
+    fn record_solve_time(
+        &self,
+        tune: &AutoTuneSetting,
+        route: &str,
+        base: u64,
+        difficulty: u64,
+    ) -> Result<(), Error> {
+        let issued = self
+            .ctx
+            .get_http_request_header(tune::ISSUED_HEADER)
+            .map_err(|s| Error::status("failed to get header: issued", s))?;
+        let max_age_ms = CHALLENGE_TTL * 1000;
+        let solve_ms =
+            issued.and_then(|issued| tune.solve_ms(&issued, route, now_ms(), max_age_ms));
+        let Some(solve_ms) = solve_ms else {
+            log::debug!("no valid issue time for {}, solve time not sampled", route);
+            return Ok(());
+        };
+        let solve_ms = (solve_ms as u128 * base as u128 / difficulty as u128) as u64;
+        let recorded = self
+            .plugin
+            .tuner
+            .record(route, tune, self.plugin.difficulty, solve_ms);
+        if let Err(e) = recorded {
+            log::warn!("failed to record solve time: {}", e);
+        }
+        Ok(())
+    }
+
     fn get_timestamp(&self) -> Result<u64, Error> {
-        self.get_header("X-PoW-Timestamp")?
-            .parse()
-            .map_err(|e| forbidden(format!("failed to parse timestamp: {}", e)))
+        self.get_solution(protocol::TIMESTAMP)?
+            .ok_or_else(|| forbidden(Reason::InvalidRequest, "missing timestamp".to_string()))?
+            .parse().map_err(|e| {
+            forbidden(
+                Reason::InvalidRequest,
+                format!("failed to parse timestamp: {}", e),
+            )
+        })
     }
 }
 
+/// What a request has to solve, see [`Hook::assess_route`].
+struct Assessment<'a> {
+    /// What the counters and quotas of the request are kept per.
+    subject: String,
+    /// Counter key of the request.
+    key: String,
+    /// Budget the request consumes from the rate limit.
+    weight: u64,
+    /// Host and pattern of the route, its difficulty is tuned for.
+    route: String,
+    /// Level of the route before the rate and floors raise it.
+    base: u64,
+    difficulty: u64,
+    /// The experiment arm of the client, see [`experiment`].
+    variant: Option<&'a ChallengeVariant>,
+}
+
 fn now() -> u64 {
-    std::time::SystemTime::now()
-        .duration_since(std::time::UNIX_EPOCH)
-        .expect("failed to get timestamp")
-        .as_secs()
+    now_ms() / 1000
 }
 
-impl HttpHook for Hook {
-    fn filter_name() -> Option<&'static str> {
-        Some("PoW")
-    }
+fn now_ms() -> u64 {
+    clock::unix_millis()
+}
 
-    async fn on_request_headers(
-        &self,
-        _num_headers: usize,
-        _end_of_stream: bool,
-    ) -> Result<(), impl Into<Response>> {
-        let addr = self.get_client_address()?;
-        let addr: SocketAddr = addr
-            .parse()
-            .map_err(|s| forbidden(format!("invalid client address {}: {}", s, addr)))?;
-        if self
-            .plugin
-            .whitelist
-            .iter()
-            .any(|cidr| cidr.contains(addr.ip()))
-        {
+impl Hook {
+    /// Tell the upstream whether the client proved its work, see `verified_header`.
+    fn set_verified(&self, verified: bool) -> Result<(), Error> {
+        let Some(header) = &self.plugin.verified_header else {
             return Ok(());
+        };
+        self.ctx
+            .set_http_request_header(header, verified.then_some("true"))
+            .map_err(|s| Error::status("failed to set verified header", s))
+    }
+
+    /// Keep the trace of the request for its logs and spans, see [`telemetry`].
+    fn trace_request(&self) {
+        match self.ctx.trace_context() {
+            Ok(Some(context)) => {
+                self.ctx.scratch().insert(Traced {
+                    context,
+                    started: now_ms(),
+                });
+            }
+            Ok(None) => {}
+            Err(e) => log::warn!("failed to read trace context: {:?}", e),
         }
-        let host = self.get_header(":authority")?;
-        let path = self.get_path()?;
+    }
 
-        log::debug!("{} -> {}{}", addr, host, path);
+    fn trace_tag(&self) -> TraceTag {
+        TraceTag(
+            self.ctx
+                .scratch()
+                .get()
+                .map(|traced: Traced| traced.context.trace_id),
+        )
+    }
 
-        let Some(found) = self.plugin.router.matches(&host, &path) else {
-            log::debug!("no matched route found, skip rate limit");
+    /// Report the step `name` of the decision on a traced request.
+    fn report(&self, name: &str, attributes: &[(&str, String)]) {
+        let Some(spans) = &self.plugin.spans else {
+            return;
+        };
+        if let Some(traced) = self.ctx.scratch().get() {
+            spans.record(&traced, name, now_ms(), attributes);
+        }
+    }
+
+    /// Replace the signature header of the client by one of the filter, see
+    /// [`signature`], or remove it unless `signed`.
+    fn set_signature(&self, signed: bool) -> Result<(), Error> {
+        let Some(signer) = &self.plugin.signer else {
             return Ok(());
         };
+        let value = match signed {
+            true => {
+                let addr = self.get_client_address()?;
+                let client = addr
+                    .parse::<SocketAddr>()
+                    .map_or(addr, |addr| addr.ip().to_string());
+                let host = self.get_authority()?;
+                Some(signer.sign(&client, &host, &self.get_path()?, now()))
+            }
+            false => None,
+        };
+        self.ctx
+            .set_http_request_header(signer.header(), value.as_deref())
+            .map_err(|s| Error::status("failed to set signature header", s))
+    }
+
+    /// What a request of `addr` to `host` on the route `found` has to solve.
+    /// A `preview` neither sets the rate limit headers nor records risk.
+    fn assess_route<'f>(
+        &self,
+        addr: SocketAddr,
+        host: &str,
+        found: &'f Found<'_, Setting>,
+        principal: &Option<(String, &PriorityClass)>,
+        rule_floor: u64,
+        preview: bool,
+    ) -> Result<Assessment<'f>, Error> {
+        let region_floor = match self.get_region_action(&found.regions)? {
+            Some(RegionAction::Block) => {
+                return Err(forbidden(
+                    Reason::RegionBlocked,
+                    "access from your region is blocked".to_string(),
+                ))
+            }
+            Some(RegionAction::MinDifficulty(level)) => *level,
+            None => 0,
+        };
 
+        let (subject, rate_limit) = match principal {
+            Some((name, class)) => (
+                format!("principal={}", name),
+                class.rate_limit.as_ref().unwrap_or(&found.rate_limit),
+            ),
+            None => (self.get_subject(found.rate_limit_key.as_ref(), addr)?, &found.rate_limit),
+        };
+        self.check_quota(&subject)?;
+        let (floor, shrink) = self
+            .plugin
+            .attack
+            .as_ref()
+            .and_then(AttackDetector::mitigation)
+            .unwrap_or((0, 1));
+        let (floor, shrink) = match self.plugin.health.as_ref().and_then(UpstreamHealth::shedding) {
+            Some((shed_floor, shed_shrink)) => (floor.max(shed_floor), shrink.max(shed_shrink)),
+            None => (floor, shrink),
+        };
         let key = format!(
-            "{}:{}:{}{}",
-            addr.ip(),
-            found.rate_limit.current_bucket(),
+            "{}{}:{}:{}",
             host,
-            found.pattern()
+            found.pattern(),
+            subject,
+            rate_limit.current_bucket_shrunk(shrink)
         );
         let counter = self
             .plugin
             .counter_bucket
             .get(&key)
             .map_err(|s| Error::other("failed to get counter", s))?;
-        let difficulty =
-            counter / found.rate_limit.requests_per_unit as u64 * self.plugin.difficulty;
-        let current = self.get_current_hash()?;
-        log::debug!(
-            "key: {}, counter: {}, difficulty: {}",
+        let multiplier = match &self.plugin.reputation {
+            Some(reputation) => reputation
+                .multiplier(&subject, now())
+                .map_err(|e| Error::other("failed to get reputation", e))?,
+            None => reputation::BASELINE,
+        };
+        // a worse reputation shrinks the allowance like it raises the difficulty
+        let limit = rate_limit.requests_per_bucket(shrink) * reputation::BASELINE / multiplier;
+        let limit = limit.max(1);
+        let weight = found.weight(self.get_method()?.as_str());
+        let crowded = match found.concurrency.as_ref().filter(|_| !preview) {
+            Some(concurrency) => {
+                let key = format!("{}{}:{}", host, found.pattern(), subject);
+                self.count_in_flight(key, concurrency)?
+            }
+            None => false,
+        };
+        if self.plugin.rate_limit_headers && !preview {
+            let remaining = limit.saturating_sub(counter + weight);
+            let reset = rate_limit.bucket_reset_shrunk(shrink);
+            self.response_headers
+                .push(RATE_LIMIT_LIMIT, limit.to_string());
+            self.response_headers
+                .push(RATE_LIMIT_REMAINING, remaining.to_string());
+            self.response_headers
+                .push(RATE_LIMIT_RESET, reset.to_string());
+        }
+        let over_limit = counter + weight > limit && !preview;
+        if let Some(reputation) = self.plugin.reputation.as_ref().filter(|_| over_limit) {
+            reputation
+                .penalize(&subject, now())
+                .map_err(|e| Error::other("failed to record reputation", e))?;
+        }
+        let risk_floor = match self.assess(addr, over_limit)? {
+            Verdict::Block => {
+                return Err(forbidden(
+                    Reason::RiskBlocked,
+                    "request blocked by risk score".to_string(),
+                ))
+            }
+            Verdict::Challenge(level) => level,
+            Verdict::Allow => 0,
+        };
+        let route = format!("{}{}", host, found.pattern());
+        let variant = experiment::assign(&found.variants, addr.ip(), &route);
+        let base = match (variant.and_then(|v| v.difficulty), &found.auto_tune) {
+            (Some(level), _) => level,
+            (None, Some(tune)) => self
+                .plugin
+                .tuner
+                .level(&route, tune, self.plugin.difficulty)
+                .map_err(|e| Error::other("failed to get tuned difficulty", e))?,
+            (None, None) => self.plugin.difficulty,
+        };
+        log::debug!("key: {}, counter: {}, multiplier: {}%", key, counter, multiplier);
+        // the budget spent with this request, below `limit` until it goes over
+        let spent = counter + weight - 1;
+        let difficulty = (spent / limit * base * multiplier / reputation::BASELINE)
+            .max(floor)
+            .max(region_floor)
+            .max(risk_floor)
+            .max(rule_floor)
+            .max(match crowded {
+                true => base,
+                false => 0,
+            });
+        let difficulty = match rollout::enforced(addr.ip(), found.rollout) {
+            true => difficulty,
+            false => {
+                log::debug!("{} is outside the rollout of {}", addr.ip(), route);
+                0
+            }
+        };
+        Ok(Assessment {
+            subject,
             key,
-            counter,
-            difficulty
-        );
+            weight,
+            route,
+            base,
+            difficulty,
+            variant,
+        })
+    }
 
-        if difficulty == 0 {
-            self.plugin.counter_bucket.inc(&key, 1);
+    /// Reject requests beyond the size limits of their route, the body is
+    /// checked as it arrives.
+    fn check_size_limits(&self, limits: &SizeLimits) -> Result<(), Error> {
+        let headers = self
+            .ctx
+            .get_http_request_headers()
+            .map_err(|s| Error::status("failed to get headers", s))?;
+        if let Some(message) = limits.check_headers(&headers) {
+            metrics::increment_counter("pow_waf.too_large", 1);
+            return Err(Error::Rejected(Rejection::TooLarge { message }));
+        }
+        if let Some(max) = limits.max_body_size {
+            self.ctx.scratch().insert(BodyLimit(max));
+        }
+        Ok(())
+    }
+
+    /// Count the request in until it is done, returns whether it is to be
+    /// challenged for going beyond the `max_in_flight` of its route.
+    fn count_in_flight(
+        &self,
+        key: String,
+        concurrency: &ConcurrencySetting,
+    ) -> Result<bool, Error> {
+        let before = self
+            .plugin
+            .in_flight
+            .acquire(&key, now())
+            .map_err(|e| Error::other("failed to count request in flight", e))?;
+        self.ctx.scratch().insert(Counted(key));
+        if before < concurrency.max_in_flight {
+            return Ok(false);
+        }
+        metrics::increment_counter("pow_waf.in_flight.exceeded", 1);
+        match concurrency.exceeded {
+            ConcurrencyAction::Reject => Err(Error::too_many_requests(
+                "too many requests in flight",
+                None,
+            )),
+            ConcurrencyAction::Challenge => Ok(true),
+        }
+    }
+
+    /// Answer a challenge endpoint with the challenge of its target path.
+    fn serve_challenge(
+        &self,
+        endpoint: &ChallengeEndpoint,
+        addr: SocketAddr,
+        host: &str,
+        path: &str,
+        exempt: bool,
+    ) -> Result<Response, Error> {
+        let target =
+            endpoint::target(path, &endpoint.target_param).unwrap_or_else(|| "/".to_string());
+        if !target.starts_with('/') {
+            return Err(forbidden(
+                Reason::InvalidRequest,
+                format!("invalid target path: {}", target),
+            ));
+        }
+        let principal = self.get_principal()?;
+        let skip_pow = exempt || principal.as_ref().is_some_and(|(_, class)| class.skip_pow);
+        let mut issued = None;
+        let level = match self.plugin.router.matches(host, &self.normalize_path(&target)?) {
+            Some(found) if !skip_pow => {
+                let assessment = self.assess_route(addr, host, &found, &principal, 0, true)?;
+                let tuned = assessment.variant.is_none_or(|v| v.difficulty.is_none());
+                if let Some(tune) = found.auto_tune.as_ref().filter(|_| tuned) {
+                    let route = format!("{}{}", host, found.pattern());
+                    issued = Some(tune.issue(&route, now_ms()));
+                }
+                assessment.difficulty
+            }
+            _ => 0,
+        };
+        let issued_at = now();
+        let challenge = endpoint::Challenge {
+            target,
+            current: self.get_current_hash()?,
+            difficulty: (level > 0).then(|| get_difficulty(level)),
+            level,
+            version: Version::LATEST,
+            issued_at,
+            expires_at: issued_at + CHALLENGE_TTL,
+        };
+        let mut response = Response::builder().header("cache-control", "no-store");
+        if let Some(issued) = issued.filter(|_| level > 0) {
+            response = response.header(tune::ISSUED_HEADER, &issued);
+        }
+        Ok(response.json(&challenge).build())
+    }
+
+    /// Decide on the request. Once a route is matched, its failure mode
+    /// replaces `failure_mode`.
+    async fn inspect(&self, failure_mode: &mut FailureMode) -> Result<(), Error> {
+        self.set_verified(false)?;
+        self.set_signature(false)?;
+        let addr = self.get_client_address()?;
+        let addr: SocketAddr = addr.parse().map_err(|s| {
+            forbidden(
+                Reason::InvalidRequest,
+                format!("invalid client address {}: {}", s, addr),
+            )
+        })?;
+        let path = self.get_path()?;
+        // the solution covers the path as sent, routes see it normalized
+        let route_path = self.normalize_path(&path)?;
+        let endpoint_path = route_path
+            .split_once('?')
+            .map_or(route_path.as_str(), |(path, _)| path);
+        if self.plugin.status_path.as_deref() == Some(endpoint_path) {
+            return Err(Error::response(admin::status(self.health())));
+        }
+        if let Some(page) = &self.plugin.challenge_page {
+            let asset = endpoint_path
+                .strip_prefix(page.assets_prefix.as_str())
+                .and_then(interstitial::asset);
+            if let Some(asset) = asset {
+                return Err(Error::response(asset));
+            }
+        }
+        if let Some(admin) = &self.plugin.admin {
+            if let Some(rest) = route_path.strip_prefix(admin.prefix.as_str()) {
+                let method = self.get_method()?;
+                let response = admin::handle(
+                    &self.plugin.access_list,
+                    &self.plugin.kill_switch,
+                    &self.plugin.counter_bucket,
+                    method.as_str(),
+                    rest,
+                );
+                return Err(Error::response(response));
+            }
+        }
+        match self.plugin.kill_switch.get(now()) {
+            Ok(Some(engagement)) => {
+                log::debug!("kill switch engaged ({:?}), skip checks", engagement.reason);
+                return Ok(());
+            }
+            Ok(None) => {}
+            Err(e) => log::warn!("failed to read kill switch, keep enforcing: {}", e),
+        }
+        let exempt = match self.plugin.access_list.check(addr.ip()) {
+            Some(Rule::Deny) => {
+                return Err(forbidden(
+                    Reason::AddressBlocked,
+                    format!("client address {} is blocked", addr.ip()),
+                ))
+            }
+            Some(Rule::Allow) => true,
+            None => self.plugin.whitelist.contains(addr.ip()),
+        };
+        let host = self.get_authority()?;
+        if let Some(response) = self.preflight(&host, endpoint_path, &route_path)? {
+            return Err(Error::response(response));
+        }
+        if let Some(endpoint) = self.plugin.challenge_endpoints.matches(&host, endpoint_path) {
+            let mut response = self.serve_challenge(&endpoint, addr, &host, &path, exempt)?;
+            self.add_cors(&mut response);
+            return Err(Error::response(response));
+        }
+        if exempt {
+            return Ok(());
+        }
+        self.check_honeypot(addr, endpoint_path)?;
+        self.check_penalty_box(addr).await?;
+        let rule_floor = self.check_rules(&route_path)?;
+        if self.is_verified_crawler(addr).await? {
             return Ok(());
         }
+        let principal = self.get_principal()?;
+        if let Some((name, class)) = &principal {
+            if class.skip_pow {
+                log::debug!("principal {} skips PoW", name);
+                let subject = format!("principal={}", name);
+                self.check_quota(&subject)?;
+                return self.consume_quota(&subject);
+            }
+        }
+        log::debug!("{} -> {}{}", addr, host, path);
+
+        let upgrade = self
+            .ctx
+            .is_upgrade_request()
+            .map_err(|s| Error::status("failed to get request headers", s))?;
+        let deny_upgrade = || {
+            forbidden(
+                Reason::UpgradeDenied,
+                format!("protocol upgrade of {}{} is not allowed", host, path),
+            )
+        };
+
+        let Some(found) = self.plugin.router.matches(&host, &route_path) else {
+            if upgrade && self.plugin.upgrade == UpgradePolicy::Deny {
+                return Err(deny_upgrade());
+            }
+            log::debug!("no matched route found, skip rate limit");
+            return Ok(());
+        };
+        if let Some(mode) = found.failure_mode {
+            *failure_mode = mode;
+        }
+        if let Some(limits) = &found.size_limits {
+            self.check_size_limits(limits)?;
+        }
+        self.take_action(&found, addr, &host, &path)?;
+        if upgrade {
+            match found.upgrade.unwrap_or(self.plugin.upgrade) {
+                UpgradePolicy::Allow => {
+                    log::debug!("upgrade of {}{} skips PoW", host, path);
+                    return Ok(());
+                }
+                UpgradePolicy::Deny => return Err(deny_upgrade()),
+                UpgradePolicy::Challenge => {}
+            }
+        }
+
+        let Assessment {
+            subject,
+            key,
+            weight,
+            route,
+            base,
+            difficulty,
+            variant,
+        } = self.assess_route(addr, &host, &found, &principal, rule_floor, false)?;
+        let current = self.get_current_hash()?;
+        match variant {
+            Some(variant) => log::debug!(
+                "{} -> {}: difficulty {}, variant {}",
+                subject,
+                route,
+                difficulty,
+                variant.name
+            ),
+            None => log::debug!("{} -> {}: difficulty {}", subject, route, difficulty),
+        }
 
+        if difficulty == 0 {
+            self.plugin.counter_bucket.inc(&key, weight);
+            return self.consume_quota(&subject);
+        }
+
+        if let Some(captcha) = self
+            .plugin
+            .captcha
+            .as_ref()
+            .filter(|captcha| captcha.applies(difficulty) || variant.is_some_and(|v| v.captcha))
+        {
+            experiment::count(variant, "challenged");
+            self.check_captcha(captcha, &subject, addr).await?;
+            experiment::count(variant, "solved");
+            self.set_verified(true)?;
+            self.plugin.counter_bucket.inc(&key, weight);
+            return self.consume_quota(&subject);
+        }
+
+        if self.has_pass_cookie(difficulty, &host, &subject)? {
+            log::debug!("{} holds a pass for difficulty {}", subject, difficulty);
+            self.plugin.counter_bucket.inc(&key, weight);
+            return self.consume_quota(&subject);
+        }
+
+        if let Some(attack) = &self.plugin.attack {
+            attack.record_challenged();
+        }
+        experiment::count(variant, "challenged");
         let target = get_difficulty(difficulty);
+        let tuned = variant.is_none_or(|v| v.difficulty.is_none());
+        let tune = found.auto_tune.as_ref().filter(|_| tuned);
+        if let Some(tune) = tune {
+            self.response_headers
+                .push(tune::ISSUED_HEADER, tune.issue(&route, now_ms()));
+        }
 
-        let make_body = |error: &str| too_many_request(current, difficulty, error.to_string());
+        let make_body = |reason: Reason, error: &str| {
+            too_many_request(reason, current, difficulty, error.to_string())
+        };
+        // A solution was submitted but is rejected, counted by the attack detector.
+        let fail = |reason: Reason, error: &str| {
+            if let Some(attack) = &self.plugin.attack {
+                attack.record_failed();
+            }
+            experiment::count(variant, "failed");
+            if let Some(scorer) = &self.plugin.scorer {
+                if let Err(e) = scorer.invalid_nonce(&addr.ip().to_string()) {
+                    log::warn!("failed to record risk: {}", e);
+                }
+            }
+            if let Some(penalty_box) = &self.plugin.penalty_box {
+                match penalty_box.record_failure(&addr.ip().to_string(), now()) {
+                    Ok(true) => {
+                        log::info!("{} put in the penalty box{}", addr.ip(), self.trace_tag());
+                        metrics::increment_counter("pow_waf.penalty_box.boxed", 1);
+                    }
+                    Ok(false) => {}
+                    Err(e) => log::warn!("failed to record invalid solution: {}", e),
+                }
+            }
+            make_body(reason, error)
+        };
 
-        let timestamp = self
-            .get_timestamp()
-            .map_err(|_| make_body("Missing X-PoW-Timestamp in header, or malformed"))?;
+        let timestamp = self.get_timestamp().map_err(|_| {
+            make_body(
+                Reason::ChallengeRequired,
+                "Missing X-PoW-Timestamp in header, or malformed",
+            )
+        })?;
 
-        if timestamp + 60 < now() {
-            return Err(make_body("timestamp expired"));
+        let version = self.get_solution(protocol::VERSION)?;
+        let version = Version::from_header(version.as_deref())
+            .map_err(|e| fail(Reason::InvalidSolution, &e.to_string()))?;
+        if version < self.plugin.min_protocol_version {
+            return Err(make_body(
+                Reason::ChallengeRequired,
+                &format!(
+                    "protocol version {} is no longer accepted, use {}",
+                    u8::from(version),
+                    u8::from(Version::LATEST)
+                ),
+            ));
+        }
+
+        if timestamp.saturating_add(CHALLENGE_TTL) < now() {
+            return Err(make_body(Reason::ChallengeExpired, "timestamp expired"));
+        }
+        if timestamp > now().saturating_add(MAX_CLOCK_SKEW) {
+            return Err(make_body(
+                Reason::ChallengeRequired,
+                "timestamp in the future",
+            ));
         }
 
         let nonce = self
-            .get_header("X-PoW-Nonce")
-            .map_err(|_| make_body("Missing X-PoW-Nonce in header"))?;
+            .get_solution(protocol::NONCE)?
+            .ok_or_else(|| make_body(Reason::ChallengeRequired, "Missing X-PoW-Nonce in header"))?;
 
-        let nonce = hex::decode(nonce)
-            .map_err(|s| make_body(&format!("X-PoW-Nonce must be a hex string: {}", s)))?;
+        let nonce = hex::decode(nonce).map_err(|s| {
+            fail(
+                Reason::InvalidSolution,
+                &format!("X-PoW-Nonce must be a hex string: {}", s),
+            )
+        })?;
 
         let last = self
-            .get_header("X-PoW-Base")
-            .map_err(|_| make_body("Missing X-PoW-Base in header"))?;
+            .get_solution(protocol::BASE)?
+            .ok_or_else(|| fail(Reason::InvalidSolution, "Missing X-PoW-Base in header"))?;
 
         if !self.plugin.btc.check_in_list(&last) {
-            return Err(make_body("X-PoW-Base are expired, please use current"));
+            return Err(fail(
+                Reason::ChallengeExpired,
+                "X-PoW-Base are expired, please use current",
+            ));
         }
 
-        let last: ByteArray32 = last
-            .as_str()
-            .try_into()
-            .map_err(|e| make_body(&format!("failed to parse X-PoW-Base hash: {}", e)))?;
-
-        let mut data = last.as_bytes().to_vec();
-        data.extend(timestamp.to_be_bytes());
-        data.extend(path.as_bytes());
+        let last: ByteArray32 = last.as_str().try_into().map_err(|e| {
+            fail(
+                Reason::InvalidSolution,
+                &format!("failed to parse X-PoW-Base hash: {}", e),
+            )
+        })?;
 
-        if !valid_nonce(&data, target, &nonce) {
-            return Err(make_body("Invalid nonce, maybe difficulty upgraded"));
+        let pre_image = version.pre_image(&last, timestamp, &host, &path);
+        if !protocol::is_solution(&pre_image, &target, &nonce) {
+            return Err(fail(
+                Reason::InvalidSolution,
+                "Invalid nonce, maybe difficulty upgraded",
+            ));
+        }
+        self.set_verified(true)?;
+        self.report(
+            "pow_waf.nonce_verified",
+            &[("pow_waf.difficulty", difficulty.to_string())],
+        );
+        experiment::count(variant, "solved");
+        if let Some(pass_cookie) = &self.plugin.pass_cookie {
+            let set_cookie = pass_cookie.issue(difficulty, &host, &subject, now());
+            self.response_headers.push("Set-Cookie", set_cookie);
+        }
+        if self.solution_cookie()?.is_some() {
+            self.response_headers
+                .push("Set-Cookie", interstitial::clear(&path));
+        }
+        if let Some(tune) = tune {
+            self.record_solve_time(tune, &route, base, difficulty)?;
         }
 
-        self.plugin.counter_bucket.inc(&key, 1);
-        Ok(())
+        self.plugin.counter_bucket.inc(&key, weight);
+        self.consume_quota(&subject)
     }
 }
 
-fn valid_nonce(data: &[u8], difficulty: ByteArray32, nonce: &[u8]) -> bool {
-    let mut hasher = sha2::Sha256::new();
-    hasher.update(data);
-    hasher.update(nonce);
-    let hash = hasher.finalize();
-    let slice: &[u8; 32] = &hash.into();
-    let target: ByteArray32 = slice.into();
-    target <= difficulty
+impl HttpHook for Hook {
+    fn filter_name() -> Option<&'static str> {
+        Some("PoW")
+    }
+
+    fn response_headers(&self) -> Vec<(String, String)> {
+        self.response_headers.take()
+    }
+
+    fn request_deadline(&self) -> Option<Duration> {
+        self.plugin.request_deadline
+    }
+
+    fn on_log(&self, info: &StreamInfo) {
+        if let Some(Counted(key)) = self.ctx.scratch().remove() {
+            if let Err(e) = self.plugin.in_flight.release(&key, now()) {
+                log::warn!("failed to count out request in flight: {}", e);
+            }
+        }
+        let Ok(Some(code)) = info.response_code() else {
+            return;
+        };
+        metrics::increment_counter(&format!("pow_waf.response.{}xx", code / 100), 1);
+        log::debug!(
+            "request done with {}, {} bytes in {:?}",
+            code,
+            info.bytes_sent().ok().flatten().unwrap_or_default(),
+            info.duration().ok().flatten().unwrap_or_default()
+        );
+    }
+
+    fn on_request_body(&self, body_size: usize, _end_of_stream: bool) -> Result<(), Response> {
+        let received = self.body_received.get() + body_size as u64;
+        self.body_received.set(received);
+        let Some(BodyLimit(max)) = self.ctx.scratch().get() else {
+            return Ok(());
+        };
+        if received <= max {
+            return Ok(());
+        }
+        metrics::increment_counter("pow_waf.too_large", 1);
+        let request_id = self
+            .ctx
+            .get_http_request_header("x-request-id")
+            .ok()
+            .flatten()
+            .unwrap_or_default();
+        let rejection = Rejection::TooLarge {
+            message: limits::body_too_large(max),
+        };
+        let mut response = rejection.render(
+            &self.plugin.responses,
+            self.plugin.error_format,
+            self.plugin.problem_type_base.as_deref(),
+            &request_id,
+        );
+        self.add_cors(&mut response);
+        Err(response)
+    }
+
+    async fn on_request_headers(
+        &self,
+        _num_headers: usize,
+        _end_of_stream: bool,
+    ) -> Result<(), impl Into<Response>> {
+        self.trace_request();
+        let mut failure_mode = self.plugin.failure_mode;
+        let ret = match self.inspect(&mut failure_mode).await {
+            Err(e) if e.is_infrastructure() => {
+                metrics::increment_counter(
+                    &format!("pow_waf.infra_failure.{}", failure_mode.as_str()),
+                    1,
+                );
+                match failure_mode {
+                    FailureMode::FailOpen => {
+                        log::warn!(
+                            "let request through on infrastructure error: {:?}{}",
+                            e,
+                            self.trace_tag()
+                        );
+                        Ok(())
+                    }
+                    FailureMode::FailClosed => Err(e),
+                }
+            }
+            Err(Error::Rejected(rejection)) => {
+                let request_id = self
+                    .ctx
+                    .get_http_request_header("x-request-id")
+                    .ok()
+                    .flatten()
+                    .unwrap_or_default();
+                let challenge = match &rejection {
+                    Rejection::TooManyRequests { reason, .. } => Some(("pow", *reason)),
+                    Rejection::Captcha { reason, .. } => Some(("captcha", *reason)),
+                    _ => None,
+                };
+                if let Some((kind, reason)) = challenge {
+                    self.report(
+                        "pow_waf.challenge_issued",
+                        &[
+                            ("pow_waf.challenge", kind.to_string()),
+                            ("pow_waf.reason", reason.as_str().to_string()),
+                        ],
+                    );
+                }
+                let throttled = matches!(
+                    rejection,
+                    Rejection::TooManyRequests { .. }
+                        | Rejection::QuotaExceeded { .. }
+                        | Rejection::Penalized { .. }
+                        | Rejection::Captcha { .. }
+                );
+                let retry_after = match &rejection {
+                    Rejection::QuotaExceeded { retry_after, .. }
+                    | Rejection::Penalized { retry_after, .. } => Some(retry_after.to_string()),
+                    _ => None,
+                };
+                let page = match &rejection {
+                    Rejection::TooManyRequests {
+                        current,
+                        difficulty,
+                        error,
+                        ..
+                    } if self.plugin.responses.too_many_requests.is_none() => self
+                        .challenge_page()
+                        .map(|setting| page::render(setting, *current, *difficulty, error)),
+                    _ => None,
+                };
+                let mut response = page.unwrap_or_else(|| {
+                    rejection.render(
+                        &self.plugin.responses,
+                        self.plugin.error_format,
+                        self.plugin.problem_type_base.as_deref(),
+                        &request_id,
+                    )
+                });
+                if throttled {
+                    let headers = self.response_headers.take();
+                    let retry_after = retry_after.or_else(|| {
+                        headers
+                            .iter()
+                            .find(|(k, _)| k == RATE_LIMIT_RESET)
+                            .map(|(_, reset)| reset.clone())
+                    });
+                    if let Some(retry_after) = retry_after {
+                        response
+                            .headers
+                            .push(("Retry-After".to_string(), retry_after));
+                    }
+                    response.headers.extend(headers);
+                }
+                self.add_cors(&mut response);
+                Err(Error::response(response))
+            }
+            Ok(()) => self.set_signature(true),
+            ret => ret,
+        };
+        let status = match &ret {
+            Ok(()) => "allow".to_string(),
+            Err(Error::Response(response)) => response.code.to_string(),
+            Err(_) => "error".to_string(),
+        };
+        self.report("pow_waf.decision", &[("pow_waf.decision", status)]);
+        ret
+    }
 }
 
 #[cfg(test)]
 mod test {
-    use crate::valid_nonce;
     use pow_types::bytearray32::ByteArray32;
+    use pow_types::protocol::is_solution;
 
     #[test]
     fn mine() {
@@ -414,7 +1775,7 @@ mod test {
 
         loop {
             let nonce = rand::random::<[u8; 8]>();
-            if valid_nonce(last.as_bytes(), difficulty, &nonce) {
+            if is_solution(last.as_bytes(), &difficulty, &nonce) {
                 print!("found nonce:");
                 print_hex(&nonce);
                 println!();
@@ -431,7 +1792,7 @@ mod test {
 
     #[test]
     fn decode() {
-        let nonce = "aaed9b41fcf6dc5";
+        let nonce = "0aaed9b41fcf6dc5";
         let hex = hex::decode(nonce).expect("invalid hex");
         print_hex(&hex);
     }
@@ -0,0 +1,89 @@
+//! Size limits of a request, see [`SizeLimits`].
+//!
+//! The headers and the `content-length` are checked once the route is
+//! matched, before PoW. The body is counted as it arrives and the request is
+//! answered with a `413` as soon as it goes beyond `max_body_size`, so chunked
+//! bodies are capped as well. Chunks arriving before the decision on the
+//! request are held back and checked once it is let through.
+//!
+//! Rejections are counted in `pow_waf.too_large`.
+//!
+//! [`SizeLimits`]: crate::config::SizeLimits
+
+use crate::config::SizeLimits;
+
+/// The `max_body_size` of the route of a request, kept in the scratch of its
+/// context for the body hook.
+#[derive(Debug, Clone, Copy)]
+pub struct BodyLimit(pub u64);
+
+impl SizeLimits {
+    /// Why `headers` are beyond the limits, if they are.
+    pub fn check_headers(&self, headers: &[(String, String)]) -> Option<String> {
+        if let Some(max) = self.max_header_count.filter(|max| headers.len() > *max) {
+            return Some(format!("more than {} headers", max));
+        }
+        if let Some(max) = self.max_header_size {
+            let large = headers.iter().find(|(k, v)| k.len() + v.len() > max);
+            if let Some((name, _)) = large {
+                return Some(format!("header {} is larger than {} bytes", name, max));
+            }
+        }
+        let max = self.max_body_size?;
+        let length = headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case("content-length"))
+            .and_then(|(_, v)| v.trim().parse::<u64>().ok());
+        match length.is_some_and(|length| length > max) {
+            true => Some(body_too_large(max)),
+            false => None,
+        }
+    }
+}
+
+pub fn body_too_large(max: u64) -> String {
+    format!("body is larger than {} bytes", max)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn headers(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn header_limits() {
+        let limits = SizeLimits {
+            max_header_count: Some(3),
+            max_header_size: Some(24),
+            max_body_size: Some(1_024),
+        };
+        let request = headers(&[(":method", "POST"), (":path", "/upload")]);
+        assert_eq!(limits.check_headers(&request), None);
+
+        let mut many = request.clone();
+        many.extend(headers(&[("a", "1"), ("b", "2")]));
+        assert_eq!(
+            limits.check_headers(&many).as_deref(),
+            Some("more than 3 headers")
+        );
+
+        let cookie = headers(&[("cookie", "session=0123456789abcdef")]);
+        assert_eq!(
+            limits.check_headers(&cookie).as_deref(),
+            Some("header cookie is larger than 24 bytes")
+        );
+
+        let upload = headers(&[("Content-Length", "4096")]);
+        assert_eq!(
+            limits.check_headers(&upload).as_deref(),
+            Some("body is larger than 1024 bytes")
+        );
+        assert_eq!(SizeLimits::default().check_headers(&many), None);
+    }
+}
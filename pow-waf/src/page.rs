@@ -0,0 +1,235 @@
+//! HTML challenge pages for browsers, see [`ChallengePageSetting`].
+//!
+//! A browser navigating to a challenged page would otherwise be shown the
+//! JSON body of the 429. Instead, GET requests preferring HTML get a page
+//! which loads the pow-mine package, solves the challenge in Web Workers,
+//! fetches the page again with the solution headers and shows the result.
+//! With a [`pass_cookie`](crate::cookie) the following navigations go through
+//! unchallenged. With `submit: cookie` the page reloads instead, see
+//! [`crate::interstitial`].
+//!
+//! Operator `too_many_requests` templates win over the page, CAPTCHA
+//! challenges and other rejections are not affected.
+
+use pow_runtime::pseudo::Method;
+use pow_runtime::response::Response;
+use pow_types::bytearray32::ByteArray32;
+use pow_types::protocol::Version;
+
+use crate::config::{ChallengePageSetting, Negotiation, Submission};
+use crate::interstitial::SOLUTION_COOKIE;
+use crate::template::escape_html;
+use crate::CHALLENGE_TTL;
+
+/// How much the `Accept` header likes `mime`, by its most specific range.
+fn quality(accept: &str, mime: &str) -> f32 {
+    let (kind, _) = mime.split_once('/').unwrap_or((mime, ""));
+    let mut best: Option<(u8, f32)> = None;
+    for range in accept.split(',') {
+        let mut params = range.split(';');
+        let media = params.next().unwrap_or_default().trim();
+        let specificity = match media.split_once('/') {
+            _ if media.eq_ignore_ascii_case(mime) => 2,
+            Some((k, "*")) if k.eq_ignore_ascii_case(kind) => 1,
+            Some(("*", "*")) => 0,
+            _ => continue,
+        };
+        let q = params
+            .filter_map(|param| param.trim().strip_prefix("q="))
+            .find_map(|q| q.parse().ok())
+            .unwrap_or(1.0);
+        if best.is_none_or(|(s, _)| specificity > s) {
+            best = Some((specificity, q));
+        }
+    }
+    best.map_or(0.0, |(_, q)| q)
+}
+
+/// Whether the request with these headers is answered with the page.
+pub fn negotiate(
+    setting: &ChallengePageSetting,
+    method: &Method,
+    accept: Option<&str>,
+    fetch_dest: Option<&str>,
+) -> bool {
+    let html = accept
+        .is_some_and(|accept| quality(accept, "text/html") > quality(accept, "application/json"));
+    let navigation = match setting.negotiation {
+        Negotiation::Accept => true,
+        // browsers predating fetch metadata are judged by `Accept` alone
+        Negotiation::Navigate => fetch_dest.is_none_or(|dest| dest == "document"),
+    };
+    *method == Method::Get && html && navigation
+}
+
+/// Inline scripts must not close their element.
+fn script_json(value: &serde_json::Value) -> String {
+    value.to_string().replace("</", "<\\/")
+}
+
+/// Show the page fetched with the solution headers.
+const FETCH: &str = r#"  const response = await fetch(location.href, {
+    headers: solution,
+    credentials: "same-origin",
+  });
+  const page = await response.text();
+  document.open();
+  document.write(page);
+  document.close();
+"#;
+
+/// Reload with the solution in the cookie, in the order of
+/// [`crate::interstitial::field`].
+const COOKIE: &str = r#"  const fields = ["X-PoW-Version", "X-PoW-Timestamp", "X-PoW-Base", "X-PoW-Nonce"];
+  const value = fields.map((name) => solution[name]).join(".");
+  const secure = location.protocol === "https:" ? "; Secure" : "";
+  document.cookie = `{name}=${value}; Max-Age={ttl}; Path=${location.pathname}`
+    + `; SameSite=Lax${secure}`;
+  location.reload();
+"#;
+
+pub fn render(
+    setting: &ChallengePageSetting,
+    current: ByteArray32,
+    difficulty: ByteArray32,
+    error: &str,
+) -> Response {
+    let challenge = serde_json::json!({
+        "current": current,
+        "difficulty": difficulty,
+        "version": Version::LATEST,
+    });
+    // checked on configure
+    let loader = script_json(&serde_json::Value::from(setting.loader().unwrap_or_default()));
+    let options = script_json(&serde_json::json!({ "script": setting.worker() }));
+    let submit = match setting.submit {
+        Submission::Fetch => FETCH.to_string(),
+        Submission::Cookie => COOKIE
+            .replace("{name}", SOLUTION_COOKIE)
+            .replace("{ttl}", &CHALLENGE_TTL.to_string()),
+    };
+    let body = format!(
+        r#"<!doctype html>
+<html>
+<head>
+<meta charset="utf-8">
+<meta name="robots" content="noindex">
+<title>{title}</title>
+</head>
+<body>
+<h1>{title}</h1>
+<p id="status">This takes a few seconds, the page loads by itself.</p>
+<noscript><p>Enable JavaScript to continue.</p></noscript>
+<!-- {error} -->
+<script type="module">
+const status = document.getElementById("status");
+try {{
+  const {{ default: init, startup, mine_parallel }} = await import({loader});
+  await init();
+  startup();
+  const challenge = {challenge};
+  const mining = mine_parallel({{
+    ...challenge,
+    host: location.host,
+    path: location.pathname + location.search,
+    timestamp: Math.floor(Date.now() / 1000),
+  }}, {options});
+  const solution = await mining.result();
+{submit}}} catch (e) {{
+  status.textContent = "Verification failed, reload the page to try again: " + e;
+}}
+</script>
+</body>
+</html>
+"#,
+        title = escape_html(&setting.title),
+        error = escape_html(error).replace("--", "- -"),
+        loader = loader,
+        challenge = script_json(&challenge),
+        options = options,
+        submit = submit,
+    );
+    Response::builder()
+        .status(429)
+        .header("Cache-Control", "no-store")
+        .html(body)
+        .build()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn setting(negotiation: &str) -> ChallengePageSetting {
+        serde_yaml::from_str(&format!(
+            "loader: /__pow/pkg/pow_mine.js\nnegotiation: {}",
+            negotiation
+        ))
+        .unwrap()
+    }
+
+    fn body(setting: &ChallengePageSetting) -> String {
+        let response = render(
+            setting,
+            ByteArray32::from(&[0; 32]),
+            ByteArray32::from(&[0; 32]),
+            "Missing X-PoW-Nonce in header",
+        );
+        String::from_utf8(response.body.unwrap()).unwrap()
+    }
+
+    #[test]
+    fn accept_quality() {
+        let browser = "text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8";
+        assert_eq!(quality(browser, "text/html"), 1.0);
+        assert_eq!(quality(browser, "application/json"), 0.8);
+        assert_eq!(quality("application/json, text/*;q=0.5", "text/html"), 0.5);
+        assert_eq!(quality("*/*", "text/html"), 1.0);
+        assert_eq!(quality("image/png", "text/html"), 0.0);
+    }
+
+    #[test]
+    fn negotiation() {
+        let browser = Some("text/html,application/xhtml+xml,*/*;q=0.8");
+        let accept = setting("accept");
+        assert!(negotiate(&accept, &Method::Get, browser, Some("iframe")));
+        assert!(!negotiate(&accept, &Method::Post, browser, None));
+        assert!(!negotiate(&accept, &Method::Get, Some("*/*"), None));
+        assert!(!negotiate(&accept, &Method::Get, Some("application/json"), None));
+        assert!(!negotiate(&accept, &Method::Get, None, None));
+
+        let navigate = setting("navigate");
+        assert!(negotiate(&navigate, &Method::Get, browser, Some("document")));
+        assert!(negotiate(&navigate, &Method::Get, browser, None));
+        assert!(!negotiate(&navigate, &Method::Get, browser, Some("iframe")));
+    }
+
+    #[test]
+    fn page() {
+        let response = render(
+            &setting("accept"),
+            ByteArray32::from(&[0; 32]),
+            ByteArray32::from(&[0; 32]),
+            "Missing X-PoW-Nonce in header -->",
+        );
+        assert_eq!(response.code, 429);
+        let body = String::from_utf8(response.body.unwrap()).unwrap();
+        assert!(body.contains(r#"await import("/__pow/pkg/pow_mine.js")"#));
+        assert!(body.contains(r#""version":2"#));
+        assert!(body.contains("<!-- Missing X-PoW-Nonce in header - -&gt; -->"));
+    }
+
+    #[test]
+    fn cookie_submission() {
+        let fetch = body(&setting("accept"));
+        assert!(fetch.contains("document.write(page)"));
+        assert!(!fetch.contains("document.cookie"));
+
+        let cookie: ChallengePageSetting =
+            serde_yaml::from_str("loader: /pkg/pow_mine.js\nsubmit: cookie").unwrap();
+        let page = body(&cookie);
+        assert!(page.contains("document.cookie = `__pow_solution=${value}; Max-Age=60;"));
+        assert!(page.contains("location.reload()"));
+        assert!(!page.contains("document.write"));
+    }
+}
@@ -0,0 +1,61 @@
+//! Penalty box for clients that keep submitting invalid solutions.
+//!
+//! Rejected solutions are counted per client address for `window` seconds,
+//! the one reaching `failures` puts the client in the box for `duration`
+//! seconds. Until then its requests are answered with a `429` right away,
+//! whatever they carry, or after holding them for `tarpit` milliseconds to
+//! slow down clients that retry immediately. Both counters and boxes are
+//! kept in shared data, so a client is boxed on every worker.
+
+use std::time::Duration;
+
+use pow_runtime::kv_store::{Error, ExpiringKVStore};
+
+use crate::config::PenaltyBoxSetting;
+
+pub struct PenaltyBox {
+    setting: PenaltyBoxSetting,
+    /// Rejected solutions within the window.
+    failures: ExpiringKVStore<u32>,
+    /// Until when a client is boxed, in seconds since the epoch.
+    boxed: ExpiringKVStore<u64>,
+}
+
+impl PenaltyBox {
+    pub fn new(context_id: u32, setting: PenaltyBoxSetting) -> Self {
+        Self {
+            setting,
+            failures: ExpiringKVStore::new(context_id, "penalty:failures"),
+            boxed: ExpiringKVStore::new(context_id, "penalty:boxed"),
+        }
+    }
+
+    /// How long to hold the answer to a boxed client back.
+    pub fn tarpit(&self) -> Option<Duration> {
+        self.setting.tarpit.map(Duration::from_millis)
+    }
+
+    /// Until when `client` is boxed, `None` if it is not.
+    pub fn until(&self, client: &str, now: u64) -> Result<Option<u64>, Error> {
+        Ok(self.boxed.get(client)?.filter(|until| *until > now))
+    }
+
+    /// A solution of `client` was rejected, returns whether it was boxed.
+    pub fn record_failure(&self, client: &str, now: u64) -> Result<bool, Error> {
+        let failures = self
+            .failures
+            .update(client, |failures| Ok::<_, Error>(failures.unwrap_or(0) + 1))?;
+        if failures == 1 {
+            let window = Duration::from_secs(self.setting.window);
+            self.failures.enqueue_expires(client, window)?;
+        }
+        if failures < self.setting.failures {
+            return Ok(false);
+        }
+        self.failures.remove(client)?;
+        let duration = self.setting.duration;
+        self.boxed
+            .put(client, &(now + duration), Duration::from_secs(duration))?;
+        Ok(true)
+    }
+}
@@ -0,0 +1,276 @@
+//! Daily and monthly request quotas per rate limit subject.
+//!
+//! Unlike rate limits, quotas are counted in shared data per subject across
+//! all routes: a request counts once it is let through, e.g. after its PoW
+//! solution was verified, and a subject with an exhausted quota is rejected
+//! before any challenge. Periods follow UTC calendar days and months.
+//!
+//! Every worker keeps the requests it counted since its last export and,
+//! with an `export` endpoint, posts them as a list of [`Usage`] records, so
+//! billing pipelines get every request exactly once without reading shared
+//! data. Records of a failed export are kept for the next one.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::time::Duration;
+
+use pow_runtime::filter_state::WeakState;
+use pow_runtime::http::Client;
+use pow_runtime::kv_store::{Error, ExpiringKVStore};
+use pow_runtime::spawn_local;
+use pow_runtime::timeout::sleep;
+use serde::{Deserialize, Serialize};
+
+use crate::config::{QuotaExport, QuotaSetting};
+use crate::Inner;
+
+const DAY: u64 = 86400;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Period {
+    Day,
+    Month,
+}
+
+/// Days since the epoch of a proleptic Gregorian date.
+fn days_from_civil(year: u64, month: u64, day: u64) -> u64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year / 400;
+    let yoe = year - era * 400;
+    let doy = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+/// Year and month of a day since the epoch.
+fn civil_from_days(days: u64) -> (u64, u64) {
+    let z = days + 719468;
+    let era = z / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+    (year, month)
+}
+
+impl Period {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Period::Day => "daily",
+            Period::Month => "monthly",
+        }
+    }
+
+    /// The period containing `now`, `2024-05-17` or `2024-05`.
+    pub fn label(&self, now: u64) -> String {
+        let days = now / DAY;
+        let (year, month) = civil_from_days(days);
+        match self {
+            Period::Day => {
+                let day = days - days_from_civil(year, month, 1) + 1;
+                format!("{:04}-{:02}-{:02}", year, month, day)
+            }
+            Period::Month => format!("{:04}-{:02}", year, month),
+        }
+    }
+
+    /// Seconds from `now` until the period ends.
+    pub fn remaining_secs(&self, now: u64) -> u64 {
+        let end = match self {
+            Period::Day => (now / DAY + 1) * DAY,
+            Period::Month => {
+                let (year, month) = civil_from_days(now / DAY);
+                let (year, month) = if month == 12 {
+                    (year + 1, 1)
+                } else {
+                    (year, month + 1)
+                };
+                days_from_civil(year, month, 1) * DAY
+            }
+        };
+        end - now
+    }
+}
+
+/// Requests a subject was let through within a period.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Usage {
+    pub subject: String,
+    /// The label of the period, see [`Period::label`].
+    pub period: String,
+    pub requests: u64,
+}
+
+pub struct Quota {
+    store: ExpiringKVStore<u64>,
+    daily: Option<u64>,
+    monthly: Option<u64>,
+    /// Requests counted by this worker since its last export, by subject
+    /// and period label.
+    unexported: Option<RefCell<HashMap<(String, String), u64>>>,
+}
+
+impl Quota {
+    pub fn new(context_id: u32, setting: &QuotaSetting) -> Self {
+        Self {
+            store: ExpiringKVStore::new(context_id, "quota"),
+            daily: setting.daily,
+            monthly: setting.monthly,
+            unexported: setting.export.as_ref().map(|_| RefCell::default()),
+        }
+    }
+
+    fn limits(&self) -> impl Iterator<Item = (Period, u64)> {
+        [(Period::Day, self.daily), (Period::Month, self.monthly)]
+            .into_iter()
+            .filter_map(|(period, limit)| limit.map(|limit| (period, limit)))
+    }
+
+    /// The period `subject` has used up its quota of, if any.
+    pub fn exhausted(&self, subject: &str, now: u64) -> Result<Option<Period>, Error> {
+        for (period, limit) in self.limits() {
+            let key = format!("{}:{}", subject, period.label(now));
+            if self.store.get(&key)?.unwrap_or(0) >= limit {
+                return Ok(Some(period));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Count a request of `subject`, the least quota it has left afterwards.
+    pub fn consume(&self, subject: &str, now: u64) -> Result<u64, Error> {
+        let mut remaining = u64::MAX;
+        for (period, limit) in self.limits() {
+            let label = period.label(now);
+            let key = format!("{}:{}", subject, label);
+            let mut first = false;
+            let used = self.store.update(&key, |used| {
+                first = used.is_none();
+                Ok::<_, Error>(used.unwrap_or(0) + 1)
+            })?;
+            if first {
+                // kept a day past the period for late reads
+                let ttl = period.remaining_secs(now) + DAY;
+                self.store.enqueue_expires(&key, Duration::from_secs(ttl))?;
+            }
+            remaining = remaining.min(limit.saturating_sub(used));
+        }
+        if let Some(unexported) = &self.unexported {
+            let label = Period::Day.label(now);
+            *unexported
+                .borrow_mut()
+                .entry((subject.to_string(), label))
+                .or_default() += 1;
+        }
+        Ok(remaining)
+    }
+
+    fn take_unexported(&self) -> Vec<Usage> {
+        let Some(unexported) = &self.unexported else {
+            return vec![];
+        };
+        unexported
+            .take()
+            .into_iter()
+            .map(|((subject, period), requests)| Usage {
+                subject,
+                period,
+                requests,
+            })
+            .collect()
+    }
+
+    fn restore(&self, usage: Vec<Usage>) {
+        let Some(unexported) = &self.unexported else {
+            return;
+        };
+        let mut unexported = unexported.borrow_mut();
+        for usage in usage {
+            *unexported.entry((usage.subject, usage.period)).or_default() += usage.requests;
+        }
+    }
+}
+
+/// Export the usage counted by this worker until the epoch behind `plugin`
+/// is torn down.
+pub(crate) fn spawn_export(plugin: WeakState<Inner>, export: QuotaExport) {
+    let client = Client::new(&export.upstream, &export.authority);
+    spawn_local(async move {
+        loop {
+            sleep(Duration::from_secs(export.interval)).await;
+            let Some(plugin) = plugin.upgrade() else {
+                log::info!("exit quota export loop");
+                break;
+            };
+            let Some(quota) = &plugin.quota else {
+                break;
+            };
+            let usage = quota.take_unexported();
+            if usage.is_empty() {
+                continue;
+            }
+            let ret = client
+                .post_json::<_, serde::de::IgnoredAny>(&export.path, &usage)
+                .await;
+            if let Err(e) = ret {
+                log::warn!("failed to export {} usage records: {}", usage.len(), e);
+                quota.restore(usage);
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn periods() {
+        // 2024-02-29T12:00:00Z
+        let now = 1709208000;
+        assert_eq!(Period::Day.label(now), "2024-02-29");
+        assert_eq!(Period::Month.label(now), "2024-02");
+        assert_eq!(Period::Day.remaining_secs(now), 12 * 3600);
+        assert_eq!(Period::Month.remaining_secs(now), 12 * 3600);
+
+        // 2023-12-31T23:59:59Z
+        let now = 1704067199;
+        assert_eq!(Period::Day.label(now), "2023-12-31");
+        assert_eq!(Period::Month.remaining_secs(now), 1);
+        assert_eq!(Period::Month.label(now + 1), "2024-01");
+        assert_eq!(Period::Month.remaining_secs(now + 1), 31 * DAY);
+
+        assert_eq!(Period::Day.label(0), "1970-01-01");
+    }
+
+    #[test]
+    fn unexported() {
+        let setting: QuotaSetting = serde_yaml::from_str(
+            r#"
+daily: 1000
+export:
+  upstream: billing
+  authority: billing.internal
+"#,
+        )
+        .unwrap();
+        let quota = Quota::new(1, &setting);
+        let usage = Usage {
+            subject: "principal=billing".to_string(),
+            period: "2024-02-29".to_string(),
+            requests: 3,
+        };
+        quota.restore(vec![usage.clone()]);
+        quota.restore(vec![usage.clone()]);
+        assert_eq!(
+            quota.take_unexported(),
+            vec![Usage {
+                requests: 6,
+                ..usage
+            }]
+        );
+        assert_eq!(quota.take_unexported(), vec![]);
+    }
+}
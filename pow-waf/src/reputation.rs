@@ -0,0 +1,111 @@
+//! Per client reputation that outlives rate limit windows.
+//!
+//! Every request beyond a rate limit adds `penalty` percent to the difficulty
+//! multiplier of its rate limit subject, which decays by half every
+//! `half_life` seconds. A subject with a multiplier of 200 percent gets half
+//! the allowance of its route and twice its difficulty, so clients that keep
+//! exceeding their limits stay challenged harder in the next windows while
+//! well-behaved ones return to the baseline of 100 percent.
+//!
+//! Reputations are kept in shared data for at most `max_clients` subjects,
+//! the least recently penalized are forgotten first.
+
+use pow_runtime::kv_store::{Capacity, Error, KVStore};
+use serde::{Deserialize, Serialize};
+
+use crate::config::ReputationSetting;
+
+/// The multiplier of a subject without a reputation, in percent.
+pub const BASELINE: u64 = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+struct Record {
+    /// Percent above the baseline, as of `at`.
+    points: f64,
+    /// Unix timestamp in seconds.
+    at: u64,
+}
+
+impl Record {
+    fn decayed(&self, now: u64, half_life: u64) -> f64 {
+        let elapsed = now.saturating_sub(self.at) as f64;
+        self.points * 0.5f64.powf(elapsed / half_life.max(1) as f64)
+    }
+}
+
+pub struct Reputation {
+    store: KVStore<Record>,
+    setting: ReputationSetting,
+}
+
+impl Reputation {
+    pub fn new(context_id: u32, setting: ReputationSetting) -> Self {
+        let capacity = Capacity {
+            max_entries: setting.max_clients,
+            evicted_metric: "pow_waf.reputation.evicted".to_string(),
+        };
+        Self {
+            store: KVStore::indexed(context_id, "reputation").capped(capacity),
+            setting,
+        }
+    }
+
+    /// A request of `subject` went beyond the rate limit of its route.
+    pub fn penalize(&self, subject: &str, now: u64) -> Result<(), Error> {
+        let half_life = self.setting.half_life;
+        let penalty = self.setting.penalty as f64;
+        self.store.update(subject, |record| {
+            let points = record.map_or(0.0, |record| record.decayed(now, half_life));
+            Ok::<_, Error>(Record {
+                points: points + penalty,
+                at: now,
+            })
+        })?;
+        Ok(())
+    }
+
+    /// The difficulty multiplier of `subject` in percent, from [`BASELINE`]
+    /// up to `max_multiplier`.
+    pub fn multiplier(&self, subject: &str, now: u64) -> Result<u64, Error> {
+        let points = self
+            .store
+            .get(subject)?
+            .map_or(0.0, |record| record.decayed(now, self.setting.half_life));
+        Ok(self.cap(points))
+    }
+
+    fn cap(&self, points: f64) -> u64 {
+        (BASELINE + points as u64).min(self.setting.max_multiplier.max(BASELINE))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn decays_by_half() {
+        let record = Record {
+            points: 80.0,
+            at: 1_000,
+        };
+        assert_eq!(record.decayed(1_000, 600), 80.0);
+        assert_eq!(record.decayed(1_600, 600), 40.0);
+        assert_eq!(record.decayed(2_200, 600), 20.0);
+        assert!(record.decayed(1_000 + 600 * 20, 600) < 0.001);
+        // a clock going back leaves the points as they are
+        assert_eq!(record.decayed(900, 600), 80.0);
+    }
+
+    #[test]
+    fn capped_multiplier() {
+        let setting: ReputationSetting = serde_yaml::from_str("max_multiplier: 400").unwrap();
+        let reputation = Reputation {
+            store: KVStore::new(1, "reputation"),
+            setting,
+        };
+        assert_eq!(reputation.cap(0.0), BASELINE);
+        assert_eq!(reputation.cap(150.7), 250);
+        assert_eq!(reputation.cap(1_000.0), 400);
+    }
+}
@@ -0,0 +1,69 @@
+//! Enforcing PoW on a share of clients first, see [`Setting::rollout`].
+//!
+//! Every client address falls into one of 100 buckets by a hash of it, a
+//! route with `rollout: 5` challenges the clients of buckets 0 to 4. The
+//! assignment is stable, so clients do not flap between challenged and not,
+//! and ramping the percentage up only adds clients. IPv6 clients are bucketed
+//! by their /64, as they rotate addresses within it.
+//!
+//! [`Setting::rollout`]: crate::config::Setting::rollout
+
+use std::net::IpAddr;
+
+/// FNV-1a, stable across workers and releases unlike the std hasher.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// A stable hash of `ip` salted with `salt`, so assignments keyed by
+/// different salts are independent of each other.
+pub(crate) fn hash(ip: IpAddr, salt: &str) -> u64 {
+    let mut bytes = salt.as_bytes().to_vec();
+    match ip {
+        IpAddr::V4(ip) => bytes.extend_from_slice(&ip.octets()),
+        IpAddr::V6(ip) => bytes.extend_from_slice(&ip.octets()[..8]),
+    }
+    fnv1a(&bytes)
+}
+
+/// The bucket of `ip`, below 100.
+pub fn bucket(ip: IpAddr) -> u64 {
+    hash(ip, "") % 100
+}
+
+/// Whether PoW is enforced on `ip` at `percent`, everyone without one.
+pub fn enforced(ip: IpAddr, percent: Option<u8>) -> bool {
+    percent.is_none_or(|percent| bucket(ip) < u64::from(percent))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn stable_and_monotonic() {
+        let ips: Vec<IpAddr> = (0..=255u8)
+            .flat_map(|a| (0..40u8).map(move |b| IpAddr::from([10, a, b, 1])))
+            .collect();
+        let share = |percent| {
+            ips.iter()
+                .filter(|ip| enforced(**ip, Some(percent)))
+                .count()
+        };
+        assert_eq!(share(0), 0);
+        assert_eq!(share(100), ips.len());
+        let five = share(5) as f64 / ips.len() as f64;
+        assert!((0.03..0.07).contains(&five), "{}", five);
+        for ip in &ips {
+            assert!(!enforced(*ip, Some(5)) || enforced(*ip, Some(50)));
+        }
+        assert!(ips.iter().all(|ip| enforced(*ip, None)));
+
+        let a: IpAddr = "2001:db8:1:2::1".parse().unwrap();
+        let b: IpAddr = "2001:db8:1:2:ffff::7".parse().unwrap();
+        assert_eq!(bucket(a), bucket(b));
+        assert_eq!(bucket(a), bucket(a));
+    }
+}
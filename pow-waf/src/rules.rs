@@ -0,0 +1,271 @@
+//! Deny rules over the path, query string and headers of requests.
+//!
+//! Rules are checked in order before rate limits and PoW, every match is
+//! counted: the first `block` rule rejects the request, `min_difficulty`
+//! rules raise the difficulty of the request and `log` rules only count.
+//! Paths and query strings are percent-decoded first, so encoded payloads
+//! match the same patterns as plain ones.
+
+use percent_encoding::percent_decode_str;
+use regex::Regex;
+
+use crate::config::{RuleAction, RuleSetting, RuleTarget, Signature};
+
+/// Tautologies, stacked queries, `UNION SELECT`, comments and time based probes.
+const SQLI: &str = r#"(?i)(\bunion\b.+\bselect\b|\bselect\b.+\bfrom\b|['"]\s*(or|and)\b.+=|['"]\s*(--|#|/\*)|;\s*(drop|delete|insert|update|shutdown)\b|\b(sleep|benchmark|pg_sleep)\s*\()"#;
+/// Script tags, `javascript:` URLs and inline event handlers.
+const XSS: &str =
+    r#"(?i)(<\s*/?\s*script\b|javascript\s*:|<[^>]*\bon[a-z]+\s*=|document\.cookie|\beval\s*\()"#;
+
+impl Signature {
+    fn pattern(&self) -> &'static str {
+        match self {
+            Signature::Sqli => SQLI,
+            Signature::Xss => XSS,
+        }
+    }
+}
+
+impl RuleAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RuleAction::Block => "block",
+            RuleAction::MinDifficulty(_) => "min_difficulty",
+            RuleAction::Log => "log",
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("rule {0}: set either pattern or signature")]
+    Pattern(String),
+    #[error("rule {0}: {1}")]
+    Regex(String, regex::Error),
+}
+
+struct Rule {
+    name: String,
+    targets: Vec<RuleTarget>,
+    regex: Regex,
+    action: RuleAction,
+}
+
+pub struct Rules(Vec<Rule>);
+
+/// The rules a request matched, up to the first `block` rule.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Outcome<'a> {
+    pub matched: Vec<(&'a str, RuleAction)>,
+}
+
+impl Outcome<'_> {
+    /// The rule rejecting the request, if any.
+    pub fn blocked_by(&self) -> Option<&str> {
+        self.matched
+            .last()
+            .filter(|(_, action)| *action == RuleAction::Block)
+            .map(|(name, _)| *name)
+    }
+
+    /// The highest level required by a `min_difficulty` rule, 0 without one.
+    pub fn min_difficulty(&self) -> u64 {
+        self.matched
+            .iter()
+            .filter_map(|(_, action)| match action {
+                RuleAction::MinDifficulty(level) => Some(*level),
+                _ => None,
+            })
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+fn decode(s: &str) -> String {
+    percent_decode_str(s).decode_utf8_lossy().into_owned()
+}
+
+impl Rules {
+    pub fn new(settings: Vec<RuleSetting>) -> Result<Self, Error> {
+        let mut rules = Vec::with_capacity(settings.len());
+        for setting in settings {
+            let pattern = match (&setting.pattern, setting.signature) {
+                (Some(pattern), None) => pattern.as_str(),
+                (None, Some(signature)) => signature.pattern(),
+                _ => return Err(Error::Pattern(setting.name)),
+            };
+            let regex = match Regex::new(pattern) {
+                Ok(regex) => regex,
+                Err(e) => return Err(Error::Regex(setting.name, e)),
+            };
+            rules.push(Rule {
+                name: setting.name,
+                targets: setting.targets,
+                regex,
+                action: setting.action,
+            });
+        }
+        Ok(Self(rules))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Check a request for `path`, including its query string, reading
+    /// headers with `header`.
+    pub fn check<E>(
+        &self,
+        path: &str,
+        mut header: impl FnMut(&str) -> Result<Option<String>, E>,
+    ) -> Result<Outcome<'_>, E> {
+        let (path, query) = path.split_once('?').unwrap_or((path, ""));
+        let path = decode(path);
+        let query = decode(&query.replace('+', " "));
+        let mut outcome = Outcome::default();
+        for rule in &self.0 {
+            let mut matched = false;
+            for target in &rule.targets {
+                matched = match target {
+                    RuleTarget::Path => rule.regex.is_match(&path),
+                    RuleTarget::Query => rule.regex.is_match(&query),
+                    RuleTarget::Header(name) => {
+                        header(name)?.is_some_and(|value| rule.regex.is_match(&value))
+                    }
+                };
+                if matched {
+                    break;
+                }
+            }
+            if !matched {
+                continue;
+            }
+            outcome.matched.push((&rule.name, rule.action));
+            if rule.action == RuleAction::Block {
+                break;
+            }
+        }
+        Ok(outcome)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn rules() -> Rules {
+        let settings: Vec<RuleSetting> = serde_yaml::from_str(
+            r#"
+- name: scanner
+  targets: [{header: user-agent}]
+  pattern: (?i)sqlmap|nikto
+  action: log
+- name: wp-admin
+  targets: [path]
+  pattern: ^/wp-(admin|login)
+  action: {min_difficulty: 5000}
+- name: sqli
+  targets: [path, query]
+  signature: sqli
+- name: xss
+  targets: [query, {header: referer}]
+  signature: xss
+"#,
+        )
+        .expect("failed to parse rules");
+        Rules::new(settings).expect("failed to compile rules")
+    }
+
+    fn check<'a>(rules: &'a Rules, path: &str, headers: &[(&str, &str)]) -> Outcome<'a> {
+        rules
+            .check(path, |name| -> Result<_, ()> {
+                Ok(headers
+                    .iter()
+                    .find(|(k, _)| *k == name)
+                    .map(|(_, v)| v.to_string()))
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn actions() {
+        let rules = rules();
+        assert_eq!(
+            check(&rules, "/search?q=shoes+red", &[]),
+            Outcome::default()
+        );
+
+        let outcome = check(
+            &rules,
+            "/wp-login.php?user=admin%27%20OR%201%3D1",
+            &[("user-agent", "sqlmap/1.7")],
+        );
+        assert_eq!(
+            outcome.matched,
+            vec![
+                ("scanner", RuleAction::Log),
+                ("wp-admin", RuleAction::MinDifficulty(5000)),
+                ("sqli", RuleAction::Block),
+            ]
+        );
+        assert_eq!(outcome.blocked_by(), Some("sqli"));
+        assert_eq!(outcome.min_difficulty(), 5000);
+
+        let outcome = check(&rules, "/wp-admin/", &[]);
+        assert_eq!(outcome.blocked_by(), None);
+        assert_eq!(outcome.min_difficulty(), 5000);
+    }
+
+    #[test]
+    fn signatures() {
+        let rules = rules();
+        let blocked = |path: &str, referer: &str| {
+            check(&rules, path, &[("referer", referer)])
+                .blocked_by()
+                .map(str::to_string)
+        };
+        assert_eq!(
+            blocked("/items?id=1%20UNION%20SELECT%20password", ""),
+            Some("sqli".into())
+        );
+        assert_eq!(
+            blocked("/items?id=1;%20DROP%20TABLE%20users", ""),
+            Some("sqli".into())
+        );
+        assert_eq!(
+            blocked("/q?s=%3Cscript%3Ealert(1)%3C/script%3E", ""),
+            Some("xss".into())
+        );
+        assert_eq!(blocked("/", "javascript:alert(1)"), Some("xss".into()));
+        assert_eq!(
+            blocked("/", r#"<img src=x onerror="alert(1)">"#),
+            Some("xss".into())
+        );
+        assert_eq!(blocked("/select?from=home&order=price", ""), None);
+        assert_eq!(blocked("/colors?pick=red&union=eu", ""), None);
+        assert_eq!(blocked("/blog/o'reilly-and-sons", ""), None);
+    }
+
+    #[test]
+    fn invalid_rules() {
+        let setting = |pattern: Option<&str>, signature| RuleSetting {
+            name: "broken".to_string(),
+            targets: vec![RuleTarget::Path],
+            pattern: pattern.map(str::to_string),
+            signature,
+            action: RuleAction::Block,
+        };
+        assert!(matches!(
+            Rules::new(vec![setting(None, None)]),
+            Err(Error::Pattern(_))
+        ));
+        assert!(matches!(
+            Rules::new(vec![setting(Some("x"), Some(Signature::Xss))]),
+            Err(Error::Pattern(_))
+        ));
+        assert!(matches!(
+            Rules::new(vec![setting(Some("("), None)]),
+            Err(Error::Regex(..))
+        ));
+    }
+}
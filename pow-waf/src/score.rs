@@ -0,0 +1,205 @@
+//! Per client risk scores.
+//!
+//! A score adds up two kinds of signals: what the client did within the last
+//! `window` seconds, i.e. requests beyond a rate limit and rejected
+//! solutions, kept per client address in shared data, and what the request
+//! itself looks like, i.e. the headers it lacks, the region it comes from and
+//! the TLS fingerprint of its client.
+//! Thresholds turn the score into a [`Verdict`]: allowed requests are
+//! challenged by their rate as usual, challenged ones at least at the
+//! `challenge_difficulty` and blocked ones are rejected right away.
+
+use std::time::Duration;
+
+use pow_runtime::kv_store::{Error, ExpiringKVStore};
+
+use crate::config::ScoringSetting;
+use crate::fingerprint::Fingerprint;
+use crate::geo::Geo;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    Allow,
+    /// Challenge at least at this difficulty level.
+    Challenge(u64),
+    Block,
+}
+
+impl Verdict {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Verdict::Allow => "allow",
+            Verdict::Challenge(_) => "challenge",
+            Verdict::Block => "block",
+        }
+    }
+}
+
+pub struct Scorer {
+    store: ExpiringKVStore<u64>,
+    setting: ScoringSetting,
+    /// Level of challenged clients without a `challenge_difficulty`.
+    difficulty: u64,
+}
+
+impl Scorer {
+    pub fn new(context_id: u32, setting: ScoringSetting, difficulty: u64) -> Self {
+        Self {
+            store: ExpiringKVStore::new(context_id, "score"),
+            setting,
+            difficulty,
+        }
+    }
+
+    /// Whether the regions of clients are scored.
+    pub fn needs_geo(&self) -> bool {
+        !self.setting.regions.is_empty()
+    }
+
+    /// Whether the TLS fingerprints of clients are scored.
+    pub fn needs_fingerprint(&self) -> bool {
+        !self.setting.fingerprints.is_empty()
+    }
+
+    /// Points of the request itself, `missing` tells whether it lacks a
+    /// header.
+    pub fn request_points<E>(
+        &self,
+        mut missing: impl FnMut(&str) -> Result<bool, E>,
+        geo: Option<&Geo>,
+        fingerprint: Option<&Fingerprint>,
+    ) -> Result<u64, E> {
+        let mut points = 0;
+        for (header, weight) in &self.setting.missing_headers {
+            if missing(header)? {
+                points += weight;
+            }
+        }
+        let region = geo.and_then(|geo| {
+            self.setting
+                .regions
+                .iter()
+                .find(|region| geo.within(&region.countries, &region.asns))
+        });
+        let fingerprint = fingerprint.and_then(|fingerprint| {
+            self.setting
+                .fingerprints
+                .iter()
+                .find(|score| fingerprint.within(&score.ja3, &score.ja4))
+        });
+        Ok(points
+            + region.map_or(0, |region| region.points)
+            + fingerprint.map_or(0, |fingerprint| fingerprint.points))
+    }
+
+    /// Points `client` earned within the window.
+    pub fn history(&self, client: &str) -> Result<u64, Error> {
+        Ok(self.store.get(client)?.unwrap_or(0))
+    }
+
+    fn record(&self, client: &str, points: u64) -> Result<(), Error> {
+        if points == 0 {
+            return Ok(());
+        }
+        let mut first = false;
+        self.store.update(client, |history| {
+            first = history.is_none();
+            Ok::<_, Error>(history.unwrap_or(0) + points)
+        })?;
+        if first {
+            let window = Duration::from_secs(self.setting.window);
+            self.store.enqueue_expires(client, window)?;
+        }
+        Ok(())
+    }
+
+    /// A request of `client` went beyond the rate limit of its route.
+    pub fn rate_overage(&self, client: &str) -> Result<(), Error> {
+        self.record(client, self.setting.rate_overage)
+    }
+
+    /// A solution of `client` was rejected.
+    pub fn invalid_nonce(&self, client: &str) -> Result<(), Error> {
+        self.record(client, self.setting.invalid_nonce)
+    }
+
+    pub fn verdict(&self, score: u64) -> Verdict {
+        let reached = |threshold: Option<u64>| threshold.is_some_and(|t| score >= t);
+        if reached(self.setting.block) {
+            Verdict::Block
+        } else if reached(self.setting.challenge) {
+            let level = self.setting.challenge_difficulty.unwrap_or(self.difficulty);
+            Verdict::Challenge(level)
+        } else {
+            Verdict::Allow
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn scorer() -> Scorer {
+        let setting: ScoringSetting = serde_yaml::from_str(
+            r#"
+missing_headers:
+  user-agent: 20
+  accept-language: 5
+regions:
+- countries: [kp]
+  points: 50
+- asns: [4134]
+  points: 10
+fingerprints:
+- ja3: [e7d705a3286e19ea42f587b344ee6865]
+  points: 30
+challenge: 25
+block: 60
+"#,
+        )
+        .expect("failed to parse setting");
+        Scorer::new(1, setting, 1000)
+    }
+
+    #[test]
+    fn request_points() {
+        let scorer = scorer();
+        let lacks = |missing: &'static [&'static str]| {
+            move |name: &str| -> Result<bool, ()> { Ok(missing.contains(&name)) }
+        };
+        assert_eq!(scorer.request_points(lacks(&[]), None, None), Ok(0));
+        assert_eq!(
+            scorer.request_points(lacks(&["user-agent", "accept-language"]), None, None),
+            Ok(25)
+        );
+        let geo = Geo {
+            country: Some("KP".to_string()),
+            asn: Some(4134),
+        };
+        assert_eq!(scorer.request_points(lacks(&[]), Some(&geo), None), Ok(50));
+        assert!(scorer.needs_geo());
+
+        let fingerprint = Fingerprint {
+            ja3: Some("e7d705a3286e19ea42f587b344ee6865".to_string()),
+            ja4: None,
+        };
+        assert_eq!(
+            scorer.request_points(lacks(&["user-agent"]), None, Some(&fingerprint)),
+            Ok(50)
+        );
+        assert_eq!(
+            scorer.request_points(lacks(&[]), None, Some(&Fingerprint::default())),
+            Ok(0)
+        );
+        assert!(scorer.needs_fingerprint());
+    }
+
+    #[test]
+    fn verdicts() {
+        let scorer = scorer();
+        assert_eq!(scorer.verdict(0), Verdict::Allow);
+        assert_eq!(scorer.verdict(25), Verdict::Challenge(1000));
+        assert_eq!(scorer.verdict(60), Verdict::Block);
+    }
+}
@@ -0,0 +1,250 @@
+//! Connection-level PoW for raw TCP upstreams.
+//!
+//! The first bytes a client sends on a new connection must be a handshake
+//! frame carrying a solution for the current challenge, which the client
+//! fetches out-of-band (e.g. from a 429 response of an HTTP route protected by
+//! the same filter). The frame is stripped before the data reaches the
+//! upstream, otherwise the connection is closed. While the kill switch is
+//! engaged connections go through unchecked, frame included.
+//!
+//! ```text
+//! +-----------+-----------+----------------+-------------+
+//! | len (u16) | base (32) | timestamp (u64)| nonce (1-32)|
+//! +-----------+-----------+----------------+-------------+
+//! ```
+//!
+//! All integers are big-endian, `len` counts the bytes following it. The hash
+//! pre-image is `base || timestamp`, the same layout as the HTTP challenge
+//! without a path.
+
+use std::fmt::Display;
+use std::net::{IpAddr, SocketAddr};
+
+use pow_runtime::filter_state::State;
+use pow_runtime::stream::{StreamCtx, StreamFlow, StreamHook};
+use pow_types::bytearray32::ByteArray32;
+use pow_types::protocol::is_solution;
+
+use crate::access_list::Rule;
+use crate::{get_difficulty, now, Inner, CHALLENGE_TTL, MAX_CLOCK_SKEW};
+
+const LEN_SIZE: usize = 2;
+const BASE_SIZE: usize = 32;
+const TIMESTAMP_SIZE: usize = 8;
+const MAX_NONCE_SIZE: usize = 32;
+
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+pub enum HandshakeError {
+    #[error("handshake frame length {0} out of range")]
+    InvalidLength(usize),
+    #[error("connection closed before handshake completed")]
+    Incomplete,
+    #[error("failed to get client address: {0}")]
+    ClientAddress(String),
+    #[error("client address {0} is blocked")]
+    Blocked(IpAddr),
+    #[error("base hash is expired, please use current")]
+    ExpiredBase,
+    #[error("timestamp expired")]
+    ExpiredTimestamp,
+    #[error("timestamp in the future")]
+    FutureTimestamp,
+    #[error("invalid nonce")]
+    InvalidNonce,
+    #[error("failed to strip handshake frame: {0}")]
+    Strip(String),
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Frame<'a> {
+    pub base: ByteArray32,
+    pub timestamp: u64,
+    pub nonce: &'a [u8],
+}
+
+impl<'a> Frame<'a> {
+    /// Parse a frame from the start of `data`, returning the frame and the
+    /// number of bytes it occupies, or `None` if more data is needed.
+    pub fn parse(data: &'a [u8]) -> Result<Option<(Self, usize)>, HandshakeError> {
+        let Some(len) = data.get(..LEN_SIZE) else {
+            return Ok(None);
+        };
+        let len = u16::from_be_bytes([len[0], len[1]]) as usize;
+        if len <= BASE_SIZE + TIMESTAMP_SIZE || len > BASE_SIZE + TIMESTAMP_SIZE + MAX_NONCE_SIZE {
+            return Err(HandshakeError::InvalidLength(len));
+        }
+        let Some(payload) = data.get(LEN_SIZE..LEN_SIZE + len) else {
+            return Ok(None);
+        };
+
+        let (base, rest) = payload.split_at(BASE_SIZE);
+        let (timestamp, nonce) = rest.split_at(TIMESTAMP_SIZE);
+        let base: &[u8; BASE_SIZE] = base.try_into().expect("length checked above");
+        let timestamp = u64::from_be_bytes(timestamp.try_into().expect("length checked above"));
+        let frame = Frame {
+            base: base.into(),
+            timestamp,
+            nonce,
+        };
+        Ok(Some((frame, LEN_SIZE + len)))
+    }
+
+    fn pre_image(&self) -> Vec<u8> {
+        let mut data = self.base.as_bytes().to_vec();
+        data.extend(self.timestamp.to_be_bytes());
+        data
+    }
+}
+
+/// Solutions are accepted for [`CHALLENGE_TTL`] after the timestamp of
+/// their frame, which the client chooses.
+fn check_timestamp(timestamp: u64, now: u64) -> Result<(), HandshakeError> {
+    if timestamp.saturating_add(CHALLENGE_TTL) < now {
+        return Err(HandshakeError::ExpiredTimestamp);
+    }
+    if timestamp > now.saturating_add(MAX_CLOCK_SKEW) {
+        return Err(HandshakeError::FutureTimestamp);
+    }
+    Ok(())
+}
+
+pub struct TcpHook {
+    pub(crate) ctx: StreamCtx,
+    pub(crate) plugin: State<Inner>,
+}
+
+impl TcpHook {
+    /// Whether the client skips the handshake. As for HTTP requests, a deny
+    /// entry of the access list closes the connection, even without a
+    /// handshake difficulty, and an allow entry or the whitelist exempts it.
+    fn is_exempt(&self) -> Result<bool, HandshakeError> {
+        let addr = self
+            .ctx
+            .get_client_address()
+            .map_err(|s| HandshakeError::ClientAddress(format!("{:?}", s)))?
+            .ok_or_else(|| HandshakeError::ClientAddress("missing".to_string()))?;
+        let addr: SocketAddr = addr
+            .parse()
+            .map_err(|e| HandshakeError::ClientAddress(format!("{}: {}", e, addr)))?;
+        match self.plugin.access_list.check(addr.ip()) {
+            Some(Rule::Deny) => Err(HandshakeError::Blocked(addr.ip())),
+            Some(Rule::Allow) => Ok(true),
+            None => Ok(self.plugin.whitelist.contains(addr.ip())),
+        }
+    }
+
+    fn verify(&self, frame: &Frame, difficulty: u64) -> Result<(), HandshakeError> {
+        check_timestamp(frame.timestamp, now())?;
+        if !self.plugin.btc.check_in_list(&format!("{:x}", frame.base)) {
+            return Err(HandshakeError::ExpiredBase);
+        }
+        if !is_solution(&frame.pre_image(), &get_difficulty(difficulty), frame.nonce) {
+            return Err(HandshakeError::InvalidNonce);
+        }
+        Ok(())
+    }
+}
+
+impl StreamHook for TcpHook {
+    async fn on_downstream_data(
+        &self,
+        data: Vec<u8>,
+        end_of_stream: bool,
+    ) -> Result<StreamFlow, impl Display> {
+        match self.plugin.kill_switch.get(now()) {
+            Ok(Some(engagement)) => {
+                log::debug!(
+                    "kill switch engaged ({:?}), skip handshake",
+                    engagement.reason
+                );
+                return Ok(StreamFlow::Passthrough);
+            }
+            Ok(None) => {}
+            Err(e) => log::warn!("failed to read kill switch, keep enforcing: {}", e),
+        }
+        let difficulty = self.plugin.tcp.as_ref().map_or(0, |tcp| tcp.difficulty);
+        if self.is_exempt()? || difficulty == 0 {
+            return Ok(StreamFlow::Passthrough);
+        }
+
+        let Some((frame, size)) = Frame::parse(&data)? else {
+            if end_of_stream {
+                return Err(HandshakeError::Incomplete);
+            }
+            return Ok(StreamFlow::NeedMore);
+        };
+
+        self.verify(&frame, difficulty)?;
+        log::debug!("tcp handshake passed, strip {} bytes", size);
+        // the upstream must never see the frame, close rather than pass it on
+        self.ctx
+            .set_downstream_data(0, size, &[])
+            .map_err(|s| HandshakeError::Strip(format!("{:?}", s)))?;
+        Ok(StreamFlow::Passthrough)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{check_timestamp, Frame, HandshakeError};
+
+    fn frame(nonce: &[u8]) -> Vec<u8> {
+        let len = (32 + 8 + nonce.len()) as u16;
+        let mut data = len.to_be_bytes().to_vec();
+        data.extend([0xab; 32]);
+        data.extend(1_700_000_000u64.to_be_bytes());
+        data.extend(nonce);
+        data
+    }
+
+    #[test]
+    fn parse_complete_frame() {
+        let mut data = frame(&[1, 2, 3]);
+        data.extend(b"EHLO example.com\r\n");
+        let (frame, size) = Frame::parse(&data)
+            .expect("valid frame")
+            .expect("complete frame");
+        assert_eq!(size, 2 + 32 + 8 + 3);
+        assert_eq!(frame.base.as_bytes(), &[0xab; 32]);
+        assert_eq!(frame.timestamp, 1_700_000_000);
+        assert_eq!(frame.nonce, &[1, 2, 3]);
+    }
+
+    #[test]
+    fn parse_partial_frame() {
+        let data = frame(&[1, 2, 3]);
+        assert_eq!(Frame::parse(&data[..1]), Ok(None));
+        assert_eq!(Frame::parse(&data[..20]), Ok(None));
+    }
+
+    #[test]
+    fn parse_invalid_length() {
+        assert_eq!(
+            Frame::parse(&[0, 40]),
+            Err(HandshakeError::InvalidLength(40))
+        );
+        assert_eq!(
+            Frame::parse(&frame(&[0; 33])),
+            Err(HandshakeError::InvalidLength(73))
+        );
+    }
+
+    #[test]
+    fn timestamp_window() {
+        let now = 1_700_000_000;
+        assert_eq!(check_timestamp(now - 60, now), Ok(()));
+        assert_eq!(check_timestamp(now + 5, now), Ok(()));
+        assert_eq!(
+            check_timestamp(now - 61, now),
+            Err(HandshakeError::ExpiredTimestamp)
+        );
+        assert_eq!(
+            check_timestamp(now + 6, now),
+            Err(HandshakeError::FutureTimestamp)
+        );
+        assert_eq!(
+            check_timestamp(u64::MAX, now),
+            Err(HandshakeError::FutureTimestamp)
+        );
+    }
+}
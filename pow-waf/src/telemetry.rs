@@ -0,0 +1,287 @@
+//! Trace context of requests and span events of the decision on them.
+//!
+//! The trace a request belongs to is read from its `traceparent` or B3
+//! headers (see [`TraceContext`]), and the lines the filter logs about the
+//! request, e.g. `honeypot:` lines, end with ` trace=<trace id>`.
+//!
+//! With `tracing` configured, the steps of the decision on a traced request
+//! are reported to an OTLP/HTTP collector as spans, children of the span of
+//! the caller:
+//!
+//! - `pow_waf.challenge_issued`, the request is answered with a challenge,
+//! - `pow_waf.nonce_verified`, the request carries a valid solution,
+//! - `pow_waf.decision`, the request is let through or rejected.
+//!
+//! A span starts when the filter receives the request and ends with its step.
+//! Requests without trace headers or not sampled by the caller are not
+//! reported. Like the usage of [`quota`](crate::quota), every worker keeps
+//! its spans and posts them as OTLP JSON every `interval` seconds. Beyond
+//! `max_spans` the oldest are dropped, counted in `pow_waf.spans.dropped`.
+//! Spans of a failed export are kept for the next one.
+
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::fmt::Display;
+use std::time::Duration;
+
+use pow_runtime::filter_state::WeakState;
+use pow_runtime::http::Client;
+use pow_runtime::metrics;
+use pow_runtime::spawn_local;
+use pow_runtime::timeout::sleep;
+use pow_runtime::trace::{self, TraceContext};
+use serde::Serialize;
+use serde_json::json;
+
+use crate::config::TracingSetting;
+use crate::Inner;
+
+/// `SPAN_KIND_INTERNAL` of OTLP.
+const KIND_INTERNAL: u8 = 1;
+
+/// The trace of a request and when the filter received it, kept in the
+/// scratch of its context.
+#[derive(Debug, Clone)]
+pub struct Traced {
+    pub context: TraceContext,
+    /// Unix timestamp in milliseconds.
+    pub started: u64,
+}
+
+/// Ends the log lines of a request with its trace id, if it is traced.
+pub struct TraceTag(pub Option<String>);
+
+impl Display for TraceTag {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.0 {
+            Some(trace_id) => write!(f, " trace={}", trace_id),
+            None => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Value {
+    string_value: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+struct Attribute {
+    key: String,
+    value: Value,
+}
+
+impl Attribute {
+    fn new(key: &str, value: &str) -> Self {
+        Self {
+            key: key.to_string(),
+            value: Value {
+                string_value: value.to_string(),
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Span {
+    trace_id: String,
+    span_id: String,
+    parent_span_id: String,
+    name: String,
+    kind: u8,
+    start_time_unix_nano: String,
+    end_time_unix_nano: String,
+    attributes: Vec<Attribute>,
+}
+
+pub struct Spans {
+    setting: TracingSetting,
+    /// Spans recorded by this worker since its last export, oldest first.
+    pending: RefCell<VecDeque<Span>>,
+    dropped: Cell<u64>,
+}
+
+impl Spans {
+    pub fn new(setting: TracingSetting) -> Self {
+        Self {
+            setting,
+            pending: RefCell::default(),
+            dropped: Cell::new(0),
+        }
+    }
+
+    /// Report the step `name` of `traced`, reached at `now` in milliseconds.
+    pub fn record(&self, traced: &Traced, name: &str, now: u64, attributes: &[(&str, String)]) {
+        if traced.context.sampled == Some(false) {
+            return;
+        }
+        let nanos = |ms: u64| (ms as u128 * 1_000_000).to_string();
+        let span = Span {
+            trace_id: traced.context.trace_id.clone(),
+            span_id: trace::span_id(now),
+            parent_span_id: traced.context.span_id.clone(),
+            name: name.to_string(),
+            kind: KIND_INTERNAL,
+            start_time_unix_nano: nanos(traced.started),
+            end_time_unix_nano: nanos(now.max(traced.started)),
+            attributes: attributes
+                .iter()
+                .map(|(key, value)| Attribute::new(key, value))
+                .collect(),
+        };
+        self.pending.borrow_mut().push_back(span);
+        self.trim();
+    }
+
+    fn trim(&self) {
+        let mut pending = self.pending.borrow_mut();
+        while pending.len() > self.setting.max_spans {
+            pending.pop_front();
+            self.dropped.set(self.dropped.get() + 1);
+        }
+    }
+
+    fn take(&self) -> Vec<Span> {
+        self.pending.take().into()
+    }
+
+    fn restore(&self, spans: Vec<Span>) {
+        {
+            let mut pending = self.pending.borrow_mut();
+            for span in spans.into_iter().rev() {
+                pending.push_front(span);
+            }
+        }
+        self.trim();
+    }
+
+    /// An OTLP `ExportTraceServiceRequest` of `spans`.
+    fn payload(&self, spans: &[Span]) -> serde_json::Value {
+        json!({
+            "resourceSpans": [{
+                "resource": {
+                    "attributes": [Attribute::new("service.name", &self.setting.service_name)],
+                },
+                "scopeSpans": [{
+                    "scope": { "name": "pow-waf" },
+                    "spans": spans,
+                }],
+            }],
+        })
+    }
+}
+
+/// Export the spans recorded by this worker until the epoch behind `plugin`
+/// is torn down.
+pub(crate) fn spawn_export(plugin: WeakState<Inner>, setting: TracingSetting) {
+    let client = Client::new(&setting.upstream, &setting.authority);
+    spawn_local(async move {
+        loop {
+            sleep(Duration::from_secs(setting.interval)).await;
+            let Some(plugin) = plugin.upgrade() else {
+                log::info!("exit span export loop");
+                break;
+            };
+            let Some(spans) = &plugin.spans else {
+                break;
+            };
+            let dropped = spans.dropped.take();
+            if dropped > 0 {
+                metrics::increment_counter("pow_waf.spans.dropped", dropped as i64);
+            }
+            let pending = spans.take();
+            if pending.is_empty() {
+                continue;
+            }
+            let ret = client
+                .post_json::<_, serde::de::IgnoredAny>(&setting.path, &spans.payload(&pending))
+                .await;
+            if let Err(e) = ret {
+                log::warn!("failed to export {} spans: {}", pending.len(), e);
+                spans.restore(pending);
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn spans() -> Spans {
+        Spans::new(
+            serde_yaml::from_str(
+                r#"
+upstream: otel-collector
+authority: otel-collector:4318
+max_spans: 2
+"#,
+            )
+            .unwrap(),
+        )
+    }
+
+    fn traced(sampled: Option<bool>) -> Traced {
+        Traced {
+            context: TraceContext {
+                trace_id: "4bf92f3577b34da6a3ce929d0e0e4736".to_string(),
+                span_id: "00f067aa0ba902b7".to_string(),
+                sampled,
+            },
+            started: 1_000,
+        }
+    }
+
+    #[test]
+    fn trace_tag() {
+        let tag = TraceTag(Some("4bf92f3577b34da6a3ce929d0e0e4736".to_string()));
+        assert_eq!(tag.to_string(), " trace=4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(TraceTag(None).to_string(), "");
+    }
+
+    #[test]
+    fn record_and_export() {
+        let spans = spans();
+        assert_eq!(spans.setting.path, "/v1/traces");
+        spans.record(&traced(Some(false)), "pow_waf.decision", 1_005, &[]);
+        assert!(spans.take().is_empty());
+
+        let attributes = [("pow_waf.difficulty", "1000".to_string())];
+        spans.record(&traced(None), "pow_waf.nonce_verified", 1_004, &attributes);
+        let recorded = spans.take();
+        let span = &recorded[0];
+        assert_eq!(span.parent_span_id, "00f067aa0ba902b7");
+        assert_ne!(span.span_id, span.parent_span_id);
+        assert_eq!(span.start_time_unix_nano, "1000000000");
+        assert_eq!(span.end_time_unix_nano, "1004000000");
+
+        let payload = spans.payload(&recorded);
+        let resource = &payload["resourceSpans"][0];
+        assert_eq!(
+            resource["resource"]["attributes"][0]["value"]["stringValue"],
+            "pow-waf"
+        );
+        let span = &resource["scopeSpans"][0]["spans"][0];
+        assert_eq!(span["traceId"], "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(span["name"], "pow_waf.nonce_verified");
+        assert_eq!(span["attributes"][0]["key"], "pow_waf.difficulty");
+        assert_eq!(span["attributes"][0]["value"]["stringValue"], "1000");
+    }
+
+    #[test]
+    fn keep_newest() {
+        let spans = spans();
+        for name in ["a", "b", "c"] {
+            spans.record(&traced(Some(true)), name, 1_001, &[]);
+        }
+        assert_eq!(spans.dropped.get(), 1);
+        let failed = spans.take();
+        spans.record(&traced(Some(true)), "d", 1_002, &[]);
+        spans.restore(failed);
+        let names: Vec<_> = spans.take().into_iter().map(|span| span.name).collect();
+        assert_eq!(names, ["c", "d"]);
+        assert_eq!(spans.dropped.get(), 2);
+    }
+}
@@ -0,0 +1,555 @@
+//! Rejection responses: operator supplied [`ResponseTemplate`]s, or else the
+//! built-in JSON bodies or RFC 9457 problem details depending on the
+//! configured [`ErrorFormat`]. Problem `type`s end with the [`Reason`] code.
+//!
+//! Templates may use these placeholders:
+//!
+//! | placeholder        | 429 | 403 |
+//! |--------------------|-----|-----|
+//! | `{{reason}}`       | yes | yes |
+//! | `{{message}}`      | yes | yes |
+//! | `{{request_id}}`   | yes | yes |
+//! | `{{error}}`        | yes |     |
+//! | `{{current}}`      | yes |     |
+//! | `{{difficulty}}`   | yes |     |
+//!
+//! Exhausted quotas are rendered with the `too_many_requests` template
+//! without a challenge, `{{error}}` tells the period. CAPTCHA challenges
+//! use the `captcha` template, or else `too_many_requests`, with
+//! `{{site_key}}` and `{{token_header}}` instead of `{{current}}` and
+//! `{{difficulty}}`.
+//!
+//! Requests beyond the size limits of their route are answered with a `413`
+//! rendered with the `payload_too_large` template, with `{{message}}`.
+//!
+//! Values are HTML-escaped when the content type is HTML, unknown
+//! placeholders render empty.
+
+use pow_runtime::error::IntoResponse;
+use pow_runtime::response::{ErrorFormat, Problem, Response};
+use pow_types::bytearray32::ByteArray32;
+use pow_types::protocol::Version;
+
+use crate::config::{ResponseTemplate, ResponseTemplates};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reason {
+    /// No solution was submitted.
+    ChallengeRequired,
+    /// The solution is for a timestamp or block hash that is too old.
+    ChallengeExpired,
+    InvalidSolution,
+    /// The request lacks what the filter needs to inspect it.
+    InvalidRequest,
+    AddressBlocked,
+    RegionBlocked,
+    /// The route does not allow switching protocols.
+    UpgradeDenied,
+    QuotaExceeded,
+    /// The risk score of the client reached the `block` threshold.
+    RiskBlocked,
+    /// A WAF rule with the `block` action matched.
+    RuleMatched,
+    /// The client has to solve a CAPTCHA instead of a PoW challenge.
+    CaptchaRequired,
+    /// The CAPTCHA provider rejected the token.
+    CaptchaFailed,
+    /// The client is in the penalty box for its rejected solutions.
+    Penalized,
+    /// The request is beyond the size limits of its route.
+    RequestTooLarge,
+}
+
+impl Reason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Reason::ChallengeRequired => "challenge-required",
+            Reason::ChallengeExpired => "challenge-expired",
+            Reason::InvalidSolution => "invalid-solution",
+            Reason::InvalidRequest => "invalid-request",
+            Reason::AddressBlocked => "address-blocked",
+            Reason::RegionBlocked => "region-blocked",
+            Reason::UpgradeDenied => "upgrade-denied",
+            Reason::QuotaExceeded => "quota-exceeded",
+            Reason::RiskBlocked => "risk-blocked",
+            Reason::RuleMatched => "rule-matched",
+            Reason::CaptchaRequired => "captcha-required",
+            Reason::CaptchaFailed => "captcha-failed",
+            Reason::Penalized => "penalized",
+            Reason::RequestTooLarge => "request-too-large",
+        }
+    }
+
+    fn title(&self) -> &'static str {
+        match self {
+            Reason::ChallengeRequired => "Proof of work required",
+            Reason::ChallengeExpired => "Proof of work challenge expired",
+            Reason::InvalidSolution => "Invalid proof of work solution",
+            Reason::InvalidRequest => "Invalid request",
+            Reason::AddressBlocked => "Client address blocked",
+            Reason::RegionBlocked => "Region blocked",
+            Reason::UpgradeDenied => "Protocol upgrade not allowed",
+            Reason::QuotaExceeded => "Quota exceeded",
+            Reason::RiskBlocked => "Risk score too high",
+            Reason::RuleMatched => "Request blocked by rule",
+            Reason::CaptchaRequired => "CAPTCHA required",
+            Reason::CaptchaFailed => "CAPTCHA verification failed",
+            Reason::Penalized => "Too many invalid solutions",
+            Reason::RequestTooLarge => "Request too large",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Rejection {
+    TooManyRequests {
+        reason: Reason,
+        current: ByteArray32,
+        difficulty: ByteArray32,
+        error: String,
+    },
+    Forbidden {
+        reason: Reason,
+        message: String,
+    },
+    QuotaExceeded {
+        error: String,
+        /// Seconds until the quota resets.
+        retry_after: u64,
+    },
+    Penalized {
+        error: String,
+        /// Seconds until the client leaves the penalty box.
+        retry_after: u64,
+    },
+    Captcha {
+        reason: Reason,
+        site_key: String,
+        token_header: String,
+        error: String,
+    },
+    TooLarge {
+        message: String,
+    },
+}
+
+#[derive(serde::Serialize)]
+struct DifficultyResponse<'a> {
+    current: ByteArray32,
+    difficulty: ByteArray32,
+    /// The latest protocol version accepted.
+    version: Version,
+    error: &'a str,
+    message: &'a str,
+}
+
+const TOO_MANY_REQUESTS_MESSAGE: &str = "Access restriction triggered";
+
+impl Rejection {
+    fn status(&self) -> u32 {
+        match self {
+            Rejection::TooManyRequests { .. }
+            | Rejection::QuotaExceeded { .. }
+            | Rejection::Penalized { .. }
+            | Rejection::Captcha { .. } => 429,
+            Rejection::Forbidden { .. } => 403,
+            Rejection::TooLarge { .. } => 413,
+        }
+    }
+
+    fn reason(&self) -> Reason {
+        match self {
+            Rejection::TooManyRequests { reason, .. }
+            | Rejection::Forbidden { reason, .. }
+            | Rejection::Captcha { reason, .. } => *reason,
+            Rejection::QuotaExceeded { .. } => Reason::QuotaExceeded,
+            Rejection::Penalized { .. } => Reason::Penalized,
+            Rejection::TooLarge { .. } => Reason::RequestTooLarge,
+        }
+    }
+
+    fn vars(&self, request_id: &str) -> Vec<(&'static str, String)> {
+        let mut vars = vec![
+            ("request_id", request_id.to_string()),
+            ("reason", self.reason().as_str().to_string()),
+        ];
+        match self {
+            Rejection::TooManyRequests {
+                current,
+                difficulty,
+                error,
+                ..
+            } => vars.extend([
+                ("message", TOO_MANY_REQUESTS_MESSAGE.to_string()),
+                ("error", error.clone()),
+                ("current", format!("{:x}", current)),
+                ("difficulty", format!("{:x}", difficulty)),
+            ]),
+            Rejection::Forbidden { message, .. } | Rejection::TooLarge { message } => {
+                vars.push(("message", message.clone()))
+            }
+            Rejection::QuotaExceeded { error, .. } | Rejection::Penalized { error, .. } => vars
+                .extend([
+                    ("message", TOO_MANY_REQUESTS_MESSAGE.to_string()),
+                    ("error", error.clone()),
+                ]),
+            Rejection::Captcha {
+                site_key,
+                token_header,
+                error,
+                ..
+            } => vars.extend([
+                ("message", TOO_MANY_REQUESTS_MESSAGE.to_string()),
+                ("error", error.clone()),
+                ("site_key", site_key.clone()),
+                ("token_header", token_header.clone()),
+            ]),
+        }
+        vars
+    }
+
+    fn problem(&self, type_base: Option<&str>, request_id: &str) -> Problem {
+        let reason = self.reason();
+        let problem = match self {
+            Rejection::TooManyRequests {
+                current,
+                difficulty,
+                error,
+                ..
+            } => Problem::new(
+                type_base,
+                reason.as_str(),
+                429,
+                reason.title(),
+                error.as_str(),
+            )
+            .extension("current", current)
+            .extension("difficulty", difficulty)
+            .extension("version", Version::LATEST),
+            Rejection::Forbidden { message, .. } | Rejection::TooLarge { message } => Problem::new(
+                type_base,
+                reason.as_str(),
+                self.status(),
+                reason.title(),
+                message.as_str(),
+            ),
+            Rejection::QuotaExceeded { error, .. } | Rejection::Penalized { error, .. } => {
+                Problem::new(
+                    type_base,
+                    reason.as_str(),
+                    429,
+                    reason.title(),
+                    error.as_str(),
+                )
+            }
+            Rejection::Captcha {
+                site_key,
+                token_header,
+                error,
+                ..
+            } => Problem::new(
+                type_base,
+                reason.as_str(),
+                429,
+                reason.title(),
+                error.as_str(),
+            )
+            .extension("site_key", site_key)
+            .extension("token_header", token_header),
+        };
+        if request_id.is_empty() {
+            problem
+        } else {
+            problem.extension("request_id", request_id)
+        }
+    }
+
+    /// Render with the configured template, or in `format`.
+    pub fn render(
+        self,
+        templates: &ResponseTemplates,
+        format: ErrorFormat,
+        problem_type_base: Option<&str>,
+        request_id: &str,
+    ) -> Response {
+        let template = match self {
+            Rejection::TooManyRequests { .. }
+            | Rejection::QuotaExceeded { .. }
+            | Rejection::Penalized { .. } => templates.too_many_requests.as_ref(),
+            Rejection::Forbidden { .. } => templates.forbidden.as_ref(),
+            Rejection::TooLarge { .. } => templates.payload_too_large.as_ref(),
+            Rejection::Captcha { .. } => templates
+                .captcha
+                .as_ref()
+                .or(templates.too_many_requests.as_ref()),
+        };
+        match (template, format) {
+            (Some(template), _) => template.render(self.status(), &self.vars(request_id)),
+            (None, ErrorFormat::Json) => self.into_response(),
+            (None, ErrorFormat::ProblemJson) => self.problem(problem_type_base, request_id).into(),
+        }
+    }
+}
+
+/// The built-in JSON response.
+impl IntoResponse for Rejection {
+    fn into_response(self) -> Response {
+        let builder = match &self {
+            Rejection::TooManyRequests {
+                current,
+                difficulty,
+                error,
+                ..
+            } => {
+                let body = DifficultyResponse {
+                    current: *current,
+                    difficulty: *difficulty,
+                    version: Version::LATEST,
+                    error,
+                    message: TOO_MANY_REQUESTS_MESSAGE,
+                };
+                Response::builder().json(&body)
+            }
+            Rejection::Forbidden { message, .. } | Rejection::TooLarge { message } => {
+                Response::builder().body(
+                    "text/json",
+                    serde_json::json!({ "message": message }).to_string(),
+                )
+            }
+            Rejection::QuotaExceeded { error, .. } | Rejection::Penalized { error, .. } => {
+                Response::builder().body(
+                    "text/json",
+                    serde_json::json!({ "error": error, "message": TOO_MANY_REQUESTS_MESSAGE })
+                        .to_string(),
+                )
+            }
+            Rejection::Captcha {
+                site_key,
+                token_header,
+                error,
+                ..
+            } => Response::builder().json(&serde_json::json!({
+                "error": error,
+                "message": TOO_MANY_REQUESTS_MESSAGE,
+                "captcha": { "site_key": site_key, "token_header": token_header },
+            })),
+        };
+        builder.status(self.status()).build()
+    }
+}
+
+impl ResponseTemplate {
+    fn render(&self, status: u32, vars: &[(&str, String)]) -> Response {
+        let html = self.content_type.contains("html");
+        let builder = Response::builder()
+            .status(self.status.unwrap_or(status))
+            .body(
+                self.content_type.as_str(),
+                substitute(&self.body, vars, html),
+            );
+        self.headers
+            .iter()
+            .fold(builder, |builder, (k, v)| {
+                builder.header(k.as_str(), substitute(v, vars, false))
+            })
+            .build()
+    }
+}
+
+pub(crate) fn escape_html(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+fn substitute(template: &str, vars: &[(&str, String)], html: bool) -> String {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        out.push_str(&rest[..start]);
+        let Some(end) = rest[start..].find("}}") else {
+            rest = &rest[start..];
+            break;
+        };
+        let name = rest[start + 2..start + end].trim();
+        if let Some((_, value)) = vars.iter().find(|(k, _)| *k == name) {
+            if html {
+                out.push_str(&escape_html(value));
+            } else {
+                out.push_str(value);
+            }
+        }
+        rest = &rest[start + end + 2..];
+    }
+    out.push_str(rest);
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn substitute_placeholders() {
+        let vars = [("message", "<b>blocked</b>".to_string())];
+        assert_eq!(
+            substitute("<p>{{ message }}</p>{{unknown}}", &vars, true),
+            "<p>&lt;b&gt;blocked&lt;/b&gt;</p>"
+        );
+        assert_eq!(
+            substitute("{{message}} {{", &vars, false),
+            "<b>blocked</b> {{"
+        );
+    }
+
+    #[test]
+    fn render_template() {
+        let templates: ResponseTemplates = serde_yaml::from_str(
+            r#"
+forbidden:
+  status: 451
+  headers:
+    X-Request-Id: "{{request_id}}"
+  body: "<h1>{{message}}</h1>"
+"#,
+        )
+        .expect("failed to parse templates");
+        let response = Rejection::Forbidden {
+            reason: Reason::RegionBlocked,
+            message: "region blocked".to_string(),
+        }
+        .render(&templates, ErrorFormat::ProblemJson, None, "abc");
+        assert_eq!(response.code, 451);
+        assert_eq!(
+            response.headers,
+            vec![
+                (
+                    "Content-Type".to_string(),
+                    "text/html; charset=utf-8".to_string()
+                ),
+                ("X-Request-Id".to_string(), "abc".to_string()),
+            ]
+        );
+        assert_eq!(
+            response.body.as_deref(),
+            Some(&b"<h1>region blocked</h1>"[..])
+        );
+
+        let response = Rejection::TooManyRequests {
+            reason: Reason::ChallengeRequired,
+            current: (&[0; 32]).into(),
+            difficulty: (&[0xff; 32]).into(),
+            error: "missing nonce".to_string(),
+        }
+        .render(&templates, ErrorFormat::Json, None, "abc");
+        assert_eq!(response.code, 429);
+        assert_eq!(
+            response.headers[0].1, "application/json",
+            "no template, built-in body"
+        );
+    }
+
+    #[test]
+    fn render_problem() {
+        let response = Rejection::TooManyRequests {
+            reason: Reason::InvalidSolution,
+            current: (&[0; 32]).into(),
+            difficulty: (&[0xff; 32]).into(),
+            error: "Invalid nonce, maybe difficulty upgraded".to_string(),
+        }
+        .render(
+            &ResponseTemplates::default(),
+            ErrorFormat::ProblemJson,
+            Some("https://errors.example.com/pow/"),
+            "",
+        );
+        assert_eq!(response.code, 429);
+        assert_eq!(response.headers[0].1, "application/problem+json");
+        let body: serde_json::Value =
+            serde_json::from_slice(response.body.as_deref().unwrap()).unwrap();
+        assert_eq!(
+            body["type"],
+            "https://errors.example.com/pow/invalid-solution"
+        );
+        assert_eq!(body["title"], "Invalid proof of work solution");
+        assert_eq!(body["status"], 429);
+        assert_eq!(body["detail"], "Invalid nonce, maybe difficulty upgraded");
+        assert!(body.get("difficulty").is_some());
+        assert!(body.get("request_id").is_none());
+    }
+
+    #[test]
+    fn render_penalized() {
+        let response = Rejection::Penalized {
+            error: "too many invalid solutions".to_string(),
+            retry_after: 300,
+        }
+        .render(
+            &ResponseTemplates::default(),
+            ErrorFormat::ProblemJson,
+            None,
+            "",
+        );
+        assert_eq!(response.code, 429);
+        let body: serde_json::Value =
+            serde_json::from_slice(response.body.as_deref().unwrap()).unwrap();
+        assert_eq!(body["type"], "urn:pow:problem:penalized");
+        assert_eq!(body["title"], "Too many invalid solutions");
+    }
+
+    #[test]
+    fn render_too_large() {
+        let response = Rejection::TooLarge {
+            message: "body is larger than 1024 bytes".to_string(),
+        }
+        .render(
+            &ResponseTemplates::default(),
+            ErrorFormat::ProblemJson,
+            None,
+            "",
+        );
+        assert_eq!(response.code, 413);
+        let body: serde_json::Value =
+            serde_json::from_slice(response.body.as_deref().unwrap()).unwrap();
+        assert_eq!(body["type"], "urn:pow:problem:request-too-large");
+        assert_eq!(body["status"], 413);
+    }
+
+    #[test]
+    fn render_captcha() {
+        let captcha = || Rejection::Captcha {
+            reason: Reason::CaptchaRequired,
+            site_key: "site-key".to_string(),
+            token_header: "X-Captcha-Token".to_string(),
+            error: "CAPTCHA required".to_string(),
+        };
+        let templates: ResponseTemplates = serde_yaml::from_str(
+            r#"
+too_many_requests:
+  body: "<div data-sitekey='{{site_key}}'>{{reason}}</div>"
+"#,
+        )
+        .expect("failed to parse templates");
+        let response = captcha().render(&templates, ErrorFormat::Json, None, "");
+        assert_eq!(response.code, 429);
+        assert_eq!(
+            response.body.as_deref(),
+            Some(&b"<div data-sitekey='site-key'>captcha-required</div>"[..])
+        );
+
+        let response = captcha().render(&ResponseTemplates::default(), ErrorFormat::Json, None, "");
+        let body: serde_json::Value =
+            serde_json::from_slice(response.body.as_deref().unwrap()).unwrap();
+        assert_eq!(body["captcha"]["site_key"], "site-key");
+        assert_eq!(body["captcha"]["token_header"], "X-Captcha-Token");
+    }
+}
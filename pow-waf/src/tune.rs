@@ -0,0 +1,224 @@
+//! Difficulty auto-tuning per route.
+//!
+//! Static difficulty levels rot as hardware improves, so routes with an
+//! `auto_tune` setting derive theirs from how long clients take to solve it.
+//!
+//! Clients choose their `X-PoW-Timestamp`, so solve times are measured from
+//! the server instead: challenges of a tuned route carry when they were
+//! issued in milliseconds and an HMAC-SHA256 of it with the route, which
+//! clients send back with their solution:
+//!
+//! ```text
+//! X-PoW-Issued: 1718000000123.5d41402abc4b2a76b9719d911017c592...
+//! ```
+//!
+//! The solve time of a valid solution is the time since it was issued,
+//! scaled to the tuned level when a floor or the rate made the challenge
+//! harder. Solutions without a ticket, with one of another route, from the
+//! future or older than a challenge lives are not sampled. Once `samples`
+//! solve times were collected across all workers, the level is scaled by
+//! how far their median is off the target, unless it is within the
+//! `hysteresis`, and kept within the bounds.
+
+use hmac::{Hmac, Mac};
+use pow_runtime::kv_store::{Error, KVStore};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+use crate::config::AutoTuneSetting;
+
+/// Header of the time a challenge was issued at, in its response and in the
+/// request with the solution.
+pub const ISSUED_HEADER: &str = "X-PoW-Issued";
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Tuning {
+    /// 0 until the first solve time was recorded.
+    level: u64,
+    samples: Vec<u64>,
+}
+
+impl AutoTuneSetting {
+    fn bound(&self, level: u64) -> u64 {
+        level.max(self.min_difficulty).min(self.max_difficulty)
+    }
+
+    /// The level after a round with `median` solve time.
+    fn adjust(&self, level: u64, median: u64) -> u64 {
+        let tolerance = self.target_ms * self.hysteresis / 100;
+        if median.abs_diff(self.target_ms) <= tolerance {
+            return level;
+        }
+        let scaled = level as u128 * self.target_ms as u128 / median.max(1) as u128;
+        self.bound(scaled.min(u64::MAX as u128) as u64)
+    }
+
+    fn mac(&self, issued_ms: u64, route: &str) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(self.secret.as_bytes())
+            .expect("HMAC takes keys of any length");
+        mac.update(format!("{}|{}", issued_ms, route).as_bytes());
+        mac
+    }
+
+    /// The [`ISSUED_HEADER`] value of a challenge of `route` issued at
+    /// `now_ms`.
+    pub fn issue(&self, route: &str, now_ms: u64) -> String {
+        let tag = self.mac(now_ms, route).finalize().into_bytes();
+        format!("{}.{}", now_ms, hex::encode(tag))
+    }
+
+    /// Milliseconds since the challenge of `route` with the [`ISSUED_HEADER`]
+    /// `value` was issued, `None` unless it was issued with the secret at
+    /// most `max_age_ms` before `now_ms`.
+    pub fn solve_ms(&self, value: &str, route: &str, now_ms: u64, max_age_ms: u64) -> Option<u64> {
+        let (issued_ms, tag) = value.split_once('.')?;
+        let (Ok(issued_ms), Ok(tag)) = (issued_ms.parse::<u64>(), hex::decode(tag)) else {
+            return None;
+        };
+        self.mac(issued_ms, route).verify_slice(&tag).ok()?;
+        now_ms
+            .checked_sub(issued_ms)
+            .filter(|solve_ms| *solve_ms <= max_age_ms)
+    }
+}
+
+fn median(samples: &mut [u64]) -> u64 {
+    samples.sort_unstable();
+    samples.get(samples.len() / 2).copied().unwrap_or_default()
+}
+
+pub struct Tuner {
+    store: KVStore<Tuning>,
+}
+
+impl Tuner {
+    pub fn new(context_id: u32) -> Self {
+        Self {
+            store: KVStore::new(context_id, "tune"),
+        }
+    }
+
+    /// The level of `route`, `initial` until it was first tuned.
+    pub fn level(
+        &self,
+        route: &str,
+        setting: &AutoTuneSetting,
+        initial: u64,
+    ) -> Result<u64, Error> {
+        let level = self
+            .store
+            .get(route)?
+            .map(|tuning| tuning.level)
+            .filter(|level| *level > 0)
+            .unwrap_or(initial);
+        Ok(setting.bound(level))
+    }
+
+    /// Record that a client took `solve_ms` to solve a challenge of `route`
+    /// at the tuned level.
+    pub fn record(
+        &self,
+        route: &str,
+        setting: &AutoTuneSetting,
+        initial: u64,
+        solve_ms: u64,
+    ) -> Result<(), Error> {
+        let mut tuned = None;
+        self.store.update(route, |tuning| {
+            let mut tuning = tuning.unwrap_or_default();
+            if tuning.level == 0 {
+                tuning.level = setting.bound(initial);
+            }
+            tuning.samples.push(solve_ms);
+            if tuning.samples.len() >= setting.samples {
+                let median = median(&mut tuning.samples);
+                let level = setting.adjust(tuning.level, median);
+                if level != tuning.level {
+                    tuned = Some((tuning.level, level, median));
+                }
+                tuning.level = level;
+                tuning.samples.clear();
+            }
+            Ok::<_, Error>(tuning)
+        })?;
+        if let Some((from, to, median)) = tuned {
+            log::info!(
+                "difficulty of {} tuned from {} to {}, median solve time {}ms",
+                route,
+                from,
+                to,
+                median
+            );
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn setting() -> AutoTuneSetting {
+        serde_yaml::from_str(
+            r#"
+target_ms: 2000
+min_difficulty: 1000
+max_difficulty: 100000
+secret: s3cret
+"#,
+        )
+        .expect("failed to parse setting")
+    }
+
+    #[test]
+    fn adjust() {
+        let setting = setting();
+        // within 25% of the target
+        assert_eq!(setting.adjust(10000, 2400), 10000);
+        assert_eq!(setting.adjust(10000, 1600), 10000);
+        assert_eq!(setting.adjust(10000, 4000), 5000);
+        assert_eq!(setting.adjust(10000, 1000), 20000);
+        assert_eq!(setting.adjust(10000, 0), 100000);
+        assert_eq!(setting.adjust(1500, 60000), 1000);
+        assert_eq!(median(&mut [3000, 1000, 2000, 9000]), 3000);
+        assert_eq!(median(&mut []), 0);
+    }
+
+    #[test]
+    fn forged_issue_times() {
+        let setting = setting();
+        let route = "example.com/api";
+        let now_ms = 1_718_000_000_000;
+        let solve =
+            |value: &str, route: &str, now_ms: u64| setting.solve_ms(value, route, now_ms, 60_000);
+        let issued = setting.issue(route, now_ms - 1_500);
+        assert_eq!(solve(&issued, route, now_ms), Some(1_500));
+        // issued for another route, or too long ago
+        assert_eq!(solve(&issued, "example.com/", now_ms), None);
+        assert_eq!(solve(&issued, route, now_ms + 60_000), None);
+
+        // a client picking when it was challenged, to look slow or fast
+        let (_, tag) = issued.split_once('.').unwrap();
+        for forged_ms in [now_ms - 59_000, now_ms + 10_000, u64::MAX] {
+            assert_eq!(
+                solve(&format!("{}.{}", forged_ms, tag), route, now_ms),
+                None
+            );
+        }
+        let other = AutoTuneSetting {
+            secret: "other".to_string(),
+            ..self::setting()
+        };
+        assert_eq!(
+            solve(&other.issue(route, now_ms - 59_000), route, now_ms),
+            None
+        );
+        // issued in the future, or without a tag
+        assert_eq!(
+            solve(&setting.issue(route, now_ms + 10_000), route, now_ms),
+            None
+        );
+        assert_eq!(solve("1718000000000", route, now_ms), None);
+        assert_eq!(solve("x.00", route, now_ms), None);
+    }
+}
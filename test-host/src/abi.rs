@@ -0,0 +1,667 @@
+//! The host functions the SDK imports, against the [`Host`] of the thread.
+//!
+//! Header maps are per context, other calls are recorded for the test to
+//! inspect. Results are handed over like a host writes them into the VM,
+//! in memory the SDK takes ownership of.
+
+use std::collections::VecDeque;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use proxy_wasm::types::{BufferType, LogLevel, MapType, MetricType, Status, StreamType};
+
+use crate::{Host, HOST};
+
+impl Host {
+    fn map(&mut self, map_type: MapType) -> Option<&mut Vec<(String, String)>> {
+        let context = self.effective_context;
+        match map_type {
+            MapType::HttpRequestHeaders => Some(self.request_headers.entry(context).or_default()),
+            MapType::HttpRequestTrailers => Some(self.request_trailers.entry(context).or_default()),
+            MapType::HttpResponseHeaders => Some(self.response_headers.entry(context).or_default()),
+            MapType::HttpCallResponseHeaders => Some(&mut self.call_response().headers),
+            MapType::HttpCallResponseTrailers => Some(&mut self.call_response().trailers),
+            _ => None,
+        }
+    }
+
+    /// Answer a hostcall the host does not simulate.
+    fn unsupported(&mut self, call: String) -> Status {
+        self.unsupported.push(call);
+        Status::BadArgument
+    }
+
+    fn call_response(&mut self) -> &mut crate::HttpResponse {
+        self.call_response
+            .as_mut()
+            .expect("call responses are only read in on_http_call_response")
+    }
+}
+
+fn with_map<T>(
+    map_type: MapType,
+    f: impl FnOnce(&mut Vec<(String, String)>) -> T,
+) -> Result<T, Status> {
+    HOST.with(|host| {
+        let mut host = host.borrow_mut();
+        match host.map(map_type) {
+            Some(map) => Ok(f(map)),
+            None => Err(host.unsupported(format!("map type {:?}", map_type))),
+        }
+    })
+}
+
+fn unsupported(call: String) -> Status {
+    HOST.with(|host| host.borrow_mut().unsupported(call))
+}
+
+#[no_mangle]
+extern "C" fn proxy_log(level: LogLevel, message_data: *const u8, message_size: usize) -> Status {
+    let message = unsafe { string(message_data, message_size) };
+    HOST.with(|host| host.borrow_mut().logs.push((level, message)));
+    Status::Ok
+}
+
+#[no_mangle]
+extern "C" fn proxy_get_log_level(return_level: *mut LogLevel) -> Status {
+    unsafe { *return_level = LogLevel::Trace };
+    Status::Ok
+}
+
+#[no_mangle]
+extern "C" fn proxy_get_current_time_nanoseconds(return_time: *mut u64) -> Status {
+    let clock = HOST.with(|host| host.borrow().clock);
+    let now = SystemTime::now() + clock;
+    let nanos = now
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    unsafe { *return_time = nanos as u64 };
+    Status::Ok
+}
+
+#[no_mangle]
+extern "C" fn proxy_set_tick_period_milliseconds(period: u32) -> Status {
+    let period = std::time::Duration::from_millis(period as u64);
+    HOST.with(|host| host.borrow_mut().tick_period = Some(period));
+    Status::Ok
+}
+
+#[no_mangle]
+extern "C" fn proxy_get_buffer_bytes(
+    // a u32, hosts know buffers the SDK does not
+    buffer_type: u32,
+    start: usize,
+    max_size: usize,
+    return_buffer_data: *mut *mut u8,
+    return_buffer_size: *mut usize,
+) -> Status {
+    let buffer = HOST.with(|host| {
+        let mut host = host.borrow_mut();
+        let buffer = match buffer_type {
+            t if t == BufferType::PluginConfiguration as u32 => host.plugin_configuration.clone(),
+            t if t == BufferType::HttpCallResponseBody as u32 => {
+                Some(host.call_response().body.clone())
+            }
+            t if t == BufferType::CallData as u32 => host.call_data.clone(),
+            _ => return Err(host.unsupported(format!("buffer type {}", buffer_type))),
+        };
+        Ok(buffer)
+    });
+    let buffer = match buffer {
+        Ok(Some(buffer)) => buffer,
+        Ok(None) => return Status::NotFound,
+        Err(status) => return status,
+    };
+    let start = start.min(buffer.len());
+    let end = start.saturating_add(max_size).min(buffer.len());
+    unsafe {
+        give(
+            buffer[start..end].to_vec(),
+            return_buffer_data,
+            return_buffer_size,
+        )
+    };
+    Status::Ok
+}
+
+#[no_mangle]
+extern "C" fn proxy_get_status(
+    return_code: *mut u32,
+    _return_message_data: *mut *mut u8,
+    _return_message_size: *mut usize,
+) -> Status {
+    // the runtime reads the status of a call response from here
+    let code = HOST.with(|host| host.borrow_mut().call_response().status);
+    unsafe { *return_code = code };
+    Status::Ok
+}
+
+/// No host function is defined, calls find none like on Envoy.
+#[no_mangle]
+extern "C" fn proxy_call_foreign_function(
+    function_name_data: *const u8,
+    function_name_size: usize,
+    arguments_data: *const u8,
+    arguments_size: usize,
+    results_data: *mut *mut u8,
+    results_size: *mut usize,
+) -> Status {
+    let name = unsafe { string(function_name_data, function_name_size) };
+    let arguments = unsafe { bytes(arguments_data, arguments_size) };
+    let result = HOST.with(|host| {
+        let mut host = host.borrow_mut();
+        host.foreign_calls.push((name.clone(), arguments));
+        host.foreign_functions.get(&name).cloned()
+    });
+    match result {
+        Some(result) => {
+            unsafe { give(result, results_data, results_size) };
+            Status::Ok
+        }
+        None => Status::NotFound,
+    }
+}
+
+unsafe fn string(data: *const u8, size: usize) -> String {
+    String::from_utf8_lossy(std::slice::from_raw_parts(data, size)).into_owned()
+}
+
+unsafe fn bytes(data: *const u8, size: usize) -> Vec<u8> {
+    match data.is_null() {
+        true => vec![],
+        false => std::slice::from_raw_parts(data, size).to_vec(),
+    }
+}
+
+/// Hand `bytes` to the SDK, which takes ownership with `Vec::from_raw_parts`.
+unsafe fn give(bytes: Vec<u8>, return_data: *mut *mut u8, return_size: *mut usize) {
+    let bytes = Box::leak(bytes.into_boxed_slice());
+    *return_size = bytes.len();
+    *return_data = bytes.as_mut_ptr();
+}
+
+#[no_mangle]
+extern "C" fn proxy_set_effective_context(context_id: u32) -> u32 {
+    HOST.with(|host| {
+        let mut host = host.borrow_mut();
+        if host.effective_context_unimplemented {
+            return 12;
+        }
+        host.effective_context = context_id;
+        Status::Ok as u32
+    })
+}
+
+#[no_mangle]
+extern "C" fn proxy_get_property(
+    path_data: *const u8,
+    path_size: usize,
+    return_value_data: *mut *mut u8,
+    return_value_size: *mut usize,
+) -> Status {
+    let path = unsafe { string(path_data, path_size) }.replace('\0', ".");
+    let value = HOST.with(|host| {
+        let host = host.borrow();
+        host.properties
+            .get(&(host.effective_context, path))
+            .cloned()
+    });
+    match value {
+        Some(value) => {
+            unsafe { give(value, return_value_data, return_value_size) };
+            Status::Ok
+        }
+        None => Status::NotFound,
+    }
+}
+
+#[no_mangle]
+extern "C" fn proxy_get_header_map_pairs(
+    map_type: MapType,
+    return_map_data: *mut *mut u8,
+    return_map_size: *mut usize,
+) -> Status {
+    let map = match with_map(map_type, |map| map.clone()) {
+        Ok(map) => map,
+        Err(status) => return status,
+    };
+    let mut bytes = (map.len() as u32).to_le_bytes().to_vec();
+    for (key, value) in &map {
+        bytes.extend((key.len() as u32).to_le_bytes());
+        bytes.extend((value.len() as u32).to_le_bytes());
+    }
+    for (key, value) in &map {
+        bytes.extend(key.as_bytes());
+        bytes.push(0);
+        bytes.extend(value.as_bytes());
+        bytes.push(0);
+    }
+    unsafe { give(bytes, return_map_data, return_map_size) };
+    Status::Ok
+}
+
+#[no_mangle]
+extern "C" fn proxy_get_header_map_value(
+    map_type: MapType,
+    key_data: *const u8,
+    key_size: usize,
+    return_value_data: *mut *mut u8,
+    return_value_size: *mut usize,
+) -> Status {
+    let key = unsafe { string(key_data, key_size) };
+    let value = with_map(map_type, |map| {
+        map.iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(&key))
+            .map(|(_, v)| v.clone())
+    });
+    match value {
+        Err(status) => status,
+        Ok(Some(value)) => {
+            unsafe { give(value.into_bytes(), return_value_data, return_value_size) };
+            Status::Ok
+        }
+        Ok(None) => Status::NotFound,
+    }
+}
+
+#[no_mangle]
+extern "C" fn proxy_add_header_map_value(
+    map_type: MapType,
+    key_data: *const u8,
+    key_size: usize,
+    value_data: *const u8,
+    value_size: usize,
+) -> Status {
+    let (key, value) = unsafe { (string(key_data, key_size), string(value_data, value_size)) };
+    with_map(map_type, |map| map.push((key, value))).map_or_else(|status| status, |()| Status::Ok)
+}
+
+#[no_mangle]
+extern "C" fn proxy_replace_header_map_value(
+    map_type: MapType,
+    key_data: *const u8,
+    key_size: usize,
+    value_data: *const u8,
+    value_size: usize,
+) -> Status {
+    let (key, value) = unsafe { (string(key_data, key_size), string(value_data, value_size)) };
+    with_map(map_type, |map| {
+        map.retain(|(k, _)| !k.eq_ignore_ascii_case(&key));
+        map.push((key, value));
+    })
+    .map_or_else(|status| status, |()| Status::Ok)
+}
+
+#[no_mangle]
+extern "C" fn proxy_remove_header_map_value(
+    map_type: MapType,
+    key_data: *const u8,
+    key_size: usize,
+) -> Status {
+    let key = unsafe { string(key_data, key_size) };
+    with_map(map_type, |map| {
+        map.retain(|(k, _)| !k.eq_ignore_ascii_case(&key))
+    })
+    .map_or_else(|status| status, |()| Status::Ok)
+}
+
+#[no_mangle]
+extern "C" fn proxy_continue_stream(stream_type: StreamType) -> Status {
+    assert_eq!(stream_type, StreamType::HttpRequest);
+    HOST.with(|host| {
+        let mut host = host.borrow_mut();
+        let context = host.effective_context;
+        host.continued.push(context);
+    });
+    Status::Ok
+}
+
+#[allow(clippy::too_many_arguments)]
+#[no_mangle]
+extern "C" fn proxy_send_local_response(
+    status_code: u32,
+    _status_code_details_data: *const u8,
+    _status_code_details_size: usize,
+    _body_data: *const u8,
+    _body_size: usize,
+    _headers_data: *const u8,
+    _headers_size: usize,
+    _grpc_status: i32,
+) -> Status {
+    HOST.with(|host| {
+        let mut host = host.borrow_mut();
+        let context = host.effective_context;
+        host.local_responses.push((context, status_code));
+    });
+    Status::Ok
+}
+
+#[no_mangle]
+extern "C" fn proxy_register_shared_queue(
+    name_data: *const u8,
+    name_size: usize,
+    return_id: *mut u32,
+) -> Status {
+    let name = unsafe { string(name_data, name_size) };
+    let id = HOST.with(|host| {
+        let mut host = host.borrow_mut();
+        let next = host.queues.len() as u32 + 1;
+        *host.queues.entry(name).or_insert(next)
+    });
+    unsafe { *return_id = id };
+    Status::Ok
+}
+
+#[no_mangle]
+extern "C" fn proxy_resolve_shared_queue(
+    _vm_id_data: *const u8,
+    _vm_id_size: usize,
+    name_data: *const u8,
+    name_size: usize,
+    return_id: *mut u32,
+) -> Status {
+    let name = unsafe { string(name_data, name_size) };
+    match HOST.with(|host| host.borrow().queues.get(&name).copied()) {
+        Some(id) => {
+            unsafe { *return_id = id };
+            Status::Ok
+        }
+        None => Status::NotFound,
+    }
+}
+
+#[no_mangle]
+extern "C" fn proxy_enqueue_shared_queue(
+    queue_id: u32,
+    value_data: *const u8,
+    value_size: usize,
+) -> Status {
+    let value = match value_data.is_null() {
+        true => vec![],
+        false => unsafe { std::slice::from_raw_parts(value_data, value_size) }.to_vec(),
+    };
+    HOST.with(|host| {
+        let mut host = host.borrow_mut();
+        if !host.queues.values().any(|&id| id == queue_id) {
+            return Status::NotFound;
+        }
+        host.messages.entry(queue_id).or_default().push_back(value);
+        if !host.ready_queues.contains(&queue_id) {
+            host.ready_queues.push(queue_id);
+        }
+        Status::Ok
+    })
+}
+
+#[no_mangle]
+extern "C" fn proxy_dequeue_shared_queue(
+    queue_id: u32,
+    return_value_data: *mut *mut u8,
+    return_value_size: *mut usize,
+) -> Status {
+    let value = HOST.with(|host| {
+        host.borrow_mut()
+            .messages
+            .get_mut(&queue_id)
+            .and_then(VecDeque::pop_front)
+    });
+    match value {
+        Some(value) => {
+            unsafe { give(value, return_value_data, return_value_size) };
+            Status::Ok
+        }
+        None => Status::Empty,
+    }
+}
+
+#[no_mangle]
+extern "C" fn proxy_get_shared_data(
+    key_data: *const u8,
+    key_size: usize,
+    return_value_data: *mut *mut u8,
+    return_value_size: *mut usize,
+    return_cas: *mut u32,
+) -> Status {
+    let key = unsafe { string(key_data, key_size) };
+    let value = HOST.with(|host| host.borrow().shared_data.get(&key).cloned());
+    match value {
+        Some((value, cas)) => {
+            unsafe {
+                give(value, return_value_data, return_value_size);
+                *return_cas = cas;
+            }
+            Status::Ok
+        }
+        None => Status::NotFound,
+    }
+}
+
+#[no_mangle]
+extern "C" fn proxy_set_shared_data(
+    key_data: *const u8,
+    key_size: usize,
+    value_data: *const u8,
+    value_size: usize,
+    cas: u32,
+) -> Status {
+    let key = unsafe { string(key_data, key_size) };
+    let value = match value_data.is_null() {
+        true => vec![],
+        false => unsafe { std::slice::from_raw_parts(value_data, value_size) }.to_vec(),
+    };
+    HOST.with(|host| {
+        let mut host = host.borrow_mut();
+        let current = host.shared_data.get(&key).map_or(0, |(_, cas)| *cas);
+        if cas != 0 && cas != current {
+            return Status::CasMismatch;
+        }
+        host.shared_data.insert(key, (value, current + 1));
+        Status::Ok
+    })
+}
+
+#[no_mangle]
+extern "C" fn proxy_define_metric(
+    _metric_type: MetricType,
+    name_data: *const u8,
+    name_size: usize,
+    return_id: *mut u32,
+) -> Status {
+    let name = unsafe { string(name_data, name_size) };
+    let id = HOST.with(|host| {
+        let mut host = host.borrow_mut();
+        host.metric_names.push(name);
+        host.metric_names.len() as u32
+    });
+    unsafe { *return_id = id };
+    Status::Ok
+}
+
+fn with_metric(metric_id: u32, f: impl FnOnce(&mut i64)) -> Status {
+    HOST.with(|host| {
+        let mut host = host.borrow_mut();
+        let Some(name) = host.metric_names.get(metric_id as usize - 1).cloned() else {
+            return Status::NotFound;
+        };
+        f(host.metrics.entry(name).or_default());
+        Status::Ok
+    })
+}
+
+#[no_mangle]
+extern "C" fn proxy_get_metric(metric_id: u32, return_value: *mut u64) -> Status {
+    with_metric(metric_id, |metric| unsafe {
+        *return_value = *metric as u64
+    })
+}
+
+#[no_mangle]
+extern "C" fn proxy_record_metric(metric_id: u32, value: u64) -> Status {
+    with_metric(metric_id, |metric| *metric = value as i64)
+}
+
+#[no_mangle]
+extern "C" fn proxy_increment_metric(metric_id: u32, offset: i64) -> Status {
+    with_metric(metric_id, |metric| *metric += offset)
+}
+
+/// Inverse of the SDK's map serialization: a count, the key and value sizes,
+/// then the NUL terminated keys and values. Counts and sizes are `u32`.
+unsafe fn map(data: *const u8, size: usize) -> Vec<(String, String)> {
+    const WORD: usize = std::mem::size_of::<u32>();
+    if size == 0 {
+        return vec![];
+    }
+    let bytes = std::slice::from_raw_parts(data, size);
+    let word = |at: usize| u32::from_le_bytes(bytes[at..at + WORD].try_into().unwrap()) as usize;
+    let count = word(0);
+    let mut offset = WORD + count * 2 * WORD;
+    let mut pairs = vec![];
+    for i in 0..count {
+        let key_size = word(WORD + i * 2 * WORD);
+        let value_size = word(2 * WORD + i * 2 * WORD);
+        let key = String::from_utf8_lossy(&bytes[offset..offset + key_size]).into_owned();
+        offset += key_size + 1;
+        let value = String::from_utf8_lossy(&bytes[offset..offset + value_size]).into_owned();
+        offset += value_size + 1;
+        pairs.push((key, value));
+    }
+    pairs
+}
+
+#[allow(clippy::too_many_arguments)]
+#[no_mangle]
+extern "C" fn proxy_http_call(
+    upstream_data: *const u8,
+    upstream_size: usize,
+    headers_data: *const u8,
+    headers_size: usize,
+    body_data: *const u8,
+    body_size: usize,
+    _trailers_data: *const u8,
+    _trailers_size: usize,
+    _timeout: u32,
+    return_token: *mut u32,
+) -> Status {
+    let upstream = unsafe { string(upstream_data, upstream_size) };
+    let headers = unsafe { map(headers_data, headers_size) };
+    let body = match body_data.is_null() {
+        true => None,
+        false => Some(unsafe { std::slice::from_raw_parts(body_data, body_size) }.to_vec()),
+    };
+    let token = HOST.with(|host| {
+        let mut host = host.borrow_mut();
+        host.http_calls.push((upstream, headers, body));
+        let token = host.http_calls.len() as u32;
+        host.pending_calls.push(token);
+        token
+    });
+    unsafe { *return_token = token };
+    Status::Ok
+}
+
+#[no_mangle]
+extern "C" fn proxy_set_header_map_pairs(
+    map_type: MapType,
+    map_data: *const u8,
+    map_size: usize,
+) -> Status {
+    let pairs = unsafe { map(map_data, map_size) };
+    with_map(map_type, |map| *map = pairs).map_or_else(|status| status, |()| Status::Ok)
+}
+
+#[no_mangle]
+extern "C" fn proxy_set_property(
+    path_data: *const u8,
+    path_size: usize,
+    value_data: *const u8,
+    value_size: usize,
+) -> Status {
+    let path = unsafe { string(path_data, path_size) }.replace('\0', ".");
+    let value = unsafe { std::slice::from_raw_parts(value_data, value_size) }.to_vec();
+    HOST.with(|host| {
+        let mut host = host.borrow_mut();
+        let context = host.effective_context;
+        host.properties.insert((context, path), value);
+    });
+    Status::Ok
+}
+
+// Not simulated, the SDK links them in all the same. They answer
+// `BadArgument` and are recorded in `Host::unsupported`.
+
+#[no_mangle]
+extern "C" fn proxy_set_buffer_bytes(
+    buffer_type: BufferType,
+    _start: usize,
+    _size: usize,
+    _buffer_data: *const u8,
+    _buffer_size: usize,
+) -> Status {
+    unsupported(format!("set buffer {:?}", buffer_type))
+}
+
+#[no_mangle]
+extern "C" fn proxy_close_stream(stream_type: StreamType) -> Status {
+    unsupported(format!("close stream {:?}", stream_type))
+}
+
+#[no_mangle]
+extern "C" fn proxy_done() -> Status {
+    unsupported("done".to_string())
+}
+
+#[allow(clippy::too_many_arguments)]
+#[no_mangle]
+extern "C" fn proxy_grpc_call(
+    _upstream_data: *const u8,
+    _upstream_size: usize,
+    _service_name_data: *const u8,
+    _service_name_size: usize,
+    _method_name_data: *const u8,
+    _method_name_size: usize,
+    _initial_metadata_data: *const u8,
+    _initial_metadata_size: usize,
+    _message_data_data: *const u8,
+    _message_data_size: usize,
+    _timeout: u32,
+    _return_callout_id: *mut u32,
+) -> Status {
+    unsupported("gRPC calls".to_string())
+}
+
+#[allow(clippy::too_many_arguments)]
+#[no_mangle]
+extern "C" fn proxy_grpc_stream(
+    _upstream_data: *const u8,
+    _upstream_size: usize,
+    _service_name_data: *const u8,
+    _service_name_size: usize,
+    _method_name_data: *const u8,
+    _method_name_size: usize,
+    _initial_metadata_data: *const u8,
+    _initial_metadata_size: usize,
+    _return_stream_id: *mut u32,
+) -> Status {
+    unsupported("gRPC streams".to_string())
+}
+
+#[no_mangle]
+extern "C" fn proxy_grpc_send(
+    _token: u32,
+    _message_ptr: *const u8,
+    _message_len: usize,
+    _end_stream: bool,
+) -> Status {
+    unsupported("gRPC streams".to_string())
+}
+
+#[no_mangle]
+extern "C" fn proxy_grpc_cancel(_token_id: u32) -> Status {
+    unsupported("gRPC calls".to_string())
+}
+
+#[no_mangle]
+extern "C" fn proxy_grpc_close(_token_id: u32) -> Status {
+    unsupported("gRPC calls".to_string())
+}
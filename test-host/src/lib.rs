@@ -0,0 +1,120 @@
+//! An in-process proxy-wasm host, to drive filters through realistic
+//! scenarios in `cargo test` rather than only inside Envoy.
+//!
+//! The SDK calls the host through functions resolved at link time. This
+//! crate defines them natively, so a test binary linking it runs the
+//! hostcalls against [`HOST`], the state of the host on the current thread:
+//! header maps per context, shared data and queues, metrics, the clock and
+//! HTTP calls, answered with canned [`HttpResponse`]s by upstream.
+//!
+//! [`Vm`] plays the proxy. It calls the filter through the entry points the
+//! SDK exports, creating contexts and delivering ticks, ready queues and
+//! call responses like Envoy does:
+//!
+//! ```no_run
+//! # use proxy_wasm::traits::RootContext;
+//! # fn root(_: u32) -> Box<dyn RootContext> { unimplemented!() }
+//! use test_host::{HttpResponse, Vm, HOST};
+//!
+//! HOST.with(|host| {
+//!     host.borrow_mut()
+//!         .responses
+//!         .insert("auth".to_string(), HttpResponse::new(200, "ok"))
+//! });
+//! let mut vm = Vm::start(root);
+//! let context_id = vm.request(&[(":method", "GET"), (":path", "/")]);
+//! vm.tick();
+//! assert!(HOST.with(|host| host.borrow().continued.contains(&context_id)));
+//! ```
+//!
+//! Every test runs on its own thread, and so with a host and a VM of its own.
+
+mod abi;
+mod vm;
+
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::time::Duration;
+
+use proxy_wasm::types::LogLevel;
+
+pub use vm::Vm;
+
+/// Upstream, headers and body of a dispatched call.
+pub type HttpCall = (String, Vec<(String, String)>, Option<Vec<u8>>);
+
+/// The answer of an upstream to a dispatched call.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HttpResponse {
+    pub status: u32,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    pub trailers: Vec<(String, String)>,
+}
+
+impl HttpResponse {
+    pub fn new(status: u32, body: impl Into<Vec<u8>>) -> Self {
+        HttpResponse {
+            status,
+            headers: vec![(":status".to_string(), status.to_string())],
+            body: body.into(),
+            trailers: vec![],
+        }
+    }
+
+    pub fn header(mut self, key: &str, value: &str) -> Self {
+        self.headers.push((key.to_string(), value.to_string()));
+        self
+    }
+}
+
+#[derive(Default)]
+pub struct Host {
+    effective_context: u32,
+    /// Answer `set_effective_context` with `Unimplemented`.
+    pub effective_context_unimplemented: bool,
+    pub plugin_configuration: Option<Vec<u8>>,
+    /// Values by context and path joined with `.`.
+    pub properties: HashMap<(u32, String), Vec<u8>>,
+    pub request_headers: HashMap<u32, Vec<(String, String)>>,
+    pub request_trailers: HashMap<u32, Vec<(String, String)>>,
+    pub response_headers: HashMap<u32, Vec<(String, String)>>,
+    /// Shared queue ids by name, and their messages.
+    pub queues: HashMap<String, u32>,
+    pub messages: HashMap<u32, VecDeque<Vec<u8>>>,
+    /// Queues enqueued to since [`Vm::tick`] last reported them ready.
+    pub ready_queues: Vec<u32>,
+    /// Metric names by id - 1, and their values.
+    pub metric_names: Vec<String>,
+    pub metrics: HashMap<String, i64>,
+    /// Shared data by key, with its cas.
+    pub shared_data: HashMap<String, (Vec<u8>, u32)>,
+    /// The token of a call is its index + 1.
+    pub http_calls: Vec<HttpCall>,
+    /// Tokens of the calls not answered yet.
+    pub pending_calls: Vec<u32>,
+    /// Answers to calls by upstream, [`Vm::tick`] delivers them.
+    pub responses: HashMap<String, HttpResponse>,
+    /// The response being delivered, read by `on_http_call_response`.
+    call_response: Option<HttpResponse>,
+    /// Contexts whose request went on, right away or once resumed.
+    pub continued: Vec<u32>,
+    /// Contexts and status codes of local responses.
+    pub local_responses: Vec<(u32, u32)>,
+    /// Results of foreign functions by name, absent ones are not found.
+    pub foreign_functions: HashMap<String, Vec<u8>>,
+    /// Names and arguments of the foreign functions called.
+    pub foreign_calls: Vec<(String, Vec<u8>)>,
+    /// The data of the foreign function event being delivered.
+    pub call_data: Option<Vec<u8>>,
+    pub tick_period: Option<Duration>,
+    /// Added to the real time the host reports, to move its clock forward.
+    pub clock: Duration,
+    pub logs: Vec<(LogLevel, String)>,
+    /// Hostcalls the host does not simulate, answered with `BadArgument`.
+    pub unsupported: Vec<String>,
+}
+
+thread_local! {
+    pub static HOST: RefCell<Host> = RefCell::new(Host::default());
+}
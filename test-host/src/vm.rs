@@ -0,0 +1,220 @@
+//! The proxy side of the ABI, calling into the filter.
+
+use std::time::{Duration, Instant};
+
+use proxy_wasm::traits::RootContext;
+use proxy_wasm::types::Action;
+
+use crate::{HttpResponse, HOST};
+
+/// Exported by the SDK, the entry points the proxy calls.
+mod entry {
+    use proxy_wasm::types::Action;
+
+    extern "C" {
+        pub fn proxy_on_context_create(context_id: u32, root_context_id: u32);
+        pub fn proxy_on_vm_start(context_id: u32, vm_configuration_size: usize) -> bool;
+        pub fn proxy_on_configure(context_id: u32, plugin_configuration_size: usize) -> bool;
+        pub fn proxy_on_tick(context_id: u32);
+        pub fn proxy_on_queue_ready(context_id: u32, queue_id: u32);
+        pub fn proxy_on_request_headers(
+            context_id: u32,
+            num_headers: usize,
+            end_of_stream: bool,
+        ) -> Action;
+        pub fn proxy_on_response_headers(
+            context_id: u32,
+            num_headers: usize,
+            end_of_stream: bool,
+        ) -> Action;
+        pub fn proxy_on_http_call_response(
+            context_id: u32,
+            token_id: u32,
+            num_headers: usize,
+            body_size: usize,
+            num_trailers: usize,
+        );
+        pub fn proxy_on_foreign_function(context_id: u32, function_id: u32, data_size: usize);
+        pub fn proxy_on_done(context_id: u32) -> bool;
+        pub fn proxy_on_log(context_id: u32);
+        pub fn proxy_on_delete(context_id: u32);
+    }
+}
+
+/// Make `context_id` the context hostcalls act on, as the proxy does before
+/// each callback.
+fn enter(context_id: u32) {
+    HOST.with(|host| host.borrow_mut().effective_context = context_id);
+}
+
+fn pairs(headers: &[(&str, &str)]) -> Vec<(String, String)> {
+    headers
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect()
+}
+
+/// A plugin loaded into the host of the current thread, with a root context
+/// and the HTTP contexts of its requests.
+pub struct Vm {
+    root_context_id: u32,
+    next_context_id: u32,
+}
+
+impl Vm {
+    /// Load the plugin whose root contexts `root` creates and start it, as
+    /// `proxy_wasm::main!` and the proxy do.
+    pub fn start(root: fn(u32) -> Box<dyn RootContext>) -> Self {
+        proxy_wasm::set_root_context(root);
+        let vm = Vm {
+            root_context_id: 1,
+            next_context_id: 2,
+        };
+        enter(vm.root_context_id);
+        unsafe {
+            entry::proxy_on_context_create(vm.root_context_id, 0);
+            assert!(
+                entry::proxy_on_vm_start(vm.root_context_id, 0),
+                "the plugin failed to start"
+            );
+        }
+        vm
+    }
+
+    pub fn root_context_id(&self) -> u32 {
+        self.root_context_id
+    }
+
+    /// Hand the plugin its configuration, whether it accepted it.
+    pub fn configure(&mut self, configuration: &[u8]) -> bool {
+        HOST.with(|host| host.borrow_mut().plugin_configuration = Some(configuration.to_vec()));
+        enter(self.root_context_id);
+        unsafe { entry::proxy_on_configure(self.root_context_id, configuration.len()) }
+    }
+
+    /// Create an HTTP context for a request with `headers` and its headers
+    /// to the plugin, the id of the context.
+    ///
+    /// Whether the request was resumed or answered shows in
+    /// [`Host::continued`](crate::Host::continued) and
+    /// [`Host::local_responses`](crate::Host::local_responses) once the plugin
+    /// decides, possibly only after some ticks.
+    pub fn request(&mut self, headers: &[(&str, &str)]) -> u32 {
+        let context_id = self.next_context_id;
+        self.next_context_id += 1;
+        HOST.with(|host| {
+            host.borrow_mut()
+                .request_headers
+                .insert(context_id, pairs(headers))
+        });
+        enter(context_id);
+        unsafe {
+            entry::proxy_on_context_create(context_id, self.root_context_id);
+            let action = entry::proxy_on_request_headers(context_id, headers.len(), true);
+            if action == Action::Continue {
+                HOST.with(|host| host.borrow_mut().continued.push(context_id));
+            }
+        }
+        context_id
+    }
+
+    /// The upstream answered the request of `context_id` with `headers`.
+    pub fn response(&mut self, context_id: u32, headers: &[(&str, &str)]) -> Action {
+        HOST.with(|host| {
+            host.borrow_mut()
+                .response_headers
+                .insert(context_id, pairs(headers))
+        });
+        enter(context_id);
+        unsafe { entry::proxy_on_response_headers(context_id, headers.len(), true) }
+    }
+
+    /// The stream of `context_id` is over, its context is deleted.
+    pub fn done(&mut self, context_id: u32) {
+        enter(context_id);
+        unsafe {
+            entry::proxy_on_done(context_id);
+            entry::proxy_on_log(context_id);
+            entry::proxy_on_delete(context_id);
+        }
+    }
+
+    /// Answer the call `token` with `response`, `None` fails it like a reset
+    /// or timed out call.
+    pub fn respond(&mut self, token: u32, response: Option<HttpResponse>) {
+        HOST.with(|host| {
+            let mut host = host.borrow_mut();
+            host.pending_calls.retain(|pending| *pending != token);
+            host.call_response = response.clone();
+        });
+        let (num_headers, body_size, num_trailers) = match &response {
+            Some(response) => (
+                response.headers.len(),
+                response.body.len(),
+                response.trailers.len(),
+            ),
+            None => (0, 0, 0),
+        };
+        enter(self.root_context_id);
+        unsafe {
+            entry::proxy_on_http_call_response(
+                self.root_context_id,
+                token,
+                num_headers,
+                body_size,
+                num_trailers,
+            )
+        };
+        HOST.with(|host| host.borrow_mut().call_response = None);
+    }
+
+    /// The foreign function event `function_id` with `data` for
+    /// `context_id`, as a host reports a function done.
+    pub fn foreign_function_event(&mut self, context_id: u32, function_id: u32, data: &[u8]) {
+        HOST.with(|host| host.borrow_mut().call_data = Some(data.to_vec()));
+        enter(context_id);
+        unsafe { entry::proxy_on_foreign_function(context_id, function_id, data.len()) };
+        HOST.with(|host| host.borrow_mut().call_data = None);
+    }
+
+    /// One round of the event loop: answer the pending calls to upstreams
+    /// with a canned response, report the queues enqueued to ready, then
+    /// tick.
+    pub fn tick(&mut self) {
+        let answers: Vec<(u32, HttpResponse)> = HOST.with(|host| {
+            let host = host.borrow();
+            host.pending_calls
+                .iter()
+                .filter_map(|token| {
+                    let (upstream, _, _) = &host.http_calls[*token as usize - 1];
+                    Some((*token, host.responses.get(upstream)?.clone()))
+                })
+                .collect()
+        });
+        for (token, response) in answers {
+            self.respond(token, Some(response));
+        }
+
+        let ready = HOST.with(|host| std::mem::take(&mut host.borrow_mut().ready_queues));
+        for queue_id in ready {
+            enter(self.root_context_id);
+            unsafe { entry::proxy_on_queue_ready(self.root_context_id, queue_id) };
+        }
+
+        enter(self.root_context_id);
+        unsafe { entry::proxy_on_tick(self.root_context_id) };
+    }
+
+    /// Run the event loop for `duration` of real time, ticking at the
+    /// period the plugin set, 1ms if it set none.
+    pub fn run_for(&mut self, duration: Duration) {
+        let period = HOST
+            .with(|host| host.borrow().tick_period)
+            .unwrap_or(Duration::from_millis(1));
+        let start = Instant::now();
+        while start.elapsed() < duration {
+            std::thread::sleep(period);
+            self.tick();
+        }
+    }
+}